@@ -0,0 +1,184 @@
+use std::{fs, path::Path};
+
+use termcolor::Color;
+
+use crate::{
+    dep_types::{Req, Version},
+    util::{
+        self, abort,
+        cross_target::Target,
+        deps::{runtime_packs, sync_deps},
+        print_color,
+    },
+};
+
+/// Signature files some wheels ship alongside `RECORD`. Nothing reads them at runtime.
+const RECORD_SIGNATURES: [&str; 2] = ["RECORD.jws", "RECORD.p7s"];
+
+/// Install the packages in `pyflow.lock` into a flat directory, eg for deployment to AWS
+/// Lambda. Doesn't create an environment there, or modify the lock or `pyproject.toml`. Packages
+/// are installed for `for_target`, if passed, or the target `pyflow.lock` was made for, if any.
+#[allow(clippy::too_many_arguments)]
+pub fn bundle(
+    target: &Path,
+    paths: &util::Paths,
+    lock_path: &Path,
+    reqs: &[Req],
+    no_dev: bool,
+    scripts: bool,
+    compact: bool,
+    force: bool,
+    os: util::Os,
+    py_vers: &Version,
+    for_target: Option<Target>,
+) {
+    let lock = util::read_lock(lock_path)
+        .unwrap_or_else(|_| abort("No `pyflow.lock` found. Run `pyflow install` to create it."));
+    let (os, py_vers) = match for_target.or_else(|| Target::from_lock(&lock.metadata)) {
+        Some(t) => {
+            t.make_current();
+            (t.os, t.python)
+        }
+        None => (os, py_vers.clone()),
+    };
+    let lock_packs = lock.package.unwrap_or_default();
+
+    if !force && !is_empty_dir(target) {
+        abort(&format!(
+            "{} isn't empty. Use `--force` to install into it anyway.",
+            target.to_string_lossy()
+        ));
+    }
+    if fs::create_dir_all(target).is_err() {
+        abort(&format!("Problem creating {}", target.to_string_lossy()));
+    }
+
+    let lock_packs = if no_dev {
+        runtime_packs(&lock_packs, reqs).unwrap_or_else(|e| abort(&e))
+    } else {
+        lock_packs
+    };
+
+    // Console scripts are only kept with `--scripts`; leave a `bin` folder alone if it was
+    // already there.
+    let bin_path = target.join("bin");
+    let had_bin = bin_path.exists();
+    let target_paths = util::Paths {
+        bin: paths.bin.clone(),
+        lib: target.to_owned(),
+        entry_pt: bin_path.clone(),
+        cache: paths.cache.clone(),
+    };
+    sync_deps(&target_paths, &lock_packs, &[], &[], os, &py_vers);
+
+    if !scripts && !had_bin && bin_path.exists() && fs::remove_dir_all(&bin_path).is_err() {
+        abort("Problem removing console scripts");
+    }
+    if compact && compact_dir(target).is_err() {
+        abort("Problem removing files with `--compact`");
+    }
+
+    print_color(
+        &format!(
+            "Installed {} packages into {}",
+            lock_packs.len(),
+            target.to_string_lossy()
+        ),
+        Color::Green,
+    );
+}
+
+fn is_empty_dir(path: &Path) -> bool {
+    match fs::read_dir(path) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(_) => !path.exists(),
+    }
+}
+
+/// Remove `__pycache__` folders and `RECORD` signature files, and their lines in `RECORD`.
+fn compact_dir(dir: &Path) -> std::io::Result<()> {
+    remove_pycaches(dir)?;
+
+    for entry in fs::read_dir(dir)? {
+        let dist_info = entry?.path();
+        if !dist_info.to_string_lossy().ends_with(".dist-info") {
+            continue;
+        }
+        for sig in &RECORD_SIGNATURES {
+            let sig_path = dist_info.join(sig);
+            if sig_path.exists() {
+                fs::remove_file(sig_path)?;
+            }
+        }
+
+        let record_path = dist_info.join("RECORD");
+        if let Ok(record) = fs::read_to_string(&record_path) {
+            fs::write(&record_path, compact_record(&record))?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_pycaches(dir: &Path) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        if path.file_name().is_some_and(|n| n == "__pycache__") {
+            fs::remove_dir_all(path)?;
+        } else {
+            remove_pycaches(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn compact_record(record: &str) -> String {
+    record
+        .lines()
+        .filter(|line| {
+            let path = line.split(',').next().unwrap_or_default().trim_matches('"');
+            !path.split('/').any(|part| part == "__pycache__")
+                && !RECORD_SIGNATURES.iter().any(|sig| path.ends_with(sig))
+        })
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_strips_caches_and_signatures() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let dist_info = dir.join("pkg-1.0.dist-info");
+        fs::create_dir_all(dir.join("pkg/__pycache__")).unwrap();
+        fs::create_dir_all(&dist_info).unwrap();
+        fs::write(dir.join("pkg/__init__.py"), "").unwrap();
+        fs::write(dir.join("pkg/__pycache__/__init__.cpython-311.pyc"), "").unwrap();
+        fs::write(dist_info.join("RECORD.jws"), "").unwrap();
+        fs::write(
+            dist_info.join("RECORD"),
+            "pkg/__init__.py,sha256=abc,0\n\
+             pkg/__pycache__/__init__.cpython-311.pyc,,\n\
+             pkg-1.0.dist-info/RECORD.jws,,\n\
+             pkg-1.0.dist-info/RECORD,,\n",
+        )
+        .unwrap();
+
+        compact_dir(&dir).unwrap();
+
+        assert!(dir.join("pkg/__init__.py").exists());
+        assert!(!dir.join("pkg/__pycache__").exists());
+        assert!(!dist_info.join("RECORD.jws").exists());
+        assert_eq!(
+            fs::read_to_string(dist_info.join("RECORD")).unwrap(),
+            "pkg/__init__.py,sha256=abc,0\npkg-1.0.dist-info/RECORD,,\n"
+        );
+        assert!(!is_empty_dir(&dir));
+        assert!(is_empty_dir(&dir.join("missing")));
+    }
+}
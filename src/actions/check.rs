@@ -0,0 +1,138 @@
+use std::{fs, path::Path};
+
+use termcolor::Color;
+
+use crate::{
+    build_tools,
+    dep_types::{Req, Version},
+    pyproject::validate,
+    util::{self, abort, lock_header::Header, print_color, print_color_, requires_python, Os},
+};
+
+/// Report on the environment's build tooling: whether it's missing, no longer matches the pins
+/// in `pyproject.toml`, or has newer compatible releases available. Also report installed
+/// packages that don't support the environment's Python version, and what the lock was made
+/// with, if it may not suit this environment.
+pub fn check(
+    vers_path: &Path,
+    lib_path: &Path,
+    lock_path: &Path,
+    pins: &[Req],
+    os: Os,
+    py_vers: &Version,
+) {
+    if let Ok(lock) = util::read_lock(lock_path) {
+        for warning in Header::from_lock(&lock.metadata).warnings(os, py_vers) {
+            print_color(&format!("{}\n", warning), Color::Yellow);
+        }
+    }
+
+    let incompatible = requires_python::find_incompatible(lib_path, py_vers);
+    if !incompatible.is_empty() {
+        print_color(
+            &format!("These packages don't support Python {}:", py_vers),
+            Color::Yellow,
+        );
+        for inc in &incompatible {
+            print_color_(&inc.name, Color::Cyan);
+            print_color(
+                &format!("=={}, requires Python {}", inc.version, inc.requires_python),
+                Color::White,
+            );
+        }
+        print_color(
+            "Run `pyflow install` to replace them with versions that do.\n",
+            Color::Yellow,
+        );
+    }
+
+    let record = match build_tools::read_record(vers_path) {
+        Some(r) => r,
+        None => {
+            print_color(
+                "No record of installed build tooling was found for this environment. \
+                 Run `pyflow reset` to recreate it.",
+                Color::Yellow,
+            );
+            return;
+        }
+    };
+
+    let mut stale = false;
+    for name in build_tools::TOOLS {
+        print_color_(name, Color::Cyan);
+        let pin = build_tools::find_pin(pins, name);
+
+        let installed = match record.version(name) {
+            Some(v) => v,
+            None => {
+                print_color(": not installed", Color::Yellow);
+                stale = true;
+                continue;
+            }
+        };
+
+        if !build_tools::satisfies_pin(pin, &installed) {
+            print_color(
+                &format!(
+                    "=={}, which doesn't match the pin in `pyproject.toml`",
+                    installed
+                ),
+                Color::Yellow,
+            );
+            stale = true;
+            continue;
+        }
+
+        match build_tools::find_latest(name, pin, py_vers) {
+            Ok(latest) if latest > installed => {
+                print_color(
+                    &format!("=={}; {} is available", installed, latest),
+                    Color::Yellow,
+                );
+                stale = true;
+            }
+            Ok(_) => print_color(&format!("=={}", installed), Color::White),
+            Err(_) => print_color(
+                &format!("=={} (unable to check for newer versions)", installed),
+                Color::White,
+            ),
+        }
+    }
+
+    if stale {
+        print_color(
+            "\nBuild tooling is out of date. Run `pyflow reset` to recreate the environment.",
+            Color::Yellow,
+        );
+    } else {
+        print_color("\nBuild tooling is up to date.", Color::Green);
+    }
+}
+
+/// Check `pyproject.toml` without setting up an environment, reporting each problem with its key
+/// and line. Exits with an error if any are found; unknown keys are only warned about.
+pub fn check_config(config_path: &Path) {
+    let toml_str = fs::read_to_string(config_path)
+        .unwrap_or_else(|_| abort(&format!("Problem reading {}", config_path.display())));
+    let validation = validate::validate(&toml_str);
+
+    for warning in &validation.warnings {
+        print_color(&format!("Warning: {}", warning), Color::Yellow);
+    }
+    let errors: Vec<_> = validation
+        .parse_error
+        .iter()
+        .chain(&validation.errors)
+        .collect();
+    for error in &errors {
+        print_color(&format!("Error: {}", error), Color::Red);
+    }
+    if !validation.is_ok() {
+        util::exit(1);
+    }
+    print_color(
+        &format!("No problems found in {}", config_path.display()),
+        Color::Green,
+    );
+}
@@ -1,51 +1,70 @@
-use std::{fs, path::Path};
+use std::{fmt, fs};
 
-use crate::util::{self, abort, success};
+use crate::util::{self, abort, paths::PyflowDirs, success};
 
 #[derive(Clone)]
 enum ClearChoice {
     Dependencies,
+    BuiltWheels,
     ScriptEnvs,
     PyInstalls,
+    PyProbes,
     //    Global,
     All,
 }
 
-impl ToString for ClearChoice {
-    fn to_string(&self) -> String {
-        "".into()
+impl fmt::Display for ClearChoice {
+    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Ok(())
     }
 }
 
 /// Clear `Pyflow`'s cache. Allow the user to select which parts to clear based on a prompt.
-pub fn clear(pyflow_path: &Path, cache_path: &Path, script_env_path: &Path) {
+pub fn clear(dirs: &PyflowDirs) {
+    let (cache_path, script_env_path) = (&dirs.cache, &dirs.script_envs);
     let result = util::prompts::list(
         "Which cached items would you like to clear?",
         "choice",
         &[
             ("Downloaded dependencies".into(), ClearChoice::Dependencies),
+            ("Wheels built from source".into(), ClearChoice::BuiltWheels),
             (
                 "Standalone-script environments".into(),
                 ClearChoice::ScriptEnvs,
             ),
             ("Python installations".into(), ClearChoice::PyInstalls),
+            (
+                "Stored Python interpreter versions".into(),
+                ClearChoice::PyProbes,
+            ),
             ("All of the above".into(), ClearChoice::All),
         ],
         false,
+        None,
+        "Remove the folders `pyflow env` lists instead.",
     );
 
     // todo: DRY
     match result.1 {
         ClearChoice::Dependencies => {
-            if fs::remove_dir_all(&cache_path).is_err() {
+            if fs::remove_dir_all(cache_path).is_err() {
                 abort(&format!(
                     "Problem removing the dependency-cache path: {:?}",
                     cache_path
                 ));
             }
         }
+        ClearChoice::BuiltWheels => {
+            let built_path = cache_path.join(util::wheel_cache::DIR_NAME);
+            if built_path.exists() && fs::remove_dir_all(&built_path).is_err() {
+                abort(&format!(
+                    "Problem removing the built-wheel cache: {:?}",
+                    built_path
+                ));
+            }
+        }
         ClearChoice::ScriptEnvs => {
-            if fs::remove_dir_all(&script_env_path).is_err() {
+            if fs::remove_dir_all(script_env_path).is_err() {
                 abort(&format!(
                     "Problem removing the script env path: {:?}",
                     script_env_path
@@ -53,12 +72,22 @@ pub fn clear(pyflow_path: &Path, cache_path: &Path, script_env_path: &Path) {
             }
         }
         ClearChoice::PyInstalls => {}
+        ClearChoice::PyProbes => {
+            if util::py_probes::clear(&dirs.data).is_err() {
+                abort("Problem removing the stored Python interpreter versions");
+            }
+        }
         ClearChoice::All => {
-            if fs::remove_dir_all(&pyflow_path).is_err() {
-                abort(&format!(
-                    "Problem removing the Pyflow path: {:?}",
-                    pyflow_path
-                ));
+            // Locations moved out of the Pyflow path are removed separately.
+            let moved = dirs
+                .entries()
+                .into_iter()
+                .map(|(_, path)| path)
+                .filter(|p| !p.starts_with(&dirs.data) && p.exists());
+            for path in moved.chain([dirs.data.as_path()]) {
+                if fs::remove_dir_all(path).is_err() {
+                    abort(&format!("Problem removing the Pyflow path: {:?}", path));
+                }
             }
         }
     }
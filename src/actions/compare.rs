@@ -0,0 +1,259 @@
+//! Compare `pyproject.toml`'s requirements with a `requirements.txt` or `Pipfile` kept for other
+//! tools, and regenerate a `requirements.txt` from `pyflow.lock`.
+
+use std::{fs, path::Path, str::FromStr};
+
+use termcolor::Color;
+
+use crate::{
+    dep_types::{self, Constraint, LockPackage, Req, Version},
+    pyproject::Config,
+    util::{self, abort, print_color},
+};
+
+/// How a requirement differs between `pyproject.toml` and the other file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Drift {
+    OnlyInConfig(String),
+    OnlyInOther(String),
+    /// No version satisfies both: the name, and the constraints in `pyproject.toml` and the
+    /// other file.
+    Disjoint(String, String, String),
+}
+
+fn constraints_str(constraints: &[Constraint]) -> String {
+    if constraints.is_empty() {
+        return "*".into();
+    }
+    constraints
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Requirements from a `requirements.txt`. Comments, blank lines, and options like `-r` and
+/// `--hash` are skipped.
+pub fn read_requirements(data: &str) -> Vec<Req> {
+    data.lines()
+        .filter_map(|line| {
+            let line = line.split(" #").next().unwrap_or_default();
+            let line = line.trim().trim_end_matches('\\').trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('-') {
+                return None;
+            }
+            // Lines with environment markers are in PyPI's format.
+            let req = Req::from_pip_str(line).or_else(|| Req::from_str(line, true).ok());
+            if req.is_none() {
+                print_color(
+                    &format!("Skipping {}, which we can't parse", line),
+                    Color::Yellow,
+                );
+            }
+            req
+        })
+        .collect()
+}
+
+fn overlaps(a: &Req, b: &Req) -> bool {
+    a.constraints.is_empty()
+        || b.constraints.is_empty()
+        || !dep_types::intersection_many(
+            &[a.constraints.as_slice(), b.constraints.as_slice()].concat(),
+        )
+        .is_empty()
+}
+
+/// Differences between the requirements in `pyproject.toml` and another file. Packages only in
+/// the other file aren't drift if a locked version satisfies them, eg dependencies it pins
+/// after `--sync-to`.
+pub fn find_drift(config_reqs: &[Req], other: &[Req], locked: &[LockPackage]) -> Vec<Drift> {
+    let mut result = vec![];
+    for req in config_reqs {
+        match other
+            .iter()
+            .find(|o| util::compare_names(&o.name, &req.name))
+        {
+            None => result.push(Drift::OnlyInConfig(req.name.clone())),
+            Some(o) if !overlaps(req, o) => result.push(Drift::Disjoint(
+                req.name.clone(),
+                constraints_str(&req.constraints),
+                constraints_str(&o.constraints),
+            )),
+            Some(_) => (),
+        }
+    }
+
+    for o in other.iter().filter(|o| {
+        !config_reqs
+            .iter()
+            .any(|r| util::compare_names(&r.name, &o.name))
+    }) {
+        let is_locked = locked.iter().any(|lp| {
+            util::compare_names(&lp.name, &o.name)
+                && Version::from_str(&lp.version)
+                    .is_ok_and(|v| o.constraints.iter().all(|c| c.is_compatible(&v)))
+        });
+        if !is_locked {
+            result.push(Drift::OnlyInOther(o.name.clone()));
+        }
+    }
+    result
+}
+
+/// A `requirements.txt` pinning each package in the lock, for tools that don't read
+/// `pyproject.toml`. Renamed copies of packages can't be expressed in one, so are left out.
+pub fn requirements_txt(lock_packs: &[LockPackage]) -> String {
+    let mut lines: Vec<String> = lock_packs
+        .iter()
+        .filter(|lp| lp.rename.is_none())
+        .map(|lp| match &lp.marker {
+            Some(m) => format!("{}=={} ; {}", lp.name, lp.version, m),
+            None => format!("{}=={}", lp.name, lp.version),
+        })
+        .collect();
+    lines.sort_by_key(|l| l.to_lowercase());
+    lines.dedup();
+    format!(
+        "# Generated from `pyflow.lock` by `pyflow check --sync-to`. Edit `pyproject.toml` \
+         instead.\n{}\n",
+        lines.join("\n")
+    )
+}
+
+/// Regenerate `sync_to` from the lock, if set, then report how `compare` differs from
+/// `pyproject.toml`. Exits with an error if they've drifted apart, eg for CI.
+pub fn compare(cfg: &Config, lock_path: &Path, compare: Option<&Path>, sync_to: Option<&Path>) {
+    let locked = util::read_lock(lock_path)
+        .ok()
+        .and_then(|l| l.package)
+        .unwrap_or_default();
+
+    if let Some(path) = sync_to {
+        if fs::write(path, requirements_txt(&locked)).is_err() {
+            abort(&format!("Problem writing {}", path.display()));
+        }
+        print_color(
+            &format!("Wrote {} from `pyflow.lock`", path.display()),
+            Color::Green,
+        );
+    }
+
+    let path = match compare {
+        Some(p) => p,
+        None => return,
+    };
+    let is_pipfile = path.file_name().is_some_and(|f| f == "Pipfile");
+    let drift = if is_pipfile {
+        let pipfile = Config::from_pipfile(path)
+            .unwrap_or_else(|| abort(&format!("Problem reading {}", path.display())));
+        [
+            find_drift(&cfg.reqs, &pipfile.reqs, &locked),
+            find_drift(&cfg.dev_reqs, &pipfile.dev_reqs, &locked),
+        ]
+        .concat()
+    } else {
+        let data = fs::read_to_string(path)
+            .unwrap_or_else(|_| abort(&format!("Problem reading {}", path.display())));
+        find_drift(
+            &[cfg.reqs.as_slice(), cfg.dev_reqs.as_slice()].concat(),
+            &read_requirements(&data),
+            &locked,
+        )
+    };
+
+    let other = path.display();
+    if drift.is_empty() {
+        print_color(
+            &format!("`pyproject.toml` and {} agree", other),
+            Color::Green,
+        );
+        return;
+    }
+    print_color(
+        &format!("`pyproject.toml` and {} have drifted apart:", other),
+        Color::Yellow,
+    );
+    for d in &drift {
+        let message = match d {
+            Drift::OnlyInConfig(name) => format!("{}: only in `pyproject.toml`", name),
+            Drift::OnlyInOther(name) => format!("{}: only in {}", name, other),
+            Drift::Disjoint(name, ours, theirs) => format!(
+                "{}: `{}` in `pyproject.toml`, but `{}` in {}; no version satisfies both",
+                name, ours, theirs, other
+            ),
+        };
+        println!("  {}", message);
+    }
+    util::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn req(s: &str) -> Req {
+        Req::from_pip_str(s).unwrap()
+    }
+
+    fn lock_pack(name: &str, version: &str, marker: Option<&str>) -> LockPackage {
+        LockPackage {
+            id: 0,
+            name: name.to_owned(),
+            version: version.to_owned(),
+            source: None,
+            dependencies: None,
+            rename: None,
+            marker: marker.map(str::to_owned),
+            modules: None,
+            files: None,
+            extras: None,
+            wheel: None,
+        }
+    }
+
+    #[test]
+    fn requirements_parsed_without_options_or_comments() {
+        let data = "# Legacy\n-r base.txt\nrequests>=2.28  # http\n\nnumpy==1.26.0 \\\n    \
+                    --hash=sha256:abc\n";
+        let names: Vec<String> = read_requirements(data)
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+        assert_eq!(names, ["requests", "numpy"]);
+    }
+
+    #[test]
+    fn drift_found() {
+        let config = [req("requests>=2.28"), req("numpy<2"), req("black")];
+        let other = [
+            req("Requests>=2.30"),
+            req("numpy>=2.0"),
+            req("urllib3==2.0.7"),
+            req("flask"),
+        ];
+        let locked = [lock_pack("urllib3", "2.0.7", None)];
+        assert_eq!(
+            find_drift(&config, &other, &locked),
+            vec![
+                Drift::Disjoint("numpy".into(), "<2".into(), ">=2.0".into()),
+                Drift::OnlyInConfig("black".into()),
+                Drift::OnlyInOther("flask".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn synced_requirements_match() {
+        let locked = [
+            lock_pack("requests", "2.31.0", None),
+            lock_pack("urllib3", "2.0.7", None),
+            lock_pack("colorama", "0.4.6", Some("sys_platform == \"win32\"")),
+        ];
+        let data = requirements_txt(&locked);
+        assert!(data.contains("colorama==0.4.6 ; sys_platform == \"win32\"\n"));
+        let synced = read_requirements(&data);
+        assert_eq!(synced.len(), 3);
+        assert!(find_drift(&[req("requests>=2.28")], &synced, &locked).is_empty());
+    }
+}
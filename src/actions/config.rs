@@ -0,0 +1,366 @@
+use std::{fs, path::Path, str::FromStr};
+
+use termcolor::Color;
+
+use crate::{
+    dep_types::Version,
+    files,
+    pyproject::{
+        settings::{self, Overrides, Settings},
+        validate,
+    },
+    util::{abort, print_color},
+    CliConfig,
+};
+
+/// What a key under `[tool.pyflow]` holds, so values from the command line are written with the
+/// right type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    /// eg `py_version = "3.11"`
+    Version,
+    Str,
+    Bool,
+    Int,
+    /// eg `authors = ["Jane <jane@example.com>"]`, from one argument per item.
+    List,
+}
+
+/// The kind of value at `key`, eg `settings.index_url`. `None` for keys pyflow doesn't know, and
+/// for tables, which are set a key at a time.
+fn kind(key: &[String]) -> Option<Kind> {
+    let key: Vec<&str> = key.iter().map(String::as_str).collect();
+    Some(match key.as_slice() {
+        ["py_version" | "version"] => Kind::Version,
+        ["py_interpreter" | "name" | "license" | "description" | "homepage" | "repository"
+        | "repo_url" | "package_url" | "readme" | "build" | "python_requires"
+        | "deps_service_url" | "resolution"] => Kind::Str,
+        ["no_scripts" | "load_dotenv" | "check_shadowing" | "frozen"] => Kind::Bool,
+        ["build_timeout"] => Kind::Int,
+        ["authors" | "classifiers" | "keywords" | "find_links" | "preserve"] => Kind::List,
+        ["scripts" | "dependencies" | "dev-dependencies" | "build_tools" | "hooks", _] => Kind::Str,
+        ["extras", _] => Kind::List,
+        ["settings", "index_url"] => Kind::Str,
+        ["settings", k] if settings::KEYS.contains(k) => Kind::Bool,
+        _ => return None,
+    })
+}
+
+/// Tables under `[tool.pyflow]`, which can be removed whole.
+const TABLES: &[&str] = &[
+    "scripts",
+    "dependencies",
+    "dev-dependencies",
+    "extras",
+    "build_tools",
+    "hooks",
+    "settings",
+];
+
+/// A value given on the command line, as `kind`.
+fn parse_value(kind: Kind, values: &[String]) -> Result<toml::Value, String> {
+    let single = || match values {
+        [v] => Ok(v.as_str()),
+        _ => Err(format!("Expected one value, but got {}", values.len())),
+    };
+    Ok(match kind {
+        Kind::Version => {
+            let v = single()?;
+            Version::from_str(&v.replace(' ', "")).map_err(|_| {
+                format!(
+                    "\"{}\" isn't a version. It should look like this: 3.11 or 3.11.4",
+                    v
+                )
+            })?;
+            toml::Value::String(v.to_owned())
+        }
+        Kind::Str => toml::Value::String(single()?.to_owned()),
+        Kind::Bool => match single()?.to_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => toml::Value::Boolean(true),
+            "false" | "no" | "off" | "0" => toml::Value::Boolean(false),
+            v => return Err(format!("\"{}\" isn't `true` or `false`", v)),
+        },
+        Kind::Int => {
+            let v = single()?;
+            toml::Value::Integer(
+                v.parse::<u32>()
+                    .map_err(|_| format!("\"{}\" isn't a whole number", v))?
+                    .into(),
+            )
+        }
+        Kind::List => match values {
+            // Eg `'["a", "b"]'`
+            [v] if v.trim_start().starts_with('[') => raw_value(v),
+            _ => toml::Value::Array(values.iter().cloned().map(toml::Value::String).collect()),
+        },
+    })
+}
+
+/// A value for `--raw`: TOML if it parses, eg `["a", "b"]` or `3`, otherwise a string.
+fn raw_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("v = {}", value))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| toml::Value::String(value.to_owned()))
+}
+
+fn split(key: &str) -> Vec<String> {
+    files::split_key(key).unwrap_or_else(|| abort(&format!("`{}` isn't a valid key", key)))
+}
+
+fn read(cfg_path: &Path) -> String {
+    fs::read_to_string(cfg_path)
+        .unwrap_or_else(|_| abort(&format!("Problem reading {}", cfg_path.display())))
+}
+
+/// The problems `validate` finds, as `key: message`, so ones a change adds can be found.
+fn problems(cfg_data: &str) -> Vec<String> {
+    let validation = validate::validate(cfg_data);
+    validation
+        .parse_error
+        .iter()
+        .chain(&validation.errors)
+        .map(|p| format!("`{}`: {}", p.key, p.message))
+        .collect()
+}
+
+/// Change `cfg_data`, with `value` set at `key`, or removed if `None`. Unless `raw`, the key must
+/// be one pyflow knows, and the changed file must not have problems the original didn't.
+fn edit(
+    cfg_data: &str,
+    key: &[String],
+    values: Option<&[String]>,
+    raw: bool,
+) -> Result<String, String> {
+    let dotted = key.join(".");
+    let kind = kind(key);
+    let table = values.is_none() && key.len() == 1 && TABLES.contains(&key[0].as_str());
+    if kind.is_none() && !table && !raw {
+        return Err(format!(
+            "`{}` isn't a key pyflow knows, under `[tool.pyflow]`. \
+             Pass `--raw` to change it anyway",
+            dotted
+        ));
+    }
+
+    let edited = match values {
+        Some(values) => {
+            let value = match kind {
+                Some(kind) if !raw => parse_value(kind, values)
+                    .map_err(|e| format!("Can't set `{}`: {}", dotted, e))?,
+                _ => raw_value(&values.join(" ")),
+            };
+            files::set_cfg_value(cfg_data, key, &value)?
+        }
+        None => files::unset_cfg_value(cfg_data, key)
+            .ok_or_else(|| format!("`{}` isn't set in `[tool.pyflow]`", dotted))?,
+    };
+
+    if !raw {
+        let before = problems(cfg_data);
+        let added: Vec<String> = problems(&edited)
+            .into_iter()
+            .filter(|p| !before.contains(p))
+            .collect();
+        if !added.is_empty() {
+            return Err(format!(
+                "Not changing `{}`, since it would make `pyproject.toml` invalid:\n  {}",
+                dotted,
+                added.join("\n  ")
+            ));
+        }
+    }
+    Ok(edited)
+}
+
+/// Print the value at `key` under `[tool.pyflow]`, eg `scripts.test`, or with no key, the
+/// section, with the settings in effect.
+pub fn config_get(cfg_path: &Path, key: Option<&str>, overrides: &Overrides) {
+    let data: toml::Table = toml::from_str(&read(cfg_path))
+        .unwrap_or_else(|e| abort(&format!("Problem parsing {}: {}", cfg_path.display(), e)));
+    let mut pyflow = data
+        .get("tool")
+        .and_then(|t| t.get("pyflow"))
+        .cloned()
+        .unwrap_or_else(|| toml::Value::Table(toml::Table::new()));
+    let json = CliConfig::current().json;
+
+    if let Some(key) = key {
+        let value = split(key)
+            .iter()
+            .try_fold(&pyflow, |value, part| value.get(part))
+            .unwrap_or_else(|| abort(&format!("`{}` isn't set in `[tool.pyflow]`", key)));
+        match value {
+            _ if json => println!(
+                "{}",
+                serde_json::to_string_pretty(value).expect("Problem serializing the value")
+            ),
+            toml::Value::String(s) => println!("{}", s),
+            toml::Value::Table(t) => print!("{}", toml::to_string(t).unwrap_or_default()),
+            v => println!("{}", v),
+        }
+        return;
+    }
+
+    let effective = Settings::from_file(cfg_path).map(|s| settings::resolve(&s, overrides));
+    if json {
+        if let (toml::Value::Table(t), Some(effective)) = (&mut pyflow, &effective) {
+            t.insert(
+                "settings".into(),
+                toml::Value::try_from(effective).expect("Problem serializing the settings"),
+            );
+        }
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&pyflow).expect("Problem serializing the section")
+        );
+        return;
+    }
+
+    if let toml::Value::Table(t) = &mut pyflow {
+        t.remove("settings");
+    }
+    let mut wrapped = toml::Table::new();
+    let mut tool = toml::Table::new();
+    tool.insert("pyflow".into(), pyflow);
+    wrapped.insert("tool".into(), toml::Value::Table(tool));
+    print!("{}", toml::to_string(&wrapped).unwrap_or_default());
+
+    if let Some(effective) = effective {
+        println!();
+        println!("# Settings in effect, and where each came from");
+        println!("[tool.pyflow.settings]");
+        for (key, value, source) in effective.entries() {
+            println!("{} = {}  # {}", key, value, source);
+        }
+    }
+}
+
+/// Set the value at `key` under `[tool.pyflow]`, eg `pyflow config set py_version 3.11`,
+/// keeping the rest of `pyproject.toml` as written.
+pub fn config_set(cfg_path: &Path, key: &str, values: &[String], raw: bool) {
+    let key_parts = split(key);
+    let edited = edit(&read(cfg_path), &key_parts, Some(values), raw).unwrap_or_else(|e| abort(&e));
+    files::write_cfg(cfg_path, &edited, &format!("set {} in [tool.pyflow]", key));
+    print_color(&format!("Set `{}`", key), Color::Green);
+}
+
+/// Remove the value, or table, at `key` under `[tool.pyflow]`.
+pub fn config_unset(cfg_path: &Path, key: &str, raw: bool) {
+    let key_parts = split(key);
+    let edited = edit(&read(cfg_path), &key_parts, None, raw).unwrap_or_else(|e| abort(&e));
+    files::write_cfg(
+        cfg_path,
+        &edited,
+        &format!("remove {} from [tool.pyflow]", key),
+    );
+    print_color(&format!("Removed `{}`", key), Color::Green);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CFG: &str = r#"# Managed with pyflow
+[tool.pyflow]
+name = "proj"
+py_version = "3.10"   # Matches CI
+authors = [
+    "Jane <jane@example.com>",  # Maintainer
+]
+
+[tool.pyflow.scripts]
+test = "pytest -q"
+
+[tool.pyflow.settings]
+require_hashes = true
+
+[tool.pyflow.dependencies]
+requests = "^2.31"  # Pinned for the proxy
+"#;
+
+    fn key(k: &str) -> Vec<String> {
+        files::split_key(k).unwrap()
+    }
+
+    fn set(cfg: &str, k: &str, values: &[&str]) -> Result<String, String> {
+        let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        edit(cfg, &key(k), Some(&values), false)
+    }
+
+    #[test]
+    fn edits_keep_comments_and_formatting() {
+        let edited = set(CFG, "py_version", &["3.11"]).unwrap();
+        assert_eq!(
+            edited,
+            CFG.replace(
+                "py_version = \"3.10\"   # Matches CI",
+                "py_version = \"3.11\"   # Matches CI"
+            )
+        );
+
+        let edited = set(
+            CFG,
+            "settings.index_url",
+            &["https://mirror.example.com/pypi"],
+        )
+        .unwrap();
+        assert!(edited.contains(
+            "require_hashes = true\nindex_url = \"https://mirror.example.com/pypi\"\n\n\
+             [tool.pyflow.dependencies]"
+        ));
+        assert!(edited.contains("requests = \"^2.31\"  # Pinned for the proxy"));
+
+        // A multi-line value is replaced whole.
+        let edited = set(CFG, "authors", &["Ann <ann@example.com>", "Bo"]).unwrap();
+        assert!(edited.contains("authors = [\"Ann <ann@example.com>\", \"Bo\"]\n\n"));
+        assert!(!edited.contains("Maintainer"));
+
+        // Tables that don't exist yet are added.
+        let edited = set(CFG, "extras.cli", &["click"]).unwrap();
+        assert!(edited.ends_with("\n[tool.pyflow.extras]\ncli = [\"click\"]\n"));
+
+        // Unsetting, then setting again, round-trips.
+        let unset = edit(CFG, &key("scripts.test"), None, false).unwrap();
+        assert!(!unset.contains("pytest"));
+        assert_eq!(set(&unset, "scripts.test", &["pytest -q"]).unwrap(), CFG);
+        assert!(edit(CFG, &key("settings.allow_prereleases"), None, false).is_err());
+        let unset = edit(CFG, &key("settings"), None, false).unwrap();
+        assert!(!unset.contains("require_hashes"));
+    }
+
+    #[test]
+    fn invalid_values_rejected() {
+        let err = set(CFG, "py_version", &["three"]).unwrap_err();
+        assert!(err.contains("isn't a version"));
+        assert!(set(CFG, "settings.require_hashes", &["maybe"]).is_err());
+        assert!(set(CFG, "build_timeout", &["-5"]).is_err());
+        assert!(set(CFG, "name", &["a", "b"]).is_err());
+        // Checked once it's in place, like `pyflow check --config`.
+        let err = set(CFG, "dependencies.numpy", &["~~1.2"]).unwrap_err();
+        assert!(err.contains("tool.pyflow.dependencies.numpy"));
+
+        let err = set(CFG, "py_verison", &["3.11"]).unwrap_err();
+        assert!(err.contains("--raw"));
+        let raw = edit(CFG, &key("custom.level"), Some(&["3".to_owned()]), true).unwrap();
+        assert!(raw.ends_with("[tool.pyflow.custom]\nlevel = 3\n"));
+
+        // A table is set a key at a time.
+        assert!(edit(CFG, &key("scripts"), Some(&["x".to_owned()]), true)
+            .unwrap_err()
+            .contains("is a table"));
+    }
+
+    #[test]
+    fn scalar_keys_known() {
+        for key in ["py_version", "name", "authors", "frozen", "build_timeout"] {
+            assert!(kind(&[key.to_owned()]).is_some());
+        }
+        assert_eq!(
+            kind(&["settings".into(), "allow_prereleases".into()]),
+            Some(Kind::Bool)
+        );
+        assert_eq!(kind(&["settings".into(), "bogus".into()]), None);
+        assert_eq!(kind(&["dependencies".into()]), None);
+    }
+}
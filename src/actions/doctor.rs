@@ -0,0 +1,612 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use termcolor::Color;
+
+use crate::{
+    commands,
+    dep_types::{LockPackage, Marker, Req, Version},
+    pyproject::{
+        self,
+        settings::{self, Overrides},
+        validate, Config, LOCK_FILENAME,
+    },
+    util::{self, abort, lock_header::Header, print_color, print_color_, Os},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// The result of one check. Failures and warnings include the command that fixes them.
+struct Check {
+    name: &'static str,
+    status: Status,
+    details: String,
+    fix: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, details: &str) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            details: details.to_owned(),
+            fix: None,
+        }
+    }
+
+    fn problem(name: &'static str, status: Status, details: &str, fix: &str) -> Self {
+        Self {
+            name,
+            status,
+            details: details.to_owned(),
+            fix: Some(fix.to_owned()),
+        }
+    }
+
+    fn print(&self) {
+        let (label, color) = match self.status {
+            Status::Pass => ("pass", Color::Green),
+            Status::Warn => ("warn", Color::Yellow),
+            Status::Fail => ("fail", Color::Red),
+        };
+        print_color_(label, color);
+        println!(" {}: {}", self.name, self.details);
+        if let Some(fix) = &self.fix {
+            println!("     Fix: {}", fix);
+        }
+    }
+}
+
+/// Check for common problems with the environment, and print a fix for each one found.
+/// Exits with an error if any check fails, so this can be used in CI.
+pub fn doctor(dep_cache_path: &Path, os: Os, overrides: &Overrides) {
+    let mut checks = vec![];
+
+    let config_path = pyproject::current::find_config_path();
+    let cfg = match &config_path {
+        Some(path) => {
+            let (check, cfg) = check_config(path);
+            checks.push(check);
+            cfg
+        }
+        None => {
+            checks.push(Check::problem(
+                "pyproject.toml",
+                Status::Fail,
+                "Can't find `pyproject.toml` in this directory or its parents",
+                "pyflow init",
+            ));
+            None
+        }
+    };
+
+    if let Some(cfg) = &cfg {
+        checks.push(check_settings(&settings::resolve(&cfg.settings, overrides)));
+    }
+
+    if let (Some(cfg), Some(config_path)) = (&cfg, &config_path) {
+        let project_path = config_path.parent().unwrap_or_else(|| Path::new("."));
+        let vers_path = find_vers_path(&project_path.join("__pypackages__"), cfg);
+        match &vers_path {
+            Some((vers_path, py_vers)) => {
+                checks.push(check_python(vers_path, py_vers));
+                checks.push(check_scripts(&util::find_bin_path(vers_path)));
+                checks.push(check_lock(
+                    &project_path.join(LOCK_FILENAME),
+                    cfg,
+                    os,
+                    py_vers,
+                ));
+                checks.push(check_wheel(&vers_path.join("lib")));
+                checks.push(check_shadowing(vers_path, project_path, cfg));
+            }
+            None => checks.push(Check::problem(
+                "environment",
+                Status::Warn,
+                "No environment has been created for this project yet",
+                "pyflow install",
+            )),
+        }
+    }
+
+    checks.push(check_cache(dep_cache_path));
+    checks.push(check_network());
+    checks.push(check_compiler(os));
+    checks.extend(conflicting_tools(|var| env::var(var).ok()));
+
+    for check in &checks {
+        check.print();
+    }
+
+    let failed = checks.iter().filter(|c| c.status == Status::Fail).count();
+    if failed > 0 {
+        abort(&format!("{} checks failed", failed));
+    }
+    print_color("No problems found that prevent using pyflow", Color::Green);
+}
+
+/// Check that `pyproject.toml` parses, and its constraints are valid. Returns the config,
+/// if we can use it for the remaining checks.
+fn check_config(path: &Path) -> (Check, Option<Config>) {
+    let name = "pyproject.toml";
+    let fix = format!("Edit {}", path.to_string_lossy());
+    let data = match fs::read_to_string(path) {
+        Ok(d) => d,
+        Err(e) => {
+            return (
+                Check::problem(name, Status::Fail, &format!("Can't read it: {}", e), &fix),
+                None,
+            )
+        }
+    };
+    match invalid_constraints(&data) {
+        Err(e) => (
+            Check::problem(name, Status::Fail, &format!("Can't parse it: {}", e), &fix),
+            None,
+        ),
+        Ok(invalid) if !invalid.is_empty() => (
+            Check::problem(
+                name,
+                Status::Fail,
+                &format!("Invalid constraints: {}", invalid.join(", ")),
+                &fix,
+            ),
+            None,
+        ),
+        Ok(_) => (
+            Check::pass(name, "Parses, and its constraints are valid"),
+            Config::from_file(path),
+        ),
+    }
+}
+
+/// Find entries in `pyproject.toml` whose constraints or versions we can't parse.
+fn invalid_constraints(toml_str: &str) -> Result<Vec<String>, String> {
+    let validation = validate::validate(toml_str);
+    if let Some(e) = validation.parse_error {
+        return Err(e.to_string());
+    }
+    let mut invalid: Vec<String> = validation.errors.into_iter().map(|e| e.name).collect();
+    invalid.sort();
+    invalid.dedup();
+    Ok(invalid)
+}
+
+/// Find the environment for the project's Python version. We look for its folder, rather
+/// than a working interpreter, since `check_python` reports on that.
+fn find_vers_path(pypackages_path: &Path, cfg: &Config) -> Option<(PathBuf, Version)> {
+    let (major, minor) = match cfg.py_version.as_ref().map(|v| &v.parsed) {
+        Some(Version {
+            major: Some(ma),
+            minor: Some(mi),
+            ..
+        }) => (*ma, *mi),
+        _ => *util::find_venvs(pypackages_path).first()?,
+    };
+    let vers_path = pypackages_path.join(format!("{}.{}", major, minor));
+    if !vers_path.join(".venv").exists() {
+        return None;
+    }
+    Some((vers_path, Version::new_short(major, minor)))
+}
+
+fn check_python(vers_path: &Path, py_vers: &Version) -> Check {
+    let name = "environment Python";
+    let fix = "pyflow reset && pyflow install";
+    let bin_path = util::find_bin_path(vers_path);
+    #[cfg(target_os = "windows")]
+    let python = bin_path.join("python.exe");
+    #[cfg(not(target_os = "windows"))]
+    let python = bin_path.join("python");
+
+    if fs::symlink_metadata(&python).is_ok() && !python.exists() {
+        return Check::problem(
+            name,
+            Status::Fail,
+            &format!(
+                "{} is a broken link. This usually happens after the system Python is upgraded",
+                python.to_string_lossy()
+            ),
+            fix,
+        );
+    }
+
+    match commands::find_py_version(&python.to_string_lossy()) {
+        Some(v) if v.major == py_vers.major && v.minor == py_vers.minor => {
+            Check::pass(name, &format!("Python {} runs", v))
+        }
+        Some(v) => Check::problem(
+            name,
+            Status::Fail,
+            &format!(
+                "The environment for Python {} runs Python {}",
+                py_vers.to_string_med(),
+                v
+            ),
+            fix,
+        ),
+        None => Check::problem(
+            name,
+            Status::Fail,
+            &format!("Can't run {}", python.to_string_lossy()),
+            fix,
+        ),
+    }
+}
+
+/// Check that scripts in the environment's `bin` folder, like `pip`, use an interpreter that
+/// exists. They break if the project folder is moved.
+fn check_scripts(bin_path: &Path) -> Check {
+    let name = "environment scripts";
+    let broken = broken_shebangs(bin_path);
+    if broken.is_empty() {
+        Check::pass(name, "Scripts use an interpreter that exists")
+    } else {
+        Check::problem(
+            name,
+            Status::Warn,
+            &format!(
+                "These scripts use an interpreter that doesn't exist: {}",
+                broken.join(", ")
+            ),
+            "pyflow reset && pyflow install",
+        )
+    }
+}
+
+fn broken_shebangs(bin_path: &Path) -> Vec<String> {
+    let mut broken = vec![];
+    for entry in fs::read_dir(bin_path).into_iter().flatten().flatten() {
+        let data = match fs::read(entry.path()) {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+        if !data.starts_with(b"#!") {
+            continue;
+        }
+        let first_line = data.split(|b| *b == b'\n').next().unwrap_or_default();
+        let shebang = String::from_utf8_lossy(&first_line[2..]);
+        let interpreter = shebang.split_whitespace().next().unwrap_or_default();
+        if Path::new(interpreter).is_absolute() && !Path::new(interpreter).exists() {
+            broken.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    broken.sort();
+    broken
+}
+
+fn check_lock(lock_path: &Path, cfg: &Config, os: Os, py_vers: &Version) -> Check {
+    let name = "pyflow.lock";
+    let lock = match util::read_lock(lock_path) {
+        Ok(l) => l,
+        Err(_) => {
+            return Check::problem(
+                name,
+                Status::Warn,
+                "There's no lock file, or it can't be read",
+                "pyflow install",
+            )
+        }
+    };
+    let reqs = [cfg.reqs.as_slice(), cfg.dev_reqs.as_slice()].concat();
+    let missing = missing_from_lock(&reqs, &lock.package.unwrap_or_default(), os, py_vers);
+    if missing.is_empty() && Header::from_lock(&lock.metadata).is_stale(lock_path) {
+        Check::problem(
+            name,
+            Status::Warn,
+            "`pyproject.toml`'s dependencies changed since it was resolved",
+            "pyflow install",
+        )
+    } else if missing.is_empty() {
+        Check::pass(name, "Consistent with `pyproject.toml`")
+    } else {
+        Check::problem(
+            name,
+            Status::Fail,
+            &format!(
+                "No locked version satisfies these requirements: {}",
+                missing.join(", ")
+            ),
+            "pyflow install",
+        )
+    }
+}
+
+/// Find requirements that no locked package satisfies. Path and git requirements aren't
+/// locked, and we skip ones whose markers don't apply here.
+pub(super) fn missing_from_lock(
+    reqs: &[Req],
+    lock_packs: &[LockPackage],
+    os: Os,
+    py_vers: &Version,
+) -> Vec<String> {
+    reqs.iter()
+        .filter(|r| r.path.is_none() && r.git.is_none())
+        .filter(|r| r.marker.as_ref().is_none_or(|m| m.evaluate(os, py_vers)))
+        .filter(|r| {
+            !lock_packs.iter().any(|lp| {
+                util::compare_names(&lp.name, &r.name)
+                    && Version::from_str(&lp.version)
+                        .is_ok_and(|v| r.constraints.iter().all(|c| c.is_compatible(&v)))
+                    && lp
+                        .marker
+                        .as_ref()
+                        .and_then(|m| Marker::from_str(m).ok())
+                        .is_none_or(|m| m.evaluate(os, py_vers))
+            })
+        })
+        .map(|r| r.name.clone())
+        .collect()
+}
+
+fn check_wheel(lib_path: &Path) -> Check {
+    let name = "wheel";
+    if util::find_installed(lib_path)
+        .iter()
+        .any(|(n, _, _)| util::compare_names(n, "wheel"))
+    {
+        Check::pass(name, "Installed, for building packages from source")
+    } else {
+        Check::problem(
+            name,
+            Status::Warn,
+            "Not installed, so packages can't be built from source",
+            "pyflow reset && pyflow install",
+        )
+    }
+}
+
+/// Check that the project's dependencies import from the environment, and not a copy elsewhere,
+/// eg in the system `site-packages`.
+fn check_shadowing(vers_path: &Path, project_path: &Path, cfg: &Config) -> Check {
+    let name = "shadowed packages";
+    let mut project_paths = vec![vers_path.join("lib")];
+    project_paths.extend(
+        cfg.reqs
+            .iter()
+            .chain(&cfg.dev_reqs)
+            .filter_map(|r| r.path.as_ref())
+            .map(|p| project_path.join(p)),
+    );
+    let python = util::find_bin_path(vers_path).join("python");
+    let shadowed = util::shadowing::find(&python, project_path, &project_paths, None);
+    if shadowed.is_empty() {
+        return Check::pass(name, "Dependencies import from the environment");
+    }
+    let details: Vec<String> = shadowed.iter().map(|s| s.describe()).collect();
+    Check::problem(
+        name,
+        Status::Warn,
+        &details.join("; "),
+        "Remove the other copies, eg with the global `pip uninstall`, or rename the folders",
+    )
+}
+
+/// Show the settings in effect, and where each came from, since they change what installing
+/// does.
+fn check_settings(effective: &settings::Effective) -> Check {
+    let name = "settings";
+    let details: Vec<String> = effective
+        .entries()
+        .iter()
+        .map(|(key, value, source)| format!("{} = {} ({})", key, value, source))
+        .collect();
+    let url = &effective.index_url.value;
+    if !url.starts_with("https://") && !url.starts_with("http://") {
+        return Check::problem(
+            name,
+            Status::Fail,
+            &details.join(", "),
+            "Set `index_url` to the URL of the index's JSON API, eg https://pypi.org/pypi",
+        );
+    }
+    Check::pass(name, &details.join(", "))
+}
+
+fn check_cache(dep_cache_path: &Path) -> Check {
+    let name = "cache";
+    let test_file = dep_cache_path.join(".pyflow_doctor");
+    let writable = fs::create_dir_all(dep_cache_path).is_ok()
+        && fs::write(&test_file, "").is_ok()
+        && fs::remove_file(&test_file).is_ok();
+    if writable {
+        Check::pass(
+            name,
+            &format!("{} is writable", dep_cache_path.to_string_lossy()),
+        )
+    } else {
+        Check::problem(
+            name,
+            Status::Fail,
+            &format!("Can't write to {}", dep_cache_path.to_string_lossy()),
+            &format!("chmod -R u+w \"{}\"", dep_cache_path.to_string_lossy()),
+        )
+    }
+}
+
+fn check_network() -> Check {
+    let name = "network";
+    let url = "https://pypi.org/simple/";
+    match util::http::client().head(url).send() {
+        Ok(resp) if resp.status().is_success() => Check::pass(name, "Can reach pypi.org"),
+        Ok(resp) => Check::problem(
+            name,
+            Status::Warn,
+            &format!("pypi.org responded with status {}", resp.status()),
+            &format!("curl -I {}", url),
+        ),
+        Err(e) => Check::problem(
+            name,
+            Status::Fail,
+            &util::http::describe(&e),
+            &format!("curl -I {}", url),
+        ),
+    }
+}
+
+/// Some packages are only published as source, and need a C compiler to build.
+fn check_compiler(os: Os) -> Check {
+    let name = "C compiler";
+    let (compilers, fix): (&[&str], &str) = match os {
+        Os::Windows | Os::Windows32 => (
+            &["cl"],
+            "Install the Visual Studio Build Tools: \
+             https://visualstudio.microsoft.com/visual-cpp-build-tools/",
+        ),
+        Os::Mac => (&["cc", "clang"], "xcode-select --install"),
+        _ => (
+            &["cc", "gcc", "clang"],
+            "sudo apt install build-essential (or your distro's equivalent)",
+        ),
+    };
+    match compilers
+        .iter()
+        .find(|c| Command::new(c).arg("--version").output().is_ok())
+    {
+        Some(c) => Check::pass(name, &format!("Found `{}`", c)),
+        None => Check::problem(
+            name,
+            Status::Warn,
+            "None found, so packages with C extensions can't be built from source",
+            fix,
+        ),
+    }
+}
+
+/// Other tools' environments change which Python and packages are used.
+fn conflicting_tools(get_var: impl Fn(&str) -> Option<String>) -> Vec<Check> {
+    let mut checks = vec![];
+    if let Some(venv) = get_var("VIRTUAL_ENV") {
+        checks.push(Check::problem(
+            "virtualenv",
+            Status::Warn,
+            &format!("A virtual environment is active: {}", venv),
+            "deactivate",
+        ));
+    }
+    if let Some(env) = get_var("CONDA_DEFAULT_ENV").or_else(|| get_var("CONDA_PREFIX")) {
+        checks.push(Check::problem(
+            "conda",
+            Status::Warn,
+            &format!("A conda environment is active: {}", env),
+            "conda deactivate",
+        ));
+    }
+    if get_var("PYTHONHOME").is_some() {
+        checks.push(Check::problem(
+            "PYTHONHOME",
+            Status::Warn,
+            "`PYTHONHOME` is set, which overrides the environment's Python",
+            "unset PYTHONHOME",
+        ));
+    }
+    if checks.is_empty() {
+        checks.push(Check::pass(
+            "other tools",
+            "No active virtualenv or conda environment",
+        ));
+    }
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constraints_validated() {
+        let cfg = r#"
+[tool.pyflow]
+py_version = "3.9"
+
+[tool.pyflow.dependencies]
+requests = "^2.31"
+numpy = "not a version"
+attrs = { version = ">=23", python = ">=3.7" }
+
+[tool.pyflow.dev-dependencies]
+pytest = { version = "~~7" }
+"#;
+        assert_eq!(invalid_constraints(cfg).unwrap(), vec!["numpy", "pytest"]);
+        assert!(invalid_constraints("[tool.pyflow").is_err());
+    }
+
+    #[test]
+    fn lock_consistency() {
+        let lock_pack = |name: &str, version: &str| LockPackage {
+            id: 1,
+            name: name.to_owned(),
+            version: version.to_owned(),
+            source: None,
+            dependencies: None,
+            rename: None,
+            marker: None,
+            modules: None,
+            files: None,
+            extras: None,
+            wheel: None,
+        };
+        let lock_packs = vec![
+            lock_pack("requests", "2.31.0"),
+            lock_pack("numpy", "1.24.0"),
+        ];
+        let reqs = vec![
+            Req::from_cfg_value("requests", "^2.28").unwrap(),
+            Req::from_cfg_value("numpy", ">=1.25").unwrap(),
+            Req::from_cfg_value("attrs", "*").unwrap(),
+            Req::from_cfg_value("pywin32", "* ; sys_platform == 'win32'").unwrap(),
+        ];
+
+        assert_eq!(
+            missing_from_lock(&reqs, &lock_packs, Os::Linux, &Version::new_short(3, 9)),
+            vec!["numpy", "attrs"]
+        );
+    }
+
+    #[test]
+    fn conflicting_environments() {
+        let checks = conflicting_tools(|var| match var {
+            "VIRTUAL_ENV" => Some("/home/user/.venv".to_owned()),
+            "CONDA_PREFIX" => Some("/opt/conda".to_owned()),
+            _ => None,
+        });
+        let fixes: Vec<_> = checks.iter().filter_map(|c| c.fix.clone()).collect();
+        assert_eq!(fixes, vec!["deactivate", "conda deactivate"]);
+
+        let checks = conflicting_tools(|_| None);
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].status, Status::Pass);
+    }
+
+    #[test]
+    fn settings_shown_with_sources() {
+        let overrides = Overrides {
+            require_hashes: Some(true),
+            ..Overrides::default()
+        };
+        let check = check_settings(&settings::resolve(&Default::default(), &overrides));
+        assert_eq!(check.status, Status::Pass);
+        assert!(check
+            .details
+            .contains("require_hashes = true (command line)"));
+        assert!(check
+            .details
+            .contains("install_dev_by_default = true (default)"));
+
+        let cfg = settings::Settings {
+            index_url: Some("pypi.example.com".into()),
+            ..Default::default()
+        };
+        let check = check_settings(&settings::resolve(&cfg, &Overrides::default()));
+        assert_eq!(check.status, Status::Fail);
+    }
+}
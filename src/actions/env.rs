@@ -0,0 +1,61 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    pyproject::{
+        self,
+        settings::{self, Effective, Overrides, Settings},
+    },
+    util::paths::{self, PyflowDirs},
+    CliConfig,
+};
+
+#[derive(Serialize)]
+struct Report {
+    dirs: BTreeMap<&'static str, String>,
+    /// The project's settings, after flags; absent outside a project.
+    settings: Option<Effective>,
+}
+
+/// Show where pyflow keeps packages, Python installations etc, in the format of its user
+/// config file. In a project, also show the settings in effect, and where each came from.
+pub fn env(dirs: &PyflowDirs, overrides: &Overrides) {
+    let effective = pyproject::current::find_config_path()
+        .and_then(|path| Settings::from_file(&path))
+        .map(|s| settings::resolve(&s, overrides));
+
+    if CliConfig::current().json {
+        let report = Report {
+            dirs: dirs
+                .entries()
+                .into_iter()
+                .map(|(key, path)| (key, path.to_string_lossy().into_owned()))
+                .collect(),
+            settings: effective,
+        };
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("Problem serializing the report")
+        );
+        return;
+    }
+
+    if let Some(path) = paths::user_config_path() {
+        println!(
+            "# Change these in {}, or with environment variables of the same name",
+            path.to_string_lossy()
+        );
+    }
+    for (key, path) in dirs.entries() {
+        println!("{} = {:?}", key, path.to_string_lossy());
+    }
+    if let Some(effective) = effective {
+        println!();
+        println!("# Settings in effect for this project");
+        println!("[tool.pyflow.settings]");
+        for (key, value, source) in effective.entries() {
+            println!("{} = {}  # {}", key, value, source);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Export the dependency graph in `pyflow.lock`, eg as DOT for Graphviz:
+//! `pyflow graph | dot -Tsvg > deps.svg`.
+
+use std::{fs, path::Path};
+
+use termcolor::Color;
+
+use crate::{
+    dep_types::LockPackage,
+    pyproject::Config,
+    util::{self, abort, dep_graph::DepGraph, print_color},
+};
+
+/// Which packages to include: the ones reachable from `package`, or from the project's
+/// requirements, in at most `depth` steps.
+pub struct GraphFilter<'a> {
+    pub package: Option<&'a str>,
+    pub depth: Option<usize>,
+}
+
+fn node_id(lp: &LockPackage) -> String {
+    format!("\"{}@{}\"", lp.name, lp.version)
+}
+
+/// The graph in DOT. Each node is a locked package; requirements of the project are bold, and
+/// ones only needed by `dev-dependencies` dashed. Edges run from a package to what it requires.
+pub fn dot(cfg: &Config, lockpacks: &[LockPackage], filter: &GraphFilter, urls: bool) -> String {
+    let graph = DepGraph::from_lock(lockpacks);
+    let runtime_names: Vec<&str> = cfg
+        .reqs
+        .iter()
+        .chain(cfg.extras.values().flatten())
+        .map(|r| r.name.as_str())
+        .collect();
+    let runtime = graph.reachable(&graph.named(runtime_names.iter().copied()), None);
+    let direct = graph.named(
+        runtime_names
+            .iter()
+            .copied()
+            .chain(cfg.dev_reqs.iter().map(|r| r.name.as_str())),
+    );
+
+    let starts = match filter.package {
+        Some(name) => {
+            let found = graph.named([name]);
+            if found.is_empty() {
+                abort(&format!("{} isn't in `pyflow.lock`", name));
+            }
+            found
+        }
+        None => direct.clone(),
+    };
+    let included = graph.reachable(&starts, filter.depth);
+
+    let mut order: Vec<usize> = (0..lockpacks.len())
+        .filter(|i| included[*i].is_some())
+        .collect();
+    order.sort_by_key(|i| {
+        (
+            lockpacks[*i].name.to_lowercase(),
+            lockpacks[*i].version.clone(),
+        )
+    });
+
+    let mut result = String::from("digraph dependencies {\n    node [shape=box];\n");
+    for &i in &order {
+        let lp = &lockpacks[i];
+        let mut attrs = vec![format!("label=\"{}\\n{}\"", lp.name, lp.version)];
+        let style: Vec<&str> = [
+            direct.contains(&i).then_some("bold"),
+            runtime[i].is_none().then_some("dashed"),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if !style.is_empty() {
+            attrs.push(format!("style=\"{}\"", style.join(",")));
+        }
+        if urls {
+            attrs.push(format!("URL=\"https://pypi.org/project/{}/\"", lp.name));
+        }
+        result.push_str(&format!("    {} [{}];\n", node_id(lp), attrs.join(", ")));
+    }
+    for &i in &order {
+        let mut deps: Vec<usize> = graph
+            .deps(i)
+            .iter()
+            .copied()
+            .filter(|j| included[*j].is_some())
+            .collect();
+        deps.sort_by_key(|j| order.iter().position(|o| o == j));
+        for j in deps {
+            result.push_str(&format!(
+                "    {} -> {};\n",
+                node_id(&lockpacks[i]),
+                node_id(&lockpacks[j])
+            ));
+        }
+    }
+    result.push_str("}\n");
+    result
+}
+
+/// Write the graph in `pyflow.lock` to `output`, or print it.
+pub fn graph(
+    cfg: &Config,
+    lock_path: &Path,
+    format: &str,
+    output: Option<&Path>,
+    filter: &GraphFilter,
+    urls: bool,
+) {
+    if format != "dot" {
+        abort(&format!("Unsupported graph format: {}", format));
+    }
+    let lock = match util::read_lock(lock_path) {
+        Ok(l) => l,
+        Err(_) => abort("Can't read `pyflow.lock`. Run `pyflow install` to create it."),
+    };
+    let data = dot(cfg, &lock.package.unwrap_or_default(), filter, urls);
+
+    match output {
+        Some(path) => {
+            if fs::write(path, data).is_err() {
+                abort(&format!("Problem writing {}", path.display()));
+            }
+            print_color(
+                &format!("Wrote the dependency graph to {}", path.display()),
+                Color::Green,
+            );
+        }
+        None => print!("{}", data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dep_types::{Lock, Req};
+
+    const LOCK: &str = r#"
+[metadata]
+
+[[package]]
+id = 1
+name = "requests"
+version = "2.31.0"
+dependencies = ["urllib3 2.0.7 pypi+https://pypi.org/pypi/urllib3/2.0.7/json", "idna 3.4 pypi+https://pypi.org/pypi/idna/3.4/json"]
+
+[[package]]
+id = 2
+name = "urllib3"
+version = "2.0.7"
+dependencies = []
+
+[[package]]
+id = 3
+name = "idna"
+version = "3.4"
+dependencies = []
+
+[[package]]
+id = 4
+name = "pytest"
+version = "7.4.3"
+dependencies = ["pluggy 1.3.0 pypi+https://pypi.org/pypi/pluggy/1.3.0/json", "idna 3.4 pypi+https://pypi.org/pypi/idna/3.4/json"]
+
+[[package]]
+id = 5
+name = "pluggy"
+version = "1.3.0"
+dependencies = []
+"#;
+
+    fn cfg() -> Config {
+        Config {
+            reqs: vec![Req::new("requests".into(), vec![])],
+            dev_reqs: vec![Req::new("pytest".into(), vec![])],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn golden_dot() {
+        let lock: Lock = toml::from_str(LOCK).unwrap();
+        let filter = GraphFilter {
+            package: None,
+            depth: None,
+        };
+        assert_eq!(
+            dot(&cfg(), &lock.package.unwrap(), &filter, true),
+            include_str!("testdata/graph.dot")
+        );
+    }
+
+    #[test]
+    fn graph_filtered() {
+        let lock: Lock = toml::from_str(LOCK).unwrap();
+        let lockpacks = lock.package.unwrap();
+        let filter = GraphFilter {
+            package: Some("pytest"),
+            depth: Some(1),
+        };
+        let data = dot(&cfg(), &lockpacks, &filter, false);
+        assert!(data.contains("\"pytest@7.4.3\" -> \"pluggy@1.3.0\";"));
+        assert!(!data.contains("requests") && !data.contains("URL="));
+
+        let filter = GraphFilter {
+            package: None,
+            depth: Some(0),
+        };
+        let data = dot(&cfg(), &lockpacks, &filter, false);
+        assert!(!data.contains("->") && !data.contains("urllib3"));
+    }
+}
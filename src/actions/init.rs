@@ -1,14 +1,16 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use termcolor::Color;
 
 use crate::{
-    files,
+    files, py_versions,
     pyproject::Config,
     util::{self, abort},
 };
 
-pub fn init(cfg_filename: &str) {
+/// Create `pyproject.toml`. `py` is a Python version, or the path of an interpreter to use;
+/// we ask for the version if it's not specified.
+pub fn init(cfg_filename: &str, py: Option<&str>) {
     let cfg_path = PathBuf::from(cfg_filename);
     if cfg_path.exists() {
         abort("pyproject.toml already exists - not overwriting.")
@@ -19,10 +21,23 @@ pub fn init(cfg_filename: &str) {
         false => Config::default(),
     };
 
-    cfg.py_version = Some(util::prompts::py_vers());
+    let (py_version, interpreter) = match py {
+        Some(py) => py_versions::parse_py_arg(py),
+        None => (
+            util::prompts::py_vers("Pass one with `--py`, eg `pyflow init --py 3.11`."),
+            None,
+        ),
+    };
+    py_versions::check_supported(&py_version)
+        .unwrap_or_else(|e| abort(&format!("{} Pick another, eg `pyflow init --py 3.11`.", e)));
+    cfg.py_version = Some(py_version.into());
+    cfg.py_interpreter = interpreter;
 
     files::parse_req_dot_text(&mut cfg, &PathBuf::from("requirements.txt"));
 
     cfg.write_file(&cfg_path);
+    if super::new::update_gitignore(Path::new(".")).is_err() {
+        util::print_color("Problem updating `.gitignore`", Color::Yellow);
+    }
     util::print_color("Created `pyproject.toml`", Color::Green);
 }
@@ -1,15 +1,103 @@
-use std::path::Path;
+use std::{fs, path::Path};
 
 use termcolor::Color;
 
 use crate::{
-    dep_types::{LockPackage, Version},
-    util::{self, process_reqs, Os, Paths},
+    dep_types::{LockPackage, Req, Version},
+    files,
+    util::{self, abort, process_reqs, Os, Paths},
     Config,
 };
 
 use util::deps::sync;
 
+/// Stores the project's extras installed with `pyflow install -e . --extras`, in the
+/// environment's directory, so later syncs keep them installed.
+const EXTRAS_FILENAME: &str = "extras.txt";
+
+/// Find requirements for the project's installed extras. Extras since removed from
+/// `pyproject.toml` are skipped.
+pub fn active_extra_reqs(vers_path: &Path, cfg: &Config) -> Vec<Req> {
+    let extras: Vec<String> = fs::read_to_string(vers_path.join(EXTRAS_FILENAME))
+        .unwrap_or_default()
+        .lines()
+        .map(|l| l.trim().to_owned())
+        .filter(|e| cfg.extras.contains_key(e))
+        .collect();
+    cfg.extra_reqs(&extras)
+}
+
+/// Handle `pyflow install -e <path>`: Record the project at `path` in `pyproject.toml` as a
+/// path dependency with `editable = true`, and install it in place. For the current project,
+/// also record the extras to install. Returns the reqs to add to this sync.
+pub fn install_editable(
+    path: &str,
+    extras: &[String],
+    project_path: &Path,
+    vers_path: &Path,
+    cfg: &Config,
+    cfg_path: &Path,
+    paths: &Paths,
+) -> Vec<Req> {
+    let target = util::editable::read(Path::new(path)).unwrap_or_else(|e| abort(&e));
+    let is_project = fs::canonicalize(project_path).is_ok_and(|p| p == target.root);
+
+    let mut reqs = if is_project {
+        // Check the extras exist before recording them.
+        let reqs = cfg.extra_reqs(extras);
+        if fs::write(vers_path.join(EXTRAS_FILENAME), extras.join("\n")).is_err() {
+            abort("Problem saving the project's extras")
+        }
+        reqs
+    } else if !extras.is_empty() {
+        abort(&format!(
+            "`--extras` only applies to the current project. To install the package at {} \
+             with extras, add them to its entry in `pyproject.toml`, eg \
+             `{} = {{ path = \"{}\", editable = true, extras = [\"cli\"] }}`",
+            path, target.name, path
+        ))
+    } else {
+        vec![]
+    };
+
+    let existing = cfg
+        .reqs
+        .iter()
+        .chain(cfg.dev_reqs.iter())
+        .find(|r| util::compare_names(&r.name, &target.name));
+    match existing {
+        Some(r) if !r.editable => util::print_color(
+            &format!(
+                "{} is already in `pyproject.toml`; add `editable = true` to its entry to keep \
+                 it installed in place",
+                r.name
+            ),
+            Color::Yellow,
+        ),
+        Some(_) => (),
+        None => {
+            let req = Req {
+                path: Some(path.to_owned()),
+                editable: true,
+                ..Req::new(target.name.clone(), vec![])
+            };
+            files::add_reqs_to_cfg(cfg_path, std::slice::from_ref(&req), &[]);
+            reqs.push(req);
+        }
+    }
+
+    util::editable::install(&target, paths);
+    util::print_color(
+        &format!(
+            "Installed {} in place, from {}",
+            target.name,
+            target.source.to_string_lossy()
+        ),
+        Color::Green,
+    );
+    reqs
+}
+
 // TODO: Refactor this function
 #[allow(clippy::too_many_arguments)]
 pub fn install(
@@ -20,10 +108,14 @@ pub fn install(
     found_lock: bool,
     packages: &[String],
     dev: bool,
+    force: bool,
+    wheel_tag: Option<&str>,
     lockpacks: &[LockPackage],
     os: &Os,
     py_vers: &Version,
     lock_path: &Path,
+    extra_reqs: &[Req],
+    prune_foreign: bool,
 ) {
     if !cfg_path.exists() {
         cfg.write_file(cfg_path);
@@ -33,13 +125,67 @@ pub fn install(
         util::print_color("Found lockfile", Color::Green);
     }
 
+    if let Some(tag) = wheel_tag {
+        util::wheel_tag::check_tag(tag, *os, py_vers).unwrap_or_else(|e| abort(&e));
+    }
+
     // Merge reqs added via cli with those in `pyproject.toml`.
-    let (updated_reqs, up_dev_reqs) = util::merge_reqs(packages, dev, cfg, cfg_path);
+    let (mut updated_reqs, mut up_dev_reqs) =
+        util::merge_reqs(packages, dev, force, wheel_tag, cfg, cfg_path);
+    updated_reqs.extend(extra_reqs.iter().cloned());
 
-    let dont_uninstall = util::find_dont_uninstall(&updated_reqs, &up_dev_reqs);
+    // Git and path reqs aren't resolved, so skip those for other platforms here.
+    let applies = |r: &Req| {
+        (r.git.is_none() && r.path.is_none())
+            || r.marker.as_ref().is_none_or(|m| m.evaluate(*os, py_vers))
+    };
+    updated_reqs.retain(applies);
+    up_dev_reqs.retain(applies);
+
+    let mut dont_uninstall = util::find_dont_uninstall(&updated_reqs, &up_dev_reqs, &cfg.preserve);
+
+    let (updated_reqs, renamed) = process_reqs(updated_reqs, git_path, paths);
+    let (up_dev_reqs, renamed_dev) = process_reqs(up_dev_reqs, git_path, paths);
+    for (declared, actual) in renamed.into_iter().chain(renamed_dev) {
+        // Git reqs added here are named after their repo until we see their metadata.
+        let added_here = packages.iter().any(|p| {
+            crate::install::git_req_from_arg(p)
+                .is_some_and(|r| util::compare_names(&r.name, &declared))
+        });
+        if added_here {
+            files::rename_req_in_cfg(cfg_path, &declared, &actual);
+            util::print_color(
+                &format!("Added {} to `pyproject.toml` as {}", declared, actual),
+                Color::Green,
+            );
+        } else {
+            util::print_color(
+                &format!(
+                    "`{}` in `pyproject.toml` installs a package named {}",
+                    declared, actual
+                ),
+                Color::Yellow,
+            );
+        }
+        dont_uninstall.push(actual);
+    }
 
-    let updated_reqs = process_reqs(updated_reqs, git_path, paths);
-    let up_dev_reqs = process_reqs(up_dev_reqs, git_path, paths);
+    let installed = util::find_installed(&paths.lib);
+    let combined = [updated_reqs.as_slice(), up_dev_reqs.as_slice()].concat();
+    if let Some(count) = util::deps::up_to_date(
+        lockpacks,
+        &combined,
+        &dont_uninstall,
+        &installed,
+        *os,
+        py_vers,
+    ) {
+        util::print_color(
+            &format!("Already up to date ({} packages)", count),
+            Color::Green,
+        );
+        return;
+    }
 
     sync(
         paths,
@@ -50,6 +196,7 @@ pub fn install(
         *os,
         py_vers,
         lock_path,
+        prune_foreign,
     );
     util::print_color("Installation complete", Color::Green);
 }
@@ -0,0 +1,381 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::Serialize;
+use termcolor::Color;
+
+use crate::{
+    dep_types::Req,
+    install,
+    pyproject::Config,
+    util::{self, print_color, Metadata},
+    CliConfig,
+};
+
+/// Listed for packages whose metadata doesn't say.
+const UNKNOWN: &str = "Unknown";
+
+/// SPDX identifiers for the last part of common license classifiers, eg
+/// `License :: OSI Approved :: MIT License`.
+const CLASSIFIERS: &[(&str, &str)] = &[
+    ("MIT License", "MIT"),
+    ("Apache Software License", "Apache-2.0"),
+    ("BSD License", "BSD"),
+    ("ISC License (ISCL)", "ISC"),
+    ("Mozilla Public License 2.0 (MPL 2.0)", "MPL-2.0"),
+    ("Python Software Foundation License", "PSF-2.0"),
+    ("The Unlicense (Unlicense)", "Unlicense"),
+    ("Eclipse Public License 2.0 (EPL-2.0)", "EPL-2.0"),
+    ("GNU General Public License v2 (GPLv2)", "GPL-2.0"),
+    (
+        "GNU General Public License v2 or later (GPLv2+)",
+        "GPL-2.0-or-later",
+    ),
+    ("GNU General Public License v3 (GPLv3)", "GPL-3.0"),
+    (
+        "GNU General Public License v3 or later (GPLv3+)",
+        "GPL-3.0-or-later",
+    ),
+    ("GNU Lesser General Public License v2 (LGPLv2)", "LGPL-2.0"),
+    (
+        "GNU Lesser General Public License v2 or later (LGPLv2+)",
+        "LGPL-2.0-or-later",
+    ),
+    ("GNU Lesser General Public License v3 (LGPLv3)", "LGPL-3.0"),
+    (
+        "GNU Lesser General Public License v3 or later (LGPLv3+)",
+        "LGPL-3.0-or-later",
+    ),
+    ("GNU Affero General Public License v3", "AGPL-3.0"),
+    (
+        "GNU Affero General Public License v3 or later (AGPLv3+)",
+        "AGPL-3.0-or-later",
+    ),
+    ("Public Domain", "Public Domain"),
+];
+
+/// SPDX identifiers for common ways of writing them in `License` fields, lowercase.
+const ALIASES: &[(&str, &str)] = &[
+    ("mit license", "MIT"),
+    ("apache 2.0", "Apache-2.0"),
+    ("apache license 2.0", "Apache-2.0"),
+    ("apache license, version 2.0", "Apache-2.0"),
+    ("apache software license", "Apache-2.0"),
+    ("bsd license", "BSD"),
+    ("3-clause bsd", "BSD-3-Clause"),
+    ("new bsd", "BSD-3-Clause"),
+    ("mpl 2.0", "MPL-2.0"),
+    ("psf", "PSF-2.0"),
+    ("gplv2", "GPL-2.0"),
+    ("gplv3", "GPL-3.0"),
+    ("lgplv3", "LGPL-3.0"),
+    ("agplv3", "AGPL-3.0"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Index,
+    Git,
+    Path,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PackageLicense {
+    pub name: String,
+    pub version: Option<String>,
+    pub license: String,
+    pub source: Source,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    packages: Vec<PackageLicense>,
+    /// Packages, by license.
+    counts: BTreeMap<String, usize>,
+    /// Packages with a license passed to `--fail-on`.
+    denied: Vec<String>,
+}
+
+/// The SPDX identifier for a common spelling of a license, or the license as written.
+fn normalize(license: &str) -> String {
+    let license = license.trim();
+    let lower = license.to_lowercase();
+    ALIASES
+        .iter()
+        .chain(CLASSIFIERS)
+        .find(|(alias, _)| alias.to_lowercase() == lower)
+        .map_or_else(|| license.to_owned(), |(_, spdx)| (*spdx).to_owned())
+}
+
+/// A package's license, from its SPDX expression, license classifiers, or `License` field, in
+/// that order. Long `License` fields are usually the license's text, so aren't used.
+pub fn license(metadata: &Metadata) -> Option<String> {
+    if let Some(expr) = metadata
+        .license_expression
+        .as_ref()
+        .filter(|e| !e.is_empty())
+    {
+        return Some(expr.clone());
+    }
+
+    let mut from_classifiers: Vec<String> = metadata
+        .license_classifiers
+        .iter()
+        .filter_map(|c| c.rsplit("::").next())
+        .map(str::trim)
+        .filter(|c| *c != "OSI Approved" && *c != "License")
+        .map(normalize)
+        .collect();
+    from_classifiers.dedup();
+    if !from_classifiers.is_empty() {
+        return Some(from_classifiers.join(" OR "));
+    }
+
+    metadata
+        .license
+        .as_deref()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.eq_ignore_ascii_case("unknown") && l.len() <= 60)
+        .map(normalize)
+}
+
+/// If each alternative in `license`'s `OR` expression includes a denied identifier. Denying eg
+/// `GPL-3.0` also denies `GPL-3.0-only` and `GPL-3.0-or-later`. Case doesn't matter.
+pub fn is_denied(license: &str, fail_on: &[String]) -> bool {
+    let denied: Vec<String> = fail_on
+        .iter()
+        .map(|d| normalize(d).to_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect();
+    if denied.is_empty() {
+        return false;
+    }
+    let matches = |id: &str| {
+        denied.iter().any(|d| {
+            id == d
+                || id
+                    .strip_prefix(d.as_str())
+                    .is_some_and(|r| r.starts_with('-'))
+        })
+    };
+
+    license.to_lowercase().split(" or ").all(|alternative| {
+        matches(alternative.trim())
+            || alternative
+                .split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+                .any(matches)
+    })
+}
+
+/// A path requirement's version and license, from a wheel built in its `dist` folder, or its
+/// `pyproject.toml`.
+fn path_license(path: &Path) -> (Option<String>, Option<String>) {
+    let wheel = fs::read_dir(path.join("dist")).ok().and_then(|entries| {
+        entries
+            .flatten()
+            .map(|e| e.path())
+            .find(|p| p.extension().is_some_and(|e| e == "whl"))
+    });
+    if let Some(metadata) = wheel.and_then(|w| util::wheel_metadata(&w).ok()) {
+        return (Some(metadata.version.to_string()), license(&metadata));
+    }
+
+    match Config::from_file(&path.join("pyproject.toml")) {
+        Some(cfg) => (
+            cfg.version.map(|v| v.to_string()),
+            cfg.license.as_deref().map(normalize),
+        ),
+        None => (None, None),
+    }
+}
+
+/// The license of each installed package, and of path requirements, which aren't installed in
+/// `lib`. Packages are sorted by name.
+pub fn find_licenses(lib_path: &Path, reqs: &[Req]) -> Vec<PackageLicense> {
+    let mut result: Vec<PackageLicense> = util::find_installed(lib_path)
+        .into_iter()
+        .map(|(name, version, _)| {
+            let metadata = install::find_dist_info(&name, &version, lib_path)
+                .and_then(|d| util::parse_metadata(&d.join("METADATA")).ok());
+            let git = reqs
+                .iter()
+                .any(|r| r.git.is_some() && util::compare_names(&r.name, &name));
+            PackageLicense {
+                license: metadata
+                    .as_ref()
+                    .and_then(license)
+                    .unwrap_or_else(|| UNKNOWN.to_owned()),
+                name,
+                version: Some(version.to_string()),
+                source: if git { Source::Git } else { Source::Index },
+            }
+        })
+        .collect();
+
+    for req in reqs {
+        if let Some(path) = &req.path {
+            let (version, license) = path_license(Path::new(path));
+            result.push(PackageLicense {
+                name: req.name.clone(),
+                version,
+                license: license.unwrap_or_else(|| UNKNOWN.to_owned()),
+                source: Source::Path,
+            });
+        }
+    }
+
+    result.sort_by_key(|p| p.name.to_lowercase());
+    result
+}
+
+fn print_report(report: &Report) {
+    if report.packages.is_empty() {
+        print_color("No packages are installed.", Color::Blue);
+        return;
+    }
+
+    let width = |f: &dyn Fn(&PackageLicense) -> usize, header: &str| {
+        report
+            .packages
+            .iter()
+            .map(f)
+            .chain([header.len()])
+            .max()
+            .unwrap_or_default()
+    };
+    let name_width = width(&|p| p.name.len(), "Package");
+    let vers_width = width(&|p| p.version.as_deref().unwrap_or("-").len(), "Version");
+
+    print_color(
+        &format!(
+            "{:name_width$}  {:vers_width$}  License",
+            "Package", "Version"
+        ),
+        Color::Blue,
+    );
+    for package in &report.packages {
+        let color = if report.denied.contains(&package.name) {
+            Color::Red
+        } else if package.license == UNKNOWN {
+            Color::Yellow
+        } else {
+            Color::White
+        };
+        print_color(
+            &format!(
+                "{:name_width$}  {:vers_width$}  {}",
+                package.name,
+                package.version.as_deref().unwrap_or("-"),
+                package.license
+            ),
+            color,
+        );
+    }
+
+    let mut counts: Vec<_> = report.counts.iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    print_color("\nBy license:", Color::Blue);
+    for (license, count) in counts {
+        println!("{}: {}", license, count);
+    }
+
+    if !report.denied.is_empty() {
+        print_color(
+            &format!(
+                "\nThese packages have a denied license: {}",
+                report.denied.join(", ")
+            ),
+            Color::Red,
+        );
+    }
+}
+
+/// Summarize the licenses of installed packages and path requirements. Exits with an error if
+/// any match `fail_on`.
+pub fn licenses(lib_path: &Path, reqs: &[Req], fail_on: &[String]) {
+    let packages = find_licenses(lib_path, reqs);
+    let mut counts = BTreeMap::new();
+    for package in &packages {
+        *counts.entry(package.license.clone()).or_insert(0) += 1;
+    }
+    let denied = packages
+        .iter()
+        .filter(|p| is_denied(&p.license, fail_on))
+        .map(|p| p.name.clone())
+        .collect();
+    let report = Report {
+        packages,
+        counts,
+        denied,
+    };
+
+    if CliConfig::current().json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("Problem serializing the report")
+        );
+    } else {
+        print_report(&report);
+    }
+    if !report.denied.is_empty() {
+        util::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn metadata(data: &str) -> Metadata {
+        let data = format!("Metadata-Version: 2.1\nName: pkg\nVersion: 1.0\n{}", data);
+        util::parse_metadata_str(&data).unwrap()
+    }
+
+    #[rstest(
+        data,
+        expected,
+        case(
+            "License-Expression: MIT OR Apache-2.0\nLicense: MIT\n",
+            Some("MIT OR Apache-2.0")
+        ),
+        case(
+            "License: UNKNOWN\nClassifier: License :: OSI Approved :: MIT License\n\
+             Classifier: Programming Language :: Python\n",
+            Some("MIT")
+        ),
+        case(
+            "Classifier: License :: OSI Approved :: GNU General Public License v3 (GPLv3)\n",
+            Some("GPL-3.0")
+        ),
+        case("License: Apache License, Version 2.0\n", Some("Apache-2.0")),
+        case("License: BSD-3-Clause\n", Some("BSD-3-Clause")),
+        case("License: UNKNOWN\n", None),
+        case(
+            "License: Copyright (c) 2010, The Authors. Permission is hereby granted, free of \
+             charge\n",
+            None
+        ),
+        case("", None)
+    )]
+    fn license_from_metadata(data: &str, expected: Option<&str>) {
+        assert_eq!(license(&metadata(data)).as_deref(), expected);
+    }
+
+    #[rstest(
+        license,
+        denied,
+        case("GPL-3.0", true),
+        case("gpl-3.0-or-later", true),
+        case("AGPL-3.0-only", true),
+        case("LGPL-3.0", false),
+        case("MIT OR GPL-3.0", false),
+        case("(GPL-3.0 OR AGPL-3.0) AND MIT", true),
+        case("Unknown", false)
+    )]
+    fn denied_licenses(license: &str, denied: bool) {
+        let fail_on = ["GPL-3.0".to_owned(), "agplv3".to_owned()];
+        assert_eq!(is_denied(license, &fail_on), denied);
+    }
+}
@@ -3,14 +3,22 @@ use std::{path::Path, process};
 use termcolor::Color;
 
 use crate::{
-    dep_types::Req,
+    dep_types::{Req, Version},
     pyproject,
-    util::{self, abort, print_color, print_color_},
+    util::{self, abort, print_color, print_color_, requires_python},
 };
 
 /// List all installed dependencies and console scripts, by examining the `libs` and `bin` folders.
-/// Also include path requirements, which won't appear in the `lib` folder.
-pub fn list(lib_path: &Path, path_reqs: &[Req]) {
+/// Also include path requirements, which won't appear in the `lib` folder. Packages that don't
+/// support the environment's Python version are flagged. With `sizes`, show the bytes each
+/// package installed, as recorded when installing.
+pub fn list(
+    lib_path: &Path,
+    entry_pt_path: &Path,
+    py_vers: &Version,
+    sizes: bool,
+    path_reqs: &[Req],
+) {
     // This part check that project and venvs exists
     let pcfg = pyproject::current::get_config().unwrap_or_else(|| process::exit(1));
     let num_venvs = util::find_venvs(&pcfg.pypackages_path).len();
@@ -22,31 +30,86 @@ pub fn list(lib_path: &Path, path_reqs: &[Req]) {
     }
 
     let installed = util::find_installed(lib_path);
-    let scripts = find_console_scripts(&lib_path.join("../bin"));
+    let scripts = find_console_scripts(entry_pt_path);
+    let incompatible = requires_python::find_incompatible(lib_path, py_vers);
 
     if installed.is_empty() {
         print_color("No packages are installed.", Color::Blue); // Dark
     } else {
         print_color("These packages are installed:", Color::Blue); // Dark
-        for (name, version, _tops) in installed {
+        for (name, version, tops) in installed {
             print_color_(&name, Color::Cyan);
-            print_color(&format!("=={}", version.to_string_color()), Color::White);
+            let size = if sizes {
+                let size = util::installed_cache::size(lib_path, &name, &version)
+                    .map_or_else(|| "size unknown".to_owned(), util::format_size);
+                format!(" [{}]", size)
+            } else {
+                String::new()
+            };
+            if let Some(inc) = incompatible
+                .iter()
+                .find(|i| i.name == name && i.version == version)
+            {
+                print_color(
+                    &format!(
+                        "=={} (requires Python {}, but this environment uses {}){}",
+                        version, inc.requires_python, py_vers, size
+                    ),
+                    Color::Yellow,
+                );
+                continue;
+            }
+            // Show what to import, if it's not the package name.
+            if tops.len() == 1 && tops[0] == util::standardize_name(&name) {
+                print_color(
+                    &format!("=={}{}", version.to_string_color(), size),
+                    Color::White,
+                );
+            } else {
+                print_color(
+                    &format!(
+                        "=={} (imports: {}){}",
+                        version.to_string_color(),
+                        tops.join(", "),
+                        size
+                    ),
+                    Color::White,
+                );
+            }
         }
         for req in path_reqs {
             print_color_(&req.name, Color::Cyan);
             print_color(
-                &format!(", at path: {}", req.path.as_ref().unwrap()),
+                &format!(
+                    ", at path: {}{}",
+                    req.path.as_ref().unwrap(),
+                    if req.editable { " (editable)" } else { "" }
+                ),
                 Color::White,
             );
         }
     }
 
+    if !incompatible.is_empty() {
+        print_color(
+            "\nRun `pyflow install` to replace packages that don't support this Python version.",
+            Color::Yellow,
+        );
+    }
+
     if scripts.is_empty() {
         print_color("\nNo console scripts are installed.", Color::Blue); // Dark
     } else {
         print_color("\nThese console scripts are installed:", Color::Blue); // Dark
+        let owners = util::scripts::Owners::read(entry_pt_path);
         for script in scripts {
-            print_color(&script, Color::Cyan); // Dark
+            match owners.scripts.get(&script) {
+                Some(owner) => {
+                    print_color_(&script, Color::Cyan); // Dark
+                    print_color(&format!(" (from {})", owner.package), Color::White);
+                }
+                None => print_color(&script, Color::Cyan), // Dark
+            }
         }
     }
 }
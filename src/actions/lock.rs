@@ -0,0 +1,351 @@
+use std::{collections::HashMap, path::Path, str::FromStr};
+
+use termcolor::Color;
+
+use crate::{
+    dep_resolution::Resolution,
+    dep_types::{Lock, LockPackage, Marker, Req, Version},
+    util::{
+        self, abort,
+        cross_target::Target,
+        deps::{self, PythonLock},
+        lock_diff::LockDiff,
+        lock_header::{self, Header},
+        print_color, Os,
+    },
+};
+
+/// Check that each package in `pyflow.lock` has an installable release file for each of
+/// `platforms`, eg `linux`, `windows`, and `macos`. If `pythons` are specified, first
+/// re-lock for each of them.
+#[allow(clippy::too_many_arguments)]
+pub fn lock(
+    paths: &util::Paths,
+    lock_path: &Path,
+    platforms: &[String],
+    pythons: &[String],
+    reqs: &[Req],
+    dont_uninstall: &[String],
+    os: Os,
+    py_vers: &Version,
+) {
+    let lock =
+        util::read_lock(lock_path).unwrap_or_else(|_| abort("Problem reading `pyflow.lock`"));
+    let mut lock_packs = lock.package.unwrap_or_default();
+
+    let mut py_versions: Vec<Version> = pythons
+        .iter()
+        .map(|p| {
+            Version::from_str(p)
+                .unwrap_or_else(|_| abort(&format!("Problem parsing Python version: {}", p)))
+        })
+        .collect();
+    py_versions.sort();
+    py_versions.dedup();
+
+    if !py_versions.is_empty() {
+        let (updated, report) = deps::lock_pythons(&lock_packs, reqs, os, &py_versions, lock_path);
+        print_python_report(&report, &py_versions);
+
+        if !py_versions
+            .iter()
+            .any(|v| v.major == py_vers.major && v.minor == py_vers.minor)
+        {
+            print_color(
+                &format!(
+                    "This project's Python version, {}, isn't one of those locked for, so some \
+                     of its packages may be missing from the lock",
+                    py_vers
+                ),
+                Color::Yellow,
+            );
+        }
+        // Packages may have changed to ones that work on all the versions.
+        let installed = util::find_installed(&paths.lib);
+        deps::sync_deps(paths, &updated, dont_uninstall, &installed, os, py_vers);
+        lock_packs = updated;
+    } else {
+        py_versions.push(py_vers.clone());
+    }
+
+    let mut problems = vec![];
+    for platform in platforms {
+        let platform_os = match Os::from_str(&platform.to_lowercase()) {
+            Ok(Os::Any) | Err(_) => abort(&format!(
+                "Unknown platform: {}. Expected linux, windows, or macos",
+                platform
+            )),
+            Ok(os) => os,
+        };
+
+        for py_vers in &py_versions {
+            for lp in &lock_packs {
+                // Skip packages that aren't installed on this platform and Python version.
+                let marker = lp.marker.as_ref().and_then(|m| Marker::from_str(m).ok());
+                if !marker.is_none_or(|m| m.evaluate(platform_os, py_vers)) {
+                    continue;
+                }
+
+                let version = Version::from_str(&lp.version).expect("Problem parsing lock version");
+                match &lp.files {
+                    Some(files) => {
+                        if let Err(e) = util::find_locked_release(
+                            files,
+                            &lp.name,
+                            &version,
+                            platform_os,
+                            py_vers,
+                            lp.wheel.as_deref(),
+                        ) {
+                            problems.push(e);
+                        }
+                    }
+                    None => problems.push(format!(
+                        "{} {} has no release files recorded in `pyflow.lock`",
+                        lp.name, lp.version
+                    )),
+                }
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        print_color(
+            &format!("Locked {} packages", lock_packs.len()),
+            Color::Green,
+        );
+        return;
+    }
+    for problem in &problems {
+        print_color(problem, Color::Yellow);
+    }
+    abort("Some locked packages can't be installed on the platforms specified");
+}
+
+/// Exit with an error, showing what would change, if resolving `reqs` would change the lock.
+/// Doesn't write the lock or install anything, eg for CI. It's resolved again with the
+/// resolution it was made with, and for the platform and Python version it was made for, from
+/// its metadata.
+#[allow(clippy::too_many_arguments)]
+pub fn check_lock(
+    lock_path: &Path,
+    metadata: &HashMap<String, String>,
+    lockpacks: &[LockPackage],
+    reqs: &[Req],
+    dont_uninstall: &[String],
+    os: Os,
+    py_vers: &Version,
+) {
+    let header = Header::from_lock(metadata);
+    lock_header::warn_if_stale(&header, lock_path);
+    let (os, py_vers) = match Target::from_lock(metadata) {
+        Some(target) => {
+            target.make_current();
+            (target.os, target.python)
+        }
+        None => (os, py_vers.clone()),
+    };
+
+    let updated = deps::relock_with(
+        lockpacks,
+        reqs,
+        dont_uninstall,
+        os,
+        &py_vers,
+        header.resolution,
+    );
+    let diff = LockDiff::new(lockpacks, &updated, reqs);
+    if diff.is_empty() {
+        print_color("`pyflow.lock` is up to date", Color::Green);
+        return;
+    }
+    diff.print("`pyflow.lock` is out of date. Resolving it again would make these changes:");
+    util::exit(1);
+}
+
+/// Lock for another platform and Python version, eg to bundle for a Linux server from a Mac.
+/// Only wheels for it are used. Nothing is installed, and the lock is marked with the target,
+/// so installing from it elsewhere warns.
+pub fn lock_for_target(
+    lock_path: &Path,
+    lockpacks: &[LockPackage],
+    reqs: &[Req],
+    dont_uninstall: &[String],
+    target: &Target,
+) {
+    target.make_current();
+    let resolution = lock_header::resolution(lock_path);
+    let mut lock_packs = deps::relock_with(
+        lockpacks,
+        reqs,
+        dont_uninstall,
+        target.os,
+        &target.python,
+        resolution,
+    );
+    deps::record_files(&mut lock_packs);
+    deps::pin_wheels(&mut lock_packs, reqs);
+
+    let problems: Vec<String> = lock_packs
+        .iter()
+        .filter(|lp| deps::applies(lp, target.os, &target.python))
+        .filter_map(|lp| {
+            let version = Version::from_str(&lp.version).expect("Problem parsing lock version");
+            let files = lp.files.as_deref().unwrap_or_default();
+            util::find_locked_release(
+                files,
+                &lp.name,
+                &version,
+                target.os,
+                &target.python,
+                lp.wheel.as_deref(),
+            )
+            .err()
+        })
+        .collect();
+    if !problems.is_empty() {
+        for problem in &problems {
+            print_color(problem, Color::Yellow);
+        }
+        abort(&format!(
+            "Some packages have no wheels for {} and Python {}; `pyflow.lock` is unchanged",
+            target.platform, target.python
+        ));
+    }
+
+    let mut lock = Lock {
+        metadata: HashMap::new(),
+        package: Some(lock_packs.clone()),
+    };
+    Header::new(
+        lock_path,
+        resolution,
+        std::slice::from_ref(&target.python),
+        std::slice::from_ref(&target.platform),
+    )
+    .mark(&mut lock.metadata);
+    target.mark(&mut lock.metadata);
+    if util::write_lock(lock_path, &lock).is_err() {
+        abort("Problem writing lock file");
+    }
+    print_color(
+        &format!(
+            "Locked {} packages for {} and Python {}",
+            lock_packs.len(),
+            target.platform,
+            target.python
+        ),
+        Color::Green,
+    );
+}
+
+/// Re-resolve the whole lock with `resolution`, eg picking the oldest compatible versions to test
+/// a library's lower bounds, and install them. The lock is marked with the resolution, so
+/// `pyflow check` can warn about it.
+pub fn lock_with_resolution(
+    paths: &util::Paths,
+    lock_path: &Path,
+    reqs: &[Req],
+    dont_uninstall: &[String],
+    os: Os,
+    py_vers: &Version,
+    resolution: Resolution,
+) -> Vec<LockPackage> {
+    let mut lock_packs = deps::relock_with(&[], reqs, dont_uninstall, os, py_vers, resolution);
+    deps::record_files(&mut lock_packs);
+    deps::pin_wheels(&mut lock_packs, reqs);
+
+    let mut lock = Lock {
+        metadata: HashMap::new(),
+        package: Some(lock_packs.clone()),
+    };
+    Header::new(
+        lock_path,
+        resolution,
+        std::slice::from_ref(py_vers),
+        &[lock_header::platform_name(os)],
+    )
+    .mark(&mut lock.metadata);
+    if util::write_lock(lock_path, &lock).is_err() {
+        abort("Problem writing lock file");
+    }
+
+    let installed = util::find_installed(&paths.lib);
+    deps::sync_deps(paths, &lock_packs, dont_uninstall, &installed, os, py_vers);
+    print_color(
+        &format!(
+            "Locked {} packages, with the {} resolution",
+            lock_packs.len(),
+            resolution.as_str()
+        ),
+        Color::Green,
+    );
+    lock_packs
+}
+
+/// Remove packages no longer required from `pyflow.lock`, without resolving or installing.
+pub fn prune_lock(lock_path: &Path, reqs: &[Req], dont_uninstall: &[String]) {
+    let mut lock =
+        util::read_lock(lock_path).unwrap_or_else(|_| abort("Problem reading `pyflow.lock`"));
+    let lock_packs = lock.package.unwrap_or_default();
+    let pruned = deps::prune_lock(&lock_packs, reqs, dont_uninstall);
+
+    for lp in lock_packs
+        .iter()
+        .filter(|lp| !pruned.iter().any(|p| p.id == lp.id))
+    {
+        println!("Removed {} {}", lp.name, lp.version);
+    }
+    let removed = lock_packs.len() - pruned.len();
+    if removed > 0 {
+        lock.package = Some(pruned);
+        if util::write_lock(lock_path, &lock).is_err() {
+            abort("Problem writing lock file");
+        }
+    }
+    print_color(
+        &format!("Pruned {} packages from the lock", removed),
+        Color::Green,
+    );
+}
+
+fn join_versions(versions: &[Version]) -> String {
+    versions
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Show which packages are locked for some Python versions only, and which use a different
+/// version on each.
+fn print_python_report(report: &[(String, PythonLock)], py_versions: &[Version]) {
+    let mut unified = 0;
+    for (name, lock) in report {
+        match lock {
+            PythonLock::Unified(_, pys) if pys.len() == py_versions.len() => unified += 1,
+            PythonLock::Unified(version, pys) => {
+                println!("{} {}: Python {} only", name, version, join_versions(pys))
+            }
+            PythonLock::Split(versions) => {
+                let versions: Vec<String> = versions
+                    .iter()
+                    .map(|(v, pys)| format!("{} for Python {}", v, join_versions(pys)))
+                    .collect();
+                print_color(
+                    &format!(
+                        "{}: Locked a different version for each Python version: {}",
+                        name,
+                        versions.join("; ")
+                    ),
+                    Color::Yellow,
+                );
+            }
+        }
+    }
+    println!(
+        "{} packages use the same version on Python {}",
+        unified,
+        join_versions(py_versions)
+    );
+}
@@ -0,0 +1,402 @@
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+use termcolor::Color;
+
+use crate::{
+    dep_types::{Constraint, Lock, LockFile, LockPackage, Marker, Version},
+    files::{PoetryLock, PoetryLockFile, PoetryLockPackage},
+    pyproject::Config,
+    util::{self, abort, print_color, Os},
+};
+
+const POETRY_LOCK_FILENAME: &str = "poetry.lock";
+
+/// Source types for packages that aren't on an index. We don't lock these.
+const UNLOCKED_SOURCES: [&str; 4] = ["directory", "file", "url", "git"];
+
+/// Convert a project managed by another tool: Create `pyflow.lock` from its lock, so the first
+/// install uses the same versions and files. Poetry's `pyproject.toml` sections are read
+/// as-is, so aren't changed.
+pub fn migrate(tool: &str, cfg_path: &Path, lock_path: &Path, os: Os) {
+    if tool != "poetry" {
+        abort(&format!(
+            "Can't migrate from {}. Only Poetry is supported, eg `pyflow migrate poetry`",
+            tool
+        ))
+    }
+    if lock_path.exists() {
+        abort("pyflow.lock already exists - not overwriting.")
+    }
+    let cfg = Config::from_file(cfg_path)
+        .unwrap_or_else(|| abort("Can't find `pyproject.toml` in this folder"));
+
+    let poetry_lock_path = cfg_path.with_file_name(POETRY_LOCK_FILENAME);
+    let data = fs::read_to_string(&poetry_lock_path)
+        .unwrap_or_else(|_| abort("Can't find `poetry.lock` in this folder"));
+    let poetry_lock: PoetryLock = toml::from_str(&data)
+        .unwrap_or_else(|e| abort(&format!("Problem parsing `poetry.lock`: {}", e)));
+
+    let (lock_packs, unconverted) = convert_poetry_lock(poetry_lock);
+    let lock = Lock {
+        metadata: HashMap::new(),
+        package: Some(lock_packs.clone()),
+    };
+    if util::write_lock(lock_path, &lock).is_err() {
+        abort("Problem writing lock file");
+    }
+    print_color(
+        &format!(
+            "Created `pyflow.lock` with {} packages from `poetry.lock`",
+            lock_packs.len()
+        ),
+        Color::Green,
+    );
+
+    if !unconverted.is_empty() {
+        print_color(
+            "These couldn't be converted, so please check them:",
+            Color::Yellow,
+        );
+        for msg in &unconverted {
+            println!("- {}", msg);
+        }
+    }
+
+    // Check that the converted lock satisfies the requirements we read from `pyproject.toml`.
+    let py_vers = match &cfg.py_version {
+        Some(v) => v.parsed.clone(),
+        None => util::prompts::py_vers(
+            "Set `py_version` in `[tool.pyflow]`, eg with `pyflow config set py_version 3.11`.",
+        ),
+    };
+    let reqs = [cfg.reqs.as_slice(), cfg.dev_reqs.as_slice()].concat();
+    let missing = super::doctor::missing_from_lock(&reqs, &lock_packs, os, &py_vers);
+    if !missing.is_empty() {
+        print_color(
+            &format!(
+                "No locked version satisfies these requirements, so they'll be resolved on \
+                 install: {}",
+                missing.join(", ")
+            ),
+            Color::Yellow,
+        );
+    }
+}
+
+/// A URL for a file on PyPI, from its name. eg
+/// `https://files.pythonhosted.org/packages/py3/r/requests/requests-2.31.0-py3-none-any.whl`
+fn file_url(name: &str, python_version: &str, filename: &str) -> String {
+    format!(
+        "https://files.pythonhosted.org/packages/{}/{}/{}/{}",
+        python_version,
+        name.chars().next().unwrap_or('_'),
+        name,
+        filename
+    )
+}
+
+fn convert_file(
+    name: &str,
+    requires_python: &Option<String>,
+    f: &PoetryLockFile,
+) -> Option<LockFile> {
+    let sha256 = f.hash.strip_prefix("sha256:")?;
+    let (packagetype, python_version) = match f.file.strip_suffix(".whl") {
+        // eg `requests-2.31.0-py3-none-any`
+        Some(stem) => ("bdist_wheel", stem.rsplit('-').nth(2).unwrap_or_default()),
+        None => ("sdist", "source"),
+    };
+    Some(LockFile {
+        filename: f.file.clone(),
+        url: file_url(name, python_version, &f.file),
+        sha256: sha256.to_owned(),
+        packagetype: packagetype.to_owned(),
+        python_version: python_version.to_owned(),
+        requires_python: requires_python.clone(),
+        platform: util::platform_tag(&f.file),
+    })
+}
+
+/// A package's dependencies, as constraints and markers. Each is a string like `">=2.0"`, a
+/// table with `version` and `markers`, or an array of these for different markers.
+fn dependency_specs(value: &toml::Value) -> Vec<(String, Option<String>)> {
+    match value {
+        toml::Value::String(constrs) => vec![(constrs.clone(), None)],
+        toml::Value::Table(t) => {
+            let field = |k: &str| t.get(k).and_then(|v| v.as_str()).map(str::to_owned);
+            vec![(
+                field("version").unwrap_or_else(|| "*".into()),
+                field("markers"),
+            )]
+        }
+        toml::Value::Array(items) => items.iter().flat_map(dependency_specs).collect(),
+        _ => vec![],
+    }
+}
+
+/// Convert `poetry.lock` to lock packages. Also returns descriptions of what we couldn't convert,
+/// eg path dependencies, and markers we can't parse.
+fn convert_poetry_lock(poetry_lock: PoetryLock) -> (Vec<LockPackage>, Vec<String>) {
+    let mut unconverted = vec![];
+    let mut metadata_files = poetry_lock
+        .metadata
+        .and_then(|m| m.files)
+        .unwrap_or_default();
+
+    let mut packages: Vec<PoetryLockPackage> = vec![];
+    for package in poetry_lock.package {
+        match &package.source {
+            Some(source) if UNLOCKED_SOURCES.contains(&source.type_.as_str()) => {
+                unconverted.push(format!(
+                    "{} {} is a {} dependency, from {}. Add it to `pyproject.toml` with `path` \
+                     or `git` if needed",
+                    package.name, package.version, source.type_, source.url
+                ))
+            }
+            _ => packages.push(package),
+        }
+    }
+    packages.sort_by(|a, b| {
+        util::standardize_name(&a.name)
+            .cmp(&util::standardize_name(&b.name))
+            .then_with(|| {
+                Version::from_str(&a.version)
+                    .ok()
+                    .cmp(&Version::from_str(&b.version).ok())
+            })
+    });
+
+    // The markers of the dependencies on each package. `None` means a dependency applies
+    // everywhere.
+    let mut edges: HashMap<String, Vec<Option<String>>> = HashMap::new();
+    // Dependencies of each package, as the locked package's name and version.
+    let mut deps: Vec<Vec<String>> = vec![];
+    for package in &packages {
+        let mut package_deps = vec![];
+        for (dep_name, value) in package.dependencies.iter().flatten() {
+            for (constrs, marker) in dependency_specs(value) {
+                let constraints = Constraint::from_str_multiple(&constrs).unwrap_or_default();
+                // Optional dependencies for extras that weren't installed aren't locked.
+                let locked = packages.iter().find(|p| {
+                    util::compare_names(&p.name, dep_name)
+                        && Version::from_str(&p.version)
+                            .is_ok_and(|v| constraints.iter().all(|c| c.is_compatible(&v)))
+                });
+                if let Some(locked) = locked {
+                    let dep = format!(
+                        "{} {} pypi+https://pypi.org/pypi/{}/{}/json",
+                        locked.name, locked.version, locked.name, locked.version
+                    );
+                    if !package_deps.contains(&dep) {
+                        package_deps.push(dep);
+                    }
+                    edges
+                        .entry(util::standardize_name(&locked.name))
+                        .or_default()
+                        .push(marker);
+                }
+            }
+        }
+        package_deps.sort();
+        deps.push(package_deps);
+    }
+
+    let lock_packs = packages
+        .into_iter()
+        .zip(deps)
+        .enumerate()
+        .map(|(i, (package, deps))| {
+            let name = util::standardize_name(&package.name);
+            // Packages only reached through dependencies with markers get those markers.
+            let markers: Vec<String> = match (&package.markers, edges.get(&name)) {
+                (Some(m), _) => vec![m.clone()],
+                (None, Some(e)) if e.iter().all(Option::is_some) => {
+                    let mut m: Vec<String> = e.iter().flatten().cloned().collect();
+                    m.sort();
+                    m.dedup();
+                    m
+                }
+                _ => vec![],
+            };
+            let parsed: Result<Vec<Marker>, _> =
+                markers.iter().map(|m| Marker::from_str(m)).collect();
+            let marker = match parsed {
+                Ok(mut m) if m.len() == 1 => Some(m.remove(0)),
+                Ok(m) if m.len() > 1 => Some(Marker::Or(m)),
+                Ok(_) => None,
+                Err(_) => {
+                    unconverted.push(format!(
+                        "{} {} has markers we can't parse, so is installed everywhere: {}",
+                        package.name,
+                        package.version,
+                        markers.join("; ")
+                    ));
+                    None
+                }
+            };
+
+            let requires_python = package.python_versions.clone().filter(|v| v.trim() != "*");
+            let poetry_files = package
+                .files
+                .or_else(|| metadata_files.remove(&package.name))
+                .unwrap_or_default();
+            let mut files: Vec<LockFile> = poetry_files
+                .iter()
+                .filter_map(|f| convert_file(&package.name, &requires_python, f))
+                .collect();
+            files.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+            LockPackage {
+                id: i as u32 + 1,
+                source: Some(format!(
+                    "pypi+https://pypi.org/pypi/{}/{}/json",
+                    package.name, package.version
+                )),
+                name: package.name,
+                version: package.version,
+                dependencies: Some(deps),
+                rename: None,
+                marker: marker.map(|m| m.to_string()),
+                modules: None,
+                // If Poetry didn't record files, they're recorded on install.
+                files: (!files.is_empty()).then_some(files),
+                extras: None,
+                wheel: None,
+            }
+        })
+        .collect();
+
+    (lock_packs, unconverted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POETRY_LOCK: &str = r#"
+[[package]]
+name = "colorama"
+version = "0.4.6"
+description = "Cross-platform colored terminal text."
+optional = false
+python-versions = "!=3.0.*,!=3.1.*,!=3.2.*,!=3.3.*,!=3.4.*,!=3.5.*,!=3.6.*,>=2.7"
+files = [
+    {file = "colorama-0.4.6-py2.py3-none-any.whl", hash = "sha256:4f1d9991f5acc0ca119f9d443620b77f9d6b33703e51011c16baf57afb285fc6"},
+    {file = "colorama-0.4.6.tar.gz", hash = "sha256:08695f5cb7ed6e0531a20572697297273c47b8cae5a63ffc6d6ed5c201be6e44"},
+]
+
+[[package]]
+name = "click"
+version = "8.1.7"
+description = "Composable command line interface toolkit"
+optional = false
+python-versions = ">=3.7"
+files = [
+    {file = "click-8.1.7-py3-none-any.whl", hash = "sha256:ae74fb96c20a0277a1d615f1e4d73c8414f5a98db8b799a7931d1582f3390c28"},
+]
+
+[package.dependencies]
+colorama = {version = "*", markers = "platform_system == \"Windows\""}
+
+[[package]]
+name = "mylib"
+version = "0.1.0"
+description = ""
+optional = false
+python-versions = "^3.8"
+files = []
+develop = true
+
+[package.source]
+type = "directory"
+url = "../mylib"
+"#;
+
+    /// The format used by Poetry before 1.2.
+    const POETRY_LOCK_OLD: &str = r#"
+[[package]]
+name = "six"
+version = "1.16.0"
+description = "Python 2 and 3 compatibility utilities"
+category = "main"
+optional = false
+python-versions = ">=2.7, !=3.0.*, !=3.1.*, !=3.2.*"
+
+[[package]]
+name = "importlib-metadata"
+version = "6.8.0"
+description = "Read metadata from Python packages"
+category = "main"
+optional = false
+python-versions = ">=3.8"
+markers = "python_version < \"3.10\""
+
+[metadata]
+lock-version = "1.1"
+python-versions = "^3.8"
+content-hash = "abc"
+
+[metadata.files]
+six = [
+    {file = "six-1.16.0-py2.py3-none-any.whl", hash = "sha256:8abb2f1d86890a2dfb989f9a77cfcfd3e47c2a354b01111771326f8aa26e0254"},
+]
+importlib-metadata = []
+"#;
+
+    #[test]
+    fn poetry_lock_converted() {
+        let (packs, unconverted) = convert_poetry_lock(toml::from_str(POETRY_LOCK).unwrap());
+        let names: Vec<(&str, &str)> = packs
+            .iter()
+            .map(|p| (p.name.as_str(), p.version.as_str()))
+            .collect();
+        assert_eq!(names, vec![("click", "8.1.7"), ("colorama", "0.4.6")]);
+
+        let (click, colorama) = (&packs[0], &packs[1]);
+        assert_eq!(
+            click.dependencies,
+            Some(vec![
+                "colorama 0.4.6 pypi+https://pypi.org/pypi/colorama/0.4.6/json".to_owned()
+            ])
+        );
+        assert_eq!(click.marker, None);
+        // Only needed on Windows, through click.
+        assert_eq!(colorama.marker, Some("sys_platform == 'win32'".to_owned()));
+
+        let files = colorama.files.as_ref().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            files[0],
+            LockFile {
+                filename: "colorama-0.4.6-py2.py3-none-any.whl".into(),
+                url: "https://files.pythonhosted.org/packages/py2.py3/c/colorama/\
+                      colorama-0.4.6-py2.py3-none-any.whl"
+                    .into(),
+                sha256: "4f1d9991f5acc0ca119f9d443620b77f9d6b33703e51011c16baf57afb285fc6".into(),
+                packagetype: "bdist_wheel".into(),
+                python_version: "py2.py3".into(),
+                requires_python: Some(
+                    "!=3.0.*,!=3.1.*,!=3.2.*,!=3.3.*,!=3.4.*,!=3.5.*,!=3.6.*,>=2.7".into()
+                ),
+                platform: "any".into(),
+            }
+        );
+        assert_eq!(files[1].packagetype, "sdist");
+        assert_eq!(files[1].platform, "source");
+
+        assert_eq!(unconverted.len(), 1);
+        assert!(unconverted[0].contains("mylib 0.1.0 is a directory dependency"));
+    }
+
+    #[test]
+    fn old_poetry_lock_converted() {
+        let (packs, unconverted) = convert_poetry_lock(toml::from_str(POETRY_LOCK_OLD).unwrap());
+        assert!(unconverted.is_empty());
+
+        let (importlib, six) = (&packs[0], &packs[1]);
+        assert_eq!(importlib.marker, Some("python_version < '3.10'".to_owned()));
+        assert_eq!(importlib.files, None);
+        assert_eq!(six.files.as_ref().unwrap()[0].python_version, "py2.py3");
+        assert_eq!((importlib.id, six.id), (1, 2));
+    }
+}
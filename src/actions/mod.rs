@@ -1,19 +1,55 @@
+mod bundle;
+mod check;
 mod clear;
+mod compare;
+mod config;
+mod doctor;
+mod env;
+mod graph;
 mod init;
 mod install;
+mod licenses;
 mod list;
+mod lock;
+mod migrate;
 mod new;
+mod outdated;
 mod package;
 mod reset;
 mod run;
+mod sbom;
+mod scripts;
 mod switch;
+mod task;
+mod uninstall;
+mod verify;
+mod which;
+mod why_not;
 
+pub use bundle::bundle;
+pub use check::{check, check_config};
 pub use clear::clear;
+pub use compare::compare;
+pub use config::{config_get, config_set, config_unset};
+pub use doctor::doctor;
+pub use env::env;
+pub use graph::{graph, GraphFilter};
 pub use init::init;
-pub use install::install;
+pub use install::{active_extra_reqs, install, install_editable};
+pub use licenses::licenses;
 pub use list::list;
+pub use lock::{check_lock, lock, lock_for_target, lock_with_resolution, prune_lock};
+pub use migrate::migrate;
 pub use new::new;
+pub use outdated::outdated;
 pub use package::package;
 pub use reset::reset;
-pub use run::run;
+pub use run::{run, watch};
+pub use sbom::sbom;
+pub use scripts::regenerate_scripts;
 pub use switch::switch;
+pub use task::{list_tasks, task};
+pub use uninstall::uninstall_all;
+pub use verify::verify;
+pub use which::which;
+pub use why_not::why_not;
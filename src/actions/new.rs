@@ -1,6 +1,6 @@
 use std::{
     error::Error,
-    fs,
+    fs, io,
     path::{Path, PathBuf},
 };
 
@@ -8,10 +8,13 @@ use termcolor::Color;
 
 use crate::{
     commands,
+    dep_types::Version,
+    py_versions,
     util::{self, abort, success},
     Config,
 };
 
+// `pyflow.lock` isn't ignored: Commit it, so installs are reproducible.
 const GITIGNORE_INIT: &str = indoc::indoc! {r##"
 # General Python ignores
 build/
@@ -19,6 +22,7 @@ dist/
 __pycache__/
 __pypackages__/
 .ipynb_checkpoints/
+.pytest_cache/
 *.pyc
 *~
 */.mypy_cache/
@@ -32,8 +36,27 @@ Problem creating the project. This may be due to a permissions problem.
 If on linux, please try again with `sudo`.
 "#};
 
-pub fn new(name: &str) {
-    if new_internal(name).is_err() {
+/// Create a project folder named `name`. `py` is a Python version, or the path of an interpreter
+/// to use; we ask for the version if it's not specified, before creating anything.
+pub fn new(name: &str, py: Option<&str>) {
+    let (py_version, interpreter) = match py {
+        Some(py) => py_versions::parse_py_arg(py),
+        None => (
+            util::prompts::py_vers(&format!(
+                "Pass one with `--py`, eg `pyflow new {} --py 3.11`.",
+                name
+            )),
+            None,
+        ),
+    };
+    py_versions::check_supported(&py_version).unwrap_or_else(|e| {
+        abort(&format!(
+            "{} Pick another, eg `pyflow new {} --py 3.11`.",
+            e, name
+        ))
+    });
+
+    if new_internal(name, py_version, interpreter).is_err() {
         abort(NEW_ERROR_MESSAGE);
     }
     success(&format!("Created a new Python project named {}", name))
@@ -41,23 +64,27 @@ pub fn new(name: &str) {
 
 // TODO: Join this function after refactoring
 /// Create a template directory for a python project.
-fn new_internal(name: &str) -> Result<(), Box<dyn Error>> {
+fn new_internal(
+    name: &str,
+    py_version: Version,
+    interpreter: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
     if !PathBuf::from(name).exists() {
-        fs::create_dir_all(&format!("{}/{}", name, name.replace("-", "_")))?;
-        fs::File::create(&format!("{}/{}/__init__.py", name, name.replace("-", "_")))?;
-        fs::File::create(&format!("{}/README.md", name))?;
-        fs::File::create(&format!("{}/.gitignore", name))?;
+        fs::create_dir_all(format!("{}/{}", name, name.replace("-", "_")))?;
+        fs::File::create(format!("{}/{}/__init__.py", name, name.replace("-", "_")))?;
+        fs::File::create(format!("{}/README.md", name))?;
     }
 
     let readme_init = &format!("# {}\n\n{}", name, "(A description)");
 
-    fs::write(&format!("{}/.gitignore", name), GITIGNORE_INIT)?;
-    fs::write(&format!("{}/README.md", name), readme_init)?;
+    update_gitignore(Path::new(name))?;
+    fs::write(format!("{}/README.md", name), readme_init)?;
 
     let cfg = Config {
         name: Some(name.to_string()),
         authors: util::get_git_author(),
-        py_version: Some(util::prompts::py_vers()),
+        py_version: Some(py_version.into()),
+        py_interpreter: interpreter,
         ..Default::default()
     };
 
@@ -72,3 +99,60 @@ fn new_internal(name: &str) -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+/// Create a `.gitignore` in `dir`, or add the entries it's missing to an existing one.
+pub(super) fn update_gitignore(dir: &Path) -> io::Result<()> {
+    let path = dir.join(".gitignore");
+    if !path.exists() {
+        return fs::write(path, GITIGNORE_INIT);
+    }
+
+    let existing = fs::read_to_string(&path)?;
+    let updated = add_missing_entries(&existing);
+    if updated != existing {
+        fs::write(path, updated)?;
+    }
+    Ok(())
+}
+
+/// Append the entries from our template that `existing` doesn't already have.
+fn add_missing_entries(existing: &str) -> String {
+    let present: Vec<&str> = existing.lines().map(str::trim).collect();
+    let missing: Vec<&str> = GITIGNORE_INIT
+        .lines()
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter(|l| !present.contains(l))
+        .collect();
+
+    if missing.is_empty() {
+        return existing.to_owned();
+    }
+
+    let mut result = existing.to_owned();
+    if !result.is_empty() && !result.ends_with('\n') {
+        result.push('\n');
+    }
+    result.push_str("\n# Added by pyflow\n");
+    for entry in missing {
+        result.push_str(entry);
+        result.push('\n');
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gitignore_entries_added_once() {
+        let existing = "*.pyc\n.env";
+        let updated = add_missing_entries(existing);
+
+        assert!(updated.starts_with("*.pyc\n.env\n"));
+        assert!(updated.contains("__pypackages__/\n"));
+        assert_eq!(updated.matches("*.pyc").count(), 1);
+        assert!(!updated.contains("pyflow.lock"));
+        assert_eq!(add_missing_entries(&updated), updated);
+    }
+}
@@ -0,0 +1,252 @@
+use std::str::FromStr;
+
+use serde::Serialize;
+use termcolor::Color;
+
+use crate::{
+    dep_resolution::{self, FetchCache},
+    dep_types::{Constraint, LockPackage, Req, ReqType, Version},
+    util::{self, abort, print_color},
+    CliConfig,
+};
+
+/// Whether the requirements resolve with an upgrade applied.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Resolution {
+    Clean,
+    /// These packages would need more than one version, so be installed renamed.
+    Conflicts {
+        packages: Vec<String>,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Outdated {
+    pub name: String,
+    pub dev: bool,
+    /// As in `pyproject.toml`, eg `^1.2.3`.
+    pub constraint: String,
+    /// The version in `pyflow.lock`.
+    pub current: Option<String>,
+    pub latest: String,
+    /// The latest version the constraint allows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latest_compatible: Option<String>,
+    /// If the constraint excludes `latest`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub constraint_update_needed: Option<bool>,
+    /// A constraint that allows `latest`, with the same operators.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggested_constraint: Option<String>,
+    /// With the requirement pinned to `latest`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolution: Option<Resolution>,
+}
+
+fn constraint_string(constraints: &[Constraint]) -> String {
+    if constraints.is_empty() {
+        return "*".into();
+    }
+    constraints
+        .iter()
+        .map(|c| c.to_string2(true, false))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Constraints that allow `latest`: Those that exclude it get its version with the same
+/// operator, eg `^1.2` becomes `^2.0.1`. Upper bounds and exclusions that can't are dropped.
+pub fn suggest_constraint(constraints: &[Constraint], latest: &Version) -> Vec<Constraint> {
+    constraints
+        .iter()
+        .filter_map(|c| {
+            if c.is_compatible(latest) {
+                return Some(c.clone());
+            }
+            match c.type_ {
+                ReqType::Lt | ReqType::Gt | ReqType::Ne => None,
+                type_ => Some(Constraint::new(type_, latest.clone())),
+            }
+        })
+        .collect()
+}
+
+/// The newest stable version, or the newest pre-release if there are no stable ones, or the
+/// req allows them.
+fn newest<'a>(versions: impl Iterator<Item = &'a Version>, pre: bool) -> Option<&'a Version> {
+    let versions: Vec<&Version> = versions.collect();
+    versions
+        .iter()
+        .filter(|v| pre || !v.is_prerelease())
+        .max()
+        .or_else(|| versions.iter().max())
+        .copied()
+}
+
+/// Compare each direct requirement with the latest version on PyPI. With
+/// `respect_constraints`, also find the latest version its constraint allows, a constraint
+/// that allows the latest, and whether the requirements resolve with it.
+pub fn find_outdated(
+    reqs: &[Req],
+    dev_reqs: &[Req],
+    lockpacks: &[LockPackage],
+    os: util::Os,
+    py_vers: &Version,
+    respect_constraints: bool,
+) -> Vec<Outdated> {
+    let mut cache = FetchCache::default();
+    let all_reqs: Vec<(&Req, bool)> = reqs
+        .iter()
+        .map(|r| (r, false))
+        .chain(dev_reqs.iter().map(|r| (r, true)))
+        .filter(|(r, _)| r.git.is_none() && r.path.is_none() && r.url.is_none())
+        .collect();
+    let combined: Vec<Req> = all_reqs.iter().map(|(r, _)| (*r).clone()).collect();
+
+    let mut result = vec![];
+    for (req, dev) in &all_reqs {
+        let versions = match cache.versions(&req.name, py_vers) {
+            Ok(v) => v,
+            Err(e) => {
+                print_color(&e.to_string(), Color::Yellow);
+                continue;
+            }
+        };
+        let pre = req.allows_prereleases();
+        let latest = match newest(versions.iter(), pre) {
+            Some(v) => v.clone(),
+            None => continue,
+        };
+        let current = lockpacks
+            .iter()
+            .find(|lp| lp.rename.is_none() && util::compare_names(&lp.name, &req.name))
+            .and_then(|lp| Version::from_str(&lp.version).ok());
+        if current.as_ref().is_some_and(|c| *c >= latest) {
+            continue;
+        }
+
+        let mut outdated = Outdated {
+            name: req.name.clone(),
+            dev: *dev,
+            constraint: constraint_string(&req.constraints),
+            current: current.map(|v| v.to_string()),
+            latest: latest.to_string(),
+            latest_compatible: None,
+            constraint_update_needed: None,
+            suggested_constraint: None,
+            resolution: None,
+        };
+        if respect_constraints {
+            let allows = |v: &Version| req.constraints.iter().all(|c| c.is_compatible(v));
+            let compatible = newest(versions.iter().filter(|v| allows(v)), pre);
+            let update_needed = !allows(&latest);
+            outdated.latest_compatible = compatible.map(|v| v.to_string());
+            outdated.constraint_update_needed = Some(update_needed);
+            outdated.suggested_constraint = update_needed
+                .then(|| constraint_string(&suggest_constraint(&req.constraints, &latest)));
+
+            let upgraded: Vec<Req> = combined
+                .iter()
+                .map(|r| {
+                    let mut r = r.clone();
+                    if util::compare_names(&r.name, &req.name) {
+                        r.constraints = vec![Constraint::new(ReqType::Exact, latest.clone())];
+                    }
+                    r
+                })
+                .collect();
+            outdated.resolution = Some(
+                match dep_resolution::try_resolve(&upgraded, os, py_vers, &mut cache) {
+                    Ok(c) if c.is_empty() => Resolution::Clean,
+                    Ok(packages) => Resolution::Conflicts { packages },
+                    Err(e) => Resolution::Failed { error: e.details },
+                },
+            );
+        }
+        result.push(outdated);
+    }
+    result
+}
+
+fn print_outdated(outdated: &[Outdated]) {
+    if outdated.is_empty() {
+        print_color("All requirements are up to date.", Color::Green);
+        return;
+    }
+    for o in outdated {
+        let mut line = format!(
+            "{} ({}): {} -> {}",
+            o.name,
+            o.constraint,
+            o.current.as_deref().unwrap_or("not locked"),
+            o.latest
+        );
+        if let Some(compatible) = &o.latest_compatible {
+            line.push_str(&format!(", {} allowed", compatible));
+        }
+        if let Some(suggested) = &o.suggested_constraint {
+            line.push_str(&format!(", needs `{}`", suggested));
+        }
+        let color = match &o.resolution {
+            None | Some(Resolution::Clean) => Color::White,
+            Some(Resolution::Conflicts { packages }) => {
+                line.push_str(&format!(", conflicts on {}", packages.join(", ")));
+                Color::Yellow
+            }
+            Some(Resolution::Failed { error }) => {
+                line.push_str(&format!(", doesn't resolve: {}", error));
+                Color::Red
+            }
+        };
+        print_color(&line, color);
+    }
+}
+
+/// Report direct requirements with newer versions. See `find_outdated`.
+pub fn outdated(
+    reqs: &[Req],
+    dev_reqs: &[Req],
+    lockpacks: &[LockPackage],
+    os: util::Os,
+    py_vers: &Version,
+    respect_constraints: bool,
+) {
+    let outdated = find_outdated(reqs, dev_reqs, lockpacks, os, py_vers, respect_constraints);
+    if CliConfig::current().json {
+        let data = serde_json::to_string_pretty(&outdated)
+            .unwrap_or_else(|_| abort("Problem serializing the report"));
+        println!("{}", data);
+    } else {
+        print_outdated(&outdated);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest(
+        constraints,
+        latest,
+        expected,
+        case("^1.2", "2.0.1", "^2.0.1"),
+        case("~=1.4", "2.0.0", "~=2.0.0"),
+        case(">=1.0, <2.0", "2.1.0", ">=1.0"),
+        case("==3.1.2", "3.2.0", "3.2.0"),
+        case(">=2.0, !=3.0.0", "3.0.0", ">=2.0")
+    )]
+    fn suggested_constraints(constraints: &str, latest: &str, expected: &str) {
+        let constraints = Constraint::from_str_multiple(constraints).unwrap();
+        let latest = Version::from_str(latest).unwrap();
+        assert_eq!(
+            constraint_string(&suggest_constraint(&constraints, &latest)),
+            expected
+        );
+    }
+}
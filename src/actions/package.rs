@@ -2,10 +2,11 @@ use std::path::Path;
 
 use crate::{
     build,
-    dep_types::{LockPackage, Version},
+    dep_types::{LockPackage, Req, Version},
     util::{self, deps::sync},
 };
 
+#[allow(clippy::too_many_arguments)]
 pub fn package(
     paths: &util::Paths,
     lockpacks: &[LockPackage],
@@ -13,17 +14,20 @@ pub fn package(
     py_vers: &Version,
     lock_path: &Path,
     cfg: &crate::Config,
+    reqs: &[Req],
     extras: &[String],
+    prune_foreign: bool,
 ) {
     sync(
         paths,
         lockpacks,
-        &cfg.reqs,
+        reqs,
         &cfg.dev_reqs,
-        &util::find_dont_uninstall(&cfg.reqs, &cfg.dev_reqs),
+        &util::find_dont_uninstall(&cfg.reqs, &cfg.dev_reqs, &cfg.preserve),
         os,
         py_vers,
         lock_path,
+        prune_foreign,
     );
 
     build::build(lockpacks, paths, cfg, extras)
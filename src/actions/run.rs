@@ -1,52 +1,57 @@
-use std::path::Path;
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus},
+    sync::atomic::Ordering,
+    thread,
+    time::Instant,
+};
 
-use regex::Regex;
+use termcolor::Color;
 
-use crate::{commands, pyproject::Config, util::abort};
+use super::list::find_console_scripts;
+use crate::{
+    cli_options::ExternalCommand,
+    commands,
+    pyproject::Config,
+    util::{
+        abort, interrupt, print_color,
+        watch::{self, Debounce, Watcher},
+    },
+};
 
 /// Execute a python CLI tool, either specified in `pyproject.toml`, or in a dependency.
-pub fn run(lib_path: &Path, bin_path: &Path, vers_path: &Path, cfg: &Config, args: Vec<String>) {
+pub fn run(
+    pythonpath: &[PathBuf],
+    bin_path: &Path,
+    entry_pt_path: &Path,
+    cfg: &Config,
+    args: Vec<String>,
+) {
     // Allow both `pyflow run ipython` (args), and `pyflow ipython` (opt.script)
     if args.is_empty() {
         return;
     }
 
-    let name = if let Some(a) = args.get(0) {
+    let name = if let Some(a) = args.first() {
         a.clone()
     } else {
         abort("`run` must be followed by the script to run, eg `pyflow run black`");
     };
 
-    // If the script we're calling is specified in `pyproject.toml`, ensure it exists.
-
-    // todo: Delete these scripts as required to sync with pyproject.toml.
-    let re = Regex::new(r"(.*?):(.*)").unwrap();
-
     let mut specified_args: Vec<String> = args.into_iter().skip(1).collect();
 
     // If a script name is specified by by this project and a dependency, favor
     // this project.
-    if let Some(s) = cfg.scripts.get(&name) {
-        let abort_msg = format!(
-            "Problem running the function {}, specified in `pyproject.toml`",
-            name,
+    if cfg.scripts.contains_key(&name) {
+        super::task(
+            &name,
+            &specified_args,
+            bin_path,
+            entry_pt_path,
+            pythonpath,
+            &cfg.scripts,
         );
-
-        if let Some(caps) = re.captures(s) {
-            let module = caps.get(1).unwrap().as_str();
-            let function = caps.get(2).unwrap().as_str();
-            let mut args_to_pass = vec![
-                "-c".to_owned(),
-                format!(r#"import {}; {}.{}()"#, module, module, function),
-            ];
-
-            args_to_pass.append(&mut specified_args);
-            if commands::run_python(bin_path, &[lib_path.to_owned()], &args_to_pass).is_err() {
-                abort(&abort_msg);
-            }
-        } else {
-            abort(&format!("Problem parsing the following script: {:#?}. Must be in the format module:function_name", s));
-        }
         return;
     }
     //            None => {
@@ -55,9 +60,18 @@ pub fn run(lib_path: &Path, bin_path: &Path, vers_path: &Path, cfg: &Config, arg
          Try running `pyflow install {}`",
         name, name
     );
-    let script_path = vers_path.join("bin").join(name);
+    let script_path = entry_pt_path.join(&name);
     if !script_path.exists() {
-        abort(&abort_msg);
+        let mut available = find_console_scripts(entry_pt_path);
+        available.sort();
+        if available.is_empty() {
+            abort(&abort_msg);
+        }
+        abort(&format!(
+            "{} These console scripts are installed: {}",
+            abort_msg,
+            available.join(", ")
+        ));
     }
 
     let mut args_to_pass = vec![script_path
@@ -66,7 +80,115 @@ pub fn run(lib_path: &Path, bin_path: &Path, vers_path: &Path, cfg: &Config, arg
         .to_owned()];
 
     args_to_pass.append(&mut specified_args);
-    if commands::run_python(bin_path, &[lib_path.to_owned()], &args_to_pass).is_err() {
+    if commands::run_python(bin_path, pythonpath, &args_to_pass).is_err() {
         abort(&abort_msg);
     }
 }
+
+/// `pyflow run --watch`: Run the command, then again each time the watched files change,
+/// stopping a run still in progress unless `--no-kill` is passed. Each run is a `pyflow run` of
+/// its own, started with `globals`, ie the options before `run`. Exits on Ctrl-C, with the
+/// last run's exit code.
+pub fn watch(
+    project_path: &Path,
+    project_name: Option<&str>,
+    cmd: &ExternalCommand,
+    globals: &[String],
+) -> ! {
+    let globs = match &cmd.watch {
+        Some(g) if !g.is_empty() => g.clone(),
+        _ => watch::default_globs(project_path, project_name),
+    };
+    let watcher = Watcher::new(project_path, &globs).unwrap_or_else(|e| abort(&e));
+
+    let exe = env::current_exe().unwrap_or_else(|_| abort("Can't find pyflow's executable"));
+    let mut command = Command::new(exe);
+    command.args(globals).arg("run");
+    for var in &cmd.env {
+        command.args(["--env", var]);
+    }
+    if let Some(dotenv) = &cmd.dotenv {
+        command.arg("--dotenv").arg(dotenv);
+    }
+    command.arg("--").args(&cmd.args);
+
+    print_color(
+        &format!(
+            "[{}] Running `{}`, and again when {} change",
+            watch::timestamp(),
+            cmd.args.join(" "),
+            globs.join(", ")
+        ),
+        Color::Cyan,
+    );
+    let mut snapshot = watcher.snapshot();
+    let mut debounce = Debounce::new(watch::QUIET_PERIOD);
+    let mut running = Some(start(&mut command));
+    let mut queued: Option<PathBuf> = None;
+    loop {
+        thread::sleep(watch::POLL_INTERVAL);
+        if let Some(child) = &mut running {
+            if let Ok(Some(status)) = child.try_wait() {
+                finished(status);
+                running = None;
+            }
+        }
+
+        let now = Instant::now();
+        let latest = watcher.snapshot();
+        for path in watch::changes(&snapshot, &latest) {
+            debounce.changed(&path, now);
+        }
+        snapshot = latest;
+        if let Some(path) = debounce.ready(now) {
+            queued = Some(path);
+        }
+        if queued.is_none() {
+            continue;
+        }
+
+        if let Some(mut child) = running.take() {
+            if !cmd.kill {
+                running = Some(child);
+                continue;
+            }
+            interrupt::flags().watched.store(0, Ordering::SeqCst);
+            commands::stop_group(child.id());
+            child.wait().ok();
+        }
+        let path = queued.take().unwrap();
+        print_color(
+            &format!(
+                "[{}] {} changed; running again",
+                watch::timestamp(),
+                path.to_string_lossy()
+            ),
+            Color::Cyan,
+        );
+        running = Some(start(&mut command));
+    }
+}
+
+fn start(command: &mut Command) -> Child {
+    let child = commands::spawn_group(command).unwrap_or_else(|_| abort("Problem running pyflow"));
+    let interrupt = interrupt::flags();
+    interrupt.watched.store(child.id(), Ordering::SeqCst);
+    interrupt.exit_code.store(130, Ordering::SeqCst);
+    child
+}
+
+fn finished(status: ExitStatus) {
+    let interrupt = interrupt::flags();
+    interrupt.watched.store(0, Ordering::SeqCst);
+    let code = status.code().unwrap_or(1);
+    interrupt.exit_code.store(code, Ordering::SeqCst);
+    let (outcome, color) = if status.success() {
+        ("Finished".to_owned(), Color::Green)
+    } else {
+        (format!("Failed with exit code {}", code), Color::Red)
+    };
+    print_color(
+        &format!("[{}] {}; waiting for changes", watch::timestamp(), outcome),
+        color,
+    );
+}
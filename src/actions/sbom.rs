@@ -0,0 +1,363 @@
+//! A [CycloneDX](https://cyclonedx.org/docs/1.5/json/) software bill of materials, built from
+//! `pyflow.lock`. Nothing is downloaded or installed.
+
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+use termcolor::Color;
+
+use crate::{
+    dep_types::LockPackage,
+    pyproject::Config,
+    util::{self, abort, dep_graph::DepGraph, deps::prune_lock, print_color},
+};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bom {
+    pub bom_format: String,
+    pub spec_version: String,
+    pub version: u32,
+    pub metadata: Metadata,
+    pub components: Vec<Component>,
+    pub dependencies: Vec<Dependency>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Metadata {
+    pub tools: Tools,
+    pub component: Component,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Tools {
+    pub components: Vec<Component>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Component {
+    #[serde(rename = "type")]
+    pub type_: String,
+    #[serde(rename = "bom-ref", skip_serializing_if = "Option::is_none")]
+    pub bom_ref: Option<String>,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// `excluded` for dev dependencies.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purl: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hashes: Vec<Hash>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub external_references: Vec<ExternalReference>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub properties: Vec<Property>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Hash {
+    pub alg: String,
+    pub content: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ExternalReference {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hashes: Vec<Hash>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Property {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Dependency {
+    #[serde(rename = "ref")]
+    pub ref_: String,
+    pub depends_on: Vec<String>,
+}
+
+fn property(name: &str, value: &str) -> Property {
+    Property {
+        name: format!("pyflow:{}", name),
+        value: value.to_owned(),
+    }
+}
+
+fn sha256(content: &str) -> Hash {
+    Hash {
+        alg: "SHA-256".into(),
+        content: content.to_owned(),
+    }
+}
+
+/// Percent-encode a purl qualifier value.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// eg `pkg:pypi/python-dateutil@2.8.2`. Names are normalized as PyPi's purl type requires.
+fn purl(name: &str, version: Option<&str>) -> String {
+    let name = name.to_lowercase().replace(['_', '.'], "-");
+    match version {
+        Some(v) => format!("pkg:pypi/{}@{}", name, v.replace('+', "%2B")),
+        None => format!("pkg:pypi/{}", name),
+    }
+}
+
+fn lock_component(lp: &LockPackage, dev: bool) -> Component {
+    let mut files: Vec<_> = lp.files.iter().flatten().collect();
+    files.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+    let mut properties = Vec::new();
+    if let Some(marker) = &lp.marker {
+        properties.push(property("marker", marker));
+    }
+    if let Some(rename) = &lp.rename {
+        properties.push(property("renamed", rename));
+    }
+
+    let purl = purl(&lp.name, Some(&lp.version));
+    Component {
+        type_: "library".into(),
+        bom_ref: Some(purl.clone()),
+        name: lp.name.clone(),
+        version: Some(lp.version.clone()),
+        scope: dev.then(|| "excluded".into()),
+        purl: Some(purl),
+        hashes: files.iter().map(|f| sha256(&f.sha256)).collect(),
+        external_references: files
+            .iter()
+            .map(|f| ExternalReference {
+                type_: "distribution".into(),
+                url: f.url.clone(),
+                hashes: vec![sha256(&f.sha256)],
+            })
+            .collect(),
+        properties,
+    }
+}
+
+/// Build the SBOM. Lock packages only needed by `dev-dependencies` are scoped `excluded`; git
+/// and path requirements, which aren't in the lock, are listed without versions.
+pub fn make_bom(cfg: &Config, lockpacks: &[LockPackage]) -> Bom {
+    let runtime_reqs: Vec<_> = cfg
+        .reqs
+        .iter()
+        .chain(cfg.extras.values().flatten())
+        .cloned()
+        .collect();
+    let runtime = prune_lock(lockpacks, &runtime_reqs, &[]);
+    let is_dev = |lp: &LockPackage| {
+        !runtime
+            .iter()
+            .any(|r| r.name == lp.name && r.version == lp.version)
+    };
+
+    let mut components: Vec<Component> = lockpacks
+        .iter()
+        .map(|lp| lock_component(lp, is_dev(lp)))
+        .collect();
+    let mut dependencies = Vec::new();
+
+    let name = cfg.name.clone().unwrap_or_else(|| "project".into());
+    let root_ref = format!("project:{}", name);
+    let mut root_deps = Vec::new();
+
+    for (req, dev) in cfg
+        .reqs
+        .iter()
+        .map(|r| (r, false))
+        .chain(cfg.dev_reqs.iter().map(|r| (r, true)))
+    {
+        let mut component = Component {
+            type_: "library".into(),
+            name: req.name.clone(),
+            scope: dev.then(|| "excluded".into()),
+            ..Default::default()
+        };
+        if let Some(git) = &req.git {
+            let git_url = format!("git+{}", git.trim_start_matches("git+"));
+            component.bom_ref = Some(format!("git:{}", req.name));
+            component.purl = Some(format!(
+                "{}?vcs_url={}",
+                purl(&req.name, None),
+                encode(&git_url)
+            ));
+            component.external_references.push(ExternalReference {
+                type_: "vcs".into(),
+                url: git.clone(),
+                hashes: vec![],
+            });
+            component.properties.push(property("unversioned", "true"));
+        } else if let Some(path) = &req.path {
+            component.bom_ref = Some(format!("path:{}", req.name));
+            component.properties.push(property("source", path));
+            component.properties.push(property("unversioned", "true"));
+        } else {
+            // Direct reqs resolve to the locked packages with their name that weren't renamed.
+            root_deps.extend(
+                lockpacks
+                    .iter()
+                    .filter(|lp| lp.rename.is_none() && util::compare_names(&lp.name, &req.name))
+                    .map(|lp| purl(&lp.name, Some(&lp.version))),
+            );
+            continue;
+        }
+        root_deps.push(component.bom_ref.clone().unwrap());
+        components.push(component);
+    }
+    root_deps.sort();
+    root_deps.dedup();
+    dependencies.push(Dependency {
+        ref_: root_ref.clone(),
+        depends_on: root_deps,
+    });
+
+    let graph = DepGraph::from_lock(lockpacks);
+    for (i, lp) in lockpacks.iter().enumerate() {
+        let mut depends_on: Vec<String> = graph
+            .deps(i)
+            .iter()
+            .map(|&j| purl(&lockpacks[j].name, Some(&lockpacks[j].version)))
+            .collect();
+        depends_on.sort();
+        depends_on.dedup();
+        dependencies.push(Dependency {
+            ref_: purl(&lp.name, Some(&lp.version)),
+            depends_on,
+        });
+    }
+
+    Bom {
+        bom_format: "CycloneDX".into(),
+        spec_version: "1.5".into(),
+        version: 1,
+        metadata: Metadata {
+            tools: Tools {
+                components: vec![Component {
+                    type_: "application".into(),
+                    name: "pyflow".into(),
+                    version: Some(env!("CARGO_PKG_VERSION").into()),
+                    ..Default::default()
+                }],
+            },
+            component: Component {
+                type_: "application".into(),
+                bom_ref: Some(root_ref),
+                name,
+                version: cfg.version.as_ref().map(|v| v.to_string()),
+                ..Default::default()
+            },
+        },
+        components,
+        dependencies,
+    }
+}
+
+/// Write an SBOM for the locked packages to `output`, or print it.
+pub fn sbom(cfg: &Config, lock_path: &Path, format: &str, output: Option<&Path>) {
+    if format != "cyclonedx-json" {
+        abort(&format!("Unsupported SBOM format: {}", format));
+    }
+    let lock = match util::read_lock(lock_path) {
+        Ok(l) => l,
+        Err(_) => abort("Can't read `pyflow.lock`. Run `pyflow install` to create it."),
+    };
+    let bom = make_bom(cfg, &lock.package.unwrap_or_default());
+    let data = serde_json::to_string_pretty(&bom).expect("Problem serializing the SBOM");
+
+    match output {
+        Some(path) => {
+            if fs::write(path, data + "\n").is_err() {
+                abort(&format!("Problem writing {}", path.display()));
+            }
+            print_color(
+                &format!("Wrote an SBOM to {}", path.display()),
+                Color::Green,
+            );
+        }
+        None => println!("{}", data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dep_types::{Lock, Req, Version};
+
+    const LOCK: &str = r#"
+[metadata]
+
+[[package]]
+id = 1
+name = "requests"
+version = "2.31.0"
+dependencies = ["urllib3 2.0.7 pypi+https://pypi.org/pypi/urllib3/2.0.7/json"]
+
+[[package.files]]
+filename = "requests-2.31.0-py3-none-any.whl"
+url = "https://files.pythonhosted.org/requests-2.31.0-py3-none-any.whl"
+sha256 = "58cd2187c01e70e6e26505bca751777aa9f2ee0b7f4300988b709f44e013003f"
+packagetype = "bdist_wheel"
+python_version = "py3"
+platform = "any"
+
+[[package]]
+id = 2
+name = "urllib3"
+version = "2.0.7"
+dependencies = []
+
+[[package]]
+id = 3
+name = "Py_Test"
+version = "7.4.3+local"
+marker = "python_version >= '3.8'"
+dependencies = []
+"#;
+
+    #[test]
+    fn golden_cyclonedx() {
+        let lock: Lock = toml::from_str(LOCK).unwrap();
+        let mut git = Req::new("mylib".into(), vec![]);
+        git.git = Some("https://github.com/me/mylib.git".into());
+        let mut path = Req::new("local-tools".into(), vec![]);
+        path.path = Some("../tools".into());
+        let cfg = Config {
+            name: Some("demo".into()),
+            version: Some(Version::new(0, 1, 0)),
+            reqs: vec![Req::new("requests".into(), vec![]), git],
+            dev_reqs: vec![Req::new("py-test".into(), vec![]), path],
+            ..Default::default()
+        };
+
+        let bom = make_bom(&cfg, &lock.package.unwrap());
+        let mut actual = serde_json::to_value(&bom).unwrap();
+        actual["metadata"]["tools"]["components"][0]["version"] = "VERSION".into();
+        let expected: serde_json::Value =
+            serde_json::from_str(include_str!("testdata/sbom.cdx.json")).unwrap();
+        assert_eq!(actual, expected);
+
+        // The output reads back into the model unchanged.
+        let data = serde_json::to_string(&bom).unwrap();
+        assert_eq!(serde_json::from_str::<Bom>(&data).unwrap(), bom);
+    }
+}
@@ -0,0 +1,22 @@
+use std::path::Path;
+
+use termcolor::Color;
+
+use crate::{install, util};
+
+/// Create the console scripts of all installed packages, from the entry points in their
+/// `dist-info` folders. Eg after installing with `--no-scripts`, or to restore scripts that were
+/// kept from a conflicting package that's since been uninstalled.
+pub fn regenerate_scripts(lib_path: &Path, entry_pt_path: &Path) {
+    let installed = util::find_installed(lib_path);
+    for (name, version, _) in &installed {
+        install::write_scripts(name, version, lib_path, entry_pt_path);
+    }
+    util::print_color(
+        &format!(
+            "Regenerated console scripts for {} installed packages",
+            installed.len()
+        ),
+        Color::Green,
+    );
+}
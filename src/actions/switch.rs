@@ -2,18 +2,47 @@ use std::{path::PathBuf, process};
 
 use termcolor::Color;
 
-use crate::{files, pyproject, util};
+use crate::{files, py_versions, pyproject, util};
 
-/// Updates `pyproject.toml` with a new python version
+/// Updates `pyproject.toml` with a new python version, or the path of an interpreter to use.
 pub fn switch(version: &str) {
     let mut pcfg = pyproject::current::get_config().unwrap_or_else(|| process::exit(1));
+    files::freeze_in_ci(pcfg.config.frozen);
 
-    let specified = util::fallible_v_parse(version);
-    pcfg.config.py_version = Some(specified.clone());
+    let (specified, interpreter) = py_versions::parse_py_arg(version);
+    py_versions::check_supported(&specified).unwrap_or_else(|e| {
+        util::abort(&format!(
+            "{} Switch to another, eg `pyflow switch 3.11`.",
+            e
+        ))
+    });
+    pcfg.config.py_version = Some(match &pcfg.config.py_version {
+        Some(current) => current.with_version(&specified),
+        None => specified.clone().into(),
+    });
     files::change_py_vers(&PathBuf::from(&pcfg.config_path), &specified);
-    util::print_color(
-        &format!("Switched to Python version {}", specified.to_string()),
-        Color::Green,
-    );
+    files::change_py_interpreter(&pcfg.config_path, interpreter.as_deref());
+    match &interpreter {
+        Some(path) => util::print_color(
+            &format!(
+                "Switched to Python {}, at {}",
+                specified,
+                path.to_string_lossy()
+            ),
+            Color::Green,
+        ),
+        None => util::print_color(
+            &format!("Switched to Python version {}", specified),
+            Color::Green,
+        ),
+    }
+    // An existing environment keeps the interpreter it was made with.
+    let venv_path = pcfg.pypackages_path.join(specified.to_string_med());
+    if interpreter.is_some() && venv_path.join(".venv").exists() {
+        util::print_color(
+            "Run with `--rebuild-venv` to rebuild this version's environment with it",
+            Color::Yellow,
+        );
+    }
     // Don't exit program here; now that we've changed the cfg version, let's run the normal flow.
 }
@@ -0,0 +1,245 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use termcolor::Color;
+
+use crate::{
+    commands,
+    pyproject::{Script, ScriptCmd},
+    util::{self, abort, print_color, print_color_},
+};
+
+/// List the tasks in `[tool.pyflow.scripts]`, with their definitions.
+pub fn list_tasks(scripts: &HashMap<String, Script>) {
+    if scripts.is_empty() {
+        print_color(
+            "No tasks are specified in `[tool.pyflow.scripts]` in `pyproject.toml`.",
+            Color::Blue,
+        );
+        return;
+    }
+
+    let mut names: Vec<&String> = scripts.keys().collect();
+    names.sort();
+    for name in names {
+        print_color_(name, Color::Cyan);
+        println!(" = {}", scripts[name].to_cfg_value());
+    }
+}
+
+/// Run a task from `[tool.pyflow.scripts]`, after the tasks it depends on. We stop at the
+/// first failure, and exit with its code. `args` are only passed to the task requested.
+pub fn task(
+    name: &str,
+    args: &[String],
+    bin_path: &Path,
+    entry_pt_path: &Path,
+    pythonpath: &[PathBuf],
+    scripts: &HashMap<String, Script>,
+) {
+    let order = task_order(scripts, name).unwrap_or_else(|e| abort(&e));
+
+    for task_name in &order {
+        let cmd = match &scripts[task_name].cmd {
+            Some(c) => c,
+            None => continue,
+        };
+        if order.len() > 1 {
+            print_color(&format!("Running task `{}`", task_name), Color::Cyan);
+        }
+
+        let task_args = if task_name == name { args } else { &[] };
+        let status = match cmd {
+            ScriptCmd::Function(module, function) => {
+                let mut args_to_pass = vec![
+                    "-c".to_owned(),
+                    format!(r#"import {}; {}.{}()"#, module, module, function),
+                ];
+                args_to_pass.extend_from_slice(task_args);
+                commands::run_python(bin_path, pythonpath, &args_to_pass)
+            }
+            ScriptCmd::Shell(line) => {
+                let line = shell_line(line, task_args, entry_pt_path);
+                commands::run_shell(bin_path, pythonpath, &line)
+            }
+        };
+
+        match status {
+            Ok(s) if s.success() => (),
+            Ok(s) => {
+                print_color(&format!("Task `{}` failed", task_name), Color::Red);
+                util::exit(s.code().unwrap_or(1));
+            }
+            Err(_) => abort(&format!("Problem running the task `{}`", task_name)),
+        }
+    }
+}
+
+/// Append CLI args, quoted, to a task's command line. Console scripts from dependencies aren't
+/// executable, so we run them with the environment's Python, as `pyflow run` does.
+fn shell_line(line: &str, args: &[String], entry_pt_path: &Path) -> String {
+    let line = line.trim_start();
+    let program = line.split_whitespace().next().unwrap_or_default();
+    let script_path = entry_pt_path.join(program);
+    let mut result = if !program.is_empty() && script_path.is_file() {
+        format!(
+            "python {}{}",
+            commands::shell_quote(&script_path.to_string_lossy()),
+            &line[program.len()..]
+        )
+    } else {
+        line.to_owned()
+    };
+    for arg in args {
+        result.push(' ');
+        result.push_str(&commands::shell_quote(arg));
+    }
+    result
+}
+
+/// Find the order to run a task and its dependencies in, so each runs once, after the
+/// tasks it depends on.
+fn task_order(scripts: &HashMap<String, Script>, name: &str) -> Result<Vec<String>, String> {
+    fn visit(
+        scripts: &HashMap<String, Script>,
+        name: &str,
+        stack: &mut Vec<String>,
+        order: &mut Vec<String>,
+    ) -> Result<(), String> {
+        if order.iter().any(|n| n == name) {
+            return Ok(());
+        }
+        if stack.iter().any(|n| n == name) {
+            return Err(format!(
+                "Tasks in `pyproject.toml` depend on each other in a cycle: {} -> {}",
+                stack.join(" -> "),
+                name
+            ));
+        }
+
+        let script = scripts.get(name).ok_or_else(|| match stack.last() {
+            Some(parent) => format!(
+                "The task `{}` depends on `{}`, which isn't in `[tool.pyflow.scripts]`",
+                parent, name
+            ),
+            None => format!(
+                "Can't find the task `{}` in `[tool.pyflow.scripts]`. \
+                 Run `pyflow task --list` to see available tasks.",
+                name
+            ),
+        })?;
+
+        stack.push(name.to_owned());
+        for dep in &script.depends_on {
+            visit(scripts, dep, stack, order)?;
+        }
+        stack.pop();
+        order.push(name.to_owned());
+        Ok(())
+    }
+
+    let mut order = vec![];
+    visit(scripts, name, &mut vec![], &mut order)?;
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn make_scripts(tasks: &[(&str, &[&str])]) -> HashMap<String, Script> {
+        tasks
+            .iter()
+            .map(|(name, deps)| {
+                let mut script = Script::new("true");
+                script.depends_on = deps.iter().map(|d| d.to_string()).collect();
+                (name.to_string(), script)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dependencies_run_first_and_once() {
+        let scripts = make_scripts(&[
+            ("fmt", &[]),
+            ("lint", &["fmt"]),
+            ("test", &["fmt"]),
+            ("ci", &["lint", "test"]),
+        ]);
+
+        assert_eq!(
+            task_order(&scripts, "ci").unwrap(),
+            vec!["fmt", "lint", "test", "ci"]
+        );
+        assert_eq!(task_order(&scripts, "fmt").unwrap(), vec!["fmt"]);
+    }
+
+    #[test]
+    fn bad_dependencies() {
+        let scripts = make_scripts(&[("a", &["b"]), ("b", &["a"]), ("c", &["missing"])]);
+
+        assert!(task_order(&scripts, "a")
+            .unwrap_err()
+            .contains("a -> b -> a"));
+        assert!(task_order(&scripts, "c").unwrap_err().contains("`missing`"));
+        assert!(task_order(&scripts, "nope").is_err());
+    }
+
+    #[test]
+    fn shell_lines() {
+        let tmp = tempfile::tempdir().unwrap();
+        let entry_pt = tmp.path().to_owned();
+        fs::write(entry_pt.join("black"), "").unwrap();
+        let script = commands::shell_quote(&entry_pt.join("black").to_string_lossy());
+
+        // Only the program is replaced, not the first match of its name.
+        assert_eq!(
+            shell_line("black --check black.py", &[], &entry_pt),
+            format!("python {} --check black.py", script)
+        );
+        assert_eq!(
+            shell_line("  pytest -x", &["-k".into(), "a b".into()], &entry_pt),
+            format!(
+                "pytest -x {} {}",
+                commands::shell_quote("-k"),
+                commands::shell_quote("a b")
+            )
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn args_passed_as_written() {
+        let args = ["it's", "a b", "$HOME", "; echo injected"].map(String::from);
+        let line = shell_line("printf '%s\\n'", &args, Path::new(""));
+        let output = commands::shell_command(&line).output().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "it's\na b\n$HOME\n; echo injected\n"
+        );
+    }
+
+    #[test]
+    fn script_forms() {
+        assert_eq!(
+            Script::new("jeejah:activate").cmd,
+            Some(ScriptCmd::Function("jeejah".into(), "activate".into()))
+        );
+        assert_eq!(
+            Script::new("pytest -x tests").cmd,
+            Some(ScriptCmd::Shell("pytest -x tests".into()))
+        );
+
+        let mut script = Script::new("pytest");
+        assert_eq!(script.to_cfg_value(), r#""pytest""#);
+        script.depends_on = vec!["lint".into()];
+        assert_eq!(
+            script.to_cfg_value(),
+            r#"{ cmd = "pytest", depends_on = ["lint"] }"#
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use std::{fs, path::Path};
+
+use termcolor::Color;
+
+use crate::{
+    install,
+    util::{self, abort, print_color, prompts},
+};
+
+/// Uninstall every installed package, keeping `pyproject.toml`, and unless `and_lock`,
+/// `pyflow.lock`, so `pyflow install` puts back what was there. Git and path dependencies, and
+/// packages in `preserve`, are kept unless `include_vcs`.
+pub fn uninstall_all(
+    paths: &util::Paths,
+    dont_uninstall: &[String],
+    lock_path: &Path,
+    and_lock: bool,
+    include_vcs: bool,
+    yes: bool,
+) {
+    let (kept, to_uninstall): (Vec<_>, Vec<_>) = util::find_installed(&paths.lib)
+        .into_iter()
+        .partition(|(name, _, _)| {
+            !include_vcs && dont_uninstall.iter().any(|d| util::compare_names(d, name))
+        });
+
+    if !kept.is_empty() {
+        let names: Vec<&str> = kept.iter().map(|(name, _, _)| name.as_str()).collect();
+        print_color(
+            &format!(
+                "Keeping git and path dependencies, and packages in `preserve`: {}. Pass \
+                 `--include-vcs` to uninstall them too.",
+                names.join(", ")
+            ),
+            Color::Yellow,
+        );
+    }
+
+    if !yes {
+        let question = format!(
+            "Uninstall {} packages from {}? {}.",
+            to_uninstall.len(),
+            paths.lib.to_string_lossy(),
+            if and_lock {
+                "`pyflow.lock` will be removed"
+            } else {
+                "`pyflow.lock` is kept, so `pyflow install` restores them"
+            }
+        );
+        if !prompts::confirm(
+            &question,
+            "Pass `-y` to uninstall every package without being asked.",
+        ) {
+            return;
+        }
+    }
+
+    let mut freed = 0;
+    for (i, (name, version, _)) in to_uninstall.iter().enumerate() {
+        freed += util::installed_cache::size(&paths.lib, name, version).unwrap_or(0);
+        print!("[{}/{}] ", i + 1, to_uninstall.len());
+        install::uninstall(name, version, paths);
+    }
+
+    if and_lock && lock_path.exists() && fs::remove_file(lock_path).is_err() {
+        abort("Problem removing `pyflow.lock`")
+    }
+
+    let mut summary = format!("Uninstalled {} packages", to_uninstall.len());
+    if freed > 0 {
+        summary.push_str(&format!(", freeing {}", util::format_size(freed)));
+    }
+    if !kept.is_empty() {
+        summary.push_str(&format!(", and kept {}", kept.len()));
+    }
+    if and_lock {
+        summary.push_str(". Removed `pyflow.lock`");
+    } else {
+        summary.push_str(". Run `pyflow install` to restore them from `pyflow.lock`");
+    }
+    print_color(&summary, Color::Green);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Write};
+
+    use super::*;
+    use crate::dep_types::{Lock, LockFile, LockPackage, Version};
+
+    fn contents(dir: &Path) -> Vec<String> {
+        let mut result: Vec<String> = fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|e| e.file_name().to_string_lossy().into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn install_restores_after_uninstalling_all() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let paths = util::Paths {
+            bin: dir.join(".venv/bin"),
+            lib: dir.join("lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        };
+        fs::create_dir_all(&paths.lib).unwrap();
+        fs::create_dir_all(&paths.entry_pt).unwrap();
+
+        // Already downloaded, so it's installed from the cache.
+        let filename = "fixture-1.0-py3-none-any.whl";
+        let archive = util::paths::archive_path(&paths.cache, filename);
+        fs::create_dir_all(archive.parent().unwrap()).unwrap();
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        for (name, data) in [
+            ("fixture.py", "def main():\n    pass\n"),
+            (
+                "fixture-1.0.dist-info/METADATA",
+                "Metadata-Version: 2.1\nName: fixture\nVersion: 1.0\n",
+            ),
+            ("fixture-1.0.dist-info/top_level.txt", "fixture\n"),
+            (
+                "fixture-1.0.dist-info/entry_points.txt",
+                "[console_scripts]\nfixture = fixture:main\n",
+            ),
+            ("fixture-1.0.dist-info/RECORD", ""),
+        ] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        let wheel = fs::read(&archive).unwrap();
+        let sha256 =
+            data_encoding::HEXUPPER.encode(install::sha256_digest(&wheel[..]).unwrap().as_ref());
+
+        let lockpacks = vec![LockPackage {
+            id: 1,
+            name: "fixture".into(),
+            version: "1.0".into(),
+            source: None,
+            dependencies: Some(vec![]),
+            rename: None,
+            marker: None,
+            modules: Some(vec!["fixture".into()]),
+            files: Some(vec![LockFile {
+                filename: filename.into(),
+                url: format!("https://example.com/{}", filename),
+                sha256,
+                packagetype: "bdist_wheel".into(),
+                python_version: "py3".into(),
+                requires_python: None,
+                platform: "any".into(),
+            }]),
+            extras: None,
+            wheel: None,
+        }];
+        let lock_path = dir.join("pyflow.lock");
+        let lock = Lock {
+            metadata: HashMap::new(),
+            package: Some(lockpacks.clone()),
+        };
+        util::write_lock(&lock_path, &lock).unwrap();
+        let written = fs::read_to_string(&lock_path).unwrap();
+
+        // A path dependency, which pyflow doesn't install.
+        fs::create_dir_all(paths.lib.join("mylib-0.1.0.dist-info")).unwrap();
+        let dont_uninstall = vec!["mylib".to_owned()];
+
+        let (os, py_vers) = (util::Os::Linux, Version::new(3, 11, 7));
+        let sync = || {
+            let installed = util::find_installed(&paths.lib);
+            util::deps::sync_deps(
+                &paths,
+                &lockpacks,
+                &dont_uninstall,
+                &installed,
+                os,
+                &py_vers,
+            );
+        };
+        sync();
+        let (lib, scripts) = (contents(&paths.lib), contents(&paths.entry_pt));
+        assert!(lib.contains(&"fixture.py".to_owned()));
+        assert!(scripts.contains(&"fixture".to_owned()));
+
+        uninstall_all(&paths, &dont_uninstall, &lock_path, false, false, true);
+        let names: Vec<String> = util::find_installed(&paths.lib)
+            .into_iter()
+            .map(|(name, _, _)| name)
+            .collect();
+        assert_eq!(names, vec!["mylib"]);
+        assert!(!paths.lib.join("fixture.py").exists());
+        assert!(!paths.entry_pt.join("fixture").exists());
+        assert_eq!(fs::read_to_string(&lock_path).unwrap(), written);
+
+        sync();
+        assert_eq!(contents(&paths.lib), lib);
+        assert_eq!(contents(&paths.entry_pt), scripts);
+
+        uninstall_all(&paths, &dont_uninstall, &lock_path, true, true, true);
+        assert!(util::find_installed(&paths.lib).is_empty());
+        assert!(!lock_path.exists());
+    }
+}
@@ -0,0 +1,375 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use termcolor::Color;
+
+use crate::{
+    dep_types::{LockPackage, Version},
+    install,
+    util::{self, deps, print_color, print_color_, Os},
+    CliConfig,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum FileStatus {
+    /// Its hash doesn't match the one in `RECORD`.
+    Modified,
+    /// In `RECORD`, but not on disk.
+    Missing,
+    /// In one of the package's modules, but not in any package's `RECORD`.
+    Extra,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct FileProblem {
+    path: String,
+    status: FileStatus,
+}
+
+#[derive(Debug, Serialize)]
+struct Changed {
+    name: String,
+    version: String,
+    files: Vec<FileProblem>,
+}
+
+/// A package we don't check, since it's installed from git or a path, and may change.
+#[derive(Debug, Serialize)]
+struct Unchecked {
+    name: String,
+    version: String,
+    reason: &'static str,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    checked: usize,
+    changed: Vec<Changed>,
+    not_locked: Vec<String>,
+    not_installed: Vec<String>,
+    unchecked: Vec<Unchecked>,
+}
+
+impl Report {
+    fn passed(&self) -> bool {
+        self.changed.is_empty() && self.not_locked.is_empty() && self.not_installed.is_empty()
+    }
+}
+
+/// Files under `dir`, relative to `lib_path`, skipping bytecode caches.
+fn files_under(dir: &Path, lib_path: &Path, result: &mut Vec<String>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.file_name().is_some_and(|n| n == "__pycache__") {
+            continue;
+        }
+        if path.is_dir() {
+            files_under(&path, lib_path, result);
+        } else if let Ok(rel) = path.strip_prefix(lib_path) {
+            result.push(rel.to_string_lossy().replace('\\', "/"));
+        }
+    }
+}
+
+/// Compare a package's installed files with the hashes in its `RECORD`. Files in its modules
+/// that no package's `RECORD` lists are extra.
+fn check_files(
+    lib_path: &Path,
+    dist_info: &Path,
+    tops: &[String],
+    recorded: &HashSet<String>,
+) -> Vec<FileProblem> {
+    let mut result = vec![];
+    for (path, hash) in util::record_entries(dist_info) {
+        // Bytecode is written after installing, and files outside `lib` aren't ours to check.
+        if path.starts_with("..") || path.contains("__pycache__/") || path.ends_with(".pyc") {
+            continue;
+        }
+        let status = match fs::File::open(lib_path.join(&path)) {
+            Err(_) => Some(FileStatus::Missing),
+            Ok(file) => match hash.split_once('=') {
+                Some(("sha256", expected)) => install::sha256_digest(file)
+                    .ok()
+                    .map(|d| data_encoding::BASE64URL_NOPAD.encode(d.as_ref()))
+                    .filter(|actual| actual != expected)
+                    .map(|_| FileStatus::Modified),
+                _ => None,
+            },
+        };
+        if let Some(status) = status {
+            result.push(FileProblem { path, status });
+        }
+    }
+
+    let mut on_disk = vec![];
+    for top in tops {
+        files_under(&lib_path.join(top), lib_path, &mut on_disk);
+    }
+    on_disk.sort();
+    for path in on_disk {
+        if !recorded.contains(&path) {
+            result.push(FileProblem {
+                path,
+                status: FileStatus::Extra,
+            });
+        }
+    }
+    result
+}
+
+fn make_report(
+    lib_path: &Path,
+    lockpacks: &[LockPackage],
+    dont_uninstall: &[String],
+    os: Os,
+    py_vers: &Version,
+) -> Report {
+    let installed = util::find_installed(lib_path);
+    let dist_infos: Vec<Option<PathBuf>> = installed
+        .iter()
+        .map(|(name, version, _)| install::find_dist_info(name, version, lib_path))
+        .collect();
+    let recorded: HashSet<String> = dist_infos
+        .iter()
+        .flatten()
+        .flat_map(|d| util::record_paths(d))
+        .collect();
+    let locked: Vec<&LockPackage> = lockpacks
+        .iter()
+        .filter(|lp| deps::applies(lp, os, py_vers))
+        .collect();
+
+    let mut report = Report::default();
+    for ((name, version, tops), dist_info) in installed.iter().zip(&dist_infos) {
+        if dont_uninstall.iter().any(|n| util::compare_names(n, name)) {
+            report.unchecked.push(Unchecked {
+                name: name.to_owned(),
+                version: version.to_string(),
+                reason: "installed from git or a path",
+            });
+            continue;
+        }
+        let lp = match locked.iter().find(|lp| util::compare_names(&lp.name, name)) {
+            Some(lp) => lp,
+            None => {
+                report.not_locked.push(format!("{} {}", name, version));
+                continue;
+            }
+        };
+        if lp.rename.is_some() {
+            report.unchecked.push(Unchecked {
+                name: name.to_owned(),
+                version: version.to_string(),
+                reason: "renamed when installed, so its RECORD doesn't match",
+            });
+            continue;
+        }
+        let dist_info = match dist_info {
+            Some(d) => d,
+            None => continue,
+        };
+        report.checked += 1;
+        let files = check_files(lib_path, dist_info, tops, &recorded);
+        if !files.is_empty() {
+            report.changed.push(Changed {
+                name: name.to_owned(),
+                version: version.to_string(),
+                files,
+            });
+        }
+    }
+
+    for lp in locked {
+        if !installed
+            .iter()
+            .any(|(n, _, _)| util::compare_names(n, &lp.name))
+        {
+            report
+                .not_installed
+                .push(format!("{} {}", lp.name, lp.version));
+        }
+    }
+    report
+}
+
+fn print_report(report: &Report) {
+    for changed in &report.changed {
+        print_color_(
+            &format!("{} {}", changed.name, changed.version),
+            Color::Cyan,
+        );
+        print_color(
+            &format!(": {} changed files", changed.files.len()),
+            Color::Red,
+        );
+        for file in &changed.files {
+            let status = match file.status {
+                FileStatus::Modified => "modified",
+                FileStatus::Missing => "missing",
+                FileStatus::Extra => "extra",
+            };
+            println!("    {:<8} {}", status, file.path);
+        }
+    }
+    for name in &report.not_locked {
+        print_color_(name, Color::Cyan);
+        print_color(": installed, but not in `pyflow.lock`", Color::Red);
+    }
+    for name in &report.not_installed {
+        print_color_(name, Color::Cyan);
+        print_color(": in `pyflow.lock`, but not installed", Color::Red);
+    }
+    for unchecked in &report.unchecked {
+        print_color_(
+            &format!("{} {}", unchecked.name, unchecked.version),
+            Color::Cyan,
+        );
+        print_color(
+            &format!(": not checked; {}", unchecked.reason),
+            Color::Yellow,
+        );
+    }
+
+    let summary = format!(
+        "Checked {} packages: {} with changed files, {} not locked, {} not installed",
+        report.checked,
+        report.changed.len(),
+        report.not_locked.len(),
+        report.not_installed.len()
+    );
+    let color = if report.passed() {
+        Color::Green
+    } else {
+        Color::Red
+    };
+    print_color(&summary, color);
+}
+
+/// Check installed packages against the hashes in their `RECORD`s, and the lock, without
+/// downloading anything. Exits with an error if anything differs.
+pub fn verify(
+    lib_path: &Path,
+    lockpacks: &[LockPackage],
+    dont_uninstall: &[String],
+    os: Os,
+    py_vers: &Version,
+) {
+    let report = make_report(lib_path, lockpacks, dont_uninstall, os, py_vers);
+    if CliConfig::current().json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("Problem serializing the report")
+        );
+    } else {
+        print_report(&report);
+    }
+    if !report.passed() {
+        util::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_file(lib_path: &Path, path: &str, data: &str) -> String {
+        let full = lib_path.join(path);
+        fs::create_dir_all(full.parent().unwrap()).unwrap();
+        fs::write(&full, data).unwrap();
+        let digest = install::sha256_digest(data.as_bytes()).unwrap();
+        format!(
+            "{},sha256={},{}\n",
+            path,
+            data_encoding::BASE64URL_NOPAD.encode(digest.as_ref()),
+            data.len()
+        )
+    }
+
+    fn lock_package(name: &str, version: &str) -> LockPackage {
+        LockPackage {
+            id: 1,
+            name: name.to_owned(),
+            version: version.to_owned(),
+            source: None,
+            dependencies: None,
+            rename: None,
+            marker: None,
+            modules: None,
+            files: None,
+            extras: None,
+            wheel: None,
+        }
+    }
+
+    #[test]
+    fn changed_files_and_packages() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lib_path = tmp.path().to_owned();
+
+        let mut record = String::new();
+        record += &write_file(&lib_path, "six.py", "# six\n");
+        fs::create_dir_all(lib_path.join("six-1.16.0.dist-info")).unwrap();
+        record += "six-1.16.0.dist-info/RECORD,,\n";
+        fs::write(lib_path.join("six-1.16.0.dist-info/RECORD"), record).unwrap();
+
+        let mut record = String::new();
+        record += &write_file(&lib_path, "requests/__init__.py", "");
+        record += &write_file(&lib_path, "requests/api.py", "def get(): ...\n");
+        record += &write_file(&lib_path, "requests/models.py", "class Response: ...\n");
+        fs::create_dir_all(lib_path.join("requests/__pycache__")).unwrap();
+        fs::write(lib_path.join("requests/__pycache__/api.pyc"), "").unwrap();
+        fs::create_dir_all(lib_path.join("requests-2.32.3.dist-info")).unwrap();
+        fs::write(lib_path.join("requests-2.32.3.dist-info/RECORD"), record).unwrap();
+        fs::write(lib_path.join("requests/api.py"), "def get(): steal()\n").unwrap();
+        fs::remove_file(lib_path.join("requests/models.py")).unwrap();
+        fs::write(lib_path.join("requests/backdoor.py"), "").unwrap();
+
+        fs::create_dir_all(lib_path.join("mylib-0.1.0.dist-info")).unwrap();
+        fs::create_dir_all(lib_path.join("extra_pkg-1.0.0.dist-info")).unwrap();
+
+        let lockpacks = vec![
+            lock_package("six", "1.16.0"),
+            lock_package("requests", "2.32.3"),
+            lock_package("urllib3", "2.2.3"),
+        ];
+        let report = make_report(
+            &lib_path,
+            &lockpacks,
+            &["mylib".to_owned()],
+            Os::Linux,
+            &Version::new(3, 12, 0),
+        );
+
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.changed.len(), 1);
+        assert_eq!(report.changed[0].name, "requests");
+        let mut files: Vec<(&str, FileStatus)> = report.changed[0]
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f.status))
+            .collect();
+        files.sort_by_key(|f| f.0);
+        assert_eq!(
+            files,
+            vec![
+                ("requests/api.py", FileStatus::Modified),
+                ("requests/backdoor.py", FileStatus::Extra),
+                ("requests/models.py", FileStatus::Missing),
+            ]
+        );
+        assert_eq!(report.not_locked, vec!["extra_pkg 1.0.0"]);
+        assert_eq!(report.not_installed, vec!["urllib3 2.2.3"]);
+        assert_eq!(report.unchecked.len(), 1);
+        assert_eq!(report.unchecked[0].name, "mylib");
+        assert!(!report.passed());
+    }
+}
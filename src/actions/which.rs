@@ -0,0 +1,91 @@
+use std::{collections::HashMap, path::Path};
+
+use termcolor::Color;
+
+use super::list::find_console_scripts;
+use crate::{
+    pyproject::Script,
+    util::{
+        abort, print_color, print_color_,
+        scripts::{Owners, ScriptOwner},
+    },
+};
+
+/// One line describing a console script, eg `black 23.1.0, runs black:patched_main`.
+fn describe(owner: &ScriptOwner) -> String {
+    let mut result = owner.package.clone();
+    if !owner.version.is_empty() {
+        result.push_str(&format!(" {}", owner.version));
+    }
+    if !owner.target.is_empty() {
+        result.push_str(&format!(", runs {}", owner.target));
+    }
+    result
+}
+
+/// Show which package provides a console script, what it runs, and where its launcher is.
+/// With `all`, list every script.
+pub fn which(
+    script: Option<&str>,
+    all: bool,
+    entry_pt_path: &Path,
+    project_scripts: &HashMap<String, Script>,
+) {
+    let owners = Owners::read(entry_pt_path);
+
+    if all {
+        let mut names: Vec<String> = project_scripts.keys().cloned().collect();
+        names.extend(find_console_scripts(entry_pt_path));
+        names.sort();
+        names.dedup();
+        if names.is_empty() {
+            print_color("No console scripts are installed.", Color::Blue);
+        }
+        for name in names {
+            print_color_(&format!("{}: ", name), Color::Cyan);
+            if project_scripts.contains_key(&name) {
+                print_color("defined in [tool.pyflow.scripts]", Color::White);
+            } else if let Some(owner) = owners.scripts.get(&name) {
+                print_color(&describe(owner), Color::White);
+            } else {
+                print_color("provider not recorded", Color::White);
+            }
+        }
+        return;
+    }
+
+    let name = script
+        .unwrap_or_else(|| abort("Specify a console script, eg `pyflow which black`, or `--all`"));
+
+    // `pyflow run` picks tasks over console scripts of the same name.
+    if let Some(task) = project_scripts.get(name) {
+        print_color_(&format!("{}: ", name), Color::Cyan);
+        match &task.cmd {
+            Some(cmd) => print_color(
+                &format!("defined in [tool.pyflow.scripts], runs {}", cmd),
+                Color::White,
+            ),
+            None => print_color("defined in [tool.pyflow.scripts]", Color::White),
+        }
+        return;
+    }
+
+    let launcher = entry_pt_path.join(name);
+    match owners.scripts.get(name) {
+        Some(owner) => {
+            print_color_(&format!("{}: ", name), Color::Cyan);
+            print_color(&describe(owner), Color::White);
+            println!("  {}", owner.path.display());
+        }
+        None if launcher.exists() => {
+            print_color_(&format!("{}: ", name), Color::Cyan);
+            print_color(
+                "provider not recorded. It may come from a path dependency, or an older version \
+                 of pyflow; `pyflow scripts regenerate` records it",
+                Color::White,
+            );
+            println!("  {}", launcher.display());
+        }
+        None => abort(&format!("No console script named {} is installed", name)),
+    }
+}
@@ -0,0 +1,171 @@
+use std::str::FromStr;
+
+use termcolor::Color;
+
+use crate::{
+    dep_resolution::res,
+    dep_types::{LockPackage, Req, Version},
+    util::{self, abort, dep_graph::DepGraph, deps::locked_packages, print_color, print_color_},
+};
+
+/// The shortest chain of requirements from the project to the locked package `name` at
+/// `version`, eg `["flask ^2", "werkzeug 2.3.0"]`. The first link is the project's requirement;
+/// the rest are the locked packages required along the way.
+fn chain_from_project(
+    graph: &DepGraph,
+    reqs: &[Req],
+    name: &str,
+    version: &Version,
+) -> Vec<String> {
+    let starts = graph.named(reqs.iter().map(|r| r.name.as_str()));
+    let target = (0..graph.packages.len()).find(|&i| {
+        let lp = &graph.packages[i];
+        util::compare_names(&lp.name, name)
+            && Version::from_str(&lp.version).ok().as_ref() == Some(version)
+    });
+    let path = match target.and_then(|t| graph.path(&starts, t)) {
+        Some(p) => p,
+        None => return vec![],
+    };
+
+    path.iter()
+        .enumerate()
+        .map(|(link, &i)| {
+            let lp = &graph.packages[i];
+            match reqs.iter().find(|r| util::compare_names(&r.name, &lp.name)) {
+                Some(req) if link == 0 => {
+                    let constrs: Vec<String> =
+                        req.constraints.iter().map(|c| c.to_string()).collect();
+                    format!("{} {}", req.name, constrs.join(","))
+                        .trim_end()
+                        .to_owned()
+                }
+                _ => format!("{} {}", lp.name, lp.version),
+            }
+        })
+        .collect()
+}
+
+/// Explain why a version of a package wasn't selected, by listing each constraint in the
+/// requirement graph that excludes it, and the chain of requirements that introduced it.
+pub fn why_not(
+    name: &str,
+    version: &str,
+    project_name: &str,
+    reqs: &[Req],
+    lockpacks: &[LockPackage],
+) {
+    let version = match Version::from_str(version) {
+        Ok(v) => v,
+        Err(_) => abort(&format!("Problem parsing version: {}", version)),
+    };
+
+    let locked = locked_packages(lockpacks);
+    let graph = DepGraph::from_lock(lockpacks);
+    let exclusions = match res::why_not(reqs, &locked, name, &version) {
+        Ok(e) => e,
+        Err(_) => abort("Problem getting dependency data. Is the internet connection ok?"),
+    };
+
+    if exclusions.is_empty() {
+        print_color(
+            &format!(
+                "Nothing in the requirement graph excludes {} {}. It wasn't the newest \
+                 compatible version when `pyflow.lock` was created; run \
+                 `pyflow lock --resolution highest` to resolve the lock again with the newest \
+                 versions.",
+                name, version
+            ),
+            Color::Green,
+        );
+        return;
+    }
+
+    for exclusion in exclusions {
+        let constrs: Vec<String> = exclusion
+            .constraints
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+
+        print_color_(&format!("{} {}", name, version), Color::Cyan);
+        print_color_(" excluded by: ", Color::White);
+        match exclusion.parent {
+            Some((parent_name, parent_vers)) => {
+                print_color_(&format!("{} {}", parent_name, parent_vers), Color::Cyan);
+                print_color_(
+                    &format!(" requires {} {}", name, constrs.join(",")),
+                    Color::Yellow,
+                );
+                let mut chain = vec![project_name.to_owned()];
+                chain.extend(chain_from_project(&graph, reqs, &parent_name, &parent_vers));
+                print_color(
+                    &format!(" (required by: {})", chain.join(" -> ")),
+                    Color::White,
+                );
+            }
+            None => print_color(
+                &format!("{} requires {} {}", project_name, name, constrs.join(",")),
+                Color::Yellow,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dep_types::{Constraint, Lock};
+
+    const LOCK: &str = r#"
+[metadata]
+
+[[package]]
+id = 1
+name = "requests"
+version = "2.28.1"
+dependencies = ["urllib3 1.26.18 pypi+https://pypi.org/pypi/urllib3/1.26.18/json"]
+
+[[package]]
+id = 2
+name = "urllib3"
+version = "1.26.18"
+dependencies = []
+
+[[package]]
+id = 3
+name = "flask"
+version = "2.3.3"
+dependencies = ["werkzeug 2.3.7 pypi+https://pypi.org/pypi/werkzeug/2.3.7/json"]
+
+[[package]]
+id = 4
+name = "werkzeug"
+version = "2.3.7"
+dependencies = []
+"#;
+
+    #[test]
+    fn chain_to_dependency() {
+        let lockpacks = toml::from_str::<Lock>(LOCK).unwrap().package.unwrap();
+        let graph = DepGraph::from_lock(&lockpacks);
+        let req = |name: &str, constrs: &str| {
+            Req::new(
+                name.to_owned(),
+                Constraint::from_str_multiple(constrs).unwrap(),
+            )
+        };
+        let reqs = vec![req("Requests", "^2.28"), req("flask", "^2")];
+        let chain = |name, version: &str| {
+            chain_from_project(&graph, &reqs, name, &Version::from_str(version).unwrap())
+        };
+
+        assert_eq!(chain("requests", "2.28.1"), ["Requests ^2.28"]);
+        assert_eq!(
+            chain("urllib3", "1.26.18"),
+            ["Requests ^2.28", "urllib3 1.26.18"]
+        );
+        assert_eq!(chain("werkzeug", "2.3.7"), ["flask ^2", "werkzeug 2.3.7"]);
+        assert!(chain("werkzeug", "3.0.0").is_empty());
+    }
+}
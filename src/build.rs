@@ -1,12 +1,27 @@
-use std::{collections::HashMap, env, fs, path::Path, process::Command};
-
+use std::{
+    collections::{HashMap, VecDeque},
+    env, fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use ignore::gitignore::Gitignore;
 use regex::Regex;
 use termcolor::Color;
 
-use crate::{dep_types::Req, util};
+use crate::{dep_types::Req, reproducible, util};
 
 // https://packaging.python.org/tutorials/packaging-projects/
 
+const DUMMY_SETUP_FILENAME: &str = "setup_temp_pyflow.py";
+
+/// Lines of a failed build script's stderr to repeat in the error.
+const STDERR_TAIL_LINES: usize = 10;
+
+/// Extension modules a build script may compile into the package.
+const NATIVE_EXTENSIONS: [&str; 2] = ["so", "pyd"];
+
 /// Serialize to a python list of strings.
 fn serialize_py_list(items: &[String], indent_level: u8) -> String {
     let mut pad = "".to_string();
@@ -23,17 +38,43 @@ fn serialize_py_list(items: &[String], indent_level: u8) -> String {
     result
 }
 
-/// Serialize to a Python dict of lists of strings.
-fn _serialize_py_dict(hm: &HashMap<String, Vec<String>>) -> String {
+/// Serialize to a Python dict of lists of strings, sorted by key.
+fn serialize_py_dict(hm: &HashMap<String, Vec<String>>, indent_level: u8) -> String {
+    let mut pad = "".to_string();
+    for _ in 0..indent_level {
+        pad.push_str("    ");
+    }
+
+    let mut keys: Vec<&String> = hm.keys().collect();
+    keys.sort();
+
     let mut result = "{\n".to_string();
-    for (key, val) in hm.iter() {
-        result.push_str(&format!("    \"{}\": {}\n", key, serialize_py_list(val, 0)));
+    for key in keys {
+        result.push_str(&format!(
+            "{}    \"{}\": {},\n",
+            &pad,
+            key,
+            serialize_py_list(&hm[key], indent_level + 1)
+        ));
     }
+    result.push_str(&pad);
     result.push('}');
     result
 }
 
-/// Serialize to a Python dict of strings.
+/// Format extras for `extras_require` in `setup.py`. Setuptools writes these to `METADATA` as
+/// `Provides-Extra`, and `Requires-Dist` lines with an `extra` marker.
+fn extras_require(extras: &HashMap<String, Vec<Req>>) -> HashMap<String, Vec<String>> {
+    extras
+        .iter()
+        .map(|(extra, reqs)| {
+            let reqs = reqs.iter().map(Req::to_setup_py_string).collect();
+            (extra.clone(), reqs)
+        })
+        .collect()
+}
+
+// Serialize to a Python dict of strings.
 //fn serialize_scripts(hm: &HashMap<String, String>) -> String {
 //    let mut result = "{\n".to_string();
 //
@@ -54,7 +95,9 @@ fn _serialize_py_dict(hm: &HashMap<String, Vec<String>>) -> String {
 //    result
 //}
 
-fn cfg_to_setup(cfg: &crate::Config) -> String {
+/// `native` marks the wheel as platform-specific, and includes compiled extension modules, eg
+/// those from the `build` script.
+fn cfg_to_setup(cfg: &crate::Config, native: bool) -> String {
     let cfg = cfg.clone();
 
     let version = match cfg.version {
@@ -74,7 +117,7 @@ fn cfg_to_setup(cfg: &crate::Config) -> String {
 
     let mut author = "".to_string();
     let mut author_email = "".to_string();
-    if let Some(first) = cfg.authors.get(0) {
+    if let Some(first) = cfg.authors.first() {
         let caps = if let Some(c) = author_re.captures(first) {
             c
         } else {
@@ -87,7 +130,36 @@ fn cfg_to_setup(cfg: &crate::Config) -> String {
         author_email = caps.get(2).unwrap().as_str().to_owned();
     }
 
-    let deps: Vec<String> = cfg.reqs.iter().map(Req::to_setup_py_string).collect();
+    // The project itself is a req when installed with `pyflow install -e .`.
+    let deps: Vec<String> = cfg
+        .reqs
+        .iter()
+        .filter(|r| {
+            cfg.name
+                .as_deref()
+                .is_none_or(|n| !util::compare_names(n, &r.name))
+        })
+        .map(Req::to_setup_py_string)
+        .collect();
+
+    let (native_class, native_args) = if native {
+        (
+            "class BinaryDistribution(setuptools.Distribution):\n    \
+             def has_ext_modules(self):\n        return True\n\n\n",
+            format!(
+                "    package_data={{\"\": {}}},\n    distclass=BinaryDistribution,\n",
+                serialize_py_list(
+                    &NATIVE_EXTENSIONS
+                        .iter()
+                        .map(|e| format!("*.{}", e))
+                        .collect::<Vec<_>>(),
+                    1
+                )
+            ),
+        )
+    } else {
+        ("", String::new())
+    };
 
     // todo: Entry pts!
     format!(
@@ -96,7 +168,7 @@ fn cfg_to_setup(cfg: &crate::Config) -> String {
 with open("{}", "r") as fh:
     long_description = fh.read()
 
-setuptools.setup(
+{}setuptools.setup(
     name="{}",
     version="{}",
     author="{}",
@@ -111,12 +183,14 @@ setuptools.setup(
     classifiers={},
     python_requires="{}",
     install_requires={},
-)
+    extras_require={},
+{})
 "#,
         //            entry_points={{
         //        "console_scripts": ,
         //    }},
         cfg.readme.unwrap_or_else(|| "README.md".into()),
+        native_class,
         cfg.name.unwrap_or_else(|| "".into()),
         version,
         author,
@@ -129,27 +203,217 @@ setuptools.setup(
         //        serialize_py_list(&cfg.console_scripts),
         cfg.python_requires.unwrap_or_else(|| "".into()),
         serialize_py_list(&deps, 1),
-        // todo:
-        //            extras_require="{}",
-        //        match cfg.extras {
-        //            Some(e) => serialize_py_dict(&e),
-        //            None => "".into(),
-        //        }
+        serialize_py_dict(&extras_require(&cfg.extras), 1),
+        native_args,
     )
 }
 
 /// Creates a temporary file which imitates setup.py
-fn create_dummy_setup(cfg: &crate::Config, filename: &str) {
-    fs::write(filename, cfg_to_setup(cfg)).expect("Problem writing dummy setup.py");
-    if util::wait_for_dirs(&[env::current_dir()
-        .expect("Problem finding current dir")
-        .join(filename)])
-    .is_err()
-    {
+fn create_dummy_setup(cfg: &crate::Config, path: &Path, native: bool) {
+    fs::write(path, cfg_to_setup(cfg, native)).expect("Problem writing dummy setup.py");
+    if util::wait_for_dirs(&[path.to_owned()]).is_err() {
         util::abort("Problem waiting for setup.py to be created.")
     };
 }
 
+/// Whether `root` has compiled extension modules, outside the environment and build output.
+fn has_native_files(root: &Path) -> bool {
+    let entries = match fs::read_dir(root) {
+        Ok(e) => e,
+        Err(_) => return false,
+    };
+    entries.flatten().any(|entry| {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if path.is_dir() {
+            let skipped = name.starts_with('.')
+                || ["__pypackages__", "build", "dist"].contains(&name.as_str());
+            !skipped && has_native_files(&path)
+        } else {
+            path.extension()
+                .is_some_and(|e| NATIVE_EXTENSIONS.iter().any(|n| e == *n))
+        }
+    })
+}
+
+/// A build script that exited with an error.
+#[derive(Debug, PartialEq)]
+struct BuildScriptFailure {
+    code: Option<i32>,
+    /// The last lines it wrote to stderr.
+    stderr_tail: Vec<String>,
+}
+
+/// Run the `build` script from `[tool.pyflow]`, eg to compile extensions with Cython or cffi,
+/// from the project directory. Its output is shown as it runs. It's told where the project is,
+/// a directory for intermediate files, and the package's name and version, with `PYFLOW_`
+/// environment variables. Files it writes into the package, eg `.so` modules, go in the wheel.
+fn run_build_script(
+    python: &Path,
+    script: &Path,
+    root: &Path,
+    cfg: &crate::Config,
+) -> Result<(), BuildScriptFailure> {
+    if !script.exists() {
+        util::abort(&format!(
+            "Can't find the build script {}, set with `build` in `pyproject.toml`",
+            script.to_string_lossy()
+        ))
+    }
+    let build_dir = root.join("build");
+    fs::create_dir_all(&build_dir).expect("Problem creating the build directory");
+
+    let mut child = Command::new(python)
+        .arg(script)
+        .current_dir(root)
+        .env("PYFLOW_PROJECT_DIR", root)
+        .env("PYFLOW_BUILD_DIR", &build_dir)
+        .env("PYFLOW_PACKAGE_NAME", cfg.name.clone().unwrap_or_default())
+        .env(
+            "PYFLOW_PACKAGE_VERSION",
+            cfg.version
+                .as_ref()
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+        )
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| {
+            util::abort(&format!(
+                "Problem running the build script {}: {}",
+                script.to_string_lossy(),
+                e
+            ))
+        });
+
+    // Pass stderr through, keeping the end of it for the error.
+    let mut stderr_tail = VecDeque::new();
+    if let Some(stderr) = child.stderr.take() {
+        for line in io::BufReader::new(stderr).lines().map_while(Result::ok) {
+            writeln!(io::stderr(), "{}", line).ok();
+            if stderr_tail.len() == STDERR_TAIL_LINES {
+                stderr_tail.pop_front();
+            }
+            stderr_tail.push_back(line);
+        }
+    }
+
+    let status = child.wait().expect("Problem waiting for the build script");
+    if status.success() {
+        Ok(())
+    } else {
+        Err(BuildScriptFailure {
+            code: status.code(),
+            stderr_tail: stderr_tail.into(),
+        })
+    }
+}
+
+/// Build the sdist and wheel into `root/dist`, from a temporary `setup.py` made from the config.
+/// They're rewritten so building the same commit again gives identical files.
+fn build_dists(python: &Path, root: &Path, cfg: &crate::Config) -> bool {
+    let native = cfg.build.is_some() && has_native_files(root);
+    let setup_path = root.join(DUMMY_SETUP_FILENAME);
+    create_dummy_setup(cfg, &setup_path, native);
+    let original_manifest = write_manifest(root);
+    let epoch = reproducible::source_date_epoch(root);
+    let dist_dir = root.join("dist");
+    let existing = reproducible::dist_files(&dist_dir);
+
+    let status = Command::new(python)
+        .args([DUMMY_SETUP_FILENAME, "sdist", "bdist_wheel"])
+        .current_dir(root)
+        .env("SOURCE_DATE_EPOCH", epoch.to_string())
+        .status();
+
+    restore_manifest(root, original_manifest);
+    if fs::remove_file(&setup_path).is_err() {
+        println!("Problem removing temporary setup file while building ")
+    };
+
+    if !matches!(status, Ok(s) if s.success()) {
+        return false;
+    }
+    if let Err(e) = reproducible::normalize_dists(&dist_dir, &existing, epoch) {
+        util::abort(&format!("Problem making the build reproducible: {}", e));
+    }
+    true
+}
+
+/// Find the files and directories in `root` that `.gitignore` excludes, so we can keep them out
+/// of the package. Returns paths relative to `root`, and whether each is a directory. We don't
+/// descend into ignored directories; Git doesn't allow re-including files inside them.
+fn ignored_paths(root: &Path, gitignore: &Gitignore) -> Vec<(PathBuf, bool)> {
+    fn visit(root: &Path, dir: &Path, gitignore: &Gitignore, result: &mut Vec<(PathBuf, bool)>) {
+        let entries = match fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+        let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok().map(|e| e.path())).collect();
+        paths.sort();
+
+        for path in paths {
+            let rel_path = path.strip_prefix(root).unwrap().to_owned();
+            let is_dir = path.is_dir();
+            // The environment and Git data are never part of a package.
+            let always_ignored = is_dir
+                && (rel_path == Path::new("__pypackages__") || rel_path == Path::new(".git"));
+
+            if always_ignored || gitignore.matched(&rel_path, is_dir).is_ignore() {
+                result.push((rel_path, is_dir));
+            } else if is_dir {
+                visit(root, &path, gitignore, result);
+            }
+        }
+    }
+
+    let mut result = vec![];
+    visit(root, root, gitignore, &mut result);
+    result
+}
+
+/// Create `MANIFEST.in` commands that remove ignored paths from the sdist.
+fn manifest_excludes(ignored: &[(PathBuf, bool)]) -> String {
+    let mut result = String::new();
+    for (path, is_dir) in ignored {
+        let path = path.to_string_lossy().replace('\\', "/");
+        let command = if *is_dir { "prune" } else { "exclude" };
+        result.push_str(&format!("{} {}\n", command, path));
+    }
+    result
+}
+
+/// Temporarily add excludes for `.gitignore`d paths to `MANIFEST.in`, so setuptools leaves them
+/// out of the sdist. Returns the original `MANIFEST.in`, if there was one, so we can restore it.
+fn write_manifest(root: &Path) -> Option<String> {
+    let manifest_path = root.join("MANIFEST.in");
+    let original = fs::read_to_string(&manifest_path).ok();
+
+    let (gitignore, _) = Gitignore::new(root.join(".gitignore"));
+    let excludes = manifest_excludes(&ignored_paths(root, &gitignore));
+
+    let mut manifest = original.clone().unwrap_or_default();
+    if !manifest.is_empty() && !manifest.ends_with('\n') {
+        manifest.push('\n');
+    }
+    manifest.push_str(&excludes);
+    fs::write(&manifest_path, manifest).expect("Problem writing MANIFEST.in");
+
+    original
+}
+
+fn restore_manifest(root: &Path, original: Option<String>) {
+    let manifest_path = root.join("MANIFEST.in");
+    let result = match original {
+        Some(o) => fs::write(manifest_path, o),
+        None => fs::remove_file(manifest_path),
+    };
+    if result.is_err() {
+        println!("Problem restoring MANIFEST.in after building");
+    }
+}
+
 pub fn build(
     lockpacks: &[crate::dep_types::LockPackage],
     paths: &util::Paths,
@@ -168,13 +432,11 @@ pub fn build(
         }
     }
 
-    let dummy_setup_fname = "setup_temp_pyflow.py";
-
     // Twine has too many dependencies to install when the environment, like we do with `wheel`, and
     // for now, it's easier to install using pip
     // todo: Install using own tools instead of pip; this is the last dependence on pip.
     let output = Command::new(paths.bin.join("python"))
-        .args(&["-m", "pip", "install", "twine"])
+        .args(["-m", "pip", "install", "twine"])
         .output()
         .expect("Problem installing Twine");
     util::check_command_output(&output, "failed to install twine");
@@ -192,29 +454,33 @@ pub fn build(
     //    )
     //    .expect("Problem installing `twine`");
 
-    create_dummy_setup(cfg, dummy_setup_fname);
-
-    util::set_pythonpath(&[paths.lib.to_owned()]);
+    util::pythonpath::set(&[paths.lib.to_owned()]);
     println!("🛠️️ Building the package...");
-    // todo: Run build script first, right?
+    let python = paths.bin.join("python");
+    let root = env::current_dir().expect("Problem finding current dir");
     if let Some(build_file) = &cfg.build {
-        let output = Command::new(paths.bin.join("python"))
-            .arg(&build_file)
-            .output()
-            .unwrap_or_else(|_| panic!("Problem building using {}", build_file));
-        util::check_command_output(&output, "failed to run build script");
+        if let Err(failure) = run_build_script(&python, &root.join(build_file), &root, cfg) {
+            let code = failure
+                .code
+                .map_or_else(|| "none".to_owned(), |c| c.to_string());
+            util::print_color(
+                &format!(
+                    "The build script {} failed, with exit code {}:\n{}",
+                    build_file,
+                    code,
+                    failure.stderr_tail.join("\n")
+                ),
+                Color::Red,
+            );
+            util::exit(failure.code.unwrap_or(1));
+        }
     }
 
-    //    Command::new(paths.bin.join("python"))
-    //        .args(&[dummy_setup_fname, "sdist", "bdist_wheel"])
-    //        .status()
-    //        .expect("Problem building");
-
-    util::print_color("Build complete.", Color::Green);
-
-    if fs::remove_file(dummy_setup_fname).is_err() {
-        println!("Problem removing temporary setup file while building ")
-    };
+    if build_dists(&python, &root, cfg) {
+        util::print_color("Build complete.", Color::Green)
+    } else {
+        util::abort("Problem building the package")
+    }
 }
 
 pub(crate) fn publish(bin_path: &Path, cfg: &crate::Config) {
@@ -231,7 +497,7 @@ pub(crate) fn publish(bin_path: &Path, cfg: &crate::Config) {
 
     println!("Uploading to {}", repo_url);
     let output = Command::new(bin_path.join("twine"))
-        .args(&["upload", "--repository-url", &repo_url, "dist/*"])
+        .args(["upload", "--repository-url", &repo_url, "dist/*"])
         .output()
         .expect("Problem publishing");
     util::check_command_output(&output, "publishing");
@@ -249,11 +515,15 @@ pub mod test {
     #[test]
     fn setup_creation() {
         let mut scripts = HashMap::new();
-        scripts.insert("activate".into(), "jeejah:activate".into());
+        scripts.insert(
+            "activate".into(),
+            crate::pyproject::Script::new("jeejah:activate"),
+        );
 
         let cfg = crate::Config {
             name: Some("everythingkiller".into()),
-            py_version: Some(Version::new_short(3, 6)),
+            py_version: Some(Version::new_short(3, 6).into()),
+            py_interpreter: None,
             version: Some(Version::new(0, 1, 0)),
             authors: vec!["Fraa Erasmas <raz@edhar.math>".into()],
             homepage: Some("https://everything.math".into()),
@@ -287,9 +557,28 @@ pub mod test {
                 "black".into(),
                 vec![Constraint::new(Caret, Version::new(18, 0, 0))],
             )],
-            extras: HashMap::new(),
+            extras: [(
+                "gui".to_owned(),
+                vec![Req::new(
+                    "pyqt5".into(),
+                    vec![Constraint::new(Exact, Version::new(5, 15, 9))],
+                )],
+            )]
+            .into_iter()
+            .collect(),
             repo_url: None,
             build: None,
+            build_tools: vec![],
+            deps_service_url: None,
+            find_links: vec![],
+            hooks: Default::default(),
+            preserve: vec![],
+            load_dotenv: false,
+            check_shadowing: false,
+            resolution: None,
+            build_timeout: None,
+            frozen: false,
+            settings: Default::default(),
         };
 
         let expected = r#"import setuptools
@@ -319,10 +608,68 @@ setuptools.setup(
         "manimlib==0.1.8",
         "ipython>=7.7.0",
     ],
+    extras_require={
+        "gui": [
+            "pyqt5==5.15.9",
+        ],
+    },
 )
 "#;
 
-        assert_eq!(expected, &cfg_to_setup(&cfg));
+        assert_eq!(expected, &cfg_to_setup(&cfg, false));
+    }
+
+    #[test]
+    fn gitignored_paths_left_out_of_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for file in [
+            "debug.log",
+            "keep.log",
+            "pkg/debug.log",
+            "pkg/keep.log",
+            "pkg/build",
+            "build/lib/pkg.py",
+            "secrets",
+            "pkg/secrets",
+        ] {
+            fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            fs::write(root.join(file), "").unwrap();
+        }
+        fs::create_dir_all(root.join("__pypackages__").join("3.11")).unwrap();
+        fs::write(
+            root.join(".gitignore"),
+            "*.log\n!keep.log\nbuild/\n/secrets\n",
+        )
+        .unwrap();
+        fs::write(root.join("MANIFEST.in"), "include README.md").unwrap();
+
+        let original = write_manifest(root);
+        // Negated files are kept, directory-only rules don't match files with the same name,
+        // and anchored rules only match at the root.
+        assert_eq!(
+            fs::read_to_string(root.join("MANIFEST.in")).unwrap(),
+            "include README.md\nprune __pypackages__\nprune build\nexclude debug.log\n\
+             exclude pkg/debug.log\nexclude secrets\n"
+        );
+
+        restore_manifest(root, original);
+        assert_eq!(
+            fs::read_to_string(root.join("MANIFEST.in")).unwrap(),
+            "include README.md"
+        );
+    }
+
+    #[test]
+    fn manifest_from_ignored() {
+        let ignored = vec![
+            (PathBuf::from("dist"), true),
+            (PathBuf::from("pkg").join("debug.log"), false),
+        ];
+        assert_eq!(
+            manifest_excludes(&ignored),
+            "prune dist\nexclude pkg/debug.log\n"
+        );
     }
 
     #[test]
@@ -345,23 +692,237 @@ setuptools.setup(
         assert_eq!(expected, actual);
     }
 
-    // todo: Re-impl if you end up using this
-    //    #[test]
-    //    fn py_dict() {
-    //        let expected = r#"{
-    //    "PDF": [
-    //        "ReportLab>=1.2",
-    //        "RXP"
-    //    ],
-    //    "reST": [
-    //        "docutils>=0.3"
-    //    ],
-    //    }"#;
-    //
-    //        let mut data = HashMap::new();
-    //        data.insert("PDF".into(), vec!["ReportLab>=1.2".into(), "RXP".into()]);
-    //        data.insert("reST".into(), vec!["docutils>=0.3".into()]);
-    //
-    //        assert_eq!(expected, serialize_py_dict(&data));
-    //    }
+    #[test]
+    fn py_dict() {
+        let expected = r#"{
+    "PDF": [
+        "ReportLab>=1.2",
+        "RXP",
+    ],
+    "reST": [
+        "docutils>=0.3",
+    ],
+}"#;
+
+        let mut data = HashMap::new();
+        data.insert("reST".into(), vec!["docutils>=0.3".into()]);
+        data.insert("PDF".into(), vec!["ReportLab>=1.2".into(), "RXP".into()]);
+
+        assert_eq!(expected, serialize_py_dict(&data, 0));
+    }
+
+    /// Setuptools writes each `extras_require` entry to the wheel's `METADATA` as
+    /// `Provides-Extra`, and `Requires-Dist` with an `extra` marker. Check that parsing these
+    /// gives our extras back.
+    #[test]
+    fn extras_metadata_round_trip() {
+        let python = match ["python3", "python"]
+            .into_iter()
+            .find(|p| crate::commands::find_py_version(p).is_some())
+        {
+            Some(p) => Path::new(p),
+            None => return,
+        };
+        let can_build = Command::new(python)
+            .args(["-c", "import setuptools, wheel"])
+            .output()
+            .is_ok_and(|o| o.status.success());
+        if !can_build {
+            return;
+        }
+
+        let mut extras = HashMap::new();
+        extras.insert(
+            "cli".to_owned(),
+            vec![
+                Req::new(
+                    "click".into(),
+                    vec![Constraint::new(Caret, Version::new(8, 1, 0))],
+                ),
+                Req::new("rich".into(), vec![]),
+            ],
+        );
+        extras.insert(
+            "yaml".to_owned(),
+            vec![Req::new(
+                "PyYAML".into(),
+                vec![Constraint::new(Exact, Version::new(6, 0, 1))],
+            )],
+        );
+        let cfg = crate::Config {
+            name: Some("mylib".into()),
+            version: Some(Version::new(0, 1, 0)),
+            extras: extras.clone(),
+            ..Default::default()
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("mylib")).unwrap();
+        fs::write(dir.path().join("README.md"), "").unwrap();
+        fs::write(dir.path().join("mylib/__init__.py"), "").unwrap();
+        assert!(build_dists(python, dir.path(), &cfg));
+
+        let wheel = fs::read_dir(dir.path().join("dist"))
+            .unwrap()
+            .flatten()
+            .map(|e| e.path())
+            .find(|p| p.extension().is_some_and(|e| e == "whl"))
+            .unwrap();
+        let mut archive = zip::ZipArchive::new(fs::File::open(wheel).unwrap()).unwrap();
+        let mut metadata = String::new();
+        io::Read::read_to_string(
+            &mut archive.by_name("mylib-0.1.0.dist-info/METADATA").unwrap(),
+            &mut metadata,
+        )
+        .unwrap();
+        let path = dir.path().join("METADATA");
+        fs::write(&path, metadata).unwrap();
+        let parsed = util::parse_metadata(&path).unwrap();
+
+        assert_eq!(parsed.provides_extra, vec!["cli", "yaml"]);
+        for (extra, reqs) in &extras {
+            let parsed_reqs: Vec<&Req> = parsed
+                .requires_dist
+                .iter()
+                .filter(|r| r.extra.as_ref() == Some(extra))
+                .collect();
+            assert_eq!(parsed_reqs.len(), reqs.len());
+            for req in reqs {
+                let parsed_req = parsed_reqs
+                    .iter()
+                    .find(|r| util::compare_names(&r.name, &req.name))
+                    .unwrap();
+                // `setup.py` can't express caret constraints, so they become a range.
+                for (p, c) in parsed_req.constraints.iter().zip(&req.constraints) {
+                    assert_eq!(p.version, c.version);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn builds_reproducible() {
+        let python = match ["python3", "python"]
+            .into_iter()
+            .find(|p| crate::commands::find_py_version(p).is_some())
+        {
+            Some(p) => Path::new(p),
+            None => return,
+        };
+        let can_build = Command::new(python)
+            .args(["-c", "import setuptools, wheel"])
+            .output()
+            .is_ok_and(|o| o.status.success());
+        if !can_build {
+            return;
+        }
+        let cfg = crate::Config {
+            name: Some("samepkg".into()),
+            version: Some(Version::new(1, 0, 0)),
+            ..Default::default()
+        };
+
+        let mut hashes = vec![];
+        for _ in 0..2 {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path().to_owned();
+            fs::create_dir_all(dir.join("samepkg")).unwrap();
+            fs::write(dir.join("README.md"), "# samepkg\n").unwrap();
+            fs::write(dir.join("samepkg/util.py"), "X = 1\n").unwrap();
+            fs::write(dir.join("samepkg/__init__.py"), "").unwrap();
+            // Separate the builds' file and build times.
+            std::thread::sleep(std::time::Duration::from_millis(1_100));
+
+            assert!(build_dists(python, &dir, &cfg));
+            let mut built: Vec<(String, String)> = fs::read_dir(dir.join("dist"))
+                .unwrap()
+                .flatten()
+                .map(|e| {
+                    let data = fs::read(e.path()).unwrap();
+                    let hash = ring::digest::digest(&ring::digest::SHA256, &data);
+                    (
+                        e.file_name().to_string_lossy().into_owned(),
+                        data_encoding::HEXLOWER.encode(hash.as_ref()),
+                    )
+                })
+                .collect();
+            built.sort();
+            hashes.push(built);
+        }
+        assert_eq!(hashes[0].len(), 2);
+        assert_eq!(hashes[0], hashes[1]);
+    }
+
+    #[test]
+    fn build_script_output_in_wheel() {
+        // Run it with whichever Python is on the `PATH`, if any.
+        let python = match ["python3", "python"]
+            .into_iter()
+            .find(|p| crate::commands::find_py_version(p).is_some())
+        {
+            Some(p) => Path::new(p),
+            None => return,
+        };
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        fs::create_dir_all(dir.join("genpkg")).unwrap();
+        fs::write(dir.join("README.md"), "").unwrap();
+        fs::write(dir.join("genpkg/__init__.py"), "").unwrap();
+        fs::write(
+            dir.join("build.py"),
+            "import os\n\
+             with open('genpkg/_generated.py', 'w') as f:\n    \
+             f.write('VERSION = %r\\n' % os.environ['PYFLOW_PACKAGE_VERSION'])\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("fail.py"),
+            "import sys\nsys.stderr.write('no compiler\\n')\nsys.exit(3)\n",
+        )
+        .unwrap();
+        let cfg = crate::Config {
+            name: Some("genpkg".into()),
+            version: Some(Version::new(0, 2, 0)),
+            build: Some("build.py".into()),
+            ..Default::default()
+        };
+
+        run_build_script(python, &dir.join("build.py"), &dir, &cfg).unwrap();
+        let generated = "VERSION = '0.2.0'\n";
+        assert_eq!(
+            fs::read_to_string(dir.join("genpkg/_generated.py")).unwrap(),
+            generated
+        );
+        assert!(!has_native_files(&dir));
+        assert_eq!(
+            run_build_script(python, &dir.join("fail.py"), &dir, &cfg),
+            Err(BuildScriptFailure {
+                code: Some(3),
+                stderr_tail: vec!["no compiler".into()],
+            })
+        );
+
+        // Assembling the wheel needs `setuptools` and `wheel`.
+        let can_build = Command::new(python)
+            .args(["-c", "import setuptools, wheel"])
+            .output()
+            .is_ok_and(|o| o.status.success());
+        if can_build {
+            assert!(build_dists(python, &dir, &cfg));
+            let wheel = fs::read_dir(dir.join("dist"))
+                .unwrap()
+                .flatten()
+                .map(|e| e.path())
+                .find(|p| p.extension().is_some_and(|e| e == "whl"))
+                .unwrap();
+            let mut archive = zip::ZipArchive::new(fs::File::open(wheel).unwrap()).unwrap();
+            let mut data = String::new();
+            io::Read::read_to_string(
+                &mut archive.by_name("genpkg/_generated.py").unwrap(),
+                &mut data,
+            )
+            .unwrap();
+            assert_eq!(data, generated);
+        }
+    }
 }
@@ -0,0 +1,207 @@
+//! Installs and tracks the build tooling (`wheel` and `setuptools`) each environment needs to
+//! build packages from source.
+
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use termcolor::Color;
+
+use crate::{
+    dep_resolution::{res, WarehouseRelease},
+    dep_types::{Constraint, DependencyError, Req, ReqType, Version},
+    install::{self, PackageType},
+    util,
+};
+
+/// Packages installed into each venv's `site-packages`. We need `wheel` to build wheels from
+/// sdists, and `setuptools` to run their `setup.py`.
+pub const TOOLS: [&str; 2] = ["wheel", "setuptools"];
+
+/// Stores the tool versions we installed, in the `__pypackages__/3.x` folder.
+pub const RECORD_FILENAME: &str = "build_tools.toml";
+
+/// A known-good `wheel` release. Only used if we're unable to query the warehouse.
+const FALLBACK_WHEEL_VERSION: (u32, u32, u32) = (0, 33, 6);
+const FALLBACK_WHEEL_URL: &str = "https://files.pythonhosted.org/packages/00/83/b4a77d044e78ad1a45610eb88f745be2fd2c6d658f9798a15e384b7d57c9/wheel-0.33.6-py2.py3-none-any.whl";
+const FALLBACK_WHEEL_FILENAME: &str = "wheel-0.33.6-py2.py3-none-any.whl";
+const FALLBACK_WHEEL_SHA256: &str =
+    "f4da1763d3becf2e2cd92a14a7c920f0f00eca30fdde9ea992c836685b9faf28";
+
+/// What we installed into an environment, keyed by tool name.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Record {
+    pub tools: HashMap<String, String>,
+}
+
+impl Record {
+    pub fn version(&self, name: &str) -> Option<Version> {
+        self.tools.get(name).and_then(|v| Version::from_str(v).ok())
+    }
+}
+
+pub fn read_record(vers_path: &Path) -> Option<Record> {
+    let data = fs::read_to_string(vers_path.join(RECORD_FILENAME)).ok()?;
+    toml::from_str(&data).ok()
+}
+
+fn write_record(vers_path: &Path, record: &Record) {
+    let data = toml::to_string(record).expect("Problem serializing build tool record");
+    if fs::write(vers_path.join(RECORD_FILENAME), data).is_err() {
+        util::print_color("Problem saving the build tool record", Color::Yellow);
+    }
+}
+
+/// Find the pin for a tool from `[tool.pyflow] build_tools`, if specified.
+pub fn find_pin<'a>(pins: &'a [Req], name: &str) -> Option<&'a Req> {
+    pins.iter().find(|r| util::compare_names(&r.name, name))
+}
+
+/// Check if a version satisfies a tool's pin. Unpinned tools accept any version.
+pub fn satisfies_pin(pin: Option<&Req>, version: &Version) -> bool {
+    pin.is_none_or(|p| p.constraints.iter().all(|c| c.is_compatible(version)))
+}
+
+/// Find the newest version of a tool compatible with its pin, and the environment's Python.
+pub fn find_latest(
+    name: &str,
+    pin: Option<&Req>,
+    py_vers: &Version,
+) -> Result<Version, DependencyError> {
+    let mut req = Req::new(
+        name.to_owned(),
+        pin.map(|p| p.constraints.clone()).unwrap_or_default(),
+    );
+    req.python_version = Some(vec![Constraint::new(ReqType::Exact, py_vers.clone())]);
+
+    let (_, version, _) = res::get_version_info(name, Some(req))?;
+    Ok(version)
+}
+
+fn find_release(
+    name: &str,
+    version: &Version,
+    py_vers: &Version,
+) -> Result<(WarehouseRelease, PackageType), DependencyError> {
+    let data = res::get_warehouse_release(name, version)?;
+    let choice = util::find_best_release(&data, name, version, util::get_os(), py_vers, None);
+    Ok((choice.release, choice.package_type))
+}
+
+/// Install `wheel` and `setuptools` into the venv's `site-packages`, and record what we
+/// installed. If we can't reach the warehouse, we keep compatible versions that are already
+/// present, then fall back to a known-good `wheel`.
+pub fn bootstrap(paths: &util::Paths, vers_path: &Path, py_vers: &Version, pins: &[Req]) {
+    let installed = util::find_installed(&paths.lib);
+    let mut record = Record::default();
+
+    for name in TOOLS {
+        let pin = find_pin(pins, name);
+        let present = installed
+            .iter()
+            .find(|(n, v, _)| util::compare_names(n, name) && satisfies_pin(pin, v))
+            .map(|(n, v, _)| (n.clone(), v.clone()));
+
+        let found = find_latest(name, pin, py_vers).and_then(|version| {
+            find_release(name, &version, py_vers).map(|release| (version, release))
+        });
+
+        let (version, (release, package_type)) = match found {
+            Ok(f) => f,
+            Err(_) => {
+                if let Some((_, version)) = present {
+                    // Eg offline, with the tool provided by the venv itself.
+                    record.tools.insert(name.to_owned(), version.to_string());
+                } else if name == "wheel" && install_fallback_wheel(paths) {
+                    util::print_color(
+                        "Unable to find the latest `wheel`; installed a known-good fallback \
+                         version instead.",
+                        Color::Yellow,
+                    );
+                    let (ma, mi, pa) = FALLBACK_WHEEL_VERSION;
+                    record
+                        .tools
+                        .insert(name.to_owned(), Version::new(ma, mi, pa).to_string());
+                } else {
+                    util::print_color(
+                        &format!(
+                            "Unable to install `{}`; building packages from source may fail.",
+                            name
+                        ),
+                        Color::Yellow,
+                    );
+                }
+                continue;
+            }
+        };
+
+        if let Some((present_name, present_vers)) = &present {
+            if *present_vers == version {
+                record.tools.insert(name.to_owned(), version.to_string());
+                continue;
+            }
+            install::uninstall(present_name, present_vers, paths);
+        }
+
+        if let Err(e) = install::download_and_install_package(
+            name,
+            &version,
+            &release.url,
+            &release.filename,
+            &release.digests.sha256,
+            paths,
+            package_type,
+            &None,
+        ) {
+            util::abort(&format!("Problem installing `{}`. {}", name, e.describe()));
+        }
+        record.tools.insert(name.to_owned(), version.to_string());
+    }
+
+    write_record(vers_path, &record);
+}
+
+fn install_fallback_wheel(paths: &util::Paths) -> bool {
+    let (ma, mi, pa) = FALLBACK_WHEEL_VERSION;
+    install::download_and_install_package(
+        "wheel",
+        &Version::new(ma, mi, pa),
+        FALLBACK_WHEEL_URL,
+        FALLBACK_WHEEL_FILENAME,
+        FALLBACK_WHEEL_SHA256,
+        paths,
+        PackageType::Wheel,
+        &None,
+    )
+    .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins() {
+        let pins = vec![Req::new(
+            "Wheel".to_owned(),
+            Constraint::from_str_multiple(">=0.37, <0.40").unwrap(),
+        )];
+
+        let pin = find_pin(&pins, "wheel");
+        assert!(pin.is_some());
+        assert!(find_pin(&pins, "setuptools").is_none());
+
+        assert!(satisfies_pin(pin, &Version::new(0, 38, 4)));
+        assert!(!satisfies_pin(pin, &Version::new(0, 33, 6)));
+        assert!(satisfies_pin(None, &Version::new(0, 33, 6)));
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let mut record = Record::default();
+        record.tools.insert("wheel".into(), "0.41.2".into());
+
+        let parsed: Record = toml::from_str(&toml::to_string(&record).unwrap()).unwrap();
+        assert_eq!(parsed.version("wheel"), Some(Version::new(0, 41, 2)));
+        assert_eq!(parsed.version("setuptools"), None);
+    }
+}
@@ -1,7 +1,9 @@
-use std::str::FromStr;
+use std::{fmt, path::PathBuf, str::FromStr};
 
 use structopt::StructOpt;
 
+use crate::{pyproject::settings::Overrides, util};
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "pyflow", about = "Python packaging and publishing")]
 pub struct Opt {
@@ -11,6 +13,147 @@ pub struct Opt {
     /// Force a color option: auto (default), always, ansi, never
     #[structopt(short, long)]
     pub color: Option<String>,
+
+    /// Show more detail, eg why each wheel was skipped when installing
+    #[structopt(short, long)]
+    pub verbose: bool,
+
+    /// Show less, eg no summary after installing
+    #[structopt(short, long)]
+    pub quiet: bool,
+
+    /// Recreate the project's `.venv`, eg after a system upgrade removed its Python
+    #[structopt(long)]
+    pub rebuild_venv: bool,
+
+    /// Don't query the dependency service; get each package's requirements from PyPI instead
+    #[structopt(long)]
+    pub no_deps_service: bool,
+
+    /// Always build packages from source, without using or storing cached builds
+    #[structopt(long)]
+    pub no_build_cache: bool,
+
+    /// Don't run the commands in `[tool.pyflow.hooks]`
+    #[structopt(long)]
+    pub no_hooks: bool,
+
+    /// If `pyflow.lock` won't parse, lock again instead of stopping
+    #[structopt(long)]
+    pub ignore_corrupt_lock: bool,
+
+    /// Use a `pyflow.lock` written by a newer pyflow in a lock format this one doesn't know,
+    /// instead of stopping
+    #[structopt(long)]
+    pub allow_newer_lock: bool,
+
+    /// Install and uninstall packages without asking first
+    #[structopt(long)]
+    pub yes: bool,
+
+    /// Check the version of each Python interpreter again, instead of using stored results
+    #[structopt(long)]
+    pub refresh_python_cache: bool,
+
+    /// Run Python with only the project's packages on `PYTHONPATH`, instead of adding them
+    /// ahead of any you set
+    #[structopt(long)]
+    pub isolated_pythonpath: bool,
+
+    /// Don't move files left by older versions of pyflow into their current places; only say
+    /// what would move
+    #[structopt(long)]
+    pub skip_migrations: bool,
+
+    /// Install wheels pinned with `wheel-tag` even if they're for another platform or Python
+    /// version
+    #[structopt(long)]
+    pub allow_foreign_wheel: bool,
+
+    /// Uninstall packages pyflow didn't install, eg copied into `lib` by hand, without asking
+    #[structopt(long)]
+    pub prune_foreign: bool,
+
+    /// Never change `pyproject.toml`; fail instead, saying what would have changed
+    #[structopt(long)]
+    pub frozen_pyproject: bool,
+
+    /// A directory of wheels and source dists to install from, alongside PyPI. May be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub find_links: Vec<String>,
+
+    /// Only install from `--find-links` directories, and `find_links` in `pyproject.toml`
+    #[structopt(long)]
+    pub no_index: bool,
+
+    /// Show how long each phase took, eg resolving, and downloading each package
+    #[structopt(long)]
+    pub timings: bool,
+
+    /// Format of reports like `--timings` and `pyflow licenses`, and of lock changes: text
+    /// (default), or json
+    #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+    pub format: String,
+
+    /// Install dev dependencies, overriding `install_dev_by_default` in
+    /// `[tool.pyflow.settings]`
+    #[structopt(long, conflicts_with = "no-dev")]
+    pub with_dev: bool,
+
+    /// Don't install dev dependencies. They're still locked
+    #[structopt(long)]
+    pub no_dev: bool,
+
+    /// Stop if a package's hash is missing or doesn't match, instead of asking
+    #[structopt(long, conflicts_with = "no-require-hashes")]
+    pub require_hashes: bool,
+
+    /// Ask if a package's hash doesn't match, overriding `require_hashes`
+    #[structopt(long)]
+    pub no_require_hashes: bool,
+
+    /// Allow pre-releases of all packages
+    #[structopt(long, conflicts_with = "no-pre")]
+    pub pre: bool,
+
+    /// Only allow pre-releases where a requirement does, overriding `allow_prereleases`
+    #[structopt(long)]
+    pub no_pre: bool,
+
+    /// Query this index, which must have PyPI's JSON API, instead of PyPI. eg
+    /// `https://pypi.example.com/pypi`
+    #[structopt(long)]
+    pub index_url: Option<String>,
+}
+
+/// `Some(true)` if a flag was passed, `Some(false)` if its opposite was, and `None` if neither.
+fn either(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+impl Opt {
+    /// Flags that override `[tool.pyflow.settings]`.
+    pub fn overrides(&self) -> Overrides {
+        let (scripts, no_scripts) = match &self.subcmds {
+            SubCommand::Install {
+                scripts,
+                no_scripts,
+                ..
+            } => (*scripts, *no_scripts),
+            _ => (false, false),
+        };
+        Overrides {
+            install_dev: either(self.with_dev, self.no_dev),
+            generate_scripts: either(scripts, no_scripts),
+            require_hashes: either(self.require_hashes, self.no_require_hashes),
+            allow_prereleases: either(self.pre, self.no_pre),
+            index_url: self.index_url.clone(),
+        }
+    }
 }
 
 #[derive(StructOpt, Debug)]
@@ -20,6 +163,9 @@ pub enum SubCommand {
     New {
         #[structopt(name = "name")]
         name: String, // holds the project name.
+        /// Python version, eg `3.11`, or the path of an interpreter to use
+        #[structopt(long)]
+        py: Option<String>,
     },
 
     /// Add packages to `pyproject.toml` and sync an environment
@@ -30,12 +176,20 @@ pub enum SubCommand {
         /// Save package to your dev-dependencies section
         #[structopt(short, long)]
         dev: bool,
+        /// Replace a package's constraints in `pyproject.toml`, even if no version satisfies both
+        /// the old and new ones
+        #[structopt(long)]
+        force: bool,
     },
 
     /** Install packages from `pyproject.toml`, `pyflow.lock`, or specified ones. Example:
 
     `pyflow install`: sync your installation with `pyproject.toml`, or `pyflow.lock` if it exists.
-    `pyflow install numpy scipy`: install `numpy` and `scipy`.*/
+    `pyflow install numpy scipy`: install `numpy` and `scipy`.
+    `pyflow install "numpy==1.26.2" --wheel-tag cp311-cp311-musllinux_1_1_x86_64`: install
+    numpy from its wheel with that tag, instead of the one pyflow would pick.
+    `pyflow install -e ../mylib`: install the project at `../mylib` in place.
+    `pyflow install -e . --extras cli`: also install the project's `cli` extra.*/
     #[structopt(name = "install")]
     Install {
         #[structopt(name = "packages")]
@@ -43,16 +197,180 @@ pub enum SubCommand {
         /// Save package to your dev-dependencies section
         #[structopt(short, long)]
         dev: bool,
+        /// Replace a package's constraints in `pyproject.toml`, even if no version satisfies both
+        /// the old and new ones
+        #[structopt(long)]
+        force: bool,
+        /// Only install the package from its wheel with this tag, eg
+        /// `cp311-cp311-manylinux_2_28_x86_64`. Saved as `wheel-tag` in `pyproject.toml`
+        #[structopt(long)]
+        wheel_tag: Option<String>,
+        /// Install the project at this path in place, eg `.` for this one, or `../mylib`
+        #[structopt(short, long)]
+        editable: Option<String>,
+        /// With `-e`, the project's extras to install, eg `--extras cli,yaml`
+        #[structopt(long, use_delimiter = true)]
+        extras: Vec<String>,
+        /// Don't create console scripts. Create them later with `pyflow scripts regenerate`
+        #[structopt(long)]
+        no_scripts: bool,
+        /// Create console scripts, overriding `generate_scripts` in `[tool.pyflow.settings]`
+        #[structopt(long, conflicts_with = "no-scripts")]
+        scripts: bool,
+        /// Replace console scripts another package already provides
+        #[structopt(long)]
+        force_scripts: bool,
+        /// Stop unpacking a wheel or source dist past this size, eg `4GiB`. Defaults to 2GiB
+        #[structopt(long)]
+        max_unpack_size: Option<String>,
+        /// If `pyproject.toml` has no pyflow section or dependencies, add pyflow's sections
+        /// without asking
+        #[structopt(long)]
+        init: bool,
     },
     /// Uninstall all packages, or ones specified
     #[structopt(name = "uninstall")]
     Uninstall {
         #[structopt(name = "packages")]
         packages: Vec<String>,
+        /// Uninstall every installed package, keeping `pyproject.toml` and `pyflow.lock`, so
+        /// `pyflow install` puts them back
+        #[structopt(long, conflicts_with = "packages")]
+        all: bool,
+        /// With `--all`, remove `pyflow.lock` too
+        #[structopt(long, requires = "all")]
+        and_lock: bool,
+        /// With `--all`, uninstall git and path dependencies, and packages in `preserve`, too
+        #[structopt(long, requires = "all")]
+        include_vcs: bool,
+        /// Don't ask before uninstalling with `--all`
+        #[structopt(short, long)]
+        yes: bool,
     },
     /// Display all installed packages and console scripts
     #[structopt(name = "list")]
-    List,
+    List {
+        /// Show the disk space each package uses
+        #[structopt(long)]
+        sizes: bool,
+    },
+    /// Summarize the licenses of installed packages. eg `pyflow licenses --fail-on GPL-3.0` exits
+    /// with an error if any package is licensed under the GPL v3
+    #[structopt(name = "licenses")]
+    Licenses {
+        /// Licenses to deny, eg `--fail-on GPL-3.0,AGPL-3.0`
+        #[structopt(long, use_delimiter = true)]
+        fail_on: Vec<String>,
+    },
+    /// Sync `pyflow.lock`, and check that its packages can be installed on other platforms. eg
+    /// `pyflow lock --platforms linux,windows,macos`. Lock for several Python versions with
+    /// eg `pyflow lock --python 3.8 --python 3.12`. `pyflow lock --prune` only removes packages
+    /// no longer required. `pyflow lock --check` fails if the lock is out of date
+    #[structopt(name = "lock")]
+    Lock {
+        /// Platforms to check for installable files: linux, windows, or macos
+        #[structopt(long, use_delimiter = true)]
+        platforms: Vec<String>,
+        /// Python versions to resolve for, eg 3.8. Packages needed by some of them only are
+        /// marked with `python_version`
+        #[structopt(long = "python", number_of_values = 1)]
+        pythons: Vec<String>,
+        /// Only remove packages no longer required from the lock
+        #[structopt(long)]
+        prune: bool,
+        /// Fail, showing what would change, if the lock doesn't match the requirements
+        #[structopt(long)]
+        check: bool,
+        /// Lock for another platform, eg `manylinux2014_x86_64`, using wheels only. Nothing is
+        /// installed
+        #[structopt(long)]
+        platform: Option<String>,
+        /// Lock for another Python version, eg 3.11, using wheels only
+        #[structopt(long)]
+        target_python: Option<String>,
+        /// Which compatible versions to pick: highest, lowest, or lowest-direct, the lowest for
+        /// the project's own requirements only. Re-resolves the whole lock
+        #[structopt(long)]
+        resolution: Option<String>,
+    },
+    /// Install the packages in `pyflow.lock` into a directory, eg for deployment to AWS Lambda.
+    /// eg `pyflow bundle build/deps --no-dev`
+    #[structopt(name = "bundle")]
+    Bundle {
+        #[structopt(name = "target")]
+        target: String,
+        /// Leave out dev dependencies
+        #[structopt(long)]
+        no_dev: bool,
+        /// Add console scripts to `<target>/bin`
+        #[structopt(long)]
+        scripts: bool,
+        /// Remove `__pycache__` folders and `RECORD` signature files
+        #[structopt(long)]
+        compact: bool,
+        /// Install into the directory even if it isn't empty
+        #[structopt(long)]
+        force: bool,
+        /// Install wheels for another platform, eg `manylinux2014_x86_64`. Defaults to the one
+        /// `pyflow.lock` was made for with `pyflow lock --platform`
+        #[structopt(long)]
+        platform: Option<String>,
+        /// Install wheels for another Python version, eg 3.11
+        #[structopt(long)]
+        target_python: Option<String>,
+    },
+    /// Show which package provides a console script, what it runs, and where it is. eg
+    /// `pyflow which black`
+    #[structopt(name = "which")]
+    Which {
+        #[structopt(name = "script")]
+        script: Option<String>,
+        /// List all console scripts
+        #[structopt(long)]
+        all: bool,
+    },
+    /// Manage the console scripts of installed packages. eg `pyflow scripts regenerate`
+    #[structopt(name = "scripts")]
+    Scripts {
+        #[structopt(subcommand)]
+        cmd: ScriptsCommand,
+    },
+    /// Get or change values under `[tool.pyflow]`, keeping the rest of `pyproject.toml` as
+    /// written. eg `pyflow config set py_version 3.11`
+    #[structopt(name = "config")]
+    Config {
+        #[structopt(subcommand)]
+        cmd: ConfigCommand,
+    },
+    /// Report on the environment's build tooling, eg outdated `wheel` or `setuptools`. eg
+    /// `pyflow check --compare requirements.txt` reports how another file's requirements differ
+    /// from `pyproject.toml`'s
+    #[structopt(name = "check")]
+    Check {
+        /// Only check `pyproject.toml`, reporting each problem with its key and line
+        #[structopt(long)]
+        config: bool,
+        /// A `requirements.txt` or `Pipfile` to compare with `pyproject.toml`. Exits with an
+        /// error if they differ
+        #[structopt(long)]
+        compare: Option<String>,
+        /// Regenerate this `requirements.txt` from `pyflow.lock`
+        #[structopt(long)]
+        sync_to: Option<String>,
+    },
+    /// Check for common problems, like a broken environment or a lock that's out of date, and
+    /// suggest fixes. Exits with an error if any check fails
+    #[structopt(name = "doctor")]
+    Doctor,
+    /// Check installed packages against the hashes in their `RECORD`s and `pyflow.lock`, without
+    /// downloading or installing anything. Exits with an error if any files or packages differ
+    #[structopt(name = "verify")]
+    Verify,
+    /// Show where packages, Python installations, script environments, and git dependencies
+    /// are kept. Move them with eg `PYFLOW_CACHE_DIR`, or `~/.config/pyflow/config.toml`. In a
+    /// project, also show the settings in effect, from `[tool.pyflow.settings]` and flags
+    #[structopt(name = "env")]
+    Env,
     /// Build the package - source and wheel
     #[structopt(name = "package")]
     Package {
@@ -64,7 +382,19 @@ pub enum SubCommand {
     Publish,
     /// Create a `pyproject.toml` from requirements.txt, pipfile etc, setup.py etc
     #[structopt(name = "init")]
-    Init,
+    Init {
+        /// Python version, eg `3.11`, or the path of an interpreter to use
+        #[structopt(long)]
+        py: Option<String>,
+    },
+    /// Create `pyflow.lock` from another tool's lock, so installs use the same versions. eg
+    /// `pyflow migrate poetry`
+    #[structopt(name = "migrate")]
+    Migrate {
+        /// The tool to migrate from. Only `poetry` is supported
+        #[structopt(name = "tool")]
+        tool: String,
+    },
     /// Remove the environment, and uninstall all packages
     #[structopt(name = "reset")]
     Reset,
@@ -83,6 +413,19 @@ pub enum SubCommand {
     #[structopt(name = "python ")]
     Python,
 
+    /// Run a package's CLI tool in an environment of its own, without touching the project. eg
+    /// `pyflow x cowsay hello`, or `pyflow x black==23.1.0 --check .`
+    #[structopt(name = "x", setting = structopt::clap::AppSettings::TrailingVarArg)]
+    X {
+        /// Python version for the environment, eg `3.11`
+        #[structopt(long)]
+        python: Option<String>,
+        #[structopt(name = "package")]
+        package: String,
+        #[structopt(name = "args", allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+
     /// Run a standalone script not associated with a project
     // Dummy option with space at the end for documentation
     #[structopt(name = "script ")]
@@ -95,12 +438,69 @@ pub enum SubCommand {
     //        name: String,
     //    },
     /// Change the Python version for this project. eg `pyflow switch 3.8`. Equivalent to setting
-    /// `py_version` in `pyproject.toml`.
+    /// `py_version` in `pyproject.toml`. Use an interpreter that isn't on the `PATH` with eg
+    /// `pyflow switch /opt/python/3.11.6/bin/python3`, which also sets `py_interpreter`.
     #[structopt(name = "switch")]
     Switch {
         #[structopt(name = "version")]
         version: String,
     },
+    /// Run a task from `[tool.pyflow.scripts]`, after the tasks it depends on. eg `pyflow task test`
+    #[structopt(name = "task")]
+    Task {
+        #[structopt(name = "name")]
+        name: Option<String>,
+        /// List the available tasks
+        #[structopt(short, long)]
+        list: bool,
+        /// Arguments to pass to the task, after `--`
+        #[structopt(name = "args", last = true)]
+        args: Vec<String>,
+    },
+    /// Explain why a version of a package can't be selected. eg `pyflow why-not urllib3 2.1.0`
+    #[structopt(name = "why-not")]
+    WhyNot {
+        #[structopt(name = "package")]
+        package: String,
+        #[structopt(name = "version")]
+        version: String,
+    },
+    /// Show requirements with newer versions on PyPI. eg `pyflow outdated --respect-constraints`
+    #[structopt(name = "outdated")]
+    Outdated {
+        /// Also show the latest version each constraint allows, a constraint for the latest,
+        /// and whether the requirements resolve with it
+        #[structopt(long)]
+        respect_constraints: bool,
+    },
+    /// Write a CycloneDX software bill of materials for the locked packages. eg
+    /// `pyflow sbom -o sbom.json`
+    #[structopt(name = "sbom")]
+    Sbom {
+        #[structopt(long, default_value = "cyclonedx-json", possible_values = &["cyclonedx-json"])]
+        format: String,
+        /// Write to this file, instead of printing
+        #[structopt(short, long)]
+        output: Option<String>,
+    },
+    /// Export the dependency graph in `pyflow.lock`. eg `pyflow graph | dot -Tsvg > deps.svg`
+    #[structopt(name = "graph")]
+    Graph {
+        #[structopt(long, default_value = "dot", possible_values = &["dot"])]
+        format: String,
+        /// Write to this file, instead of printing
+        #[structopt(short, long)]
+        output: Option<String>,
+        /// Only include this package, and what it requires
+        #[structopt(long)]
+        package: Option<String>,
+        /// Only include packages this many requirements away, eg 1 for direct dependencies' own
+        #[structopt(long)]
+        depth: Option<usize>,
+        /// Link each package to its PyPI page
+        #[structopt(long)]
+        urls: bool,
+    },
     // Documentation for supported external subcommands can be documented by
     // adding a `dummy` subcommand with the name having a trailing space.
     // #[structopt(name = "external ")]
@@ -108,7 +508,42 @@ pub enum SubCommand {
     External(Vec<String>),
 }
 
-#[derive(Clone, Debug)]
+#[derive(StructOpt, Debug)]
+pub enum ScriptsCommand {
+    /// Create the console scripts of all installed packages, eg after installing with
+    /// `--no-scripts`
+    #[structopt(name = "regenerate")]
+    Regenerate,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum ConfigCommand {
+    /// Show a value, eg `scripts.test`, or without a key, the section and the settings in
+    /// effect
+    #[structopt(name = "get")]
+    Get { key: Option<String> },
+    /// Set a value, eg `pyflow config set settings.require_hashes true`. Lists, like `authors`,
+    /// take one value per item
+    #[structopt(name = "set")]
+    Set {
+        key: String,
+        #[structopt(required = true)]
+        values: Vec<String>,
+        /// Allow keys pyflow doesn't know, and write the value as TOML, without checking it
+        #[structopt(long)]
+        raw: bool,
+    },
+    /// Remove a value, or a table, eg `pyflow config unset settings.index_url`
+    #[structopt(name = "unset")]
+    Unset {
+        key: String,
+        /// Allow keys pyflow doesn't know
+        #[structopt(long)]
+        raw: bool,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum ExternalSubcommands {
     Run,
     Script,
@@ -117,15 +552,15 @@ pub enum ExternalSubcommands {
     ImpliedPython(String),
 }
 
-impl ToString for ExternalSubcommands {
-    fn to_string(&self) -> String {
-        match self {
-            Self::Run => "run".into(),
-            Self::Script => "script".into(),
-            Self::Python => "python".into(),
-            Self::ImpliedRun(x) => x.into(),
-            Self::ImpliedPython(x) => x.into(),
-        }
+impl fmt::Display for ExternalSubcommands {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Run => "run",
+            Self::Script => "script",
+            Self::Python => "python",
+            Self::ImpliedRun(x) => x,
+            Self::ImpliedPython(x) => x,
+        })
     }
 }
 
@@ -147,6 +582,15 @@ impl FromStr for ExternalSubcommands {
 pub struct ExternalCommand {
     pub cmd: ExternalSubcommands,
     pub args: Vec<String>,
+    /// `KEY=VALUE` pairs from `--env`, for `run` and `python`.
+    pub env: Vec<String>,
+    /// From `--dotenv`: A file to load variables from, instead of the project's `.env`.
+    pub dotenv: Option<PathBuf>,
+    /// From `--watch`, for `run`: Globs of files to rerun the command when they change. Empty
+    /// to watch the defaults.
+    pub watch: Option<Vec<String>>,
+    /// Stop a run still in progress when files change; off with `--no-kill`.
+    pub kill: bool,
 }
 
 impl ExternalCommand {
@@ -163,9 +607,58 @@ impl ExternalCommand {
             ExternalSubcommands::ImpliedPython(_) => ExternalSubcommands::Python,
             x => x,
         };
+        let mut args = cmd_args.to_vec();
+        let mut env = vec![];
+        let mut dotenv = None;
+        let mut watch: Option<Vec<String>> = None;
+        let mut kill = true;
+        if matches!(cmd, ExternalSubcommands::Run | ExternalSubcommands::Python) {
+            // Only leading options are ours; later ones belong to the command being run, as do
+            // all after `--`.
+            loop {
+                match args.first().map(String::as_str) {
+                    Some("--") => {
+                        args.remove(0);
+                        break;
+                    }
+                    Some("--env") if args.len() > 1 => {
+                        env.push(args.remove(1));
+                        args.remove(0);
+                    }
+                    Some(a) if a.starts_with("--env=") => {
+                        env.push(args.remove(0)["--env=".len()..].to_owned());
+                    }
+                    Some("--dotenv") if args.len() > 1 => {
+                        dotenv = Some(PathBuf::from(args.remove(1)));
+                        args.remove(0);
+                    }
+                    // The glob is optional, so we tell it from the command by its characters.
+                    Some("--watch") if cmd == ExternalSubcommands::Run => {
+                        args.remove(0);
+                        let globs = watch.get_or_insert_with(Vec::new);
+                        if args.first().is_some_and(|a| util::watch::is_glob(a)) {
+                            globs.push(args.remove(0));
+                        }
+                    }
+                    Some(a) if a.starts_with("--watch=") && cmd == ExternalSubcommands::Run => {
+                        let glob = args.remove(0)["--watch=".len()..].to_owned();
+                        watch.get_or_insert_with(Vec::new).push(glob);
+                    }
+                    Some("--no-kill") if cmd == ExternalSubcommands::Run => {
+                        args.remove(0);
+                        kill = false;
+                    }
+                    _ => break,
+                }
+            }
+        }
         Self {
             cmd,
-            args: cmd_args.to_vec(),
+            args,
+            env,
+            dotenv,
+            watch,
+            kill,
         }
     }
 }
@@ -1,8 +1,10 @@
 use std::{
+    env,
     error::Error,
     fmt,
     path::{Path, PathBuf},
-    process::{Command, Stdio},
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    str::FromStr,
 };
 
 use regex::Regex;
@@ -26,60 +28,78 @@ impl fmt::Display for _ExecutionError {
     }
 }
 
-/// Todo: Dry from `find_py_version`
-pub fn find_py_dets(alias: &str) -> Option<String> {
-    let output = Command::new(alias).args(&["--version, --version"]).output();
-
-    let output_bytes = match output {
-        Ok(ob) => {
-            // Old versions of python output `--version` to `stderr`; newer ones to `stdout`,
-            // so check both.
-            if ob.stdout.is_empty() {
-                ob.stderr
-            } else {
-                ob.stdout
-            }
-        }
-        Err(_) => return None,
-    };
-
-    match std::str::from_utf8(&output_bytes) {
-        Ok(r) => Some(r.to_owned()),
-        Err(_) => None,
-    }
+/// What an interpreter's `--version` reports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PyVersionInfo {
+    pub version: crate::Version,
+    /// Markers and wheel tags are matched as for CPython, so compiled packages may not work.
+    pub pypy: bool,
 }
 
 /// Find the Python version from the `python --py_version` command. Eg: "Python 3.7".
 pub fn find_py_version(alias: &str) -> Option<crate::Version> {
-    let output = Command::new(alias).arg("--version").output();
-
-    let output_bytes = match output {
-        Ok(ob) => {
-            // Old versions of python output `--version` to `stderr`; newer ones to `stdout`,
-            // so check both.
-            if ob.stdout.is_empty() {
-                ob.stderr
-            } else {
-                ob.stdout
-            }
-        }
-        Err(_) => return None,
-    };
+    find_py_info(alias).map(|info| info.version)
+}
 
-    if let Ok(version) = std::str::from_utf8(&output_bytes) {
-        let re = Regex::new(r"Python\s+(\d{1,4})\.(\d{1,4})\.(\d{1,4})").unwrap();
-        match re.captures(version) {
-            Some(caps) => {
-                let major = caps.get(1).unwrap().as_str().parse::<u32>().unwrap();
-                let minor = caps.get(2).unwrap().as_str().parse::<u32>().unwrap();
-                let patch = caps.get(3).unwrap().as_str().parse::<u32>().unwrap();
-                Some(crate::Version::new(major, minor, patch))
-            }
-            None => None,
-        }
-    } else {
-        None
+/// Find the Python version, and whether it's PyPy, from `python --version`.
+pub fn find_py_info(alias: &str) -> Option<PyVersionInfo> {
+    let output = Command::new(alias).arg("--version").output().ok()?;
+    parse_py_info(&output)
+}
+
+/// The version reported by `python --version`.
+pub fn parse_py_version(output: &Output) -> Option<crate::Version> {
+    parse_py_info(output).map(|info| info.version)
+}
+
+fn parse_py_info(output: &Output) -> Option<PyVersionInfo> {
+    // Old versions of python output `--version` to `stderr`; newer ones to `stdout`,
+    // so check both.
+    let text = [
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr),
+    ]
+    .concat();
+    parse_version_text(&text)
+}
+
+/// Parse `--version` output, eg `Python 3.7.3 :: Anaconda, Inc.`, or PyPy's
+/// `Python 3.9.18 (7.3.13+dfsg-1, ...)`, followed by `[PyPy 7.3.13 ...]`. Vendor annotations
+/// and build info are dropped. Windows Store stubs, which only offer to install Python, give
+/// `None`.
+pub fn parse_version_text(text: &str) -> Option<PyVersionInfo> {
+    let re = Regex::new(r"^Python\s+(\S+)").unwrap();
+    let version = text.lines().find_map(|line| {
+        let line = line.split("::").next()?.split('(').next()?.trim();
+        let raw = re.captures(line)?.get(1)?.as_str().trim_end_matches('+');
+        let v = crate::Version::from_str(raw).ok()?;
+        Some(crate::Version::new(
+            v.major?,
+            v.minor?,
+            v.patch.unwrap_or(0),
+        ))
+    })?;
+    Some(PyVersionInfo {
+        version,
+        pypy: text.contains("PyPy"),
+    })
+}
+
+/// Find the real path of an interpreter, following symlinks, eg `/usr/bin/python3.11` for
+/// `python3`.
+pub fn find_py_executable(alias: &Path) -> Option<PathBuf> {
+    let output = Command::new(alias)
+        .args([
+            "-c",
+            "import os, sys; print(os.path.realpath(sys.executable))",
+        ])
+        .output()
+        .ok()?;
+    let path = std::str::from_utf8(&output.stdout).ok()?.trim();
+    if !output.status.success() || path.is_empty() {
+        return None;
     }
+    Some(PathBuf::from(path))
 }
 
 /// Create the virtual env. Assume we're running Python 3.3+, where `venv` is included.
@@ -87,7 +107,7 @@ pub fn find_py_version(alias: &str) -> Option<crate::Version> {
 pub fn create_venv(py_alias: &str, lib_path: &Path, name: &str) -> Result<(), Box<dyn Error>> {
     // While creating the lib path, we're creating the __pypackages__ structure.
     let output = Command::new(py_alias)
-        .args(&["-m", "venv", name])
+        .args(["-m", "venv", name])
         .current_dir(lib_path.join("../"))
         .output()?;
     util::check_command_output(&output, "creating virtual environment");
@@ -99,7 +119,7 @@ pub fn create_venv(py_alias: &str, lib_path: &Path, name: &str) -> Result<(), Bo
 pub fn create_venv2(py_alias: &Path, lib_path: &Path, name: &str) -> Result<(), Box<dyn Error>> {
     // While creating the lib path, we're creating the __pypackages__ structure.
     let output = Command::new(py_alias)
-        .args(&["-m", "venv", name])
+        .args(["-m", "venv", name])
         .current_dir(lib_path.join("../"))
         .output()?;
     util::check_command_output(&output, "creating virtual environment");
@@ -107,19 +127,89 @@ pub fn create_venv2(py_alias: &Path, lib_path: &Path, name: &str) -> Result<(),
     Ok(())
 }
 
+/// The environment's Python, with `args` passed as-is, eg `--version` or `-c`.
+pub fn python_command(bin_path: &Path, lib_paths: &[PathBuf], args: &[String]) -> Command {
+    util::pythonpath::set(lib_paths);
+    let mut command = Command::new(bin_path.join("python"));
+    util::dotenv::apply(&mut command).args(args);
+    command
+}
+
 pub fn run_python(
     bin_path: &Path,
     lib_paths: &[PathBuf],
     args: &[String],
-) -> Result<(), Box<dyn Error>> {
-    util::set_pythonpath(lib_paths);
-    Command::new(bin_path.join("python"))
-        .args(args)
+) -> Result<ExitStatus, Box<dyn Error>> {
+    Ok(python_command(bin_path, lib_paths, args)
         .stdin(Stdio::inherit())
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
-        .output()?;
-    Ok(())
+        .status()?)
+}
+
+/// A command line, to run in the system shell.
+pub fn shell_command(cmd: &str) -> Command {
+    let (shell, flag) = if cfg!(target_os = "windows") {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut command = Command::new(shell);
+    command.args([flag, cmd]);
+    command
+}
+
+/// Quote an argument for the shell `shell_command` runs it in, so it's passed as written, as
+/// one argument.
+pub fn shell_quote(arg: &str) -> String {
+    if cfg!(target_os = "windows") {
+        format!("\"{}\"", arg.replace('"', "\\\""))
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Run a command line in the system shell, with the environment's `bin` directory first
+/// on the `PATH`.
+pub fn run_shell(
+    bin_path: &Path,
+    lib_paths: &[PathBuf],
+    cmd: &str,
+) -> Result<ExitStatus, Box<dyn Error>> {
+    util::pythonpath::set(lib_paths);
+    let mut path = vec![bin_path.to_owned()];
+    if let Some(existing) = env::var_os("PATH") {
+        path.extend(env::split_paths(&existing));
+    }
+
+    Ok(util::dotenv::apply(&mut shell_command(cmd))
+        .env("PATH", env::join_paths(path)?)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()?)
+}
+
+/// Start a command in a process group of its own, so `stop_group` can stop it along with
+/// anything it starts, eg the Python a `pyflow run` starts. On Unix, this means it can't read
+/// from the terminal.
+pub fn spawn_group(command: &mut Command) -> std::io::Result<Child> {
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command, 0);
+    command.spawn()
+}
+
+/// Stop a process started with `spawn_group`, and the processes it started.
+pub fn stop_group(id: u32) {
+    #[cfg(unix)]
+    unsafe {
+        libc::killpg(id as libc::pid_t, libc::SIGTERM);
+    }
+    #[cfg(windows)]
+    Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &id.to_string()])
+        .output()
+        .ok();
 }
 
 pub fn download_git_repo(repo: &str, dest_path: &Path) -> Result<(), Box<dyn Error>> {
@@ -131,18 +221,152 @@ pub fn download_git_repo(repo: &str, dest_path: &Path) -> Result<(), Box<dyn Err
 
     let output = Command::new("git")
         .current_dir(dest_path)
-        .args(&["clone", repo])
+        .args(["clone", repo])
         .output()?;
-    util::check_command_output(&output, "cloning repo");
-    Ok(())
+    git_result(&output)
+}
+
+/// Check out a branch, tag, or commit in a cloned repo, fetching from its remote if the clone
+/// doesn't have it yet.
+pub fn git_checkout(repo_path: &Path, git_ref: &str) -> Result<(), Box<dyn Error>> {
+    let checkout = || {
+        Command::new("git")
+            .current_dir(repo_path)
+            .args(["checkout", "--quiet", git_ref])
+            .output()
+    };
+    if checkout()?.status.success() {
+        return Ok(());
+    }
+    let fetch = Command::new("git")
+        .current_dir(repo_path)
+        .args(["fetch", "--quiet", "--tags", "origin"])
+        .output()?;
+    git_result(&fetch)?;
+    git_result(&checkout()?)
+}
+
+/// How many lines from the end of git's stderr to show when it fails.
+const GIT_STDERR_TAIL: usize = 10;
+
+/// An error with the end of git's stderr, if it failed.
+fn git_result(output: &Output) -> Result<(), Box<dyn Error>> {
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<&str> = stderr.lines().filter(|l| !l.trim().is_empty()).collect();
+    let tail = lines[lines.len().saturating_sub(GIT_STDERR_TAIL)..].join("\n");
+    Err(tail.into())
 }
 
 /// Initialize a new git repo.
 pub fn git_init(dir: &Path) -> Result<(), Box<dyn Error>> {
     let output = Command::new("git")
         .current_dir(dir)
-        .args(&["init", "--quiet"])
+        .args(["init", "--quiet"])
         .output()?;
     util::check_command_output(&output, "initializing git repository");
     Ok(())
 }
+
+/// The commit checked out in a git repo.
+pub fn git_head(repo: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .current_dir(repo)
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    let commit = std::str::from_utf8(&output.stdout).ok()?.trim();
+    if !output.status.success() || commit.is_empty() {
+        return None;
+    }
+    Some(commit.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+        text,
+        expected,
+        pypy,
+        case::cpython("Python 3.11.7\n", Some((3, 11, 7)), false),
+        case::anaconda("Python 3.7.3 :: Anaconda, Inc.\n", Some((3, 7, 3)), false),
+        case::anaconda_py2("Python 2.7.15 :: Anaconda, Inc.\n", Some((2, 7, 15)), false),
+        case::py2("Python 2.7.18\n", Some((2, 7, 18)), false),
+        case::pypy(
+            "Python 3.9.18 (7.3.13+dfsg-1~ppa1~ubuntu22.04, Oct 02 2023, 10:26:22)\n\
+             [PyPy 7.3.13 with GCC 11.4.0]\n",
+            Some((3, 9, 18)),
+            true
+        ),
+        case::dev_build("Python 3.12.0+\n", Some((3, 12, 0)), false),
+        case::release_candidate("Python 3.13.0rc2\r\n", Some((3, 13, 0)), false),
+        case::windows_store_stub(
+            "Python was not found; run without arguments to install from the Microsoft Store, \
+             or disable this shortcut from Settings > Manage App Execution Aliases.\r\n",
+            None,
+            false
+        ),
+        case::empty("", None, false)
+    )]
+    fn version_output(text: &str, expected: Option<(u32, u32, u32)>, pypy: bool) {
+        let info = parse_version_text(text);
+        assert_eq!(
+            info.as_ref().map(|i| i.version.clone()),
+            expected.map(|(major, minor, patch)| crate::Version::new(major, minor, patch))
+        );
+        assert_eq!(info.is_some_and(|i| i.pypy), pypy);
+    }
+
+    #[test]
+    fn version_on_stderr() {
+        let output = Output {
+            status: ExitStatus::default(),
+            stdout: vec![],
+            stderr: b"Python 2.7.18\n".to_vec(),
+        };
+        assert_eq!(
+            parse_py_version(&output),
+            Some(crate::Version::new(2, 7, 18))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn python_args_passed_through() {
+        use crate::cli_options::ExternalCommand;
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let bin = tmp.path().to_owned();
+        let python = bin.join("python");
+        std::fs::write(
+            &python,
+            "#!/bin/sh\nif [ \"$1\" = --version ]; then echo 'Python 3.11.7'; \
+             else printf '%s\\n' \"$@\"; fi\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&python, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let output = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            let cmd = ExternalCommand::from_opt(args);
+            let out = python_command(&bin, &[], &cmd.args).output().unwrap();
+            String::from_utf8(out.stdout).unwrap()
+        };
+        let direct = Command::new(&python).arg("--version").output().unwrap();
+        assert_eq!(output(&["python", "--version"]).as_bytes(), direct.stdout);
+        assert_eq!(
+            output(&["python", "-c", "print(1)", "--env", "A=1"]),
+            "-c\nprint(1)\n--env\nA=1\n"
+        );
+        assert_eq!(
+            output(&["python", "--env", "A=1", "--", "--env", "B=2"]),
+            "--env\nB=2\n"
+        );
+    }
+}
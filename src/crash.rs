@@ -0,0 +1,198 @@
+//! Write a crash report to a local file when pyflow panics, so users can attach it when
+//! filing an issue. Nothing is sent anywhere.
+
+use std::{
+    backtrace::Backtrace,
+    collections::VecDeque,
+    env, fs, panic,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use regex::Regex;
+
+use crate::dep_types::Version;
+
+/// How many lines of output to include in reports.
+const LOG_LINES: usize = 50;
+
+/// Set this to `1` to skip writing crash reports.
+const OPT_OUT_VAR: &str = "PYFLOW_NO_CRASH_REPORT";
+
+static LOG: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+static PY_VERSION: Mutex<Option<String>> = Mutex::new(None);
+
+/// Keep a line of output, to include in crash reports.
+pub fn log(line: &str) {
+    if let Ok(mut log) = LOG.lock() {
+        if log.len() == LOG_LINES {
+            log.pop_front();
+        }
+        log.push_back(line.to_owned());
+    }
+}
+
+/// Record the Python version in use, once we know it.
+pub fn set_py_version(py_vers: &Version) {
+    if let Ok(mut v) = PY_VERSION.lock() {
+        *v = Some(py_vers.to_string());
+    }
+}
+
+/// Install a panic hook that writes a crash report under `pyflow_path`, after the usual
+/// panic message.
+pub fn install_hook(pyflow_path: PathBuf) {
+    if env::var(OPT_OUT_VAR).is_ok_and(|v| v == "1") {
+        return;
+    }
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+
+        let project_dir = env::current_dir().unwrap_or_default();
+        let args: Vec<String> = env::args().skip(1).collect();
+        let report = make_report(
+            &sanitize_args(&args, &project_dir),
+            &info.to_string(),
+            &Backtrace::force_capture().to_string(),
+            &project_dir,
+        );
+
+        match write_report(&pyflow_path.join("crash_reports"), &report) {
+            Ok(path) => eprintln!(
+                "\nA crash report was saved to {}\n\
+                 Please attach this file when filing an issue: \
+                 https://github.com/David-OConnor/pyflow/issues",
+                path.to_string_lossy()
+            ),
+            Err(_) => eprintln!("Problem saving a crash report"),
+        }
+    }));
+}
+
+fn write_report(dir: &Path, report: &str) -> std::io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let path = dir.join(format!("crash-{}.txt", timestamp));
+    fs::write(&path, report)?;
+    Ok(path)
+}
+
+fn make_report(args: &[String], message: &str, backtrace: &str, project_dir: &Path) -> String {
+    let py_version = PY_VERSION
+        .lock()
+        .ok()
+        .and_then(|v| v.clone())
+        .unwrap_or_else(|| "(unknown)".into());
+    let log: Vec<String> = LOG
+        .lock()
+        .map(|l| l.iter().map(|line| redact(line, project_dir)).collect())
+        .unwrap_or_default();
+
+    format!(
+        "pyflow version: {}\nOS: {} {}\nCommand: pyflow {}\nPython version: {}\n\n\
+         Recent output:\n{}\n\nPanic: {}\n\nBacktrace:\n{}\n",
+        env!("CARGO_PKG_VERSION"),
+        env::consts::OS,
+        env::consts::ARCH,
+        args.join(" "),
+        py_version,
+        log.join("\n"),
+        redact(message, project_dir),
+        redact(backtrace, project_dir),
+    )
+}
+
+/// Keep subcommands, flags, and package names; replace paths, and anything that looks like one.
+fn sanitize_args(args: &[String], project_dir: &Path) -> Vec<String> {
+    args.iter()
+        .map(|arg| {
+            if arg.contains('/') || arg.contains('\\') || arg.starts_with('~') {
+                let rel = Path::new(arg)
+                    .strip_prefix(project_dir)
+                    .ok()
+                    .filter(|_| project_dir.is_absolute());
+                match rel {
+                    Some(r) => r.to_string_lossy().into_owned(),
+                    None => "<path>".into(),
+                }
+            } else {
+                arg.clone()
+            }
+        })
+        .collect()
+}
+
+/// Make paths in the project directory project-relative, and replace other absolute paths.
+fn redact(text: &str, project_dir: &Path) -> String {
+    let mut text = text.to_owned();
+    if project_dir.parent().is_some() {
+        for sep in ["/", "\\"] {
+            text = text.replace(&format!("{}{}", project_dir.to_string_lossy(), sep), "./");
+        }
+    }
+
+    let unix = Regex::new(r#"(^|[\s"'(=])(?:~|/)[^\s"')]*"#).unwrap();
+    let text = unix.replace_all(&text, "$1<path>");
+    let windows = Regex::new(r#"[A-Za-z]:\\[^\s"')]*"#).unwrap();
+    windows.replace_all(&text, "<path>").into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn args_keep_packages_only() {
+        let project_dir = Path::new("/home/user/proj");
+        let args: Vec<String> = [
+            "install",
+            "requests>=2",
+            "--dev",
+            "/home/user/proj/pkg/module.py",
+            "/etc/secrets",
+            "~/notes.txt",
+        ]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+
+        assert_eq!(
+            sanitize_args(&args, project_dir),
+            vec![
+                "install",
+                "requests>=2",
+                "--dev",
+                "pkg/module.py",
+                "<path>",
+                "<path>"
+            ]
+        );
+    }
+
+    #[test]
+    fn paths_redacted() {
+        let project_dir = Path::new("/home/user/proj");
+
+        assert_eq!(
+            redact(
+                "Problem opening /home/user/proj/pyflow.lock and '/home/user/proj2/x'",
+                project_dir
+            ),
+            "Problem opening ./pyflow.lock and '<path>'"
+        );
+        assert_eq!(
+            redact(r"Can't find C:\Users\user\python.exe", project_dir),
+            "Can't find <path>"
+        );
+        assert_eq!(
+            redact("Installed requests 2.31.0", project_dir),
+            "Installed requests 2.31.0"
+        );
+    }
+}
@@ -2,44 +2,131 @@ use std::str::FromStr;
 
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take, take_till},
+    bytes::complete::{tag, take_till, take_till1, take_while1},
     character::{
-        complete::{digit1, space0, space1},
+        complete::{digit1, space0},
         is_alphabetic,
     },
-    combinator::{flat_map, map, map_parser, map_res, opt, value},
-    multi::separated_list,
-    sequence::{delimited, preceded, separated_pair, tuple},
+    combinator::{flat_map, map, map_opt, map_res, opt, value},
+    multi::{separated_list, separated_nonempty_list},
+    sequence::{delimited, preceded, separated_pair, terminated, tuple},
     AsChar, IResult, InputTakeAtPosition,
 };
 
 use crate::{
-    dep_types::{Constraint, Extras, Req, ReqType, Version, VersionModifier},
+    dep_types::{Constraint, Extras, Marker, Req, ReqType, Version, VersionModifier},
     util::Os,
 };
 
-enum ExtrasPart {
-    Extra(String),
-    SysPlatform(ReqType, Os),
-    PythonVersion(Constraint),
-}
+/// Constraints, git url, and environment markers from a dependency's string form.
+type CfgValue = (Vec<Constraint>, Option<String>, Option<Extras>);
 
 pub fn parse_req(input: &str) -> IResult<&str, Req> {
     // eg saturn = ">=0.3.4", as in pyproject.toml
-    map(
-        alt((
+    alt((
+        map(
             separated_pair(
                 parse_package_name,
                 tuple((space0, tag("="), space0)),
-                delimited(quote, parse_constraints, quote),
+                delimited(quote, parse_cfg_value, quote),
+            ),
+            |(name, (constraints, git, extras))| make_cfg_req(name, constraints, git, extras),
+        ),
+        map(parse_package_name, |x| Req::new(x.to_string(), vec![])),
+    ))(input)
+}
+
+/// A dependency's value in string form, as in `pyproject.toml`. Eg `>=2.28`,
+/// `>=2.28 ; python_version >= '3.8'`, or `saturn @ git+https://github.com/org/saturn`. The name
+/// before `@` is optional, since it repeats the key.
+pub fn parse_cfg_value(input: &str) -> IResult<&str, CfgValue> {
+    map(
+        tuple((
+            preceded(
+                space0,
+                alt((
+                    map(parse_git_url, |url| (vec![], Some(url))),
+                    map(parse_constraints, |c| (c, None)),
+                )),
             ),
-            map(parse_package_name, |x| (x, vec![])),
+            opt(preceded(tuple((space0, tag(";"), space0)), parse_extras)),
+            space0,
         )),
-        |(name, constraints)| Req::new(name.to_string(), constraints),
+        |((constraints, git), extras, _)| (constraints, git, extras),
+    )(input)
+}
+
+/// Build a `Req` from the parts of a dependency's string form.
+pub fn make_cfg_req(
+    name: &str,
+    constraints: Vec<Constraint>,
+    git: Option<String>,
+    extras: Option<Extras>,
+) -> Req {
+    let mut req = match extras {
+        Some(ex) => Req::new_with_extras(name.to_string(), constraints, ex),
+        None => Req::new(name.to_string(), constraints),
+    };
+    req.git = git;
+    req
+}
+
+/// Eg `@ git+https://github.com/org/saturn`, optionally preceded by the package name. We
+/// only support git repos, so other urls must end in `.git`. Returns the url to clone.
+fn parse_git_url(input: &str) -> IResult<&str, String> {
+    map_opt(
+        preceded(
+            tuple((
+                opt(terminated(parse_package_name, space0)),
+                tag("@"),
+                space0,
+            )),
+            // Urls are separated from markers by whitespace, and can't contain quotes.
+            take_till1(|c: char| c.is_whitespace() || c == '"' || c == '\''),
+        ),
+        |url: &str| {
+            if let Some(stripped) = url.strip_prefix("git+") {
+                Some(stripped.to_string())
+            } else if url.ends_with(".git") {
+                Some(url.to_string())
+            } else {
+                None
+            }
+        },
+    )(input)
+}
+
+/// A PEP 508 direct reference, eg `mylib[cli] @ https://example.com/mylib-1.0.tar.gz ;
+/// python_version >= "3.8"`.
+fn parse_direct_ref(input: &str) -> IResult<&str, Req> {
+    map(
+        tuple((
+            parse_package_name,
+            opt(preceded(space0, parse_install_with_extras)),
+            preceded(
+                tuple((space0, tag("@"), space0)),
+                take_till1(|c: char| c.is_whitespace() || c == ';'),
+            ),
+            opt(preceded(tuple((space0, tag(";"), space0)), parse_extras)),
+        )),
+        |(name, install_with_extras, url, extras): (&str, _, &str, _)| {
+            let mut r = make_cfg_req(name, vec![], None, extras);
+            match url.strip_prefix("git+") {
+                Some(git) => r.git = Some(git.to_string()),
+                None if url.ends_with(".git") => r.git = Some(url.to_string()),
+                None => r.url = Some(url.to_string()),
+            }
+            r.install_with_extras = install_with_extras;
+            r
+        },
     )(input)
 }
 
 pub fn parse_req_pypi_fmt(input: &str) -> IResult<&str, Req> {
+    alt((parse_direct_ref, parse_req_pypi_constrained))(input)
+}
+
+fn parse_req_pypi_constrained(input: &str) -> IResult<&str, Req> {
     // eg saturn (>=0.3.4) or argon2-cffi (>=16.1.0) ; extra == 'argon2'
     // Note: We specify what chars are acceptable in a name instead of using
     // wildcard, so we don't accidentally match a semicolon here if a
@@ -48,16 +135,22 @@ pub fn parse_req_pypi_fmt(input: &str) -> IResult<&str, Req> {
     map(
         alt((
             tuple((
-                tuple((parse_package_name, opt(parse_install_with_extras))),
+                tuple((
+                    parse_package_name,
+                    opt(preceded(space0, parse_install_with_extras)),
+                )),
                 alt((
                     preceded(space0, delimited(tag("("), parse_constraints, tag(")"))),
-                    preceded(space1, parse_constraints),
+                    preceded(space0, parse_constraints),
                 )),
                 opt(preceded(tuple((space0, tag(";"), space0)), parse_extras)),
             )),
             map(
                 tuple((
-                    tuple((parse_package_name, opt(parse_install_with_extras))),
+                    tuple((
+                        parse_package_name,
+                        opt(preceded(space0, parse_install_with_extras)),
+                    )),
                     opt(preceded(tuple((space0, tag(";"), space0)), parse_extras)),
                 )),
                 |(x, y)| (x, vec![], y),
@@ -82,6 +175,8 @@ pub fn parse_pip_str(input: &str) -> IResult<&str, Req> {
     )(input)
 }
 
+/// A release file's `python_version` on the warehouse, eg `py2.py3`, `cp311.cp312`, `3.10`, or
+/// `source`. The empty string is handled by `Constraint::from_wh_py_vers`.
 pub fn parse_wh_py_vers(input: &str) -> IResult<&str, Vec<Constraint>> {
     alt((
         map(tag("any"), |_| {
@@ -91,34 +186,39 @@ pub fn parse_wh_py_vers(input: &str) -> IResult<&str, Vec<Constraint>> {
             vec![Constraint::new(ReqType::Gte, Version::new(2, 0, 0))]
         }),
         map(parse_version, |v| vec![Constraint::new(ReqType::Caret, v)]),
-        separated_list(tag("."), parse_wh_py_ver),
+        separated_nonempty_list(delimited(space0, tag("."), space0), parse_wh_py_ver),
     ))(input)
 }
 
 fn parse_wh_py_ver(input: &str) -> IResult<&str, Constraint> {
-    map(
+    map_opt(
         tuple((
             alt((tag("cp"), tag("py"), tag("pp"))),
             alt((tag("2"), tag("3"), tag("4"))),
-            opt(map_parser(take(1u8), digit1)),
             opt(digit1),
         )),
-        |(_, major, minor, patch): (_, &str, Option<&str>, Option<&str>)| {
+        |(_, major, rest): (_, &str, Option<&str>)| {
             let major: u32 = major.parse().unwrap();
-            let patch = patch.map(|p| p.parse().unwrap());
-            match minor {
-                Some(mi) => Constraint::new(
-                    ReqType::Exact,
-                    Version::new_opt(Some(major), Some(mi.parse().unwrap()), patch),
-                ),
-                None => {
-                    if major == 2 {
-                        Constraint::new(ReqType::Lte, Version::new_short(2, 10))
-                    } else {
-                        Constraint::new(ReqType::Gte, Version::new_short(3, 0))
-                    }
+            let version = match rest {
+                None if major == 2 => {
+                    return Some(Constraint::new(ReqType::Lte, Version::new_short(2, 10)))
                 }
-            }
+                None => return Some(Constraint::new(ReqType::Gte, Version::new_short(3, 0))),
+                // Python 3 tags give the whole minor version, eg `cp311` for any 3.11.
+                Some(minor) if major >= 3 => {
+                    Version::new_star(Some(major), Some(minor.parse().ok()?), None, true)
+                }
+                // Older PyPy tags give its own version, eg `pp257`.
+                Some(rest) => {
+                    let (minor, patch) = rest.split_at(1);
+                    let patch = match patch {
+                        "" => None,
+                        p => Some(p.parse().ok()?),
+                    };
+                    Version::new_opt(Some(major), Some(minor.parse().ok()?), patch)
+                }
+            };
+            Some(Constraint::new(ReqType::Exact, version))
         },
     )(input)
 }
@@ -131,48 +231,102 @@ fn parse_install_with_extras(input: &str) -> IResult<&str, Vec<String>> {
     map(
         delimited(
             tag("["),
-            separated_list(tag(","), parse_package_name),
-            tag("]"),
+            separated_list(
+                delimited(space0, tag(","), space0),
+                preceded(space0, parse_package_name),
+            ),
+            preceded(space0, tag("]")),
         ),
         |extras| extras.iter().map(|x| x.to_string()).collect(),
     )(input)
 }
 
 pub fn parse_extras(input: &str) -> IResult<&str, Extras> {
+    map(parse_marker, Extras::from_marker)(input)
+}
+
+/// Parse an environment marker, eg `sys_platform == "darwin" or sys_platform == "linux"`.
+/// As in PEP 508, `and` binds more tightly than `or`.
+pub fn parse_marker(input: &str) -> IResult<&str, Marker> {
     map(
-        separated_list(
-            delimited(space0, tag("and"), space0),
-            delimited(
-                opt(preceded(tag("("), space0)),
-                parse_extra_part,
-                opt(preceded(space0, tag(")"))),
-            ),
-        ),
-        |ps| {
-            let mut extra = None;
-            let mut sys_platform = None;
-            let mut python_version = None;
-
-            for p in ps {
-                match p {
-                    ExtrasPart::Extra(s) => extra = Some(s),
-                    ExtrasPart::SysPlatform(r, o) => sys_platform = Some((r, o)),
-                    ExtrasPart::PythonVersion(c) => python_version = Some(c),
-                }
+        separated_nonempty_list(delimited(space0, tag("or"), space0), parse_marker_and),
+        |mut ms| {
+            if ms.len() == 1 {
+                ms.remove(0)
+            } else {
+                Marker::Or(ms)
             }
+        },
+    )(input)
+}
 
-            Extras {
-                extra,
-                sys_platform,
-                python_version,
+fn parse_marker_and(input: &str) -> IResult<&str, Marker> {
+    map(
+        separated_nonempty_list(delimited(space0, tag("and"), space0), parse_marker_atom),
+        |mut ms| {
+            if ms.len() == 1 {
+                ms.remove(0)
+            } else {
+                Marker::And(ms)
             }
         },
     )(input)
 }
 
-fn parse_extra_part(input: &str) -> IResult<&str, ExtrasPart> {
+fn parse_marker_atom(input: &str) -> IResult<&str, Marker> {
+    alt((
+        delimited(
+            tuple((tag("("), space0)),
+            parse_marker,
+            tuple((space0, tag(")"))),
+        ),
+        parse_marker_leaf,
+    ))(input)
+}
+
+fn parse_marker_leaf(input: &str) -> IResult<&str, Marker> {
+    alt((parse_marker_known, parse_marker_other))(input)
+}
+
+/// Markers we don't evaluate precisely, eg `platform_machine in "x86_64 AMD64"`.
+fn parse_marker_other(input: &str) -> IResult<&str, Marker> {
+    map(
+        tuple((
+            take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+            delimited(
+                space0,
+                alt((
+                    tag("==="),
+                    tag("=="),
+                    tag("!="),
+                    tag("<="),
+                    tag(">="),
+                    tag("~="),
+                    tag("<"),
+                    tag(">"),
+                    tag("not in"),
+                    tag("in"),
+                )),
+                space0,
+            ),
+            // Values may contain spaces.
+            delimited(quote, take_till(|c| c == '"' || c == '\''), quote),
+        )),
+        |(var, op, value): (&str, &str, &str)| {
+            Marker::Other(var.to_owned(), op.to_owned(), value.to_owned())
+        },
+    )(input)
+}
+
+fn parse_marker_known(input: &str) -> IResult<&str, Marker> {
     flat_map(
-        alt((tag("extra"), tag("sys_platform"), tag("python_version"))),
+        alt((
+            tag("extra"),
+            tag("sys_platform"),
+            tag("platform_system"),
+            tag("python_version"),
+            tag("python_full_version"),
+        )),
         |type_| {
             move |input: &str| match type_ {
                 "extra" => map(
@@ -180,21 +334,25 @@ fn parse_extra_part(input: &str) -> IResult<&str, ExtrasPart> {
                         separated_pair(space0, tag("=="), space0),
                         delimited(quote, parse_package_name, quote),
                     ),
-                    |x| ExtrasPart::Extra(x.to_string()),
+                    |x| Marker::Extra(x.to_string()),
                 )(input),
-                "sys_platform" => map(
+                // `platform_system` uses names like "Windows" and "Darwin".
+                "sys_platform" | "platform_system" => map_res(
                     tuple((
-                        delimited(space0, tag("=="), space0),
+                        delimited(space0, alt((tag("=="), tag("!="))), space0),
                         delimited(quote, parse_package_name, quote),
                     )),
-                    |(_, o)| ExtrasPart::SysPlatform(ReqType::Exact, Os::from_str(o).unwrap()),
+                    |(r, o)| {
+                        Os::from_str(&o.to_lowercase())
+                            .map(|os| Marker::SysPlatform(ReqType::from_str(r).unwrap(), os))
+                    },
                 )(input),
-                "python_version" => map(
+                "python_version" | "python_full_version" => map(
                     tuple((
                         delimited(space0, parse_req_type, space0),
                         delimited(quote, parse_version, quote),
                     )),
-                    |(r, v)| ExtrasPart::PythonVersion(Constraint::new(r, v)),
+                    |(r, v)| Marker::PythonVersion(Constraint::new(r, v)),
                 )(input),
                 _ => panic!("Found unexpected"),
             }
@@ -210,7 +368,7 @@ pub fn parse_constraint(input: &str) -> IResult<&str, Constraint> {
     map(
         alt((
             value((Some(ReqType::Gte), Version::new(0, 0, 0)), tag("*")),
-            tuple((opt(parse_req_type), parse_version)),
+            tuple((opt(terminated(parse_req_type, space0)), parse_version)),
         )),
         |(r, v)| Constraint::new(r.unwrap_or(ReqType::Exact), v),
     )(input)
@@ -229,7 +387,7 @@ pub fn parse_version(input: &str) -> IResult<&str, Version> {
     version.modifier = modifire;
     // check if u32::MAX in any version. (marker for `*`). then set that field
     // and any subsequent fields to `None`
-    version.star = vec![Some(major), minor, patch, extra_num].contains(&Some(u32::MAX));
+    version.star = [Some(major), minor, patch, extra_num].contains(&Some(u32::MAX));
     if version.star {
         if version.major == Some(u32::MAX) {
             version.major = None;
@@ -305,6 +463,8 @@ fn parse_modifier_version(input: &str) -> IResult<&str, VersionModifier> {
         "b" => VersionModifier::Beta,
         "rc" => VersionModifier::ReleaseCandidate,
         "dep" => VersionModifier::Dep,
+        "dev" => VersionModifier::Dev,
+        "post" => VersionModifier::Post,
         x => VersionModifier::Other(x.to_string()),
     })(input)
 }
@@ -408,7 +568,7 @@ mod tests {
                  minor: Some(4),
                  patch: Some(3),
                  extra_num: None,
-                 modifier: Some((VersionModifier::Other("dev".to_string()), 0)),
+                 modifier: Some((VersionModifier::Dev, 0)),
                  star: false,
         }))),
     )]
@@ -430,43 +590,52 @@ mod tests {
     #[rstest(input, expected,
         case(
             "extra == \"test\" and ( python_version == \"2.7\")",
-            Ok(("", Extras{
-                extra: Some("test".to_string()),
-                sys_platform: None,
-                python_version: Some(Constraint{ type_: ReqType::Exact, version: Version::new(2, 7, 0)})
-            }))
+            Ok(("", Extras::from_marker(Marker::And(vec![
+                Marker::Extra("test".to_string()),
+                Marker::PythonVersion(Constraint::new(ReqType::Exact, Version::new(2, 7, 0))),
+            ]))))
         ),
-       case(
+        case(
             "( python_version == \"2.7\")",
-            Ok(("", Extras{
-                extra: None,
-                sys_platform: None,
-                python_version: Some(Constraint{ type_: ReqType::Exact, version: Version::new(2, 7, 0)})
-            }))
+            Ok(("", Extras::from_marker(Marker::PythonVersion(
+                Constraint::new(ReqType::Exact, Version::new(2, 7, 0))
+            ))))
         ),
-       case(
+        case(
             "python_version == \"2.7\"",
-            Ok(("", Extras{
-                extra: None,
-                sys_platform: None,
-                python_version: Some(Constraint{ type_: ReqType::Exact, version: Version::new(2, 7, 0)})
-            }))
+            Ok(("", Extras::from_marker(Marker::PythonVersion(
+                Constraint::new(ReqType::Exact, Version::new(2, 7, 0))
+            ))))
         ),
         case(
             "( python_version==\"2.7\")",
-            Ok(("", Extras{
-                extra: None,
-                sys_platform: None,
-                python_version: Some(Constraint{ type_: ReqType::Exact, version: Version::new(2, 7, 0)})
-            }))
+            Ok(("", Extras::from_marker(Marker::PythonVersion(
+                Constraint::new(ReqType::Exact, Version::new(2, 7, 0))
+            ))))
         ),
         case(
             "sys_platform == \"win32\" and python_version < \"3.6\"",
-            Ok(("", Extras{
-                extra: None,
-                sys_platform: Some((ReqType::Exact, Os::Windows32)),
-                python_version: Some(Constraint{ type_: ReqType::Lt, version: Version::new(3, 6, 0)})
-            }))
+            Ok(("", Extras::from_marker(Marker::And(vec![
+                Marker::SysPlatform(ReqType::Exact, Os::Windows32),
+                Marker::PythonVersion(Constraint::new(ReqType::Lt, Version::new(3, 6, 0))),
+            ]))))
+        ),
+        case(
+            "sys_platform == 'darwin' or sys_platform == 'linux'",
+            Ok(("", Extras::from_marker(Marker::Or(vec![
+                Marker::SysPlatform(ReqType::Exact, Os::Mac),
+                Marker::SysPlatform(ReqType::Exact, Os::Linux),
+            ]))))
+        ),
+        case(
+            "python_version >= '3.8' and (platform_system == 'Windows' or sys_platform == 'darwin')",
+            Ok(("", Extras::from_marker(Marker::And(vec![
+                Marker::PythonVersion(Constraint::new(ReqType::Gte, Version::new(3, 8, 0))),
+                Marker::Or(vec![
+                    Marker::SysPlatform(ReqType::Exact, Os::Windows),
+                    Marker::SysPlatform(ReqType::Exact, Os::Mac),
+                ]),
+            ]))))
         ),
     )]
     fn test_parse_extras(input: &str, expected: IResult<&str, Extras>) {
@@ -495,8 +664,83 @@ mod tests {
         assert_eq!(parse_req(input), expected);
     }
 
+    #[rstest(input, expected,
+        case::plain(">=2.28", Ok(("", (
+            vec![Constraint::new(ReqType::Gte, Version::new(2, 28, 0))], None, None
+        )))),
+        case::multiple(">= 2.28, <3", Ok(("", (
+            vec![
+                Constraint::new(ReqType::Gte, Version::new(2, 28, 0)),
+                Constraint::new(ReqType::Lt, Version::new(3, 0, 0)),
+            ],
+            None,
+            None
+        )))),
+        case::marker(">=2.28 ; python_version >= '3.8'", Ok(("", (
+            vec![Constraint::new(ReqType::Gte, Version::new(2, 28, 0))],
+            None,
+            Some(Extras::from_marker(Marker::PythonVersion(
+                Constraint::new(ReqType::Gte, Version::new(3, 8, 0))
+            ))),
+        )))),
+        case::platform_marker("^1.2; sys_platform != 'win32'", Ok(("", (
+            vec![Constraint::new(ReqType::Caret, Version::new(1, 2, 0))],
+            None,
+            Some(Extras::from_marker(Marker::SysPlatform(ReqType::Ne, Os::Windows32))),
+        )))),
+        case::git("saturn @ git+https://github.com/org/saturn", Ok(("", (
+            vec![], Some("https://github.com/org/saturn".to_string()), None
+        )))),
+        case::git_no_name("@ https://github.com/org/saturn.git", Ok(("", (
+            vec![], Some("https://github.com/org/saturn.git".to_string()), None
+        )))),
+        case::git_marker("saturn @ git+https://github.com/org/saturn ; python_version < '3.10'",
+            Ok(("", (
+                vec![],
+                Some("https://github.com/org/saturn".to_string()),
+                Some(Extras::from_marker(Marker::PythonVersion(
+                    Constraint::new(ReqType::Lt, Version::new(3, 10, 0))
+                ))),
+            )))
+        ),
+    )]
+    fn test_parse_cfg_value(input: &str, expected: IResult<&str, CfgValue>) {
+        assert_eq!(parse_cfg_value(input), expected);
+    }
+
+    #[test]
+    fn parse_cfg_value_rejects_non_git_url() {
+        assert!(nom::combinator::all_consuming(parse_cfg_value)(
+            "saturn @ https://x.org/saturn.tar.gz"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parse_req_with_marker_and_git() {
+        let mut expected = Req::new(
+            "saturn".to_string(),
+            vec![Constraint::new(ReqType::Gte, Version::new(0, 3, 4))],
+        );
+        let py_constr = Constraint::new(ReqType::Gte, Version::new(3, 8, 0));
+        expected.python_version = Some(vec![py_constr.clone()]);
+        expected.marker = Some(Marker::PythonVersion(py_constr));
+        assert_eq!(
+            parse_req(r#"saturn = ">=0.3.4 ; python_version >= '3.8'""#),
+            Ok(("", expected))
+        );
+
+        let mut expected = Req::new("saturn".to_string(), vec![]);
+        expected.git = Some("https://github.com/org/saturn".to_string());
+        assert_eq!(
+            parse_req(r#"saturn = "saturn @ git+https://github.com/org/saturn""#),
+            Ok(("", expected))
+        );
+    }
+
     #[rstest(input, expected,
     case("saturn (>=0.3.4)", Ok(("", Req::new("saturn".to_string(), vec![Constraint::new(ReqType::Gte, Version::new(0, 3, 4))])))),
+    case("saturn>=0.3.4", Ok(("", Req::new("saturn".to_string(), vec![Constraint::new(ReqType::Gte, Version::new(0, 3, 4))])))),
     )]
     fn test_parse_req_pypi(input: &str, expected: IResult<&str, Req>) {
         assert_eq!(parse_req_pypi_fmt(input), expected);
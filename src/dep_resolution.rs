@@ -1,50 +1,157 @@
-use std::{cmp::min, collections::HashMap, str::FromStr};
+use std::{
+    cmp::min,
+    collections::HashMap,
+    error::Error,
+    fmt,
+    io::{self, Write},
+    panic,
+    str::FromStr,
+    sync::atomic::Ordering,
+    thread,
+    time::{Duration, Instant},
+};
 
+#[cfg(test)]
+use mockall::automock;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use termcolor::Color;
 
 use crate::{
     dep_types::{
-        self, Constraint, Dependency, DependencyError, Package, Rename, Req, ReqType, Version,
+        self, Constraint, DepExtras, Dependency, DependencyError, Marker, Package, Rename, Req,
+        ReqType, Version,
+    },
+    util::{
+        self,
+        find_links::LocalRelease,
+        interrupt::{self, InterruptFlags},
     },
-    util,
 };
 
+// Old uploads may have `null` or missing fields, so only the ones we can't work without are
+// required.
 #[derive(Debug, Deserialize)]
 struct WarehouseInfo {
     name: String, // Pulling this ensure proper capitalization
+    #[serde(default)]
     requires_dist: Option<Vec<String>>,
+    #[serde(default)]
     requires_python: Option<String>,
+    #[serde(default, deserialize_with = "null_as_default")]
     version: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[allow(dead_code)]
 pub struct WarehouseDigests {
+    #[serde(default, deserialize_with = "null_as_default")]
     pub md5: String,
+    /// Empty if PyPI doesn't list one, eg for some old uploads.
+    #[serde(default, deserialize_with = "null_as_default")]
     pub sha256: String,
 }
 
 #[derive(Clone, Debug, Deserialize)]
+#[allow(dead_code)]
 pub struct WarehouseRelease {
     // Could use digests field, which has sha256 as well as md5.
     pub filename: String,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub has_sig: bool,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub digests: WarehouseDigests,
     pub packagetype: String,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub python_version: String,
+    #[serde(default)]
     pub requires_python: Option<String>,
     pub url: String,
+    #[serde(default)]
     pub dependencies: Option<Vec<String>>,
+    /// In bytes. Not in the lock, so unknown for locked releases.
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+/// The PyPI JSON for a single version.
+#[derive(Debug, Deserialize)]
+struct WarehouseVersionData {
+    info: WarehouseInfo,
 }
 
 /// Only deserialize the info we need to resolve dependencies etc.
 #[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 struct WarehouseData {
     info: WarehouseInfo,
+    #[serde(default, deserialize_with = "releases_or_skip")]
     releases: HashMap<String, Vec<WarehouseRelease>>,
+    #[serde(default, deserialize_with = "files_or_skip")]
     urls: Vec<WarehouseRelease>,
 }
 
+/// Read `null` as the field's default.
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Release files we can't read, eg without a URL, are skipped, instead of failing the package.
+fn read_files(files: Vec<serde_json::Value>) -> Vec<WarehouseRelease> {
+    files
+        .into_iter()
+        .filter_map(|file| match WarehouseRelease::deserialize(&file) {
+            Ok(release) => Some(release),
+            Err(e) => {
+                if crate::CliConfig::current().verbose {
+                    println!(
+                        "Skipping a release file we can't read ({}): {}",
+                        e,
+                        file.get("filename").unwrap_or(&file)
+                    );
+                }
+                None
+            }
+        })
+        .collect()
+}
+
+fn files_or_skip<'de, D>(deserializer: D) -> Result<Vec<WarehouseRelease>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let files: Option<Vec<serde_json::Value>> = Option::deserialize(deserializer)?;
+    Ok(read_files(files.unwrap_or_default()))
+}
+
+fn releases_or_skip<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, Vec<WarehouseRelease>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let releases: Option<HashMap<String, Option<Vec<serde_json::Value>>>> =
+        Option::deserialize(deserializer)?;
+    Ok(releases
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(version, files)| (version, read_files(files.unwrap_or_default())))
+        .collect())
+}
+
+/// Parse a package's PyPI JSON. Errors name the package, and the field we couldn't read.
+fn parse_warehouse_json<'a, T: Deserialize<'a>>(
+    name: &str,
+    data: &'a str,
+) -> Result<T, FetchError> {
+    serde_json::from_str(data)
+        .map_err(|e| FetchError::new(name, FetchErrorKind::Decode(e.to_string())))
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct ReqCache {
     // Name is present from pydeps if gestruct packagetting deps for multiple package names. Otherwise, we commit
@@ -65,15 +172,939 @@ impl ReqCache {
     }
 }
 
+/// Packages per request to the dependency service.
+const MULTIPLE_BATCH_SIZE: usize = 50;
+
 #[derive(Debug, Serialize)]
 struct MultipleBody {
     // name, (version, version). Having trouble implementing Serialize for Version.
     packages: HashMap<String, Vec<String>>,
 }
 
+/// Why we couldn't get data for a package from the index.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FetchErrorKind {
+    /// The index has no package by this name. Holds similarly-named packages, if we found any.
+    NotFound(Vec<String>),
+    /// The index responded with an unexpected HTTP status.
+    Status(u16),
+    /// We couldn't reach the index.
+    Network(String),
+    /// The response wasn't in the format we expect.
+    Decode(String),
+    /// The package exists, but no release is compatible with the constraints and Python version.
+    NoCompatibleVersions,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FetchError {
+    pub package: String,
+    pub kind: FetchErrorKind,
+}
+
+impl FetchError {
+    pub fn new(package: &str, kind: FetchErrorKind) -> Self {
+        Self {
+            package: package.to_owned(),
+            kind,
+        }
+    }
+
+    fn from_reqwest(package: &str, e: &reqwest::Error) -> Self {
+        let kind = if e.is_decode() {
+            FetchErrorKind::Decode(e.to_string())
+        } else if let Some(status) = e.status() {
+            FetchErrorKind::Status(status.as_u16())
+        } else {
+            FetchErrorKind::Network(util::http::describe(e))
+        };
+        Self::new(package, kind)
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            FetchErrorKind::NotFound(suggestions) => {
+                write!(
+                    f,
+                    "Can't find the package `{}` on PyPi. Is it spelled correctly?",
+                    self.package
+                )?;
+                if !suggestions.is_empty() {
+                    write!(f, " Similar packages: {}", suggestions.join(", "))?;
+                }
+                Ok(())
+            }
+            FetchErrorKind::Status(status) => write!(
+                f,
+                "PyPi responded with status {} for `{}`. Please try again in a few minutes.",
+                status, self.package
+            ),
+            FetchErrorKind::Network(e) => {
+                write!(f, "Problem fetching data for `{}`. {}", self.package, e)
+            }
+            FetchErrorKind::Decode(e) => write!(
+                f,
+                "Problem reading package data for `{}`: {}",
+                self.package, e
+            ),
+            FetchErrorKind::NoCompatibleVersions => write!(
+                f,
+                "Can't find a version of `{}` compatible with its constraints and \
+                 Python version",
+                self.package
+            ),
+        }
+    }
+}
+
+impl Error for FetchError {}
+
+impl From<FetchError> for DependencyError {
+    fn from(e: FetchError) -> Self {
+        Self::new(&e.to_string())
+    }
+}
+
+/// Find packages with names similar to `name`, using PyPi's search page. This is only used
+/// for suggestions, so we return nothing if the search fails.
+fn search_similar(name: &str) -> Vec<String> {
+    let url = format!("https://pypi.org/search/?q={}", name);
+    let body = match util::http::get(&url).and_then(|r| r.text()) {
+        Ok(b) => b,
+        Err(_) => return vec![],
+    };
+
+    let re = Regex::new(r#"<span class="package-snippet__name">([^<]+)</span>"#).unwrap();
+    re.captures_iter(&body)
+        .map(|caps| caps[1].trim().to_owned())
+        .filter(|n| !util::compare_names(n, name))
+        .take(5)
+        .collect()
+}
+
+/// Map an unsuccessful warehouse response to an error. `suggest` finds similar package names,
+/// and is only called if the package doesn't exist.
+fn check_status(
+    name: &str,
+    status: reqwest::StatusCode,
+    suggest: impl FnOnce(&str) -> Vec<String>,
+) -> Result<(), FetchError> {
+    if status.is_success() {
+        Ok(())
+    } else if status == reqwest::StatusCode::NOT_FOUND {
+        Err(FetchError::new(
+            name,
+            FetchErrorKind::NotFound(suggest(name)),
+        ))
+    } else {
+        Err(FetchError::new(
+            name,
+            FetchErrorKind::Status(status.as_u16()),
+        ))
+    }
+}
+
+/// The key in `releases` for `version`. We try the index's exact string first, since
+/// several strings may parse to the same version, eg `1.0` and `1.0.0`.
+fn release_key<'a>(
+    releases: &'a HashMap<String, Vec<WarehouseRelease>>,
+    version: &Version,
+) -> Option<&'a str> {
+    let exact = version.to_string();
+    if let Some((key, _)) = releases.get_key_value(&exact) {
+        return Some(key);
+    }
+    releases
+        .keys()
+        .find(|k| Version::from_str(k).ok().as_ref() == Some(version))
+        .map(String::as_str)
+}
+
+/// Warehouse data built only from `find_links` files, for `--no-index` or when PyPI is
+/// unreachable.
+fn local_warehouse_data(local: &[LocalRelease]) -> WarehouseData {
+    WarehouseData {
+        info: WarehouseInfo {
+            name: local[0].name.clone(),
+            requires_dist: None,
+            requires_python: None,
+            version: String::new(),
+        },
+        releases: HashMap::new(),
+        urls: vec![],
+    }
+}
+
+/// Add `find_links` files to the warehouse's releases, under the key of the matching version.
+/// A local file replaces the warehouse's file of the same name, so we don't download it.
+fn merge_local_releases(
+    releases: &mut HashMap<String, Vec<WarehouseRelease>>,
+    local: Vec<LocalRelease>,
+) {
+    for lr in local {
+        let key = releases
+            .keys()
+            .find(|k| Version::from_str(k).ok().as_ref() == Some(&lr.version))
+            .cloned()
+            .unwrap_or(lr.version_str);
+        let files = releases.entry(key).or_default();
+        files.retain(|f| f.filename != lr.release.filename);
+        files.push(lr.release);
+    }
+}
+
+/// Which of the compatible versions of each package to pick.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Resolution {
+    /// The newest, as normal.
+    #[default]
+    Highest,
+    /// The oldest, eg to test that a library's lower bounds work.
+    Lowest,
+    /// The oldest for the project's own requirements, and the newest for their dependencies.
+    LowestDirect,
+}
+
+/// The key in `pyflow.lock`'s metadata recording the resolution that made it. Locks without it
+/// were made with the default.
+const RESOLUTION_KEY: &str = "resolution";
+
+impl Resolution {
+    /// Whether to pick the oldest version for a requirement, from the project if `direct`.
+    pub fn lowest(self, direct: bool) -> bool {
+        match self {
+            Self::Highest => false,
+            Self::Lowest => true,
+            Self::LowestDirect => direct,
+        }
+    }
+
+    /// The oldest or newest of `items`, by `version`.
+    fn pick<T>(
+        self,
+        direct: bool,
+        items: impl Iterator<Item = T>,
+        version: impl Fn(&T) -> &Version,
+    ) -> Option<T> {
+        if self.lowest(direct) {
+            items.min_by(|a, b| version(a).cmp(version(b)))
+        } else {
+            items.max_by(|a, b| version(a).cmp(version(b)))
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Highest => "highest",
+            Self::Lowest => "lowest",
+            Self::LowestDirect => "lowest-direct",
+        }
+    }
+
+    /// The resolution a lock was made with, from its metadata.
+    pub fn from_lock(metadata: &HashMap<String, String>) -> Self {
+        metadata
+            .get(RESOLUTION_KEY)
+            .and_then(|r| Self::from_str(r).ok())
+            .unwrap_or_default()
+    }
+
+    /// Mark a lock's metadata with this resolution.
+    pub fn mark(self, metadata: &mut HashMap<String, String>) {
+        metadata.insert(RESOLUTION_KEY.into(), self.as_str().into());
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "highest" => Self::Highest,
+            "lowest" => Self::Lowest,
+            "lowest-direct" => Self::LowestDirect,
+            _ => {
+                return Err(DependencyError::new(&format!(
+                    "Unknown resolution: {}. Expected highest, lowest, or lowest-direct",
+                    s
+                )))
+            }
+        })
+    }
+}
+
+/// Most version queries in flight at once.
+const MAX_CONCURRENT_QUERIES: usize = 8;
+
+/// Run `f` on each item in its own thread, returning results in order.
+fn in_parallel<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let f = &f;
+    thread::scope(|s| {
+        let handles: Vec<_> = items.iter().map(|item| s.spawn(move || f(item))).collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+/// Split a query into batches of at most `size` packages, in name order.
+fn chunk_query(
+    packages: &HashMap<String, Vec<Version>>,
+    size: usize,
+) -> Vec<HashMap<String, Vec<Version>>> {
+    let mut names: Vec<&String> = packages.keys().collect();
+    names.sort();
+    names
+        .chunks(size)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .map(|name| ((*name).clone(), packages[*name].clone()))
+                .collect()
+        })
+        .collect()
+}
+
+/// Query the dependency service one batch at a time, so a huge body isn't sent at once,
+/// and a failed batch only sends its own packages to the slower PyPI path.
+fn get_req_cache_remote_with(
+    packages: &HashMap<String, Vec<Version>>,
+    mut post: impl FnMut(&MultipleBody) -> Option<Vec<ReqCache>>,
+    mut pypi: impl FnMut(&HashMap<String, Vec<Version>>) -> Result<Vec<ReqCache>, FetchError>,
+) -> Result<Vec<ReqCache>, FetchError> {
+    let mut result = vec![];
+    for chunk in chunk_query(packages, MULTIPLE_BATCH_SIZE) {
+        let body = MultipleBody {
+            packages: chunk
+                .iter()
+                .map(|(name, versions)| {
+                    (
+                        name.clone(),
+                        versions.iter().map(Version::to_string).collect(),
+                    )
+                })
+                .collect(),
+        };
+        if crate::CliConfig::current().verbose {
+            let mut names: Vec<&str> = chunk.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            println!("Querying dependency data for {}", names.join(", "));
+        }
+
+        let timer = util::timings::start("resolve: metadata queries", None);
+        match post(&body) {
+            Some(mut data) => result.append(&mut data),
+            None => {
+                drop(timer);
+                result.append(&mut pypi(&chunk)?);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Narrow down the versions to query for each req, and fetch their dependencies in a single
+/// call. Version info and dependency data come from `get_version_info` and `get_req_cache`,
+/// so this can run without HTTP calls in tests. Any fetch error stops resolution; we never
+/// query or return data for a package we couldn't find versions for.
+fn fetch_req_data_with(
+    reqs: &[Req],
+    vers_cache: &mut HashMap<String, (String, Version, Vec<Version>)>,
+    py_vers: &Version,
+    progress: &mut Progress,
+    lowest: bool,
+    get_version_info: impl Fn(&str, Req) -> Result<(String, Version, Vec<Version>), FetchError> + Sync,
+    get_req_cache: impl FnOnce(&HashMap<String, Vec<Version>>) -> Result<Vec<ReqCache>, FetchError>,
+) -> Result<Vec<ReqCache>, FetchError> {
+    // Query versions of packages we haven't seen concurrently, and each only once, even if
+    // several reqs name it. The cache is keyed by the standardized name, since reqs may use
+    // different capitalization.
+    let mut to_query: Vec<(String, &Req)> = vec![];
+    for req in reqs {
+        let key = util::standardize_name(&req.name);
+        if !vers_cache.contains_key(&key) && !to_query.iter().any(|(k, _)| *k == key) {
+            to_query.push((key, req));
+        }
+    }
+    for batch in to_query.chunks(MAX_CONCURRENT_QUERIES) {
+        for (_, req) in batch {
+            progress.querying(&req.name);
+            progress.http_call();
+        }
+        let results = in_parallel(batch, |(_, req)| {
+            get_version_info(&req.name, req.clone_or_default_py(py_vers))
+        });
+        for ((key, _), result) in batch.iter().zip(results) {
+            vers_cache.insert(key.clone(), result?);
+        }
+    }
+
+    let mut query_data = HashMap::new();
+    for req in reqs {
+        let (_, latest_version, all_versions) =
+            vers_cache[&util::standardize_name(&req.name)].clone();
+
+        // The cached latest version may have been picked for a req that excludes pre-releases.
+        let allow_pre = req.allows_prereleases();
+        let mut max_v_to_query = if allow_pre {
+            all_versions.iter().max().cloned().unwrap_or(latest_version)
+        } else {
+            latest_version
+        };
+
+        // Find the maximum version compatible with the constraints.
+        // todo: May need to factor in additional constraints here, and put
+        // todo in fn signature for things that don't resolve with the optimal soln.
+        for constr in &req.constraints {
+            // For Ne, we have two ranges; the second one being ones higher than the version specified.
+            // For other types, we only have one item in the compatible range.
+            let i = match constr.type_ {
+                ReqType::Ne => 1,
+                _ => 0,
+            };
+
+            // Ensure we don't query past the latest.
+            max_v_to_query = min(constr.compatible_range()[i].1.clone(), max_v_to_query);
+        }
+
+        // To minimimize request time, only query the latest compatible version, or the oldest.
+        let compatible = all_versions
+            .into_iter()
+            .filter(|v| *v <= max_v_to_query && (allow_pre || !v.is_prerelease()));
+        let best_version = if lowest {
+            compatible
+                .filter(|v| res::is_compat(&req.constraints, v))
+                .min()
+        } else {
+            compatible.max()
+        }
+        .ok_or_else(|| FetchError::new(&req.name, FetchErrorKind::NoCompatibleVersions))?;
+
+        query_data.insert(req.name.to_owned(), vec![best_version]);
+    }
+
+    if query_data.is_empty() {
+        return Ok(vec![]);
+    }
+
+    progress.http_call();
+    get_req_cache(&query_data)
+}
+
+/// How often to print a status line when stdout isn't a terminal, and we can't update in place.
+const PLAIN_STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reports dependency-resolution progress, and handles cancellation requested with Ctrl-C.
+/// Passed through `guess_graph`, so the user can tell resolution is working instead of frozen.
+struct Progress {
+    resolved: usize,
+    discovered: usize,
+    http_calls: usize,
+    is_tty: bool,
+    last_line_len: usize,
+    last_plain_print: Option<Instant>,
+    interrupt: &'static InterruptFlags,
+}
+
+impl Progress {
+    fn new() -> Self {
+        let interrupt = interrupt::flags();
+        interrupt.cancelled.store(false, Ordering::SeqCst);
+        interrupt.resolving.store(true, Ordering::SeqCst);
+
+        Self {
+            resolved: 0,
+            discovered: 0,
+            http_calls: 0,
+            is_tty: atty::is(atty::Stream::Stdout),
+            last_line_len: 0,
+            last_plain_print: None,
+            interrupt,
+        }
+    }
+
+    /// Show the package currently being queried. On a terminal, this updates a single line;
+    /// otherwise, print a plain line periodically.
+    fn querying(&mut self, name: &str) {
+        let line = format!(
+            "Resolving dependencies: querying {} ({}/{} requirements resolved, {} HTTP calls)",
+            name, self.resolved, self.discovered, self.http_calls
+        );
+
+        if self.is_tty {
+            let padding = self.last_line_len.saturating_sub(line.len());
+            print!("\r{}{}", line, " ".repeat(padding));
+            io::stdout().flush().ok();
+            self.last_line_len = line.len();
+        } else if self
+            .last_plain_print
+            .is_none_or(|t| t.elapsed() >= PLAIN_STATUS_INTERVAL)
+        {
+            println!("{}", line);
+            self.last_plain_print = Some(Instant::now());
+        }
+    }
+
+    /// Call before each HTTP request; exits cleanly if the user pressed Ctrl-C.
+    fn http_call(&mut self) {
+        self.check_cancelled();
+        self.http_calls += 1;
+    }
+
+    fn check_cancelled(&mut self) {
+        if self.interrupt.cancelled.load(Ordering::SeqCst) {
+            self.clear();
+            util::abort("Dependency resolution cancelled");
+        }
+    }
+
+    /// Remove the status line, so it doesn't mix with subsequent output.
+    fn clear(&mut self) {
+        if self.is_tty && self.last_line_len > 0 {
+            print!("\r{}\r", " ".repeat(self.last_line_len));
+            io::stdout().flush().ok();
+            self.last_line_len = 0;
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.clear();
+        self.interrupt.resolving.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Find the marker for a package required by several parents: It applies if any of
+/// theirs do, and unconditionally if any parent requires it without a marker.
+fn combine_markers(deps: &[Dependency]) -> Option<Marker> {
+    let mut markers: Vec<Marker> = vec![];
+    for dep in deps {
+        match &dep.marker {
+            Some(m) => {
+                if !markers.contains(m) {
+                    markers.push(m.clone())
+                }
+            }
+            None => return None,
+        }
+    }
+    match markers.len() {
+        0 => None,
+        1 => markers.pop(),
+        _ => Some(Marker::Or(markers)),
+    }
+}
+
+/// Constraints that exclude a version of a package, and where they came from.
+#[derive(Clone, Debug)]
+pub struct Exclusion {
+    /// The package and version imposing the constraints; `None` for the project's own reqs.
+    pub parent: Option<(String, Version)>,
+    pub constraints: Vec<Constraint>,
+}
+
+/// Names, latest versions, and all versions of packages we've fetched data for. Keyed by
+/// standardized name.
+type VersionCache = HashMap<String, (String, Version, Vec<Version>)>;
+
+/// Fetches dependency data for reqs that aren't locked, picking the oldest compatible versions
+/// if the flag is set. This is `res::fetch_req_data`, except in tests.
+type FetchReqData<'a> = dyn FnMut(
+        &[Req],
+        &mut VersionCache,
+        &Version,
+        &mut Progress,
+        bool,
+    ) -> Result<Vec<ReqCache>, FetchError>
+    + 'a;
+
+/// Build the dependency graph for `reqs`. Locked packages are fixed choices: we only fetch
+/// data for reqs no locked package satisfies, ie new ones, and locked ones whose constraints
+/// have changed. Returns the graph, and versions found for the packages we fetched.
+fn build_graph(
+    reqs: &[Req],
+    locked: &[crate::Package],
+    os: util::Os,
+    py_vers: &Version,
+    resolution: Resolution,
+    fetch: &mut FetchReqData,
+) -> Result<(Vec<Dependency>, VersionCache), DependencyError> {
+    let mut result = Vec::new();
+    let mut version_cache = HashMap::new();
+    let mut progress = Progress::new();
+    guess_graph(
+        0,
+        reqs,
+        locked,
+        os,
+        &[],
+        py_vers,
+        resolution,
+        &mut result,
+        &mut version_cache,
+        &mut Vec::new(),
+        &mut progress,
+        fetch,
+    )?;
+    Ok((result, version_cache))
+}
+
+/// Version info and dependency data, kept across resolutions, so trying several sets of
+/// requirements, eg for `pyflow outdated`, only fetches each package's data once.
+#[derive(Default)]
+pub struct FetchCache {
+    versions: VersionCache,
+    reqs: Vec<ReqCache>,
+}
+
+impl FetchCache {
+    /// All versions of a package compatible with `py_vers`, fetching them if we haven't.
+    pub fn versions(&mut self, name: &str, py_vers: &Version) -> Result<Vec<Version>, FetchError> {
+        let key = util::standardize_name(name);
+        if !self.versions.contains_key(&key) {
+            let req = Req::new(name.to_owned(), vec![]).clone_or_default_py(py_vers);
+            let info = res::get_version_info(name, Some(req))?;
+            self.versions.insert(key.clone(), info);
+        }
+        Ok(self.versions[&key].2.clone())
+    }
+
+    /// As `fetch_req_data_with`, only calling `get_version_info` and `get_req_cache` for data
+    /// we haven't fetched yet.
+    #[allow(clippy::too_many_arguments)]
+    fn fetch_with<G>(
+        &mut self,
+        reqs: &[Req],
+        vers_cache: &mut VersionCache,
+        py_vers: &Version,
+        progress: &mut Progress,
+        lowest: bool,
+        get_version_info: impl Fn(&str, Req) -> Result<(String, Version, Vec<Version>), FetchError>
+            + Sync,
+        get_req_cache: G,
+    ) -> Result<Vec<ReqCache>, FetchError>
+    where
+        G: FnOnce(&HashMap<String, Vec<Version>>) -> Result<Vec<ReqCache>, FetchError>,
+    {
+        // The latest version stored was picked for another req's constraints.
+        for (key, (name, _, all)) in &self.versions {
+            let latest = all
+                .iter()
+                .filter(|v| !v.is_prerelease())
+                .max()
+                .or_else(|| all.iter().max());
+            if let Some(latest) = latest {
+                vers_cache
+                    .entry(key.clone())
+                    .or_insert_with(|| (name.clone(), latest.clone(), all.clone()));
+            }
+        }
+
+        let cached_reqs = &mut self.reqs;
+        let result = fetch_req_data_with(
+            reqs,
+            vers_cache,
+            py_vers,
+            progress,
+            lowest,
+            get_version_info,
+            |query| {
+                let is_cached = |name: &str, version: &Version| {
+                    cached_reqs.iter().find(|rc| {
+                        rc.name
+                            .as_deref()
+                            .is_some_and(|n| util::compare_names(n, name))
+                            && Version::from_str(&rc.version).ok().as_ref() == Some(version)
+                    })
+                };
+                let mut result = vec![];
+                let mut missing = HashMap::new();
+                for (name, versions) in query {
+                    match versions
+                        .iter()
+                        .map(|v| is_cached(name, v))
+                        .collect::<Option<Vec<_>>>()
+                    {
+                        Some(found) => result.extend(found.into_iter().cloned()),
+                        None => {
+                            missing.insert(name.clone(), versions.clone());
+                        }
+                    }
+                }
+                if !missing.is_empty() {
+                    let fetched = get_req_cache(&missing)?;
+                    cached_reqs.extend(fetched.iter().cloned());
+                    result.extend(fetched);
+                }
+                Ok(result)
+            },
+        );
+
+        for (key, info) in vers_cache.iter() {
+            self.versions
+                .entry(key.clone())
+                .or_insert_with(|| info.clone());
+        }
+        result
+    }
+}
+
+/// Packages that need more than one version to satisfy `reqs`, and would be installed
+/// renamed, eg because two packages require incompatible versions of a third.
+fn find_conflicts(reqs: &[Req], graph: &[Dependency]) -> Vec<String> {
+    let mut by_name: HashMap<String, Vec<Dependency>> = HashMap::new();
+    for dep in graph {
+        by_name
+            .entry(util::standardize_name(&dep.name))
+            .or_default()
+            .push(dep.clone());
+    }
+    let mut conflicts: Vec<String> = by_name
+        .values()
+        .filter(|deps| deps.len() > 1)
+        .filter(|deps| {
+            dep_types::intersection_many(&res::find_constraints(reqs, graph, deps)).is_empty()
+        })
+        .map(|deps| deps[0].name.clone())
+        .collect();
+    conflicts.sort();
+    conflicts
+}
+
+/// Resolve `reqs` without locking or installing, and return the packages that conflict, if
+/// any. Data is fetched with `fetch`.
+fn try_resolve_with(
+    reqs: &[Req],
+    os: util::Os,
+    py_vers: &Version,
+    fetch: &mut FetchReqData,
+) -> Result<Vec<String>, DependencyError> {
+    let (graph, _) = build_graph(reqs, &[], os, py_vers, Resolution::Highest, fetch)?;
+    Ok(find_conflicts(reqs, &graph))
+}
+
+/// As `try_resolve_with`, fetching from PyPI and the dependency service through `cache`.
+pub fn try_resolve(
+    reqs: &[Req],
+    os: util::Os,
+    py_vers: &Version,
+    cache: &mut FetchCache,
+) -> Result<Vec<String>, DependencyError> {
+    try_resolve_with(
+        reqs,
+        os,
+        py_vers,
+        &mut |reqs, vers_cache, py_vers, progress, lowest| {
+            cache.fetch_with(
+                reqs,
+                vers_cache,
+                py_vers,
+                progress,
+                lowest,
+                |name, req| res::get_version_info(name, Some(req)),
+                res::get_req_cache_multiple,
+            )
+        },
+    )
+}
+
+/// `res::resolve`, fetching data with `fetch`.
+fn resolve_with(
+    reqs: &[Req],
+    locked: &[crate::Package],
+    os: util::Os,
+    py_vers: &Version,
+    resolution: Resolution,
+    fetch: &mut FetchReqData,
+) -> Vec<crate::Package> {
+    let _timer = util::timings::start("resolve", None);
+    // The status line is cleared when this returns, before reporting on conflicts below.
+    let (result, version_cache) = build_graph(reqs, locked, os, py_vers, resolution, fetch)
+        .unwrap_or_else(|e| util::abort(&e.details));
+
+    // Keyed by the standardized name, so capitalization differences between reqs, the
+    // lock, and the warehouse don't split a package in two.
+    let mut by_name: HashMap<String, Vec<Dependency>> = HashMap::new();
+    for mut dep in result.clone() {
+        // The formatted name may be different from the pypi one. Eg `IPython` vice `ipython`.
+        dep.name = res::format_name(&dep.name, &version_cache);
+        by_name
+            .entry(util::standardize_name(&dep.name))
+            .or_default()
+            .push(dep);
+    }
+
+    // Deal with duplicates, conflicts etc. The code above assumed no conflicts, and that
+    // we can pick the newest compatible version for each req. We pass only the info
+    // needed to build the locked dependencies, and strip intermediary info like ids.
+
+    // updated_ids is used to remap lockpack dependencies, when a dep(version) other than their
+    // parent is chosen for the package.
+    let mut updated_ids = HashMap::new();
+    let mut result_cleaned = vec![];
+    for (name, deps) in &by_name {
+        let fmtd_name = res::format_name(&deps[0].name, &version_cache);
+        match deps.len() {
+            1 => {
+                // This dep is only specified once; no need to resolve conflicts.
+                let dep = &deps[0];
+
+                result_cleaned.push(Package {
+                    id: dep.id,
+                    parent: dep.parent,
+                    name: fmtd_name,
+                    version: dep.version.clone(),
+                    deps: vec![], // to be filled in after resolution
+                    rename: Rename::No,
+                    marker: dep.marker.clone(),
+                });
+            }
+            x if x > 1 => {
+                // Find what constraints are driving each dep that shares a name.
+                let constraints = res::find_constraints(reqs, &result, deps);
+
+                let _names: Vec<String> = deps.iter().map(|d| d.version.to_string()).collect();
+                let inter = dep_types::intersection_many(&constraints);
+
+                if inter.is_empty() {
+                    result_cleaned.append(&mut res::make_renamed_packs(
+                        &version_cache,
+                        deps,
+                        &result,
+                        locked,
+                        &fmtd_name,
+                    ));
+                    continue;
+                }
+
+                // If a version we've examined meets all constraints for packages that use it, use it -
+                // we've already built the graph to accommodate its sub-deps.
+
+                // If unable, find the highest version that meets the constraints, and determine
+                // what its dependencies are.
+
+                // Otherwise install all,
+                // and rename as-required(By which criteria? the older one?). This ensures our
+                // graph is always resolveable, and avoids diving through the graph recursively,
+                // dealing with cycles etc. There may be ways around this in some cases.
+                // todo: Renaming may not work if the renamed dep uses compiled code.
+
+                // Whether any of them is the project's own requirement.
+                let direct = deps.iter().any(|d| d.parent == 0);
+                let best_compatible = resolution.pick(
+                    direct,
+                    deps.iter().filter(|dep| {
+                        inter
+                            .iter()
+                            .any(|i| i.0 <= dep.version && dep.version <= i.1)
+                    }),
+                    |d| &d.version,
+                );
+
+                if let Some(best) = best_compatible {
+                    result_cleaned.push(Package {
+                        id: best.id,
+                        parent: best.parent,
+                        name: fmtd_name,
+                        version: best.version.clone(),
+                        deps: vec![], // to be filled in after resolution
+                        rename: Rename::No,
+                        marker: combine_markers(deps),
+                    });
+
+                    // Indicate we need to update the parent. We can't do it here, since
+                    // we don't know if we're pr
+                    // ocessed the parent[s] yet. Not doing this will
+                    // result in incorrect dependencies listed in lock packs.
+                    for dep in deps {
+                        // note that we push the old ids, so we can update the subdeps with the new versions.
+                        //                        updated_ids.insert(dep.id, best.id).expect("Problem inserting updated id");
+                        updated_ids.insert(dep.id, best.id);
+                    }
+                } else {
+                    // We consider the possibility there's a compatible version
+                    // that wasn't one of the best-per-req we queried.
+                    println!("⛏️ Digging deeper to resolve dependencies for {}...", name);
+
+                    // I think we should query with the raw name, not fmted?
+                    let versions = &version_cache.get(name).unwrap().2;
+
+                    if versions.is_empty() {
+                        result_cleaned.append(&mut res::make_renamed_packs(
+                            &version_cache,
+                            deps,
+                            &result,
+                            locked,
+                            &fmtd_name,
+                        ));
+                        continue;
+                    }
+
+                    // Generate dependencies here for all avail versions.
+                    let unresolved_deps = versions.iter().filter_map(|vers| {
+                        if inter.iter().any(|i| i.0 <= *vers && *vers <= i.1) {
+                            Some(Dependency {
+                                id: 0, // placeholder; we'll assign an id to the one we pick.
+                                name: fmtd_name.clone(),
+                                version: vers.clone(),
+                                reqs: vec![], // todo
+                                parent: 0,    // todo
+                                marker: combine_markers(deps),
+                                extras: DepExtras::default(),
+                            })
+                        } else {
+                            None
+                        }
+                    });
+
+                    let mut newest_unresolved = resolution
+                        .pick(direct, unresolved_deps, |d| &d.version)
+                        .unwrap();
+
+                    newest_unresolved.id = result.iter().map(|d| d.id).max().unwrap_or(0) + 1;
+
+                    result_cleaned.push(Package {
+                        id: newest_unresolved.id,
+                        parent: newest_unresolved.parent,
+                        name: fmtd_name,
+                        version: newest_unresolved.version,
+                        deps: vec![], // to be filled in after resolution
+                        rename: Rename::No,
+                        marker: newest_unresolved.marker,
+                    });
+
+                    // todo: Do a check on newest_unresolved! If fails, execute renamed plan
+
+                    for dep in deps {
+                        // note that we push the old ids, so we can update the subdeps with the new versions.
+                        updated_ids.insert(dep.id, newest_unresolved.id);
+                    }
+                }
+            }
+            _ => panic!("We shouldn't be seeing this!"),
+        }
+    }
+
+    // Now, assign subdeps, so we can store them in the lock.
+    res::assign_subdeps(&mut result_cleaned, &result, &updated_ids);
+
+    let mut a = result;
+    for b in &mut a {
+        b.reqs = vec![];
+    }
+
+    result_cleaned
+}
+
 // TODO: figure out lifetimes so we can automock this function
 // guess_graph removed from mod res because of lifetime issue with automock
-// Build a graph: Start by assuming we can pick the newest compatible dependency at each step.
+// Build a graph: Start by assuming we can pick the newest compatible dependency at each step,
+// or the oldest, depending on `resolution`.
 // If unable to resolve this way, subsequently run this with additional deconfliction reqs.
 #[allow(clippy::too_many_arguments)]
 fn guess_graph(
@@ -83,16 +1114,23 @@ fn guess_graph(
     os: util::Os,
     extras: &[String],
     py_vers: &Version,
+    resolution: Resolution,
     result: &mut Vec<Dependency>, // parent id, self id.
-    cache: &mut HashMap<(String, Version), Vec<&ReqCache>>,
     vers_cache: &mut HashMap<String, (String, Version, Vec<Version>)>,
     reqs_searched: &mut Vec<Req>,
+    progress: &mut Progress,
+    fetch: &mut FetchReqData,
 ) -> Result<(), DependencyError> {
     // Sometimes requirements are specified on separate lines; combine them if so, or we'll
     // have problems resolving.
 
+    // Skip reqs whose environment markers don't apply before combining, so we don't merge
+    // constraints meant for different platforms or Python versions.
     let mut cleaned_reqs: Vec<Req> = vec![];
-    for req in reqs {
+    for req in reqs
+        .iter()
+        .filter(|r| r.marker.as_ref().is_none_or(|m| m.evaluate(os, py_vers)))
+    {
         if cleaned_reqs
             .iter()
             .map(|cr| cr.name.clone())
@@ -109,7 +1147,14 @@ fn guess_graph(
                     if req.extra.is_none() && c.extra.is_some() {
                         c.extra = None
                     }
-                    // todo: Should merge sys_platform, python_version, install_with_extras too.
+                    // We need this package if either marker applies; we store the
+                    // combination in the lock, for use on other platforms.
+                    c.marker = match (c.marker.take(), &req.marker) {
+                        (Some(m1), Some(m2)) if m1 != *m2 => Some(Marker::Or(vec![m1, m2.clone()])),
+                        (m1, Some(_)) => m1,
+                        _ => None,
+                    };
+                    // todo: Should merge python_version, install_with_extras too.
                 }
             }
 
@@ -127,19 +1172,6 @@ fn guess_graph(
             Some(ex) => extras.contains(ex),
             None => true,
         })
-        .filter(|r| match r.sys_platform {
-            Some((rt, os_)) => match rt {
-                // A specified win32 req could apply to 64-bit windows too.
-                ReqType::Exact => {
-                    os_ == os || (os_ == util::Os::Windows32 && os == util::Os::Windows)
-                }
-                ReqType::Ne => os_ != os,
-                _ => {
-                    util::abort("Reqtypes for Os must be == or !=");
-                }
-            },
-            None => true,
-        })
         .filter(|r| match &r.python_version {
             Some(v) => res::is_compat(v, py_vers),
             None => true,
@@ -147,58 +1179,67 @@ fn guess_graph(
         .collect();
 
     let mut non_locked_reqs = vec![];
-    let mut locked_reqs: Vec<Req> = vec![];
+    let mut locked_reqs: Vec<&crate::Package> = vec![];
 
     // Partition reqs into ones we have lock-file data for, and ones where we need to make
     // http calls to the pypi warehouse (for versions) and pydeps (for deps).
     for req in &reqs {
         reqs_searched.push((*req).clone());
-
-        let mut found_in_locked = false;
-        for package in locked.iter() {
-            if !util::compare_names(&package.name, &req.name) {
-                continue;
-            }
-
-            if res::is_compat(&req.constraints, &package.version) {
-                locked_reqs.push((*req).clone());
-                found_in_locked = true;
-                break;
-            }
-        }
-        if !found_in_locked {
-            non_locked_reqs.push((*req).clone());
+        progress.discovered += 1;
+
+        // The lock may have more than one version of a package, if it's renamed.
+        match locked.iter().find(|p| {
+            util::compare_names(&p.name, &req.name) && res::is_compat(&req.constraints, &p.version)
+        }) {
+            Some(package) => locked_reqs.push(package),
+            None => non_locked_reqs.push((*req).clone()),
         }
     }
 
     // Single http call here to pydeps for all this package's reqs, plus version calls for each req.
-    let mut query_data = if let Ok(d) = res::fetch_req_data(&non_locked_reqs, vers_cache, py_vers) {
-        d
-    } else {
-        util::abort(&format!(
-            "Aborting graph creation: Problem getting dependency data\n \
-             Reqs: {:#?}
-             It's taking a long time to get dependency data - this \
-             usually suggests that the dependency tree is being newly \
-             built. Please try again in a few minutes, and if the error \
-             still occurs, consider opening an issue on github.",
-            &reqs
-        ));
+    // Reqs with a parent of 0 are the project's own.
+    let direct = parent_id == 0;
+    let mut query_data = match fetch(
+        &non_locked_reqs,
+        vers_cache,
+        py_vers,
+        progress,
+        resolution.lowest(direct),
+    ) {
+        Ok(d) => d,
+        Err(e) => {
+            progress.clear();
+            return Err(e.into());
+        }
     };
 
     // Now add info from lock packs for data we didn't query. The purpose of passing locks
     // into the dep resolution process is to avoid unnecessary HTTP calls and resolution iterations.
-    for req in locked_reqs {
-        // Find the corresponding lock package. There should be exactly one.
-        let package = locked
-            .iter()
-            .find(|p| util::compare_names(&p.name, &req.name))
-            .expect("Can't find matching lock package");
-
+    for package in locked_reqs {
         let requires_dist = package
             .deps
             .iter()
-            .map(|(_, name, vers)| format!("{} (=={})", name, vers.to_string()))
+            .map(|(_, name, vers, extras)| {
+                // Include the marker, so it's re-evaluated for this OS and Python version, and
+                // the extras, so the dependency is only followed when they're requested.
+                let marker = locked
+                    .iter()
+                    .find(|p| util::compare_names(&p.name, name))
+                    .and_then(|p| p.marker.clone());
+                let marker = match (extras.extra.clone().map(Marker::Extra), marker) {
+                    (Some(e), Some(m)) => Some(Marker::And(vec![e, m])),
+                    (e, m) => e.or(m),
+                };
+                let name = if extras.install_with.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}[{}]", name, extras.install_with.join(","))
+                };
+                match marker {
+                    Some(m) => format!("{} (=={}) ; {}", name, vers, m),
+                    None => format!("{} (=={})", name, vers),
+                }
+            })
             .collect();
 
         // Note that we convert from normal data types to strings here, for the sake of consistency
@@ -211,8 +1252,6 @@ fn guess_graph(
         });
     }
 
-    // todo: We must take locked ids into account, or will bork renames on subsequent runs!
-
     // We've now merged the query data with locked data. A difference though, is we've already
     // narrowed down the locked ones to one version with an exact constraint.
 
@@ -247,6 +1286,8 @@ fn guess_graph(
                         version: Version::from_str(&r.version).expect("Problem parsing vers"),
                         reqs: r.reqs(),
                         parent: parent_id,
+                        marker: req.marker.clone(),
+                        extras: DepExtras::from_req(req),
                     })
                 } else {
                     None
@@ -255,53 +1296,188 @@ fn guess_graph(
             .collect();
 
         if deps.is_empty() {
+            progress.clear();
             util::abort(&format!("Can't find a compatible package for {:?}", &req));
         }
 
-        let newest_compat = deps
-            .into_iter()
-            .max_by(|a, b| a.version.cmp(&b.version))
+        let best_compat = resolution
+            .pick(direct, deps.into_iter(), |d| &d.version)
             .expect("Problem finding newest compatible match");
 
-        result.push(newest_compat.clone());
+        result.push(best_compat.clone());
+        progress.resolved += 1;
 
         if let Err(e) = guess_graph(
-            newest_compat.id,
-            &newest_compat.reqs,
+            best_compat.id,
+            &best_compat.reqs,
             locked,
             os,
             req.install_with_extras.as_ref().unwrap_or(&vec![]),
             py_vers,
+            resolution,
             result,
-            cache,
             vers_cache,
             reqs_searched,
+            progress,
+            fetch,
         ) {
-            println!("Problem pulling dependency info for {}", &req.name);
-            util::abort(&e.details)
+            progress.clear();
+            return Err(e);
         }
     }
     Ok(())
 }
 
+/// The latest version in a package's warehouse data compatible with `req`, and all versions
+/// compatible with its Python version.
+fn version_info(
+    name: &str,
+    data: WarehouseData,
+    req: Option<Req>,
+) -> Result<(String, Version, Vec<Version>), FetchError> {
+    let all_versions = data
+        .releases
+        .keys()
+        .filter_map(|v| {
+            data.releases.get(v).map(|releases| {
+                releases.iter().map(move |release| {
+                    Req::from_warehouse_release(name.to_string(), v.to_string(), release.clone())
+                })
+            })
+        })
+        .flatten();
+    let py_vers = if let Some(ref r) = req {
+        r.py_ver_or_default()
+    } else {
+        Version::new_star(None, None, None, true)
+    };
+    let select_version = if let Some(ref r) = req {
+        let av: Vec<Req> = all_versions.clone().collect();
+        let compat_av: Vec<Version> = av
+            .iter()
+            .filter_map(|x: &Req| {
+                let v = &x.constraints[0].version;
+                if res::is_compat(&r.constraints, v)
+                    && (r.allows_prereleases() || !v.is_prerelease())
+                {
+                    if let Some(ref pv) = x.python_version {
+                        if res::is_compat(pv, &py_vers) {
+                            Some(x.constraints[0].version.clone())
+                        } else {
+                            None
+                        }
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let v = compat_av.iter().max();
+        v.cloned()
+    } else {
+        None
+    };
+
+    #[cfg(not(debug_assertions))]
+    let all_compat: Vec<Version>;
+    #[cfg(debug_assertions)]
+    let mut all_compat: Vec<Version>;
+
+    all_compat = all_versions
+        .filter_map(|x| {
+            if let Some(y) = x.python_version {
+                if res::is_compat(&y, &py_vers) {
+                    Some(x.constraints.first().unwrap().version.clone())
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    #[cfg(debug_assertions)]
+    all_compat.sort();
+
+    // Fall back to a pre-release only if there are no stable versions.
+    let fallback = all_compat
+        .iter()
+        .filter(|v| !v.is_prerelease())
+        .max()
+        .or_else(|| all_compat.iter().max());
+    let version = match select_version.or_else(|| fallback.cloned()) {
+        Some(v) => v,
+        None => return Err(FetchError::new(name, FetchErrorKind::NoCompatibleVersions)),
+    };
+    Ok((data.info.name, version, all_compat))
+}
+
+/// In the names of renamed packages' modules.
+pub const RENAMED_INFIX: &str = "_renamed_";
+
+/// What to rename a package installed more than once to, from the package that requires it, eg
+/// `six_renamed_oldlib_1_0_0`. This is the same on each run, so the lock and installed copy
+/// match.
+pub fn renamed_name(name: &str, parent: Option<(&str, &Version)>) -> String {
+    let suffix = match parent {
+        Some((parent_name, version)) => format!("{}_{}", parent_name, version),
+        None => "root".to_owned(),
+    };
+    format!("{}{}{}", name, RENAMED_INFIX, suffix)
+        .to_lowercase()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+}
+
 #[cfg_attr(test, automock())]
 pub(super) mod res {
     use super::*;
 
     /// Format a name based on how it's listed on `PyPi`. Ie capitalize or convert - to _'
     /// a required.
-    fn format_name(name: &str, cache: &HashMap<String, (String, Version, Vec<Version>)>) -> String {
-        match cache.get(name) {
+    pub(super) fn format_name(
+        name: &str,
+        cache: &HashMap<String, (String, Version, Vec<Version>)>,
+    ) -> String {
+        match cache.get(&util::standardize_name(name)) {
             Some(vc) => vc.0.clone(),
             None => name.to_owned(), // ie this is from a locked dep.
         }
     }
 
-    /// Fetch data about a package from the [Pypi Warehouse](https://warehouse.pypa.io/api-reference/json/).
-    fn get_warehouse_data(name: &str) -> Result<WarehouseData, reqwest::Error> {
-        let url = format!("https://pypi.org/pypi/{}/json", name);
-        let resp = reqwest::blocking::get(&url)?.json()?;
-        Ok(resp)
+    /// Fetch data about a package from the [Pypi Warehouse](https://warehouse.pypa.io/api-reference/json/),
+    /// or the index set with `index_url`.
+    /// Files from `find_links` directories are included.
+    fn get_warehouse_data(name: &str) -> Result<WarehouseData, FetchError> {
+        let local = util::find_links::releases(name);
+        let mut data = if util::find_links::no_index() {
+            if local.is_empty() {
+                return Err(FetchError::new(name, FetchErrorKind::NotFound(vec![])));
+            }
+            local_warehouse_data(&local)
+        } else {
+            let _timer = util::timings::start("resolve: warehouse queries", Some(name));
+            let url = format!("{}/{}/json", util::index::url(), name);
+            let fetched = util::http::get(&url)
+                .map_err(|e| FetchError::from_reqwest(name, &e))
+                .and_then(|resp| {
+                    check_status(name, resp.status(), search_similar)?;
+                    let text = resp
+                        .text()
+                        .map_err(|e| FetchError::from_reqwest(name, &e))?;
+                    parse_warehouse_json(name, &text)
+                });
+            match fetched {
+                Ok(d) => d,
+                // Eg offline, with the packages we need in a local directory.
+                Err(_) if !local.is_empty() => local_warehouse_data(&local),
+                Err(e) => return Err(e),
+            }
+        };
+        merge_local_releases(&mut data.releases, local);
+        Ok(data)
     }
 
     /// Find the latest version of a package by querying the warehouse.  Also return
@@ -310,142 +1486,96 @@ pub(super) mod res {
     pub fn get_version_info(
         name: &str,
         req: Option<Req>,
-    ) -> Result<(String, Version, Vec<Version>), DependencyError> {
+    ) -> Result<(String, Version, Vec<Version>), FetchError> {
         let data = get_warehouse_data(name)?;
-
-        let all_versions = data
-            .releases
-            .keys()
-            .filter_map(|v| {
-                data.releases.get(v).map(|releases| {
-                    releases.iter().map(move |release| {
-                        Req::from_warehouse_release(
-                            name.to_string(),
-                            v.to_string(),
-                            release.clone(),
-                        )
-                    })
-                })
-            })
-            .flatten();
-        let py_vers = if let Some(ref r) = req {
-            r.py_ver_or_default()
-        } else {
-            Version::new_star(None, None, None, true)
-        };
-        let select_version = if let Some(ref r) = req {
-            let av: Vec<Req> = all_versions.clone().collect();
-            let compat_av: Vec<Version> = av
-                .iter()
-                .filter_map(|x: &Req| {
-                    if is_compat(&r.constraints, &x.constraints[0].version) {
-                        if let Some(ref pv) = x.python_version {
-                            if is_compat(pv, &py_vers) {
-                                Some(x.constraints[0].version.clone())
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-            let v = compat_av.iter().max();
-            v.cloned()
-        } else {
-            None
-        };
-
-        #[cfg(not(debug_assertions))]
-        let all_compat: Vec<Version>;
-        #[cfg(debug_assertions)]
-        let mut all_compat: Vec<Version>;
-
-        all_compat = all_versions
-            .filter_map(|x| {
-                if let Some(y) = x.python_version {
-                    if is_compat(&y, &py_vers) {
-                        Some(x.constraints.first().unwrap().version.clone())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect();
-
-        #[cfg(debug_assertions)]
-        all_compat.sort();
-
-        if let Some(v) = select_version {
-            Ok((data.info.name, v, all_compat))
-        } else {
-            Ok((
-                data.info.name,
-                all_compat
-                    .iter()
-                    .max()
-                    .unwrap_or_else(|| panic!("Can't find a valid version for {}", name))
-                    .clone(),
-                all_compat,
-            ))
-        }
+        version_info(name, data, req)
     }
 
     /// Get release data from the warehouse, ie the file url, name, and hash.
     pub fn get_warehouse_release(
         name: &str,
         version: &Version,
-    ) -> Result<Vec<WarehouseRelease>, reqwest::Error> {
+    ) -> Result<Vec<WarehouseRelease>, FetchError> {
         let data = get_warehouse_data(name)?;
-        // some packages 0-pad their version numbers or have less digits. Lets map
-        // the parsed version to the key.
-        let mut version_map = HashMap::new();
-        for key in data.releases.keys() {
-            if let Ok(ver) = Version::from_str(key) {
-                version_map.insert(ver, key.as_str());
-            } else if cfg!(debug_assertions) {
-                eprintln!("Unable to parse \"{}\" version \"{}\"; skipped.", name, key);
-            }
-        }
-
-        let key = version_map
-            .get(version)
-            .expect("Unable to reverse mapping of Version to release key");
-        let release_data = data
-            .releases
-            .get::<str>(key)
+        // Some packages 0-pad their version numbers, or have fewer digits.
+        let key = release_key(&data.releases, version)
             .unwrap_or_else(|| panic!("Unable to find a release for {} = \"{}\"", name, version));
 
-        Ok(release_data.clone())
+        Ok(data.releases[key].clone())
     }
 
-    /// Fetch items from multiple packages; cuts down on API calls.
-    fn get_req_cache_multiple(
+    /// Fetch the requirements of each version from its PyPI JSON; slower than querying the
+    /// dependency service, since it takes a request per version.
+    fn get_req_cache_pypi(
         packages: &HashMap<String, Vec<Version>>,
-    ) -> Result<Vec<ReqCache>, reqwest::Error> {
-        // input tuple is name, min version, max version.
-        // parse strings here.
-        let mut packages2 = HashMap::new();
+    ) -> Result<Vec<ReqCache>, FetchError> {
+        let mut result = vec![];
         for (name, versions) in packages.iter() {
-            let versions = versions.iter().map(Version::to_string).collect();
-            packages2.insert(name.to_owned(), versions);
+            for version in versions {
+                let _timer = util::timings::start("resolve: warehouse queries", Some(name));
+                let url = util::index::json_url(name, &version.to_string());
+                let resp = util::http::get(&url).map_err(|e| FetchError::from_reqwest(name, &e))?;
+                check_status(name, resp.status(), search_similar)?;
+                let text = resp
+                    .text()
+                    .map_err(|e| FetchError::from_reqwest(name, &e))?;
+                let data: WarehouseVersionData = parse_warehouse_json(name, &text)?;
+                result.push(ReqCache {
+                    name: Some(data.info.name),
+                    version: data.info.version,
+                    requires_python: data.info.requires_python,
+                    requires_dist: data.info.requires_dist.unwrap_or_default(),
+                });
+            }
         }
+        Ok(result)
+    }
 
-        let url = "https://pydeps.herokuapp.com/multiple/";
-        //                let url = "http://localhost:8000/multiple/";
+    /// Fetch items from multiple packages; cuts down on API calls. Versions we have a
+    /// `find_links` file for are read from its metadata instead.
+    pub(super) fn get_req_cache_multiple(
+        packages: &HashMap<String, Vec<Version>>,
+    ) -> Result<Vec<ReqCache>, FetchError> {
+        let mut local_result = vec![];
+        let mut remote = HashMap::new();
+        for (name, versions) in packages.iter() {
+            let local = util::find_links::releases(name);
+            for version in versions {
+                // Prefer a wheel's metadata; source dists don't list their requirements.
+                let found = local
+                    .iter()
+                    .filter(|lr| lr.version == *version)
+                    .max_by_key(|lr| lr.release.packagetype == "bdist_wheel");
+                match found {
+                    Some(lr) => local_result.push(ReqCache {
+                        name: Some(name.clone()),
+                        version: lr.version_str.clone(),
+                        requires_python: lr.release.requires_python.clone(),
+                        requires_dist: lr.requires_dist.clone(),
+                    }),
+                    None => remote
+                        .entry(name.clone())
+                        .or_insert_with(Vec::new)
+                        .push(version.clone()),
+                }
+            }
+        }
+        if remote.is_empty() {
+            return Ok(local_result);
+        }
+        if util::find_links::no_index() {
+            let name = remote.keys().next().unwrap();
+            return Err(FetchError::new(name, FetchErrorKind::NotFound(vec![])));
+        }
+        let mut result = get_req_cache_remote(&remote)?;
+        result.append(&mut local_result);
+        Ok(result)
+    }
 
-        reqwest::blocking::Client::new()
-            .post(url)
-            .json(&MultipleBody {
-                packages: packages2,
-            })
-            .send()?
-            .json()
+    fn get_req_cache_remote(
+        packages: &HashMap<String, Vec<Version>>,
+    ) -> Result<Vec<ReqCache>, FetchError> {
+        get_req_cache_remote_with(packages, util::deps_service::post, get_req_cache_pypi)
     }
 
     /// Helper fn for `guess_graph`.
@@ -459,109 +1589,151 @@ pub(super) mod res {
     }
 
     /// Pull data on pydeps for a req. Only pull what we need.
-    /// todo: Group all reqs and pull with a single call to pydeps to improve speed?
     pub(super) fn fetch_req_data(
         reqs: &[Req],
         vers_cache: &mut HashMap<String, (String, Version, Vec<Version>)>,
         py_vers: &Version,
-    ) -> Result<Vec<ReqCache>, DependencyError> {
-        // Narrow-down our list of versions to query.
-
-        let mut query_data = HashMap::new();
-        for req in reqs {
-            // todo: cache version info; currently may get this multiple times.
-            let (_, latest_version, all_versions) = match vers_cache.get(&req.name) {
-                Some(c) => c.clone(),
-                None => {
-                    if let Ok(data) =
-                        get_version_info(&req.name, Some(req.clone_or_default_py(py_vers)))
-                    {
-                        vers_cache.insert(req.name.clone(), data.clone());
-                        data
-                    } else {
-                        util::abort(&format!(
-                            "Can't get version info for the dependency `{}`. \
-                         Is it spelled correctly? Is the internet connection ok?",
-                            &req.name
-                        ))
-                    }
-                }
-            };
-
-            let mut max_v_to_query = latest_version;
-
-            // Find the maximum version compatible with the constraints.
-            // todo: May need to factor in additional constraints here, and put
-            // todo in fn signature for things that don't resolve with the optimal soln.
-            for constr in &req.constraints {
-                // For Ne, we have two ranges; the second one being ones higher than the version specified.
-                // For other types, we only have one item in the compatible range.
-                let i = match constr.type_ {
-                    ReqType::Ne => 1,
-                    _ => 0,
-                };
+        progress: &mut Progress,
+        lowest: bool,
+    ) -> Result<Vec<ReqCache>, FetchError> {
+        fetch_req_data_with(
+            reqs,
+            vers_cache,
+            py_vers,
+            progress,
+            lowest,
+            |name, req| get_version_info(name, Some(req)),
+            get_req_cache_multiple,
+        )
+    }
 
-                // Ensure we don't query past the latest.
-                max_v_to_query = min(constr.compatible_range()[i].1.clone(), max_v_to_query);
-            }
+    /// Find the constraints each relevant dep's parent places on it. A parent of `None`
+    /// indicates the top-level requirements.
+    fn find_constraints_by_parent(
+        all_reqs: &[Req],
+        all_deps: &[Dependency],
+        relevant_deps: &[Dependency],
+    ) -> Vec<(Option<Dependency>, Vec<Constraint>)> {
+        let mut result = vec![];
 
-            // To minimimize request time, only query the latest compatible version.
-            let best_version = match all_versions
-                .into_iter()
-                .filter(|v| *v <= max_v_to_query)
-                .max()
-            {
-                Some(v) => vec![v],
-                None => vec![],
+        for dep in relevant_deps.iter() {
+            let parent = all_deps.iter().find(|d| d.id == dep.parent);
+            let parent_reqs = match parent {
+                Some(p) => &p.reqs,
+                None => all_reqs,
             };
 
-            query_data.insert(req.name.to_owned(), best_version);
-        }
-
-        if query_data.is_empty() {
-            return Ok(vec![]);
+            let mut constraints = vec![];
+            for req in parent_reqs
+                .iter()
+                .filter(|r| util::compare_names(&r.name, &dep.name))
+            {
+                constraints.append(&mut req.constraints.clone())
+            }
+            result.push((parent.cloned(), constraints));
         }
-
-        Ok(get_req_cache_multiple(&query_data)?)
+        result
     }
 
-    fn find_constraints(
+    pub(super) fn find_constraints(
         all_reqs: &[Req],
         all_deps: &[Dependency],
         relevant_deps: &[Dependency],
     ) -> Vec<Constraint> {
-        let mut result = vec![];
+        find_constraints_by_parent(all_reqs, all_deps, relevant_deps)
+            .into_iter()
+            .flat_map(|(_, constraints)| constraints)
+            .collect()
+    }
 
-        for dep in relevant_deps.iter() {
-            let parent = match all_deps.iter().find(|d| d.id == dep.parent) {
-                Some(p) => p.clone(),
-                // ie top-level; set up a dummy
-                None => Dependency {
-                    id: 999,
-                    name: "top".to_owned(),
-                    version: Version::new(0, 0, 0),
-                    reqs: all_reqs.to_vec(),
-                    parent: 0,
-                },
-            };
+    /// Find each constraint in the requirement graph that excludes `version` of a package, for
+    /// `pyflow why-not`. We build the graph from the lock, but query the requirements of each
+    /// locked package, since the lock only stores which versions were picked.
+    pub fn why_not(
+        reqs: &[Req],
+        locked: &[crate::Package],
+        name: &str,
+        version: &Version,
+    ) -> Result<Vec<Exclusion>, DependencyError> {
+        let mut query = HashMap::new();
+        for package in locked {
+            query.insert(package.name.clone(), vec![package.version.clone()]);
+        }
+        let query_data = if query.is_empty() {
+            vec![]
+        } else {
+            get_req_cache_multiple(&query).map_err(|e| DependencyError::new(&e.to_string()))?
+        };
+
+        let all_deps: Vec<Dependency> = locked
+            .iter()
+            .map(|package| Dependency {
+                id: package.id,
+                name: package.name.clone(),
+                version: package.version.clone(),
+                reqs: query_data
+                    .iter()
+                    .find(|d| {
+                        util::compare_names(d.name.as_deref().unwrap_or_default(), &package.name)
+                    })
+                    .map(ReqCache::reqs)
+                    .unwrap_or_default()
+                    .into_iter()
+                    // We don't track which extras each package was installed with.
+                    .filter(|r| r.extra.is_none())
+                    .collect(),
+                parent: 0,
+                marker: None,
+                extras: DepExtras::default(),
+            })
+            .collect();
+
+        // One entry for each parent that places constraints on the package. Parent 0 is the
+        // top level.
+        let mut relevant = vec![];
+        let target = |parent| Dependency {
+            id: 0,
+            name: name.to_owned(),
+            version: version.clone(),
+            reqs: vec![],
+            parent,
+            marker: None,
+            extras: DepExtras::default(),
+        };
+        if reqs.iter().any(|r| util::compare_names(&r.name, name)) {
+            relevant.push(target(0));
+        }
+        for dep in &all_deps {
+            if dep.reqs.iter().any(|r| util::compare_names(&r.name, name)) {
+                relevant.push(target(dep.id));
+            }
+        }
 
-            for req in parent
-                .clone()
-                .reqs
+        let mut result = vec![];
+        for (parent, constraints) in find_constraints_by_parent(reqs, &all_deps, &relevant) {
+            // `intersection_many` doesn't account for `!=`, so check those separately.
+            let in_range = dep_types::intersection_many(&constraints)
                 .iter()
-                .filter(|r| util::compare_names(&r.name, &dep.name))
-            {
-                result.append(&mut req.constraints.clone())
+                .any(|(min, max)| min <= version && version <= max);
+            if in_range && is_compat(&constraints, version) {
+                continue;
             }
+
+            result.push(Exclusion {
+                parent: parent.map(|p| (p.name, p.version)),
+                constraints,
+            });
         }
-        result
+        Ok(result)
     }
 
     /// We've determined we need to add all the included packages, and renamed all but one.
-    fn make_renamed_packs(
+    /// Packages renamed in the lock keep their names.
+    pub(super) fn make_renamed_packs(
         _vers_cache: &HashMap<String, (String, Version, Vec<Version>)>,
         deps: &[Dependency],
-        //    all_deps: &[Dependency],
+        all_deps: &[Dependency],
+        locked: &[crate::Package],
         name: &str,
     ) -> Vec<Package> {
         util::print_color(
@@ -585,14 +1757,35 @@ pub(super) mod res {
             .collect();
         println!("Installing these versions: {:#?}", &dep_display);
 
+        let parent = |dep: &Dependency| {
+            all_deps
+                .iter()
+                .find(|d| d.id == dep.parent)
+                .map(|p| (p.name.as_str(), &p.version))
+        };
+        let mut deps: Vec<&Dependency> = deps.iter().collect();
+        deps.sort_by(|a, b| {
+            b.version.cmp(&a.version).then_with(|| {
+                renamed_name(&a.name, parent(a)).cmp(&renamed_name(&b.name, parent(b)))
+            })
+        });
+
         let mut result = vec![];
         // We were unable to resolve using the newest version; add and rename packages.
-        for (i, dep) in deps.iter().enumerate() {
-            // Don't rename the first one.
+        for (i, dep) in deps.into_iter().enumerate() {
+            // Don't rename the newest one.
             let rename = if i == 0 {
                 Rename::No
             } else {
-                Rename::Yes(dep.parent, dep.id, format!("{}_renamed_{}", dep.name, i))
+                let locked_name = locked
+                    .iter()
+                    .filter(|p| util::compare_names(&p.name, &dep.name) && p.version == dep.version)
+                    .find_map(|p| match &p.rename {
+                        Rename::Yes(_, _, n) => Some(n.clone()),
+                        Rename::No => None,
+                    });
+                let new_name = locked_name.unwrap_or_else(|| renamed_name(&dep.name, parent(dep)));
+                Rename::Yes(dep.parent, dep.id, new_name)
             };
 
             result.push(Package {
@@ -602,6 +1795,7 @@ pub(super) mod res {
                 version: dep.version.clone(),
                 deps: vec![], // to be filled in after resolution
                 rename,
+                marker: dep.marker.clone(),
             });
         }
         result
@@ -609,250 +1803,160 @@ pub(super) mod res {
 
     /// Assign dependencies to packages-to-install, for use in the lock file.
     /// Do this only after the dependencies are resolved.
-    fn assign_subdeps(packages: &mut Vec<Package>, updated_ids: &HashMap<u32, u32>) {
-        // We run through the non-cleaned deps first, since the parent may point to
-        // one that didn't make the cut, including cases where the versions were identical.
-        let packs2 = packages.clone(); // to search
+    pub(super) fn assign_subdeps(
+        packages: &mut [Package],
+        all_deps: &[Dependency],
+        updated_ids: &HashMap<u32, u32>,
+    ) {
+        // We run through the non-cleaned deps, since the parent may point to one that didn't
+        // make the cut, including cases where the versions were identical. Each keeps the
+        // extras its parent required it with.
+        let packs2 = packages.to_vec(); // to search
         for package in packages.iter_mut() {
-            let mut children: Vec<(u32, String, Version)> = packs2
-                .iter()
-                .filter_map(|p| {
-                    // If there were multiple instances of this dep, the parent id may have been updated.
-                    let parent_id = match updated_ids.get(&p.parent) {
-                        Some(updated_parent) => *updated_parent,
-                        None => p.parent,
-                    };
-                    if parent_id == package.id {
-                        Some((p.id, p.name.clone(), p.version.clone()))
-                    } else {
-                        None
+            for dep in all_deps {
+                // If there were multiple instances of this dep, the ids may have been updated.
+                let parent_id = *updated_ids.get(&dep.parent).unwrap_or(&dep.parent);
+                let child_id = *updated_ids.get(&dep.id).unwrap_or(&dep.id);
+                if parent_id != package.id {
+                    continue;
+                }
+                if let Some(child) = packs2.iter().find(|p| p.id == child_id) {
+                    let child = (
+                        child.id,
+                        child.name.clone(),
+                        child.version.clone(),
+                        dep.extras.clone(),
+                    );
+                    if !package.deps.contains(&child) {
+                        package.deps.push(child);
                     }
-                })
-                .collect();
-            package.deps.append(&mut children);
+                }
+            }
         }
     }
 
     /// Determine which dependencies we need to install, using the newest ones which meet
-    /// all constraints. Gets data from a cached repo, and Pypi. Returns name, version, and name/version of its deps.
+    /// all constraints, or the oldest, depending on `resolution`. Gets data from a cached repo,
+    /// and Pypi. Returns name, version, and name/version of its deps.
     pub fn resolve(
         reqs: &[Req],
         locked: &[crate::Package],
         os: util::Os,
         py_vers: &Version,
+        resolution: Resolution,
         //) -> Result<Vec<(String, Version, Vec<Req>)>, reqwest::Error> {
     ) -> Result<Vec<crate::Package>, reqwest::Error> {
-        let mut result = Vec::new();
-        let mut cache = HashMap::new();
-        let mut reqs_searched = Vec::new();
-
-        let mut version_cache = HashMap::new();
-        if guess_graph(
-            0,
+        Ok(resolve_with(
             reqs,
             locked,
             os,
-            &[],
             py_vers,
-            &mut result,
-            &mut cache,
-            &mut version_cache,
-            &mut reqs_searched,
-        )
-        .is_err()
-        {
-            util::abort("Problem resolving dependencies");
-        }
-
-        let mut by_name: HashMap<String, Vec<Dependency>> = HashMap::new();
-        for mut dep in result.clone() {
-            // The formatted name may be different from the pypi one. Eg `IPython` vice `ipython`.
-            let fmtd_name = format_name(&dep.name, &version_cache);
-            dep.name = fmtd_name.clone();
-
-            if let Some(k) = by_name.get_mut(&dep.name) {
-                k.push(dep)
-            } else {
-                by_name.insert(fmtd_name, vec![dep]);
-            }
-        }
-
-        // Deal with duplicates, conflicts etc. The code above assumed no conflicts, and that
-        // we can pick the newest compatible version for each req. We pass only the info
-        // needed to build the locked dependencies, and strip intermediary info like ids.
-
-        // updated_ids is used to remap lockpack dependencies, when a dep(version) other than their
-        // parent is chosen for the package.
-        let mut updated_ids = HashMap::new();
-        let mut result_cleaned = vec![];
-        for (name, deps) in &by_name {
-            let fmtd_name = format_name(name, &version_cache);
-            match deps.len() {
-                1 => {
-                    // This dep is only specified once; no need to resolve conflicts.
-                    let dep = &deps[0];
-
-                    result_cleaned.push(Package {
-                        id: dep.id,
-                        parent: dep.parent,
-                        name: fmtd_name,
-                        version: dep.version.clone(),
-                        deps: vec![], // to be filled in after resolution
-                        rename: Rename::No,
-                    });
-                }
-                x if x > 1 => {
-                    // Find what constraints are driving each dep that shares a name.
-                    let constraints = find_constraints(reqs, &result, deps);
-
-                    let _names: Vec<String> = deps.iter().map(|d| d.version.to_string()).collect();
-                    let inter = dep_types::intersection_many(&constraints);
-
-                    if inter.is_empty() {
-                        result_cleaned.append(&mut make_renamed_packs(
-                            &version_cache,
-                            deps,
-                            &fmtd_name,
-                        ));
-                        continue;
-                    }
-
-                    // If a version we've examined meets all constraints for packages that use it, use it -
-                    // we've already built the graph to accommodate its sub-deps.
-
-                    // If unable, find the highest version that meets the constraints, and determine
-                    // what its dependencies are.
-
-                    // Otherwise install all,
-                    // and rename as-required(By which criteria? the older one?). This ensures our
-                    // graph is always resolveable, and avoids diving through the graph recursively,
-                    // dealing with cycles etc. There may be ways around this in some cases.
-                    // todo: Renaming may not work if the renamed dep uses compiled code.
-
-                    let newest_compatible = deps
-                        .iter()
-                        .filter(|dep| {
-                            inter
-                                .iter()
-                                .any(|i| i.0 <= dep.version && dep.version <= i.1)
-                        })
-                        .max_by(|a, b| a.version.cmp(&b.version));
-
-                    if let Some(best) = newest_compatible {
-                        result_cleaned.push(Package {
-                            id: best.id,
-                            parent: best.parent,
-                            name: fmtd_name,
-                            version: best.version.clone(),
-                            deps: vec![], // to be filled in after resolution
-                            rename: Rename::No,
-                        });
-
-                        // Indicate we need to update the parent. We can't do it here, since
-                        // we don't know if we're pr
-                        // ocessed the parent[s] yet. Not doing this will
-                        // result in incorrect dependencies listed in lock packs.
-                        for dep in deps {
-                            // note that we push the old ids, so we can update the subdeps with the new versions.
-                            //                        updated_ids.insert(dep.id, best.id).expect("Problem inserting updated id");
-                            updated_ids.insert(dep.id, best.id);
-                        }
-                    } else {
-                        // We consider the possibility there's a compatible version
-                        // that wasn't one of the best-per-req we queried.
-                        println!("⛏️ Digging deeper to resolve dependencies for {}...", name);
-
-                        // I think we should query with the raw name, not fmted?
-                        let versions = &version_cache.get(name).unwrap().2;
-
-                        if versions.is_empty() {
-                            result_cleaned.append(&mut make_renamed_packs(
-                                &version_cache,
-                                deps,
-                                //                            &result,
-                                &fmtd_name,
-                            ));
-                            continue;
-                        }
-
-                        // Generate dependencies here for all avail versions.
-                        let unresolved_deps = versions.iter().filter_map(|vers| {
-                            if inter.iter().any(|i| i.0 <= *vers && *vers <= i.1) {
-                                Some(Dependency {
-                                    id: 0, // placeholder; we'll assign an id to the one we pick.
-                                    name: fmtd_name.clone(),
-                                    version: vers.clone(),
-                                    reqs: vec![], // todo
-                                    parent: 0,    // todo
-                                })
-                            } else {
-                                None
-                            }
-                        });
-
-                        let mut newest_unresolved = unresolved_deps
-                            .max_by(|a, b| a.version.cmp(&b.version))
-                            .unwrap();
-
-                        newest_unresolved.id = result.iter().map(|d| d.id).max().unwrap_or(0) + 1;
-
-                        result_cleaned.push(Package {
-                            id: newest_unresolved.id,
-                            parent: newest_unresolved.parent,
-                            name: fmtd_name,
-                            version: newest_unresolved.version,
-                            deps: vec![], // to be filled in after resolution
-                            rename: Rename::No,
-                        });
-
-                        // todo: Do a check on newest_unresolved! If fails, execute renamed plan
-
-                        for dep in deps {
-                            // note that we push the old ids, so we can update the subdeps with the new versions.
-                            updated_ids.insert(dep.id, newest_unresolved.id);
-                        }
-                    }
-                }
-                _ => panic!("We shouldn't be seeing this!"),
-            }
-        }
-
-        // Now, assign subdeps, so we can store them in the lock.
-        assign_subdeps(&mut result_cleaned, &updated_ids);
-
-        let mut a = result;
-        for b in &mut a {
-            b.reqs = vec![];
-        }
-
-        Ok(result_cleaned)
+            resolution,
+            &mut fetch_req_data,
+        ))
     }
 }
 #[cfg(test)]
 pub mod tests {
     use super::{res::*, *};
+    use rstest::rstest;
 
     #[test]
     fn warehouse_versions() {
         // Makes API call
         // Assume no new releases since writing this test.
+        let mut versions = get_version_info("scinot", None).unwrap().2;
+        versions.sort();
         assert_eq!(
-            get_version_info("scinot", None).unwrap().2.sort(),
-            vec![
-                Version::new(0, 0, 1),
-                Version::new(0, 0, 2),
-                Version::new(0, 0, 3),
-                Version::new(0, 0, 4),
-                Version::new(0, 0, 5),
-                Version::new(0, 0, 6),
-                Version::new(0, 0, 7),
-                Version::new(0, 0, 8),
-                Version::new(0, 0, 9),
-                Version::new(0, 0, 10),
-                Version::new(0, 0, 11),
-            ]
-            .sort()
+            versions,
+            (1..=11)
+                .map(|patch| Version::new(0, 0, patch))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[rstest(
+        name,
+        data,
+        files,
+        latest,
+        chosen,
+        case(
+            "wsgiref",
+            include_str!("testdata/warehouse/wsgiref.json"),
+            1,
+            Version::new(0, 1, 2),
+            "wsgiref-0.1.2.zip"
+        ),
+        // No `requires_python`, `null` and missing digests, and an installer without a URL.
+        case(
+            "pycrypto",
+            include_str!("testdata/warehouse/pycrypto.json"),
+            2,
+            Version::new(2, 6, 1),
+            "pycrypto-2.6.1.tar.gz"
+        ),
+        case(
+            "argparse",
+            include_str!("testdata/warehouse/argparse.json"),
+            2,
+            Version::new(1, 4, 0),
+            "argparse-1.4.0-py2.py3-none-any.whl"
+        )
+    )]
+    fn old_warehouse_data(name: &str, data: &str, files: usize, latest: Version, chosen: &str) {
+        let data: WarehouseData = parse_warehouse_json(name, data).unwrap();
+        assert_eq!(data.releases.values().flatten().count(), files);
+        let releases = data.releases[release_key(&data.releases, &latest).unwrap()].clone();
+
+        let (found_name, version, _) = version_info(name, data, None).unwrap();
+        assert_eq!((found_name.as_str(), &version), (name, &latest));
+        let choice = util::find_best_release(
+            &releases,
+            name,
+            &version,
+            util::Os::Linux,
+            &Version::new(3, 11, 0),
+            None,
         );
+        assert_eq!(choice.release.filename, chosen);
+    }
+
+    #[test]
+    fn unreadable_warehouse_data() {
+        let err =
+            parse_warehouse_json::<WarehouseData>("oldpkg", r#"{"info": {"version": "1.0"}}"#)
+                .unwrap_err();
+        assert_eq!(err.package, "oldpkg");
+        assert!(err.to_string().contains("missing field `name`"));
+    }
+
+    #[test]
+    fn release_keys_prefer_exact_string() {
+        // pytz's calendar versions, and a synthetic four-part one.
+        let releases: HashMap<String, Vec<WarehouseRelease>> = [
+            "2023.3",
+            "2023.3.post1",
+            "2013b",
+            "1.2.3",
+            "1.2.3.4",
+            "1.0",
+            "1.0.0",
+            "3.1",
+        ]
+        .iter()
+        .map(|k| (k.to_string(), vec![]))
+        .collect();
+        let key = |v: &str| release_key(&releases, &Version::from_str(v).unwrap());
+
+        assert_eq!(key("2023.3.post1"), Some("2023.3.post1"));
+        assert_eq!(key("2023.3"), Some("2023.3"));
+        assert_eq!(key("1.2.3.4"), Some("1.2.3.4"));
+        assert_eq!(key("1.2.3"), Some("1.2.3"));
+        assert_eq!(key("1.0"), Some("1.0"));
+        assert_eq!(key("1.0.0"), Some("1.0.0"));
+        assert_eq!(key("3.1.0"), Some("3.1"));
+        assert_eq!(key("1.2.3.5"), None);
     }
 
     //    #[test]
@@ -897,5 +2001,711 @@ pub mod tests {
     // todo Add more of these, for variety.
     //    }
 
+    #[test]
+    fn markers_combine_across_parents() {
+        let win = Marker::from_str("sys_platform == 'win32'").unwrap();
+        let mac = Marker::from_str("sys_platform == 'darwin'").unwrap();
+        let dep = |marker: Option<&Marker>| Dependency {
+            id: 0,
+            name: "colorama".to_owned(),
+            version: Version::new(0, 4, 6),
+            reqs: vec![],
+            parent: 0,
+            marker: marker.cloned(),
+            extras: DepExtras::default(),
+        };
+
+        assert_eq!(combine_markers(&[dep(Some(&win))]), Some(win.clone()));
+        assert_eq!(
+            combine_markers(&[dep(Some(&win)), dep(Some(&mac)), dep(Some(&win))]),
+            Some(Marker::Or(vec![win.clone(), mac]))
+        );
+        assert_eq!(combine_markers(&[dep(Some(&win)), dep(None)]), None);
+    }
+
+    type VersionInfo = Result<(String, Version, Vec<Version>), FetchError>;
+
+    /// Run `fetch_req_data_with` for `reqs`, using canned version info in place of HTTP calls.
+    /// Returns the result, and the packages we queried dependency data for.
+    fn fetch_with(
+        reqs: &[Req],
+        version_info: impl Fn(&str) -> VersionInfo + Sync,
+    ) -> (Result<Vec<ReqCache>, FetchError>, Vec<String>) {
+        let mut queried = vec![];
+        let result = fetch_req_data_with(
+            reqs,
+            &mut HashMap::new(),
+            &Version::new(3, 9, 0),
+            &mut Progress::new(),
+            false,
+            |name, _| version_info(name),
+            |query_data| {
+                queried = query_data.keys().cloned().collect();
+                Ok(query_data
+                    .iter()
+                    .map(|(name, versions)| ReqCache {
+                        name: Some(name.clone()),
+                        version: versions[0].to_string(),
+                        requires_python: None,
+                        requires_dist: vec![],
+                    })
+                    .collect())
+            },
+        );
+        (result, queried)
+    }
+
+    #[test]
+    fn fetch_queries_best_version() {
+        let reqs = vec![Req::new(
+            "urllib3".to_owned(),
+            Constraint::from_str_multiple("<1.27").unwrap(),
+        )];
+        let (result, queried) = fetch_with(&reqs, |name| {
+            Ok((
+                name.to_owned(),
+                Version::new(2, 0, 7),
+                vec![Version::new(1, 26, 17), Version::new(2, 0, 7)],
+            ))
+        });
+
+        assert_eq!(queried, vec!["urllib3"]);
+        assert_eq!(result.unwrap()[0].version, "1.26.17");
+    }
+
+    #[rstest(
+        kind,
+        case::not_found(FetchErrorKind::NotFound(vec!["boto3".to_owned()])),
+        case::status(FetchErrorKind::Status(503)),
+        case::network(FetchErrorKind::Network("connection refused".to_owned())),
+        case::decode(FetchErrorKind::Decode("expected value".to_owned()))
+    )]
+    fn fetch_errors_propagate(kind: FetchErrorKind) {
+        let reqs = vec![
+            Req::new("boto3".to_owned(), vec![]),
+            Req::new("botoo3".to_owned(), vec![]),
+        ];
+        let (result, queried) = fetch_with(&reqs, |name| match name {
+            "boto3" => Ok((
+                name.to_owned(),
+                Version::new(1, 28, 0),
+                vec![Version::new(1, 28, 0)],
+            )),
+            _ => Err(FetchError::new(name, kind.clone())),
+        });
+
+        assert_eq!(result.unwrap_err(), FetchError::new("botoo3", kind));
+        assert!(queried.is_empty());
+    }
+
+    #[test]
+    fn fetch_without_compatible_versions() {
+        // Nothing older than 1.0 exists, so we mustn't query a placeholder version.
+        let reqs = vec![Req::new(
+            "boto3".to_owned(),
+            Constraint::from_str_multiple("<1.0").unwrap(),
+        )];
+        let (result, queried) = fetch_with(&reqs, |name| {
+            Ok((
+                name.to_owned(),
+                Version::new(1, 28, 0),
+                vec![Version::new(1, 28, 0)],
+            ))
+        });
+
+        assert_eq!(
+            result.unwrap_err().kind,
+            FetchErrorKind::NoCompatibleVersions
+        );
+        assert!(queried.is_empty());
+    }
+
+    fn query_of(count: usize) -> HashMap<String, Vec<Version>> {
+        (0..count)
+            .map(|i| (format!("pkg{:03}", i), vec![Version::new(1, 0, 0)]))
+            .collect()
+    }
+
+    fn req_caches(names: impl IntoIterator<Item = String>) -> Vec<ReqCache> {
+        names
+            .into_iter()
+            .map(|name| ReqCache {
+                name: Some(name),
+                version: "1.0.0".into(),
+                requires_python: None,
+                requires_dist: vec![],
+            })
+            .collect()
+    }
+
+    #[rstest(count, expected,
+        case(1, vec![1]),
+        case(50, vec![50]),
+        case(51, vec![50, 1]),
+        case(120, vec![50, 50, 20])
+    )]
+    fn dependency_queries_chunked(count: usize, expected: Vec<usize>) {
+        let mut sizes = vec![];
+        let result = get_req_cache_remote_with(
+            &query_of(count),
+            |body| {
+                sizes.push(body.packages.len());
+                Some(req_caches(body.packages.keys().cloned()))
+            },
+            |_| panic!("PyPI shouldn't be queried"),
+        )
+        .unwrap();
+        assert_eq!(sizes, expected);
+        assert_eq!(result.len(), count);
+    }
+
+    #[test]
+    fn failed_chunk_falls_back_to_pypi() {
+        let mut posts = 0;
+        let mut from_pypi = vec![];
+        let result = get_req_cache_remote_with(
+            &query_of(120),
+            |body| {
+                posts += 1;
+                (posts != 2).then(|| req_caches(body.packages.keys().cloned()))
+            },
+            |chunk| {
+                from_pypi.extend(chunk.keys().cloned());
+                Ok(req_caches(chunk.keys().cloned()))
+            },
+        )
+        .unwrap();
+        assert_eq!(posts, 3);
+        from_pypi.sort();
+        assert_eq!(from_pypi.len(), 50);
+        assert_eq!(from_pypi.first().unwrap(), "pkg050");
+        assert_eq!(from_pypi.last().unwrap(), "pkg099");
+
+        let mut names: Vec<String> = result.into_iter().filter_map(|r| r.name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), 120);
+    }
+
+    #[test]
+    fn failed_fallback_propagates() {
+        let result = get_req_cache_remote_with(
+            &query_of(3),
+            |_| None,
+            |chunk| {
+                let name = chunk.keys().next().unwrap();
+                Err(FetchError::new(name, FetchErrorKind::NotFound(vec![])))
+            },
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn warehouse_status() {
+        let suggest = |_: &str| vec!["requests".to_owned()];
+
+        assert!(check_status("requests", reqwest::StatusCode::OK, suggest).is_ok());
+        let not_found =
+            check_status("reqests", reqwest::StatusCode::NOT_FOUND, suggest).unwrap_err();
+        assert_eq!(
+            not_found.kind,
+            FetchErrorKind::NotFound(vec!["requests".to_owned()])
+        );
+        assert!(not_found.to_string().contains("Is it spelled correctly?"));
+        assert!(not_found.to_string().contains("requests"));
+        assert_eq!(
+            check_status("requests", reqwest::StatusCode::BAD_GATEWAY, |_| panic!())
+                .unwrap_err()
+                .kind,
+            FetchErrorKind::Status(502)
+        );
+    }
+
+    /// A locked graph of 50 packages: 10 top-level ones, each with 4 dependencies.
+    fn locked_graph() -> (Vec<Req>, Vec<Package>) {
+        let v1 = Version::new(1, 0, 0);
+        let mut reqs = vec![];
+        let mut locked = vec![];
+        for i in 0..10 {
+            let top_id = i * 5 + 1;
+            let deps: Vec<(u32, String, Version, DepExtras)> = (1..5)
+                .map(|j| {
+                    let name = format!("dep{}-{}", i, j);
+                    (top_id + j, name, v1.clone(), DepExtras::default())
+                })
+                .collect();
+            for (id, name, version, _) in &deps {
+                locked.push(Package {
+                    id: *id,
+                    parent: top_id,
+                    name: name.clone(),
+                    version: version.clone(),
+                    deps: vec![],
+                    rename: Rename::No,
+                    marker: None,
+                });
+            }
+            locked.push(Package {
+                id: top_id,
+                parent: 0,
+                name: format!("top{}", i),
+                version: v1.clone(),
+                deps,
+                rename: Rename::No,
+                marker: None,
+            });
+            reqs.push(Req::new(
+                format!("top{}", i),
+                Constraint::from_str_multiple(">=1.0").unwrap(),
+            ));
+        }
+        (reqs, locked)
+    }
+
+    /// Build the graph for `reqs`, answering fetches from `index`, which maps names to the
+    /// version we'll pick, and its requirements. Returns the graph, and the reqs fetched.
+    fn graph_with(
+        reqs: &[Req],
+        locked: &[Package],
+        index: &[(&str, &str, &[&str])],
+    ) -> (Vec<Dependency>, Vec<String>) {
+        graph_for(util::Os::Linux, reqs, locked, index)
+    }
+
+    /// Answer fetches from `index`, which maps names to the version we'll pick, and its
+    /// requirements, noting the reqs fetched in `fetched`.
+    fn fetch_from<'a>(
+        index: &'a [(&str, &str, &[&str])],
+        fetched: &'a mut Vec<String>,
+    ) -> impl FnMut(
+        &[Req],
+        &mut VersionCache,
+        &Version,
+        &mut Progress,
+        bool,
+    ) -> Result<Vec<ReqCache>, FetchError>
+           + 'a {
+        move |reqs, _, _, _, _| {
+            fetched.extend(reqs.iter().map(|r| r.name.clone()));
+            Ok(reqs
+                .iter()
+                .map(|r| {
+                    let (name, version, requires) =
+                        index.iter().find(|(n, _, _)| *n == r.name).unwrap();
+                    ReqCache {
+                        name: Some(name.to_string()),
+                        version: version.to_string(),
+                        requires_python: None,
+                        requires_dist: requires.iter().map(|r| r.to_string()).collect(),
+                    }
+                })
+                .collect())
+        }
+    }
+
+    fn graph_for(
+        os: util::Os,
+        reqs: &[Req],
+        locked: &[Package],
+        index: &[(&str, &str, &[&str])],
+    ) -> (Vec<Dependency>, Vec<String>) {
+        let mut fetched = vec![];
+        let (graph, _) = build_graph(
+            reqs,
+            locked,
+            os,
+            &Version::new(3, 9, 0),
+            Resolution::Highest,
+            &mut fetch_from(index, &mut fetched),
+        )
+        .unwrap();
+        fetched.sort();
+        (graph, fetched)
+    }
+
+    /// Resolve `reqs` for Python 3.9 on Linux, as `res::resolve` does, answering fetches
+    /// from `index`.
+    pub fn resolve_from(
+        reqs: &[Req],
+        locked: &[Package],
+        index: &[(&str, &str, &[&str])],
+    ) -> Vec<Package> {
+        resolve_with(
+            reqs,
+            locked,
+            util::Os::Linux,
+            &Version::new(3, 9, 0),
+            Resolution::Highest,
+            &mut fetch_from(index, &mut vec![]),
+        )
+    }
+
+    #[test]
+    fn locked_graph_not_fetched() {
+        let (mut reqs, locked) = locked_graph();
+        let (graph, fetched) = graph_with(&reqs, &locked, &[]);
+        assert_eq!(graph.len(), 50);
+        assert!(fetched.is_empty());
+
+        // Adding a leaf only fetches the leaf, even if it depends on locked packages.
+        reqs.push(Req::new("leaf".to_owned(), vec![]));
+        let index: &[(&str, &str, &[&str])] = &[("leaf", "0.5.0", &["dep3-2 (>=1.0)"])];
+        let (graph, fetched) = graph_with(&reqs, &locked, index);
+        assert_eq!(graph.len(), 52);
+        assert_eq!(fetched, vec!["leaf"]);
+    }
+
+    #[test]
+    fn violated_locks_fetched() {
+        let (mut reqs, locked) = locked_graph();
+        reqs.push(Req::new("leaf".to_owned(), vec![]));
+        let index: &[(&str, &str, &[&str])] = &[
+            ("leaf", "0.5.0", &["dep3-2 (>=2.0)"]),
+            ("dep3-2", "2.1.0", &[]),
+        ];
+        let (graph, fetched) = graph_with(&reqs, &locked, index);
+
+        assert_eq!(fetched, vec!["dep3-2", "leaf"]);
+        assert!(graph
+            .iter()
+            .any(|d| d.name == "dep3-2" && d.version == Version::new(2, 1, 0)));
+    }
+
+    #[test]
+    fn platform_reqs_resolved_per_os() {
+        let platform = |name: &str, version: &str, sys_platform: &str| Req {
+            marker: Some(Marker::from_str(&format!("sys_platform == '{}'", sys_platform)).unwrap()),
+            ..Req::new(
+                name.to_owned(),
+                Constraint::from_str_multiple(version).unwrap(),
+            )
+        };
+        let reqs = vec![
+            Req::new("requests".to_owned(), vec![]),
+            platform("pywin32", "^306", "win32"),
+            platform("torch", "==2.1.0", "linux"),
+            platform("torch", "==2.0.1", "darwin"),
+        ];
+        let index: &[(&str, &str, &[&str])] = &[
+            ("requests", "2.32.3", &[]),
+            ("pywin32", "306", &[]),
+            ("torch", "2.1.0", &[]),
+        ];
+
+        let (graph, fetched) = graph_for(util::Os::Linux, &reqs, &[], index);
+        assert_eq!(fetched, vec!["requests", "torch"]);
+        let torch = graph.iter().find(|d| d.name == "torch").unwrap();
+        assert_eq!(torch.version, Version::new(2, 1, 0));
+        assert_eq!(
+            torch.marker.as_ref().unwrap().to_string(),
+            "sys_platform == 'linux'"
+        );
+
+        let index: &[(&str, &str, &[&str])] =
+            &[("requests", "2.32.3", &[]), ("pywin32", "306", &[])];
+        let (graph, fetched) = graph_for(util::Os::Windows, &reqs, &[], index);
+        assert_eq!(fetched, vec!["pywin32", "requests"]);
+        let pywin32 = graph.iter().find(|d| d.name == "pywin32").unwrap();
+        assert_eq!(
+            pywin32.marker.as_ref().unwrap().to_string(),
+            "sys_platform == 'win32'"
+        );
+    }
+
+    /// Versions on the (mocked) warehouse, with pre-releases ahead of the latest release.
+    const RELEASES: &[(&str, &[&str])] = &[
+        ("fastapi", &["0.99.1", "0.100.0rc1"]),
+        ("pydantic", &["1.10.0", "2.0.0b3", "2.0.0b4"]),
+        ("cryptography", &["1.0.0", "1.0.0.post1", "1.0.0.post2"]),
+    ];
+
+    #[rstest(
+        name,
+        constrs,
+        allow_prereleases,
+        expected,
+        case::stable_by_default("fastapi", ">=0.90", false, "0.99.1"),
+        case::allowed("fastapi", ">=0.90", true, "0.100.0rc1"),
+        case::rc_pin("fastapi", "==0.100.0rc1", false, "0.100.0rc1"),
+        case::beta_pin("pydantic", "==2.0.0b3", false, "2.0.0b3"),
+        case::caret_excludes_next_major("pydantic", "^1.10", false, "1.10.0"),
+        case::post_pin("cryptography", "==1.0.0.post1", false, "1.0.0.post1"),
+        case::post_is_stable("cryptography", ">=1.0", false, "1.0.0.post2")
+    )]
+    fn resolve_prereleases(name: &str, constrs: &str, allow_prereleases: bool, expected: &str) {
+        let req = Req {
+            allow_prereleases,
+            ..Req::new(
+                name.to_owned(),
+                Constraint::from_str_multiple(constrs).unwrap(),
+            )
+        };
+        let version_info = |name: &str| -> VersionInfo {
+            let (_, releases) = RELEASES.iter().find(|(n, _)| *n == name).unwrap();
+            let all: Vec<Version> = releases
+                .iter()
+                .map(|v| Version::from_str(v).unwrap())
+                .collect();
+            let latest = all.iter().filter(|v| !v.is_prerelease()).max().unwrap();
+            Ok((name.to_owned(), latest.clone(), all))
+        };
+
+        let (graph, _) = build_graph(
+            &[req],
+            &[],
+            util::Os::Linux,
+            &Version::new(3, 9, 0),
+            Resolution::Highest,
+            &mut |reqs, vers_cache, py_vers, progress, lowest| {
+                fetch_req_data_with(
+                    reqs,
+                    vers_cache,
+                    py_vers,
+                    progress,
+                    lowest,
+                    |name, _| version_info(name),
+                    |query_data| {
+                        Ok(query_data
+                            .iter()
+                            .map(|(name, versions)| ReqCache {
+                                name: Some(name.clone()),
+                                version: versions[0].to_string(),
+                                requires_python: None,
+                                requires_dist: vec![],
+                            })
+                            .collect())
+                    },
+                )
+            },
+        )
+        .unwrap();
+
+        // The version is written to the lock as a string, and must read back the same.
+        let version = graph[0].version.to_string();
+        assert_eq!(version, expected);
+        assert_eq!(Version::from_str(&version).unwrap(), graph[0].version);
+    }
+
+    #[rstest(
+        resolution,
+        expected,
+        case(Resolution::Highest, ("2.0.0", "1.2.0")),
+        case(Resolution::Lowest, ("1.5.0", "1.1.0")),
+        case(Resolution::LowestDirect, ("1.5.0", "1.2.0"))
+    )]
+    fn resolution_strategies(resolution: Resolution, expected: (&str, &str)) {
+        // `app` requires `lib>=1.1` in each version.
+        let versions = |name: &str| -> Vec<Version> {
+            let versions: &[&str] = match name {
+                "app" => &["1.0.0", "1.5.0", "2.0.0"],
+                _ => &["1.0.0", "1.1.0", "1.2.0"],
+            };
+            versions
+                .iter()
+                .map(|v| Version::from_str(v).unwrap())
+                .collect()
+        };
+        let req = Req::new(
+            "app".to_owned(),
+            Constraint::from_str_multiple(">=1.5").unwrap(),
+        );
+
+        let (graph, _) = build_graph(
+            &[req],
+            &[],
+            util::Os::Linux,
+            &Version::new(3, 9, 0),
+            resolution,
+            &mut |reqs, vers_cache, py_vers, progress, lowest| {
+                fetch_req_data_with(
+                    reqs,
+                    vers_cache,
+                    py_vers,
+                    progress,
+                    lowest,
+                    |name, _| {
+                        let all = versions(name);
+                        Ok((name.to_owned(), all.iter().max().unwrap().clone(), all))
+                    },
+                    |query_data| {
+                        Ok(query_data
+                            .iter()
+                            .flat_map(|(name, versions)| {
+                                versions.iter().map(move |v| ReqCache {
+                                    name: Some(name.clone()),
+                                    version: v.to_string(),
+                                    requires_python: None,
+                                    requires_dist: match name.as_str() {
+                                        "app" => vec!["lib>=1.1".into()],
+                                        _ => vec![],
+                                    },
+                                })
+                            })
+                            .collect())
+                    },
+                )
+            },
+        )
+        .unwrap();
+
+        let version = |name: &str| {
+            graph
+                .iter()
+                .find(|d| d.name == name)
+                .unwrap()
+                .version
+                .to_string()
+        };
+        assert_eq!((version("app").as_str(), version("lib").as_str()), expected);
+    }
+
+    #[test]
+    fn resolution_recorded_in_lock() {
+        let mut metadata = HashMap::new();
+        assert_eq!(Resolution::from_lock(&metadata), Resolution::Highest);
+        Resolution::LowestDirect.mark(&mut metadata);
+        assert_eq!(Resolution::from_lock(&metadata), Resolution::LowestDirect);
+        Resolution::Highest.mark(&mut metadata);
+        assert_eq!(metadata[RESOLUTION_KEY], "highest");
+    }
+
+    #[test]
+    fn versions_queried_once_and_concurrently() {
+        // `a` requires `b`, `c`, and `D`; `b` requires `c` and `d`.
+        let requires = |name: &str| -> Vec<String> {
+            match name {
+                "a" => vec!["b>=1.0".into(), "c>=1.0".into(), "D>=1.0".into()],
+                "b" => vec!["c>=1.0".into(), "d>=1.0".into()],
+                _ => vec![],
+            }
+        };
+        let queried = std::sync::Mutex::new(vec![]);
+        let test_thread = thread::current().id();
+
+        let reqs = vec![
+            Req::new("a".to_owned(), vec![]),
+            Req::new("B".to_owned(), vec![]),
+        ];
+        let (graph, _) = build_graph(
+            &reqs,
+            &[],
+            util::Os::Linux,
+            &Version::new(3, 9, 0),
+            Resolution::Highest,
+            &mut |reqs, vers_cache, py_vers, progress, lowest| {
+                fetch_req_data_with(
+                    reqs,
+                    vers_cache,
+                    py_vers,
+                    progress,
+                    lowest,
+                    |name, _| {
+                        queried
+                            .lock()
+                            .unwrap()
+                            .push((name.to_lowercase(), thread::current().id()));
+                        let version = Version::new(1, 0, 0);
+                        Ok((name.to_owned(), version.clone(), vec![version]))
+                    },
+                    |query_data| {
+                        Ok(query_data
+                            .iter()
+                            .map(|(name, versions)| ReqCache {
+                                name: Some(name.clone()),
+                                version: versions[0].to_string(),
+                                requires_python: None,
+                                requires_dist: requires(&name.to_lowercase()),
+                            })
+                            .collect())
+                    },
+                )
+            },
+        )
+        .unwrap();
+        assert!(graph.len() >= 4);
+
+        let queried = queried.into_inner().unwrap();
+        let mut names: Vec<&str> = queried.iter().map(|(n, _)| n.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["a", "b", "c", "d"]);
+        assert!(queried.iter().all(|(_, id)| *id != test_thread));
+    }
+
+    #[test]
+    fn hypothetical_resolutions_share_fetches() {
+        // `app` and `tool` need incompatible versions of `lib`.
+        let requires = |name: &str| -> Vec<String> {
+            match name {
+                "app" => vec!["lib<2".into()],
+                "tool" => vec!["lib>=2".into()],
+                _ => vec![],
+            }
+        };
+        let version_calls = std::sync::atomic::AtomicUsize::new(0);
+        let req_calls = std::cell::Cell::new(0);
+        let mut cache = FetchCache::default();
+        let mut resolve = |reqs: &[Req]| {
+            try_resolve_with(
+                reqs,
+                util::Os::Linux,
+                &Version::new(3, 11, 0),
+                &mut |reqs, vers_cache, py_vers, progress, lowest| {
+                    cache.fetch_with(
+                        reqs,
+                        vers_cache,
+                        py_vers,
+                        progress,
+                        lowest,
+                        |name, _| {
+                            version_calls.fetch_add(1, Ordering::SeqCst);
+                            let versions = match name {
+                                "lib" => vec![Version::new(1, 0, 0), Version::new(2, 0, 0)],
+                                _ => vec![Version::new(1, 0, 0)],
+                            };
+                            Ok((
+                                name.to_owned(),
+                                versions[versions.len() - 1].clone(),
+                                versions,
+                            ))
+                        },
+                        |query_data| {
+                            req_calls.set(req_calls.get() + query_data.len());
+                            Ok(query_data
+                                .iter()
+                                .flat_map(|(name, versions)| {
+                                    versions.iter().map(move |v| ReqCache {
+                                        name: Some(name.clone()),
+                                        version: v.to_string(),
+                                        requires_python: None,
+                                        requires_dist: requires(name),
+                                    })
+                                })
+                                .collect())
+                        },
+                    )
+                },
+            )
+            .unwrap()
+        };
+
+        let both = vec![
+            Req::new("app".to_owned(), vec![]),
+            Req::new("tool".to_owned(), vec![]),
+        ];
+        assert_eq!(resolve(&both), vec!["lib".to_owned()]);
+        let fetched = (version_calls.load(Ordering::SeqCst), req_calls.get());
+        assert_eq!(fetched, (3, 4));
+
+        let pinned = vec![Req::new(
+            "app".to_owned(),
+            Constraint::from_str_multiple("==1.0.0").unwrap(),
+        )];
+        assert!(resolve(&pinned).is_empty());
+        assert_eq!(
+            (version_calls.load(Ordering::SeqCst), req_calls.get()),
+            fetched
+        );
+    }
+
     // todo: Make dep-resolver tests, including both simple, conflicting/resolvable, and confliction/unresolvable.
 }
@@ -1,24 +1,25 @@
 use std::{
     cmp,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     error::Error,
     fmt,
     hash::{Hash, Hasher},
     io::Write,
     num,
     str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use nom::combinator::all_consuming;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use termcolor::{Buffer, BufferWriter, Color, ColorSpec, WriteColor};
 
-// #[mockall_double::double]
+#[mockall_double::double]
 use crate::dep_resolution::res;
 use crate::{
     dep_parser::{
-        parse_constraint, parse_pip_str, parse_req, parse_req_pypi_fmt, parse_version,
-        parse_wh_py_vers,
+        make_cfg_req, parse_cfg_value, parse_constraint, parse_marker, parse_pip_str, parse_req,
+        parse_req_pypi_fmt, parse_version, parse_wh_py_vers,
     },
     dep_resolution::WarehouseRelease,
     util, CliConfig,
@@ -26,6 +27,14 @@ use crate::{
 
 pub const MAX_VER: u32 = 999_999; // Represents the highest major version we can have
 
+static ALL_PRERELEASES: AtomicBool = AtomicBool::new(false);
+
+/// For `allow_prereleases` in `[tool.pyflow.settings]`, or `--pre`: pre-releases of every
+/// package are candidates.
+pub fn allow_all_prereleases() {
+    ALL_PRERELEASES.store(true, Ordering::SeqCst);
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Dependency {
     pub id: u32,
@@ -36,6 +45,26 @@ pub struct Dependency {
     // The latter is so we know which package to mangle the inputs for, if
     // we need to rename this one.
     pub parent: u32, // id
+    pub marker: Option<Marker>,
+    pub extras: DepExtras,
+}
+
+/// How a package is required by its parent, eg `uvicorn[standard] ; extra == 'server'`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DepExtras {
+    /// The parent's extra that requires it; it's not needed without.
+    pub extra: Option<String>,
+    /// The extras it's required with.
+    pub install_with: Vec<String>,
+}
+
+impl DepExtras {
+    pub fn from_req(req: &Req) -> Self {
+        Self {
+            extra: req.extra.clone(),
+            install_with: req.install_with_extras.clone().unwrap_or_default(),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -72,9 +101,9 @@ impl From<num::ParseIntError> for DependencyError {
 }
 
 impl From<reqwest::Error> for DependencyError {
-    fn from(_: reqwest::Error) -> Self {
+    fn from(e: reqwest::Error) -> Self {
         Self {
-            details: "Network error".into(),
+            details: util::http::describe(&e),
         }
     }
 }
@@ -86,8 +115,11 @@ pub enum VersionModifier {
     Beta,
     ReleaseCandidate,
     Dep, // todo: Not sure what this is, but have found it.
+    Dev,
     // Used to allow comparisons between versions that have and don't have modifiers.
     Null,
+    /// A post-release, eg `1.0.post1`. Unlike the others, this comes after the release.
+    Post,
     Other(String),
 }
 
@@ -100,6 +132,8 @@ impl FromStr for VersionModifier {
             "b" => Self::Beta,
             "rc" => Self::ReleaseCandidate,
             "dep" => Self::Dep,
+            "dev" => Self::Dev,
+            "post" => Self::Post,
             //_ => return Err(DependencyError::new("Problem parsing version modifier")),
             _x => Self::Other(s.to_string()),
         };
@@ -107,26 +141,31 @@ impl FromStr for VersionModifier {
     }
 }
 
-impl ToString for VersionModifier {
-    fn to_string(&self) -> String {
-        match self {
-            Self::Alpha => "a".into(),
-            Self::Beta => "b".into(),
-            Self::ReleaseCandidate => "rc".into(),
-            Self::Dep => "dep".into(),
+impl fmt::Display for VersionModifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Alpha => "a",
+            Self::Beta => "b",
+            Self::ReleaseCandidate => "rc",
+            Self::Dep => "dep",
+            // These are conventionally separated from the release with a dot, eg `1.0.post1`.
+            Self::Dev => ".dev",
+            Self::Post => ".post",
             Self::Null => panic!("Can't convert Null to string; misused"),
-            Self::Other(x) => x.into(),
-        }
+            Self::Other(x) => x,
+        })
     }
 }
 
 impl VersionModifier {
     fn orderval(self) -> u8 {
         match self {
-            Self::Null => 5,
-            Self::ReleaseCandidate => 4,
-            Self::Beta => 3,
-            Self::Alpha => 2,
+            Self::Post => 7,
+            Self::Null => 6,
+            Self::ReleaseCandidate => 5,
+            Self::Beta => 4,
+            Self::Alpha => 3,
+            Self::Dev => 2,
             Self::Dep => 1,
             Self::Other(_x) => 0,
         }
@@ -232,17 +271,70 @@ impl Version {
         }
     }
 
+    /// Alpha, beta, RC, and dev releases. Post-releases aren't pre-releases.
+    pub fn is_prerelease(&self) -> bool {
+        matches!(&self.modifier, Some((m, _)) if *m != VersionModifier::Post)
+    }
+
+    /// PEP 440: An upper bound of `<2.0` excludes `2.0rc1`, although it sorts lower, unless
+    /// the bound is itself a pre-release.
+    fn prerelease_of(&self, bound: &Self) -> bool {
+        self.is_prerelease()
+            && !bound.is_prerelease()
+            && Self {
+                modifier: None,
+                ..self.clone()
+            } == *bound
+    }
+
+    /// The release components, with `extra_num` if there is one.
+    fn release(&self) -> Vec<u32> {
+        let mut result = vec![
+            self.major.unwrap_or(0),
+            self.minor.unwrap_or(0),
+            self.patch.unwrap_or(0),
+        ];
+        result.extend(self.extra_num);
+        result
+    }
+
+    fn from_release(parts: &[u32]) -> Self {
+        Self {
+            extra_num: parts.get(3).copied(),
+            ..Self::new(parts[0], parts[1], parts[2])
+        }
+    }
+
+    /// The highest version below this one, to use `<` bounds as inclusive ones. eg 2.8.MAX for
+    /// 2.9.0, or 1.2.3.3 for 1.2.3.4. We don't go below 0.0.0.
+    fn next_below(&self) -> Self {
+        let mut parts = self.release();
+        if let Some(i) = parts.iter().rposition(|p| *p > 0) {
+            parts[i] -= 1;
+            for p in &mut parts[i + 1..] {
+                *p = MAX_VER;
+            }
+        }
+        Self::from_release(&parts)
+    }
+
+    /// The lowest version above this one, to use `>` bounds as inclusive ones.
+    fn next_above(&self) -> Self {
+        let mut parts = self.release();
+        if let Some(last) = parts.last_mut() {
+            *last += 1;
+        }
+        Self::from_release(&parts)
+    }
+
     pub const fn _max() -> Self {
         Self::new_opt(Some(MAX_VER), None, None)
     }
 
-    /// Prevents repetition.
+    /// Prevents repetition. The forms this is used for leave out the patch, and so `extra_num`.
     fn add_str_mod(&self, s: &mut String) {
-        if let Some(extra_num) = self.extra_num {
-            s.push_str(&format!(".{}", extra_num.to_string()));
-        }
         if let Some((modifier, num)) = self.modifier.clone() {
-            s.push_str(&format!("{}{}", modifier.to_string(), num.to_string()));
+            s.push_str(&format!("{}{}", modifier, num));
         }
     }
 
@@ -251,18 +343,6 @@ impl Version {
         self.add_str_mod(&mut result);
         result
     }
-    pub fn to_string_short(&self) -> String {
-        let mut result = format!("{}", self.major.unwrap_or(0));
-        self.add_str_mod(&mut result);
-        result
-    }
-
-    /// unlike Display, which overwrites to_string, don't add colors.
-    pub fn to_string_no_patch(&self) -> String {
-        let mut result = format!("{}.{}", self.major.unwrap_or(0), self.minor.unwrap_or(0));
-        self.add_str_mod(&mut result);
-        result
-    }
 
     pub fn to_string_color(&self) -> String {
         self.colorize().unwrap_or_else(|_| self.to_string())
@@ -319,26 +399,20 @@ impl Ord for Version {
         // None version modifiers should rank highest. Ie 17.0 > 17.0rc1
         let self_mod = self.modifier.clone().unwrap_or((VersionModifier::Null, 0));
         let other_mod = other.modifier.clone().unwrap_or((VersionModifier::Null, 0));
-        // Mirror the set field if we have a star present
-        let cmp_star = |obj: Option<u32>, oth: Option<u32>, star: bool| -> cmp::Ordering {
-            let none_val = if star {
-                if obj.is_none() && oth.is_none() {
-                    0
-                } else if let Some(x) = obj {
-                    x
-                } else {
-                    oth.unwrap_or(0)
-                }
-            } else {
-                0
-            };
-            obj.unwrap_or(none_val).cmp(&oth.unwrap_or(none_val))
+        // A starred version's missing parts match anything, eg 3.10 for `3.*`. The other
+        // version's missing parts are still 0, so `3.10.*` doesn't match `3`.
+        let cmp_star = |obj: Option<u32>, oth: Option<u32>| -> cmp::Ordering {
+            match (obj, oth) {
+                (None, _) if self.star => cmp::Ordering::Equal,
+                (_, None) if other.star => cmp::Ordering::Equal,
+                _ => obj.unwrap_or(0).cmp(&oth.unwrap_or(0)),
+            }
         };
         let star = self.star || other.star;
-        let maj = cmp_star(self.major, other.major, star);
-        let min = cmp_star(self.minor, other.minor, star);
-        let pat = cmp_star(self.patch, other.patch, star);
-        let ext = cmp_star(self.extra_num, other.extra_num, star);
+        let maj = cmp_star(self.major, other.major);
+        let min = cmp_star(self.minor, other.minor);
+        let pat = cmp_star(self.patch, other.patch);
+        let ext = cmp_star(self.extra_num, other.extra_num);
         if !matches!(maj, cmp::Ordering::Equal) {
             maj
         } else if !matches!(min, cmp::Ordering::Equal) {
@@ -400,7 +474,7 @@ impl fmt::Display for Version {
         };
         if self.major.is_some() {
             let mut star_handled = false;
-            let parts = vec![self.minor, self.patch, self.extra_num];
+            let parts = [self.minor, self.patch, self.extra_num];
             for part in parts.iter() {
                 if let Some(p) = part {
                     version.push('.');
@@ -431,6 +505,52 @@ impl fmt::Debug for Version {
     }
 }
 
+/// A version read from `pyproject.toml`, and the text it was read from. We write the text back
+/// as-is, so `3.10` doesn't become `3.10.0`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct VersionSpec {
+    pub raw: String,
+    pub parsed: Version,
+}
+
+impl VersionSpec {
+    /// Keep the text if it still means `version`. Otherwise, use `version`'s normal form.
+    pub fn with_version(&self, version: &Version) -> Self {
+        if self.parsed == *version {
+            self.clone()
+        } else {
+            version.clone().into()
+        }
+    }
+}
+
+impl FromStr for VersionSpec {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            raw: s.to_owned(),
+            parsed: Version::from_str(s)?,
+        })
+    }
+}
+
+/// For versions pyflow picks, rather than ones the user wrote.
+impl From<Version> for VersionSpec {
+    fn from(version: Version) -> Self {
+        Self {
+            raw: version.to_string(),
+            parsed: version,
+        }
+    }
+}
+
+impl fmt::Display for VersionSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
 /// Specify the type of version requirement
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub enum ReqType {
@@ -446,20 +566,20 @@ pub enum ReqType {
              // todo wildcard
 }
 
-impl ToString for ReqType {
+impl fmt::Display for ReqType {
     /// These show immediately before the version numbers
-    fn to_string(&self) -> String {
-        match self {
-            Self::Exact => "==".into(),
-            Self::Gte => ">=".into(),
-            Self::Lte => "<=".into(),
-            Self::Gt => ">".into(),
-            Self::Lt => "<".into(),
-            Self::Ne => "!=".into(),
-            Self::Caret => "^".into(),
-            Self::Tilde => "~".into(),
-            Self::TildeEq => "~=".into(),
-        }
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Exact => "==",
+            Self::Gte => ">=",
+            Self::Lte => "<=",
+            Self::Gt => ">",
+            Self::Lt => "<",
+            Self::Ne => "!=",
+            Self::Caret => "^",
+            Self::Tilde => "~",
+            Self::TildeEq => "~=",
+        })
     }
 }
 
@@ -539,6 +659,10 @@ impl Constraint {
     /// Important: The result is intended to be used in an "any" way. Ie "cp35.36" should match
     /// either Python 3.5 or 3.6.
     pub fn from_wh_py_vers(s: &str) -> Result<Vec<Self>, DependencyError> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(vec![Self::new(ReqType::Gte, Version::new(2, 0, 0))]);
+        }
         all_consuming(parse_wh_py_vers)(s)
             .map_err(|_| DependencyError::new(&format!("Problem parsing wh_py_vers: {}", s)))
             .map(|(_, vs)| vs)
@@ -559,7 +683,7 @@ impl Constraint {
                 _ => (),
             }
         }
-        format!("{}{}", type_str, self.version.to_string())
+        format!("{}{}", type_str, self.version)
     }
 
     /// Find the lowest and highest compatible versions. Return a vec, since the != requirement type
@@ -567,79 +691,26 @@ impl Constraint {
     pub fn compatible_range(&self) -> Vec<(Version, Version)> {
         let highest = Version::_max();
         let lowest = Version::new(0, 0, 0);
-        let max;
-
-        let safely_subtract = |major: Option<u32>, minor: Option<u32>, patch: Option<u32>| {
-            let mut major = major.unwrap_or(0);
-            let mut minor = minor.unwrap_or(0);
-            let mut patch = patch.unwrap_or(0);
-            // Don't try to make a negative version component.
-            // ie 0.0.0. Return max of 0.0.0
-            if major == 0 && minor == 0 && patch == 0 {
-            }
-            // ie 3.0.0. Return max of 2.999999.999999
-            else if minor == 0 && patch == 0 {
-                major -= 1;
-                minor = MAX_VER;
-                patch = MAX_VER;
-            // ie 2.9.0. Return max of 2.8.999999
-            } else if patch == 0 {
-                minor -= 1;
-                patch = MAX_VER
-            } else {
-                patch -= 1;
-            }
-            (major, minor, patch)
-        };
 
         // Note that other than for not-equals, the the resulting Vec has len 1.
         match self.type_ {
             ReqType::Exact => vec![(self.version.new_unstar(), self.get_max_version())],
             ReqType::Gte => vec![(self.version.new_unstar(), highest)],
             ReqType::Lte => vec![(lowest, self.version.new_unstar())],
-            ReqType::Gt => vec![(
-                Version::new(
-                    self.version.major.unwrap_or(0),
-                    self.version.minor.unwrap_or(0),
-                    self.version.patch.unwrap_or(0) + 1,
-                ),
-                highest,
-            )],
-            ReqType::Lt => {
-                let (major, minor, patch) =
-                    safely_subtract(self.version.major, self.version.minor, self.version.patch);
-                vec![(lowest, Version::new(major, minor, patch))]
-            }
-            ReqType::Ne => {
-                let (major, minor, patch) =
-                    safely_subtract(self.version.major, self.version.minor, self.version.patch);
-                vec![
-                    (lowest, Version::new(major, minor, patch)),
-                    (
-                        Version::new(
-                            self.version.major.unwrap_or(0),
-                            self.version.minor.unwrap_or(0),
-                            self.version.patch.unwrap_or(0) + 1,
-                        ),
-                        highest,
-                    ),
-                ]
-            }
-            ReqType::Caret => {
-                max = self.get_max_version();
-                // We need to use Lt logic for ^ and ~.
-                let (major, minor, patch) = safely_subtract(max.major, max.minor, max.patch);
-                vec![(self.version.clone(), Version::new(major, minor, patch))]
-            }
-            ReqType::Tilde => {
-                max = self.get_max_version();
-                let (major, minor, patch) = safely_subtract(max.major, max.minor, max.patch);
-                vec![(self.version.clone(), Version::new(major, minor, patch))]
-            }
-            ReqType::TildeEq => {
-                max = self.get_max_version();
-                let (major, minor, patch) = safely_subtract(max.major, max.minor, max.patch);
-                vec![(self.version.clone(), Version::new(major, minor, patch))]
+            ReqType::Gt => vec![(self.version.next_above(), highest)],
+            ReqType::Lt => vec![(lowest, self.version.next_below())],
+            ReqType::Ne => vec![
+                (lowest, self.version.next_below()),
+                (self.version.next_above(), highest),
+            ],
+            // We need to use Lt logic for ^ and ~.
+            ReqType::Caret | ReqType::Tilde | ReqType::TildeEq => {
+                let mut max = self.get_max_version();
+                // Keep the bound as precise as the constraint, eg 1.2.3.MAX for `~=1.2.3.4`.
+                if self.version.extra_num.is_some() {
+                    max.extra_num.get_or_insert(0);
+                }
+                vec![(self.version.clone(), max.next_below())]
             }
         }
     }
@@ -660,22 +731,22 @@ impl Constraint {
             ReqType::Gte => self.version <= *version,
             ReqType::Lte => self.version >= *version,
             ReqType::Gt => self.version < *version,
-            ReqType::Lt => self.version > *version,
+            ReqType::Lt => self.version > *version && !version.prerelease_of(&self.version),
             ReqType::Ne => self.version != *version,
             ReqType::Caret => {
                 max = self.get_max_version();
-                min <= *version && *version < max
+                min <= *version && *version < max && !version.prerelease_of(&max)
             }
             // For tilde, if minor's specified, can only increment patch.
             // If not, can increment minor or patch.
             ReqType::Tilde => {
                 max = self.get_max_version();
-                min <= *version && *version < max
+                min <= *version && *version < max && !version.prerelease_of(&max)
             }
 
             ReqType::TildeEq => {
                 max = self.get_max_version();
-                min <= *version && *version < max
+                min <= *version && *version < max && !version.prerelease_of(&max)
             }
         }
     }
@@ -708,6 +779,13 @@ impl Constraint {
                     Version::new(self.version.major.unwrap_or(0) + 1, 0, 0)
                 } else if self.version.minor > Some(0) {
                     Version::new(0, self.version.minor.unwrap() + 1, 0)
+                } else if let (Some(0) | None, Some(x)) =
+                    (self.version.patch, self.version.extra_num)
+                {
+                    Version {
+                        extra_num: Some(x + 1),
+                        ..Version::new(0, 0, 0)
+                    }
                 } else {
                     Version::new(0, 0, self.version.patch.unwrap_or(0) + 1)
                 }
@@ -734,7 +812,13 @@ impl Constraint {
 
              */
             ReqType::TildeEq => {
-                if self.version.patch.is_some() {
+                if self.version.extra_num.is_some() {
+                    Version::new(
+                        self.version.major.unwrap_or(0),
+                        self.version.minor.unwrap_or(0),
+                        self.version.patch.unwrap_or(0) + 1,
+                    )
+                } else if self.version.patch.is_some() {
                     Version::new(
                         self.version.major.unwrap_or(0),
                         self.version.minor.unwrap_or(0) + 1,
@@ -755,7 +839,7 @@ impl Constraint {
 
 impl fmt::Display for Constraint {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}{}", self.type_.to_string(), self.version)
+        write!(f, "{}{}", self.type_, self.version)
     }
 }
 
@@ -791,7 +875,7 @@ fn intersection_many2(reqs: &[(Version, Version)]) -> Vec<(Version, Version)> {
     // todo: Broken for notequals, which involves joining two ranges with OR logic.
     let init = vec![(Version::new(0, 0, 0), Version::new(MAX_VER, 0, 0))];
     reqs.iter().fold(init, |acc, constraint_set| {
-        intersection(&[constraint_set.clone()], &acc)
+        intersection(std::slice::from_ref(constraint_set), &acc)
     })
 }
 
@@ -817,19 +901,154 @@ pub fn intersection(
     result
 }
 
+/// An environment marker, eg `python_version < "3.8" and sys_platform == "win32"`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub enum Marker {
+    Extra(String),
+    SysPlatform(ReqType, util::Os),
+    PythonVersion(Constraint),
+    /// Other variables, eg `platform_python_implementation != 'PyPy'`: The variable, operator,
+    /// and value.
+    Other(String, String, String),
+    And(Vec<Marker>),
+    Or(Vec<Marker>),
+}
+
+impl Marker {
+    /// Determine if the marker applies to an OS and Python version. Extras are matched
+    /// separately, against those requested by the parent, so they evaluate as true here.
+    pub fn evaluate(&self, os: util::Os, py_vers: &Version) -> bool {
+        match self {
+            Self::Extra(_) => true,
+            Self::SysPlatform(type_, os_) => {
+                // A specified win32 req applies to 64-bit windows too.
+                let matches =
+                    *os_ == os || (*os_ == util::Os::Windows32 && os == util::Os::Windows);
+                match type_ {
+                    ReqType::Ne => !matches,
+                    _ => matches,
+                }
+            }
+            Self::PythonVersion(constr) => constr.is_compatible(py_vers),
+            Self::Other(var, op, value) => {
+                // We only install CPython. Include the dependency if we don't know the value.
+                let actual = match var.as_str() {
+                    "platform_python_implementation" => "CPython",
+                    "implementation_name" => "cpython",
+                    "os_name" if matches!(os, util::Os::Windows | util::Os::Windows32) => "nt",
+                    "os_name" => "posix",
+                    _ => return true,
+                };
+                match op.as_str() {
+                    "==" => actual == value,
+                    "!=" => actual != value,
+                    "in" => value.contains(actual),
+                    "not in" => !value.contains(actual),
+                    _ => true,
+                }
+            }
+            Self::And(markers) => markers.iter().all(|m| m.evaluate(os, py_vers)),
+            Self::Or(markers) => markers.iter().any(|m| m.evaluate(os, py_vers)),
+        }
+    }
+
+    /// Conditions that must always hold: The marker itself, or the parts of a top-level `and`.
+    fn conjuncts(&self) -> Vec<&Self> {
+        match self {
+            Self::And(markers) => markers.iter().flat_map(Self::conjuncts).collect(),
+            m => vec![m],
+        }
+    }
+
+    pub fn extra(&self) -> Option<String> {
+        self.conjuncts().into_iter().find_map(|m| match m {
+            Self::Extra(e) => Some(e.clone()),
+            _ => None,
+        })
+    }
+
+    /// Python version constraints that always apply, eg not ones joined by `or`.
+    pub fn python_versions(&self) -> Vec<Constraint> {
+        self.conjuncts()
+            .into_iter()
+            .filter_map(|m| match m {
+                Self::PythonVersion(c) => Some(c.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl FromStr for Marker {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        all_consuming(parse_marker)(s)
+            .map_err(|_| DependencyError::new(&format!("Problem parsing marker: {}", s)))
+            .map(|(_, m)| m)
+    }
+}
+
+impl fmt::Display for Marker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |markers: &[Self], sep: &str| {
+            markers
+                .iter()
+                .map(|m| match m {
+                    // `and` binds tighter than `or`.
+                    Self::Or(_) if sep == " and " => format!("({})", m),
+                    _ => m.to_string(),
+                })
+                .collect::<Vec<String>>()
+                .join(sep)
+        };
+        match self {
+            Self::Extra(e) => write!(f, "extra == '{}'", e),
+            Self::SysPlatform(type_, os) => {
+                let platform = match os {
+                    util::Os::Linux | util::Os::Linux32 => "linux",
+                    util::Os::Windows | util::Os::Windows32 => "win32",
+                    util::Os::Mac => "darwin",
+                    util::Os::Any => "any",
+                };
+                write!(f, "sys_platform {} '{}'", type_, platform)
+            }
+            Self::PythonVersion(c) => {
+                write!(f, "python_version {} '{}'", c.type_, c.version)
+            }
+            Self::Other(var, op, value) => write!(f, "{} {} '{}'", var, op, value),
+            Self::And(markers) => write!(f, "{}", join(markers, " and ")),
+            Self::Or(markers) => write!(f, "{}", join(markers, " or ")),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Extras {
     pub extra: Option<String>,
-    pub sys_platform: Option<(ReqType, util::Os)>,
-    pub python_version: Option<Constraint>,
+    pub python_version: Option<Vec<Constraint>>,
+    pub marker: Option<Marker>,
 }
 
 impl Extras {
-    pub const fn new_py(python_version: Constraint) -> Self {
+    pub fn new_py(python_version: Constraint) -> Self {
         Self {
             extra: None,
-            sys_platform: None,
-            python_version: Some(python_version),
+            python_version: Some(vec![python_version]),
+            marker: None,
+        }
+    }
+
+    pub fn from_marker(marker: Marker) -> Self {
+        let python_version = marker.python_versions();
+        Self {
+            extra: marker.extra(),
+            python_version: if python_version.is_empty() {
+                None
+            } else {
+                Some(python_version)
+            },
+            marker: Some(marker),
         }
     }
 }
@@ -839,11 +1058,25 @@ pub struct Req {
     pub name: String,
     pub constraints: Vec<Constraint>,
     pub extra: Option<String>,
-    pub sys_platform: Option<(ReqType, util::Os)>,
     pub python_version: Option<Vec<Constraint>>,
+    pub marker: Option<Marker>,
     pub install_with_extras: Option<Vec<String>>,
     pub path: Option<String>,
     pub git: Option<String>, // String is the git repo. // todo: Branch
+    /// A direct reference that isn't a git repo, eg `mylib @ https://example.com/mylib.tar.gz`.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Set with `allow-prereleases = true` in `pyproject.toml`.
+    #[serde(default)]
+    pub allow_prereleases: bool,
+    /// Set with `editable = true` on a path dependency: installed in place, with its console
+    /// scripts, so changes to its source apply without reinstalling.
+    #[serde(default)]
+    pub editable: bool,
+    /// Set with `wheel-tag`, eg `cp311-cp311-manylinux_2_28_x86_64`: only its wheel with this
+    /// tag is installed, instead of the one we'd pick.
+    #[serde(default)]
+    pub wheel_tag: Option<String>,
 }
 
 impl Req {
@@ -852,24 +1085,40 @@ impl Req {
             name,
             constraints,
             extra: None,
-            sys_platform: None,
             python_version: None,
+            marker: None,
             install_with_extras: None,
             path: None,
             git: None,
+            url: None,
+            allow_prereleases: false,
+            editable: false,
+            wheel_tag: None,
         }
     }
 
+    /// PEP 440: Pre-releases are only candidates if allowed in `pyproject.toml`, or if a
+    /// constraint names one, eg `==2.0.0b3`.
+    pub fn allows_prereleases(&self) -> bool {
+        self.allow_prereleases
+            || ALL_PRERELEASES.load(Ordering::SeqCst)
+            || self.constraints.iter().any(|c| c.version.is_prerelease())
+    }
+
     pub fn new_with_extras(name: String, constraints: Vec<Constraint>, extras: Extras) -> Self {
         Self {
             name,
             constraints,
             extra: extras.extra,
-            sys_platform: extras.sys_platform,
-            python_version: extras.python_version.map(|x| vec![x]),
+            python_version: extras.python_version,
+            marker: extras.marker,
             install_with_extras: None,
             path: None,
             git: None,
+            url: None,
+            allow_prereleases: false,
+            editable: false,
+            wheel_tag: None,
         }
     }
 
@@ -883,6 +1132,14 @@ impl Req {
         .map(|x| x.1)
     }
 
+    /// From a dependency's string form in `pyproject.toml`, eg `">=2.28 ; python_version >= '3.8'"`
+    /// or `"saturn @ git+https://github.com/org/saturn"`.
+    pub fn from_cfg_value(name: &str, value: &str) -> Result<Self, DependencyError> {
+        all_consuming(parse_cfg_value)(value)
+            .map_err(|_| DependencyError::new(&format!("Problem parsing dependency: {}", value)))
+            .map(|(_, (constraints, git, extras))| make_cfg_req(name, constraints, git, extras))
+    }
+
     /// We use this for parsing requirements.txt.
     pub fn from_pip_str(s: &str) -> Option<Self> {
         // todo multiple ie single quotes support?
@@ -901,28 +1158,31 @@ impl Req {
         let py_ver = Constraint::from_wh_py_vers(&release.python_version);
         let requires = if let Some(x) = release.requires_python {
             if x.as_str() > "" {
-                if let Ok(c) = Constraint::from_str_multiple(&x) {
-                    Some(c)
-                } else {
-                    None
-                }
+                Constraint::from_str_multiple(&x).ok()
             } else {
                 None
             }
         } else {
             None
         };
-        let py_req = requires.unwrap_or_else(|| py_ver.unwrap());
+        // If we can't parse either, assume any version, and leave it to `find_best_release`.
+        let py_req = requires
+            .or_else(|| py_ver.ok())
+            .unwrap_or_else(|| vec![Constraint::new(ReqType::Gte, Version::new(2, 0, 0))]);
 
         Self {
             name,
             constraints: vec![constraint],
             extra: None,
-            sys_platform: None,
             python_version: Some(py_req),
+            marker: None,
             install_with_extras: None,
             path: None,
             git: None,
+            url: None,
+            allow_prereleases: false,
+            editable: false,
+            wheel_tag: None,
         }
     }
 
@@ -932,7 +1192,7 @@ impl Req {
             name: self.name.clone(),
             constraints: self.constraints.clone(),
             extra: self.extra.clone(),
-            sys_platform: self.sys_platform,
+            marker: self.marker.clone(),
             python_version: if let Some(ref pv) = self.python_version {
                 Some(pv.clone())
             } else {
@@ -941,47 +1201,72 @@ impl Req {
             install_with_extras: self.install_with_extras.clone(),
             path: self.path.clone(),
             git: self.path.clone(),
+            url: self.url.clone(),
+            allow_prereleases: self.allow_prereleases,
+            editable: self.editable,
+            wheel_tag: self.wheel_tag.clone(),
         }
     }
 
     /// eg `saturn = "^0.3.1"` or `matplotlib = "3.1.1"`
     pub fn to_cfg_string(&self) -> String {
+        if let Some(git) = &self.git {
+            return format!(
+                r#"{} = "{} @ git+{}{}""#,
+                self.name,
+                self.name,
+                git,
+                self.to_marker_string()
+            );
+        }
         match self.constraints.len() {
             0 => {
-                let (name, latest_version) = if let Ok((fmtd_name, version, _)) =
-                    res::get_version_info(
-                        &self.name,
-                        Some(Req::new_with_extras(
-                            self.name.clone(),
-                            vec![Constraint::new_any()],
-                            Extras::new_py(Constraint::new(
-                                ReqType::Exact,
-                                self.py_ver_or_default(),
-                            )),
-                        )),
-                    ) {
-                    (fmtd_name, version)
-                } else {
-                    util::abort(&format!("Unable to find version info for {:?}", &self.name));
-                };
+                let (name, latest_version, _) = res::get_version_info(
+                    &self.name,
+                    Some(Req::new_with_extras(
+                        self.name.clone(),
+                        vec![Constraint::new_any()],
+                        Extras::new_py(Constraint::new(ReqType::Exact, self.py_ver_or_default())),
+                    )),
+                )
+                .unwrap_or_else(|e| util::abort(&e.to_string()));
                 format!(
-                    r#"{} = "{}""#,
+                    r#"{} = "{}{}""#,
                     name,
-                    Constraint::new(ReqType::Caret, latest_version).to_string2(true, false)
+                    Constraint::new(ReqType::Caret, latest_version).to_string2(true, false),
+                    self.to_marker_string()
                 )
             }
             _ => format!(
-                r#"{} = "{}""#,
+                r#"{} = "{}{}""#,
                 self.name,
                 self.constraints
                     .iter()
                     .map(|r| r.to_string2(true, false))
                     .collect::<Vec<String>>()
-                    .join(", ")
+                    .join(", "),
+                self.to_marker_string()
             ),
         }
     }
 
+    /// Format environment markers to follow a dependency's string form, eg
+    /// ` ; python_version >= '3.8'`. Empty if there are none.
+    fn to_marker_string(&self) -> String {
+        let marker = match (&self.marker, &self.python_version) {
+            (Some(m), _) => m.clone(),
+            // eg from `{ python = ">=3.8" }` in `pyproject.toml`.
+            (None, Some(constrs)) if !constrs.is_empty() => Marker::And(
+                constrs
+                    .iter()
+                    .map(|c| Marker::PythonVersion(c.clone()))
+                    .collect(),
+            ),
+            _ => return String::new(),
+        };
+        format!(" ; {}", marker)
+    }
+
     pub fn py_ver_or_default(&self) -> Version {
         let default = vec![Constraint::from_str("==*").ok().unwrap()];
         self.python_version
@@ -1031,7 +1316,7 @@ impl fmt::Display for Req {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Rename {
     No,
     // todo: May not need to store self id.
@@ -1041,11 +1326,13 @@ pub enum Rename {
 #[derive(Clone, Debug)]
 pub struct Package {
     pub id: u32,
+    #[allow(dead_code)]
     pub parent: u32,
     pub name: String,
     pub version: Version,
-    pub deps: Vec<(u32, String, Version)>,
+    pub deps: Vec<(u32, String, Version, DepExtras)>,
     pub rename: Rename,
+    pub marker: Option<Marker>, // Only install if this applies to the OS and Python version
 }
 
 /// Similar to that used by Cargo.lock. Represents an exact package to download. // todo(Although
@@ -1061,14 +1348,48 @@ pub struct LockPackage {
     pub source: Option<String>,
     pub dependencies: Option<Vec<String>>,
     pub rename: Option<String>,
+    // We store the marker so locks generated on one OS or Python version install correctly
+    // on others.
+    pub marker: Option<String>,
+    // The modules the package installs, eg `dateutil` for `python-dateutil`, from its metadata.
+    // Recorded after installing.
+    pub modules: Option<Vec<String>>,
+    // Release files available when locked. We install from these, so each machine installs
+    // an artifact that was reviewed with the lock.
+    pub files: Option<Vec<LockFile>>,
+    // Extras its dependencies were resolved with. If it's required with others, it's resolved
+    // again, to find what they add.
+    pub extras: Option<Vec<String>>,
+    // The file from `files` pinned with `wheel-tag`, installed instead of the one we'd pick.
+    #[serde(default)]
+    pub wheel: Option<String>,
+}
+
+/// A release file for a locked package, eg a wheel for one platform, or a source dist.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LockFile {
+    pub filename: String,
+    pub url: String,
+    pub sha256: String,
+    pub packagetype: String,
+    pub python_version: String,
+    pub requires_python: Option<String>,
+    pub platform: String, // eg `manylinux1_x86_64`, `any`, or `source`
 }
 
 /// Modelled after [Cargo.lock](https://doc.rust-lang.org/cargo/guide/cargo-toml-vs-cargo-lock.html)
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Lock {
     pub package: Option<Vec<LockPackage>>,
-    //    pub metadata: Option<Vec<String>>, // ie checksums
-    pub metadata: HashMap<String, String>, // ie checksums
+    // What the lock was made from and for; see `util::lock_header`. Only strings, so older
+    // versions of pyflow can read new keys.
+    #[serde(serialize_with = "sorted")]
+    pub metadata: HashMap<String, String>,
+}
+
+/// Write a map in key order, so rewriting a lock doesn't reorder it.
+fn sorted<S: Serializer>(map: &HashMap<String, String>, serializer: S) -> Result<S::Ok, S::Error> {
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
 }
 
 #[cfg(test)]
@@ -1339,6 +1660,108 @@ pub mod tests {
         assert_eq!(constraint.is_compatible(&ver_match), is_compat);
     }
 
+    #[rstest(
+        constr,
+        version,
+        is_compat,
+        case::caret("^1.0", "2.0.0rc1", false),
+        case::lt("<2.0", "2.0rc1", false),
+        case::tilde_eq("~=1.4", "2.0.dev1", false),
+        case::lt_prerelease("<2.0rc2", "2.0rc1", true),
+        case::gte_post(">=1.0", "1.0.post1", true),
+        case::exact_beta("==2.0.0b3", "2.0.0b3", true)
+    )]
+    fn pep440_prerelease_bounds(constr: &str, version: &str, is_compat: bool) {
+        let constraint = Constraint::from_str(constr).unwrap();
+        let version = Version::from_str(version).unwrap();
+        assert_eq!(constraint.is_compatible(&version), is_compat);
+    }
+
+    /// Two- and three-part versions, stars on either side, and caret and tilde constraints with
+    /// missing parts.
+    #[rstest(
+        constr,
+        version,
+        is_compat,
+        case("^3.7", "3.7", true),
+        case("^3.7", "3.7.0", true),
+        case("^3.7", "3.10", true),
+        case("^3.7", "3.12", true),
+        case("^3.7", "3.12.1", true),
+        case("^3.7", "3", false),
+        case("^3.7", "3.6.9", false),
+        case("^3.7", "4", false),
+        case("^3.7", "4.0", false),
+        case("^3.7", "4.0.0", false),
+        case("^3.7.2", "3.7.1", false),
+        case("^3.7.2", "3.10", true),
+        case("^3", "3", true),
+        case("^3", "3.12", true),
+        case("^3", "2.7", false),
+        case("^3", "4.0", false),
+        case("^0.3", "0.3.5", true),
+        case("^0.3", "0.4", false),
+        case("~3.7", "3.7.9", true),
+        case("~3.7", "3.8", false),
+        case("~3.7", "3.10", false),
+        case("~3", "3.10", true),
+        case("~3", "4", false),
+        case("~=3.7", "3.10", true),
+        case("~=3.7", "3.6", false),
+        case("~=3.7", "4.0", false),
+        case("~=3.7.1", "3.7.9", true),
+        case("~=3.7.1", "3.8", false),
+        case("==3.7", "3.7.0", true),
+        case("==3.7", "3.7.1", false),
+        case("==3.*", "3", true),
+        case("==3.*", "3.10", true),
+        case("==3.*", "3.10.4", true),
+        case("==3.*", "2.7", false),
+        case("==3.*", "4.0", false),
+        case("==3.10.*", "3.10", true),
+        case("==3.10.*", "3.10.4", true),
+        case("==3.10.*", "3", false),
+        case("==3.10.*", "3.1", false),
+        case("==3.10.*", "3.11", false),
+        case("==3", "3.*", true),
+        case("==3.10", "3.*", true),
+        case("==3.10", "3.10.*", true),
+        case("==3", "3.10.*", false),
+        case("==3.1", "3.10.*", false),
+        case("==4", "3.*", false),
+        case("!=3.7.*", "3.7.2", false),
+        case("!=3.7.*", "3.8", true),
+        case(">=3.7", "3.10", true),
+        case(">=3.10", "3.9.18", false),
+        case(">3.7", "3.7", false),
+        case(">3.7", "3.7.1", true),
+        case("<3.10", "3.9", true),
+        case("<3.10", "3.10.0", false),
+        case("<=3.7", "3.7.0", true)
+    )]
+    fn compatibility_table(constr: &str, version: &str, is_compat: bool) {
+        let constraint = Constraint::from_str(constr).unwrap();
+        let parsed = Version::from_str(version).unwrap();
+        assert_eq!(
+            constraint.is_compatible(&parsed),
+            is_compat,
+            "{} with {}",
+            constr,
+            version
+        );
+    }
+
+    #[rstest(
+        vers,
+        case("0.100.0rc1"),
+        case("2.0.0b3"),
+        case("1.0.0.post2"),
+        case("1.0.dev1")
+    )]
+    fn version_modifier_round_trip(vers: &str) {
+        assert_eq!(Version::from_str(vers).unwrap().to_string(), vers);
+    }
+
     #[test]
     fn version_parse() {
         assert_eq!(Version::from_str("3.12.5").unwrap(), Version::new(3, 12, 5));
@@ -1525,11 +1948,15 @@ pub mod tests {
             name: "pyOpenSSL".into(),
             constraints: vec![Constraint::new(Gte, Version::new(0, 14, 0))],
             extra: Some("security".into()),
-            sys_platform: None,
             python_version: None,
+            marker: Some(Marker::Extra("security".into())),
             install_with_extras: None,
             path: None,
             git: None,
+            url: None,
+            allow_prereleases: false,
+            editable: false,
+            wheel_tag: None,
         };
 
         let actual2 = Req::from_str(
@@ -1542,11 +1969,18 @@ pub mod tests {
             name: "pathlib2".into(),
             constraints: vec![],
             extra: Some("test".into()),
-            sys_platform: None,
             python_version: Some(vec![Constraint::new(Exact, Version::new(2, 7, 0))]),
+            marker: Some(Marker::And(vec![
+                Marker::Extra("test".into()),
+                Marker::PythonVersion(Constraint::new(Exact, Version::new(2, 7, 0))),
+            ])),
             install_with_extras: None,
             path: None,
             git: None,
+            url: None,
+            allow_prereleases: false,
+            editable: false,
+            wheel_tag: None,
         };
 
         let actual3 = Req::from_str(
@@ -1559,11 +1993,18 @@ pub mod tests {
             name: "win-unicode-console".into(),
             constraints: vec![Constraint::new(Gte, Version::new(0, 5, 0))],
             extra: None,
-            sys_platform: Some((Exact, util::Os::Windows32)),
             python_version: Some(vec![Constraint::new(Lt, Version::new(3, 6, 0))]),
+            marker: Some(Marker::And(vec![
+                Marker::SysPlatform(Exact, util::Os::Windows32),
+                Marker::PythonVersion(Constraint::new(Lt, Version::new(3, 6, 0))),
+            ])),
             install_with_extras: None,
             path: None,
             git: None,
+            url: None,
+            allow_prereleases: false,
+            editable: false,
+            wheel_tag: None,
         };
 
         let actual4 = Req::from_str("envisage ; extra == 'app'", true).unwrap();
@@ -1573,11 +2014,15 @@ pub mod tests {
             name: "envisage".into(),
             constraints: vec![],
             extra: Some("app".into()),
-            sys_platform: None,
             python_version: None,
+            marker: Some(Marker::Extra("app".into())),
             install_with_extras: None,
             path: None,
             git: None,
+            url: None,
+            allow_prereleases: false,
+            editable: false,
+            wheel_tag: None,
         };
 
         assert_eq!(actual, expected);
@@ -1599,11 +2044,15 @@ pub mod tests {
                 Constraint::new(Lte, Version::new(0, 32, 2)),
             ],
             extra: None,
-            sys_platform: None,
             python_version: None,
+            marker: None,
             install_with_extras: None,
             path: None,
             git: None,
+            url: None,
+            allow_prereleases: false,
+            editable: false,
+            wheel_tag: None,
         };
 
         let expected2 = Req {
@@ -1613,11 +2062,15 @@ pub mod tests {
                 Constraint::new(Lte, Version::new(0, 12, 8)),
             ],
             extra: None,
-            sys_platform: None,
             python_version: None,
+            marker: None,
             install_with_extras: None,
             path: None,
             git: None,
+            url: None,
+            allow_prereleases: false,
+            editable: false,
+            wheel_tag: None,
         };
 
         assert_eq!(actual1, expected1);
@@ -1797,6 +2250,41 @@ pub mod tests {
         assert_eq!(req.to_cfg_string(), expected.to_string());
     }
 
+    #[rstest(
+        value,
+        case::plain(">=2.28"),
+        case::marker(">=2.28 ; python_version >= '3.8'"),
+        case::platform("^1.2.3 ; sys_platform != 'win32' and python_version < '3.10'"),
+        case::or("^1.2.3 ; python_version >= '3.8' and (sys_platform == 'darwin' or sys_platform == 'linux')"),
+        case::git("requests @ git+https://github.com/psf/requests"),
+        case::other(">=1.12 ; platform_python_implementation != 'PyPy'")
+    )]
+    fn req_cfg_string_round_trip(value: &str) {
+        let req = Req::from_cfg_value("requests", value).unwrap();
+        let line = req.to_cfg_string();
+        assert_eq!(line, format!(r#"requests = "{}""#, value));
+        assert_eq!(Req::from_str(&line, false).unwrap(), req);
+    }
+
+    #[rstest(marker, os, py_vers, expected,
+        case::win32_on_win64("sys_platform == 'win32'", util::Os::Windows, (3, 9), true),
+        case::ne("sys_platform != 'win32'", util::Os::Linux, (3, 9), true),
+        case::or("sys_platform == 'darwin' or sys_platform == 'linux'", util::Os::Windows, (3, 9), false),
+        case::and_or("python_version < '3.8' and (sys_platform == 'darwin' or sys_platform == 'linux')", util::Os::Mac, (3, 7), true),
+        case::and_fails("python_version < '3.8' and sys_platform == 'darwin'", util::Os::Mac, (3, 11), false),
+        case::extra("extra == 'socks'", util::Os::Linux, (3, 9), true),
+        case::not_pypy("platform_python_implementation != 'PyPy'", util::Os::Linux, (3, 9), true),
+        case::pypy("platform_python_implementation == 'PyPy'", util::Os::Linux, (3, 9), false),
+        case::os_name("os_name == 'nt'", util::Os::Windows, (3, 9), true),
+        case::os_name_in("os_name in 'posix java'", util::Os::Mac, (3, 9), true),
+        case::unknown("platform_release == '5.10.0 generic'", util::Os::Linux, (3, 9), true)
+    )]
+    fn marker_evaluate(marker: &str, os: util::Os, py_vers: (u32, u32), expected: bool) {
+        let marker = Marker::from_str(marker).unwrap();
+        let py_vers = Version::new(py_vers.0, py_vers.1, 0);
+        assert_eq!(marker.evaluate(os, &py_vers), expected);
+    }
+
     #[test]
     fn req_to_cfg_string_empty_constraints() {
         let ctx = res::get_version_info_context();
@@ -1899,6 +2387,72 @@ pub mod tests {
         assert_eq!(actual.compatible_range(), expected);
     }
 
+    fn four_part(major: u32, minor: u32, patch: u32, extra_num: u32) -> Version {
+        Version {
+            extra_num: Some(extra_num),
+            ..Version::new(major, minor, patch)
+        }
+    }
+
+    #[rstest(actual,
+             expected,
+             case::caret_calendar(Constraint::from_str("^2023.3").unwrap(),
+                                  vec![(Version::new_short(2023, 3),
+                                        Version::new(2023, MAX_VER, MAX_VER))]),
+             case::caret_four_part(Constraint::from_str("^1.2.3.4").unwrap(),
+                                   vec![(four_part(1, 2, 3, 4),
+                                         four_part(1, MAX_VER, MAX_VER, MAX_VER))]),
+             case::caret_zero_four_part(Constraint::from_str("^0.0.0.4").unwrap(),
+                                        vec![(four_part(0, 0, 0, 4), four_part(0, 0, 0, 4))]),
+             case::tilde_eq_four_part(Constraint::from_str("~=1.2.3.4").unwrap(),
+                                      vec![(four_part(1, 2, 3, 4),
+                                            four_part(1, 2, 3, MAX_VER))]),
+             case::gt_four_part(Constraint::from_str(">1.2.3.4").unwrap(),
+                                vec![(four_part(1, 2, 3, 5), Version::_max())]),
+             case::lt_four_part(Constraint::from_str("<1.2.3.0").unwrap(),
+                                vec![(Version::new(0, 0, 0), four_part(1, 2, 2, MAX_VER))]),
+             case::ne_four_part(Constraint::from_str("!=1.2.3.4").unwrap(),
+                                vec![(Version::new(0, 0, 0), four_part(1, 2, 3, 3)),
+                                     (four_part(1, 2, 3, 5), Version::_max())])
+    )]
+    fn compat_rng_four_part(actual: Constraint, expected: Vec<(Version, Version)>) {
+        assert_eq!(actual.compatible_range(), expected);
+    }
+
+    #[rstest(
+        constr,
+        version,
+        is_compat,
+        case::caret_calendar_post("^2023.3", "2023.3.post1", true),
+        case::caret_calendar_next("^2023.3", "2023.4", true),
+        case::caret_calendar_year("^2023.3", "2024.1", false),
+        case::caret_calendar_below("^2023.3", "2023.2", false),
+        case::tilde_eq_calendar("~=2023.3", "2023.4", true),
+        case::tilde_eq_four_part("~=1.2.3.4", "1.2.3.9", true),
+        case::tilde_eq_four_part_patch("~=1.2.3.4", "1.2.4", false),
+        case::tilde_eq_four_part_below("~=1.2.3.4", "1.2.3.3", false),
+        case::caret_zero_four_part("^0.0.0.4", "0.0.0.5", false),
+        case::lt_four_part("<1.2.3.4", "1.2.3.3", true)
+    )]
+    fn calendar_and_four_part_versions(constr: &str, version: &str, is_compat: bool) {
+        let constraint = Constraint::from_str(constr).unwrap();
+        let version = Version::from_str(version).unwrap();
+        assert_eq!(constraint.is_compatible(&version), is_compat);
+        // The range agrees.
+        let in_range = constraint
+            .compatible_range()
+            .iter()
+            .any(|(min, max)| min <= &version && &version <= max);
+        assert_eq!(in_range, is_compat);
+    }
+
+    #[test]
+    fn truncated_forms_drop_extra_num() {
+        let v = four_part(1, 2, 3, 4);
+        assert_eq!(v.to_string(), "1.2.3.4");
+        assert_eq!(v.to_string_med(), "1.2");
+    }
+
     #[test]
     fn intersections_empty() {
         let reqs1 = vec![
@@ -2014,10 +2568,43 @@ pub mod tests {
              case::pp36("pp36", vec![Constraint::new(Exact, Version::new(3, 6, 0))]),
              case::any("any", vec![Constraint::new(Gte, Version::new(2, 0, 0))]),
              case::semver("2.7", vec![Constraint::new(Caret, Version::new(2, 7, 0))]),
-             case::pp257("pp257", vec![Constraint::new(Exact, Version::new(2, 5, 7))])
+             case::pp257("pp257", vec![Constraint::new(Exact, Version::new(2, 5, 7))]),
+             // Values seen on the warehouse, eg for numpy, six, and pywin32.
+             case::cp311("cp311", vec![Constraint::new(Exact, Version::new(3, 11, 0))]),
+             case::py310("py310", vec![Constraint::new(Exact, Version::new(3, 10, 0))]),
+             case::py30("py30", vec![Constraint::new(Exact, Version::new(3, 0, 0))]),
+             case::pp310("pp310", vec![Constraint::new(Exact, Version::new(3, 10, 0))]),
+             case::cp_two_digit_chain("cp311.cp312",
+                            vec![
+                                Constraint::new(Exact, Version::new(3, 11, 0)),
+                                Constraint::new(Exact, Version::new(3, 12, 0)),
+                            ]),
+             case::mixed_chain("py2.py3.cp35",
+                            vec![
+                                Constraint::new(Lte, Version::new(2, 10, 0)),
+                                Constraint::new(Gte, Version::new(3, 0, 0)),
+                                Constraint::new(Exact, Version::new(3, 5, 0)),
+                            ]),
+             case::semver_two_digit("3.10", vec![Constraint::new(Caret, Version::new(3, 10, 0))]),
+             case::empty("", vec![Constraint::new(Gte, Version::new(2, 0, 0))]),
+             case::source_wheel("source", vec![Constraint::new(Gte, Version::new(2, 0, 0))]),
+             case::whitespace(" py2 . py3 ",
+                            vec![
+                                Constraint::new(Lte, Version::new(2, 10, 0)),
+                                Constraint::new(Gte, Version::new(3, 0, 0)),
+                            ])
     )]
     fn python_version_from_warehouse(input: &str, expected: Vec<Constraint>) {
         let a1 = Constraint::from_wh_py_vers(input).unwrap();
         assert_eq!(a1, expected)
     }
+
+    #[test]
+    fn version_spec_text_kept_while_it_matches() {
+        let spec = VersionSpec::from_str("3.10").unwrap();
+        let same = Version::from_str("3.10.0").unwrap();
+        assert_eq!(spec.with_version(&same).raw, "3.10");
+        let other = Version::new_short(3, 11);
+        assert_eq!(spec.with_version(&other).raw, "3.11");
+    }
 }
@@ -1,8 +1,10 @@
 use std::{
     collections::HashMap,
-    fs,
+    env, fs,
     io::{BufRead, BufReader},
     path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use regex::Regex;
@@ -11,9 +13,50 @@ use termcolor::Color;
 
 use crate::{
     dep_types::{Req, Version},
-    util, Config,
+    install, pyproject, util, Config,
 };
 
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+/// For `--frozen-pyproject`: fail instead of changing `pyproject.toml`.
+pub fn freeze() {
+    FROZEN.store(true, Ordering::SeqCst);
+}
+
+/// Honor `frozen = true` in `[tool.pyflow]`. It only applies with the `CI` environment variable
+/// set, so the file can still be changed locally.
+pub fn freeze_in_ci(frozen: bool) {
+    let in_ci = env::var("CI").is_ok_and(|v| !v.is_empty() && v != "false");
+    if frozen && in_ci {
+        freeze();
+    }
+}
+
+fn frozen() -> bool {
+    FROZEN.load(Ordering::SeqCst)
+}
+
+/// Write `pyproject.toml`, unless it's frozen; then abort, describing `change`, eg `add requests
+/// = "^2.31" to [tool.pyflow.dependencies]`. If the contents are unchanged, nothing's written.
+pub fn write_cfg(cfg_path: &Path, data: &str, change: &str) {
+    write_cfg_with(cfg_path, data, change, frozen()).unwrap_or_else(|e| util::abort(&e))
+}
+
+fn write_cfg_with(cfg_path: &Path, data: &str, change: &str, frozen: bool) -> Result<(), String> {
+    if fs::read_to_string(cfg_path).is_ok_and(|d| d == data) {
+        return Ok(());
+    }
+    if frozen {
+        return Err(format!(
+            "{} is frozen, so it wasn't changed. pyflow would {}",
+            cfg_path.display(),
+            change
+        ));
+    }
+    fs::write(cfg_path, data)
+        .map_err(|_| format!("Problem writing {} to {}", cfg_path.display(), change))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Pipfile {
     // Pipfile doesn't use a prefix; assume `[packages]` and [`dev-packages`] sections
@@ -23,13 +66,61 @@ pub struct Pipfile {
     pub dev_packages: Option<HashMap<String, DepComponentWrapper>>,
 }
 
+/// `poetry.lock`. Older versions of Poetry list each package's files under `[metadata.files]`.
+#[derive(Debug, Deserialize)]
+pub struct PoetryLock {
+    pub package: Vec<PoetryLockPackage>,
+    pub metadata: Option<PoetryLockMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PoetryLockPackage {
+    pub name: String,
+    pub version: String,
+    #[serde(rename = "python-versions")]
+    pub python_versions: Option<String>,
+    /// Set by older versions of Poetry. Newer ones put markers on dependencies instead.
+    pub markers: Option<String>,
+    /// Constraints, eg `">=2.0"`, or tables with `version` and `markers`.
+    pub dependencies: Option<HashMap<String, toml::Value>>,
+    pub files: Option<Vec<PoetryLockFile>>,
+    pub source: Option<PoetryLockSource>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PoetryLockFile {
+    pub file: String,
+    pub hash: String, // eg `sha256:...`
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PoetryLockSource {
+    #[serde(rename = "type")]
+    pub type_: String, // eg `directory`, `git`, `url`, or `legacy` for other indexes
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PoetryLockMetadata {
+    pub files: Option<HashMap<String, Vec<PoetryLockFile>>>,
+}
+
 /// This nested structure is required based on how the `toml` crate handles dots.
 #[derive(Debug, Deserialize)]
 pub struct Pyproject {
+    #[serde(default)]
     pub tool: Tool,
+    pub project: Option<Project>,
 }
 
+/// The PEP 621 `[project]` table. We only read optional dependencies from it.
 #[derive(Debug, Deserialize)]
+pub struct Project {
+    #[serde(rename = "optional-dependencies")]
+    pub optional_dependencies: Option<HashMap<String, Vec<String>>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct Tool {
     pub pyflow: Option<Pyflow>,
     pub poetry: Option<Poetry>,
@@ -38,16 +129,33 @@ pub struct Tool {
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 /// Allows use of both Strings, ie "ipython = "^7.7.0", and maps: "ipython = {version = "^7.7.0", extras=["qtconsole"]}"
+/// A list of maps gives a package different entries per platform, eg
+/// `torch = [{ version = "2.1.0", platform = "linux" }, { version = "2.0.1", platform = "macos" }]`
 pub enum DepComponentWrapper {
     A(String),
-    B(DepComponent),
+    B(Box<DepComponent>),
+    C(Vec<DepComponent>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+/// Allows use of both Strings, ie `test = "pytest -x"`, and maps: `ci = {cmd = "pytest", depends_on = ["lint"]}`
+pub enum ScriptComponentWrapper {
+    A(String),
+    B(ScriptComponent),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScriptComponent {
+    pub cmd: Option<String>,
+    pub depends_on: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 pub enum DepComponentWrapperPoetry {
     A(String),
-    B(DepComponentPoetry),
+    B(Box<DepComponentPoetry>),
 }
 
 #[derive(Debug, Deserialize)]
@@ -57,27 +165,84 @@ pub struct DepComponent {
     pub extras: Option<Vec<String>>,
     pub path: Option<String>,
     pub git: Option<String>,
+    /// Where the package is in a git repo, if not at its root.
+    pub subdirectory: Option<String>,
+    /// A git branch, tag, or commit to check out. Only one is used, in this order.
     pub branch: Option<String>,
+    pub tag: Option<String>,
+    pub rev: Option<String>,
+    #[allow(dead_code)]
     pub service: Option<String>,
     pub python: Option<String>,
+    /// Only install on this OS: `linux`, `windows`, or `macos`.
+    pub platform: Option<String>,
+    /// Environment markers, eg `platform_machine == 'aarch64'`.
+    pub markers: Option<String>,
+    #[serde(rename = "allow-prereleases")]
+    pub allow_prereleases: Option<bool>,
+    /// With `path`: install in place, eg the project itself with `pyflow install -e .`.
+    pub editable: Option<bool>,
+    /// Only install its wheel with this tag, eg `cp311-cp311-manylinux_2_28_x86_64`.
+    #[serde(rename = "wheel-tag")]
+    pub wheel_tag: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct DepComponentPoetry {
     #[serde(rename = "version")]
-    pub constrs: String,
+    pub constrs: Option<String>,
     pub python: Option<String>,
     pub extras: Option<Vec<String>>,
     pub optional: Option<bool>,
+    #[serde(rename = "allow-prereleases")]
+    pub allow_prereleases: Option<bool>,
+    pub path: Option<String>,
+    pub git: Option<String>,
+    pub subdirectory: Option<String>,
+    pub branch: Option<String>,
+    pub tag: Option<String>,
+    pub rev: Option<String>,
+    pub url: Option<String>,
     // todo: more fields
     //    pub repository: Option<String>,
-    //    pub branch: Option<String>,
     //    pub service: Option<String>,
 }
 
+/// A Poetry dependency group, eg `[tool.poetry.group.test.dependencies]`.
+#[derive(Debug, Deserialize)]
+pub struct PoetryGroup {
+    pub dependencies: Option<HashMap<String, DepComponentWrapperPoetry>>,
+}
+
+/// A hook's commands in `[tool.pyflow.hooks]`: one, or several to run in order.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum HookCmdsWrapper {
+    A(String),
+    B(Vec<String>),
+}
+
+impl HookCmdsWrapper {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::A(cmd) => vec![cmd],
+            Self::B(cmds) => cmds,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Hooks {
+    pub pre_lock: Option<HookCmdsWrapper>,
+    pub post_lock: Option<HookCmdsWrapper>,
+    pub pre_install: Option<HookCmdsWrapper>,
+    pub post_install: Option<HookCmdsWrapper>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Pyflow {
     pub py_version: Option<String>,
+    pub py_interpreter: Option<String>,
     pub name: Option<String>,
     pub version: Option<String>,
     pub authors: Option<Vec<String>>,
@@ -87,20 +252,34 @@ pub struct Pyflow {
     pub keywords: Option<Vec<String>>,
     pub homepage: Option<String>,
     pub repository: Option<String>,
+    #[allow(dead_code)]
     pub repo_url: Option<String>,
     pub package_url: Option<String>,
     pub readme: Option<String>,
     pub build: Option<String>,
     //    pub entry_points: Option<HashMap<String, Vec<String>>>,
-    pub scripts: Option<HashMap<String, String>>,
+    pub scripts: Option<HashMap<String, ScriptComponentWrapper>>,
     pub python_requires: Option<String>,
     pub dependencies: Option<HashMap<String, DepComponentWrapper>>,
     #[serde(rename = "dev-dependencies")]
     pub dev_dependencies: Option<HashMap<String, DepComponentWrapper>>,
-    pub extras: Option<HashMap<String, String>>,
+    pub extras: Option<HashMap<String, Vec<String>>>,
+    pub build_tools: Option<HashMap<String, String>>,
+    pub deps_service_url: Option<String>,
+    pub find_links: Option<Vec<String>>,
+    pub no_scripts: Option<bool>,
+    pub hooks: Option<Hooks>,
+    pub preserve: Option<Vec<String>>,
+    pub load_dotenv: Option<bool>,
+    pub check_shadowing: Option<bool>,
+    pub resolution: Option<String>,
+    pub build_timeout: Option<u64>,
+    pub frozen: Option<bool>,
+    pub settings: Option<pyproject::settings::Settings>,
 }
 
 #[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 pub struct Poetry {
     pub name: Option<String>,
     pub version: Option<String>,
@@ -117,10 +296,12 @@ pub struct Poetry {
     pub packages: Option<Vec<HashMap<String, String>>>,
     pub include: Option<Vec<String>>,
     pub exclude: Option<Vec<String>>,
-    pub extras: Option<HashMap<String, String>>,
+    pub extras: Option<HashMap<String, Vec<String>>>,
 
     pub dependencies: Option<HashMap<String, DepComponentWrapperPoetry>>,
+    #[serde(rename = "dev-dependencies")]
     pub dev_dependencies: Option<HashMap<String, DepComponentWrapperPoetry>>,
+    pub group: Option<HashMap<String, PoetryGroup>>,
     // todo: Include these
     //    pub source: Option<HashMap<String, String>>,
     pub scripts: Option<HashMap<String, String>>,
@@ -133,7 +314,7 @@ pub struct Poetry {
 /// * lines: A vector containing each line of the section
 /// * i_start: Zero-indexed indicating the line of the header.
 /// * i_end: Zero-indexed indicating the line number of the next section header,
-///     or the last line of the file.
+///   or the last line of the file.
 struct Section {
     lines: Vec<String>,
     i_start: usize,
@@ -185,6 +366,52 @@ fn collect_section(cfg_lines: &[String], title: &str) -> Option<Section> {
 ///
 /// If the section is detected, then the dependencies are appended to that section. Otherwise,
 /// a new section is appended to the end of the file.
+/// A req's line in `pyproject.toml`. Git reqs use the table form, with their ref and
+/// subdirectory as keys of their own, as do path reqs, and those with a `wheel-tag`.
+fn cfg_line(req: &Req) -> String {
+    if let Some(path) = &req.path {
+        let mut line = format!(
+            "{} = {{ path = {}",
+            req.name,
+            toml::Value::String(path.clone())
+        );
+        if req.editable {
+            line.push_str(", editable = true");
+        }
+        return line + " }";
+    }
+    if let Some(tag) = &req.wheel_tag {
+        let line = req.to_cfg_string();
+        let (name, version) = line.split_once(" = ").unwrap_or((&req.name, "\"*\""));
+        return format!(
+            "{} = {{ version = {}, wheel-tag = {} }}",
+            name,
+            version,
+            toml::Value::String(tag.clone())
+        );
+    }
+    let git = match &req.git {
+        Some(git) => git,
+        None => return req.to_cfg_string(),
+    };
+    let (url, subdirectory) = install::split_subdirectory(git);
+    let (url, git_ref) = install::split_git_ref(url);
+    let mut line = format!(r#"{} = {{ git = "{}""#, req.name, url);
+    if let Some(git_ref) = git_ref {
+        line.push_str(&format!(r#", rev = "{}""#, git_ref));
+    }
+    if let Some(sub) = subdirectory {
+        line.push_str(&format!(r#", subdirectory = "{}""#, sub));
+    }
+    line + " }"
+}
+
+/// The package a dependency line is for, eg `requests` for `requests = "^2.31"`.
+fn line_name(line: &str) -> Option<&str> {
+    let (key, _) = line.split_once('=')?;
+    Some(key.trim().trim_matches('"'))
+}
+
 fn extend_or_insert(mut cfg_lines: Vec<String>, section_header: &str, reqs: &[Req]) -> Vec<String> {
     let collected = collect_section(&cfg_lines, section_header);
 
@@ -195,13 +422,19 @@ fn extend_or_insert(mut cfg_lines: Vec<String>, section_header: &str, reqs: &[Re
             // and later we append a trailing empty line
             let mut all_deps: Vec<String> = section
                 .lines
-                .to_owned()
                 .into_iter()
                 .filter(|x| !x.is_empty())
                 .collect();
 
+            // A req that's already listed replaces its line.
             for req in reqs {
-                all_deps.push(req.to_cfg_string())
+                let listed = all_deps
+                    .iter()
+                    .position(|l| line_name(l).is_some_and(|n| util::compare_names(n, &req.name)));
+                match listed {
+                    Some(i) => all_deps[i] = cfg_line(req),
+                    None => all_deps.push(cfg_line(req)),
+                }
             }
             all_deps.push("".into());
 
@@ -214,7 +447,7 @@ fn extend_or_insert(mut cfg_lines: Vec<String>, section_header: &str, reqs: &[Re
             // A section is composed of its header, followed by all the requirements
             // and then an empty line
             let mut section = vec![section_header.to_string()];
-            section.extend(reqs.iter().map(|r| r.to_cfg_string()));
+            section.extend(reqs.iter().map(cfg_line));
             section.push("".into());
 
             // We want an empty line before adding the new section
@@ -234,6 +467,9 @@ fn extend_or_insert(mut cfg_lines: Vec<String>, section_header: &str, reqs: &[Re
 /// The added sections are appended to the end of the file. Split from `add_reqs_to_cfg`
 /// to accommodate testing.
 fn update_cfg(cfg_data: &str, added: &[Req], added_dev: &[Req]) -> String {
+    if added.is_empty() && added_dev.is_empty() {
+        return cfg_data.to_owned();
+    }
     let cfg_lines: Vec<String> = cfg_data.lines().map(str::to_string).collect();
 
     // First we update the dependencies section
@@ -261,12 +497,82 @@ fn update_cfg(cfg_data: &str, added: &[Req], added_dev: &[Req]) -> String {
 /// we should update the version. Assume we've already parsed the config, and are only
 /// adding new reqs, or ones with a changed version.
 pub fn add_reqs_to_cfg(cfg_path: &Path, added: &[Req], added_dev: &[Req]) {
+    add_reqs_to_cfg_with(cfg_path, added, added_dev, frozen()).unwrap_or_else(|e| util::abort(&e))
+}
+
+fn add_reqs_to_cfg_with(
+    cfg_path: &Path,
+    added: &[Req],
+    added_dev: &[Req],
+    frozen: bool,
+) -> Result<(), String> {
     let data = fs::read_to_string(cfg_path)
         .expect("Unable to read pyproject.toml while attempting to add a dependency");
 
     let updated = update_cfg(&data, added, added_dev);
-    fs::write(cfg_path, updated)
-        .expect("Unable to write pyproject.toml while attempting to add a dependency");
+    let change = describe_added(&data, added, added_dev);
+    write_cfg_with(cfg_path, &updated, &change, frozen)
+}
+
+/// Eg `add requests = "^2.31" to [tool.pyflow.dependencies]`.
+fn describe_added(cfg_data: &str, added: &[Req], added_dev: &[Req]) -> String {
+    let cfg_lines: Vec<String> = cfg_data.lines().map(str::to_string).collect();
+    let sections = [
+        (added, "[tool.pyflow.dependencies]"),
+        (added_dev, "[tool.pyflow.dev-dependencies]"),
+    ];
+    let mut changes = vec![];
+    for (reqs, section) in sections {
+        let existing = collect_section(&cfg_lines, section)
+            .map(|s| s.lines)
+            .unwrap_or_default();
+        for req in reqs {
+            let replacing = existing
+                .iter()
+                .any(|l| line_name(l).is_some_and(|n| util::compare_names(n, &req.name)));
+            changes.push(if replacing {
+                format!("set {} in {}", cfg_line(req), section)
+            } else {
+                format!("add {} to {}", cfg_line(req), section)
+            });
+        }
+    }
+    changes.join(", and ")
+}
+
+/// Rename a dependency in pyproject.toml, keeping its spec, eg when a git req's metadata names a
+/// different package than its repo.
+pub fn rename_req_in_cfg(cfg_path: &Path, old: &str, new: &str) {
+    let data = fs::read_to_string(cfg_path)
+        .expect("Unable to read pyproject.toml while attempting to rename a dependency");
+    write_cfg(
+        cfg_path,
+        &rename_req(&data, old, new),
+        &format!("rename the dependency {} to {}", old, new),
+    );
+}
+
+fn rename_req(cfg_data: &str, old: &str, new: &str) -> String {
+    let mut in_deps = false;
+    let mut result: Vec<String> = vec![];
+    for line in cfg_data.lines() {
+        if line.starts_with('[') {
+            in_deps = line.trim_end().ends_with("dependencies]");
+        }
+        match line.split_once('=') {
+            Some((_, spec))
+                if in_deps && line_name(line).is_some_and(|n| util::compare_names(n, old)) =>
+            {
+                result.push(format!("{} ={}", new, spec))
+            }
+            _ => result.push(line.to_owned()),
+        }
+    }
+    let mut result = result.join("\n");
+    if cfg_data.ends_with('\n') {
+        result.push('\n');
+    }
+    result
 }
 
 /// Remove dependencies from pyproject.toml.
@@ -335,8 +641,11 @@ pub fn remove_reqs_from_cfg(cfg_path: &Path, reqs: &[String]) {
         result.push('\n');
     }
 
-    fs::write(cfg_path, result)
-        .expect("Unable to write to pyproject.toml while attempting to add a dependency");
+    write_cfg(
+        cfg_path,
+        &result,
+        &format!("remove {} from the dependencies", reqs.join(", ")),
+    );
 }
 
 pub fn parse_req_dot_text(cfg: &mut Config, path: &Path) {
@@ -345,7 +654,7 @@ pub fn parse_req_dot_text(cfg: &mut Config, path: &Path) {
         Err(_) => return,
     };
 
-    for line in BufReader::new(file).lines().flatten() {
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
         match Req::from_pip_str(&line) {
             Some(r) => {
                 cfg.reqs.push(r.clone());
@@ -360,20 +669,374 @@ pub fn parse_req_dot_text(cfg: &mut Config, path: &Path) {
 
 /// Update the config file with a new version.
 pub fn change_py_vers(cfg_path: &Path, specified: &Version) {
-    let f = fs::File::open(&cfg_path)
+    change_py_vers_with(cfg_path, specified, frozen()).unwrap_or_else(|e| util::abort(&e))
+}
+
+fn change_py_vers_with(cfg_path: &Path, specified: &Version, frozen: bool) -> Result<(), String> {
+    let data = fs::read_to_string(cfg_path)
         .expect("Unable to read pyproject.toml while adding Python version");
-    let mut new_data = String::new();
-    for line in BufReader::new(f).lines().flatten() {
-        if line.starts_with("py_version") {
-            new_data.push_str(&format!("py_version = \"{}\"\n", specified.to_string()));
-        } else {
-            new_data.push_str(&line);
-            new_data.push('\n');
+
+    let change = format!(r#"set py_version = "{}" in [tool.pyflow]"#, specified);
+    write_cfg_with(cfg_path, &set_py_vers(&data, specified), &change, frozen)
+}
+
+/// Replace the value of `py_version` in `cfg_data`, unless it already means `specified`. The
+/// rest of the file, including the line's quotes and comments, is kept as written.
+fn set_py_vers(cfg_data: &str, specified: &Version) -> String {
+    let re = Regex::new(r#"^(\s*py_version\s*=\s*["'])([^"']*)(["'])"#).unwrap();
+
+    cfg_data
+        .split_inclusive('\n')
+        .map(|line| match re.captures(line) {
+            Some(caps) if Version::from_str(caps[2].trim()).ok().as_ref() != Some(specified) => re
+                .replace(line, format!("${{1}}{}${{3}}", specified))
+                .into_owned(),
+            _ => line.to_owned(),
+        })
+        .collect()
+}
+
+/// Set `py_interpreter` in the config file, or remove it with `None`.
+pub fn change_py_interpreter(cfg_path: &Path, interpreter: Option<&Path>) {
+    let data = fs::read_to_string(cfg_path)
+        .expect("Unable to read pyproject.toml while setting the Python interpreter");
+
+    let interpreter = interpreter.map(|p| p.to_string_lossy().into_owned());
+    let change = match &interpreter {
+        Some(p) => format!(
+            "set py_interpreter = {} in [tool.pyflow]",
+            toml::Value::from(p.as_str())
+        ),
+        None => "remove py_interpreter from [tool.pyflow]".to_owned(),
+    };
+    write_cfg(
+        cfg_path,
+        &set_py_interpreter(&data, interpreter.as_deref()),
+        &change,
+    );
+}
+
+/// Replace the `py_interpreter` line in `cfg_data`, or add one after `py_version`.
+fn set_py_interpreter(cfg_data: &str, interpreter: Option<&str>) -> String {
+    let re_interpreter = Regex::new(r"^\s*py_interpreter\s*=").unwrap();
+    let re_vers = Regex::new(r"^\s*py_version\s*=").unwrap();
+    let replacing = cfg_data.lines().any(|l| re_interpreter.is_match(l));
+    let new_line = |eol: &str| {
+        interpreter.map(|p| format!("py_interpreter = {}{}", toml::Value::from(p), eol))
+    };
+
+    let mut result = String::new();
+    for line in cfg_data.split_inclusive('\n') {
+        let eol = if line.ends_with("\r\n") { "\r\n" } else { "\n" };
+        if re_interpreter.is_match(line) {
+            result.extend(new_line(eol));
+            continue;
+        }
+        result.push_str(line);
+        if !replacing && re_vers.is_match(line) {
+            if !line.ends_with('\n') {
+                result.push_str(eol);
+            }
+            result.extend(new_line(eol));
+        }
+    }
+    result
+}
+
+/// A key, or table header, in `pyproject.toml`, split into its dotted parts, eg
+/// `dependencies."zope.interface"` into `dependencies` and `zope.interface`. Also returns where
+/// what follows `end`, eg the `=` of a key, starts.
+fn key_parts(text: &str, end: char) -> Option<(Vec<String>, usize)> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                for (_, q) in chars.by_ref() {
+                    if q == c {
+                        break;
+                    }
+                    current.push(q);
+                }
+            }
+            '.' => parts.push(std::mem::take(&mut current).trim().to_owned()),
+            '#' => return None,
+            _ if c == end => {
+                parts.push(current.trim().to_owned());
+                if parts.iter().any(String::is_empty) {
+                    return None;
+                }
+                return Some((parts, i + c.len_utf8()));
+            }
+            _ => current.push(c),
         }
     }
+    None
+}
 
-    fs::write(cfg_path, new_data)
-        .expect("Unable to write pyproject.toml while adding Python version");
+/// Split a key under `[tool.pyflow]`, eg `settings.index_url`.
+pub fn split_key(key: &str) -> Option<Vec<String>> {
+    key_parts(&format!("{}=", key), '=').map(|(parts, _)| parts)
+}
+
+/// Write a key part, quoting it unless it's bare, eg `zope.interface` as `"zope.interface"`.
+fn key_text(parts: &[String]) -> String {
+    parts
+        .iter()
+        .map(|p| {
+            if !p.is_empty()
+                && p.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                p.clone()
+            } else {
+                toml::Value::String(p.clone()).to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// The last line of the value starting after byte `offset` of line `start`, which can span
+/// lines, eg an array with an item per line, and the comment after it, with the whitespace
+/// before that.
+fn value_end(lines: &[String], start: usize, offset: usize) -> (usize, Option<String>) {
+    let mut depth = 0i32;
+    // The delimiter of the string we're in, if any.
+    let mut in_str: Option<&[u8]> = None;
+    for (i, line) in lines.iter().enumerate().skip(start) {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let b = line.as_bytes();
+        let mut k = if i == start { offset } else { 0 };
+        let mut comment = None;
+        while k < b.len() {
+            match in_str {
+                Some(d) => {
+                    // Literal strings, in single quotes, have no escapes.
+                    if d[0] != b'\'' && b[k] == b'\\' {
+                        k += 2;
+                        continue;
+                    }
+                    if b[k..].starts_with(d) {
+                        in_str = None;
+                        k += d.len();
+                        continue;
+                    }
+                }
+                None => {
+                    if b[k..].starts_with(b"\"\"\"") || b[k..].starts_with(b"'''") {
+                        in_str = Some(&b[k..k + 3]);
+                        k += 3;
+                        continue;
+                    }
+                    match b[k] {
+                        b'"' | b'\'' => in_str = Some(&b[k..k + 1]),
+                        b'[' | b'{' => depth += 1,
+                        b']' | b'}' => depth -= 1,
+                        b'#' => {
+                            let code = line[..k].trim_end().len();
+                            comment = Some(line[code..].to_owned());
+                            break;
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            k += 1;
+        }
+        // Only multi-line strings continue on the next line.
+        if in_str.is_some_and(|d| d.len() == 1) {
+            in_str = None;
+        }
+        if depth <= 0 && in_str.is_none() {
+            return (i, comment);
+        }
+    }
+    (lines.len().saturating_sub(1), None)
+}
+
+/// A key line in `pyproject.toml`, and the lines its value spans.
+struct Entry {
+    /// The table it's in, then its key.
+    path: Vec<String>,
+    /// How many parts of `path` are its table's.
+    table_len: usize,
+    start: usize,
+    /// Of the `=`, on the first line.
+    eq: usize,
+    end: usize,
+    comment: Option<String>,
+}
+
+/// The headers and key lines of `pyproject.toml`; not the keys inside a value, eg an inline
+/// table.
+fn cfg_entries(lines: &[String]) -> (Vec<(Vec<String>, usize)>, Vec<Entry>) {
+    let mut headers = vec![];
+    let mut entries = vec![];
+    let mut table: Option<(Vec<String>, usize)> = None;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i].trim();
+        if line.starts_with("[[") {
+            // An array of tables, which we don't edit.
+            table = None;
+        } else if let Some(rest) = line.strip_prefix('[') {
+            table = key_parts(rest, ']').map(|(path, _)| (path, i));
+            headers.extend(table.clone());
+        } else if !line.is_empty() && !line.starts_with('#') {
+            if let Some((key, eq)) = key_parts(&lines[i], '=') {
+                let (end, comment) = value_end(lines, i, eq);
+                let mut path = table.as_ref().map(|(p, _)| p.clone()).unwrap_or_default();
+                let table_len = path.len();
+                path.extend(key);
+                entries.push(Entry {
+                    path,
+                    table_len,
+                    start: i,
+                    eq,
+                    end,
+                    comment,
+                });
+                i = end + 1;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    (headers, entries)
+}
+
+fn cfg_lines(cfg_data: &str) -> (Vec<String>, &'static str) {
+    let eol = if cfg_data.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let mut lines: Vec<String> = cfg_data
+        .split_inclusive('\n')
+        .map(ToOwned::to_owned)
+        .collect();
+    if let Some(last) = lines.last_mut() {
+        if !last.ends_with('\n') {
+            last.push_str(eol);
+        }
+    }
+    (lines, eol)
+}
+
+fn pyflow_path(key: &[String]) -> Vec<String> {
+    let mut path = vec!["tool".to_owned(), "pyflow".to_owned()];
+    path.extend_from_slice(key);
+    path
+}
+
+/// Set a value under `[tool.pyflow]`, eg `key` `["settings", "index_url"]`, keeping the rest of
+/// `cfg_data`, including comments, as written. An existing value is replaced in place, keeping
+/// its comment; otherwise the key's added to the end of its table, which is created if needed.
+pub fn set_cfg_value(
+    cfg_data: &str,
+    key: &[String],
+    value: &toml::Value,
+) -> Result<String, String> {
+    let (mut lines, eol) = cfg_lines(cfg_data);
+    let (headers, entries) = cfg_entries(&lines);
+    let path = pyflow_path(key);
+    let dotted = key_text(key);
+
+    if let Some(e) = entries
+        .iter()
+        .find(|e| path.starts_with(&e.path) && e.path != path)
+    {
+        return Err(format!(
+            "`{}` is in an inline table, at line {}. Edit it in `pyproject.toml` instead",
+            dotted,
+            e.start + 1
+        ));
+    }
+    if headers.iter().any(|(h, _)| h.starts_with(&path))
+        || entries
+            .iter()
+            .any(|e| e.path.starts_with(&path) && e.path != path)
+    {
+        return Err(format!(
+            "`{}` is a table; set its keys instead, eg `{}.<key>`",
+            dotted, dotted
+        ));
+    }
+
+    if let Some(e) = entries.iter().find(|e| e.path == path) {
+        let line = format!(
+            "{} {}{}{}",
+            &lines[e.start][..e.eq],
+            value,
+            e.comment.as_deref().unwrap_or_default(),
+            eol
+        );
+        lines.splice(e.start..=e.end, [line]);
+        return Ok(lines.concat());
+    }
+
+    // After the last of its siblings in a table that holds it, eg after `require_hashes` in
+    // `[tool.pyflow.settings]` for `settings.index_url`, or after `settings.require_hashes` in
+    // `[tool.pyflow]`.
+    let parent = &path[..path.len() - 1];
+    let sibling = entries
+        .iter()
+        .rfind(|e| e.path.starts_with(parent) && parent.starts_with(&e.path[..e.table_len]));
+    let header = headers.iter().rev().find(|(h, _)| h == parent);
+    match (sibling, header) {
+        (Some(e), _) => {
+            let line = format!("{} = {}{}", key_text(&path[e.table_len..]), value, eol);
+            lines.insert(e.end + 1, line);
+        }
+        (None, Some((_, header))) => {
+            let line = format!("{} = {}{}", key_text(&path[parent.len()..]), value, eol);
+            lines.insert(header + 1, line);
+        }
+        (None, None) => {
+            if lines.last().is_some_and(|l| !l.trim().is_empty()) {
+                lines.push(eol.to_owned());
+            }
+            lines.push(format!("[{}]{}", key_text(parent), eol));
+            lines.push(format!(
+                "{} = {}{}",
+                key_text(&path[parent.len()..]),
+                value,
+                eol
+            ));
+        }
+    }
+    Ok(lines.concat())
+}
+
+/// Remove a value, or a table, under `[tool.pyflow]`, keeping the rest of `cfg_data` as written.
+/// `None` if it isn't set.
+pub fn unset_cfg_value(cfg_data: &str, key: &[String]) -> Option<String> {
+    let (mut lines, _) = cfg_lines(cfg_data);
+    let (headers, entries) = cfg_entries(&lines);
+    let path = pyflow_path(key);
+
+    let mut removed = vec![false; lines.len()];
+    for e in entries.iter().filter(|e| e.path.starts_with(&path)) {
+        removed[e.start..=e.end].fill(true);
+    }
+    // Tables, to the next header that isn't theirs.
+    for (i, (h, line)) in headers.iter().enumerate() {
+        if h.starts_with(&path) {
+            let end = headers[i + 1..]
+                .iter()
+                .find(|(other, _)| !other.starts_with(&path))
+                .map_or(lines.len(), |(_, l)| *l);
+            removed[*line..end].fill(true);
+        }
+    }
+    if !removed.contains(&true) {
+        return None;
+    }
+    let mut removed = removed.into_iter();
+    lines.retain(|_| !removed.next().unwrap_or_default());
+    Some(lines.concat())
 }
 
 #[cfg(test)]
@@ -534,4 +1197,173 @@ dev_b = "^0.0.1"
 "#;
         assert_eq!(expected, &actual);
     }
+
+    const USER_FORMATTED: &str = "# Edited by hand\r\n\
+[tool.pyflow]\r\n\
+name = \"\"\r\n\
+py_version = '3'  # Any Python 3\r\n\
+\r\n\
+[tool.pyflow.dependencies]\r\n\
+numpy = \"1.2.*\"\r\n\
+requests = \">=2.28, <3\"  # Pinned below 3\r\n";
+
+    /// Installing without adding packages, or changing the Python version, leaves the file as-is.
+    #[test]
+    fn git_req_written_as_table() {
+        let req = crate::install::git_req_from_arg(
+            "git+https://github.com/org/mono.git@v1.2#subdirectory=client",
+        )
+        .unwrap();
+        let actual = update_cfg(BASELINE_NO_DEV_DEPS, &[req], &[]);
+        let expected = r#"
+[tool.pyflow]
+name = ""
+
+[tool.pyflow.dependencies]
+a = "^0.3.5"
+mono = { git = "https://github.com/org/mono.git", rev = "v1.2", subdirectory = "client" }
+"#;
+        assert_eq!(expected, &actual);
+
+        let renamed = rename_req(&actual, "mono", "mono-client");
+        assert!(renamed.contains(r#"mono-client = { git = "https://github.com/org/mono.git""#));
+        assert!(renamed.contains("a = \"^0.3.5\""));
+        assert_eq!(rename_req(&renamed, "a", "a"), renamed);
+    }
+
+    #[test]
+    fn unchanged_cfg_is_byte_identical() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pyproject.toml");
+        fs::write(&path, USER_FORMATTED).unwrap();
+        let cfg = Config::from_file(&path).unwrap();
+
+        let py_vers = cfg.py_version.unwrap();
+        assert_eq!(py_vers.raw, "3");
+        change_py_vers(&path, &py_vers.parsed);
+        add_reqs_to_cfg(&path, &[], &[]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), USER_FORMATTED);
+    }
+
+    #[test]
+    fn change_py_vers_keeps_formatting() {
+        let updated = set_py_vers(USER_FORMATTED, &Version::new_short(3, 11));
+        assert_eq!(
+            updated,
+            USER_FORMATTED.replace("py_version = '3'", "py_version = '3.11'")
+        );
+
+        // Equivalent versions aren't rewritten.
+        assert_eq!(
+            set_py_vers(USER_FORMATTED, &Version::new(3, 0, 0)),
+            USER_FORMATTED
+        );
+    }
+
+    #[test]
+    fn py_interpreter_set_after_py_version() {
+        let interpreter = r"C:\Python311\python.exe";
+        let updated = set_py_interpreter(USER_FORMATTED, Some(interpreter));
+        assert_eq!(
+            updated,
+            USER_FORMATTED.replace(
+                "py_version = '3'  # Any Python 3\r\n",
+                "py_version = '3'  # Any Python 3\r\n\
+                 py_interpreter = 'C:\\Python311\\python.exe'\r\n"
+            )
+        );
+        let cfg: Pyproject = toml::from_str(&updated).unwrap();
+        assert_eq!(
+            cfg.tool.pyflow.unwrap().py_interpreter.as_deref(),
+            Some(interpreter)
+        );
+
+        let moved = set_py_interpreter(&updated, Some("/opt/python/bin/python3"));
+        assert_eq!(moved.matches("py_interpreter").count(), 1);
+        assert!(moved.contains("py_interpreter = \"/opt/python/bin/python3\"\r\n"));
+        assert_eq!(set_py_interpreter(&updated, None), USER_FORMATTED);
+    }
+
+    #[test]
+    fn frozen_pyproject_untouched() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let cfg_path = dir.join("pyproject.toml");
+        let data = "[tool.pyflow]\npy_version = \"3.10\"\n\n[tool.pyflow.dependencies]\n\
+                    a = \"^0.3.5\"\n";
+        fs::write(&cfg_path, data).unwrap();
+
+        // `pyflow install requests`
+        let requests = Req::new(
+            "requests".into(),
+            vec![Constraint::new(Caret, Version::new_short(2, 31))],
+        );
+        let err = add_reqs_to_cfg_with(&cfg_path, std::slice::from_ref(&requests), &[], true)
+            .unwrap_err();
+        assert!(err.contains(r#"would add requests = "^2.31" to [tool.pyflow.dependencies]"#));
+        assert_eq!(fs::read_to_string(&cfg_path).unwrap(), data);
+
+        // `pyflow switch 3.11`, or answering the version prompt.
+        let err = change_py_vers_with(&cfg_path, &Version::new_short(3, 11), true).unwrap_err();
+        assert!(err.contains(r#"would set py_version = "3.11" in [tool.pyflow]"#));
+        assert_eq!(fs::read_to_string(&cfg_path).unwrap(), data);
+        // Changes that are already made aren't a problem.
+        assert!(change_py_vers_with(&cfg_path, &Version::new_short(3, 10), true).is_ok());
+
+        // Answering the version prompt without a `pyproject.toml`.
+        let missing = dir.join("new").join("pyproject.toml");
+        assert!(write_cfg_with(&missing, data, "create it", true).is_err());
+        assert!(!missing.exists());
+
+        add_reqs_to_cfg_with(&cfg_path, &[requests], &[], false).unwrap();
+        assert!(fs::read_to_string(&cfg_path)
+            .unwrap()
+            .contains(r#"requests = "^2.31""#));
+    }
+
+    fn key(k: &str) -> Vec<String> {
+        split_key(k).unwrap()
+    }
+
+    #[test]
+    fn cfg_values_set_in_place() {
+        let cfg = "[tool.pyflow]\r\nname = \"proj\"\r\nsettings.require_hashes = true\r\n\
+                   deps = { a = \"1\" }\r\nmsg = '''\r\n[not.a.table]\r\n'''\r\n\r\n\
+                   [tool.pyflow.dependencies]\r\n\"zope.interface\" = \"^6.0\" # Pinned\r\n";
+
+        assert_eq!(
+            key("dependencies.\"zope.interface\""),
+            ["dependencies", "zope.interface"]
+        );
+        assert_eq!(split_key("a..b"), None);
+
+        // Replaced, keeping the comment and line endings.
+        let zope = key("dependencies.\"zope.interface\"");
+        let edited = set_cfg_value(cfg, &zope, &toml::Value::String("^7.0".into())).unwrap();
+        assert_eq!(
+            edited,
+            cfg.replace("\"^6.0\" # Pinned", "\"^7.0\" # Pinned")
+        );
+
+        // After a dotted sibling.
+        let index_url = key("settings.index_url");
+        let edited = set_cfg_value(cfg, &index_url, &toml::Value::String("x".into())).unwrap();
+        assert!(edited.contains("require_hashes = true\r\nsettings.index_url = \"x\"\r\n"));
+
+        // The header inside a multi-line string isn't a table.
+        let edited = set_cfg_value(cfg, &key("not.a"), &toml::Value::Boolean(true)).unwrap();
+        assert!(edited.ends_with("\r\n\r\n[tool.pyflow.not]\r\na = true\r\n"));
+
+        let err = set_cfg_value(cfg, &key("deps.b"), &toml::Value::Integer(2)).unwrap_err();
+        assert!(err.contains("inline table, at line 4"));
+        assert!(set_cfg_value(cfg, &key("dependencies"), &toml::Value::Integer(2)).is_err());
+
+        // Removing a table removes its keys, wherever they are.
+        let edited = unset_cfg_value(cfg, &key("dependencies")).unwrap();
+        assert!(!edited.contains("zope"));
+        let edited = unset_cfg_value(&edited, &key("settings")).unwrap();
+        assert!(!edited.contains("require_hashes"));
+        assert!(edited.starts_with("[tool.pyflow]\r\nname = \"proj\"\r\ndeps"));
+        assert_eq!(unset_cfg_value(cfg, &key("license")), None);
+    }
 }
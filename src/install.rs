@@ -1,17 +1,62 @@
-use std::{fs, fs::File, io, io::BufRead, path::Path, process::Command};
+use std::{
+    fs,
+    fs::File,
+    io,
+    io::BufRead,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use flate2::read::GzDecoder;
 use regex::Regex;
 use ring::digest;
-<<<<<<< HEAD
-=======
-use std::path::PathBuf;
-use std::{fs, io, io::BufRead, path::Path, process::Command};
->>>>>>> 4c6ec9bc8dcf2c486d5820627d70162e44d6b5a7
 use tar::Archive;
 use termcolor::Color;
 
-use crate::{commands, dep_types::Version, util, util::print_color};
+use crate::{
+    commands,
+    dep_types::{Req, Version},
+    util,
+    util::print_color,
+};
+
+static REQUIRE_HASHES: AtomicBool = AtomicBool::new(false);
+
+/// For `require_hashes` in `[tool.pyflow.settings]`, or `--require-hashes`: stop if a file's
+/// hash is missing or doesn't match, instead of asking.
+pub fn require_hashes() {
+    REQUIRE_HASHES.store(true, Ordering::SeqCst);
+}
+
+/// Why installing a package failed.
+#[derive(Debug)]
+pub enum InstallError {
+    Download(reqwest::Error),
+    /// Writing to disk failed, eg as it's full.
+    Fs(util::fs_errors::FsError),
+}
+
+impl InstallError {
+    /// A message that points users to a fix.
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Download(e) => util::http::describe(e),
+            Self::Fs(e) => util::fs_errors::describe(e, None),
+        }
+    }
+}
+
+impl From<reqwest::Error> for InstallError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Download(e)
+    }
+}
+
+impl From<util::fs_errors::FsError> for InstallError {
+    fn from(e: util::fs_errors::FsError) -> Self {
+        Self::Fs(e)
+    }
+}
 
 #[derive(Copy, Clone, Debug)]
 pub enum PackageType {
@@ -20,7 +65,7 @@ pub enum PackageType {
 }
 
 /// [Cookbook](https://rust-lang-nursery.github.io/rust-cookbook/cryptography/hashing.html)
-fn sha256_digest<R: io::Read>(mut reader: R) -> Result<digest::Digest, std::io::Error> {
+pub fn sha256_digest<R: io::Read>(mut reader: R) -> Result<digest::Digest, std::io::Error> {
     let mut context = digest::Context::new(&digest::SHA256);
     let mut buffer = [0; 1024];
 
@@ -38,15 +83,9 @@ fn sha256_digest<R: io::Read>(mut reader: R) -> Result<digest::Digest, std::io::
 /// If the setup.py file uses `distutils.core`, replace with `setuptools`. This is required to build
 /// a wheel. Eg, replace `from distutils.core import setup` with `from setuptools import setup`.
 fn replace_distutils(setup_path: &Path) {
-    let setup_text = if let Ok(t) = fs::read_to_string(setup_path) {
-        t
-    } else {
-        util::abort(&format!(
-            "Can't find setup.py in this source distribution \
-             path: {:?}. This could mean there are no suitable wheels for this package, \
-             and there's a problem with its setup.py.",
-            setup_path
-        ));
+    let setup_text = match fs::read_to_string(setup_path) {
+        Ok(t) => t,
+        Err(_) => return,
     };
 
     let re = Regex::new(r"distutils.core").unwrap();
@@ -58,23 +97,60 @@ fn replace_distutils(setup_path: &Path) {
     }
 }
 
-/// Remove scripts. Used when uninstalling.
-fn remove_scripts(scripts: &[String], scripts_path: &Path) {
-    // todo: Likely not a great approach. QC.
-    for entry in
-        fs::read_dir(scripts_path).expect("Problem reading dist directory when removing scripts")
-    {
-        let entry = entry.unwrap();
-        if !entry.file_type().unwrap().is_file() {
-            continue;
-        }
-        let data = fs::read_to_string(entry.path()).unwrap();
-        for script in scripts {
-            if data.contains(&format!("from {}", script)) {
-                fs::remove_file(entry.path()).expect("Problem removing console script");
-                util::print_color(&format!("Removed console script {}:", script), Color::Green);
-            }
-        }
+/// What setuptools builds from when a source dist only has a `setup.cfg`.
+const SETUP_SHIM: &str = "from setuptools import setup\n\nsetup()\n";
+
+/// Get an unpacked source dist ready to build with `setup.py bdist_wheel`. Some older ones only
+/// have a `setup.cfg`, eg with a flat module; setuptools builds these from a `setup.py` that
+/// calls `setup()`, so we add one. If there's neither, returns the files that are there.
+fn prepare_setup(dir: &Path) -> Result<(), String> {
+    let setup_path = dir.join("setup.py");
+    if setup_path.is_file() {
+        replace_distutils(&setup_path);
+        return Ok(());
+    }
+    if dir.join("setup.cfg").is_file() {
+        fs::write(&setup_path, SETUP_SHIM).expect("Problem adding a `setup.py` for `setup.cfg`");
+        return Ok(());
+    }
+    if !dir.is_dir() {
+        return Err(format!(
+            "it didn't unpack into a `{}` folder",
+            dir.file_name().unwrap_or_default().to_string_lossy()
+        ));
+    }
+
+    let mut found: Vec<String> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|e| {
+                    let name = e.file_name().to_string_lossy().into_owned();
+                    if e.path().is_dir() {
+                        name + "/"
+                    } else {
+                        name
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    found.sort();
+    Err(if found.is_empty() {
+        "it's empty".to_owned()
+    } else {
+        format!("it has {}", found.join(", "))
+    })
+}
+
+/// Remove a package's console scripts, using the owners recorded when they were created.
+/// Used when uninstalling.
+fn remove_scripts(package: &str, entry_points: &[String], scripts_path: &Path) {
+    if !scripts_path.exists() {
+        return;
+    }
+    for script in util::scripts::release(package, entry_points, scripts_path) {
+        util::print_color(&format!("Removed console script {}", script), Color::Green);
     }
 }
 
@@ -95,30 +171,42 @@ if __name__ == '__main__':
         .unwrap_or_else(|_| util::abort(&format!("Problem creating script file for {}", name)));
 }
 
-/// Find `dist-info` folder for package.
-fn find_dist_info_path(name: &str, version: &Version, lib_path: &Path) -> PathBuf {
-    let mut dist_info_path = lib_path.join(format!("{}-{}.dist-info", name, version.to_string()));
-    // If we can't find the dist_info path, it may be due to it not using a full 3-digit semver format.
-    if !dist_info_path.exists() && (version.patch == Some(0) || version.patch == None) {
-        dist_info_path = lib_path.join(format!("{}-{}.dist-info", name, version.to_string_med()));
-        if !dist_info_path.exists() && (version.minor == Some(0) || version.minor == None) {
-            dist_info_path =
-                lib_path.join(format!("{}-{}.dist-info", name, version.to_string_short()));
-        }
+/// Find a package's `dist-info` folder, by scanning the lib folder. Its name may differ in
+/// capitalization, `-` vs `_`, or how its version is written, eg `2.0` vs `2.0.0`, from the
+/// one we have.
+pub fn find_dist_info(name: &str, version: &Version, lib_path: &Path) -> Option<PathBuf> {
+    let re_dist = Regex::new(r"^(.*?)-(.*?)\.dist-info$").unwrap();
+    if !lib_path.exists() {
+        return None;
     }
-    dist_info_path
+    util::find_folders(lib_path)
+        .into_iter()
+        .find(|folder| {
+            re_dist.captures(folder).is_some_and(|caps| {
+                util::compare_names(&caps[1], name)
+                    && caps[2].parse::<Version>().ok().as_ref() == Some(version)
+            })
+        })
+        .map(|folder| lib_path.join(folder))
 }
 
-/// Set up entry points (ie scripts like `ipython`, `black` etc) in a single file.
-/// Alternatively, we could just parse all `dist-info` folders every run; this should
-/// be faster.
-pub fn setup_scripts(name: &str, version: &Version, lib_path: &Path, entry_pt_path: &Path) {
-    let mut scripts = vec![];
-    let dist_info_path = find_dist_info_path(name, version, lib_path);
+/// Find `dist-info` folder for package. If it isn't installed, where it would be.
+pub fn find_dist_info_path(name: &str, version: &Version, lib_path: &Path) -> PathBuf {
+    find_dist_info(name, version, lib_path)
+        .unwrap_or_else(|| lib_path.join(format!("{}-{}.dist-info", name, version)))
+}
 
-    if let Ok(ep_file) = fs::File::open(&dist_info_path.join("entry_points.txt")) {
+/// Find the console scripts a package provides, from its `entry_points.txt`. Eg
+/// `black=black:patched_main`.
+pub fn find_console_scripts(name: &str, version: &Version, lib_path: &Path) -> Vec<String> {
+    dist_info_scripts(&find_dist_info_path(name, version, lib_path))
+}
+
+fn dist_info_scripts(dist_info_path: &Path) -> Vec<String> {
+    let mut scripts = vec![];
+    if let Ok(ep_file) = fs::File::open(dist_info_path.join("entry_points.txt")) {
         let mut in_scripts_section = false;
-        for line in io::BufReader::new(ep_file).lines().flatten() {
+        for line in io::BufReader::new(ep_file).lines().map_while(Result::ok) {
             if line.contains("[console_scripts]") {
                 in_scripts_section = true;
                 continue;
@@ -133,29 +221,101 @@ pub fn setup_scripts(name: &str, version: &Version, lib_path: &Path, entry_pt_pa
             }
         }
     } // else: Probably no scripts.
+    scripts
+}
+
+/// Set up entry points (ie scripts like `ipython`, `black` etc) in a single file.
+/// Alternatively, we could just parse all `dist-info` folders every run; this should
+/// be faster. Skipped with `--no-scripts`.
+pub fn setup_scripts(name: &str, version: &Version, lib_path: &Path, entry_pt_path: &Path) {
+    if util::scripts::skip() {
+        return;
+    }
+    write_scripts(name, version, lib_path, entry_pt_path);
+}
+
+/// Create a package's console scripts, except ones another installed package already provides,
+/// unless `--force-scripts`.
+pub fn write_scripts(name: &str, version: &Version, lib_path: &Path, entry_pt_path: &Path) {
+    let _timer = util::timings::start("script setup", Some(name));
+    let scripts = find_console_scripts(name, version, lib_path);
+    let scripts = util::scripts::claim(
+        &scripts,
+        name,
+        &version.to_string(),
+        entry_pt_path,
+        lib_path,
+        util::scripts::force(),
+    );
 
-    // Now that we've found scripts, add them to our unified file.
     // Note that normally, python uses a bin directory.
-    //    // todo: Currently we're setting up the unified file, and the bin/script file.
-    //    let scripts_file = &lib_path.join("../console_scripts.txt");
-    //    if !scripts_file.exists() {
-    //        fs::File::create(scripts_file).expect("Problem creating console_scripts.txt");
-    //    }
-    //
-    //    let mut existing_scripts =
-    //        fs::read_to_string(scripts_file).expect("Can't find console_scripts.txt");
-
-    if !entry_pt_path.exists() && fs::create_dir(&entry_pt_path).is_err() {
+    make_scripts(&scripts, entry_pt_path, true);
+}
+
+/// Set up the console scripts of a path dependency, from the `dist-info` folders in it, eg
+/// from `pip install -e`. Scripts that already exist are left alone.
+pub fn setup_path_scripts(path: &Path, entry_pt_path: &Path) {
+    let re_dist = Regex::new(r"^.*?-.*?\.dist-info$").unwrap();
+    for folder_name in util::find_folders(path) {
+        if re_dist.is_match(&folder_name) {
+            make_scripts(
+                &dist_info_scripts(&path.join(folder_name)),
+                entry_pt_path,
+                false,
+            );
+        }
+    }
+}
+
+/// Set up the console scripts of a project installed in place, eg `greet=mylib.cli:main`. They
+/// import from its source, so only missing ones are created.
+pub fn setup_editable_scripts(
+    name: &str,
+    version: &str,
+    scripts: &[String],
+    lib_path: &Path,
+    entry_pt_path: &Path,
+) {
+    if util::scripts::skip() {
+        return;
+    }
+    let missing: Vec<String> = scripts
+        .iter()
+        .filter(|s| {
+            s.split_once('=')
+                .is_some_and(|(n, _)| !entry_pt_path.join(n.trim()).exists())
+        })
+        .cloned()
+        .collect();
+    if missing.is_empty() {
+        return;
+    }
+    let scripts = util::scripts::claim(
+        &missing,
+        name,
+        version,
+        entry_pt_path,
+        lib_path,
+        util::scripts::force(),
+    );
+    make_scripts(&scripts, entry_pt_path, false);
+}
+
+fn make_scripts(scripts: &[String], entry_pt_path: &Path, overwrite: bool) {
+    if !entry_pt_path.exists() && fs::create_dir_all(entry_pt_path).is_err() {
         util::abort("Problem creating script path")
     }
 
+    let re = Regex::new(r"^(.*?)\s*=\s*(.*?):(.*)$").unwrap();
     for new_script in scripts {
-        let re = Regex::new(r"^(.*?)\s*=\s*(.*?):(.*)$").unwrap();
-        if let Some(caps) = re.captures(&new_script) {
+        if let Some(caps) = re.captures(new_script) {
             let name = caps.get(1).unwrap().as_str();
             let module = caps.get(2).unwrap().as_str();
             let func = caps.get(3).unwrap().as_str();
             let path = entry_pt_path.join(name);
+            if !overwrite && path.exists() {
+                continue;
+            }
             make_script(&path, name, module, func);
             // `wheel` is a dependency required internally, but the user doesn't care.
             if name != "wheel" {
@@ -163,12 +323,255 @@ pub fn setup_scripts(name: &str, version: &Version, lib_path: &Path, entry_pt_pa
             }
         }
     }
+}
 
-    //    fs::write(scripts_file, existing_scripts).expect("Unable to write to the console_scripts file");
+/// Older versions of pyflow could put the scripts of project dependencies in the venv's `bin`
+/// folder. Move them to `entry_pt`, where we look for them.
+pub fn migrate_scripts(paths: &util::Paths) {
+    let entries = match fs::read_dir(&paths.bin) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let module = match fs::read_to_string(&path)
+            .ok()
+            .and_then(|d| script_module(&d))
+        {
+            Some(m) => m,
+            None => continue,
+        };
+        // Leave the scripts of packages installed in the venv itself, eg `wheel`.
+        if !paths.lib.join(&module).exists() && !paths.lib.join(format!("{}.py", module)).exists() {
+            continue;
+        }
+        let dest = paths.entry_pt.join(entry.file_name());
+        if (paths.entry_pt.exists() || fs::create_dir_all(&paths.entry_pt).is_ok())
+            && !dest.exists()
+            && fs::rename(&path, &dest).is_ok()
+        {
+            print_color(
+                &format!(
+                    "Moved the console script {} to {}",
+                    entry.file_name().to_string_lossy(),
+                    paths.entry_pt.to_string_lossy()
+                ),
+                Color::Green,
+            );
+        }
+    }
+}
+
+/// The top-level module a script made by `make_script` imports from.
+fn script_module(contents: &str) -> Option<String> {
+    let re = Regex::new(r"(?m)^import re\s+import sys\s+from ([\w.]+) import \w+").unwrap();
+    let caps = re.captures(contents)?;
+    let module = caps.get(1).unwrap().as_str();
+    Some(module.split('.').next().unwrap().to_owned())
+}
+
+/// What to rename a package's files from and to. We rename its top-level module, which may
+/// not match the package name, eg `dateutil` for `python-dateutil`.
+fn module_rename(wheel: &File, name: &str, new: &str) -> (String, String) {
+    let old = util::wheel_modules(wheel)
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| util::standardize_name(name));
+    (old, util::standardize_name(new))
+}
+
+/// The Python we build wheels from source dists with.
+fn sdist_python(paths: &util::Paths) -> PathBuf {
+    if cfg!(target_os = "windows") {
+        paths.bin.join("python")
+    } else {
+        PathBuf::from("python3")
+    }
+}
+
+/// Unpack a source dist into the lib folder, and build a wheel from it. Returns the unpacked
+/// folder, which the caller removes, and the wheel. If the disk is full or can't be written to,
+/// the unpacked folder is removed, and the error returned.
+fn build_from_source(
+    name: &str,
+    version: &Version,
+    filename: &str,
+    archive_path: &Path,
+    archive_file: &File,
+    paths: &util::Paths,
+) -> Result<(PathBuf, PathBuf), util::fs_errors::FsError> {
+    // todo: Support .tar.bz2
+    if archive_path.extension().unwrap() == "bz2" {
+        util::abort(&format!(
+            "Extracting source packages in the `.bz2` format isn't supported \
+             at this time: {:?}",
+            &archive_path
+        ));
+    }
+
+    // The archive unpacks into a parent folder from the `tar.gz`. Place
+    // its sub-folders directly in the lib folder, and delete the parent.
+    let re = Regex::new(r"^(.*?)(?:\.tar\.gz|\.zip)$").unwrap();
+    let folder_name = re
+        .captures(filename)
+        .expect("Problem matching extracted folder name")
+        .get(1)
+        .unwrap_or_else(|| {
+            util::abort(&format!(
+                "Unable to find extracted folder name: {}",
+                filename
+            ))
+        })
+        .as_str();
+    let extracted_parent = paths.lib.join(folder_name);
+
+    // Extract the tar.gz source code.
+    let tar = GzDecoder::new(archive_file);
+    let mut archive = Archive::new(tar);
+
+    // Some python archives don't have file create times set which
+    // breaks wheel builds. Don't preserve mtime fixes this.
+    archive.set_preserve_mtime(false);
+
+    // We iterate over and copy entries instead of running `Archive.unpack`, since
+    // symlinks in the archive may cause the unpack to break. If this happens, we want
+    // to continue unpacking the other files.
+    // Overall, this is a pretty verbose workaround!
+    let mut archive_error = Ok(());
+    let mut budget = util::archive_limits::Budget::new();
+    match archive.entries() {
+        Ok(entries) => {
+            for file in entries {
+                match file {
+                    Ok(mut f) => {
+                        if let Err(e) = util::archive_limits::check_tar_entry(&f, &mut budget) {
+                            util::abort(&format!(
+                                "Refusing to unpack {}: {}",
+                                archive_path.display(),
+                                e
+                            ));
+                        }
+                        match f.unpack_in(&paths.lib) {
+                            Ok(_) => (),
+                            Err(e)
+                                if util::fs_errors::classify(&e)
+                                    != util::fs_errors::FsErrorKind::Other =>
+                            {
+                                let path = paths.lib.join(f.path().unwrap_or_default());
+                                fs::remove_dir_all(&extracted_parent).ok();
+                                return Err(util::fs_errors::FsError::new("unpacking", &path, e));
+                            }
+                            Err(e) => {
+                                print_color(
+                                    &format!("Problem unpacking file {:?}: {:?}", f.path(), e),
+                                    Color::Yellow, // Dark
+                                );
+                                let f_path = f.path().expect("Problem getting path from archive");
+
+                                let filename =
+                                    f_path.file_name().expect("Problem getting file name");
+
+                                // In the `pandocfilters` Python package, the readme file specified in
+                                // `setup.py` is a symlink, which we can't unwrap, and is required to exist,
+                                // or the wheel build fails. Workaround here; may apply to other packages as well.
+                                if filename.to_str().unwrap().to_lowercase().contains("readme")
+                                    && fs::File::create(paths.lib.join(f.path().unwrap())).is_err()
+                                {
+                                    print_color(
+                                        "Problem creating dummy readme",
+                                        Color::Yellow, // Dark
+                                    );
+                                }
+                            }
+                        };
+                    }
+                    Err(e) => {
+                        // We'll continue with this leg, then check if we have a zip file instead.
+                        archive_error = Err(e);
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            // We'll continue with this leg, then check if we have a zip file instead.
+            archive_error = Err(e);
+        }
+    }
+    // Check if we have a zip file instead.
+    if let Err(e) = archive_error {
+        println!(
+            "Problem opening the tar.gz archive: {:?}: {:?}, checking if it's a zip...",
+            &archive_file, e
+        );
+        util::extract_zip(
+            archive_file,
+            archive_path,
+            &paths.lib,
+            &None,
+            &Some((name, filename)),
+        )
+        .inspect_err(|_| {
+            fs::remove_dir_all(&extracted_parent).ok();
+        })?;
+    }
+
+    // todo: This fs_extras move does a full copy. Normal fs lib doesn't include
+    // todo moves, only copies. Figure out how to do a normal move,
+    // todo, to speed this up.
+
+    if let Err(found) = prepare_setup(&extracted_parent) {
+        fs::remove_dir_all(&extracted_parent).ok();
+        util::abort(&format!(
+            "Can't build {} {} from source: there's no `setup.py` or `setup.cfg` in {}; {}. \
+             This could mean there are no suitable wheels for this package, and its source \
+             distribution has a layout pyflow can't build. Please report it, with this message.",
+            name, version, filename, found
+        ));
+    }
+
+    // The Linux and Mac builds appear to be unable to build wheels due to
+    // missing the ctypes library; we use the system python for those.
+    let python = sdist_python(paths);
+    let label = format!("building {} {} from source", name, version);
+    let built = util::source_build::run(
+        &python,
+        &extracted_parent,
+        &["setup.py", "bdist_wheel"],
+        &label,
+    );
+
+    let dist_path = &extracted_parent.join("dist");
+    if built.is_err() || !dist_path.exists() {
+        // Don't leave the unpacked source in the lib folder.
+        fs::remove_dir_all(&extracted_parent).ok();
+        let hint = if cfg!(target_os = "windows") {
+            "for Windows, and the system is missing dependencies required to compile it, \
+             or if on WSL and installing to a mounted directory."
+        } else {
+            "for this OS and this system is missing dependencies required to compile it. \
+             Try running `pip install --upgrade wheel`, then try again."
+        };
+        util::abort(&format!(
+            "Problem building {} from source. This may occur if a package that requires \
+             compiling has no wheels available {}\n{}",
+            name,
+            hint,
+            built.err().unwrap_or_default()
+        ));
+    }
+
+    let built_wheel_filename = util::find_first_file(dist_path)
+        .file_name()
+        .expect("Unable to find built wheel filename")
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    Ok((extracted_parent, dist_path.join(built_wheel_filename)))
 }
 
 /// Download and install a package. For wheels, we can just extract the contents into
-/// the lib folder.  For source dists, make a wheel first.
+/// the lib folder.  For source dists, make a wheel first. Returns the bytes installed.
 #[allow(clippy::too_many_arguments)]
 pub fn download_and_install_package(
     name: &str,
@@ -179,317 +582,180 @@ pub fn download_and_install_package(
     paths: &util::Paths,
     package_type: PackageType,
     rename: &Option<(u32, String)>,
-) -> Result<(), reqwest::Error> {
+) -> Result<u64, InstallError> {
+    let create_dir = |dir: &Path| {
+        fs::create_dir_all(dir).map_err(|e| util::fs_errors::FsError::new("creating", dir, e))
+    };
     if !paths.lib.exists() {
-        fs::create_dir_all(&paths.lib).expect("Problem creating lib directory");
+        create_dir(&paths.lib)?;
     }
-    if !paths.cache.exists() {
-        fs::create_dir_all(&paths.cache).expect("Problem creating cache directory");
+    let archive_path = util::paths::archive_path(&paths.cache, filename);
+    if let Some(archives) = archive_path.parent() {
+        if !archives.exists() {
+            create_dir(archives)?;
+        }
     }
-    let archive_path = paths.cache.join(filename);
+    // The cache is shared between projects.
+    let cache_guard = util::guard::guard_cached(&archive_path);
 
     // If the archive is already in the lib folder, don't re-download it. Note that this
     // isn't the usual flow, but may have some uses.
     if !archive_path.exists() {
-        // Save the file
-        let mut resp = reqwest::blocking::get(url)?; // Download the file
-        let mut out = File::create(&archive_path).expect("Failed to save downloaded package file");
-
-        // todo: DRY between here and py_versions.
-        if let Err(e) = io::copy(&mut resp, &mut out) {
-            // Clean up the downloaded file, or we'll get an error next time.
-            fs::remove_file(&archive_path).expect("Problem removing the broken file");
-            util::abort(&format!("Problem downloading the package archive: {:?}", e));
+        let _timer = util::timings::start("download", Some(name));
+        if url.starts_with(util::find_links::PATH_PREFIX) {
+            // From a `find_links` directory.
+            let local = util::find_links::locate(url, filename).unwrap_or_else(|| {
+                util::abort(&format!(
+                    "Can't find {}; it's locked from a local directory. Pass that directory \
+                     with `--find-links`, or set `find_links` in `pyproject.toml`.",
+                    filename
+                ))
+            });
+            if let Err(e) = fs::copy(&local, &archive_path) {
+                if util::fs_errors::classify(&e) != util::fs_errors::FsErrorKind::Other {
+                    fs::remove_file(&archive_path).ok();
+                    return Err(
+                        util::fs_errors::FsError::new("copying to", &archive_path, e).into(),
+                    );
+                }
+                util::abort(&format!(
+                    "Problem copying {}: {}",
+                    local.to_string_lossy(),
+                    e
+                ));
+            }
+        } else {
+            // Save the file
+            let mut resp = util::http::get(url)?; // Download the file
+            let mut out = File::create(&archive_path)
+                .map_err(|e| util::fs_errors::FsError::new("creating", &archive_path, e))?;
+
+            // todo: DRY between here and py_versions.
+            if let Err(e) = io::copy(&mut resp, &mut out) {
+                // Clean up the downloaded file, or we'll get an error next time.
+                fs::remove_file(&archive_path).expect("Problem removing the broken file");
+                if util::fs_errors::classify(&e) != util::fs_errors::FsErrorKind::Other {
+                    return Err(util::fs_errors::FsError::new("writing", &archive_path, e).into());
+                }
+                util::abort(&format!("Problem downloading the package archive: {:?}", e));
+            }
         }
     }
+    util::cache_policy::record_use(&paths.cache.join(util::paths::ARCHIVE_DIR), filename);
+    drop(cache_guard);
 
     let file = util::open_archive(&archive_path);
 
     // https://rust-lang-nursery.github.io/rust-cookbook/cryptography/hashing.html
     let reader = io::BufReader::new(&file);
+    let hash_timer = util::timings::start("hash verification", Some(name));
     let file_digest = sha256_digest(reader).unwrap_or_else(|_| {
         util::abort(&format!("Problem reading hash for {}", filename));
     });
+    drop(hash_timer);
 
     let file_digest_str = data_encoding::HEXUPPER.encode(file_digest.as_ref());
-    if file_digest_str.to_lowercase() != expected_digest.to_lowercase() {
-        util::print_color(&format!("Hash failed for {}. Expected: {}, Actual: {}. Continue with installation anyway? (yes / no)", filename, expected_digest.to_lowercase(), file_digest_str.to_lowercase()), Color::Red);
-
-        let mut input = String::new();
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Unable to read user input hash fail decision");
-
-        let input = input
-            .chars()
-            .next()
-            .expect("Problem reading input")
-            .to_string();
-
-        if input.to_lowercase().contains('y') {
-        } else {
+    let required = REQUIRE_HASHES.load(Ordering::SeqCst);
+    if expected_digest.is_empty() && required {
+        util::abort(&format!(
+            "PyPI lists no hash for {}, and hashes are required",
+            filename
+        ));
+    } else if expected_digest.is_empty() {
+        // Some old uploads have no hash on PyPI.
+        util::print_color(
+            &format!(
+                "PyPI lists no hash for {}, so it can't be verified",
+                filename
+            ),
+            Color::Yellow,
+        );
+    } else if file_digest_str.to_lowercase() != expected_digest.to_lowercase() && required {
+        util::abort(&format!(
+            "Hash failed for {}. Expected: {}, Actual: {}",
+            filename,
+            expected_digest.to_lowercase(),
+            file_digest_str.to_lowercase()
+        ));
+    } else if file_digest_str.to_lowercase() != expected_digest.to_lowercase() {
+        util::print_color(
+            &format!(
+                "Hash failed for {}. Expected: {}, Actual: {}",
+                filename,
+                expected_digest.to_lowercase(),
+                file_digest_str.to_lowercase()
+            ),
+            Color::Red,
+        );
+        if !util::prompts::confirm(
+            "Continue with installation anyway?",
+            "Pass `--require-hashes` to stop without being asked.",
+        ) {
             util::abort("Exiting due to failed hash");
         }
     }
 
     // We must re-open the file after computing the hash.
     let archive_file = util::open_archive(&archive_path);
+    let extract_timer = util::timings::start("extract and build", Some(name));
 
-    let rename = rename
-        .as_ref()
-        .map(|(_, new)| (name.to_owned(), new.to_owned()));
+    let rename = rename.as_ref().map(|(_, new)| new.as_str());
 
-    match package_type {
+    let size = match package_type {
         PackageType::Wheel => {
-            util::extract_zip(&archive_file, &paths.lib, &rename, &None);
+            let rename = rename.map(|new| module_rename(&archive_file, name, new));
+            util::extract_zip(&archive_file, &archive_path, &paths.lib, &rename, &None)?
         }
         PackageType::Source => {
-            // todo: Support .tar.bz2
-            if archive_path.extension().unwrap() == "bz2" {
-                util::abort(&format!(
-                    "Extracting source packages in the `.bz2` format isn't supported \
-                     at this time: {:?}",
-                    &archive_path
-                ));
-            }
-
-            // Extract the tar.gz source code.
-            let tar = GzDecoder::new(&archive_file);
-            let mut archive = Archive::new(tar);
-
-            // Some python archives don't have file create times set which
-            // breaks wheel builds. Don't preserve mtime fixes this.
-            archive.set_preserve_mtime(false);
-
-            // We iterate over and copy entries instead of running `Archive.unpack`, since
-            // symlinks in the archive may cause the unpack to break. If this happens, we want
-            // to continue unpacking the other files.
-            // Overall, this is a pretty verbose workaround!
-            let mut archive_error = Ok(());
-            match archive.entries() {
-                Ok(entries) => {
-                    for file in entries {
-                        match file {
-                            Ok(mut f) => {
-                                match f.unpack_in(&paths.lib) {
-                                    Ok(_) => (),
-                                    Err(e) => {
-                                        print_color(
-                                            &format!(
-                                                "Problem unpacking file {:?}: {:?}",
-                                                f.path(),
-                                                e
-                                            ),
-                                            Color::Yellow, // Dark
-                                        );
-                                        let f_path =
-                                            f.path().expect("Problem getting path from archive");
-
-                                        let filename =
-                                            f_path.file_name().expect("Problem getting file name");
-
-                                        // In the `pandocfilters` Python package, the readme file specified in
-                                        // `setup.py` is a symlink, which we can't unwrap, and is required to exist,
-                                        // or the wheel build fails. Workaround here; may apply to other packages as well.
-                                        if filename
-                                            .to_str()
-                                            .unwrap()
-                                            .to_lowercase()
-                                            .contains("readme")
-                                            && fs::File::create(&paths.lib.join(f.path().unwrap()))
-                                                .is_err()
-                                        {
-                                            print_color(
-                                                "Problem creating dummy readme",
-                                                Color::Yellow, // Dark
-                                            );
-                                        }
-                                    }
-                                };
-                            }
-                            Err(e) => {
-                                // We'll continue with this leg, then check if we have a zip file instead.
-                                archive_error = Err(e);
-                            }
-                        }
+            let cache_key = util::wheel_cache::key(
+                name,
+                Some(version),
+                &file_digest_str.to_lowercase(),
+                &util::wheel_cache::py_tag(&sdist_python(paths)),
+            );
+            let (wheel, extracted_parent) =
+                match util::wheel_cache::lookup(&paths.cache, &cache_key) {
+                    Some(wheel) => (wheel, None),
+                    None => {
+                        let (extracted_parent, built) = build_from_source(
+                            name,
+                            version,
+                            filename,
+                            &archive_path,
+                            &archive_file,
+                            paths,
+                        )?;
+                        let wheel = util::wheel_cache::store(&paths.cache, &cache_key, &built);
+                        (wheel, Some(extracted_parent))
                     }
-                }
-                Err(e) => {
-                    // We'll continue with this leg, then check if we have a zip file instead.
-                    archive_error = Err(e);
-                }
-            }
-            // Check if we have a zip file instead.
-            if let Err(e) = archive_error {
-                println!(
-                    "Problem opening the tar.gz archive: {:?}: {:?}, checking if it's a zip...",
-                    &archive_file, e
-                );
-                util::extract_zip(&archive_file, &paths.lib, &None, &Some((name, filename)));
-            }
+                };
 
-            // The archive is now unpacked into a parent folder from the `tar.gz`. Place
-            // its sub-folders directly in the lib folder, and delete the parent.
-            let re = Regex::new(r"^(.*?)(?:\.tar\.gz|\.zip)$").unwrap();
-            let folder_name = re
-                .captures(filename)
-                .expect("Problem matching extracted folder name")
-                .get(1)
-                .unwrap_or_else(|| {
-                    util::abort(&format!(
-                        "Unable to find extracted folder name: {}",
-                        filename
-                    ))
-                })
-                .as_str();
-
-            // todo: This fs_extras move does a full copy. Normal fs lib doesn't include
-            // todo moves, only copies. Figure out how to do a normal move,
-            // todo, to speed this up.
-
-            let extracted_parent = paths.lib.join(folder_name);
-
-            replace_distutils(&extracted_parent.join("setup.py"));
-
-            #[cfg(target_os = "windows")]
-            {
-                let output = Command::new(paths.bin.join("python"))
-                    .current_dir(&extracted_parent)
-                    .args(&["setup.py", "bdist_wheel"])
-                    .output()
-                    .unwrap_or_else(|_| {
-                        panic!(
-                            "Problem running setup.py bdist_wheel in folder: {:?}. Py path: {:?}",
-                            &extracted_parent,
-                            paths.bin.join("python")
-                        )
-                    });
-                util::check_command_output_with(&output, |s| {
-                    panic!(
-                        "running setup.py bdist_wheel in folder {:?}. Py path: {:?}: {}",
-                        &extracted_parent,
-                        paths.bin.join("python"),
-                        s
-                    );
-                });
-            }
-            // The Linux and Mac builds appear to be unable to build wheels due to
-            // missing the ctypes library; revert to system python.
-            #[cfg(target_os = "linux")]
-            {
-                let output = Command::new("python3")
-                    .current_dir(&extracted_parent)
-                    .args(&["setup.py", "bdist_wheel"])
-                    .output()
-                    .unwrap_or_else(|_| {
-                        panic!(
-                            "Problem running setup.py bdist_wheel in folder: {:?}. Py path: {:?}",
-                            &extracted_parent,
-                            paths.bin.join("python")
-                        )
-                    });
-                util::check_command_output_with(&output, |s| {
-                    panic!(
-                        "running setup.py bdist_wheel in folder {:?}. Py path: {:?}: {}",
-                        &extracted_parent,
-                        paths.bin.join("python"),
-                        s
-                    );
-                });
-            }
-            #[cfg(target_os = "macos")]
-            {
-                let output = Command::new("python3")
-                    .current_dir(&extracted_parent)
-                    .args(&["setup.py", "bdist_wheel"])
-                    .output()
-                    .unwrap_or_else(|_| {
-                        panic!(
-                            "Problem running setup.py bdist_wheel in folder: {:?}. Py path: {:?}",
-                            &extracted_parent,
-                            paths.bin.join("python")
-                        )
-                    });
-                util::check_command_output_with(&output, |s| {
-                    panic!(
-                        "running setup.py bdist_wheel in folder {:?}. Py path: {:?}: {}",
-                        &extracted_parent,
-                        paths.bin.join("python"),
-                        s
-                    );
-                });
-            }
-
-            let dist_path = &extracted_parent.join("dist");
-            if !dist_path.exists() {
-                #[cfg(target_os = "windows")]
-                let error = &format!(
-                    "Problem building {} from source. \
-                 This may occur if a package that requires compiling has no wheels available \
-                 for Windows, and the system is missing dependencies required to compile it, \
-                 or if on WSL and installing to a mounted directory.",
-                    name
-                );
-
-                #[cfg(target_os = "linux")]
-                let error = format!(
-                    "Problem building {} from source. \
-                 This may occur if a package that requires compiling has no wheels available \
-                 for this OS and this system is missing dependencies required to compile it.\
-                 Try running `pip install --upgrade wheel`, then try again",
-                    name
-                );
-                #[cfg(target_os = "macos")]
-                let error = format!(
-                    "Problem building {} from source. \
-                 This may occur if a package that requires compiling has no wheels available \
-                 for this OS and this system is missing dependencies required to compile it.
-                 Try running `pip install --upgrade wheel`, then try again",
-                    name
-                );
-
-                util::abort(&error);
-            }
-
-            let built_wheel_filename = util::find_first_file(dist_path)
-                .file_name()
-                .expect("Unable to find built wheel filename")
-                .to_str()
-                .unwrap()
-                .to_owned();
+            let file_created = fs::File::open(&wheel).expect("Can't find created wheel.");
+            let rename = rename.map(|new| module_rename(&file_created, name, new));
+            let size = util::extract_zip(&file_created, &wheel, &paths.lib, &rename, &None);
 
-            let moved_path = paths.lib.join(&built_wheel_filename);
-
-            // todo: Again, try to move vice copy.
-            let options = fs_extra::file::CopyOptions::new();
-            fs_extra::file::move_file(dist_path.join(&built_wheel_filename), &moved_path, &options)
-                .expect("Problem copying wheel built from source");
-
-            let file_created = fs::File::open(&moved_path).expect("Can't find created wheel.");
-            util::extract_zip(&file_created, &paths.lib, &rename, &None);
-
-            // Remove the created and moved wheel
-            if fs::remove_file(moved_path).is_err() {
-                util::abort(&format!(
-                    "Problem removing this downloaded package: {:?}",
-                    &built_wheel_filename
-                ));
-            }
             // Remove the source directeory extracted from the tar.gz file.
-            if fs::remove_dir_all(&extracted_parent).is_err() {
-                util::abort(&format!(
-                    "Problem removing parent folder of this downloaded package: {:?}",
-                    &extracted_parent
-                ));
+            if let Some(extracted_parent) = extracted_parent {
+                if fs::remove_dir_all(&extracted_parent).is_err() {
+                    util::abort(&format!(
+                        "Problem removing parent folder of this downloaded package: {:?}",
+                        &extracted_parent
+                    ));
+                }
             }
+            size?
         }
-    }
+    };
+    drop(extract_timer);
     setup_scripts(name, version, &paths.lib, &paths.entry_pt);
+    util::installed_cache::update(&paths.lib, name, version);
+    util::installed_cache::record_size(&paths.lib, name, version, size);
 
-    Ok(())
+    Ok(size)
 }
 
-pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &Path) {
+pub fn uninstall(name_ins: &str, vers_ins: &Version, paths: &util::Paths) {
+    let lib_path = &paths.lib;
     #[cfg(target_os = "windows")]
     println!(
         "Uninstalling {}: {}...",
@@ -497,39 +763,27 @@ pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &Path) {
         vers_ins.to_string_color()
     );
     #[cfg(target_os = "linux")]
-    println!("🗑 Uninstalling {}: {}...", name_ins, vers_ins.to_string());
+    println!("🗑 Uninstalling {}: {}...", name_ins, vers_ins);
     #[cfg(target_os = "macos")]
-    println!("🗑 Uninstalling {}: {}...", name_ins, vers_ins.to_string());
+    println!("🗑 Uninstalling {}: {}...", name_ins, vers_ins);
 
     // Uninstall the package
     // package folders appear to be lowercase, while metadata keeps the package title's casing.
 
     let dist_info_path = find_dist_info_path(name_ins, vers_ins, lib_path);
-    let egg_info_path = lib_path.join(format!("{}-{}.egg-info", name_ins, vers_ins.to_string()));
+    let egg_info_path = lib_path.join(format!("{}-{}.egg-info", name_ins, vers_ins));
 
     // todo: could top_level.txt be in egg-info too?
-    // Sometimes the folder unpacked to isn't the same name as on pypi. Check for `top_level.txt`.
-    let folder_names = match fs::File::open(dist_info_path.join("top_level.txt")) {
-        Ok(f) => {
-            let mut names = vec![];
-            for line in io::BufReader::new(f).lines().flatten() {
-                names.push(line);
-            }
-            names
-        }
-        Err(_) => vec![name_ins.to_lowercase()],
-    };
-
-    for folder_name in folder_names {
-        if fs::remove_dir_all(lib_path.join(&folder_name)).is_err() {
-            // Some packages include a .py file directly in the lib directory instead of a folder.
-            // Check that if removing the folder fails.
-            if fs::remove_file(lib_path.join(&format!("{}.py", folder_name))).is_err() {
-                print_color(
-                    &format!("Problem uninstalling {} {}", name_ins, vers_ins.to_string(),),
-                    Color::Red, // Dark
-                );
-            }
+    // Sometimes the folder unpacked to isn't the same name as on pypi. Check for `top_level.txt`,
+    // or the files listed in `RECORD`.
+    let record = util::record_paths(&dist_info_path);
+    let entry_points = dist_info_scripts(&dist_info_path);
+    for module in util::top_level_modules(&dist_info_path, name_ins) {
+        if !remove_module(lib_path, &module, &record) {
+            print_color(
+                &format!("Problem uninstalling {} {}", name_ins, vers_ins),
+                Color::Red, // Dark
+            );
         }
     }
 
@@ -553,45 +807,126 @@ pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &Path) {
     }
 
     // Remove the data directory, if it exists.
-    fs::remove_dir_all(lib_path.join(format!("{}-{}.data", name_ins, vers_ins.to_string())))
-        .unwrap_or(());
+    fs::remove_dir_all(lib_path.join(format!("{}-{}.data", name_ins, vers_ins))).unwrap_or(());
 
     // Remove console scripts.
-    remove_scripts(&[name_ins.into()], &lib_path.join("../bin"));
+    remove_scripts(name_ins, &entry_points, &paths.entry_pt);
+    util::installed_cache::update(lib_path, name_ins, vers_ins);
+}
+
+/// Remove an installed module: A package folder, a single `.py` file, or a compiled extension.
+/// Namespace packages, ie folders without `__init__.py`, may be shared with other packages, so
+/// we only remove the files `RECORD` lists in them.
+fn remove_module(lib_path: &Path, module: &str, record: &[String]) -> bool {
+    let dir = lib_path.join(module);
+    if dir.is_dir() {
+        if dir.join("__init__.py").exists() || record.is_empty() {
+            return fs::remove_dir_all(dir).is_ok();
+        }
+        let prefix = format!("{}/", module);
+        for path in record.iter().filter(|p| p.starts_with(&prefix)) {
+            fs::remove_file(lib_path.join(path)).ok();
+        }
+        remove_empty_dirs(&dir);
+        return true;
+    }
+
+    let mut removed = false;
+    if let Ok(entries) = fs::read_dir(lib_path) {
+        for entry in entries.flatten() {
+            let filename = entry.file_name().to_string_lossy().into_owned();
+            let is_module_file = filename.starts_with(&format!("{}.", module))
+                && [".py", ".so", ".pyd"]
+                    .iter()
+                    .any(|ext| filename.ends_with(ext));
+            if is_module_file && fs::remove_file(entry.path()).is_ok() {
+                removed = true;
+            }
+        }
+    }
+    // Its bytecode, eg `__pycache__/six.cpython-311.pyc`, would keep the old code importable.
+    let cache = lib_path.join("__pycache__");
+    if let Ok(entries) = fs::read_dir(&cache) {
+        for entry in entries.flatten() {
+            let filename = entry.file_name().to_string_lossy().into_owned();
+            if filename.starts_with(&format!("{}.", module)) && filename.ends_with(".pyc") {
+                fs::remove_file(entry.path()).ok();
+            }
+        }
+    }
+    // This fails if other modules' bytecode is there, which is what we want.
+    fs::remove_dir(cache).ok();
+    removed
+}
+
+/// Remove folders left empty after uninstalling, along with their bytecode caches.
+fn remove_empty_dirs(dir: &Path) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                if entry.file_name() == "__pycache__" {
+                    fs::remove_dir_all(&path).ok();
+                } else {
+                    remove_empty_dirs(&path);
+                }
+            }
+        }
+    }
+    // This fails if the folder isn't empty, which is what we want.
+    fs::remove_dir(dir).ok();
+}
+
+/// Where a module is installed: its package folder, or for modules that are a single file,
+/// eg `six`, its `.py` file.
+pub fn module_path(lib_path: &Path, module: &str) -> PathBuf {
+    let dir = lib_path.join(module);
+    let file = lib_path.join(format!("{}.py", module));
+    if !dir.is_dir() && file.is_file() {
+        file
+    } else {
+        dir
+    }
 }
 
-/// Rename files in a package. Assume we already renamed the folder, ie during installation.
+/// Rename files in a package, or a single-file module. Assume we already renamed the folder or
+/// file, ie during installation.
 pub fn rename_package_files(top_path: &Path, old: &str, new: &str) {
+    if top_path.is_file() {
+        rename_in_file(top_path, old, new);
+        return;
+    }
     for entry in fs::read_dir(top_path).expect("Problem reading renamed package path") {
         let entry = entry.expect("Problem reading file while renaming");
         let path = entry.path();
 
         if path.is_dir() {
             rename_package_files(&path, old, new);
-            continue;
+        } else if path.is_file() {
+            rename_in_file(&path, old, new);
         }
+    }
+}
 
-        if !path.is_file() {
-            continue;
-        }
-        if path.extension().is_none() || path.extension().unwrap() != "py" {
-            continue;
-        }
+/// Rename imports in a Python file.
+fn rename_in_file(path: &Path, old: &str, new: &str) {
+    if path.extension().is_none() || path.extension().unwrap() != "py" {
+        return;
+    }
 
-        let mut data = fs::read_to_string(&path).expect("Problem reading file while renaming");
+    let mut data = fs::read_to_string(path).expect("Problem reading file while renaming");
 
-        // todo: More flexible with regex?
-        data = data.replace(
-            &format!("from {} import", old),
-            &format!("from {} import", new),
-        );
-        data = data.replace(&format!("from {}.", old), &format!("from {}.", new));
-        data = data.replace(&format!("import {}", old), &format!("import {}", new));
-        // Todo: Is this one too general? Supersedes the first. Needed for things like `add_newdoc('numpy.core.multiarray...`
-        data = data.replace(&format!("{}.", old), &format!("{}.", new));
+    // todo: More flexible with regex?
+    data = data.replace(
+        &format!("from {} import", old),
+        &format!("from {} import", new),
+    );
+    data = data.replace(&format!("from {}.", old), &format!("from {}.", new));
+    data = data.replace(&format!("import {}", old), &format!("import {}", new));
+    // Todo: Is this one too general? Supersedes the first. Needed for things like `add_newdoc('numpy.core.multiarray...`
+    data = data.replace(&format!("{}.", old), &format!("{}.", new));
 
-        fs::write(path, data).expect("Problem writing file while renaming");
-    }
+    fs::write(path, data).expect("Problem writing file while renaming");
 }
 
 /// Rename metadata files.
@@ -607,6 +942,97 @@ pub fn rename_metadata(path: &Path, _old: &str, new: &str) {
     // todo: Modify other files like entry_points.txt, perhaps.
 }
 
+/// The folder `git clone` puts a repo in, eg `dateutil` for
+/// `https://github.com/dateutil/dateutil.git`. This may not match the package name.
+pub(crate) fn repo_folder(url: &str) -> Option<String> {
+    let last = url.trim_end_matches('/').rsplit(['/', ':']).next()?;
+    let folder = last.strip_suffix(".git").unwrap_or(last);
+    if folder.is_empty() {
+        None
+    } else {
+        Some(folder.to_owned())
+    }
+}
+
+/// Split pip's `#subdirectory=` fragment from a git url, eg
+/// `https://github.com/org/mono#subdirectory=libs/client`, for packages that aren't at the root
+/// of their repo. Other fragment keys, like `egg`, are ignored.
+pub fn split_subdirectory(url: &str) -> (&str, Option<&str>) {
+    match url.split_once('#') {
+        Some((base, fragment)) => {
+            let subdirectory = fragment
+                .split('&')
+                .find_map(|kv| kv.strip_prefix("subdirectory="))
+                .map(|s| s.trim_matches('/'))
+                .filter(|s| !s.is_empty());
+            (base, subdirectory)
+        }
+        None => (url, None),
+    }
+}
+
+/// Split a branch, tag, or commit from a git url, as pip writes them, eg
+/// `https://github.com/org/repo@v1.2`. The user in `git@github.com:org/repo` isn't one.
+pub fn split_git_ref(url: &str) -> (&str, Option<&str>) {
+    let path_start = match url.find("://") {
+        Some(i) => url[i + 3..].find('/').map_or(url.len(), |j| i + 3 + j),
+        None => url.find(':').unwrap_or(0),
+    };
+    match url[path_start..].rfind('@') {
+        Some(i) if path_start + i + 1 < url.len() => {
+            (&url[..path_start + i], Some(&url[path_start + i + 1..]))
+        }
+        _ => (url, None),
+    }
+}
+
+/// A git req passed on the command line, eg `git+https://github.com/org/repo@v1.2` or
+/// `git@github.com:org/repo.git`. It's named after an `egg=` fragment if there is one, or else
+/// the repo, until its metadata says otherwise. `None` if it isn't a git url.
+pub fn git_req_from_arg(arg: &str) -> Option<Req> {
+    let url = match arg.strip_prefix("git+") {
+        Some(url) => url,
+        None if arg.starts_with("git@") => arg,
+        None => return None,
+    };
+    let egg = url.split_once('#').and_then(|(_, fragment)| {
+        fragment
+            .split('&')
+            .find_map(|kv| kv.strip_prefix("egg="))
+            .filter(|e| !e.is_empty())
+    });
+    let name = match egg {
+        Some(egg) => egg.to_owned(),
+        None => repo_folder(split_git_ref(split_subdirectory(url).0).0)?,
+    };
+    Some(Req {
+        git: Some(url.to_owned()),
+        ..Req::new(name, vec![])
+    })
+}
+
+/// The arguments to pass to Python to build a wheel into `dist`, depending on the project's
+/// build files. `None` if it has none we can build from.
+fn wheel_build_args(project_path: &Path) -> Option<&'static [&'static str]> {
+    if project_path.join("setup.py").exists() {
+        Some(&["setup.py", "bdist_wheel"])
+    } else if project_path.join("pyproject.toml").exists() {
+        // Let pip run the build backend the project specifies, eg for `src/` layouts configured
+        // only in `pyproject.toml`.
+        Some(&[
+            "-m",
+            "pip",
+            "wheel",
+            ".",
+            "--no-deps",
+            "--wheel-dir",
+            "dist",
+        ])
+    } else {
+        None
+    }
+}
+
 /// Clone a git repo of a Python package, and build/install a wheel from it.
 /// Or do the same, but with a path instead of git.
 pub fn download_and_install_git(
@@ -619,77 +1045,707 @@ pub fn download_and_install_git(
         fs::create_dir_all(git_path).expect("Problem creating git path");
     }
 
-    let folder_name = util::standardize_name(name); // todo: Will this always work?
-                                                    //    match url {
-                                                    //        GitPath::Git(url) => {
-                                                    // Download the repo into the pyflow folder.
-                                                    // todo: Handle checking if it's current and correct; not just a matching folder
-                                                    // todo name.
-    if !&git_path.join(&folder_name).exists() && commands::download_git_repo(url, git_path).is_err()
-    {
-        util::abort(&format!("Problem cloning this repo: {}", url));
-    } // todo to keep dl small while troubleshooting.
-      //        }
-      //        GitPath::Path(path) => {
-      //            let f = &git_path.join(&folder_name);
-      //            if !&f.exists() {
-      //                fs::create_dir(f).expect("Problem creating dir for a path dependency");
-      //                let options = fs_extra::dir::CopyOptions::new();
-      //                fs_extra::dir::copy(PathBuf::from(path), &git_path, &options)
-      //                    .expect("Problem copying path requirement to lib folder");
-      //            }
-      //        }
-      //}
-
-    // Build a wheel from the repo
-    let output = Command::new(paths.bin.join("python"))
-        // We assume that the module code is in the repo's immediate subfolder that has
-        // the package's name.
-        .current_dir(&git_path.join(&folder_name))
-        .args(&["setup.py", "bdist_wheel"])
-        .output()
-        .expect("Problem running setup.py bdist_wheel");
-    util::check_command_output(&output, "running setup.py bdist_wheel");
-
-    let archive_path = util::find_first_file(&git_path.join(folder_name).join("dist"));
-    let filename = archive_path
-        .file_name()
-        .expect("Problem pulling filename from archive path");
-
-    // We've built the wheel; now move it into the lib path, as we would for a wheel download
-    // from Pypi.
-    let options = fs_extra::file::CopyOptions::new();
-    fs_extra::file::move_file(&archive_path, paths.lib.join(&filename), &options)
-        .expect("Problem moving the wheel.");
-
-    let archive_path = &paths.lib.join(&filename);
-    let archive_file = util::open_archive(archive_path);
-
-    util::extract_zip(&archive_file, &paths.lib, &None, &None);
-
-    // Use the wheel's name to find the dist-info path, to avoid the chicken-egg scenario
-    // of need the dist-info path to find the version.
-    let re = Regex::new(r"^(.*?)-(.*?)-.*$").unwrap();
-    let dist_info = if let Some(caps) = re.captures(filename.to_str().unwrap()) {
-        format!(
-            "{}-{}.dist-info",
-            caps.get(1).unwrap().as_str(),
-            caps.get(2).unwrap().as_str()
-        )
-    } else {
-        util::abort("Unable to find the dist info path from wheel filename")
+    let (url, subdirectory) = split_subdirectory(url);
+    let (url, git_ref) = split_git_ref(url);
+    let folder_name = repo_folder(url).unwrap_or_else(|| util::standardize_name(name));
+    //    match url {
+    //        GitPath::Git(url) => {
+    // Download the repo into the pyflow folder.
+    // todo: Handle checking if it's current and correct; not just a matching folder
+    // todo name.
+    if !&git_path.join(&folder_name).exists() {
+        if let Err(e) = commands::download_git_repo(url, git_path) {
+            util::abort(&format!("Problem cloning {}:\n{}", url, e));
+        }
+    }
+    if let Some(git_ref) = git_ref {
+        if let Err(e) = commands::git_checkout(&git_path.join(&folder_name), git_ref) {
+            util::abort(&format!(
+                "Problem checking out `{}` from {}:\n{}",
+                git_ref, url, e
+            ));
+        }
+    }
+    //        }
+    //        GitPath::Path(path) => {
+    //            let f = &git_path.join(&folder_name);
+    //            if !&f.exists() {
+    //                fs::create_dir(f).expect("Problem creating dir for a path dependency");
+    //                let options = fs_extra::dir::CopyOptions::new();
+    //                fs_extra::dir::copy(PathBuf::from(path), &git_path, &options)
+    //                    .expect("Problem copying path requirement to lib folder");
+    //            }
+    //        }
+    //}
+
+    let project_path = match subdirectory {
+        Some(sub) => git_path.join(&folder_name).join(sub),
+        None => git_path.join(&folder_name),
     };
+    let location = match subdirectory {
+        Some(sub) => format!("the `{}` directory of {}", sub, url),
+        None => format!("the root of {}", url),
+    };
+    if !project_path.is_dir() {
+        util::abort(&format!("Can't find {}", location));
+    }
+    let build_args = wheel_build_args(&project_path).unwrap_or_else(|| {
+        util::abort(&format!(
+            "Can't build {}: there's no `setup.py` or `pyproject.toml` in {}. If the package is \
+             in a subdirectory of the repo, set it with `subdirectory = \"path/to/package\"`.",
+            name, location
+        ))
+    });
 
-    let metadata = util::parse_metadata(&paths.lib.join(dist_info).join("METADATA")); // todo temp!
+    // Reuse a wheel built from this commit, if we have one.
+    let cache_key = commands::git_head(&git_path.join(&folder_name)).map(|head| {
+        let source = match subdirectory {
+            Some(sub) => format!("{}#{}", head, sub),
+            None => head,
+        };
+        let py_tag = util::wheel_cache::py_tag(&paths.bin.join("python"));
+        util::wheel_cache::key(name, None, &source, &py_tag)
+    });
+    let cached = cache_key
+        .as_ref()
+        .and_then(|k| util::wheel_cache::lookup(&paths.cache, k));
+
+    let archive_path = match cached {
+        Some(wheel) => wheel,
+        None => {
+            // Build a wheel from the repo. Remove wheels from earlier builds, so we find this one.
+            let dist_path = project_path.join("dist");
+            if dist_path.exists() {
+                fs::remove_dir_all(&dist_path).expect("Problem removing an old dist directory");
+            }
+            let label = format!("building a wheel from {}", location);
+            if let Err(e) = util::source_build::run(
+                &paths.bin.join("python"),
+                &project_path,
+                build_args,
+                &label,
+            ) {
+                util::abort(&e);
+            }
 
-    setup_scripts(name, &metadata.version, &paths.lib, &paths.entry_pt);
+            let built = util::find_first_file(&dist_path);
+            match &cache_key {
+                Some(k) => util::wheel_cache::store(&paths.cache, k, &built),
+                None => built,
+            }
+        }
+    };
 
-    // Remove the created and moved wheel
-    if fs::remove_file(&archive_path).is_err() {
-        util::abort(&format!(
-            "Problem removing this wheel built from a git repo: {:?}",
-            archive_path
-        ));
+    // The package's name and version come from what the project builds; we don't guess them
+    // from the repo's layout.
+    let metadata = util::wheel_metadata(&archive_path).unwrap_or_else(|e| util::abort(&e.details));
+    util::print_metadata_warnings(&metadata);
+    if !util::compare_names(&metadata.name, name) {
+        util::print_color(
+            &format!(
+                "{} builds a package named {}, not {}",
+                location, metadata.name, name
+            ),
+            Color::Yellow,
+        );
     }
+
+    // Install from the wheel where it is; it may be the cached copy, which we keep.
+    let archive_file = util::open_archive(&archive_path);
+    let size = util::extract_zip(&archive_file, &archive_path, &paths.lib, &None, &None)
+        .unwrap_or_else(|e| util::abort(&util::fs_errors::describe(&e, None)));
+    util::installed_cache::record_size(&paths.lib, &metadata.name, &metadata.version, size);
+
+    setup_scripts(
+        &metadata.name,
+        &metadata.version,
+        &paths.lib,
+        &paths.entry_pt,
+    );
     metadata
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn path_dep_script_runs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let dep = dir.join("mytool");
+        let bin = dir.join("bin");
+        fs::create_dir_all(dep.join("mytool")).unwrap();
+        fs::create_dir_all(dep.join("mytool-0.1.0.dist-info")).unwrap();
+        fs::write(
+            dep.join("mytool/__init__.py"),
+            "import sys\n\ndef main():\n    open(sys.argv[1], 'w').write('ran')\n",
+        )
+        .unwrap();
+        fs::write(
+            dep.join("mytool-0.1.0.dist-info/entry_points.txt"),
+            "[console_scripts]\nmytool = mytool:main\n\n[other]\nignored = mytool:main\n",
+        )
+        .unwrap();
+
+        setup_path_scripts(&dep, &bin);
+        let script = bin.join("mytool");
+        assert!(script.exists());
+        assert!(!bin.join("ignored").exists());
+        assert_eq!(
+            script_module(&fs::read_to_string(&script).unwrap()),
+            Some("mytool".into())
+        );
+
+        // Run it with whichever Python is on the `PATH`, if any.
+        let python = ["python3", "python"]
+            .into_iter()
+            .find(|p| commands::find_py_version(p).is_some());
+        if let Some(python) = python {
+            let out = dir.join("out.txt");
+            let status = std::process::Command::new(python)
+                .arg(&script)
+                .arg(&out)
+                .env("PYTHONPATH", &dep)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            assert_eq!(fs::read_to_string(&out).unwrap(), "ran");
+        }
+    }
+
+    #[test]
+    fn legacy_scripts_migrated() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let paths = util::Paths {
+            bin: dir.join(".venv/bin"),
+            lib: dir.join("lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        };
+        fs::create_dir_all(&paths.bin).unwrap();
+        fs::create_dir_all(paths.lib.join("black")).unwrap();
+        make_script(&paths.bin.join("black"), "black", "black", "patched_main");
+        make_script(&paths.bin.join("wheel"), "wheel", "wheel.cli", "main");
+        fs::write(paths.bin.join("python"), "").unwrap();
+
+        migrate_scripts(&paths);
+
+        assert!(paths.entry_pt.join("black").exists());
+        assert!(!paths.bin.join("black").exists());
+        assert!(paths.bin.join("wheel").exists());
+        assert!(paths.bin.join("python").exists());
+    }
+
+    /// Write a wheel with the given files, eg for `python-dateutil`, whose module is `dateutil`.
+    fn make_wheel(path: &Path, files: &[(&str, &str)]) -> File {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for (name, contents) in files {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        File::open(path).unwrap()
+    }
+
+    #[test]
+    fn wheel_modules_from_top_level_and_record() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+
+        let dateutil = make_wheel(
+            &dir.join("python_dateutil-2.8.2-py2.py3-none-any.whl"),
+            &[
+                ("dateutil/__init__.py", ""),
+                ("dateutil/parser.py", "from dateutil import tz\n"),
+                (
+                    "python_dateutil-2.8.2.dist-info/top_level.txt",
+                    "dateutil\n",
+                ),
+                (
+                    "python_dateutil-2.8.2.dist-info/RECORD",
+                    "dateutil/__init__.py,,\n",
+                ),
+            ],
+        );
+        assert_eq!(util::wheel_modules(&dateutil), vec!["dateutil"]);
+
+        // No `top_level.txt`, and vendored libraries alongside the module.
+        let pillow = make_wheel(
+            &dir.join("Pillow-10.0.0-cp311-cp311-manylinux_2_28_x86_64.whl"),
+            &[
+                ("PIL/__init__.py", ""),
+                ("PIL/Image.py", ""),
+                ("pillow.libs/libjpeg-1.so", ""),
+                (
+                    "Pillow-10.0.0.dist-info/RECORD",
+                    "PIL/__init__.py,,\nPIL/Image.py,,\npillow.libs/libjpeg-1.so,,\n",
+                ),
+            ],
+        );
+        assert_eq!(util::wheel_modules(&pillow), vec!["PIL"]);
+    }
+
+    #[test]
+    fn renamed_wheel_uses_module_name() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        fs::create_dir_all(dir.join("lib")).unwrap();
+
+        let wheel = make_wheel(
+            &dir.join("python_dateutil-2.8.2-py2.py3-none-any.whl"),
+            &[
+                ("dateutil/__init__.py", ""),
+                ("dateutil/parser.py", ""),
+                (
+                    "python_dateutil-2.8.2.dist-info/top_level.txt",
+                    "dateutil\n",
+                ),
+            ],
+        );
+        let rename = module_rename(&wheel, "python-dateutil", "python-dateutil_renamed_x");
+        assert_eq!(
+            rename,
+            ("dateutil".into(), "python_dateutil_renamed_x".into())
+        );
+
+        util::extract_zip(
+            &wheel,
+            &dir.join("python_dateutil-2.8.2-py2.py3-none-any.whl"),
+            &dir.join("lib"),
+            &Some(rename),
+            &None,
+        )
+        .unwrap();
+        assert!(dir.join("lib/python_dateutil_renamed_x/parser.py").exists());
+        assert!(!dir.join("lib/dateutil").exists());
+    }
+
+    #[rstest::rstest(
+        url,
+        expected,
+        case("https://github.com/dateutil/dateutil.git", Some("dateutil")),
+        case("https://github.com/pallets/flask/", Some("flask")),
+        case("git@github.com:psf/black.git", Some("black")),
+        case("", None)
+    )]
+    fn git_clone_folder(url: &str, expected: Option<&str>) {
+        assert_eq!(repo_folder(url).as_deref(), expected);
+    }
+
+    #[rstest::rstest(
+        url,
+        expected,
+        case("https://github.com/org/mono", ("https://github.com/org/mono", None)),
+        case(
+            "https://github.com/org/mono#subdirectory=libs/client/",
+            ("https://github.com/org/mono", Some("libs/client"))
+        ),
+        case(
+            "https://github.com/org/mono.git#egg=client&subdirectory=client",
+            ("https://github.com/org/mono.git", Some("client"))
+        )
+    )]
+    fn git_subdirectory_split(url: &str, expected: (&str, Option<&str>)) {
+        assert_eq!(split_subdirectory(url), expected);
+        assert_eq!(
+            repo_folder(split_subdirectory(url).0).as_deref(),
+            Some("mono")
+        );
+    }
+
+    #[rstest::rstest(
+        url,
+        expected,
+        case("https://github.com/org/repo", ("https://github.com/org/repo", None)),
+        case(
+            "https://github.com/org/repo.git@v1.2",
+            ("https://github.com/org/repo.git", Some("v1.2"))
+        ),
+        case(
+            "ssh://git@github.com/org/repo@main",
+            ("ssh://git@github.com/org/repo", Some("main"))
+        ),
+        case("git@github.com:org/repo.git", ("git@github.com:org/repo.git", None)),
+        case(
+            "git@github.com:org/repo.git@1a2b3c",
+            ("git@github.com:org/repo.git", Some("1a2b3c"))
+        )
+    )]
+    fn git_ref_split(url: &str, expected: (&str, Option<&str>)) {
+        assert_eq!(split_git_ref(url), expected);
+    }
+
+    #[rstest::rstest(
+        arg,
+        name,
+        git,
+        case(
+            "git+https://github.com/org/repo.git@v1.2",
+            "repo",
+            "https://github.com/org/repo.git@v1.2"
+        ),
+        case(
+            "git+https://github.com/org/mono@main#egg=client&subdirectory=client",
+            "client",
+            "https://github.com/org/mono@main#egg=client&subdirectory=client"
+        ),
+        case("git@github.com:org/repo.git", "repo", "git@github.com:org/repo.git")
+    )]
+    fn git_req_from_cli(arg: &str, name: &str, git: &str) {
+        let req = git_req_from_arg(arg).unwrap();
+        assert_eq!(req.name, name);
+        assert_eq!(req.git.as_deref(), Some(git));
+        assert!(git_req_from_arg("requests").is_none());
+    }
+
+    #[test]
+    fn wheel_built_from_setup_py_or_pyproject() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        assert_eq!(wheel_build_args(&dir), None);
+
+        fs::write(dir.join("pyproject.toml"), "[build-system]\n").unwrap();
+        assert_eq!(wheel_build_args(&dir).unwrap()[..2], ["-m", "pip"]);
+
+        // `setup.py` takes precedence, as it always has.
+        fs::write(dir.join("setup.py"), "").unwrap();
+        assert_eq!(
+            wheel_build_args(&dir),
+            Some(&["setup.py", "bdist_wheel"][..])
+        );
+    }
+
+    #[test]
+    fn setup_py_added_for_setup_cfg_only_sdists() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("pyflow_sdist_layouts");
+        assert_eq!(
+            prepare_setup(&dir).unwrap_err(),
+            "it didn't unpack into a `pyflow_sdist_layouts` folder"
+        );
+
+        // Eg a flat module, with only a `PKG-INFO`.
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("PKG-INFO"), "").unwrap();
+        fs::write(dir.join("flat.py"), "").unwrap();
+        assert_eq!(
+            prepare_setup(&dir).unwrap_err(),
+            "it has PKG-INFO, docs/, flat.py"
+        );
+        assert!(!dir.join("setup.py").exists());
+
+        fs::write(dir.join("setup.cfg"), "[metadata]\nname = flat\n").unwrap();
+        prepare_setup(&dir).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("setup.py")).unwrap(),
+            SETUP_SHIM
+        );
+
+        fs::write(
+            dir.join("setup.py"),
+            "from distutils.core import setup\nsetup()\n",
+        )
+        .unwrap();
+        prepare_setup(&dir).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("setup.py")).unwrap(),
+            "from setuptools import setup\nsetup()\n"
+        );
+    }
+
+    #[test]
+    fn dist_info_found_with_other_casing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        fs::create_dir_all(dir.join("Django-4.2.0.dist-info")).unwrap();
+        fs::create_dir_all(dir.join("zope.interface-6.1.dist-info")).unwrap();
+
+        assert_eq!(
+            find_dist_info_path("django", &Version::new(4, 2, 0), &dir),
+            dir.join("Django-4.2.0.dist-info")
+        );
+        assert_eq!(
+            find_dist_info_path("zope_interface", &Version::new(6, 1, 0), &dir),
+            dir.join("zope.interface-6.1.dist-info")
+        );
+    }
+
+    #[test]
+    fn dist_info_found_for_calendar_and_four_part_versions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        fs::create_dir_all(dir.join("pytz-2023.3.post1.dist-info")).unwrap();
+        fs::create_dir_all(dir.join("synthetic-1.2.3.4.dist-info")).unwrap();
+
+        let version = |v: &str| v.parse::<Version>().unwrap();
+        assert_eq!(
+            find_dist_info("pytz", &version("2023.3.post1"), &dir),
+            Some(dir.join("pytz-2023.3.post1.dist-info"))
+        );
+        assert_eq!(find_dist_info("pytz", &version("2023.3"), &dir), None);
+        assert_eq!(
+            find_dist_info("synthetic", &version("1.2.3.4"), &dir),
+            Some(dir.join("synthetic-1.2.3.4.dist-info"))
+        );
+        assert_eq!(find_dist_info("synthetic", &version("1.2.4"), &dir), None);
+    }
+
+    #[test]
+    fn installed_size_recorded() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let paths = util::Paths {
+            bin: dir.join(".venv/bin"),
+            lib: dir.join("__pypackages__/3.11/lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        };
+        fs::create_dir_all(&paths.lib).unwrap();
+        fs::create_dir_all(paths.cache.join(util::paths::ARCHIVE_DIR)).unwrap();
+        let files = [
+            ("fixture.py", "x = 1\n"),
+            ("fixture-1.0.dist-info/top_level.txt", "fixture\n"),
+            (
+                "fixture-1.0.dist-info/METADATA",
+                "Name: fixture\nVersion: 1.0\n",
+            ),
+        ];
+        let expected: usize = files.iter().map(|(_, contents)| contents.len()).sum();
+        // Already in the cache, so it's not downloaded.
+        let filename = "fixture-1.0-py3-none-any.whl";
+        make_wheel(&util::paths::archive_path(&paths.cache, filename), &files);
+        let wheel = fs::read(util::paths::archive_path(&paths.cache, filename)).unwrap();
+        let digest = data_encoding::HEXUPPER.encode(sha256_digest(&wheel[..]).unwrap().as_ref());
+        assert!(util::find_installed(&paths.lib).is_empty());
+
+        let version = Version::new(1, 0, 0);
+        let size = download_and_install_package(
+            "fixture",
+            &version,
+            "https://example.com/fixture-1.0-py3-none-any.whl",
+            filename,
+            &digest,
+            &paths,
+            PackageType::Wheel,
+            &None,
+        )
+        .unwrap();
+        assert_eq!(size, expected as u64);
+        assert_eq!(
+            util::installed_cache::size(&paths.lib, "fixture", &version),
+            Some(size)
+        );
+        // Kept when the list is read again.
+        assert_eq!(util::find_installed(&paths.lib).len(), 1);
+        assert_eq!(
+            util::installed_cache::size(&paths.lib, "Fixture", &version),
+            Some(size)
+        );
+
+        uninstall("fixture", &version, &paths);
+        util::find_installed(&paths.lib);
+        assert_eq!(
+            util::installed_cache::size(&paths.lib, "fixture", &version),
+            None
+        );
+    }
+
+    #[test]
+    fn single_file_module_renamed_and_uninstalled() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let paths = util::Paths {
+            bin: dir.join(".venv/bin"),
+            lib: dir.join("__pypackages__/3.11/lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        };
+        fs::create_dir_all(paths.cache.join(util::paths::ARCHIVE_DIR)).unwrap();
+        let install = |version: &str, rename: Option<(u32, String)>| {
+            // Already in the cache, so it's not downloaded.
+            let filename = format!("six-{}-py2.py3-none-any.whl", version);
+            let dist_info = format!("six-{}.dist-info", version);
+            make_wheel(
+                &util::paths::archive_path(&paths.cache, &filename),
+                &[
+                    ("six.py", "import six.moves\nfrom six import PY3\n"),
+                    (&format!("{}/top_level.txt", dist_info), "six\n"),
+                ],
+            );
+            let version = version.parse::<Version>().unwrap();
+            let url = format!("https://example.com/{}", filename);
+            let package_type = PackageType::Wheel;
+            download_and_install_package(
+                "six",
+                &version,
+                &url,
+                &filename,
+                "",
+                &paths,
+                package_type,
+                &rename,
+            )
+            .unwrap();
+            version
+        };
+
+        let new = install("1.16.0", None);
+        let old = install("1.10.0", Some((1, "six_renamed_oldlib_1_0_0".into())));
+        assert!(paths.lib.join("six.py").is_file());
+        let renamed = module_path(&paths.lib, "six_renamed_oldlib_1_0_0");
+        assert_eq!(renamed, paths.lib.join("six_renamed_oldlib_1_0_0.py"));
+        assert_eq!(module_path(&paths.lib, "absent"), paths.lib.join("absent"));
+
+        rename_package_files(&renamed, "six", "six_renamed_oldlib_1_0_0");
+        rename_metadata(
+            &paths.lib.join("six-1.10.0.dist-info"),
+            "six",
+            "six_renamed_oldlib_1_0_0",
+        );
+        assert_eq!(
+            fs::read_to_string(&renamed).unwrap(),
+            "import six_renamed_oldlib_1_0_0.moves\nfrom six_renamed_oldlib_1_0_0 import PY3\n"
+        );
+        // The other version is untouched.
+        assert!(fs::read_to_string(paths.lib.join("six.py"))
+            .unwrap()
+            .starts_with("import six.moves"));
+
+        let cache = paths.lib.join("__pycache__");
+        fs::create_dir_all(&cache).unwrap();
+        fs::write(cache.join("six.cpython-311.pyc"), "").unwrap();
+        fs::write(cache.join("six_renamed_oldlib_1_0_0.cpython-311.pyc"), "").unwrap();
+
+        uninstall("six", &new, &paths);
+        assert!(!paths.lib.join("six.py").exists());
+        assert!(!cache.join("six.cpython-311.pyc").exists());
+        assert!(renamed.exists());
+        assert!(cache
+            .join("six_renamed_oldlib_1_0_0.cpython-311.pyc")
+            .exists());
+
+        uninstall("six", &old, &paths);
+        assert!(!renamed.exists());
+        assert!(!cache.exists());
+    }
+
+    #[test]
+    fn downloads_go_to_relocated_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let cache = dir.join("cache");
+        std::env::set_var(util::paths::CACHE_DIR_VAR, &cache);
+        let dirs = util::paths::get_paths();
+        std::env::remove_var(util::paths::CACHE_DIR_VAR);
+        assert_eq!(dirs.cache, cache);
+
+        let filename = "six-1.16.0-py2.py3-none-any.whl";
+        make_wheel(&dir.join(filename), &[("six.py", "")]);
+        let wheel = fs::read(dir.join(filename)).unwrap();
+        let digest = data_encoding::HEXUPPER.encode(sha256_digest(&wheel[..]).unwrap().as_ref());
+
+        // Serve the wheel for one request.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/{}", listener.local_addr().unwrap(), filename);
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            io::Read::read(&mut stream, &mut [0; 4096]).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                wheel.len()
+            )
+            .unwrap();
+            stream.write_all(&wheel).unwrap();
+        });
+
+        let paths = util::Paths {
+            bin: dir.join(".venv/bin"),
+            lib: dir.join("lib"),
+            entry_pt: dir.join("bin"),
+            cache: dirs.cache,
+        };
+        download_and_install_package(
+            "six",
+            &Version::new(1, 16, 0),
+            &url,
+            filename,
+            &digest,
+            &paths,
+            PackageType::Wheel,
+            &None,
+        )
+        .unwrap();
+        server.join().unwrap();
+        assert!(util::paths::archive_path(&cache, filename).exists());
+        assert!(paths.lib.join("six.py").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sdist_built_once() {
+        if commands::find_py_version("python3").is_none() {
+            return;
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let paths = util::Paths {
+            bin: dir.join(".venv/bin"),
+            lib: dir.join("lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        };
+        fs::create_dir_all(paths.cache.join(util::paths::ARCHIVE_DIR)).unwrap();
+        let marker = dir.join("builds");
+
+        // A setup.py that records each build, and writes the wheel itself.
+        let setup = format!(
+            "import os, zipfile\n\
+             open({:?}, 'a').write('built\\n')\n\
+             os.makedirs('dist', exist_ok=True)\n\
+             with zipfile.ZipFile('dist/fixture-1.0-py3-none-any.whl', 'w') as z:\n    \
+             z.writestr('fixture.py', 'VALUE = 1\\n')\n    \
+             z.writestr('fixture-1.0.dist-info/METADATA', \
+             'Metadata-Version: 2.1\\nName: fixture\\nVersion: 1.0\\n')\n    \
+             z.writestr('fixture-1.0.dist-info/RECORD', '')\n",
+            marker.to_str().unwrap()
+        );
+        let filename = "fixture-1.0.tar.gz";
+        let sdist = File::create(util::paths::archive_path(&paths.cache, filename)).unwrap();
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            sdist,
+            flate2::Compression::fast(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(setup.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "fixture-1.0/setup.py", setup.as_bytes())
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+        let sdist = fs::read(util::paths::archive_path(&paths.cache, filename)).unwrap();
+        let digest = data_encoding::HEXUPPER.encode(sha256_digest(&sdist[..]).unwrap().as_ref());
+
+        for _ in 0..2 {
+            download_and_install_package(
+                "fixture",
+                &Version::new(1, 0, 0),
+                "https://example.com/fixture-1.0.tar.gz",
+                filename,
+                &digest,
+                &paths,
+                PackageType::Source,
+                &None,
+            )
+            .unwrap();
+            assert!(paths.lib.join("fixture.py").exists());
+            assert!(!paths.lib.join("fixture-1.0").exists());
+        }
+        assert_eq!(fs::read_to_string(&marker).unwrap(), "built\n");
+    }
+}
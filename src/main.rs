@@ -1,47 +1,27 @@
 use crate::actions::run;
-use crate::cli_options::{ExternalCommand, ExternalSubcommands, Opt, SubCommand};
+use crate::cli_options::{
+    ConfigCommand, ExternalCommand, ExternalSubcommands, Opt, ScriptsCommand, SubCommand,
+};
+use crate::dep_resolution::Resolution;
 use crate::dep_types::{Lock, Package, Req, Version};
-use crate::pyproject::{Config, CFG_FILENAME};
+use crate::pyproject::{Config, CFG_FILENAME, LOCK_FILENAME};
 use crate::util::abort;
 use crate::util::deps::sync;
 
-<<<<<<< HEAD
-use std::{
-    collections::HashMap,
-    env,
-    error::Error,
-    fs,
-    io::{BufRead, BufReader},
-    path::{Path, PathBuf},
-    str::FromStr,
-    sync::{Arc, RwLock},
-};
-
-use regex::Regex;
-use serde::Deserialize;
-use structopt::StructOpt;
-use termcolor::{Color, ColorChoice};
-
-use crate::{
-    dep_resolution::res,
-    dep_types::{Constraint, Extras, Lock, LockPackage, Package, Rename, Req, ReqType, Version},
-    util::{abort, Os},
-};
-
-=======
 use std::process;
 use std::{
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
 };
 
 use termcolor::{Color, ColorChoice};
 
 mod actions;
->>>>>>> 4c6ec9bc8dcf2c486d5820627d70162e44d6b5a7
 mod build;
+mod build_tools;
 mod cli_options;
 mod commands;
+mod crash;
 mod dep_parser;
 mod dep_resolution;
 mod dep_types;
@@ -49,23 +29,33 @@ mod files;
 mod install;
 mod py_versions;
 mod pyproject;
+mod reproducible;
 mod script;
 mod util;
 
 type PackToInstall = ((String, Version), Option<(u32, String)>); // ((Name, Version), (parent id, rename name))
 
 ///////////////////////////////////////////////////////////////////////////////
-/// Global multithreaded variables part
+// Global multithreaded variables part
 ///////////////////////////////////////////////////////////////////////////////
 
 struct CliConfig {
     pub color_choice: ColorChoice,
+    pub verbose: bool,
+    pub quiet: bool,
+    pub rebuild_venv: bool,
+    /// `--format json`
+    pub json: bool,
 }
 
 impl Default for CliConfig {
     fn default() -> Self {
         Self {
             color_choice: ColorChoice::Auto,
+            verbose: false,
+            quiet: false,
+            rebuild_venv: false,
+            json: false,
         }
     }
 }
@@ -84,7 +74,7 @@ thread_local! {
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-/// \ Global multithreaded variables part
+// \ Global multithreaded variables part
 ///////////////////////////////////////////////////////////////////////////////
 
 /// We process input commands in a deliberate order, to ensure the required, and only the required
@@ -93,7 +83,9 @@ thread_local! {
 #[allow(clippy::single_match)]
 // TODO: Remove clippy::match_single_binding and clippy::single_match after full function refactoring
 fn main() {
-    let (pyflow_path, dep_cache_path, script_env_path, git_path) = util::paths::get_paths();
+    let dirs = util::paths::get_paths();
+    crash::install_hook(dirs.data.clone());
+    util::nesting::enter();
     let os = util::get_os();
 
     let opt = <Opt as structopt::StructOpt>::from_args();
@@ -101,11 +93,48 @@ fn main() {
     eprintln!("opts {:?}", opt);
 
     CliConfig {
-        color_choice: util::handle_color_option(
-            opt.color.unwrap_or_else(|| String::from("auto")).as_str(),
-        ),
+        color_choice: util::handle_color_option(opt.color.as_deref().unwrap_or("auto")),
+        verbose: opt.verbose,
+        quiet: opt.quiet,
+        rebuild_venv: opt.rebuild_venv,
+        json: opt.format == "json",
     }
     .make_current();
+    util::layout::init(&dirs, opt.skip_migrations);
+    if opt.no_deps_service {
+        util::deps_service::disable();
+    }
+    if opt.no_build_cache {
+        util::wheel_cache::disable();
+    }
+    if opt.no_hooks {
+        util::hooks::disable();
+    }
+    if opt.ignore_corrupt_lock {
+        util::lock_recovery::ignore_corrupt();
+    }
+    if opt.allow_newer_lock {
+        util::lock_header::allow_newer();
+    }
+    if opt.yes {
+        util::deps::assume_yes();
+    }
+    util::py_probes::configure(&dirs.data, opt.refresh_python_cache);
+    if opt.isolated_pythonpath {
+        util::pythonpath::isolate();
+    }
+    if opt.allow_foreign_wheel {
+        util::wheel_tag::allow_foreign();
+    }
+    if opt.frozen_pyproject {
+        files::freeze();
+    }
+    // Printed when `main` returns.
+    let _timings = opt
+        .timings
+        .then(|| util::timings::enable(opt.format == "json"));
+
+    let overrides = opt.overrides();
 
     // Handle commands that don't involve operating out of a project before one that do, with setup
     // code in-between.
@@ -116,18 +145,88 @@ fn main() {
     } else {
         None
     };
+    // The options before an external subcommand, which its arguments end, for `run --watch` to
+    // pass to each run.
+    let mut globals: Vec<String> = std::env::args().skip(1).collect();
+    if let SubCommand::External(ref x) = subcmd {
+        globals.truncate(globals.len().saturating_sub(x.len()));
+    }
 
     match &subcmd {
         // Actions requires nothing to know about the project
-        SubCommand::New { name } => actions::new(name),
-        SubCommand::Init => actions::init(CFG_FILENAME),
-        SubCommand::Reset {} => actions::reset(),
-        SubCommand::Clear {} => actions::clear(&pyflow_path, &dep_cache_path, &script_env_path),
+        SubCommand::New { name, py } => actions::new(name, py.as_deref()),
+        SubCommand::Init { py } => actions::init(CFG_FILENAME, py.as_deref()),
+        SubCommand::Migrate { tool } => {
+            actions::migrate(tool, Path::new(CFG_FILENAME), Path::new(LOCK_FILENAME), os);
+            return;
+        }
+        SubCommand::Reset => actions::reset(),
+        SubCommand::Clear => actions::clear(&dirs),
         SubCommand::Switch { version } => actions::switch(version),
+        SubCommand::Doctor => {
+            actions::doctor(&dirs.cache, os, &overrides);
+            return;
+        }
+        SubCommand::Check { config: true, .. } => {
+            match pyproject::current::find_config_path() {
+                Some(path) => actions::check_config(&path),
+                None => abort("Can't find `pyproject.toml` in this directory or its parents"),
+            }
+            return;
+        }
+        SubCommand::Config { cmd } => {
+            let path = pyproject::current::find_config_path().unwrap_or_else(|| {
+                abort("Can't find `pyproject.toml` in this directory or its parents")
+            });
+            match cmd {
+                ConfigCommand::Get { key } => {
+                    actions::config_get(&path, key.as_deref(), &overrides)
+                }
+                ConfigCommand::Set { key, values, raw } => {
+                    actions::config_set(&path, key, values, *raw)
+                }
+                ConfigCommand::Unset { key, raw } => actions::config_unset(&path, key, *raw),
+            }
+            return;
+        }
+        SubCommand::Env => {
+            actions::env(&dirs, &overrides);
+            return;
+        }
+        SubCommand::X {
+            python,
+            package,
+            args,
+        } => {
+            let args = [std::slice::from_ref(package), args.as_slice()].concat();
+            script::run_isolated(
+                &dirs.script_envs,
+                &dirs.cache,
+                os,
+                &dirs.python,
+                python.as_deref(),
+                &args,
+            );
+            return;
+        }
         SubCommand::External(ref x) => match ExternalCommand::from_opt(x.to_owned()) {
-            ExternalCommand { cmd, args } => match cmd {
+            ExternalCommand { cmd, args, .. } => match cmd {
                 ExternalSubcommands::Script => {
-                    script::run_script(&script_env_path, &dep_cache_path, os, &args, &pyflow_path);
+                    script::run_script(&dirs.script_envs, &dirs.cache, os, &args, &dirs.python);
+                }
+                // `pyflow run --isolated black --check .` is equivalent to `pyflow x black ...`.
+                ExternalSubcommands::Run
+                    if args.first().map(String::as_str) == Some("--isolated") =>
+                {
+                    script::run_isolated(
+                        &dirs.script_envs,
+                        &dirs.cache,
+                        os,
+                        &dirs.python,
+                        None,
+                        &args[1..],
+                    );
+                    return;
                 }
                 // TODO: Move branches to omitted match
                 _ => (),
@@ -138,11 +237,111 @@ fn main() {
         _ => {}
     }
 
+    let config_timer = util::timings::start("config parse", None);
     let pcfg = pyproject::current::get_config().unwrap_or_else(|| process::exit(1));
+    drop(config_timer);
+    files::freeze_in_ci(pcfg.config.frozen);
+    let pcfg = match &subcmd {
+        SubCommand::Install { init, .. } => pyproject::current::adopt_unmanaged(pcfg, *init),
+        _ => pcfg,
+    };
+    let settings = pyproject::settings::resolve(&pcfg.config.settings, &overrides);
+    settings.apply();
+    util::deps_service::configure(pcfg.config.deps_service_url.as_deref());
+    util::source_build::configure(pcfg.config.build_timeout);
+    let find_links: Vec<PathBuf> = opt
+        .find_links
+        .iter()
+        .map(PathBuf::from)
+        .chain(
+            pcfg.config
+                .find_links
+                .iter()
+                .map(|d| pcfg.project_path.join(d)),
+        )
+        .collect();
+    util::find_links::configure(&find_links, opt.no_index);
+    let force_scripts = match &subcmd {
+        SubCommand::Install { force_scripts, .. } => *force_scripts,
+        _ => false,
+    };
+    util::scripts::configure(!settings.generate_scripts.value, force_scripts);
+    if let SubCommand::Install {
+        editable: None,
+        extras,
+        ..
+    } = &subcmd
+    {
+        if !extras.is_empty() {
+            abort("`--extras` installs the project's extras, so requires `-e .`")
+        }
+    }
+    if let SubCommand::Install {
+        max_unpack_size: Some(size),
+        ..
+    } = &subcmd
+    {
+        let size = util::archive_limits::parse_size(size).unwrap_or_else(|e| util::abort(&e));
+        util::archive_limits::configure(Some(size));
+    }
+    util::hooks::configure(&pcfg.config.hooks, &pcfg.project_path);
+
+    // This only inspects the requirement graph, so don't set up or sync the environment.
+    if let SubCommand::WhyNot { package, version } = &subcmd {
+        let lock = util::read_lock(&pcfg.lock_path).unwrap_or_default();
+        actions::why_not(
+            package,
+            version,
+            pcfg.config.name.as_deref().unwrap_or("project"),
+            &[pcfg.config.reqs.as_slice(), pcfg.config.dev_reqs.as_slice()].concat(),
+            &lock.package.unwrap_or_default(),
+        );
+        return;
+    }
+    if let SubCommand::Graph {
+        format,
+        output,
+        package,
+        depth,
+        urls,
+    } = &subcmd
+    {
+        actions::graph(
+            &pcfg.config,
+            &pcfg.lock_path,
+            format,
+            output.as_ref().map(Path::new),
+            &actions::GraphFilter {
+                package: package.as_deref(),
+                depth: *depth,
+            },
+            *urls,
+        );
+        return;
+    }
+    if let SubCommand::Sbom { format, output } = &subcmd {
+        actions::sbom(
+            &pcfg.config,
+            &pcfg.lock_path,
+            format,
+            output.as_ref().map(Path::new),
+        );
+        return;
+    }
+    // Listing tasks only reads the config.
+    if let SubCommand::Task { name, list, .. } = &subcmd {
+        if *list || name.is_none() {
+            actions::list_tasks(&pcfg.config.scripts);
+            return;
+        }
+    }
+    // Everything past here may change the lock or environment.
+    let guard = util::guard::guard_project(&pcfg.pypackages_path);
+
     let cfg_vers = if let Some(v) = pcfg.config.py_version.clone() {
-        v
+        v.parsed
     } else {
-        let specified = util::prompts::py_vers();
+        let specified = util::prompts::py_vers("Set one with `pyflow switch 3.11`.");
 
         if !pcfg.config_path.exists() {
             pcfg.config.write_file(&pcfg.config_path);
@@ -152,53 +351,304 @@ fn main() {
         specified
     };
 
+    // Python 2 environments can't be created, but checking the lock doesn't need one.
+    if let SubCommand::Lock { check: true, .. } = &subcmd {
+        let have_venv = util::find_venvs(&pcfg.pypackages_path)
+            .iter()
+            .any(|&(major, minor)| cfg_vers.major == Some(major) && cfg_vers.minor == Some(minor));
+        if py_versions::check_supported(&cfg_vers).is_err() && !have_venv {
+            let lock = util::lock_recovery::load(&pcfg.lock_path).unwrap_or_default();
+            util::lock_header::refuse_newer(&util::lock_header::Header::from_lock(&lock.metadata));
+            let lockpacks = lock.package.unwrap_or_default();
+            actions::check_lock(
+                &pcfg.lock_path,
+                &lock.metadata,
+                &lockpacks,
+                &[pcfg.config.reqs.as_slice(), pcfg.config.dev_reqs.as_slice()].concat(),
+                &util::find_dont_uninstall(
+                    &pcfg.config.reqs,
+                    &pcfg.config.dev_reqs,
+                    &pcfg.config.preserve,
+                ),
+                os,
+                &cfg_vers,
+            );
+            return;
+        }
+    }
+
     // Check for environments. Create one if none exist. Set `vers_path`.
     let (vers_path, py_vers) = util::find_or_create_venv(
         &cfg_vers,
         &pcfg.pypackages_path,
-        &pyflow_path,
-        &dep_cache_path,
+        &dirs.python,
+        &dirs.cache,
+        &pcfg.config.build_tools,
+        pcfg.config
+            .py_interpreter
+            .as_ref()
+            .map(|p| pcfg.project_path.join(p))
+            .as_deref(),
     );
+    crash::set_py_version(&py_vers);
 
     let paths = util::Paths {
         bin: util::find_bin_path(&vers_path),
         lib: vers_path.join("lib"),
         entry_pt: vers_path.join("bin"),
-        cache: dep_cache_path,
+        cache: dirs.cache.clone(),
     };
 
+    // Bundling only reads the lock, so don't sync it with `pyproject.toml` first.
+    if let SubCommand::Bundle {
+        target,
+        no_dev,
+        scripts,
+        compact,
+        force,
+        platform,
+        target_python,
+    } = &subcmd
+    {
+        let for_target = util::cross_target::Target::from_args(
+            platform.as_deref(),
+            target_python.as_deref(),
+            os,
+            &py_vers,
+        );
+        actions::bundle(
+            Path::new(target),
+            &paths,
+            &pcfg.lock_path,
+            &pcfg.config.reqs,
+            *no_dev,
+            *scripts,
+            *compact,
+            *force,
+            os,
+            &py_vers,
+            for_target,
+        );
+        return;
+    }
+
+    // This only reads the lock, and queries PyPI.
+    if let SubCommand::Outdated {
+        respect_constraints,
+    } = &subcmd
+    {
+        let lock = util::read_lock(&pcfg.lock_path).unwrap_or_default();
+        actions::outdated(
+            &pcfg.config.reqs,
+            &pcfg.config.dev_reqs,
+            &lock.package.unwrap_or_default(),
+            os,
+            &py_vers,
+            *respect_constraints,
+        );
+        return;
+    }
+
     // Add all path reqs to the PYTHONPATH; this is the way we make these packages accessible when
     // running `pyflow`.
+    // Their console scripts go with those of other dependencies, in `entry_pt`.
+    install::migrate_scripts(&paths);
+    // Editable ones are installed in place, from their source.
     let mut pythonpath = vec![paths.lib.clone()];
-    for r in pcfg.config.reqs.iter().filter(|r| r.path.is_some()) {
-        pythonpath.push(PathBuf::from(r.path.clone().unwrap()));
-    }
-    for r in pcfg.config.dev_reqs.iter().filter(|r| r.path.is_some()) {
-        pythonpath.push(PathBuf::from(r.path.clone().unwrap()));
+    for r in pcfg
+        .config
+        .reqs
+        .iter()
+        .chain(pcfg.config.dev_reqs.iter())
+        .filter(|r| r.path.is_some())
+    {
+        let path = PathBuf::from(r.path.clone().unwrap());
+        if r.editable {
+            let target = util::editable::read(&path).unwrap_or_else(|e| abort(&e));
+            util::editable::install(&target, &paths);
+            pythonpath.push(target.source);
+        } else {
+            install::setup_path_scripts(&path, &paths.entry_pt);
+            pythonpath.push(path);
+        }
     }
 
     let mut found_lock = false;
-    let lock = match util::read_lock(&pcfg.lock_path) {
-        Ok(l) => {
+    let lock_timer = util::timings::start("lock read", None);
+    let lock = match util::lock_recovery::load(&pcfg.lock_path) {
+        Some(l) => {
             found_lock = true;
             l
         }
-        Err(_) => Lock::default(),
+        None => Lock::default(),
     };
+    drop(lock_timer);
+
+    util::lock_header::refuse_newer(&util::lock_header::Header::from_lock(&lock.metadata));
+    util::cross_target::warn_if_foreign(&lock.metadata, os, &py_vers);
+    let mut lockpacks = lock.package.unwrap_or_else(Vec::new);
+    if util::deps::dedupe_lock_names(&mut lockpacks) {
+        let deduped = Lock {
+            metadata: lock.metadata.clone(),
+            package: Some(lockpacks.clone()),
+        };
+        if util::write_lock(&pcfg.lock_path, &deduped).is_err() {
+            abort("Problem writing lock file");
+        }
+    }
+
+    // Include the project's extras installed with `pyflow install -e . --extras`.
+    let extra_reqs = actions::active_extra_reqs(&vers_path, &pcfg.config);
+    let reqs = [pcfg.config.reqs.as_slice(), extra_reqs.as_slice()].concat();
+
+    // Pruning only edits the lock, so don't sync first.
+    if let SubCommand::Lock { prune: true, .. } = subcmd {
+        actions::prune_lock(
+            &pcfg.lock_path,
+            &[reqs.as_slice(), pcfg.config.dev_reqs.as_slice()].concat(),
+            &util::find_dont_uninstall(
+                &pcfg.config.reqs,
+                &pcfg.config.dev_reqs,
+                &pcfg.config.preserve,
+            ),
+        );
+        return;
+    }
+    if let SubCommand::Lock { check: true, .. } = subcmd {
+        actions::check_lock(
+            &pcfg.lock_path,
+            &lock.metadata,
+            &lockpacks,
+            &[reqs.as_slice(), pcfg.config.dev_reqs.as_slice()].concat(),
+            &util::find_dont_uninstall(
+                &pcfg.config.reqs,
+                &pcfg.config.dev_reqs,
+                &pcfg.config.preserve,
+            ),
+            os,
+            &py_vers,
+        );
+        return;
+    }
+
+    // Locking for another platform doesn't install anything here.
+    if let SubCommand::Lock {
+        platform,
+        target_python,
+        ..
+    } = &subcmd
+    {
+        if let Some(target) = util::cross_target::Target::from_args(
+            platform.as_deref(),
+            target_python.as_deref(),
+            os,
+            &py_vers,
+        ) {
+            actions::lock_for_target(
+                &pcfg.lock_path,
+                &lockpacks,
+                &[reqs.as_slice(), pcfg.config.dev_reqs.as_slice()].concat(),
+                &util::find_dont_uninstall(
+                    &pcfg.config.reqs,
+                    &pcfg.config.dev_reqs,
+                    &pcfg.config.preserve,
+                ),
+                &target,
+            );
+            return;
+        }
+    }
+
+    // Syncing first would keep the versions already locked.
+    if let SubCommand::Lock { resolution, .. } = &subcmd {
+        let resolution = resolution
+            .as_deref()
+            .map(|r| {
+                r.parse::<Resolution>()
+                    .unwrap_or_else(|e| abort(&e.details))
+            })
+            .or(pcfg.config.resolution);
+        if let Some(resolution) = resolution {
+            lockpacks = actions::lock_with_resolution(
+                &paths,
+                &pcfg.lock_path,
+                &[reqs.as_slice(), pcfg.config.dev_reqs.as_slice()].concat(),
+                &util::find_dont_uninstall(
+                    &pcfg.config.reqs,
+                    &pcfg.config.dev_reqs,
+                    &pcfg.config.preserve,
+                ),
+                os,
+                &py_vers,
+                resolution,
+            );
+        }
+    }
+
+    // Syncing would replace what we're checking.
+    if let SubCommand::Verify = subcmd {
+        actions::verify(
+            &paths.lib,
+            &lockpacks,
+            &util::find_dont_uninstall(
+                &pcfg.config.reqs,
+                &pcfg.config.dev_reqs,
+                &pcfg.config.preserve,
+            ),
+            os,
+            &py_vers,
+        );
+        return;
+    }
 
-    let lockpacks = lock.package.unwrap_or_else(Vec::new);
+    // Syncing would install what we're about to remove.
+    if let SubCommand::Uninstall {
+        all: true,
+        and_lock,
+        include_vcs,
+        yes,
+        ..
+    } = subcmd
+    {
+        actions::uninstall_all(
+            &paths,
+            &util::find_dont_uninstall(
+                &pcfg.config.reqs,
+                &pcfg.config.dev_reqs,
+                &pcfg.config.preserve,
+            ),
+            &pcfg.lock_path,
+            and_lock,
+            include_vcs,
+            yes,
+        );
+        return;
+    }
 
     sync(
         &paths,
         &lockpacks,
-        &pcfg.config.reqs,
+        &reqs,
         &pcfg.config.dev_reqs,
-        &util::find_dont_uninstall(&pcfg.config.reqs, &pcfg.config.dev_reqs),
+        &util::find_dont_uninstall(
+            &pcfg.config.reqs,
+            &pcfg.config.dev_reqs,
+            &pcfg.config.preserve,
+        ),
         os,
         &py_vers,
         &pcfg.lock_path,
+        opt.prune_foreign,
     );
 
+    // Running tasks and scripts can take a while, and doesn't change the environment.
+    if extcmd.is_some() || matches!(subcmd, SubCommand::Task { .. }) {
+        drop(guard);
+    }
+
+    // Keep the shared caches within their limits once an install's done with them.
+    let installs = matches!(subcmd, SubCommand::Install { .. } | SubCommand::Add { .. });
+
     // Now handle subcommands that require info about the environment
     match subcmd {
         // Add package names to `pyproject.toml` if needed. Then sync installed packages
@@ -206,23 +656,68 @@ fn main() {
         // We use data from three sources: `pyproject.toml`, `pyflow.lock`, and
         // the currently-installed packages, found by crawling metadata in the `lib` path.
         // See the readme section `How installation and locking work` for details.
-        SubCommand::Install { packages, dev } | SubCommand::Add { packages, dev } => {
+        SubCommand::Install {
+            packages,
+            dev,
+            force,
+            wheel_tag,
+            editable,
+            extras,
+            ..
+        } => {
+            let extra_reqs = match editable {
+                Some(path) => actions::install_editable(
+                    &path,
+                    &extras,
+                    &pcfg.project_path,
+                    &vers_path,
+                    &pcfg.config,
+                    &pcfg.config_path,
+                    &paths,
+                ),
+                None => extra_reqs,
+            };
             actions::install(
                 &pcfg.config_path,
                 &pcfg.config,
-                &git_path,
+                &dirs.git,
                 &paths,
                 found_lock,
                 &packages,
                 dev,
+                force,
+                wheel_tag.as_deref(),
                 &lockpacks,
                 &os,
                 &py_vers,
                 &pcfg.lock_path,
+                &extra_reqs,
+                opt.prune_foreign,
             )
         }
+        SubCommand::Add {
+            packages,
+            dev,
+            force,
+        } => actions::install(
+            &pcfg.config_path,
+            &pcfg.config,
+            &dirs.git,
+            &paths,
+            found_lock,
+            &packages,
+            dev,
+            force,
+            None,
+            &lockpacks,
+            &os,
+            &py_vers,
+            &pcfg.lock_path,
+            &extra_reqs,
+            opt.prune_foreign,
+        ),
 
-        SubCommand::Uninstall { packages } => {
+        SubCommand::Uninstall { packages, .. } => {
             // todo: uninstall dev?
             // Remove dependencies specified in the CLI from the config, then lock and sync.
 
@@ -238,10 +733,7 @@ fn main() {
             files::remove_reqs_from_cfg(&pcfg.config_path, &removed_reqs);
 
             // Filter reqs here instead of re-reading the config from file.
-            let updated_reqs: Vec<Req> = pcfg
-                .config
-                .clone()
-                .reqs
+            let updated_reqs: Vec<Req> = reqs
                 .into_iter()
                 .filter(|req| !removed_reqs.contains(&req.name))
                 .collect();
@@ -251,10 +743,15 @@ fn main() {
                 &lockpacks,
                 &updated_reqs,
                 &pcfg.config.dev_reqs,
-                &[],
+                &util::find_dont_uninstall(
+                    &updated_reqs,
+                    &pcfg.config.dev_reqs,
+                    &pcfg.config.preserve,
+                ),
                 os,
                 &py_vers,
                 &pcfg.lock_path,
+                opt.prune_foreign,
             );
             util::print_color("Uninstall complete", Color::Green);
         }
@@ -266,11 +763,74 @@ fn main() {
             &py_vers,
             &pcfg.lock_path,
             &pcfg.config,
+            &reqs,
             &extras,
+            opt.prune_foreign,
+        ),
+        SubCommand::Publish => build::publish(&paths.bin, &pcfg.config),
+        SubCommand::Task {
+            name: Some(name),
+            args,
+            ..
+        } => actions::task(
+            &name,
+            &args,
+            &paths.bin,
+            &paths.entry_pt,
+            &pythonpath,
+            &pcfg.config.scripts,
+        ),
+        SubCommand::Lock {
+            platforms, pythons, ..
+        } => actions::lock(
+            &paths,
+            &pcfg.lock_path,
+            &platforms,
+            &pythons,
+            &[reqs.as_slice(), pcfg.config.dev_reqs.as_slice()].concat(),
+            &util::find_dont_uninstall(
+                &pcfg.config.reqs,
+                &pcfg.config.dev_reqs,
+                &pcfg.config.preserve,
+            ),
+            os,
+            &py_vers,
+        ),
+        SubCommand::Scripts {
+            cmd: ScriptsCommand::Regenerate,
+        } => actions::regenerate_scripts(&paths.lib, &paths.entry_pt),
+        SubCommand::Which { script, all } => actions::which(
+            script.as_deref(),
+            all,
+            &paths.entry_pt,
+            &pcfg.config.scripts,
+        ),
+        SubCommand::Check {
+            compare, sync_to, ..
+        } if compare.is_some() || sync_to.is_some() => actions::compare(
+            &pcfg.config,
+            &pcfg.lock_path,
+            compare.as_deref().map(Path::new),
+            sync_to.as_deref().map(Path::new),
+        ),
+        SubCommand::Check { .. } => actions::check(
+            &vers_path,
+            &paths.lib,
+            &pcfg.lock_path,
+            &pcfg.config.build_tools,
+            os,
+            &py_vers,
+        ),
+        SubCommand::Licenses { fail_on } => actions::licenses(
+            &paths.lib,
+            &[pcfg.config.reqs.as_slice(), pcfg.config.dev_reqs.as_slice()].concat(),
+            &fail_on,
         ),
-        SubCommand::Publish {} => build::publish(&paths.bin, &pcfg.config),
-        SubCommand::List {} => actions::list(
+        SubCommand::List { sizes } => actions::list(
             &paths.lib,
+            &paths.entry_pt,
+            &py_vers,
+            sizes,
             &[pcfg.config.reqs.as_slice(), pcfg.config.dev_reqs.as_slice()]
                 .concat()
                 .into_iter()
@@ -279,16 +839,49 @@ fn main() {
         ),
         _ => (),
     }
+    if installs {
+        util::cache_policy::enforce(&dirs);
+    }
 
     if let Some(x) = extcmd {
+        util::dotenv::configure(
+            &pcfg.project_path,
+            pcfg.config.load_dotenv,
+            x.dotenv.as_deref(),
+            &x.env,
+        );
         match x.cmd {
             ExternalSubcommands::Python => {
-                if commands::run_python(&paths.bin, &pythonpath, &x.args).is_err() {
-                    abort("Problem running Python");
+                match commands::run_python(&paths.bin, &pythonpath, &x.args) {
+                    Ok(status) if !status.success() => util::exit(status.code().unwrap_or(1)),
+                    Ok(_) => (),
+                    Err(_) => abort("Problem running Python"),
                 }
             }
+            ExternalSubcommands::Run if x.watch.is_some() => {
+                actions::watch(
+                    &pcfg.project_path,
+                    pcfg.config.name.as_deref(),
+                    &x,
+                    &globals,
+                );
+            }
             ExternalSubcommands::Run => {
-                run(&paths.lib, &paths.bin, &vers_path, &pcfg.config, x.args);
+                if pcfg.config.check_shadowing {
+                    util::shadowing::warn(
+                        &paths.bin.join("python"),
+                        &pcfg.project_path,
+                        &pythonpath,
+                        &vers_path.join(util::shadowing::FILENAME),
+                    );
+                }
+                run(
+                    &pythonpath,
+                    &paths.bin,
+                    &paths.entry_pt,
+                    &pcfg.config,
+                    x.args,
+                );
             }
             x => {
                 abort(&format!(
@@ -2,14 +2,19 @@
 
 use std::error::Error;
 #[allow(unused_imports)]
-use std::{fmt, fs, io, path::Path, path::PathBuf};
+use std::{fmt, fs, io, path::Path, path::PathBuf, str::FromStr};
 
+use serde::{Deserialize, Serialize};
 use termcolor::Color;
 
-use crate::{commands, dep_types::Version, install, util};
+use crate::{
+    build_tools, commands,
+    dep_types::{Req, Version},
+    util,
+};
 
 /// Only versions we've built and hosted
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum PyVers {
     V3_12_0, // unreleased
     V3_11_0, // unreleased
@@ -24,116 +29,116 @@ enum PyVers {
     V3_4_10, // Linux
 }
 
-/// Reduces code repetition for error messages related to Python binaries we don't support.
-fn abort_helper(version: &str, os: &str) {
-    util::abort(&format!(
-        "Automatic installation of Python {} on {} is currently unsupported. If you'd like \
-         to use this version of Python, please install it.",
-        version, os
-    ))
-}
+impl PyVers {
+    /// Newest first.
+    const ALL: [Self; 11] = [
+        Self::V3_12_0,
+        Self::V3_11_0,
+        Self::V3_10_2,
+        Self::V3_9_0,
+        Self::V3_8_0,
+        Self::V3_7_4,
+        Self::V3_6_9,
+        Self::V3_6_8,
+        Self::V3_5_7,
+        Self::V3_5_4,
+        Self::V3_4_10,
+    ];
 
-impl From<(Version, Os)> for PyVers {
-    fn from(v_o: (Version, Os)) -> Self {
-        let unsupported = "Unsupported python version requested; only Python ≥ 3.4 is supported. \
-        to fix this, edit the `py_version` line of `pyproject.toml`, or run `pyflow switch 3.7`";
-        if v_o.0.major != Some(3) {
-            util::abort(unsupported)
+    fn built_for(self, os: PybinOs) -> bool {
+        let linux = matches!(os, PybinOs::Ubuntu | PybinOs::Centos);
+        match self {
+            Self::V3_12_0 | Self::V3_11_0 | Self::V3_9_0 | Self::V3_8_0 | Self::V3_7_4 => {
+                linux || os == PybinOs::Windows
+            }
+            Self::V3_10_2 | Self::V3_6_8 | Self::V3_5_4 => os == PybinOs::Windows,
+            Self::V3_6_9 | Self::V3_5_7 | Self::V3_4_10 => linux,
         }
-        match v_o.0.minor.unwrap_or(0) {
-            4 => match v_o.1 {
-                Os::Windows => {
-                    abort_helper("3.4", "Windows");
-                    unreachable!()
-                }
-                Os::Ubuntu | Os::Centos => Self::V3_4_10,
-                _ => {
-                    abort_helper("3.4", "Mac");
-                    unreachable!()
-                }
-            },
-            5 => match v_o.1 {
-                Os::Windows => Self::V3_5_4,
-                Os::Ubuntu | Os::Centos => Self::V3_5_7,
-                _ => {
-                    abort_helper("3.5", "Mac");
-                    unreachable!()
-                }
-            },
-            6 => match v_o.1 {
-                Os::Windows => Self::V3_6_8,
-                Os::Ubuntu | Os::Centos => Self::V3_6_9,
-                _ => {
-                    abort_helper("3.6", "Mac");
-                    unreachable!()
-                }
-            },
-            7 => match v_o.1 {
-                Os::Windows | Os::Ubuntu | Os::Centos => Self::V3_7_4,
-                _ => {
-                    abort_helper("3.7", "Mac");
-                    unreachable!()
-                }
-            },
-            8 => match v_o.1 {
-                Os::Windows | Os::Ubuntu | Os::Centos => Self::V3_8_0,
-                _ => {
-                    abort_helper("3.8", "Mac");
-                    unreachable!()
-                }
-            },
-            9 => match v_o.1 {
-                Os::Windows | Os::Ubuntu | Os::Centos => Self::V3_9_0,
-                _ => {
-                    abort_helper("3.9", "Mac");
-                    unreachable!()
-                }
-            },
-            10 => match v_o.1 {
-                Os::Windows => Self::V3_10_2,
-                Os::Ubuntu | Os::Centos => {
-                    abort_helper("3.10", "Linux");
-                    unreachable!()
-                }
-                _ => {
-                    abort_helper("3.10", "Mac");
-                    unreachable!()
-                }
-            },
-            11 => match v_o.1 {
-                Os::Windows | Os::Ubuntu | Os::Centos => Self::V3_11_0,
-                _ => {
-                    abort_helper("3.11", "Mac");
-                    unreachable!()
-                }
-            },
-            12 => match v_o.1 {
-                Os::Windows | Os::Ubuntu | Os::Centos => Self::V3_12_0,
-                _ => {
-                    abort_helper("3.12", "Mac");
-                    unreachable!()
-                }
-            },
-            _ => util::abort(unsupported),
+    }
+
+    /// The build for `os` with `version`'s major and minor versions. Without a minor version,
+    /// the newest one.
+    fn find(version: &Version, os: PybinOs) -> Result<Self, String> {
+        let oldest = Self::V3_4_10.to_vers();
+        let newest = Self::ALL[0].to_vers();
+        let minor = |v: &Version| (v.major.unwrap_or(0), v.minor.unwrap_or(0));
+        let available = installable(os);
+        let available = if available.is_empty() {
+            format!("pyflow can't install any version on {}", os.family())
+        } else {
+            let listed: Vec<String> = available.iter().map(Version::to_string_med).collect();
+            format!(
+                "pyflow can install these on {}: {}",
+                os.family(),
+                listed.join(", ")
+            )
+        };
+
+        let too_old = match version.minor {
+            Some(_) => minor(version) < minor(&oldest),
+            None => version.major < oldest.major,
+        };
+        if too_old {
+            return Err(format!(
+                "Python {} is older than pyflow supports; the oldest it supports is {}. Edit the \
+                 `py_version` line of `pyproject.toml`, or run eg `pyflow switch 3.11`. {}",
+                version,
+                oldest.to_string_med(),
+                available
+            ));
+        }
+        let found = Self::ALL.iter().copied().find(|v| {
+            v.built_for(os)
+                && v.to_vers().major == version.major
+                && (version.minor.is_none() || v.to_vers().minor == version.minor)
+        });
+        match found {
+            Some(v) => Ok(v),
+            None if minor(version) > minor(&newest) => Err(format!(
+                "Python {} is newer than the versions pyflow can install. Install it yourself, eg \
+                 from python.org, and pyflow will use it; or change `py_version`. {}",
+                version, available
+            )),
+            None => Err(format!(
+                "pyflow can't install Python {} on {}. Install it yourself, and pyflow will use \
+                 it; or change `py_version`. {}",
+                version,
+                os.family(),
+                available
+            )),
         }
     }
 }
 
-impl ToString for PyVers {
-    fn to_string(&self) -> String {
-        match self {
-            Self::V3_12_0 => "3.12.0".into(),
-            Self::V3_11_0 => "3.11.0".into(),
-            Self::V3_10_2 => "3.10.2".into(),
-            Self::V3_9_0 => "3.9.0".into(),
-            Self::V3_8_0 => "3.8.0".into(),
-            Self::V3_7_4 => "3.7.4".into(),
-            Self::V3_6_9 => "3.6.9".into(),
-            Self::V3_6_8 => "3.6.8".into(),
-            Self::V3_5_7 => "3.5.7".into(),
-            Self::V3_5_4 => "3.5.4".into(),
-            Self::V3_4_10 => "3.4.10".into(),
-        }
+/// The Python versions pyflow can install on `os`, oldest first, with only their major and minor
+/// versions.
+fn installable(os: PybinOs) -> Vec<Version> {
+    PyVers::ALL
+        .iter()
+        .rev()
+        .filter(|v| v.built_for(os))
+        .map(|v| {
+            let v = v.to_vers();
+            Version::new_opt(v.major, v.minor, None)
+        })
+        .collect()
+}
+
+impl fmt::Display for PyVers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::V3_12_0 => "3.12.0",
+            Self::V3_11_0 => "3.11.0",
+            Self::V3_10_2 => "3.10.2",
+            Self::V3_9_0 => "3.9.0",
+            Self::V3_8_0 => "3.8.0",
+            Self::V3_7_4 => "3.7.4",
+            Self::V3_6_9 => "3.6.9",
+            Self::V3_6_8 => "3.6.8",
+            Self::V3_5_7 => "3.5.7",
+            Self::V3_5_4 => "3.5.4",
+            Self::V3_4_10 => "3.4.10",
+        })
     }
 }
 
@@ -155,13 +160,13 @@ impl PyVers {
     }
 }
 
-/// Only Oses we've built and hosted
+/// Only Oses we've built and hosted Python binaries for, in the `pybin` repo. Not `util::Os`,
+/// which is for picking package releases.
 /// todo: How cross-compat are these? Eg work across diff versions of Ubuntu?
 /// todo: 32-bit
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[allow(dead_code)]
-enum Os {
-    // Don't confuse with crate::Os
+enum PybinOs {
     Ubuntu, // Builds on Ubuntu 18.04 work on Ubuntu 19.04, Debian, Arch, and Kali
     Centos, // Will this work on Red Hat and Fedora as well?
     Windows,
@@ -169,7 +174,7 @@ enum Os {
 }
 
 /// For use in the Linux distro prompt
-impl fmt::Display for Os {
+impl fmt::Display for PybinOs {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
@@ -184,13 +189,24 @@ impl fmt::Display for Os {
     }
 }
 
-fn download(py_install_path: &Path, version: &Version) {
+impl PybinOs {
+    /// For messages, where the distro doesn't matter.
+    fn family(self) -> &'static str {
+        match self {
+            Self::Ubuntu | Self::Centos => "Linux",
+            Self::Windows => "Windows",
+            Self::Mac => "Mac",
+        }
+    }
+}
+
+fn download(py_install_path: &Path, vers_to_dl: PyVers) {
     // We use the `.xz` format due to its small size compared to `.zip`. On order half the size.
     let os;
     let os_str;
     #[cfg(target_os = "windows")]
     {
-        os = Os::Windows;
+        os = PybinOs::Windows;
         os_str = "windows";
     }
     #[cfg(target_os = "linux")]
@@ -201,38 +217,41 @@ fn download(py_install_path: &Path, version: &Version) {
             &[
                 (
                     "2016 or newer (Ubuntu≥16.04, Debian≥9, SUSE≥15, Arch, Kali, etc)".to_owned(),
-                    Os::Ubuntu,
+                    PybinOs::Ubuntu,
                 ),
                 (
                     "Older (Centos, Redhat, Fedora, older versions of distros listed in option 1)"
                         .to_owned(),
-                    Os::Centos,
+                    PybinOs::Centos,
                 ),
             ],
             false,
+            Some(0),
+            "Set `py_interpreter` in `[tool.pyflow]` to use a Python that's already installed.",
         );
         os = result.1;
         os_str = match os {
-            Os::Ubuntu => "ubuntu",
-            Os::Centos => "centos",
-            _ => {
-                util::abort(
-                    "Unfortunately, we don't yet support other Operating systems.\
-                     It's worth trying the other options, to see if one works anyway.",
-                );
-                unreachable!()
-            }
+            PybinOs::Ubuntu => "ubuntu",
+            PybinOs::Centos => "centos",
+            _ => util::abort(
+                "Unfortunately, we don't yet support other Operating systems.\
+                 It's worth trying the other options, to see if one works anyway.",
+            ),
         };
     }
     #[cfg(target_os = "macos")]
     {
-        os = Os::Mac;
+        os = PybinOs::Mac;
         os_str = "mac";
     }
 
-    // Match up our version to the closest match (major+minor will match) we've built.
-    let vers_to_dl2: PyVers = (version.clone(), os).into();
-    let vers_to_dl = vers_to_dl2.to_string();
+    if !vers_to_dl.built_for(os) {
+        util::abort(&format!(
+            "pyflow can't install Python {} on {}",
+            vers_to_dl, os
+        ));
+    }
+    let vers_to_dl = vers_to_dl.to_string();
 
     let url = format!(
         "https://github.com/David-OConnor/pybin/releases/\
@@ -241,14 +260,15 @@ fn download(py_install_path: &Path, version: &Version) {
     );
 
     // eg `python-3.7.4-ubuntu.tar.xz`
-    let archive_path = py_install_path.join(&format!("python-{}-{}.tar.xz", vers_to_dl, os_str));
+    let archive_path = py_install_path.join(format!("python-{}-{}.tar.xz", vers_to_dl, os_str));
     if !archive_path.exists() {
         // Save the file
         util::print_color(
             &format!("Downloading Python {}...", vers_to_dl),
             Color::Cyan,
         );
-        let mut resp = reqwest::blocking::get(&url).expect("Problem downloading Python"); // Download the file
+        let mut resp =
+            util::http::get(&url).unwrap_or_else(|e| util::abort(&util::http::describe(&e)));
         let mut out =
             fs::File::create(&archive_path).expect("Failed to save downloaded Python archive");
         if let Err(e) = io::copy(&mut resp, &mut out) {
@@ -257,15 +277,22 @@ fn download(py_install_path: &Path, version: &Version) {
             util::abort(&format!("Problem downloading the Python archive: {:?}", e));
         }
     }
+    util::cache_policy::record_use(
+        py_install_path,
+        &archive_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy(),
+    );
     util::print_color(&format!("Installing Python {}...", vers_to_dl), Color::Cyan);
 
     util::unpack_tar_xz(&archive_path, py_install_path);
 
     // Strip the OS tag from the extracted Python folder name
-    let extracted_path = py_install_path.join(&format!("python-{}", vers_to_dl));
+    let extracted_path = py_install_path.join(format!("python-{}", vers_to_dl));
 
     fs::rename(
-        py_install_path.join(&format!("python-{}-{}", vers_to_dl, os_str)),
+        py_install_path.join(format!("python-{}-{}", vers_to_dl, os_str)),
         &extracted_path,
     )
     .expect("Problem renaming extracted Python folder");
@@ -288,52 +315,136 @@ impl fmt::Display for AliasError {
     }
 }
 
+/// Commands that may run Python, newest first.
+const ALIASES: [&str; 22] = [
+    "python3.19",
+    "python3.18",
+    "python3.17",
+    "python3.16",
+    "python3.15",
+    "python3.14",
+    "python3.13",
+    "python3.12",
+    "python3.11",
+    "python3.10",
+    "python3.9",
+    "python3.8",
+    "python3.7",
+    "python3.6",
+    "python3.5",
+    "python3.4",
+    "python3.3",
+    "python3.2",
+    "python3.1",
+    "python3",
+    "python",
+    "python2",
+];
+
 /// Make an educated guess at the command needed to execute python the
 /// current system.  An alternative approach is trying to find python
 /// installations.
 pub fn find_py_aliases(version: &Version) -> Vec<(String, Version)> {
-    let possible_aliases = &[
-        "python3.19",
-        "python3.18",
-        "python3.17",
-        "python3.16",
-        "python3.15",
-        "python3.14",
-        "python3.13",
-        "python3.12",
-        "python3.11",
-        "python3.10",
-        "python3.9",
-        "python3.8",
-        "python3.7",
-        "python3.6",
-        "python3.5",
-        "python3.4",
-        "python3.3",
-        "python3.2",
-        "python3.1",
-        "python3",
-        "python",
-        "python2",
-    ];
-
     let mut result = Vec::new();
-    let mut found_dets = Vec::new();
+    let mut found_paths = Vec::new();
 
-    for alias in possible_aliases {
+    for alias in &ALIASES {
         // We use the --version command as a quick+effective way to determine if
         // this command is associated with Python.
-        let dets = commands::find_py_dets(alias);
-        if let Some(v) = commands::find_py_version(alias) {
-            if v.major == version.major && v.minor == version.minor && !found_dets.contains(&dets) {
-                result.push((alias.to_string(), v));
-                found_dets.push(dets);
-            }
+        let info = match util::py_probes::find_py_info(alias) {
+            Some(i) => i,
+            None => continue,
+        };
+        let v = info.version;
+        if v.major != version.major || v.minor != version.minor {
+            continue;
+        }
+        // Aliases are often links to the same interpreter, eg `python3` and `python3.11`.
+        let path =
+            util::py_probes::find_py_executable(alias).unwrap_or_else(|| PathBuf::from(alias));
+        if found_paths.contains(&path) {
+            continue;
         }
+        if info.pypy {
+            util::print_color(
+                &format!(
+                    "`{}` is PyPy {}. Packages are resolved and picked as for CPython, so ones \
+                     with compiled extensions may not work with it",
+                    alias, v
+                ),
+                Color::Yellow,
+            );
+        }
+        result.push((alias.to_string(), v));
+        found_paths.push(path);
     }
     result
 }
 
+/// Whether a `pyflow switch` or `pyflow init --py` argument is the path of an interpreter, eg
+/// `/opt/python/3.11.6/bin/python3`, rather than a version.
+pub fn is_interpreter_path(arg: &str) -> bool {
+    arg.contains('/') || arg.contains('\\') || Path::new(arg).is_file()
+}
+
+/// Run an interpreter given by path, eg a custom build that isn't on the `PATH`, to find its
+/// version. Returns its absolute path, and the version.
+pub fn probe_interpreter(path: &Path) -> (PathBuf, Version) {
+    let abs_path = std::path::absolute(path).unwrap_or_else(|_| path.to_owned());
+    if !abs_path.is_file() {
+        util::abort(&format!(
+            "Can't find the Python interpreter {}",
+            abs_path.to_string_lossy()
+        ))
+    }
+    let output = std::process::Command::new(&abs_path)
+        .arg("--version")
+        .output()
+        .unwrap_or_else(|e| {
+            util::abort(&format!(
+                "Can't run {}; is it executable? {}",
+                abs_path.to_string_lossy(),
+                e
+            ))
+        });
+    let version = commands::parse_py_version(&output).unwrap_or_else(|| {
+        util::abort(&format!(
+            "{} didn't report a Python version. Is it a Python interpreter?",
+            abs_path.to_string_lossy()
+        ))
+    });
+    (abs_path, version)
+}
+
+/// A Python version, eg `3.11`, or the path of an interpreter, with its version. Only the
+/// major and minor versions are kept, as for `py_version`.
+pub fn parse_py_arg(arg: &str) -> (Version, Option<PathBuf>) {
+    if is_interpreter_path(arg) {
+        let (path, version) = probe_interpreter(Path::new(arg));
+        (
+            Version::new_opt(version.major, version.minor, None),
+            Some(path),
+        )
+    } else {
+        (util::fallible_v_parse(arg), None)
+    }
+}
+
+/// Check that the interpreter in `py_interpreter` still exists, and is the `py_version` one.
+fn check_interpreter(path: &Path, cfg_v: &Version) -> Version {
+    let (_, version) = probe_interpreter(path);
+    if version.major != cfg_v.major || version.minor != cfg_v.minor {
+        util::abort(&format!(
+            "{} is Python {}, but `py_version` is {}. Run `pyflow switch` with the interpreter's \
+             path, or a version, to update them.",
+            path.to_string_lossy(),
+            version,
+            cfg_v
+        ))
+    }
+    version
+}
+
 // Find versions installed with this tool.
 fn find_installed_versions(pyflow_dir: &Path) -> Vec<Version> {
     #[cfg(target_os = "windows")]
@@ -343,7 +454,7 @@ fn find_installed_versions(pyflow_dir: &Path) -> Vec<Version> {
     #[cfg(target_os = "macos")]
     let py_name = "bin/python3";
 
-    if !&pyflow_dir.exists() && fs::create_dir_all(&pyflow_dir).is_err() {
+    if !&pyflow_dir.exists() && fs::create_dir_all(pyflow_dir).is_err() {
         util::abort("Problem creating the Pyflow directory")
     }
 
@@ -357,20 +468,73 @@ fn find_installed_versions(pyflow_dir: &Path) -> Vec<Version> {
             continue;
         }
 
-        if let Some(v) = commands::find_py_version(entry.path().join(py_name).to_str().unwrap()) {
-            result.push(v);
+        let python = entry.path().join(py_name);
+        if let Some(info) = util::py_probes::find_py_info(&python.to_string_lossy()) {
+            result.push(info.version);
         }
     }
     result
 }
 
-/// Create a new virtual environment, and install `wheel`.
+/// The Python versions we can use without downloading: ones pyflow installed, and ones on the
+/// `PATH`. Newest first, with only their major and minor versions.
+pub fn available_versions(pyflow_dir: &Path) -> Vec<Version> {
+    let system = ALIASES
+        .iter()
+        .filter_map(|alias| util::py_probes::find_py_info(alias))
+        .map(|info| info.version);
+    let mut result: Vec<Version> = find_installed_versions(pyflow_dir)
+        .into_iter()
+        .chain(system)
+        .map(|v| Version::new_opt(v.major, v.minor, None))
+        .collect();
+    result.sort_unstable_by(|a, b| b.cmp(a));
+    result.dedup();
+    result
+}
+
+/// The Python versions pyflow can download for this OS, oldest first.
+pub fn installable_here() -> Vec<Version> {
+    #[cfg(target_os = "windows")]
+    let os = PybinOs::Windows;
+    // Our Linux distros have the same versions.
+    #[cfg(target_os = "linux")]
+    let os = PybinOs::Ubuntu;
+    #[cfg(target_os = "macos")]
+    let os = PybinOs::Mac;
+    installable(os)
+}
+
+/// Whether pyflow can target `version`. Python 2 has no `venv` module to create environments
+/// with, so it can't; `Err` says so, and callers add what to do instead.
+pub fn check_supported(version: &Version) -> Result<(), String> {
+    if version.major == Some(2) {
+        return Err(format!(
+            "pyflow can't target Python {}; it only creates environments for Python 3.4 and newer.",
+            version
+        ));
+    }
+    Ok(())
+}
+
+/// Create a new virtual environment, and install build tooling.
 pub fn create_venv(
     cfg_v: &Version,
     pypackages_dir: &Path,
     pyflow_dir: &Path,
     dep_cache_path: &Path,
+    build_tools: &[Req],
+    interpreter: Option<&Path>,
 ) -> Version {
+    // An existing Python 2 environment is still used; we just can't make one.
+    if let Err(e) = check_supported(cfg_v) {
+        util::abort(&format!(
+            "{} Set a Python 3 `py_version` under `[tool.pyflow]`, eg with \
+             `pyflow config set py_version 3.11`. Commands that don't need an environment, like \
+             `pyflow graph`, `pyflow sbom`, and `pyflow lock --check`, work without one.",
+            e
+        ))
+    }
     let os;
     let python_name;
     #[allow(unused_mut)]
@@ -378,19 +542,19 @@ pub fn create_venv(
     #[cfg(target_os = "windows")]
     {
         py_name = "python".to_string();
-        os = Os::Windows;
+        os = PybinOs::Windows;
         python_name = "python.exe";
     }
     #[cfg(target_os = "linux")]
     {
         py_name = "bin/python3".to_string();
-        os = Os::Ubuntu;
+        os = PybinOs::Ubuntu;
         python_name = "python";
     }
     #[cfg(target_os = "macos")]
     {
         py_name = "bin/python3".to_string();
-        os = Os::Mac;
+        os = PybinOs::Mac;
         python_name = "python";
     }
 
@@ -398,15 +562,23 @@ pub fn create_venv(
     let mut alias_path = None;
     let mut py_ver = None;
 
+    // An interpreter set with `pyflow switch <path>` takes precedence.
+    if let Some(path) = interpreter {
+        py_ver = Some(check_interpreter(path, cfg_v));
+        alias_path = Some(path.to_owned());
+    }
+
     // If we find both a system alias, and internal version installed, go with the internal.
     // One's this tool installed
-    let installed_versions = find_installed_versions(pyflow_dir);
-    for iv in &installed_versions {
-        if iv.major == cfg_v.major && iv.minor == cfg_v.minor {
-            let folder_name = format!("python-{}", iv.to_string());
-            alias_path = Some(pyflow_dir.join(folder_name).join(&py_name));
-            py_ver = Some(iv.clone());
-            break;
+    if py_ver.is_none() {
+        let installed_versions = find_installed_versions(pyflow_dir);
+        for iv in &installed_versions {
+            if iv.major == cfg_v.major && iv.minor == cfg_v.minor {
+                let folder_name = format!("python-{}", iv);
+                alias_path = Some(pyflow_dir.join(folder_name).join(&py_name));
+                py_ver = Some(iv.clone());
+                break;
+            }
         }
     }
 
@@ -427,43 +599,56 @@ pub fn create_venv(
             _ => {
                 //                let r = prompt_alias(&aliases);
                 let r = util::prompts::list(
-                    "Found multiple compatible Python versions. Please enter the number associated with the one you'd like to use:",
+                    "Found multiple compatible Python versions. Please enter the number or name of the one you'd like to use:",
                     "Python alias",
                     &aliases,
                     true,
+                    None,
+                    "Pick one with `pyflow switch`, eg `pyflow switch /usr/bin/python3.11`.",
                 );
                 alias = Some(r.0);
                 py_ver = Some(r.1);
             }
         };
+        if let Some(alias) = &alias {
+            let path = commands::find_py_executable(Path::new(alias))
+                .unwrap_or_else(|| PathBuf::from(alias));
+            let note = match PyVers::find(cfg_v, os) {
+                Ok(_) => String::new(),
+                Err(_) => "; pyflow can't install this version itself".to_owned(),
+            };
+            util::print_color(
+                &format!(
+                    "Using the system's {} at {}{}",
+                    alias,
+                    path.to_string_lossy(),
+                    note
+                ),
+                Color::Cyan,
+            );
+        }
     }
 
     if py_ver.is_none() {
         // Download and install the appropriate Python binary, if we can't find either a
         // custom install, or on the Path.
-        download(pyflow_dir, cfg_v);
-        let py_ver2: PyVers = (cfg_v.clone(), os).into();
+        let py_ver2 = PyVers::find(cfg_v, os).unwrap_or_else(|e| {
+            util::abort(&format!(
+                "Can't find Python {} on this system. {}",
+                cfg_v, e
+            ))
+        });
+        download(pyflow_dir, py_ver2);
         py_ver = Some(py_ver2.to_vers());
 
-        let folder_name = format!("python-{}", py_ver2.to_string());
+        let folder_name = format!("python-{}", py_ver2);
 
         // We appear to have symlink issues on some builds, where `python3` won't work, but
         // `python3.7` (etc) will. Note that this is no longer applicable once the venv is built,
         // and we're using its `python`.
         #[cfg(target_os = "linux")]
         {
-            match py_ver.clone().unwrap().minor.unwrap_or(0) {
-                12 => py_name += ".12",
-                11 => py_name += ".11",
-                10 => py_name += ".10",
-                9 => py_name += ".9",
-                8 => py_name += ".8",
-                7 => py_name += ".7",
-                6 => py_name += ".6",
-                5 => py_name += ".5",
-                4 => py_name += ".4",
-                _ => panic!("Invalid python minor version"),
-            }
+            py_name += &format!(".{}", py_ver.clone().unwrap().minor.unwrap_or(0));
         }
 
         alias_path = Some(pyflow_dir.join(folder_name).join(py_name));
@@ -486,6 +671,11 @@ pub fn create_venv(
     #[cfg(target_os = "macos")]
     println!("🐍 Setting up Python...");
 
+    let base_interpreter = alias
+        .clone()
+        .map(PathBuf::from)
+        .or_else(|| alias_path.clone());
+
     // For an alias on the PATH
     if let Some(alias) = alias {
         if commands::create_venv(&alias, &lib_path, ".venv").is_err() {
@@ -503,6 +693,17 @@ pub fn create_venv(
     util::wait_for_dirs(&[bin_path.join(python_name)])
         .expect("Timed out waiting for venv to be created.");
 
+    if let Some(base) = base_interpreter {
+        let python = commands::find_py_executable(&base).unwrap_or(base);
+        write_interpreter_record(
+            &vers_path,
+            &InterpreterRecord {
+                python,
+                version: py_ver.to_string(),
+            },
+        );
+    }
+
     // Try 64 first; if not, use 32.
     #[allow(unused_variables)]
     let lib = if vers_path.join(".venv").join("lib64").exists() {
@@ -514,9 +715,9 @@ pub fn create_venv(
     #[cfg(target_os = "windows")]
     let venv_lib_path = "Lib";
     #[cfg(target_os = "linux")]
-    let venv_lib_path = PathBuf::from(lib).join(&format!("python{}", py_ver.to_string_med()));
+    let venv_lib_path = PathBuf::from(lib).join(format!("python{}", py_ver.to_string_med()));
     #[cfg(target_os = "macos")]
-    let venv_lib_path = PathBuf::from(lib).join(&format!("python{}", py_ver.to_string_med()));
+    let venv_lib_path = PathBuf::from(lib).join(format!("python{}", py_ver.to_string_med()));
 
     let paths = util::Paths {
         bin: bin_path.clone(),
@@ -528,22 +729,201 @@ pub fn create_venv(
         cache: dep_cache_path.to_owned(),
     };
 
-    // We need `wheel` installed to build wheels from source.
-    // We use `twine` to upload packages to pypi.
+    // We need `wheel` and `setuptools` installed to build wheels from source.
     // Note: This installs to the venv's site-packages, not __pypackages__/3.x/lib.
-    let wheel_url = "https://files.pythonhosted.org/packages/00/83/b4a77d044e78ad1a45610eb88f745be2fd2c6d658f9798a15e384b7d57c9/wheel-0.33.6-py2.py3-none-any.whl";
-
-    install::download_and_install_package(
-        "wheel",
-        &Version::new(0, 33, 6),
-        wheel_url,
-        "wheel-0.33.6-py2.py3-none-any.whl",
-        "f4da1763d3becf2e2cd92a14a7c920f0f00eca30fdde9ea992c836685b9faf28",
-        &paths,
-        install::PackageType::Wheel,
-        &None,
-    )
-    .expect("Problem installing `wheel`");
+    build_tools::bootstrap(&paths, &vers_path, &py_ver, build_tools);
 
     py_ver
 }
+
+/// Stores the interpreter a venv was created from, in its `.venv` folder.
+pub const INTERPRETER_FILENAME: &str = "pyflow_interpreter.toml";
+
+/// The interpreter a venv was created from: its real path, and full version.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct InterpreterRecord {
+    pub python: PathBuf,
+    pub version: String,
+}
+
+pub fn read_interpreter_record(vers_path: &Path) -> Option<InterpreterRecord> {
+    let data = fs::read_to_string(vers_path.join(".venv").join(INTERPRETER_FILENAME)).ok()?;
+    toml::from_str(&data).ok()
+}
+
+fn write_interpreter_record(vers_path: &Path, record: &InterpreterRecord) {
+    let data = toml::to_string(record).expect("Problem serializing the interpreter record");
+    if fs::write(vers_path.join(".venv").join(INTERPRETER_FILENAME), data).is_err() {
+        util::print_color("Problem saving the interpreter record", Color::Yellow);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VenvHealth {
+    Ok,
+    /// The interpreter got a patch update, which venvs survive.
+    Updated(Version, Version),
+    /// The interpreter is gone, or is now a different minor version.
+    Broken(String),
+}
+
+/// Check that the interpreter a venv was created from is still there, and compatible.
+pub fn check_venv(vers_path: &Path) -> VenvHealth {
+    #[cfg(target_os = "windows")]
+    let python_name = "python.exe";
+    #[cfg(not(target_os = "windows"))]
+    let python_name = "python";
+
+    match read_interpreter_record(vers_path) {
+        Some(record) => {
+            let found = commands::find_py_version(&record.python.to_string_lossy());
+            venv_health(&record, found)
+        }
+        // Created by an older pyflow; we can only check that its Python runs.
+        None => {
+            let venv_python = util::find_bin_path(vers_path).join(python_name);
+            if commands::find_py_version(&venv_python.to_string_lossy()).is_some() {
+                VenvHealth::Ok
+            } else {
+                VenvHealth::Broken(format!(
+                    "The Python interpreter for the environment in {} is missing or broken, \
+                     eg from a system upgrade.",
+                    vers_path.to_string_lossy()
+                ))
+            }
+        }
+    }
+}
+
+fn venv_health(record: &InterpreterRecord, found: Option<Version>) -> VenvHealth {
+    let recorded = Version::from_str(&record.version).unwrap_or_else(|_| Version::new_any());
+    match found {
+        None => VenvHealth::Broken(format!(
+            "Python {} at {}, which this environment was created from, is missing. This usually \
+             means a system upgrade replaced it.",
+            record.version,
+            record.python.to_string_lossy()
+        )),
+        Some(v) if v.major != recorded.major || v.minor != recorded.minor => {
+            VenvHealth::Broken(format!(
+                "{} was Python {} when this environment was created, but is now Python {}.",
+                record.python.to_string_lossy(),
+                record.version,
+                v
+            ))
+        }
+        Some(v) if v != recorded => VenvHealth::Updated(recorded, v),
+        Some(_) => VenvHealth::Ok,
+    }
+}
+
+/// Record a patch update of the interpreter, so we only mention it once.
+pub fn record_interpreter_update(vers_path: &Path, version: &Version) {
+    if let Some(mut record) = read_interpreter_record(vers_path) {
+        record.version = version.to_string();
+        write_interpreter_record(vers_path, &record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest(
+        found,
+        expected,
+        case(Some(Version::new(3, 11, 7)), VenvHealth::Ok),
+        case(
+            Some(Version::new(3, 11, 9)),
+            VenvHealth::Updated(Version::new(3, 11, 7), Version::new(3, 11, 9))
+        ),
+        case(Some(Version::new(3, 12, 1)), VenvHealth::Broken(String::new())),
+        case(None, VenvHealth::Broken(String::new()))
+    )]
+    fn interpreter_changes(found: Option<Version>, expected: VenvHealth) {
+        let record = InterpreterRecord {
+            python: PathBuf::from("/usr/bin/python3.11"),
+            version: "3.11.7".into(),
+        };
+        match (venv_health(&record, found), expected) {
+            (VenvHealth::Broken(msg), VenvHealth::Broken(_)) => {
+                assert!(msg.contains("/usr/bin/python3.11"))
+            }
+            (actual, expected) => assert_eq!(actual, expected),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn interpreter_path_probed() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let python = dir.join("python3");
+        fs::write(&python, "#!/bin/sh\necho 'Python 3.11.6'\n").unwrap();
+        fs::set_permissions(&python, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let arg = python.to_string_lossy();
+        assert!(is_interpreter_path(&arg));
+        assert!(!is_interpreter_path("3.11"));
+        assert_eq!(
+            parse_py_arg(&arg),
+            (Version::new_short(3, 11), Some(python.clone()))
+        );
+        assert_eq!(
+            check_interpreter(&python, &Version::new_short(3, 11)),
+            Version::new(3, 11, 6)
+        );
+    }
+
+    #[rstest(
+        version,
+        os,
+        expected,
+        case(Version::new_short(3, 11), PybinOs::Ubuntu, Ok(PyVers::V3_11_0)),
+        case(Version::new_short(3, 10), PybinOs::Windows, Ok(PyVers::V3_10_2)),
+        case(
+            Version::new_opt(Some(3), None, None),
+            PybinOs::Centos,
+            Ok(PyVers::V3_12_0)
+        ),
+        case(Version::new_short(3, 13), PybinOs::Ubuntu, Err("newer than")),
+        case(Version::new_short(3, 3), PybinOs::Ubuntu, Err("older than")),
+        case(Version::new_short(2, 7), PybinOs::Windows, Err("older than")),
+        case(
+            Version::new_short(3, 10),
+            PybinOs::Ubuntu,
+            Err("can't install Python 3.10 on Linux")
+        ),
+        case(
+            Version::new_short(3, 9),
+            PybinOs::Mac,
+            Err("can't install any version on Mac")
+        )
+    )]
+    fn runtime_found_for_os(version: Version, os: PybinOs, expected: Result<PyVers, &str>) {
+        match (PyVers::find(&version, os), expected) {
+            (Err(e), Err(part)) => assert!(e.contains(part), "{}", e),
+            (actual, expected) => assert_eq!(actual, expected.map_err(str::to_owned)),
+        }
+    }
+
+    #[test]
+    fn installable_versions_listed() {
+        let listed: Vec<String> = installable(PybinOs::Ubuntu)
+            .iter()
+            .map(Version::to_string_med)
+            .collect();
+        assert_eq!(
+            listed,
+            ["3.4", "3.5", "3.6", "3.7", "3.8", "3.9", "3.11", "3.12"]
+        );
+        let err = PyVers::find(&Version::new_short(3, 13), PybinOs::Ubuntu).unwrap_err();
+        assert!(err.ends_with(
+            "pyflow can install these on Linux: 3.4, 3.5, 3.6, 3.7, 3.8, 3.9, 3.11, 3.12"
+        ));
+    }
+}
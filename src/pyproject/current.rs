@@ -1,4 +1,9 @@
-use std::{env, path::PathBuf};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::Once,
+};
 
 use termcolor::Color;
 
@@ -13,30 +18,19 @@ To get started, run `pyflow new projname` to create a project folder, or
 "#};
 
 pub fn get_config() -> Option<PresentConfig> {
-    let mut config_path = PathBuf::from(CFG_FILENAME);
-    if !&config_path.exists() {
-        // Try looking recursively in parent directories for a config file.
-        let recursion_limit = 8; // How my levels to look up
-        let mut current_level = env::current_dir().expect("Can't access current directory");
-        for _ in 0..recursion_limit {
-            if let Some(parent) = current_level.parent() {
-                let parent_cfg_path = parent.join(CFG_FILENAME);
-                if parent_cfg_path.exists() {
-                    config_path = parent_cfg_path;
-                    break;
-                }
-                current_level = parent.to_owned();
-            }
-        }
-
-        if !&config_path.exists() {
-            // we still can't find it after searching parents.
+    let config_path = match find_config_path() {
+        Some(p) => p,
+        None => {
             util::print_color(NOT_FOUND_ERROR_MESSAGE, Color::Cyan); // Dark Cyan
             return None;
         }
-    }
+    };
+    Some(present_config(config_path))
+}
 
-    // Base pypackages_path and lock_path on the `pyproject.toml` folder.
+/// Base all project paths on the `pyproject.toml` folder, never the current directory, so
+/// running from a subfolder doesn't create files there.
+fn present_config(config_path: PathBuf) -> PresentConfig {
     let project_path = config_path
         .parent()
         .expect("Can't find project path via parent")
@@ -45,12 +39,162 @@ pub fn get_config() -> Option<PresentConfig> {
     let lock_path = project_path.join(LOCK_FILENAME);
 
     let mut config = Config::from_file(&config_path).unwrap_or_default();
+    config.resolve_req_paths(&project_path);
     config.populate_path_subreqs();
-    Some(PresentConfig {
+    PresentConfig {
         config,
         config_path,
         project_path,
         pypackages_path,
         lock_path,
-    })
+    }
+}
+
+/// If `pyproject.toml` isn't set up for pyflow, or another dependency manager, eg it only holds
+/// black's settings, ask before adding pyflow's tables to it; declining leaves it untouched.
+/// Without a terminal to ask on, exits with code 2, unless `init` is set.
+pub fn adopt_unmanaged(pcfg: PresentConfig, init: bool) -> PresentConfig {
+    let found = match fs::read_to_string(&pcfg.config_path)
+        .ok()
+        .and_then(|data| super::unmanaged_tables(&data))
+    {
+        Some(f) => f,
+        None => return pcfg,
+    };
+
+    let found = if found.is_empty() {
+        "It's empty.".to_owned()
+    } else {
+        let tables: Vec<String> = found.iter().map(|t| format!("`[{}]`", t)).collect();
+        format!("It contains {}.", tables.join(", "))
+    };
+    util::print_color(
+        &format!(
+            "{} has no `[tool.pyflow]` section, or dependencies. {}",
+            pcfg.config_path.display(),
+            found
+        ),
+        Color::Yellow,
+    );
+
+    if !init {
+        if !util::prompts::interactive() {
+            util::print_color(
+                "Run `pyflow install --init` to add pyflow's sections to it.",
+                Color::Red,
+            );
+            process::exit(2);
+        }
+        if !util::prompts::confirm(
+            "Add pyflow's sections to the end of it?",
+            "Run `pyflow install --init` to add them.",
+        ) {
+            util::abort("Leaving `pyproject.toml` unchanged.");
+        }
+    }
+
+    let mut cfg = pcfg.config;
+    cfg.py_version = Some(
+        util::prompts::py_vers(
+            "Add `py_version` to `[tool.pyflow]` in `pyproject.toml`, eg `py_version = \"3.11\"`.",
+        )
+        .into(),
+    );
+    cfg.append_to_file(&pcfg.config_path);
+    util::print_color("Added pyflow's sections to `pyproject.toml`", Color::Green);
+    present_config(pcfg.config_path)
+}
+
+static PYPACKAGES_NOTICE: Once = Once::new();
+
+/// Find `pyproject.toml` in the current directory, or a parent.
+pub fn find_config_path() -> Option<PathBuf> {
+    let current_dir = env::current_dir().expect("Can't access current directory");
+    // Packages installed in `__pypackages__` may include their own `pyproject.toml`.
+    let start = match pypackages_root(&current_dir) {
+        Some(root) => {
+            // Some commands look up the config more than once.
+            PYPACKAGES_NOTICE.call_once(|| {
+                util::print_color(
+                    &format!(
+                        "You're inside `__pypackages__`; using the project at {}",
+                        root.to_string_lossy()
+                    ),
+                    Color::Yellow,
+                )
+            });
+            root
+        }
+        None => current_dir,
+    };
+    find_config_from(&start)
+}
+
+/// If `dir` is inside a `__pypackages__` folder, the folder that contains it.
+fn pypackages_root(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .filter(|a| a.file_name().is_some_and(|n| n == "__pypackages__"))
+        .last()
+        .and_then(Path::parent)
+        .map(Path::to_path_buf)
+}
+
+fn find_config_from(dir: &Path) -> Option<PathBuf> {
+    let recursion_limit = 8; // How my levels to look up
+    dir.ancestors()
+        .take(recursion_limit + 1)
+        .map(|d| d.join(CFG_FILENAME))
+        .find(|p| p.exists())
+        .and_then(|p| p.canonicalize().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rstest::rstest;
+
+    use super::*;
+
+    /// Find the config from inside generated folders, as when running `pyflow install` there.
+    #[rstest(
+        subdir,
+        case("."),
+        case("src/mylib"),
+        case("__pypackages__"),
+        case("__pypackages__/3.10/lib"),
+        case("__pypackages__/3.10/lib/saturn"),
+        case("__pypackages__/3.10/bin")
+    )]
+    fn config_found_from_subdirs(subdir: &str) {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().to_owned();
+        let lib = root.join("__pypackages__/3.10/lib");
+        fs::create_dir_all(lib.join("saturn")).unwrap();
+        fs::create_dir_all(root.join("__pypackages__/3.10/bin")).unwrap();
+        fs::create_dir_all(root.join("src/mylib")).unwrap();
+        fs::create_dir_all(root.join("deps/numpy")).unwrap();
+        fs::write(
+            root.join(CFG_FILENAME),
+            "[tool.pyflow]\nname = \"mylib\"\n\n\
+             [tool.pyflow.dependencies]\nnumpy = { path = \"deps/numpy\" }\n",
+        )
+        .unwrap();
+        // An installed package that ships its own config.
+        fs::write(lib.join("saturn").join(CFG_FILENAME), "[tool.pyflow]\n").unwrap();
+
+        let cwd = root.join(subdir);
+        let start = pypackages_root(&cwd).unwrap_or(cwd);
+        let pcfg = present_config(find_config_from(&start).unwrap());
+
+        let root = root.canonicalize().unwrap();
+        assert_eq!(pcfg.project_path, root);
+        assert_eq!(pcfg.pypackages_path, root.join("__pypackages__"));
+        assert_eq!(pcfg.lock_path, root.join(LOCK_FILENAME));
+        assert_eq!(
+            pcfg.config.reqs[0].path.as_deref(),
+            Some(root.join("deps/numpy").to_str().unwrap())
+        );
+        assert!(!lib.join("__pypackages__").exists());
+    }
 }
@@ -1,17 +1,23 @@
 pub mod current;
+pub mod settings;
+pub mod validate;
 
 use std::{
     collections::HashMap,
-    fs,
+    fmt, fs,
     path::{Path, PathBuf},
     str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use regex::Regex;
 use serde::Deserialize;
+use termcolor::Color;
 
 use crate::{
-    dep_types::{Constraint, Req, Version},
+    build_tools,
+    dep_resolution::Resolution,
+    dep_types::{Constraint, Marker, Req, Version, VersionSpec},
     files,
     util::{self, abort},
 };
@@ -28,31 +34,154 @@ pub struct PresentConfig {
     pub config: Config,
 }
 
+static WARNED_PYTHON2: AtomicBool = AtomicBool::new(false);
+
+/// The Python version for Poetry's `python` constraint, eg 3.9 for `^3.9`. With alternatives, eg
+/// `^2.7 || ^3.9`, the first that allows Python 3.
+fn poetry_py_version(constrs: &str) -> Option<Version> {
+    let versions: Vec<Version> = constrs
+        .split("||")
+        .filter_map(|alt| {
+            let constraints = Constraint::from_str_multiple(alt).ok()?;
+            constraints.first().map(|c| c.version.clone())
+        })
+        .collect();
+    versions
+        .iter()
+        .find(|v| v.major != Some(2))
+        .or(versions.first())
+        .cloned()
+}
+
+/// A project that only allows Python 2 still loads, so commands that don't need an environment
+/// work; we only stop if one would be created. Warned about once, however often it's loaded.
+fn warn_python2(constrs: &str) {
+    if WARNED_PYTHON2.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    util::print_color(
+        &format!(
+            "`python = \"{}\"` in `[tool.poetry.dependencies]` only allows Python 2, which \
+             pyflow can't create environments for. Set a Python 3 `py_version` under \
+             `[tool.pyflow]` to install, eg with `pyflow config set py_version 3.11`.",
+            constrs
+        ),
+        Color::Yellow,
+    );
+}
+
 /// A config, parsed from pyproject.toml
 #[derive(Clone, Debug, Default, Deserialize)]
 // todo: Auto-desr some of these
 pub struct Config {
     pub name: Option<String>,
-    pub py_version: Option<Version>,
+    pub py_version: Option<VersionSpec>,
+    pub py_interpreter: Option<PathBuf>, // Path of the `py_version` interpreter, eg a custom build
     pub reqs: Vec<Req>,
     pub dev_reqs: Vec<Req>,
     pub version: Option<Version>,
     pub authors: Vec<String>,
     pub license: Option<String>,
-    pub extras: HashMap<String, String>,
+    pub extras: HashMap<String, Vec<Req>>, // Optional dependencies, by extra name
     pub description: Option<String>,
     pub classifiers: Vec<String>, // https://pypi.org/classifiers/
     pub keywords: Vec<String>,
     pub homepage: Option<String>,
     pub repository: Option<String>,
+    #[allow(dead_code)]
     pub repo_url: Option<String>,
     pub package_url: Option<String>,
     pub readme: Option<String>,
     pub build: Option<String>, // A python file used to build non-python extensions
     //    entry_points: HashMap<String, Vec<String>>, // todo option?
-    pub scripts: HashMap<String, String>, //todo: put under [tool.pyflow.scripts] ?
+    pub scripts: HashMap<String, Script>, // Tasks from `[tool.pyflow.scripts]`
     //    console_scripts: Vec<String>, // We don't parse these; pass them to `setup.py` as-entered.
     pub python_requires: Option<String>,
+    pub build_tools: Vec<Req>, // Pins for `wheel` and `setuptools`
+    pub deps_service_url: Option<String>, // Comma-separated mirrors of the dependency service
+    pub find_links: Vec<String>, // Local directories of release files, relative to the project
+    pub hooks: util::hooks::Hooks, // Commands from `[tool.pyflow.hooks]`
+    pub preserve: Vec<String>, // Installed packages syncing never uninstalls, eg local patches
+    pub load_dotenv: bool,     // Pass variables from the project's `.env` to `pyflow run`
+    pub check_shadowing: bool, // Warn before `pyflow run` if dependencies import from elsewhere
+    pub resolution: Option<Resolution>, // Which compatible versions `pyflow lock` picks
+    pub build_timeout: Option<u64>, // Seconds a build from source may take before it's stopped
+    pub frozen: bool,          // In CI, fail instead of changing this file
+    pub settings: settings::Settings, // Defaults from `[tool.pyflow.settings]`
+}
+
+/// What a script in `[tool.pyflow.scripts]` runs.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub enum ScriptCmd {
+    /// A Python function, eg `jeejah:activate`: (module, function)
+    Function(String, String),
+    /// A command line, eg `pytest -x tests`
+    Shell(String),
+}
+
+impl FromStr for ScriptCmd {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let re = Regex::new(r"^([\w.]+):(\w+)$").unwrap();
+        Ok(match re.captures(s.trim()) {
+            Some(caps) => Self::Function(caps[1].to_owned(), caps[2].to_owned()),
+            None => Self::Shell(s.to_owned()),
+        })
+    }
+}
+
+impl fmt::Display for ScriptCmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Function(module, function) => write!(f, "{}:{}", module, function),
+            Self::Shell(cmd) => write!(f, "{}", cmd),
+        }
+    }
+}
+
+/// A task from `[tool.pyflow.scripts]`. Tasks without a command just run their dependencies.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Script {
+    pub cmd: Option<ScriptCmd>,
+    pub depends_on: Vec<String>,
+}
+
+impl Script {
+    pub fn new(cmd: &str) -> Self {
+        Self {
+            cmd: Some(ScriptCmd::from_str(cmd).unwrap()),
+            depends_on: vec![],
+        }
+    }
+
+    fn from_wrapper(data: files::ScriptComponentWrapper) -> Self {
+        match data {
+            files::ScriptComponentWrapper::A(cmd) => Self::new(&cmd),
+            files::ScriptComponentWrapper::B(subdata) => Self {
+                cmd: subdata.cmd.map(|c| ScriptCmd::from_str(&c).unwrap()),
+                depends_on: subdata.depends_on.unwrap_or_default(),
+            },
+        }
+    }
+
+    /// Format the script as it appears in `pyproject.toml`, eg `"pytest"`, or
+    /// `{ cmd = "pytest", depends_on = ["lint"] }`
+    pub fn to_cfg_value(&self) -> String {
+        let quote = |s: &str| toml::Value::String(s.to_owned()).to_string();
+        match (&self.cmd, self.depends_on.is_empty()) {
+            (Some(cmd), true) => quote(&cmd.to_string()),
+            (cmd, _) => {
+                let mut fields = vec![];
+                if let Some(c) = cmd {
+                    fields.push(format!("cmd = {}", quote(&c.to_string())));
+                }
+                let depends_on: Vec<String> = self.depends_on.iter().map(|d| quote(d)).collect();
+                fields.push(format!("depends_on = [{}]", depends_on.join(", ")));
+                format!("{{ {} }}", fields.join(", "))
+            }
+        }
+    }
 }
 
 impl Config {
@@ -60,64 +189,240 @@ impl Config {
     pub fn parse_deps(deps: HashMap<String, files::DepComponentWrapper>) -> Vec<Req> {
         let mut result = Vec::new();
         for (name, data) in deps {
-            let constraints;
-            let mut extras = None;
-            let mut git = None;
-            let mut path = None;
-            let mut python_version = None;
             match data {
-                files::DepComponentWrapper::A(constrs) => {
-                    constraints = if let Ok(c) = Constraint::from_str_multiple(&constrs) {
-                        c
+                files::DepComponentWrapper::A(value) => {
+                    let req = if let Ok(r) = Req::from_cfg_value(&name, &value) {
+                        r
                     } else {
                         abort(&format!(
-                            "Problem parsing constraints in `pyproject.toml`: {}",
-                            &constrs
+                            "Problem parsing dependency in `pyproject.toml`: {} = \"{}\". \
+                             Expected constraints, optionally followed by `; <markers>`, or a \
+                             git url, like `{} @ git+https://github.com/org/repo`.",
+                            &name, &value, &name
                         ))
                     };
+                    result.push(Req {
+                        constraints: req.constraints,
+                        marker: req.marker,
+                        python_version: req.python_version,
+                        git: req.git,
+                        ..Req::new(name, vec![])
+                    });
                 }
                 files::DepComponentWrapper::B(subdata) => {
-                    constraints = match subdata.constrs {
-                        Some(constrs) => {
-                            if let Ok(c) = Constraint::from_str_multiple(&constrs) {
-                                c
-                            } else {
-                                abort(&format!(
-                                    "Problem parsing constraints in `pyproject.toml`: {}",
-                                    &constrs
-                                ))
-                            }
+                    result.push(Self::parse_dep_component(&name, *subdata))
+                }
+                // Entries for different platforms.
+                files::DepComponentWrapper::C(entries) => {
+                    for subdata in entries {
+                        let req = Self::parse_dep_component(&name, subdata);
+                        if req.marker.is_none() {
+                            abort(&format!(
+                                "Each entry for {} in `pyproject.toml` needs a `platform` or \
+                                 `markers`, so only one applies on each platform",
+                                name
+                            ))
                         }
-                        None => vec![],
-                    };
-
-                    if let Some(ex) = subdata.extras {
-                        extras = Some(ex);
-                    }
-                    if let Some(p) = subdata.path {
-                        path = Some(p);
+                        result.push(req);
                     }
-                    if let Some(repo) = subdata.git {
-                        git = Some(repo);
+                }
+            }
+        }
+        result
+    }
+
+    fn parse_dep_component(name: &str, subdata: files::DepComponent) -> Req {
+        let constraints = match subdata.constrs {
+            Some(constrs) => {
+                if let Ok(c) = Constraint::from_str_multiple(&constrs) {
+                    c
+                } else {
+                    abort(&format!(
+                        "Problem parsing constraints in `pyproject.toml`: {}",
+                        &constrs
+                    ))
+                }
+            }
+            None => vec![],
+        };
+
+        let python_version = subdata.python.map(|v| {
+            vec![Constraint::from_str(&v).expect("Problem parsing python version in dependency")]
+        });
+
+        let mut markers = vec![];
+        if let Some(platform) = subdata.platform {
+            match platform_marker(&platform) {
+                Some(m) => markers.push(m),
+                None => abort(&format!(
+                    "Problem parsing the platform for {} in `pyproject.toml`: {}. Expected \
+                     `linux`, `windows`, or `macos`",
+                    name, platform
+                )),
+            }
+        }
+        if let Some(m) = subdata.markers {
+            match Marker::from_str(&m) {
+                Ok(m) => markers.push(m),
+                Err(_) => abort(&format!(
+                    "Problem parsing the markers for {} in `pyproject.toml`: {}",
+                    name, m
+                )),
+            }
+        }
+        let marker = match markers.len() {
+            0 => None,
+            1 => markers.pop(),
+            _ => Some(Marker::And(markers)),
+        };
+
+        Req {
+            name: name.to_owned(),
+            constraints,
+            extra: None,
+            python_version,
+            marker,
+            install_with_extras: subdata.extras,
+            path: subdata.path,
+            git: subdata.git.map(|repo| {
+                git_url(
+                    repo,
+                    subdata.branch.or(subdata.tag).or(subdata.rev),
+                    subdata.subdirectory,
+                )
+            }),
+            url: None,
+            allow_prereleases: subdata.allow_prereleases.unwrap_or(false),
+            editable: subdata.editable.unwrap_or(false),
+            wheel_tag: subdata.wheel_tag,
+        }
+    }
+
+    /// Parse extras, eg `{ cli = ["click", "rich>=13"] }`. Entries that are only a package name,
+    /// as in Poetry, use the constraints of the dependency with that name in `declared`.
+    fn parse_extras(
+        extras: HashMap<String, Vec<String>>,
+        declared: &[Req],
+    ) -> HashMap<String, Vec<Req>> {
+        let name_re = Regex::new(r"^[\w.-]+$").unwrap();
+        let mut result = HashMap::new();
+        for (extra, entries) in extras {
+            let reqs = entries
+                .iter()
+                .map(|entry| {
+                    let entry = entry.trim();
+                    let declared_req = declared
+                        .iter()
+                        .find(|r| name_re.is_match(entry) && util::compare_names(&r.name, entry));
+                    match declared_req {
+                        Some(r) => r.clone(),
+                        None => Req::from_pip_str(entry).unwrap_or_else(|| {
+                            abort(&format!(
+                                "Problem parsing the requirement `{}` in the extra `{}` in \
+                                 `pyproject.toml`",
+                                entry, extra
+                            ))
+                        }),
                     }
+                })
+                .collect();
+            result.insert(extra, reqs);
+        }
+        result
+    }
+
+    /// Parse Poetry dependencies. Returns normal ones, and optional ones, which are only
+    /// installed as part of an extra.
+    fn parse_poetry_deps(
+        deps: HashMap<String, files::DepComponentWrapperPoetry>,
+    ) -> (Vec<Req>, Vec<Req>) {
+        let mut reqs = vec![];
+        let mut optional_reqs = vec![];
+        for (name, data) in deps {
+            let mut req = Req::new(name, vec![]);
+            let mut optional = false;
+            let constrs = match data {
+                files::DepComponentWrapperPoetry::A(constrs) => Some(constrs),
+                files::DepComponentWrapperPoetry::B(subdata) => {
+                    let subdata = *subdata;
+                    req.install_with_extras = subdata.extras;
                     if let Some(v) = subdata.python {
                         let pv = Constraint::from_str(&v)
                             .expect("Problem parsing python version in dependency");
-                        python_version = Some(vec![pv]);
+                        req.python_version = Some(vec![pv]);
                     }
+                    optional = subdata.optional.unwrap_or(false);
+                    req.allow_prereleases = subdata.allow_prereleases.unwrap_or(false);
+                    req.path = subdata.path;
+                    req.git = subdata.git.map(|repo| {
+                        git_url(
+                            repo,
+                            subdata.branch.or(subdata.tag).or(subdata.rev),
+                            subdata.subdirectory,
+                        )
+                    });
+                    req.url = subdata.url;
+                    // todo repository etc
+                    subdata.constrs
                 }
+            };
+            if let Some(constrs) = constrs {
+                req.constraints = Constraint::from_str_multiple(&constrs)
+                    .expect("Problem parsing constraints in `pyproject.toml`.");
+            }
+            if optional {
+                optional_reqs.push(req);
+            } else {
+                reqs.push(req);
             }
+        }
+        (reqs, optional_reqs)
+    }
+
+    /// Find the requirements of the extras specified, eg from `pyflow install -e . --extras cli`.
+    pub fn extra_reqs(&self, extras: &[String]) -> Vec<Req> {
+        let mut result = vec![];
+        for extra in extras {
+            match self.extras.get(extra) {
+                Some(reqs) => result.extend(reqs.iter().cloned()),
+                None => {
+                    let mut available: Vec<&String> = self.extras.keys().collect();
+                    available.sort();
+                    abort(&format!(
+                        "Can't find the extra `{}` in `pyproject.toml`. Available extras: {}",
+                        extra,
+                        available
+                            .iter()
+                            .map(|e| e.as_str())
+                            .collect::<Vec<&str>>()
+                            .join(", ")
+                    ))
+                }
+            }
+        }
+        result
+    }
 
-            result.push(Req {
-                name,
-                constraints,
-                extra: None,
-                sys_platform: None,
-                python_version,
-                install_with_extras: extras,
-                path,
-                git,
-            });
+    /// Parse `build_tools` pins, eg `{ wheel = "0.41.2", setuptools = ">=68" }`.
+    fn parse_build_tools(tools: HashMap<String, String>) -> Vec<Req> {
+        let mut result = Vec::new();
+        for (name, constrs) in tools {
+            if !build_tools::TOOLS.contains(&name.as_str()) {
+                abort(&format!(
+                    "Unknown build tool in `pyproject.toml`: {}. Expected one of: {}",
+                    name,
+                    build_tools::TOOLS.join(", ")
+                ))
+            }
+            let constraints = if let Ok(c) = Constraint::from_str_multiple(&constrs) {
+                c
+            } else {
+                abort(&format!(
+                    "Problem parsing build tool constraints in `pyproject.toml`: {}",
+                    &constrs
+                ))
+            };
+            result.push(Req::new(name, constraints));
         }
         result
     }
@@ -126,10 +431,7 @@ impl Config {
     pub fn from_pipfile(path: &Path) -> Option<Self> {
         // todo: Lots of tweaks and QC could be done re what fields to parse, and how best to
         // todo parse and store them.
-        let toml_str = match fs::read_to_string(path).ok() {
-            Some(d) => d,
-            None => return None,
-        };
+        let toml_str = fs::read_to_string(path).ok()?;
 
         let decoded: files::Pipfile = if let Ok(d) = toml::from_str(&toml_str) {
             d
@@ -158,6 +460,24 @@ impl Config {
             Err(_) => return None,
         };
 
+        let validation = validate::validate(&toml_str);
+        for warning in &validation.warnings {
+            util::print_color(
+                &format!("Warning in `pyproject.toml`: {}", warning),
+                Color::Yellow,
+            );
+        }
+        if let Some(e) = &validation.parse_error {
+            abort(&format!("Problem parsing `pyproject.toml`: {}", e));
+        }
+        if !validation.errors.is_empty() {
+            let errors: Vec<String> = validation.errors.iter().map(|e| e.to_string()).collect();
+            abort(&format!(
+                "Problems in `pyproject.toml`:\n{}",
+                errors.join("\n")
+            ));
+        }
+
         let decoded: files::Pyproject = if let Ok(d) = toml::from_str(&toml_str) {
             d
         } else {
@@ -206,9 +526,6 @@ impl Config {
             //            if let Some(v) = po.scripts {
             //                result.console_scripts = v;
             //            }
-            if let Some(v) = po.extras {
-                result.extras = v;
-            }
 
             if let Some(v) = po.version {
                 result.version = Some(
@@ -216,54 +533,48 @@ impl Config {
                 )
             }
 
-            // todo: DRY (c+p) from pyflow dependency parsing, other than parsing python version here,
-            // todo which only poetry does.
-            // todo: Parse poetry dev deps
-            if let Some(deps) = po.dependencies {
-                for (name, data) in deps {
-                    let constraints;
-                    let mut extras = None;
-                    let mut python_version = None;
-                    match data {
-                        files::DepComponentWrapperPoetry::A(constrs) => {
-                            constraints = Constraint::from_str_multiple(&constrs)
-                                .expect("Problem parsing constraints in `pyproject.toml`.");
-                        }
-                        files::DepComponentWrapperPoetry::B(subdata) => {
-                            constraints = Constraint::from_str_multiple(&subdata.constrs)
-                                .expect("Problem parsing constraints in `pyproject.toml`.");
-                            if let Some(ex) = subdata.extras {
-                                extras = Some(ex);
-                            }
-                            if let Some(v) = subdata.python {
-                                let pv = Constraint::from_str(&v)
-                                    .expect("Problem parsing python version in dependency");
-                                python_version = Some(vec![pv]);
-                            }
-                            // todo repository etc
-                        }
-                    }
-                    if &name.to_lowercase() == "python" {
-                        if let Some(constr) = constraints.get(0) {
-                            result.py_version = Some(constr.version.clone())
+            // Optional dependencies are only installed as part of an extra.
+            let mut optional_reqs = vec![];
+            if let Some(mut deps) = po.dependencies {
+                let python = deps
+                    .keys()
+                    .find(|name| name.to_lowercase() == "python")
+                    .cloned();
+                let python_constrs = match python.and_then(|p| deps.remove(&p)) {
+                    Some(files::DepComponentWrapperPoetry::A(constrs)) => Some(constrs),
+                    Some(files::DepComponentWrapperPoetry::B(subdata)) => subdata.constrs,
+                    None => None,
+                };
+                if let Some(constrs) = python_constrs {
+                    if let Some(version) = poetry_py_version(&constrs) {
+                        if version.major == Some(2) {
+                            warn_python2(&constrs);
                         }
-                    } else {
-                        result.reqs.push(Req {
-                            name,
-                            constraints,
-                            extra: None,
-                            sys_platform: None,
-                            python_version,
-                            install_with_extras: extras,
-                            path: None,
-                            git: None,
-                        });
+                        result.py_version = Some(version.into())
                     }
                 }
+                let (reqs, optional) = Self::parse_poetry_deps(deps);
+                result.reqs = reqs;
+                optional_reqs = optional;
+            }
+            // Dev dependencies, from Poetry's old format, or any dependency group.
+            let groups = po.group.into_iter().flatten().map(|(_, g)| g.dependencies);
+            for deps in po.dev_dependencies.into_iter().chain(groups.flatten()) {
+                let (reqs, optional) = Self::parse_poetry_deps(deps);
+                result.dev_reqs.extend(reqs);
+                result.dev_reqs.extend(optional);
+            }
+            if let Some(v) = po.extras {
+                result.extras = Self::parse_extras(v, &optional_reqs);
             }
         }
 
+        if let Some(v) = decoded.project.and_then(|p| p.optional_dependencies) {
+            result.extras.extend(Self::parse_extras(v, &[]));
+        }
+
         if let Some(pf) = decoded.tool.pyflow {
+            result.settings = settings::Settings::from_pyflow(&pf);
             if let Some(v) = pf.name {
                 result.name = Some(v);
             }
@@ -305,7 +616,10 @@ impl Config {
             //                result.entry_points = v;
             //            } // todo
             if let Some(v) = pf.scripts {
-                result.scripts = v;
+                result.scripts = v
+                    .into_iter()
+                    .map(|(name, data)| (name, Script::from_wrapper(data)))
+                    .collect();
             }
 
             if let Some(v) = pf.python_requires {
@@ -324,10 +638,13 @@ impl Config {
 
             if let Some(v) = pf.py_version {
                 result.py_version = Some(
-                    Version::from_str(&v)
+                    VersionSpec::from_str(&v)
                         .expect("Problem parsing python version in `pyproject.toml`"),
                 );
             }
+            if let Some(v) = pf.py_interpreter {
+                result.py_interpreter = Some(PathBuf::from(v));
+            }
 
             if let Some(deps) = pf.dependencies {
                 result.reqs = Self::parse_deps(deps);
@@ -335,25 +652,107 @@ impl Config {
             if let Some(deps) = pf.dev_dependencies {
                 result.dev_reqs = Self::parse_deps(deps);
             }
+            if let Some(tools) = pf.build_tools {
+                result.build_tools = Self::parse_build_tools(tools);
+            }
+            if let Some(v) = pf.deps_service_url {
+                result.deps_service_url = Some(v);
+            }
+            if let Some(v) = pf.find_links {
+                result.find_links = v;
+            }
+            if let Some(v) = pf.preserve {
+                result.preserve = v;
+            }
+            if let Some(v) = pf.load_dotenv {
+                result.load_dotenv = v;
+            }
+            if let Some(v) = pf.check_shadowing {
+                result.check_shadowing = v;
+            }
+            if let Some(v) = pf.build_timeout {
+                result.build_timeout = Some(v);
+            }
+            if let Some(v) = pf.frozen {
+                result.frozen = v;
+            }
+            if let Some(v) = pf.resolution {
+                result.resolution =
+                    Some(Resolution::from_str(&v).unwrap_or_else(|e| abort(&e.details)));
+            }
+            if let Some(h) = pf.hooks {
+                let cmds = |c: Option<files::HookCmdsWrapper>| c.map(|c| c.into_vec());
+                result.hooks = util::hooks::Hooks {
+                    pre_lock: cmds(h.pre_lock).unwrap_or_default(),
+                    post_lock: cmds(h.post_lock).unwrap_or_default(),
+                    pre_install: cmds(h.pre_install).unwrap_or_default(),
+                    post_install: cmds(h.post_install).unwrap_or_default(),
+                };
+            }
+            if let Some(v) = pf.extras {
+                let extras = Self::parse_extras(v, &result.reqs);
+                result.extras.extend(extras);
+            }
         }
 
         Some(result)
     }
 
+    /// Make the paths of `path` reqs relative to the project, rather than the current directory.
+    pub fn resolve_req_paths(&mut self, project_path: &Path) {
+        for req in self.reqs.iter_mut().chain(self.dev_reqs.iter_mut()) {
+            if let Some(path) = &req.path {
+                req.path = Some(project_path.join(path).to_string_lossy().into_owned());
+            }
+        }
+    }
+
     /// For reqs of `path` type, add their sub-reqs by parsing `setup.py` or `pyproject.toml`.
+    /// Ones we already have are skipped, eg the project's own, if it's installed in place.
     pub fn populate_path_subreqs(&mut self) {
-        self.reqs.append(&mut pop_reqs_helper(&self.reqs, false));
-        self.dev_reqs
-            .append(&mut pop_reqs_helper(&self.dev_reqs, true));
+        let new = |existing: &[Req], subreqs: Vec<Req>| -> Vec<Req> {
+            subreqs
+                .into_iter()
+                .filter(|s| {
+                    !existing
+                        .iter()
+                        .any(|r| util::compare_names(&r.name, &s.name))
+                })
+                .collect()
+        };
+        let mut subreqs = new(&self.reqs, pop_reqs_helper(&self.reqs, false));
+        self.reqs.append(&mut subreqs);
+        let mut subreqs = new(&self.dev_reqs, pop_reqs_helper(&self.dev_reqs, true));
+        self.dev_reqs.append(&mut subreqs);
     }
 
     /// Create a new `pyproject.toml` file.
     pub fn write_file(&self, path: &Path) {
-        let file = path;
-        if file.exists() {
+        if path.exists() {
             abort("`pyproject.toml` already exists")
         }
+        files::write_cfg(
+            path,
+            &self.pyflow_tables(),
+            "create it, with pyflow's sections",
+        );
+    }
+
+    /// Add pyflow's tables to the end of an existing `pyproject.toml`. What's already there is
+    /// kept as written.
+    pub fn append_to_file(&self, path: &Path) {
+        let mut data = match fs::read_to_string(path) {
+            Ok(d) => d,
+            Err(_) => abort("Problem reading `pyproject.toml`"),
+        };
+        if !data.is_empty() && !data.ends_with('\n') {
+            data.push('\n');
+        }
+        data.push_str(&self.pyflow_tables());
+        files::write_cfg(path, &data, "add pyflow's sections to the end of it");
+    }
 
+    fn pyflow_tables(&self) -> String {
         let mut result = String::new();
 
         result.push_str("\n[tool.pyflow]\n");
@@ -364,10 +763,14 @@ impl Config {
             result.push_str(&("name = \"\"".to_owned() + "\n"));
         }
         if let Some(py_v) = &self.py_version {
-            result.push_str(&("py_version = \"".to_owned() + &py_v.to_string_no_patch() + "\"\n"));
+            result.push_str(&("py_version = \"".to_owned() + &py_v.raw + "\"\n"));
         } else {
             result.push_str(&("py_version = \"3.8\"".to_owned() + "\n"));
         }
+        if let Some(path) = &self.py_interpreter {
+            let path = toml::Value::String(path.to_string_lossy().into_owned());
+            result.push_str(&format!("py_interpreter = {}\n", path));
+        }
         if let Some(vers) = self.version.clone() {
             result.push_str(&(format!("version = \"{}\"", vers.to_string() + "\n")));
         } else {
@@ -396,8 +799,8 @@ impl Config {
 
         result.push('\n');
         result.push_str("[tool.pyflow.scripts]\n");
-        for (name, mod_fn) in &self.scripts {
-            result.push_str(&(format!("{} = \"{}\"", name, mod_fn) + "\n"));
+        for (name, script) in &self.scripts {
+            result.push_str(&(format!("{} = {}", name, script.to_cfg_value()) + "\n"));
         }
 
         result.push('\n');
@@ -413,16 +816,65 @@ impl Config {
         }
 
         result.push('\n'); // trailing newline
+        result
+    }
+}
+
+/// The tables in `toml_str`, eg `tool.black`, if none of them are pyflow's, or list
+/// dependencies for Poetry or PEP 621; ie the file only configures other tools.
+pub fn unmanaged_tables(toml_str: &str) -> Option<Vec<String>> {
+    let value: toml::Value = toml::from_str(toml_str).ok()?;
+    let has = |table: Option<&toml::Value>, key: &str| table.and_then(|t| t.get(key)).is_some();
+    let tool = value.get("tool");
+    let poetry = tool.and_then(|t| t.get("poetry"));
+    if has(tool, "pyflow")
+        || ["dependencies", "dev-dependencies", "group"]
+            .iter()
+            .any(|k| has(poetry, k))
+        || has(value.get("project"), "dependencies")
+    {
+        return None;
+    }
 
-        if fs::write(file, result).is_err() {
-            abort("Problem writing `pyproject.toml`")
+    let mut found = Vec::new();
+    for (key, v) in value.as_table()? {
+        match (key.as_str(), v.as_table()) {
+            ("tool", Some(tools)) => found.extend(tools.keys().map(|k| format!("tool.{}", k))),
+            _ => found.push(key.clone()),
         }
     }
+    Some(found)
+}
+
+/// Add a ref and `subdirectory` from a dependency table to its git url, as pip does, eg
+/// `https://github.com/org/mono@v1.2#subdirectory=libs/client`.
+fn git_url(repo: String, git_ref: Option<String>, subdirectory: Option<String>) -> String {
+    let repo = match git_ref {
+        Some(git_ref) => format!("{}@{}", repo, git_ref),
+        None => repo,
+    };
+    match subdirectory {
+        Some(sub) => format!("{}#subdirectory={}", repo, sub),
+        None => repo,
+    }
+}
+
+/// The `sys_platform` marker for a dependency's `platform`, eg `windows`.
+fn platform_marker(platform: &str) -> Option<Marker> {
+    let sys_platform = match platform.to_lowercase().as_str() {
+        "linux" => "linux",
+        "windows" | "win32" => "win32",
+        "macos" | "mac" | "darwin" => "darwin",
+        _ => return None,
+    };
+    Marker::from_str(&format!("sys_platform == '{}'", sys_platform)).ok()
 }
 
 /// Reduce repetition between reqs and dev reqs when populating reqs of path reqs.
 fn pop_reqs_helper(reqs: &[Req], dev: bool) -> Vec<Req> {
     let mut result = vec![];
+    // todo: Dry from `util` and `install`.
+    let re_dist = Regex::new(r"^(.*?)-(.*?)\.dist-info$").unwrap();
     for req in reqs.iter().filter(|r| r.path.is_some()) {
         let req_path = PathBuf::from(req.path.clone().unwrap());
         let pyproj = req_path.join("pyproject.toml");
@@ -455,11 +907,11 @@ fn pop_reqs_helper(reqs: &[Req], dev: bool) -> Vec<Req> {
 
         // Check for metadata of a built wheel
         for folder_name in util::find_folders(&req_path) {
-            // todo: Dry from `util` and `install`.
-            let re_dist = Regex::new(r"^(.*?)-(.*?)\.dist-info$").unwrap();
             if re_dist.captures(&folder_name).is_some() {
                 let metadata_path = req_path.join(folder_name).join("METADATA");
-                let mut metadata = util::parse_metadata(&metadata_path);
+                let mut metadata = util::parse_metadata(&metadata_path)
+                    .unwrap_or_else(|e| util::abort(&e.details));
+                util::print_metadata_warnings(&metadata);
 
                 result.append(&mut metadata.requires_dist);
             }
@@ -467,3 +919,262 @@ fn pop_reqs_helper(reqs: &[Req], dev: bool) -> Vec<Req> {
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dep_types::ReqType;
+
+    #[test]
+    fn extras_from_each_format() {
+        let cfg_str = r#"
+[project.optional-dependencies]
+yaml = ["PyYAML>=6.0"]
+
+[tool.poetry.dependencies]
+click = { version = "^8.1", optional = true }
+requests = "^2.28"
+
+[tool.poetry.extras]
+cli = ["click"]
+
+[tool.pyflow]
+name = "mylib"
+
+[tool.pyflow.extras]
+docs = ["sphinx"]
+"#;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pyproject.toml");
+        fs::write(&path, cfg_str).unwrap();
+        let cfg = Config::from_file(&path).unwrap();
+
+        // Optional Poetry dependencies are only installed with their extra.
+        assert_eq!(cfg.reqs.len(), 1);
+        assert_eq!(cfg.reqs[0].name, "requests");
+
+        let cli = cfg.extra_reqs(&["cli".to_owned()]);
+        assert_eq!(cli.len(), 1);
+        assert_eq!(cli[0].name, "click");
+        assert_eq!(cli[0].constraints[0].type_, ReqType::Caret);
+
+        let yaml = cfg.extra_reqs(&["yaml".to_owned()]);
+        assert_eq!(yaml[0].name, "PyYAML");
+        assert_eq!(yaml[0].constraints[0].version, Version::new(6, 0, 0));
+
+        assert_eq!(cfg.extra_reqs(&["docs".to_owned()])[0].name, "sphinx");
+    }
+    #[test]
+    fn allow_prereleases_per_dep() {
+        let cfg_str = r#"
+[tool.pyflow.dependencies]
+fastapi = { version = ">=0.95", allow-prereleases = true }
+requests = "^2.28"
+"#;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pyproject.toml");
+        fs::write(&path, cfg_str).unwrap();
+        let cfg = Config::from_file(&path).unwrap();
+
+        let allowed = |name: &str| {
+            cfg.reqs
+                .iter()
+                .find(|r| r.name == name)
+                .unwrap()
+                .allow_prereleases
+        };
+        assert!(allowed("fastapi"));
+        assert!(!allowed("requests"));
+    }
+
+    #[test]
+    fn git_subdirectory_in_url() {
+        let cfg_str = r#"
+[tool.pyflow.dependencies]
+client = { git = "https://github.com/org/mono", subdirectory = "libs/client" }
+saturn = { git = "https://github.com/org/saturn" }
+titan = { git = "https://github.com/org/titan", rev = "v2.0", subdirectory = "py" }
+"#;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pyproject.toml");
+        fs::write(&path, cfg_str).unwrap();
+        let cfg = Config::from_file(&path).unwrap();
+
+        let git = |name: &str| {
+            cfg.reqs
+                .iter()
+                .find(|r| r.name == name)
+                .unwrap()
+                .git
+                .clone()
+        };
+        assert_eq!(
+            git("client").as_deref(),
+            Some("https://github.com/org/mono#subdirectory=libs/client")
+        );
+        assert_eq!(
+            git("saturn").as_deref(),
+            Some("https://github.com/org/saturn")
+        );
+        assert_eq!(
+            git("titan").as_deref(),
+            Some("https://github.com/org/titan@v2.0#subdirectory=py")
+        );
+    }
+
+    #[test]
+    fn poetry_dev_deps_and_groups() {
+        let cfg_str = r#"
+[tool.poetry.dependencies]
+python = "^3.9"
+mylib = { path = "../mylib" }
+requests = "^2.28"
+
+[tool.poetry.dev-dependencies]
+pytest = "^7.0"
+
+[tool.poetry.group.lint.dependencies]
+black = "^23.1"
+"#;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pyproject.toml");
+        fs::write(&path, cfg_str).unwrap();
+        let cfg = Config::from_file(&path).unwrap();
+
+        assert_eq!(cfg.py_version.unwrap().parsed, Version::new_short(3, 9));
+        let mut reqs: Vec<(&str, Option<&str>)> = cfg
+            .reqs
+            .iter()
+            .map(|r| (r.name.as_str(), r.path.as_deref()))
+            .collect();
+        reqs.sort();
+        assert_eq!(reqs, vec![("mylib", Some("../mylib")), ("requests", None)]);
+
+        let mut dev_reqs: Vec<&str> = cfg.dev_reqs.iter().map(|r| r.name.as_str()).collect();
+        dev_reqs.sort();
+        assert_eq!(dev_reqs, vec!["black", "pytest"]);
+    }
+
+    #[test]
+    fn poetry_python2_constraints() {
+        let cfg_str = r#"
+[tool.poetry.dependencies]
+python = "^2.7"
+requests = "^2.20"
+"#;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pyproject.toml");
+        fs::write(&path, cfg_str).unwrap();
+        let cfg = Config::from_file(&path).unwrap();
+        // Kept, so it's reported if an environment's needed.
+        assert_eq!(cfg.py_version.unwrap().parsed, Version::new_short(2, 7));
+        assert_eq!(cfg.reqs[0].name, "requests");
+        assert!(WARNED_PYTHON2.load(Ordering::Relaxed));
+
+        assert_eq!(
+            poetry_py_version("^2.7 || ^3.6"),
+            Some(Version::new_short(3, 6))
+        );
+        assert_eq!(
+            poetry_py_version("~2.7 || >=3.8,<4"),
+            Some(Version::new_short(3, 8))
+        );
+        assert_eq!(
+            poetry_py_version(">=2.7,<3"),
+            Some(Version::new_short(2, 7))
+        );
+        assert_eq!(poetry_py_version("not a version"), None);
+    }
+
+    #[test]
+    fn hooks_from_strings_or_lists() {
+        let cfg_str = r#"
+[tool.pyflow.hooks]
+pre_install = "python scripts/check_licenses.py"
+post_install = ["python -m grpc_tools.protoc proto/api.proto", "black api_pb2.py"]
+"#;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pyproject.toml");
+        fs::write(&path, cfg_str).unwrap();
+        let cfg = Config::from_file(&path).unwrap();
+
+        assert_eq!(
+            cfg.hooks.pre_install,
+            vec!["python scripts/check_licenses.py"]
+        );
+        assert_eq!(cfg.hooks.post_install.len(), 2);
+        assert_eq!(cfg.hooks.post_install[1], "black api_pb2.py");
+        assert!(cfg.hooks.pre_lock.is_empty());
+    }
+
+    #[test]
+    fn platform_entries() {
+        let cfg_str = r#"
+[tool.pyflow.dependencies]
+pywin32 = { version = "^306", platform = "windows" }
+torch = [
+    { version = "2.1.0", platform = "linux", markers = "platform_machine == 'x86_64'" },
+    { version = "2.0.1", platform = "macos" },
+]
+"#;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pyproject.toml");
+        fs::write(&path, cfg_str).unwrap();
+        let cfg = Config::from_file(&path).unwrap();
+
+        let marker = |name: &str, version: &str| {
+            let req = cfg
+                .reqs
+                .iter()
+                .find(|r| {
+                    r.name == name
+                        && r.constraints[0].version == Version::from_str(version).unwrap()
+                })
+                .unwrap();
+            req.marker.as_ref().unwrap().to_string()
+        };
+        assert_eq!(cfg.reqs.len(), 3);
+        assert_eq!(marker("pywin32", "306"), "sys_platform == 'win32'");
+        assert_eq!(
+            marker("torch", "2.1.0"),
+            "sys_platform == 'linux' and platform_machine == 'x86_64'"
+        );
+        assert_eq!(marker("torch", "2.0.1"), "sys_platform == 'darwin'");
+    }
+
+    #[test]
+    fn pyflow_tables_appended_to_tool_config() {
+        let black = "# Formatting\n[tool.black]\nline-length = 100  # wide\n\n\
+                     [tool.isort]\nprofile='black'";
+        assert_eq!(
+            unmanaged_tables(black),
+            Some(vec!["tool.black".to_owned(), "tool.isort".to_owned()])
+        );
+        assert_eq!(unmanaged_tables("[tool.pyflow]\n"), None);
+        assert_eq!(
+            unmanaged_tables("[tool.poetry.dependencies]\nsix = '*'\n"),
+            None
+        );
+        assert_eq!(
+            unmanaged_tables("[project]\ndependencies = ['six']\n"),
+            None
+        );
+        assert_eq!(
+            unmanaged_tables("[project]\nname = 'demo'\n"),
+            Some(vec!["project".to_owned()])
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pyproject.toml");
+        fs::write(&path, black).unwrap();
+        let mut cfg = Config::from_file(&path).unwrap();
+        cfg.py_version = Some(Version::new(3, 11, 0).into());
+        cfg.append_to_file(&path);
+
+        let data = fs::read_to_string(&path).unwrap();
+        assert!(data.starts_with(&format!("{}\n\n[tool.pyflow]\n", black)));
+        assert_eq!(unmanaged_tables(&data), None);
+        let cfg = Config::from_file(&path).unwrap();
+        assert_eq!(cfg.py_version.unwrap().parsed, Version::new(3, 11, 0));
+    }
+}
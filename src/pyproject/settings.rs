@@ -0,0 +1,311 @@
+//! Project-level defaults from `[tool.pyflow.settings]`, eg to always require hashes, or to
+//! query another index. Flags on the command line override them, for one run.
+
+use std::{fmt, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{dep_types, files, install, util};
+
+/// Keys of `[tool.pyflow.settings]`.
+pub const KEYS: &[&str] = &[
+    "install_dev_by_default",
+    "generate_scripts",
+    "require_hashes",
+    "allow_prereleases",
+    "index_url",
+];
+
+/// `[tool.pyflow.settings]`. Keys that aren't set use pyflow's defaults.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Settings {
+    /// Install dev dependencies when syncing. They're locked either way.
+    pub install_dev_by_default: Option<bool>,
+    /// Create the console scripts of installed packages.
+    pub generate_scripts: Option<bool>,
+    /// Stop if a file's hash is missing or doesn't match, instead of asking.
+    pub require_hashes: Option<bool>,
+    /// Pre-releases of every package are candidates, not only those a requirement allows.
+    pub allow_prereleases: Option<bool>,
+    /// An index with PyPI's JSON API, eg `https://pypi.example.com/pypi`.
+    pub index_url: Option<String>,
+}
+
+impl Settings {
+    /// Read from `[tool.pyflow]`, where `no_scripts` predates `generate_scripts`.
+    pub fn from_pyflow(pf: &files::Pyflow) -> Self {
+        let mut result = pf.settings.clone().unwrap_or_default();
+        if let (None, Some(no_scripts)) = (result.generate_scripts, pf.no_scripts) {
+            result.generate_scripts = Some(!no_scripts);
+        }
+        result
+    }
+
+    /// Read from `pyproject.toml`, without reporting problems with the rest of it.
+    pub fn from_file(path: &Path) -> Option<Self> {
+        let data = fs::read_to_string(path).ok()?;
+        let decoded: files::Pyproject = toml::from_str(&data).ok()?;
+        Some(
+            decoded
+                .tool
+                .pyflow
+                .map(|pf| Self::from_pyflow(&pf))
+                .unwrap_or_default(),
+        )
+    }
+}
+
+/// Flags passed on the command line, eg `--no-dev`. `None` where neither a flag nor its
+/// opposite was passed, so the setting applies.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overrides {
+    pub install_dev: Option<bool>,
+    pub generate_scripts: Option<bool>,
+    pub require_hashes: Option<bool>,
+    pub allow_prereleases: Option<bool>,
+    pub index_url: Option<String>,
+}
+
+/// Where the value of a setting came from.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Flag,
+    Settings,
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Flag => write!(f, "command line"),
+            Self::Settings => write!(f, "[tool.pyflow.settings]"),
+            Self::Default => write!(f, "default"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+/// The settings in effect for this run.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Effective {
+    pub install_dev_by_default: Setting<bool>,
+    pub generate_scripts: Setting<bool>,
+    pub require_hashes: Setting<bool>,
+    pub allow_prereleases: Setting<bool>,
+    pub index_url: Setting<String>,
+}
+
+/// A flag takes precedence over a setting, which takes precedence over the default.
+fn pick<T>(flag: Option<T>, setting: Option<T>, default: T) -> Setting<T> {
+    match (flag, setting) {
+        (Some(value), _) => Setting {
+            value,
+            source: Source::Flag,
+        },
+        (None, Some(value)) => Setting {
+            value,
+            source: Source::Settings,
+        },
+        (None, None) => Setting {
+            value: default,
+            source: Source::Default,
+        },
+    }
+}
+
+/// Find the settings in effect, from the project's settings, and flags that override them.
+pub fn resolve(settings: &Settings, overrides: &Overrides) -> Effective {
+    Effective {
+        install_dev_by_default: pick(overrides.install_dev, settings.install_dev_by_default, true),
+        generate_scripts: pick(overrides.generate_scripts, settings.generate_scripts, true),
+        require_hashes: pick(overrides.require_hashes, settings.require_hashes, false),
+        allow_prereleases: pick(
+            overrides.allow_prereleases,
+            settings.allow_prereleases,
+            false,
+        ),
+        index_url: pick(
+            overrides.index_url.clone(),
+            settings.index_url.clone(),
+            util::index::DEFAULT_URL.to_owned(),
+        ),
+    }
+}
+
+impl Effective {
+    /// Each setting's key, value, and where it came from, for `pyflow env` and `pyflow doctor`.
+    pub fn entries(&self) -> Vec<(&'static str, String, Source)> {
+        let flags = [
+            ("install_dev_by_default", &self.install_dev_by_default),
+            ("generate_scripts", &self.generate_scripts),
+            ("require_hashes", &self.require_hashes),
+            ("allow_prereleases", &self.allow_prereleases),
+        ];
+        let mut result: Vec<_> = flags
+            .iter()
+            .map(|(key, s)| (*key, s.value.to_string(), s.source))
+            .collect();
+        result.push((
+            "index_url",
+            format!("{:?}", self.index_url.value),
+            self.index_url.source,
+        ));
+        result
+    }
+
+    /// Use these settings for this run. Console scripts are set up with `--force-scripts`, by
+    /// the caller.
+    pub fn apply(&self) {
+        if !self.install_dev_by_default.value {
+            util::deps::skip_dev();
+        }
+        if self.require_hashes.value {
+            install::require_hashes();
+        }
+        if self.allow_prereleases.value {
+            dep_types::allow_all_prereleases();
+        }
+        util::index::configure(&self.index_url.value);
+        // The dependency service only knows about packages on PyPI.
+        if !util::index::is_default() {
+            util::deps_service::disable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            install_dev_by_default: Some(false),
+            generate_scripts: Some(false),
+            require_hashes: Some(true),
+            allow_prereleases: Some(true),
+            index_url: Some("https://pypi.example.com/pypi".into()),
+        }
+    }
+
+    #[test]
+    fn defaults_without_settings_or_flags() {
+        let effective = resolve(&Settings::default(), &Overrides::default());
+        for (_, _, source) in effective.entries() {
+            assert_eq!(source, Source::Default);
+        }
+        assert!(effective.install_dev_by_default.value);
+        assert!(effective.generate_scripts.value);
+        assert!(!effective.require_hashes.value);
+        assert!(!effective.allow_prereleases.value);
+        assert_eq!(effective.index_url.value, util::index::DEFAULT_URL);
+    }
+
+    #[test]
+    fn install_dev_by_default() {
+        let setting = resolve(&settings(), &Overrides::default()).install_dev_by_default;
+        assert_eq!((setting.value, setting.source), (false, Source::Settings));
+
+        let overrides = Overrides {
+            install_dev: Some(true),
+            ..Overrides::default()
+        };
+        let setting = resolve(&settings(), &overrides).install_dev_by_default;
+        assert_eq!((setting.value, setting.source), (true, Source::Flag));
+    }
+
+    #[test]
+    fn generate_scripts() {
+        let setting = resolve(&settings(), &Overrides::default()).generate_scripts;
+        assert_eq!((setting.value, setting.source), (false, Source::Settings));
+
+        let overrides = Overrides {
+            generate_scripts: Some(true),
+            ..Overrides::default()
+        };
+        let setting = resolve(&settings(), &overrides).generate_scripts;
+        assert_eq!((setting.value, setting.source), (true, Source::Flag));
+    }
+
+    #[test]
+    fn require_hashes() {
+        let setting = resolve(&settings(), &Overrides::default()).require_hashes;
+        assert_eq!((setting.value, setting.source), (true, Source::Settings));
+
+        let overrides = Overrides {
+            require_hashes: Some(false),
+            ..Overrides::default()
+        };
+        let setting = resolve(&settings(), &overrides).require_hashes;
+        assert_eq!((setting.value, setting.source), (false, Source::Flag));
+    }
+
+    #[test]
+    fn allow_prereleases() {
+        let setting = resolve(&settings(), &Overrides::default()).allow_prereleases;
+        assert_eq!((setting.value, setting.source), (true, Source::Settings));
+
+        let overrides = Overrides {
+            allow_prereleases: Some(false),
+            ..Overrides::default()
+        };
+        let setting = resolve(&settings(), &overrides).allow_prereleases;
+        assert_eq!((setting.value, setting.source), (false, Source::Flag));
+    }
+
+    #[test]
+    fn index_url() {
+        let setting = resolve(&settings(), &Overrides::default()).index_url;
+        assert_eq!(setting.value, "https://pypi.example.com/pypi");
+        assert_eq!(setting.source, Source::Settings);
+
+        let overrides = Overrides {
+            index_url: Some("https://mirror.example.com/pypi".into()),
+            ..Overrides::default()
+        };
+        let setting = resolve(&settings(), &overrides).index_url;
+        assert_eq!(setting.value, "https://mirror.example.com/pypi");
+        assert_eq!(setting.source, Source::Flag);
+    }
+
+    #[test]
+    fn parses_from_pyproject() {
+        let data = r#"
+[tool.pyflow]
+name = "proj"
+
+[tool.pyflow.settings]
+install_dev_by_default = false
+index_url = "https://pypi.example.com/pypi"
+"#;
+        let decoded: files::Pyproject = toml::from_str(data).unwrap();
+        let settings = Settings::from_pyflow(&decoded.tool.pyflow.unwrap());
+        assert_eq!(settings.install_dev_by_default, Some(false));
+        assert_eq!(settings.generate_scripts, None);
+        assert_eq!(
+            settings.index_url.as_deref(),
+            Some("https://pypi.example.com/pypi")
+        );
+    }
+
+    #[test]
+    fn no_scripts_sets_generate_scripts() {
+        let decode = |data: &str| {
+            let decoded: files::Pyproject = toml::from_str(data).unwrap();
+            Settings::from_pyflow(&decoded.tool.pyflow.unwrap()).generate_scripts
+        };
+        assert_eq!(decode("[tool.pyflow]\nno_scripts = true\n"), Some(false));
+        assert_eq!(
+            decode(
+                "[tool.pyflow]\nno_scripts = true\n\n[tool.pyflow.settings]\n\
+                 generate_scripts = true\n"
+            ),
+            Some(true)
+        );
+    }
+}
@@ -0,0 +1,642 @@
+//! Check `pyproject.toml` for problems we can point to precisely: TOML that doesn't have the
+//! shape we expect, dependencies we can't parse, and unknown keys under `[tool.pyflow]`, which
+//! are usually typos.
+
+use std::{fmt, str::FromStr};
+
+use super::settings;
+use crate::{
+    dep_types::{Constraint, Marker, Req, Version},
+    files,
+};
+
+/// Keys of `[tool.pyflow]`.
+const PYFLOW_KEYS: &[&str] = &[
+    "py_version",
+    "py_interpreter",
+    "name",
+    "version",
+    "authors",
+    "license",
+    "description",
+    "classifiers",
+    "keywords",
+    "homepage",
+    "repository",
+    "repo_url",
+    "package_url",
+    "readme",
+    "build",
+    "scripts",
+    "python_requires",
+    "dependencies",
+    "dev-dependencies",
+    "extras",
+    "build_tools",
+    "deps_service_url",
+    "find_links",
+    "no_scripts",
+    "hooks",
+    "preserve",
+    "load_dotenv",
+    "check_shadowing",
+    "resolution",
+    "build_timeout",
+    "frozen",
+    "settings",
+];
+
+/// Keys of a dependency table, eg `{ version = "^1.0", extras = ["cli"] }`.
+const DEP_KEYS: &[&str] = &[
+    "version",
+    "extras",
+    "path",
+    "git",
+    "subdirectory",
+    "branch",
+    "tag",
+    "rev",
+    "service",
+    "python",
+    "platform",
+    "markers",
+    "allow-prereleases",
+    "editable",
+    "wheel-tag",
+];
+
+/// Script names that would be shadowed by a subcommand, since `pyflow <name>` runs a script.
+const RESERVED_SCRIPTS: [(&str, &str); 2] = [
+    ("python", "`pyflow python` runs the environment's Python"),
+    ("run", "`pyflow run` runs a script or command"),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Problem {
+    /// Eg `tool.pyflow.dependencies.numpy`. Empty if we can't tell.
+    pub key: String,
+    /// What to call the entry in a short list, eg `numpy`.
+    pub name: String,
+    /// 1-based.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(toml_str: &str, table: &str, name: &str, message: String) -> Self {
+        Self {
+            key: format!("{}.{}", table, name),
+            name: name.to_owned(),
+            line: find_line(toml_str, table, name),
+            column: None,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(l), Some(c)) => write!(f, "line {}, column {}: ", l, c)?,
+            (Some(l), None) => write!(f, "line {}: ", l)?,
+            _ => (),
+        }
+        if !self.key.is_empty() {
+            write!(f, "`{}`: ", self.key)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Validation {
+    /// The file isn't TOML, or doesn't have the shape we expect. Nothing else is checked.
+    pub parse_error: Option<Problem>,
+    pub errors: Vec<Problem>,
+    /// Eg unknown keys.
+    pub warnings: Vec<Problem>,
+}
+
+impl Validation {
+    pub fn is_ok(&self) -> bool {
+        self.parse_error.is_none() && self.errors.is_empty()
+    }
+}
+
+/// The key in a `key = value` line.
+fn line_key(line: &str) -> Option<String> {
+    let line = line.trim();
+    if line.starts_with('#') || line.starts_with('[') {
+        return None;
+    }
+    let (key, _) = line.split_once('=')?;
+    Some(
+        key.trim()
+            .trim_matches(|c| c == '"' || c == '\'')
+            .to_owned(),
+    )
+}
+
+/// The name of a table header line, eg `tool.pyflow` for `[tool.pyflow]`.
+fn header(line: &str) -> Option<String> {
+    let line = line.trim();
+    if !line.starts_with('[') {
+        return None;
+    }
+    let name = line.trim_start_matches('[').split(']').next()?;
+    Some(name.trim().replace(['"', '\''], ""))
+}
+
+/// The 1-based line of `key` in `table`, or of its own `[table.key]` header.
+fn find_line(toml_str: &str, table: &str, key: &str) -> Option<usize> {
+    let own_header = format!("{}.{}", table, key);
+    let mut current = String::new();
+    for (i, line) in toml_str.lines().enumerate() {
+        if let Some(h) = header(line) {
+            if h == own_header {
+                return Some(i + 1);
+            }
+            current = h;
+        } else if current == table && line_key(line).as_deref() == Some(key) {
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+/// The key a 1-based line belongs to, eg `tool.pyflow.dependencies.numpy`. Lines inside
+/// multi-line values belong to the unindented `key =` line above them.
+fn key_at(toml_str: &str, line_num: usize) -> String {
+    let mut table = String::new();
+    let mut key = None;
+    for line in toml_str.lines().take(line_num) {
+        if let Some(h) = header(line) {
+            table = h;
+            key = None;
+        } else if !line.starts_with(char::is_whitespace) {
+            if let Some(k) = line_key(line) {
+                key = Some(k);
+            }
+        }
+    }
+    match key {
+        Some(k) if table.is_empty() => k,
+        Some(k) => format!("{}.{}", table, k),
+        None => table,
+    }
+}
+
+/// Explain errors from our types, which serde reports by name.
+fn describe_toml_error(message: &str) -> String {
+    if message.contains("DepComponentWrapper") {
+        "Expected constraints, like \"^1.2\", a table, like { version = \"^1.2\" }, or a list \
+         of tables, each for a platform"
+            .to_owned()
+    } else {
+        message.trim().to_owned()
+    }
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { prev } else { prev + 1 };
+            prev = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(prev + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The known key `key` is probably a typo of.
+fn suggest<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    let normalized = key.to_lowercase().replace('_', "-");
+    known
+        .iter()
+        .find(|k| k.replace('_', "-") == normalized)
+        .or_else(|| {
+            known
+                .iter()
+                .map(|k| (k, levenshtein(&normalized, &k.replace('_', "-"))))
+                .filter(|(_, d)| *d <= 2)
+                .min_by_key(|(_, d)| *d)
+                .map(|(k, _)| k)
+        })
+        .copied()
+}
+
+fn unknown_keys(
+    toml_str: &str,
+    table_name: &str,
+    table: &toml::Table,
+    known: &[&str],
+    result: &mut Vec<Problem>,
+) {
+    for key in table.keys().filter(|k| !known.contains(&k.as_str())) {
+        let message = match suggest(key, known) {
+            Some(s) => format!("Unknown key; did you mean `{}`?", s),
+            None => "Unknown key".to_owned(),
+        };
+        result.push(Problem::new(toml_str, table_name, key, message));
+    }
+}
+
+/// Problems with a dependency table, eg an invalid constraint.
+fn check_component(subdata: &files::DepComponent) -> Vec<String> {
+    let mut result = vec![];
+    if let Some(c) = &subdata.constrs {
+        if Constraint::from_str_multiple(c).is_err() {
+            result.push(format!("Invalid version constraints \"{}\"", c));
+        }
+    }
+    if let Some(p) = &subdata.python {
+        if Constraint::from_str(p).is_err() {
+            result.push(format!("Invalid `python` constraint \"{}\"", p));
+        }
+    }
+    if let Some(p) = &subdata.platform {
+        if super::platform_marker(p).is_none() {
+            result.push(format!(
+                "Unknown platform \"{}\"; expected `linux`, `windows`, or `macos`",
+                p
+            ));
+        }
+    }
+    if let Some(m) = &subdata.markers {
+        if Marker::from_str(m).is_err() {
+            result.push(format!("Invalid markers \"{}\"", m));
+        }
+    }
+    if let Some(t) = &subdata.wheel_tag {
+        if !crate::util::wheel_tag::is_valid(t) {
+            result.push(format!(
+                "Invalid `wheel-tag` \"{}\"; expected eg \"cp311-cp311-manylinux_2_28_x86_64\"",
+                t
+            ));
+        }
+    }
+    result
+}
+
+fn check_pyflow(toml_str: &str, pf: &files::Pyflow, result: &mut Validation) {
+    if let Some(v) = &pf.py_version {
+        if Version::from_str(v).is_err() {
+            result.errors.push(Problem::new(
+                toml_str,
+                "tool.pyflow",
+                "py_version",
+                format!("Invalid version \"{}\"", v),
+            ));
+        }
+    }
+
+    let tables = [
+        ("tool.pyflow.dependencies", &pf.dependencies),
+        ("tool.pyflow.dev-dependencies", &pf.dev_dependencies),
+    ];
+    for (table, deps) in tables {
+        let mut deps: Vec<_> = deps.iter().flatten().collect();
+        deps.sort_by_key(|(name, _)| *name);
+        for (name, dep) in deps {
+            let messages = match dep {
+                files::DepComponentWrapper::A(value) => match Req::from_cfg_value(name, value) {
+                    Ok(_) => vec![],
+                    Err(_) => vec![format!(
+                        "Can't parse \"{}\". Expected constraints, optionally followed by \
+                         `; <markers>`, or a git url",
+                        value
+                    )],
+                },
+                files::DepComponentWrapper::B(subdata) => check_component(subdata),
+                files::DepComponentWrapper::C(entries) => {
+                    let mut messages: Vec<String> =
+                        entries.iter().flat_map(check_component).collect();
+                    if entries
+                        .iter()
+                        .any(|e| e.platform.is_none() && e.markers.is_none())
+                    {
+                        messages.push(
+                            "Each entry in a list needs a `platform` or `markers`".to_owned(),
+                        );
+                    }
+                    messages
+                }
+            };
+            for message in messages {
+                result
+                    .errors
+                    .push(Problem::new(toml_str, table, name, message));
+            }
+        }
+    }
+
+    for (name, reason) in RESERVED_SCRIPTS {
+        if pf.scripts.as_ref().is_some_and(|s| s.contains_key(name)) {
+            result.errors.push(Problem::new(
+                toml_str,
+                "tool.pyflow.scripts",
+                name,
+                format!(
+                    "This name is reserved: {}. Please rename the script",
+                    reason
+                ),
+            ));
+        }
+    }
+
+    let mut extras: Vec<_> = pf.extras.iter().flatten().collect();
+    extras.sort_by_key(|(name, _)| *name);
+    for (extra, entries) in extras {
+        for entry in entries {
+            // Bare names refer to a declared dependency.
+            let entry = entry.trim();
+            let bare_name = entry
+                .chars()
+                .all(|c| c.is_alphanumeric() || "-_.".contains(c));
+            if !bare_name && Req::from_pip_str(entry).is_none() {
+                result.errors.push(Problem {
+                    name: format!("{} (in the extra `{}`)", entry, extra),
+                    ..Problem::new(
+                        toml_str,
+                        "tool.pyflow.extras",
+                        extra,
+                        format!("Can't parse the requirement \"{}\"", entry),
+                    )
+                });
+            }
+        }
+    }
+}
+
+fn check_poetry(toml_str: &str, po: &files::Poetry, result: &mut Validation) {
+    let groups = po.group.iter().flatten().map(|(name, g)| {
+        (
+            format!("tool.poetry.group.{}.dependencies", name),
+            &g.dependencies,
+        )
+    });
+    let tables = [
+        ("tool.poetry.dependencies".to_owned(), &po.dependencies),
+        (
+            "tool.poetry.dev-dependencies".to_owned(),
+            &po.dev_dependencies,
+        ),
+    ]
+    .into_iter()
+    .chain(groups);
+    for (table, deps) in tables {
+        let mut deps: Vec<_> = deps.iter().flatten().collect();
+        deps.sort_by_key(|(name, _)| *name);
+        for (name, dep) in deps {
+            let constrs = match dep {
+                files::DepComponentWrapperPoetry::A(c) => c,
+                files::DepComponentWrapperPoetry::B(subdata) => match &subdata.constrs {
+                    Some(c) => c,
+                    None => continue,
+                },
+            };
+            // Poetry allows alternatives for the Python version, eg `^2.7 || ^3.6`.
+            let valid = if name.eq_ignore_ascii_case("python") {
+                constrs
+                    .split("||")
+                    .all(|alt| Constraint::from_str_multiple(alt).is_ok())
+            } else {
+                Constraint::from_str_multiple(constrs).is_ok()
+            };
+            if !valid {
+                result.errors.push(Problem::new(
+                    toml_str,
+                    &table,
+                    name,
+                    format!("Invalid version constraints \"{}\"", constrs),
+                ));
+            }
+        }
+    }
+}
+
+/// Warn on keys under `[tool.pyflow]`, and in its dependency tables, that we don't use.
+fn check_unknown_keys(toml_str: &str, result: &mut Validation) {
+    let doc: toml::Table = match toml_str.parse() {
+        Ok(d) => d,
+        Err(_) => return,
+    };
+    let pyflow = match doc
+        .get("tool")
+        .and_then(|t| t.get("pyflow"))
+        .and_then(|p| p.as_table())
+    {
+        Some(p) => p,
+        None => return,
+    };
+    unknown_keys(
+        toml_str,
+        "tool.pyflow",
+        pyflow,
+        PYFLOW_KEYS,
+        &mut result.warnings,
+    );
+    if let Some(table) = pyflow.get("settings").and_then(|s| s.as_table()) {
+        unknown_keys(
+            toml_str,
+            "tool.pyflow.settings",
+            table,
+            settings::KEYS,
+            &mut result.warnings,
+        );
+    }
+
+    for table_name in ["dependencies", "dev-dependencies"] {
+        let deps = match pyflow.get(table_name).and_then(|d| d.as_table()) {
+            Some(d) => d,
+            None => continue,
+        };
+        for (name, dep) in deps {
+            let tables: Vec<&toml::Table> = match dep {
+                toml::Value::Table(t) => vec![t],
+                toml::Value::Array(entries) => {
+                    entries.iter().filter_map(|e| e.as_table()).collect()
+                }
+                _ => continue,
+            };
+            let table = format!("tool.pyflow.{}", table_name);
+            for t in tables {
+                for key in t.keys().filter(|k| !DEP_KEYS.contains(&k.as_str())) {
+                    let message = match suggest(key, DEP_KEYS) {
+                        Some(s) => format!("Unknown key `{}`; did you mean `{}`?", key, s),
+                        None => format!("Unknown key `{}`", key),
+                    };
+                    result
+                        .warnings
+                        .push(Problem::new(toml_str, &table, name, message));
+                }
+            }
+        }
+    }
+}
+
+/// Check `pyproject.toml`, collecting all the problems we find.
+pub fn validate(toml_str: &str) -> Validation {
+    let mut result = Validation::default();
+    let decoded: files::Pyproject = match toml::from_str(toml_str) {
+        Ok(d) => d,
+        Err(e) => {
+            let position = e.span().map(|span| {
+                let before = &toml_str[..span.start.min(toml_str.len())];
+                let line = before.matches('\n').count() + 1;
+                let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+                (line, column)
+            });
+            result.parse_error = Some(Problem {
+                key: position.map_or_else(String::new, |(l, _)| key_at(toml_str, l)),
+                name: String::new(),
+                line: position.map(|(l, _)| l),
+                column: position.map(|(_, c)| c),
+                message: describe_toml_error(e.message()),
+            });
+            return result;
+        }
+    };
+
+    if let Some(pf) = &decoded.tool.pyflow {
+        check_pyflow(toml_str, pf, &mut result);
+    }
+    if let Some(po) = &decoded.tool.poetry {
+        check_poetry(toml_str, po, &mut result);
+    }
+    check_unknown_keys(toml_str, &mut result);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shape_errors_located() {
+        let cfg = r#"[tool.pyflow]
+name = "mylib"
+
+[tool.pyflow.dependencies]
+requests = "^2.28"
+numpy = 5
+"#;
+        let problem = validate(cfg).parse_error.unwrap();
+        assert_eq!(problem.key, "tool.pyflow.dependencies.numpy");
+        assert_eq!(problem.line, Some(6));
+        assert!(problem.message.contains("Expected constraints"));
+
+        let cfg = "[tool.pyflow]\nname = \"mylib\"\nauthors = \"me\"\n";
+        let problem = validate(cfg).parse_error.unwrap();
+        assert_eq!(problem.key, "tool.pyflow.authors");
+        assert_eq!((problem.line, problem.column), (Some(3), Some(11)));
+
+        let problem = validate("[tool.pyflow\nname = 1\n").parse_error.unwrap();
+        assert_eq!(problem.line, Some(1));
+    }
+
+    #[test]
+    fn all_invalid_entries_reported() {
+        let cfg = r#"[tool.pyflow]
+py_version = "three"
+
+[tool.pyflow.dependencies]
+numpy = "~~1.2"
+requests = { version = ">=2.0", markers = "sys_platform == 'linux' and" }
+torch = [
+    { version = "2.1.0", platform = "linux" },
+    { version = "2.0.1", platform = "beos" },
+]
+scipy = { version = "1.11.4", wheel-tag = "manylinux_2_28_x86_64" }
+
+[tool.pyflow.extras]
+cli = ["click", "rich >>= 13"]
+"#;
+        let validation = validate(cfg);
+        assert!(validation.parse_error.is_none());
+        let found: Vec<(&str, Option<usize>)> = validation
+            .errors
+            .iter()
+            .map(|p| (p.key.as_str(), p.line))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("tool.pyflow.py_version", Some(2)),
+                ("tool.pyflow.dependencies.numpy", Some(5)),
+                ("tool.pyflow.dependencies.requests", Some(6)),
+                ("tool.pyflow.dependencies.scipy", Some(11)),
+                ("tool.pyflow.dependencies.torch", Some(7)),
+                ("tool.pyflow.extras.cli", Some(14)),
+            ]
+        );
+        assert!(validation.errors[1].message.contains("~~1.2"));
+        assert!(validation.errors[3].message.contains("wheel-tag"));
+        assert!(validation.errors[4].message.contains("beos"));
+        assert_eq!(
+            validation.errors[5].name,
+            "rich >>= 13 (in the extra `cli`)"
+        );
+    }
+
+    #[test]
+    fn reserved_script_names_rejected() {
+        let cfg = r#"[tool.pyflow.scripts]
+lint = "ruff check ."
+python = "python3 -m myapp"
+run = { cmd = "myapp:main" }
+"#;
+        let found: Vec<String> = validate(cfg).errors.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            found,
+            vec![
+                "line 3: `tool.pyflow.scripts.python`: This name is reserved: `pyflow python` \
+                 runs the environment's Python. Please rename the script",
+                "line 4: `tool.pyflow.scripts.run`: This name is reserved: `pyflow run` runs a \
+                 script or command. Please rename the script",
+            ]
+        );
+        assert!(validate(
+            "[tool.pyflow.scripts]
+lint = \"ruff check .\"\n"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn unknown_keys_warned() {
+        let cfg = r#"[tool.pyflow]
+name = "mylib"
+py_verison = "3.11"
+
+[tool.pyflow.dev_dependencies]
+pytest = "^7"
+
+[tool.pyflow.dependencies]
+requests = { verison = "^2.28" }
+
+[tool.pyflow.settings]
+require_hash = true
+"#;
+        let validation = validate(cfg);
+        assert!(validation.is_ok());
+        let found: Vec<String> = validation.warnings.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            found,
+            vec![
+                "line 5: `tool.pyflow.dev_dependencies`: Unknown key; did you mean \
+                 `dev-dependencies`?",
+                "line 3: `tool.pyflow.py_verison`: Unknown key; did you mean `py_version`?",
+                "line 12: `tool.pyflow.settings.require_hash`: Unknown key; did you mean \
+                 `require_hashes`?",
+                "line 9: `tool.pyflow.dependencies.requests`: Unknown key `verison`; did you \
+                 mean `version`?",
+            ]
+        );
+    }
+}
@@ -0,0 +1,282 @@
+//! Rewrite built wheels and sdists so building the same commit twice gives identical files:
+//! member timestamps come from `SOURCE_DATE_EPOCH`, or the latest commit, entries are sorted,
+//! permissions are normalized, and the wheel's `RECORD` is written in a stable order.
+
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    time::SystemTime,
+};
+
+use flate2::{read::GzDecoder, Compression, GzBuilder};
+use ring::digest;
+use zip::{write::SimpleFileOptions, CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+/// 1980-01-01; Zip can't store earlier times.
+const ZIP_EPOCH: u64 = 315_532_800;
+
+/// The time to give archive members: `SOURCE_DATE_EPOCH`, or the latest commit's time in `root`,
+/// or 1980-01-01 if neither is available.
+pub fn source_date_epoch(root: &Path) -> u64 {
+    if let Some(epoch) = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|e| u64::from_str(e.trim()).ok())
+    {
+        return epoch.max(ZIP_EPOCH);
+    }
+    Command::new("git")
+        .args(["log", "-1", "--format=%ct"])
+        .current_dir(root)
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| u64::from_str(String::from_utf8_lossy(&o.stdout).trim()).ok())
+        .map_or(ZIP_EPOCH, |e| e.max(ZIP_EPOCH))
+}
+
+/// Executable files keep being executable; nothing else about the mode carries over.
+fn normalized_mode(mode: Option<u32>, is_dir: bool) -> u32 {
+    if is_dir || mode.is_some_and(|m| m & 0o111 != 0) {
+        0o755
+    } else {
+        0o644
+    }
+}
+
+/// Year, month, day, hour, minute, and second, in UTC.
+fn civil_time(epoch: u64) -> (u16, u8, u8, u8, u8, u8) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let (days, secs) = (epoch / 86_400, epoch % 86_400);
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (
+        year as u16,
+        month as u8,
+        day as u8,
+        (secs / 3600) as u8,
+        (secs % 3600 / 60) as u8,
+        (secs % 60) as u8,
+    )
+}
+
+/// Write through a temporary file, so a failure doesn't leave a partial archive.
+fn replace(path: &Path, write: impl FnOnce(fs::File) -> io::Result<()>) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    write(fs::File::create(&tmp_path)?)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Dist-info files go last, as the wheel spec recommends, with `RECORD` at the very end.
+fn wheel_order(name: &str) -> (bool, bool, &str) {
+    let dist_info = name
+        .split('/')
+        .next()
+        .is_some_and(|d| d.ends_with(".dist-info"));
+    (dist_info, dist_info && name.ends_with("/RECORD"), name)
+}
+
+/// Rewrite a wheel with sorted entries, fixed timestamps and permissions, and a sorted `RECORD`.
+pub fn normalize_wheel(path: &Path, epoch: u64) -> io::Result<()> {
+    let mut archive = ZipArchive::new(fs::File::open(path)?)?;
+    let mut files = vec![];
+    let mut record_path = None;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.is_dir() {
+            continue;
+        }
+        let name = file.name().to_owned();
+        if name.ends_with(".dist-info/RECORD") {
+            record_path = Some(name);
+            continue;
+        }
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        files.push((name, normalized_mode(file.unix_mode(), false), data));
+    }
+    files.sort_by(|a, b| wheel_order(&a.0).cmp(&wheel_order(&b.0)));
+
+    if let Some(record_path) = record_path {
+        let mut record = String::new();
+        for (name, _, data) in &files {
+            let hash = digest::digest(&digest::SHA256, data);
+            record.push_str(&format!(
+                "{},sha256={},{}\n",
+                name,
+                data_encoding::BASE64URL_NOPAD.encode(hash.as_ref()),
+                data.len()
+            ));
+        }
+        record.push_str(&format!("{},,\n", record_path));
+        files.push((record_path, 0o644, record.into_bytes()));
+    }
+
+    let (year, month, day, hour, minute, second) = civil_time(epoch);
+    let time = DateTime::from_date_and_time(year, month, day, hour, minute, second)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    replace(path, |out| {
+        let mut zip = ZipWriter::new(out);
+        for (name, mode, data) in files {
+            let options = SimpleFileOptions::default()
+                .compression_method(CompressionMethod::Deflated)
+                .last_modified_time(time)
+                .unix_permissions(mode);
+            zip.start_file(name, options)?;
+            zip.write_all(&data)?;
+        }
+        zip.finish()?;
+        Ok(())
+    })
+}
+
+/// Rewrite a `.tar.gz` sdist with sorted entries, and fixed timestamps, owners, and permissions.
+pub fn normalize_sdist(path: &Path, epoch: u64) -> io::Result<()> {
+    let mut archive = tar::Archive::new(GzDecoder::new(fs::File::open(path)?));
+    let mut entries = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        let mode = entry.header().mode().ok();
+        let entry_path = entry.path()?.into_owned();
+        let link = entry.link_name()?.map(|l| l.into_owned());
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        entries.push((entry_path, entry_type, mode, link, data));
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    replace(path, |out| {
+        let encoder = GzBuilder::new()
+            .mtime(epoch as u32)
+            .write(out, Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (entry_path, entry_type, mode, link, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(normalized_mode(mode, entry_type.is_dir()));
+            header.set_mtime(epoch);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_username("")?;
+            header.set_groupname("")?;
+            header.set_size(data.len() as u64);
+            match link {
+                Some(link) => builder.append_link(&mut header, &entry_path, link)?,
+                None => builder.append_data(&mut header, &entry_path, data.as_slice())?,
+            }
+        }
+        builder.into_inner()?.finish()?;
+        Ok(())
+    })
+}
+
+/// The files in `dist_dir`, with when they were modified, to find what a build writes.
+pub fn dist_files(dist_dir: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let entries = match fs::read_dir(dist_dir) {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+    entries
+        .flatten()
+        .map(|e| (e.path(), e.metadata().and_then(|m| m.modified()).ok()))
+        .collect()
+}
+
+/// Normalize the wheels and sdists in `dist_dir` that are new or changed since `before`, from
+/// `dist_files`, ie those this build wrote.
+pub fn normalize_dists(
+    dist_dir: &Path,
+    before: &[(PathBuf, Option<SystemTime>)],
+    epoch: u64,
+) -> io::Result<()> {
+    for file in dist_files(dist_dir) {
+        if before.contains(&file) {
+            continue;
+        }
+        let name = file.0.to_string_lossy();
+        if name.ends_with(".whl") {
+            normalize_wheel(&file.0, epoch)?;
+        } else if name.ends_with(".tar.gz") {
+            normalize_sdist(&file.0, epoch)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn epoch_to_civil() {
+        assert_eq!(civil_time(ZIP_EPOCH), (1980, 1, 1, 0, 0, 0));
+        assert_eq!(civil_time(1_709_251_199), (2024, 2, 29, 23, 59, 59));
+    }
+
+    /// Two wheels with the same contents, written in different orders at different times, and
+    /// with a stale `RECORD`, come out identical.
+    #[test]
+    fn wheels_normalized() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+
+        let files = [
+            ("pkg/__init__.py", "", 0o664),
+            ("pkg/cli.py", "print('hi')\n", 0o775),
+            ("pkg-1.0.dist-info/WHEEL", "Wheel-Version: 1.0\n", 0o600),
+            ("pkg-1.0.dist-info/RECORD", "stale\n", 0o644),
+        ];
+        let write = |name: &str, reverse: bool, time: DateTime| {
+            let path = dir.join(name);
+            let mut zip = ZipWriter::new(fs::File::create(&path).unwrap());
+            let mut files = files.to_vec();
+            if reverse {
+                files.reverse();
+            }
+            for (name, data, mode) in files {
+                let options = SimpleFileOptions::default()
+                    .last_modified_time(time)
+                    .unix_permissions(mode);
+                zip.start_file(name, options).unwrap();
+                zip.write_all(data.as_bytes()).unwrap();
+            }
+            zip.finish().unwrap();
+            normalize_wheel(&path, 1_700_000_000).unwrap();
+            fs::read(path).unwrap()
+        };
+
+        let first = write("a.whl", false, DateTime::default());
+        let second = write(
+            "b.whl",
+            true,
+            DateTime::from_date_and_time(2020, 5, 17, 8, 30, 0).unwrap(),
+        );
+        assert_eq!(first, second);
+
+        let mut archive = ZipArchive::new(io::Cursor::new(first)).unwrap();
+        let names: Vec<&str> = archive.file_names().collect();
+        assert_eq!(names.last(), Some(&"pkg-1.0.dist-info/RECORD"));
+        assert_eq!(
+            archive.by_name("pkg/cli.py").unwrap().unix_mode(),
+            Some(0o100755)
+        );
+        let mut record = String::new();
+        archive
+            .by_name("pkg-1.0.dist-info/RECORD")
+            .unwrap()
+            .read_to_string(&mut record)
+            .unwrap();
+        assert_eq!(record.lines().next().unwrap().split(',').nth(2), Some("0"));
+        assert_eq!(record.lines().last(), Some("pkg-1.0.dist-info/RECORD,,"));
+    }
+}
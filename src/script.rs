@@ -1,9 +1,13 @@
 use crate::dep_resolution::res;
-use crate::dep_types::{Constraint, Extras, Lock, Req, ReqType, Version};
+use crate::dep_types::{Constraint, Extras, Req, ReqType, Version};
+use crate::install;
+use crate::py_versions;
 use crate::util;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::Path;
+use termcolor::Color;
 
 use crate::commands;
 use crate::dep_parser::parse_version;
@@ -23,7 +27,7 @@ pub fn run_script(
     eprintln!("Run script args: {:?}", args);
 
     // todo: DRY with run_cli_tool and subcommand::Install
-    let filename = if let Some(arg) = args.get(0) {
+    let filename = if let Some(arg) = args.first() {
         arg
     } else {
         util::abort(
@@ -39,37 +43,59 @@ pub fn run_script(
         fs::create_dir_all(&env_path).expect("Problem creating environment for the script");
     }
 
-    // Write the version we found to a file.
-    let cfg_vers;
-    let py_vers_path = env_path.join("py_vers.txt");
-
     let script = fs::read_to_string(filename).expect("Problem opening the Python script file.");
-    let dunder_python_vers = check_for_specified_py_vers(&script);
-
-    if let Some(dpv) = dunder_python_vers {
-        cfg_vers = dpv;
-        create_or_update_version_file(&py_vers_path, &cfg_vers);
-    } else if py_vers_path.exists() {
-        cfg_vers = Version::from_str(
-            &fs::read_to_string(py_vers_path)
-                .expect("Problem reading Python version for this script")
-                .replace("\n", ""),
-        )
-        .expect("Problem parsing version from file");
-    } else {
-        cfg_vers = util::prompts::py_vers();
-        create_or_update_version_file(&py_vers_path, &cfg_vers);
-    }
+    let declared = find_py_declaration(&script);
+    let key = declared.as_ref().map(PyDeclaration::key);
 
     // todo DRY
     let pypackages_dir = env_path.join("__pypackages__");
-    let (vers_path, py_vers) =
-        util::find_or_create_venv(&cfg_vers, &pypackages_dir, pyflow_dir, dep_cache_path);
+    let lock_path = env_path.join("pyproject.lock");
+
+    // Reuse the version we picked last time, unless the script's declaration changed.
+    let cfg_vers = match ScriptManifest::read(&env_path) {
+        Some(m) if m.declared == key => m.py_version(),
+        previous => {
+            if previous.is_some() && pypackages_dir.exists() {
+                util::print_color(
+                    "The script's Python requirement changed; rebuilding its environment",
+                    Color::Yellow,
+                );
+                fs::remove_dir_all(&pypackages_dir)
+                    .expect("Problem removing the script's environment");
+                let _ = fs::remove_file(&lock_path);
+            }
+            let py_version = match &declared {
+                Some(d) => select_py_vers(
+                    d,
+                    &py_versions::available_versions(pyflow_dir),
+                    &py_versions::installable_here(),
+                )
+                .unwrap_or_else(|e| util::abort(&e)),
+                None => {
+                    util::prompts::py_vers("Declare one in the script, eg `__python__ = \"3.11\"`.")
+                }
+            };
+            ScriptManifest {
+                declared: key,
+                py_version: py_version.to_string(),
+            }
+            .write(&env_path);
+            py_version
+        }
+    };
+
+    let (vers_path, py_vers) = util::find_or_create_venv(
+        &cfg_vers,
+        &pypackages_dir,
+        pyflow_dir,
+        dep_cache_path,
+        &[],
+        None,
+    );
 
     let bin_path = util::find_bin_path(&vers_path);
     let lib_path = vers_path.join("lib");
     let script_path = vers_path.join("bin");
-    let lock_path = env_path.join("pyproject.lock");
 
     let paths = util::Paths {
         bin: bin_path,
@@ -80,12 +106,9 @@ pub fn run_script(
 
     let deps = find_deps_from_script(&script);
 
-    let lock = match util::read_lock(&lock_path) {
-        Ok(l) => l,
-        Err(_) => Lock::default(),
-    };
+    let lock = util::read_lock(&lock_path).unwrap_or_default();
 
-    let lockpacks = lock.package.unwrap_or_else(Vec::new);
+    let lockpacks = lock.package.unwrap_or_default();
 
     let reqs: Vec<Req> = deps
         .iter()
@@ -107,7 +130,7 @@ pub fn run_script(
                         Extras::new_py(Constraint::new(ReqType::Exact, py_vers.clone())),
                     )),
                 )
-                .unwrap_or_else(|_| panic!("Problem getting version info for {}", &name));
+                .unwrap_or_else(|e| util::abort(&e.to_string()));
                 (vinfo.0, vinfo.1)
             };
 
@@ -124,6 +147,7 @@ pub fn run_script(
         os,
         &py_vers,
         &lock_path,
+        false,
     );
 
     if commands::run_python(&paths.bin, &[paths.lib], args).is_err() {
@@ -131,40 +155,345 @@ pub fn run_script(
     };
 }
 
+/// Environments for `pyflow x` are stored in this folder of the script environment path,
+/// keyed by package name and version.
+const ISOLATED_DIR: &str = "isolated";
+
+/// Run a package's console script in an environment of its own, eg `pyflow x cowsay hello`.
+/// This doesn't read or modify the current project, if there is one.
+pub fn run_isolated(
+    script_env_path: &Path,
+    dep_cache_path: &Path,
+    os: util::Os,
+    pyflow_dir: &Path,
+    python: Option<&str>,
+    args: &[String],
+) {
+    let spec = if let Some(arg) = args.first() {
+        arg
+    } else {
+        util::abort("`x` must be followed by the package to run, eg `pyflow x cowsay hello`");
+    };
+    let req = Req::from_str(spec, false).unwrap_or_else(|_| {
+        util::abort(&format!(
+            "Problem parsing the package to run: {}. Use a name, optionally with a version, \
+             eg `black` or `black==23.1.0`",
+            spec
+        ))
+    });
+
+    let isolated_path = script_env_path.join(ISOLATED_DIR);
+    if !isolated_path.exists() {
+        fs::create_dir_all(&isolated_path).expect("Problem creating isolated environment path");
+    }
+
+    // Use the Python version specified, or the one we last used for this package.
+    let py_vers_path =
+        isolated_path.join(format!("{}.py_vers.txt", util::standardize_name(&req.name)));
+    let cfg_vers = if let Some(p) = python {
+        Version::from_str(p).unwrap_or_else(|_| {
+            util::abort("Problem parsing the Python version. It should look like this: 3.11")
+        })
+    } else if py_vers_path.exists() {
+        Version::from_str(
+            &fs::read_to_string(&py_vers_path)
+                .expect("Problem reading Python version for this package")
+                .replace("\n", ""),
+        )
+        .expect("Problem parsing version from file")
+    } else {
+        util::prompts::py_vers("Pass one with `--python`, eg `--python 3.11`.")
+    };
+    create_or_update_version_file(&py_vers_path, &cfg_vers);
+
+    let (name, version, _) = res::get_version_info(
+        &req.name,
+        Some(Req::new_with_extras(
+            req.name.clone(),
+            req.constraints.clone(),
+            Extras::new_py(Constraint::new(ReqType::Exact, cfg_vers.clone())),
+        )),
+    )
+    .unwrap_or_else(|e| {
+        util::abort(&format!(
+            "Problem finding a version of {} to run: {}",
+            spec, e
+        ))
+    });
+
+    let env_path = isolated_path.join(format!("{}-{}", util::standardize_name(&name), version));
+    let pypackages_dir = env_path.join("__pypackages__");
+    let (vers_path, py_vers) = util::find_or_create_venv(
+        &cfg_vers,
+        &pypackages_dir,
+        pyflow_dir,
+        dep_cache_path,
+        &[],
+        None,
+    );
+
+    let paths = util::Paths {
+        bin: util::find_bin_path(&vers_path),
+        lib: vers_path.join("lib"),
+        entry_pt: vers_path.join("bin"),
+        cache: dep_cache_path.to_owned(),
+    };
+    let lock_path = env_path.join("pyflow.lock");
+    let lockpacks = util::read_lock(&lock_path)
+        .unwrap_or_default()
+        .package
+        .unwrap_or_default();
+
+    util::deps::sync(
+        &paths,
+        &lockpacks,
+        &[Req::new(
+            name.clone(),
+            vec![Constraint::new(ReqType::Exact, version.clone())],
+        )],
+        &[],
+        &[],
+        os,
+        &py_vers,
+        &lock_path,
+        false,
+    );
+
+    let scripts = install::find_console_scripts(&name, &version, &paths.lib);
+    let script = pick_console_script(&name, &scripts).unwrap_or_else(|| {
+        if scripts.is_empty() {
+            util::abort(&format!("{} doesn't provide any console scripts", name))
+        }
+        util::abort(&format!(
+            "{} provides several console scripts; we're not sure which to run: {}",
+            name,
+            scripts.join(", ")
+        ))
+    });
+
+    let mut args_to_pass = vec![paths
+        .entry_pt
+        .join(script)
+        .to_str()
+        .expect("Can't find script path")
+        .to_owned()];
+    args_to_pass.extend_from_slice(&args[1..]);
+
+    match commands::run_python(&paths.bin, &[paths.lib], &args_to_pass) {
+        Ok(status) if status.success() => (),
+        Ok(status) => util::exit(status.code().unwrap_or(1)),
+        Err(_) => util::abort(&format!("Problem running {}", name)),
+    }
+}
+
+/// Pick the console script to run for a package: The one named after it, or its only one.
+/// `scripts` are entry points, eg `black=black:patched_main`.
+fn pick_console_script<'a>(package: &str, scripts: &'a [String]) -> Option<&'a str> {
+    let names: Vec<&str> = scripts.iter().filter_map(|s| s.split('=').next()).collect();
+
+    match names.iter().find(|n| util::compare_names(n, package)) {
+        Some(n) => Some(n),
+        None if names.len() == 1 => Some(names[0]),
+        None => None,
+    }
+}
+
 /// Create the `py_vers.txt` if it doesn't exist, and then store `cfg_vers` within.
 fn create_or_update_version_file(py_vers_path: &Path, cfg_vers: &Version) {
     if !py_vers_path.exists() {
-        fs::File::create(&py_vers_path)
+        fs::File::create(py_vers_path)
             .expect("Problem creating a file to store the Python version for this script");
     }
-    fs::write(py_vers_path, &cfg_vers.to_string()).expect("Problem writing Python version file.");
+    fs::write(py_vers_path, cfg_vers.to_string()).expect("Problem writing Python version file.");
+}
+
+/// Stored in the script's environment folder.
+const MANIFEST_FILENAME: &str = "script.toml";
+
+/// What we picked for a script's environment, so later runs don't search for interpreters again.
+#[derive(Debug, Deserialize, Serialize)]
+struct ScriptManifest {
+    /// The script's Python declaration when we picked, from `PyDeclaration::key`.
+    declared: Option<String>,
+    py_version: String,
+}
+
+impl ScriptManifest {
+    /// Falls back to `py_vers.txt`, from older versions of pyflow.
+    fn read(env_path: &Path) -> Option<Self> {
+        let manifest = match fs::read_to_string(env_path.join(MANIFEST_FILENAME)) {
+            Ok(data) => toml::from_str(&data).ok()?,
+            Err(_) => Self {
+                declared: None,
+                py_version: fs::read_to_string(env_path.join("py_vers.txt")).ok()?,
+            },
+        };
+        Version::from_str(manifest.py_version.trim()).ok()?;
+        Some(manifest)
+    }
+
+    fn py_version(&self) -> Version {
+        Version::from_str(self.py_version.trim()).expect("Problem parsing the script's version")
+    }
+
+    fn write(&self, env_path: &Path) {
+        let data = toml::to_string(self).expect("Problem serializing the script's manifest");
+        fs::write(env_path.join(MANIFEST_FILENAME), data)
+            .expect("Problem writing the script's manifest");
+    }
+}
+
+/// The Python versions a script says it runs on.
+#[derive(Debug, PartialEq)]
+enum PyDeclaration {
+    /// `__python__ = "3.11"`, optionally commented out.
+    Pinned(Version),
+    /// `requires-python` in a PEP 723 `# /// script` block, eg `>=3.11`.
+    RequiresPython(String, Vec<Constraint>),
+}
+
+impl PyDeclaration {
+    /// Identifies the declaration in the manifest, so we notice when it changes.
+    fn key(&self) -> String {
+        match self {
+            Self::Pinned(v) => format!("__python__ {}", v.to_string_med()),
+            Self::RequiresPython(spec, _) => format!("requires-python {}", spec.replace(' ', "")),
+        }
+    }
+
+    fn allows(&self, version: &Version) -> bool {
+        match self {
+            Self::Pinned(v) => v.major == version.major && v.minor == version.minor,
+            Self::RequiresPython(_, constraints) => {
+                constraints.iter().all(|c| c.is_compatible(version))
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::Pinned(v) => format!("Python {}", v.to_string_med()),
+            Self::RequiresPython(spec, _) => format!("Python {}", spec),
+        }
+    }
 }
 
-/// Find a script's Python version specificion by looking for the `__python__` variable.
+/// Pick a Python version allowed by `declared`: the newest one we have, or else the newest one
+/// pyflow can download. `available` and `installable` have only major and minor versions.
+fn select_py_vers(
+    declared: &PyDeclaration,
+    available: &[Version],
+    installable: &[Version],
+) -> Result<Version, String> {
+    let newest = |versions: &[Version]| {
+        versions
+            .iter()
+            .filter(|v| declared.allows(v))
+            .max()
+            .cloned()
+    };
+    if let Some(v) = newest(available) {
+        return Ok(v);
+    }
+    if let Some(v) = newest(installable) {
+        util::print_color(
+            &format!(
+                "This script needs {}; pyflow will install Python {}",
+                declared.describe(),
+                v
+            ),
+            Color::Cyan,
+        );
+        return Ok(v);
+    }
+    let list = |versions: &[Version]| {
+        if versions.is_empty() {
+            "none".to_owned()
+        } else {
+            let listed: Vec<String> = versions.iter().map(Version::to_string_med).collect();
+            listed.join(", ")
+        }
+    };
+    Err(format!(
+        "This script needs {}, which no Python on this system provides, and pyflow can't \
+         install. Found: {}. pyflow can install: {}. Install a matching Python yourself, or \
+         change the script's declaration.",
+        declared.describe(),
+        list(available),
+        list(installable)
+    ))
+}
+
+/// Find the Python versions a script runs on: `__python__`, or `requires-python` in its
+/// PEP 723 metadata block.
+fn find_py_declaration(script: &str) -> Option<PyDeclaration> {
+    if let Some(v) = check_for_specified_py_vers(script) {
+        return Some(PyDeclaration::Pinned(v));
+    }
+    let spec = find_requires_python(script)?;
+    let constraints = Constraint::from_str_multiple(&spec).unwrap_or_else(|_| {
+        util::abort(&format!(
+            "Problem parsing the script's `requires-python`: {}",
+            spec
+        ))
+    });
+    Some(PyDeclaration::RequiresPython(spec, constraints))
+}
+
+/// Find `requires-python` in a PEP 723 block:
+/// ```text
+/// # /// script
+/// # requires-python = ">=3.11"
+/// # ///
+/// ```
+fn find_requires_python(script: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Metadata {
+        #[serde(rename = "requires-python")]
+        requires_python: Option<String>,
+    }
+
+    let re = Regex::new(r"(?ms)^# /// script\s*$(.*?)^# ///\s*$").unwrap();
+    let block = re.captures(script)?.get(1)?.as_str();
+    let toml_data: Vec<&str> = block
+        .lines()
+        .map(|l| {
+            l.strip_prefix("# ")
+                .unwrap_or_else(|| l.trim_start_matches('#'))
+        })
+        .collect();
+    let metadata: Metadata = toml::from_str(&toml_data.join("\n"))
+        .unwrap_or_else(|e| util::abort(&format!("Problem parsing the script's metadata: {}", e)));
+    metadata.requires_python
+}
+
+/// Find a script's Python version specificion by looking for the `__python__` variable, which
+/// may be commented out.
 ///
-/// If a `__python__` variable is identified, the version must have major, minor, and
-/// patch components to be considered valid. Otherwise, there is still some ambiguity in
+/// If a `__python__` variable is identified, the version must have major and minor
+/// components to be considered valid. Otherwise, there is still some ambiguity in
 /// which version to use and an error is thrown.
 fn check_for_specified_py_vers(script: &str) -> Option<Version> {
-    let re = Regex::new(r#"^__python__\s*=\s*"(.*?)"$"#).unwrap();
+    let re = Regex::new(r#"^(?:#\s*)?__python__\s*=\s*"(.*?)"\s*$"#).unwrap();
 
     for line in script.lines() {
         if let Some(capture) = re.captures(line) {
             let specification = capture.get(1).unwrap().as_str();
-            let (_, version) = parse_version(specification).unwrap();
-            match version {
-                Version {
-                    major: Some(_),
-                    minor: Some(_),
-                    patch: Some(_),
-                    extra_num: None,
-                    modifier: None,
-                    ..
-                } => return Some(version),
+            match parse_version(specification) {
+                Ok((
+                    "",
+                    version @ Version {
+                        major: Some(_),
+                        minor: Some(_),
+                        extra_num: None,
+                        modifier: None,
+                        ..
+                    },
+                )) => return Some(version),
                 _ => {
                     util::abort(
                         "Problem parsing `__python__` variable. Make sure you've included \
-                        major, minor, and patch specifications (eg `__python__ = X.Y.Z`)",
+                        major and minor specifications (eg `__python__ = \"3.11\"`)",
                     );
                 }
             }
@@ -205,6 +534,17 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn console_script_named_after_package() {
+        let scripts = vec![
+            "blackd=blackd:patched_main".to_string(),
+            "black=black:patched_main".to_string(),
+        ];
+        assert_eq!(pick_console_script("Black", &scripts), Some("black"));
+        assert_eq!(pick_console_script("cowsay", &scripts[..1]), Some("blackd"));
+        assert_eq!(pick_console_script("cowsay", &scripts), None);
+    }
+
     #[test]
     fn parse_python_version_with_no_dunder_specified() {
         let script = indoc! { r#"
@@ -244,6 +584,56 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn parse_commented_python_version() {
+        let script = indoc! { r#"
+            # __python__ = "3.11"
+            print("Hello, world")
+        "# };
+
+        let declared = find_py_declaration(script).unwrap();
+        assert_eq!(declared, PyDeclaration::Pinned(Version::new_short(3, 11)));
+        assert_eq!(declared.key(), "__python__ 3.11");
+    }
+
+    #[test]
+    fn parse_pep_723_requires_python() {
+        let script = indoc! { r#"
+            # /// script
+            # requires-python = ">= 3.11"
+            # dependencies = ["requests"]
+            # ///
+
+            print("Hello, world")
+        "# };
+
+        let declared = find_py_declaration(script).unwrap();
+        assert_eq!(declared.key(), "requires-python >=3.11");
+        assert!(declared.allows(&Version::new_short(3, 12)));
+        assert!(!declared.allows(&Version::new_short(3, 10)));
+        assert_eq!(find_requires_python("# /// script\n# ///\n"), None);
+    }
+
+    #[test]
+    fn python_version_selected() {
+        let declared =
+            find_py_declaration("# /// script\n# requires-python = \">=3.11\"\n# ///\n").unwrap();
+        let available = [Version::new_short(3, 12), Version::new_short(3, 10)];
+        let installable = [Version::new_short(3, 11)];
+
+        assert_eq!(
+            select_py_vers(&declared, &available, &installable),
+            Ok(Version::new_short(3, 12))
+        );
+        assert_eq!(
+            select_py_vers(&declared, &available[1..], &installable),
+            Ok(Version::new_short(3, 11))
+        );
+        let err = select_py_vers(&declared, &available[1..], &[]).unwrap_err();
+        assert!(err.contains("needs Python >=3.11"));
+        assert!(err.contains("Found: 3.10. pyflow can install: none"));
+    }
+
     #[test]
     fn parse_no_dependencies_with_no_requires() {
         let script = indoc! { r#"
@@ -0,0 +1,284 @@
+//! Limits on unpacking wheels and source dists, so a corrupted or malicious archive, eg from a
+//! compromised mirror, can't fill the disk, or write outside the folder it's unpacked into.
+
+use std::{
+    io::{self, Read, Write},
+    path::{Component, Path},
+    sync::OnceLock,
+};
+
+/// How many bytes one archive may unpack to, unless `--max-unpack-size` sets another.
+const DEFAULT_MAX_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Entries that unpack to less than this aren't checked for their compression ratio; small
+/// files of repeated text can compress very well.
+const RATIO_MIN_SIZE: u64 = 1024 * 1024;
+
+/// The most an entry may compress by, 200:1. Packages' files that size compress by far less;
+/// zip bombs approach deflate's limit of about 1000:1.
+const MAX_RATIO: u64 = 200;
+
+static MAX_SIZE: OnceLock<u64> = OnceLock::new();
+
+/// Set from `--max-unpack-size`. Only the first call has an effect.
+pub fn configure(max_size: Option<u64>) {
+    MAX_SIZE.get_or_init(|| max_size.unwrap_or(DEFAULT_MAX_SIZE));
+}
+
+fn max_size() -> u64 {
+    *MAX_SIZE.get_or_init(|| DEFAULT_MAX_SIZE)
+}
+
+/// Parse a size, eg `4GiB`, `500MB`, or a number of bytes.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000 * 1000,
+        "gb" => 1000 * 1000 * 1000,
+        "k" | "kib" => 1024,
+        "m" | "mib" => 1024 * 1024,
+        "g" | "gib" => 1024 * 1024 * 1024,
+        _ => return Err(format!("Can't parse the size {}; try eg `4GiB`", s)),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Can't parse the size {}; try eg `4GiB`", s))
+}
+
+/// Whether `path` is absolute, or has `..` components.
+fn is_unsafe(path: &Path) -> bool {
+    path.components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Check an entry's path, after any rewriting for renamed packages.
+pub fn check_path(entry: &str, path: &Path) -> Result<(), String> {
+    if is_unsafe(path) {
+        return Err(format!(
+            "the entry `{}` is written to {}, outside the folder it's unpacked into",
+            entry,
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Check that a link at `path`, to `target`, stays in the folder it's unpacked into. Symbolic
+/// link targets are relative to the link's folder; hard link targets to the archive's root.
+pub fn check_link(entry: &str, path: &Path, target: &Path, hard: bool) -> Result<(), String> {
+    let base = if hard {
+        Path::new("")
+    } else {
+        path.parent().unwrap_or_else(|| Path::new(""))
+    };
+    let mut depth: i64 = base.components().count() as i64;
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => (),
+            Component::ParentDir => depth -= 1,
+            Component::RootDir | Component::Prefix(_) => depth = -1,
+        }
+        if depth < 0 {
+            return Err(format!(
+                "the link `{}` points to {}, outside the folder it's unpacked into",
+                entry,
+                target.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Reject entries that unpack to many times their compressed size: zip bombs.
+pub fn check_ratio(entry: &str, compressed: u64, uncompressed: u64) -> Result<(), String> {
+    if uncompressed >= RATIO_MIN_SIZE && uncompressed / compressed.max(1) > MAX_RATIO {
+        return Err(format!(
+            "the entry `{}` unpacks from {} bytes to {}; it may be a zip bomb",
+            entry, compressed, uncompressed
+        ));
+    }
+    Ok(())
+}
+
+/// Why copying an entry stopped.
+#[derive(Debug)]
+pub enum CopyError {
+    /// The archive unpacks to more than its limit.
+    Refused(String),
+    /// Reading the entry, or writing it, failed, eg as the disk is full.
+    Io(io::Error),
+}
+
+/// How many more bytes one archive may unpack to.
+pub struct Budget {
+    limit: u64,
+    used: u64,
+}
+
+impl Budget {
+    pub fn new() -> Self {
+        Self::with_limit(max_size())
+    }
+
+    pub fn with_limit(limit: u64) -> Self {
+        Self { limit, used: 0 }
+    }
+
+    fn exceeded(&self, entry: &str) -> String {
+        format!(
+            "it unpacks to more than {} bytes, at the entry `{}`. Install with \
+             `--max-unpack-size` to allow larger archives",
+            self.limit, entry
+        )
+    }
+
+    /// Bytes counted so far.
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    /// Count an entry whose size we know, eg from a tar header.
+    pub fn reserve(&mut self, entry: &str, bytes: u64) -> Result<(), String> {
+        self.used = self.used.saturating_add(bytes);
+        if self.used > self.limit {
+            return Err(self.exceeded(entry));
+        }
+        Ok(())
+    }
+
+    /// Copy an entry, counting the bytes actually written; the size an archive claims may be
+    /// wrong.
+    pub fn copy(
+        &mut self,
+        entry: &str,
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+    ) -> Result<(), CopyError> {
+        let remaining = self.limit.saturating_sub(self.used);
+        let written = io::copy(&mut reader.take(remaining + 1), writer).map_err(CopyError::Io)?;
+        self.reserve(entry, written).map_err(CopyError::Refused)
+    }
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Check a source dist's entry before unpacking it: its path, where links point, and the
+/// archive's total size.
+pub fn check_tar_entry<R: Read>(entry: &tar::Entry<R>, budget: &mut Budget) -> Result<(), String> {
+    let path = entry
+        .path()
+        .map_err(|_| "an entry has an unreadable path".to_owned())?;
+    let name = path.display().to_string();
+    check_path(&name, &path)?;
+
+    let kind = entry.header().entry_type();
+    if kind.is_symlink() || kind.is_hard_link() {
+        if let Ok(Some(target)) = entry.link_name() {
+            check_link(&name, &path, &target, kind.is_hard_link())?;
+        }
+    }
+    budget.reserve(&name, entry.header().size().unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tar with one entry, whose path and link target are written as-is, since `tar` refuses
+    /// to build unsafe ones.
+    fn tar_with(path: &str, link: Option<&str>, data: &[u8]) -> Vec<u8> {
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+        match link {
+            Some(target) => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_link_name(target).unwrap();
+                header.set_size(0);
+            }
+            None => header.set_size(data.len() as u64),
+        }
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(vec![]);
+        let data: &[u8] = if link.is_some() { &[] } else { data };
+        builder.append(&header, data).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn check_tar(data: &[u8], budget: &mut Budget) -> Result<(), String> {
+        let mut archive = tar::Archive::new(data);
+        let entry = archive.entries().unwrap().next().unwrap().unwrap();
+        check_tar_entry(&entry, budget)
+    }
+
+    #[test]
+    fn sizes_parsed() {
+        assert_eq!(parse_size("2GiB"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("500MB"), Ok(500_000_000));
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert!(parse_size("lots").is_err());
+    }
+
+    #[test]
+    fn unsafe_paths_rejected() {
+        assert!(check_path("a", Path::new("numpy/core/__init__.py")).is_ok());
+        assert!(check_path("a", Path::new("../evil.py")).is_err());
+        assert!(check_path("a", Path::new("pkg/../../evil.py")).is_err());
+        assert!(check_path("a", Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn tar_links_stay_inside() {
+        let mut budget = Budget::with_limit(1000);
+        let readme = tar_with("pkg-1.0/README", Some("docs/README.md"), b"");
+        assert!(check_tar(&readme, &mut budget).is_ok());
+        let sibling = tar_with("pkg-1.0/docs/README", Some("../README.md"), b"");
+        assert!(check_tar(&sibling, &mut budget).is_ok());
+
+        let escape = tar_with("pkg-1.0/evil", Some("../../../home/.bashrc"), b"");
+        let err = check_tar(&escape, &mut budget).unwrap_err();
+        assert!(err.contains("pkg-1.0/evil") && err.contains("outside"));
+        let absolute = tar_with("pkg-1.0/evil", Some("/etc/passwd"), b"");
+        assert!(check_tar(&absolute, &mut budget).is_err());
+
+        let traversal = tar_with("pkg-1.0/../../evil.py", None, b"print()");
+        assert!(check_tar(&traversal, &mut budget).is_err());
+    }
+
+    #[test]
+    fn oversized_archives_stopped() {
+        let mut budget = Budget::with_limit(10);
+        let small = tar_with("pkg-1.0/a.py", None, b"12345");
+        assert!(check_tar(&small, &mut budget).is_ok());
+        let large = tar_with("pkg-1.0/b.py", None, b"123456");
+        let err = check_tar(&large, &mut budget).unwrap_err();
+        assert!(err.contains("pkg-1.0/b.py") && err.contains("more than 10 bytes"));
+
+        // Bytes are counted as written, whatever size the archive claims.
+        let mut budget = Budget::with_limit(10);
+        let mut out = vec![];
+        match budget.copy("big.bin", &mut io::repeat(0).take(100), &mut out) {
+            Err(CopyError::Refused(err)) => assert!(err.contains("big.bin")),
+            other => panic!("expected the copy to be refused, got {:?}", other),
+        }
+        assert!(out.len() <= 11);
+    }
+
+    #[test]
+    fn zip_bombs_rejected() {
+        assert!(check_ratio("a", 10_000, 100_000).is_ok());
+        // Small files may compress very well.
+        assert!(check_ratio("a", 10, 100_000).is_ok());
+        assert!(check_ratio("bomb", 2_000, 2_000_000_000).is_err());
+    }
+}
@@ -0,0 +1,489 @@
+//! Keeps the shared caches from growing without bound. After an install, entries past their
+//! pool's limits are removed, least recently used first. Since filesystems are often mounted
+//! `noatime`, each pool keeps a log of when its entries were last used, in `.last_used`;
+//! entries missing from it go by their modification time.
+//!
+//! Limits are set in `~/.config/pyflow/config.toml`, eg `max_cache_size = "5GiB"` and
+//! `max_cache_age = "90d"`. Built wheels and Python runtime archives are expensive to get
+//! again, so have their own, higher, limits.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+use termcolor::Color;
+
+use crate::{
+    util::{self, archive_limits, guard, paths, paths::PyflowDirs},
+    CliConfig,
+};
+
+/// In each pool's folder.
+pub const LOG_FILENAME: &str = ".last_used";
+
+const GIB: u64 = 1024 * 1024 * 1024;
+const DAY: u64 = 24 * 60 * 60;
+
+/// Used by this process, so never removed by it.
+static USED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Limits from `~/.config/pyflow/config.toml`. Sizes are eg `5GiB` or `500MB`, and ages eg
+/// `90d`, `2w`, or `12h`. Without an age, entries are only removed to stay under the size.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Config {
+    pub max_cache_size: Option<String>,
+    pub max_cache_age: Option<String>,
+    pub max_built_wheel_cache_size: Option<String>,
+    pub max_built_wheel_cache_age: Option<String>,
+    pub max_python_archive_cache_size: Option<String>,
+    pub max_python_archive_cache_age: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pool {
+    /// Downloaded wheels and source dists.
+    Archives,
+    /// Wheels we've built from source.
+    BuiltWheels,
+    /// Downloaded Python installations.
+    PythonArchives,
+}
+
+const POOLS: [Pool; 3] = [Pool::Archives, Pool::BuiltWheels, Pool::PythonArchives];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub max_size: u64,
+    pub max_age: Option<Duration>,
+}
+
+impl Pool {
+    fn dir(self, dirs: &PyflowDirs) -> PathBuf {
+        match self {
+            Self::Archives => dirs.cache.join(paths::ARCHIVE_DIR),
+            Self::BuiltWheels => dirs.cache.join(util::wheel_cache::DIR_NAME),
+            Self::PythonArchives => dirs.python.clone(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Archives => "dependency cache",
+            Self::BuiltWheels => "built-wheel cache",
+            Self::PythonArchives => "Python archive cache",
+        }
+    }
+
+    /// The keys for this pool's size and age.
+    fn keys(self) -> (&'static str, &'static str) {
+        match self {
+            Self::Archives => ("max_cache_size", "max_cache_age"),
+            Self::BuiltWheels => ("max_built_wheel_cache_size", "max_built_wheel_cache_age"),
+            Self::PythonArchives => (
+                "max_python_archive_cache_size",
+                "max_python_archive_cache_age",
+            ),
+        }
+    }
+
+    fn limits(self, config: &Config) -> Result<Limits, String> {
+        let (size, age, default_size) = match self {
+            Self::Archives => (&config.max_cache_size, &config.max_cache_age, 5 * GIB),
+            Self::BuiltWheels => (
+                &config.max_built_wheel_cache_size,
+                &config.max_built_wheel_cache_age,
+                20 * GIB,
+            ),
+            Self::PythonArchives => (
+                &config.max_python_archive_cache_size,
+                &config.max_python_archive_cache_age,
+                10 * GIB,
+            ),
+        };
+        let (size_key, age_key) = self.keys();
+        let in_key = |key: &str, e: String| format!("`{}` in the user config: {}", key, e);
+        Ok(Limits {
+            max_size: match size {
+                Some(s) => archive_limits::parse_size(s).map_err(|e| in_key(size_key, e))?,
+                None => default_size,
+            },
+            max_age: age
+                .as_deref()
+                .map(parse_age)
+                .transpose()
+                .map_err(|e| in_key(age_key, e))?,
+        })
+    }
+
+    /// Whether `name`, in the pool's folder, is one of its entries.
+    fn holds(self, name: &str, is_dir: bool) -> bool {
+        if name.starts_with('.') {
+            // The log, and entries being written.
+            return false;
+        }
+        match self {
+            Self::Archives => !is_dir && !name.ends_with(".lock"),
+            Self::BuiltWheels => is_dir,
+            Self::PythonArchives => {
+                !is_dir && name.starts_with("python-") && name.ends_with(".tar.xz")
+            }
+        }
+    }
+}
+
+/// Parse an age, eg `90d`, `2w`, `12h`, or a number of days.
+pub fn parse_age(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let multiplier = match unit.trim().to_lowercase().as_str() {
+        "" | "d" | "day" | "days" => DAY,
+        "h" | "hour" | "hours" => 60 * 60,
+        "w" | "week" | "weeks" => 7 * DAY,
+        _ => return Err(format!("Can't parse the age {}; try eg `90d`", s)),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Can't parse the age {}; try eg `90d`", s))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Record that `name`, in a pool's folder `dir`, was just used, eg an archive we installed from.
+/// It won't be removed by this process.
+pub fn record_use(dir: &Path, name: &str) {
+    USED.lock().unwrap().push(dir.join(name));
+    // Appending keeps other processes' records; it's compacted when entries are removed.
+    let appended = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(LOG_FILENAME))
+        .and_then(|mut log| log.write_all(format!("{} {}\n", now(), name).as_bytes()));
+    // Without it, the entry goes by its modification time.
+    appended.ok();
+}
+
+/// When each entry in the log was last used.
+fn read_log(data: &str) -> Vec<(String, u64)> {
+    let mut result: Vec<(String, u64)> = vec![];
+    for line in data.lines() {
+        let (time, name) = match line.split_once(' ') {
+            Some((t, n)) => match t.parse::<u64>() {
+                Ok(t) => (t, n),
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        match result.iter_mut().find(|(n, _)| n == name) {
+            Some((_, last)) => *last = (*last).max(time),
+            None => result.push((name.to_owned(), time)),
+        }
+    }
+    result
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// In the pool's folder.
+    pub name: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub last_used: u64,
+}
+
+fn size_of(path: &Path) -> u64 {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.is_dir() => fs::read_dir(path)
+            .map(|entries| entries.flatten().map(|e| size_of(&e.path())).sum())
+            .unwrap_or(0),
+        Ok(m) => m.len(),
+        Err(_) => 0,
+    }
+}
+
+fn modified(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+fn scan(pool: Pool, dir: &Path) -> Vec<Entry> {
+    let log = read_log(&fs::read_to_string(dir.join(LOG_FILENAME)).unwrap_or_default());
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+    entries
+        .flatten()
+        .filter_map(|e| {
+            let name = e.file_name().to_str()?.to_owned();
+            if !pool.holds(&name, e.path().is_dir()) {
+                return None;
+            }
+            let last_used = log
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map_or_else(|| modified(&e.path()), |(_, t)| *t);
+            Some(Entry {
+                size: size_of(&e.path()),
+                name,
+                last_used,
+            })
+        })
+        .collect()
+}
+
+/// The entries to remove to keep within `limits`: those last used longer ago than the max age,
+/// then the least recently used, until the rest fit in the max size. `keep` entries are never
+/// chosen, but count towards the size.
+pub fn plan<'a>(
+    entries: &'a [Entry],
+    limits: &Limits,
+    now: u64,
+    keep: impl Fn(&Entry) -> bool,
+) -> Vec<&'a Entry> {
+    let mut by_use: Vec<&Entry> = entries.iter().collect();
+    by_use.sort_by(|a, b| a.last_used.cmp(&b.last_used).then(a.name.cmp(&b.name)));
+
+    let mut total: u64 = entries.iter().map(|e| e.size).sum();
+    let mut result = vec![];
+    for entry in by_use {
+        let expired = limits
+            .max_age
+            .is_some_and(|age| now.saturating_sub(entry.last_used) > age.as_secs());
+        if (expired || total > limits.max_size) && !keep(entry) {
+            total -= entry.size;
+            result.push(entry);
+        }
+    }
+    result
+}
+
+fn remove(pool: Pool, path: &Path) -> bool {
+    if pool != Pool::Archives {
+        return if path.is_dir() {
+            fs::remove_dir_all(path).is_ok()
+        } else {
+            fs::remove_file(path).is_ok()
+        };
+    }
+    // Skip archives another process is downloading or installing from.
+    let mut guard_path = PathBuf::from(path);
+    guard_path.as_mut_os_string().push(".lock");
+    let guard = match guard::acquire(&guard_path, Duration::ZERO, |_| ()) {
+        Ok(g) => g,
+        Err(_) => return false,
+    };
+    let removed = fs::remove_file(path).is_ok();
+    drop(guard);
+    fs::remove_file(&guard_path).ok();
+    removed
+}
+
+/// Rewrite a pool's log with only the entries that are left.
+fn compact_log(dir: &Path, left: &[&Entry]) {
+    let log = read_log(&fs::read_to_string(dir.join(LOG_FILENAME)).unwrap_or_default());
+    let data: String = log
+        .into_iter()
+        .filter(|(name, _)| left.iter().any(|e| e.name == *name))
+        .map(|(name, time)| format!("{} {}\n", time, name))
+        .collect();
+    let tmp = dir.join(format!("{}.tmp", LOG_FILENAME));
+    if fs::write(&tmp, data).is_ok() {
+        fs::rename(&tmp, dir.join(LOG_FILENAME)).ok();
+    }
+}
+
+/// Remove what's past its limits from each pool. Run after installs.
+pub fn enforce(dirs: &PyflowDirs) {
+    let config = paths::cache_config();
+    let used = USED.lock().unwrap().clone();
+    let verbose = CliConfig::current().verbose;
+    for pool in POOLS {
+        let limits = match pool.limits(&config) {
+            Ok(l) => l,
+            Err(e) => {
+                util::print_color(
+                    &format!("Not limiting the {}: {}", pool.name(), e),
+                    Color::Yellow,
+                );
+                continue;
+            }
+        };
+        let dir = pool.dir(dirs);
+        let entries = scan(pool, &dir);
+        let now = now();
+        let chosen = plan(&entries, &limits, now, |e| {
+            used.contains(&dir.join(&e.name))
+        });
+        if chosen.is_empty() {
+            continue;
+        }
+
+        let mut removed = vec![];
+        for entry in chosen {
+            if !remove(pool, &dir.join(&entry.name)) {
+                continue;
+            }
+            if verbose {
+                println!(
+                    "Removed {} from the {} ({}, last used {} days ago)",
+                    entry.name,
+                    pool.name(),
+                    util::format_size(entry.size),
+                    now.saturating_sub(entry.last_used) / DAY
+                );
+            }
+            removed.push(&entry.name);
+        }
+        let left: Vec<&Entry> = entries
+            .iter()
+            .filter(|e| !removed.contains(&&e.name))
+            .collect();
+        compact_log(&dir, &left);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, size: u64, last_used: u64) -> Entry {
+        Entry {
+            name: name.to_owned(),
+            size,
+            last_used,
+        }
+    }
+
+    fn names(entries: Vec<&Entry>) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn least_recently_used_removed_first() {
+        let now = 100 * DAY;
+        let entries = [
+            entry("recent.whl", 40, now - DAY),
+            entry("oldest.whl", 30, now - 50 * DAY),
+            entry("old.whl", 20, now - 20 * DAY),
+            entry("older.tar.gz", 20, now - 30 * DAY),
+        ];
+        let limits = |max_size: u64, max_age: Option<u64>| Limits {
+            max_size,
+            max_age: max_age.map(|days| Duration::from_secs(days * DAY)),
+        };
+
+        // 110 in total.
+        assert!(plan(&entries, &limits(110, None), now, |_| false).is_empty());
+        assert_eq!(
+            names(plan(&entries, &limits(109, None), now, |_| false)),
+            ["oldest.whl"]
+        );
+        // Removing `oldest` leaves 80; `older` too leaves exactly 60.
+        assert_eq!(
+            names(plan(&entries, &limits(60, None), now, |_| false)),
+            ["oldest.whl", "older.tar.gz"]
+        );
+        assert_eq!(
+            names(plan(&entries, &limits(0, None), now, |_| false)).len(),
+            4
+        );
+
+        // Past the age, whatever the size.
+        assert_eq!(
+            names(plan(&entries, &limits(1000, Some(25)), now, |_| false)),
+            ["oldest.whl", "older.tar.gz"]
+        );
+
+        // What this run used stays, and the next least recent goes instead.
+        let keep = |e: &Entry| e.name == "oldest.whl";
+        assert_eq!(
+            names(plan(&entries, &limits(80, None), now, keep)),
+            ["older.tar.gz", "old.whl"]
+        );
+    }
+
+    #[test]
+    fn last_use_from_log() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        fs::create_dir_all(dir.join("built-key")).unwrap();
+        fs::write(dir.join("a-1.0-py3-none-any.whl"), vec![0; 10]).unwrap();
+        fs::write(dir.join("a-1.0-py3-none-any.whl.lock"), "").unwrap();
+        fs::write(dir.join("b-2.0.tar.gz"), vec![0; 5]).unwrap();
+        fs::write(
+            dir.join(LOG_FILENAME),
+            "50 a-1.0-py3-none-any.whl\n70 b-2.0.tar.gz\n60 a-1.0-py3-none-any.whl\nbroken\n",
+        )
+        .unwrap();
+
+        let mut entries = scan(Pool::Archives, &dir);
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(
+            entries,
+            [
+                entry("a-1.0-py3-none-any.whl", 10, 60),
+                entry("b-2.0.tar.gz", 5, 70)
+            ]
+        );
+
+        record_use(&dir, "a-1.0-py3-none-any.whl");
+        let log = read_log(&fs::read_to_string(dir.join(LOG_FILENAME)).unwrap());
+        assert!(log[0].1 >= now() - 60);
+        assert!(USED
+            .lock()
+            .unwrap()
+            .contains(&dir.join("a-1.0-py3-none-any.whl")));
+
+        compact_log(&dir, &[&entries[1]]);
+        assert_eq!(
+            fs::read_to_string(dir.join(LOG_FILENAME)).unwrap(),
+            "70 b-2.0.tar.gz\n"
+        );
+    }
+
+    #[test]
+    fn limits_from_config() {
+        let config = Config {
+            max_cache_size: Some("500MB".into()),
+            max_cache_age: Some("2w".into()),
+            max_built_wheel_cache_age: Some("soon".into()),
+            ..Config::default()
+        };
+        assert_eq!(
+            Pool::Archives.limits(&config).unwrap(),
+            Limits {
+                max_size: 500 * 1000 * 1000,
+                max_age: Some(Duration::from_secs(14 * DAY)),
+            }
+        );
+        assert!(Pool::BuiltWheels
+            .limits(&config)
+            .unwrap_err()
+            .contains("max_built_wheel_cache_age"));
+        assert_eq!(
+            Pool::PythonArchives.limits(&config).unwrap(),
+            Limits {
+                max_size: 10 * GIB,
+                max_age: None,
+            }
+        );
+        assert_eq!(parse_age("90").unwrap(), Duration::from_secs(90 * DAY));
+        assert_eq!(parse_age("12h").unwrap(), Duration::from_secs(12 * 60 * 60));
+    }
+}
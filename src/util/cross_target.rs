@@ -0,0 +1,207 @@
+//! Locking and bundling for another platform and Python version, eg for a Linux container built
+//! on a Mac, with `--platform` and `--target-python`. We can't build source dists for another
+//! platform, so only wheels are used, and the lock is marked with what it targets.
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+use termcolor::Color;
+
+use crate::{
+    dep_types::Version,
+    util::{abort, print_color, Os},
+};
+
+const PLATFORM_KEY: &str = "target_platform";
+const PYTHON_KEY: &str = "target_python";
+
+/// Machine types in wheel platform tags, eg `x86_64` in `manylinux2014_x86_64`.
+const ARCHES: [&str; 9] = [
+    "x86_64",
+    "aarch64",
+    "arm64",
+    "amd64",
+    "i686",
+    "ppc64le",
+    "s390x",
+    "armv7l",
+    "universal2",
+];
+
+static WHEELS_ONLY: AtomicBool = AtomicBool::new(false);
+static PLATFORM: OnceLock<String> = OnceLock::new();
+
+/// The platform and Python version to lock or bundle for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+    /// As passed, eg `manylinux2014_x86_64`, or `linux`.
+    pub platform: String,
+    pub os: Os,
+    pub python: Version,
+}
+
+impl Target {
+    /// From `--platform` and `--target-python`. `None` if neither was passed; a missing one is
+    /// taken from this machine, or the project.
+    pub fn from_args(
+        platform: Option<&str>,
+        python: Option<&str>,
+        os: Os,
+        py_vers: &Version,
+    ) -> Option<Self> {
+        if platform.is_none() && python.is_none() {
+            return None;
+        }
+        let (platform, os) = match platform {
+            Some(p) => match Os::from_str(&p.to_lowercase()) {
+                Ok(Os::Any) | Err(_) => abort(&format!(
+                    "Unknown platform: {}. Expected a wheel platform tag like \
+                     `manylinux2014_x86_64`, or linux, windows, or macos",
+                    p
+                )),
+                Ok(target_os) => (p.to_owned(), target_os),
+            },
+            None => (format!("{:?}", os).to_lowercase(), os),
+        };
+        let python = match python {
+            Some(p) => Version::from_str(p)
+                .unwrap_or_else(|_| abort(&format!("Problem parsing Python version: {}", p))),
+            None => py_vers.clone(),
+        };
+        Some(Self {
+            platform,
+            os,
+            python,
+        })
+    }
+
+    /// The target a lock was made for, from its metadata.
+    pub fn from_lock(metadata: &HashMap<String, String>) -> Option<Self> {
+        let platform = metadata.get(PLATFORM_KEY)?;
+        Some(Self {
+            platform: platform.clone(),
+            os: Os::from_str(&platform.to_lowercase()).ok()?,
+            python: Version::from_str(metadata.get(PYTHON_KEY)?).ok()?,
+        })
+    }
+
+    /// Mark a lock's metadata with this target.
+    pub fn mark(&self, metadata: &mut HashMap<String, String>) {
+        metadata.insert(PLATFORM_KEY.into(), self.platform.clone());
+        metadata.insert(PYTHON_KEY.into(), self.python.to_string());
+    }
+
+    /// Only pick wheels, for this target's machine type, from now on.
+    pub fn make_current(&self) {
+        WHEELS_ONLY.store(true, Ordering::Relaxed);
+        PLATFORM.set(self.platform.clone()).ok();
+    }
+}
+
+/// If we're installing for another platform, so can't build source dists.
+pub fn wheels_only() -> bool {
+    WHEELS_ONLY.load(Ordering::Relaxed)
+}
+
+/// eg `x86_64` for `manylinux_2_17_x86_64.manylinux2014_x86_64`.
+fn arch(platform: &str) -> Option<&'static str> {
+    let platform = platform.to_lowercase();
+    ARCHES.into_iter().find(|a| {
+        platform
+            .split('.')
+            .any(|tag| tag == *a || tag.ends_with(&format!("_{}", a)))
+    })
+}
+
+/// If a wheel's platform tag can run on `target`'s machine type. Tags without one, like `any`
+/// or `win32`, and targets without one, like `linux`, always can.
+pub fn arch_matches(target: &str, wheel_platform: &str) -> bool {
+    match (arch(target), arch(wheel_platform)) {
+        (Some(t), Some(w)) => {
+            t == w
+                || matches!((t, w), ("x86_64", "amd64") | ("amd64", "x86_64"))
+                || (w == "universal2" && matches!(t, "x86_64" | "arm64"))
+        }
+        _ => true,
+    }
+}
+
+/// If a wheel can run on the target's machine type, if we're installing for another platform.
+pub fn wheel_matches(wheel_platform: &str) -> bool {
+    PLATFORM
+        .get()
+        .is_none_or(|target| arch_matches(target, wheel_platform))
+}
+
+/// Warn before installing from a lock made for another platform or Python version.
+pub fn warn_if_foreign(metadata: &HashMap<String, String>, os: Os, py_vers: &Version) {
+    let target = match Target::from_lock(metadata) {
+        Some(t) => t,
+        None => return,
+    };
+    let same_python = target.python.major == py_vers.major && target.python.minor == py_vers.minor;
+    if target.os == os && same_python {
+        return;
+    }
+    print_color(
+        &format!(
+            "`pyflow.lock` was made for {} and Python {}, with `pyflow lock --platform`. \
+             Packages that don't apply to this machine will be resolved again here.",
+            target.platform, target.python
+        ),
+        Color::Yellow,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::dep_types::Lock;
+
+    #[rstest(
+        target,
+        wheel,
+        expected,
+        case(
+            "manylinux2014_x86_64",
+            "manylinux_2_17_x86_64.manylinux2014_x86_64",
+            true
+        ),
+        case("manylinux2014_x86_64", "manylinux2014_aarch64", false),
+        case("manylinux2014_x86_64", "any", true),
+        case("linux", "manylinux2014_aarch64", true),
+        case("macosx_11_0_arm64", "macosx_10_9_universal2", true),
+        case("macosx_11_0_arm64", "macosx_10_9_x86_64", false),
+        case("win_amd64", "win_amd64", true),
+        case("win_amd64", "win32", true)
+    )]
+    fn wheel_arches(target: &str, wheel: &str, expected: bool) {
+        assert_eq!(arch_matches(target, wheel), expected);
+    }
+
+    #[test]
+    fn lock_metadata() {
+        let target = Target::from_args(
+            Some("manylinux2014_x86_64"),
+            Some("3.11"),
+            Os::Mac,
+            &Version::new(3, 12, 0),
+        )
+        .unwrap();
+        assert_eq!(target.os, Os::Linux);
+
+        let mut lock = Lock::default();
+        target.mark(&mut lock.metadata);
+        let lock: Lock = toml::from_str(&toml::to_string(&lock).unwrap()).unwrap();
+        assert_eq!(Target::from_lock(&lock.metadata), Some(target));
+        assert_eq!(Target::from_lock(&HashMap::new()), None);
+    }
+}
@@ -0,0 +1,326 @@
+//! The dependency graph recorded in `pyflow.lock`: which locked package each one requires. Used
+//! to prune the lock, and for `pyflow graph`, `pyflow sbom`, and `pyflow why-not`.
+
+use std::{collections::VecDeque, str::FromStr};
+
+use crate::{
+    dep_types::{DepExtras, LockPackage, Marker, Version},
+    util,
+};
+
+/// A locked package's dependency, eg
+/// `uvicorn[standard] 0.23.2 pypi+https://pypi.org/pypi/uvicorn/0.23.2/json ; extra == 'server'`:
+/// `uvicorn` with its `standard` extra, needed only with the parent's `server` extra.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LockDep {
+    pub name: String,
+    pub version: Option<Version>,
+    pub extras: DepExtras,
+}
+
+impl LockDep {
+    pub fn parse(dep: &str) -> Self {
+        let (dep, marker) = match dep.split_once(" ; ") {
+            Some((d, m)) => (d, Some(m)),
+            None => (dep, None),
+        };
+        let mut parts = dep.split_whitespace();
+        let name = parts.next().unwrap_or_default();
+        let (name, install_with) = match name.split_once('[') {
+            Some((n, extras)) => (
+                n,
+                extras
+                    .trim_end_matches(']')
+                    .split(',')
+                    .filter(|e| !e.is_empty())
+                    .map(ToOwned::to_owned)
+                    .collect(),
+            ),
+            None => (name, vec![]),
+        };
+        Self {
+            name: name.to_owned(),
+            version: parts.next().and_then(|v| Version::from_str(v).ok()),
+            extras: DepExtras {
+                extra: marker
+                    .and_then(|m| Marker::from_str(m).ok())
+                    .and_then(|m| m.extra()),
+                install_with,
+            },
+        }
+    }
+
+    /// The form stored in the lock.
+    pub fn format(name: &str, version: &Version, extras: &DepExtras) -> String {
+        let mut result = name.to_owned();
+        if !extras.install_with.is_empty() {
+            result.push_str(&format!("[{}]", extras.install_with.join(",")));
+        }
+        result.push_str(&format!(
+            " {} pypi+{}",
+            version,
+            util::index::json_url(name, &version.to_string())
+        ));
+        if let Some(extra) = &extras.extra {
+            result.push_str(&format!(" ; {}", Marker::Extra(extra.clone())));
+        }
+        result
+    }
+}
+
+pub struct DepGraph<'a> {
+    pub packages: &'a [LockPackage],
+    /// For each package, the indices of the ones it requires.
+    deps: Vec<Vec<usize>>,
+    /// For each package, its dependencies, with the packages they match. Dependencies that
+    /// match none aren't locked.
+    edges: Vec<Vec<(LockDep, Vec<usize>)>>,
+}
+
+impl<'a> DepGraph<'a> {
+    pub fn from_lock(packages: &'a [LockPackage]) -> Self {
+        let edges: Vec<Vec<(LockDep, Vec<usize>)>> = packages
+            .iter()
+            .map(|lp| {
+                lp.dependencies
+                    .iter()
+                    .flatten()
+                    .map(|dep| {
+                        let dep = LockDep::parse(dep);
+                        let found = (0..packages.len())
+                            .filter(|j| {
+                                util::compare_names(&packages[*j].name, &dep.name)
+                                    && Version::from_str(&packages[*j].version).ok() == dep.version
+                            })
+                            .collect();
+                        (dep, found)
+                    })
+                    .collect()
+            })
+            .collect();
+        let deps = edges
+            .iter()
+            .map(|es| {
+                let mut result: Vec<usize> =
+                    es.iter().flat_map(|(_, found)| found).copied().collect();
+                result.sort_unstable();
+                result.dedup();
+                result
+            })
+            .collect();
+        Self {
+            packages,
+            deps,
+            edges,
+        }
+    }
+
+    /// The packages `i` requires.
+    pub fn deps(&self, i: usize) -> &[usize] {
+        &self.deps[i]
+    }
+
+    /// Packages with any of these names, eg the project's requirements. Every locked version
+    /// counts, including renamed ones.
+    pub fn named<'n>(&self, names: impl IntoIterator<Item = &'n str>) -> Vec<usize> {
+        let names: Vec<&str> = names.into_iter().collect();
+        (0..self.packages.len())
+            .filter(|i| {
+                names
+                    .iter()
+                    .any(|n| util::compare_names(n, &self.packages[*i].name))
+            })
+            .collect()
+    }
+
+    /// For each package, the fewest requirements from one in `starts` to it, if it's reachable
+    /// in at most `max_depth`.
+    pub fn reachable(&self, starts: &[usize], max_depth: Option<usize>) -> Vec<Option<usize>> {
+        let mut depths = vec![None; self.packages.len()];
+        let mut to_visit = VecDeque::new();
+        for &i in starts {
+            depths[i] = Some(0);
+            to_visit.push_back(i);
+        }
+        while let Some(i) = to_visit.pop_front() {
+            let depth = depths[i].unwrap() + 1;
+            if max_depth.is_some_and(|max| depth > max) {
+                continue;
+            }
+            for &j in &self.deps[i] {
+                if depths[j].is_none() {
+                    depths[j] = Some(depth);
+                    to_visit.push_back(j);
+                }
+            }
+        }
+        depths
+    }
+
+    /// The shortest chain of requirements from a package in `starts` to `target`, including both.
+    /// `None` if it isn't reachable.
+    pub fn path(&self, starts: &[usize], target: usize) -> Option<Vec<usize>> {
+        let mut previous: Vec<Option<usize>> = vec![None; self.packages.len()];
+        let mut visited = vec![false; self.packages.len()];
+        let mut to_visit = VecDeque::new();
+        for &i in starts {
+            visited[i] = true;
+            to_visit.push_back(i);
+        }
+        while let Some(i) = to_visit.pop_front() {
+            if i == target {
+                let mut path = vec![i];
+                while let Some(p) = previous[*path.last().unwrap()] {
+                    path.push(p);
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for &j in &self.deps[i] {
+                if !visited[j] {
+                    visited[j] = true;
+                    previous[j] = Some(i);
+                    to_visit.push_back(j);
+                }
+            }
+        }
+        None
+    }
+
+    /// For each package, the extras it's required with, if it's reachable from `starts`: packages
+    /// with the extras requested for them. Dependencies of an extra are only followed if it's
+    /// requested.
+    pub fn required_extras(&self, starts: &[(usize, Vec<String>)]) -> Vec<Option<Vec<String>>> {
+        fn require(required: &mut [Option<Vec<String>>], i: usize, extras: &[String]) -> bool {
+            let mut changed = required[i].is_none();
+            let current = required[i].get_or_insert_with(Vec::new);
+            for extra in extras.iter().map(|e| util::standardize_name(e)) {
+                if !current.contains(&extra) {
+                    current.push(extra);
+                    changed = true;
+                }
+            }
+            current.sort();
+            changed
+        }
+
+        let mut required = vec![None; self.packages.len()];
+        let mut to_visit = VecDeque::new();
+        for (i, extras) in starts {
+            if require(&mut required, *i, extras) {
+                to_visit.push_back(*i);
+            }
+        }
+        // A package is visited again if it's later found to need more extras.
+        while let Some(i) = to_visit.pop_front() {
+            for (dep, found) in self.active_edges(i, &required) {
+                for &j in found {
+                    if require(&mut required, j, &dep.extras.install_with) {
+                        to_visit.push_back(j);
+                    }
+                }
+            }
+        }
+        required
+    }
+
+    /// Dependencies of required packages that aren't locked, and only needed with an extra: eg
+    /// ones pruned when the extra was dropped, and since requested again.
+    pub fn missing_extra_deps(&self, required: &[Option<Vec<String>>]) -> Vec<&LockDep> {
+        (0..self.packages.len())
+            .flat_map(|i| self.active_edges(i, required))
+            .filter(|(dep, found)| dep.extras.extra.is_some() && found.is_empty())
+            .map(|(dep, _)| dep)
+            .collect()
+    }
+
+    fn active_edges<'s>(
+        &'s self,
+        i: usize,
+        required: &[Option<Vec<String>>],
+    ) -> impl Iterator<Item = &'s (LockDep, Vec<usize>)> {
+        let extras = required[i].clone();
+        self.edges[i]
+            .iter()
+            .filter(move |(dep, _)| match (&extras, &dep.extras.extra) {
+                (None, _) => false,
+                (Some(_), None) => true,
+                (Some(extras), Some(extra)) => extras.contains(&util::standardize_name(extra)),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dep_types::Lock;
+
+    const LOCK: &str = r#"
+[metadata]
+
+[[package]]
+id = 1
+name = "requests"
+version = "2.31.0"
+dependencies = ["urllib3 2.0.7 pypi+https://pypi.org/pypi/urllib3/2.0.7/json", "idna 3.4 pypi+https://pypi.org/pypi/idna/3.4/json"]
+
+[[package]]
+id = 2
+name = "urllib3"
+version = "2.0.7"
+dependencies = []
+
+[[package]]
+id = 3
+name = "idna"
+version = "3.4"
+dependencies = []
+
+[[package]]
+id = 4
+name = "six"
+version = "1.16.0"
+dependencies = []
+"#;
+
+    #[test]
+    fn reachable_within_depth() {
+        let lock: Lock = toml::from_str(LOCK).unwrap();
+        let packages = lock.package.unwrap();
+        let graph = DepGraph::from_lock(&packages);
+        assert_eq!(graph.deps(0), [1, 2]);
+
+        let starts = graph.named(["Requests"]);
+        assert_eq!(starts, [0]);
+        assert_eq!(
+            graph.reachable(&starts, None),
+            [Some(0), Some(1), Some(1), None]
+        );
+        assert_eq!(
+            graph.reachable(&starts, Some(0)),
+            [Some(0), None, None, None]
+        );
+
+        assert_eq!(graph.path(&starts, 2), Some(vec![0, 2]));
+        assert_eq!(graph.path(&starts, 0), Some(vec![0]));
+        assert_eq!(graph.path(&starts, 3), None);
+    }
+
+    #[test]
+    fn lock_deps_with_extras() {
+        let dep = "uvicorn[standard] 0.23.2 pypi+https://pypi.org/pypi/uvicorn/0.23.2/json ; \
+                   extra == 'server'";
+        let parsed = LockDep::parse(dep);
+        assert_eq!(parsed.name, "uvicorn");
+        assert_eq!(parsed.version, Some(Version::new(0, 23, 2)));
+        assert_eq!(parsed.extras.extra.as_deref(), Some("server"));
+        assert_eq!(parsed.extras.install_with, ["standard"]);
+        assert_eq!(
+            LockDep::format("uvicorn", &Version::new(0, 23, 2), &parsed.extras),
+            dep.split_whitespace().collect::<Vec<_>>().join(" ")
+        );
+
+        let plain = LockDep::parse("idna 3.4 pypi+https://pypi.org/pypi/idna/3.4/json");
+        assert_eq!(plain.extras, DepExtras::default());
+    }
+}
@@ -1,149 +1,1234 @@
-use std::{collections::HashMap, path::Path, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use termcolor::Color;
+
+use crate::{
+    dep_resolution::{res, Resolution, WarehouseRelease, RENAMED_INFIX},
+    dep_types::{
+        Constraint, Lock, LockFile, LockPackage, Marker, Package, Rename, Req, ReqType, Version,
+    },
+    install::{self, PackageType},
+    util::{
+        self, abort,
+        dep_graph::{DepGraph, LockDep},
+        hooks::Event,
+        lock_diff::LockDiff,
+        lock_header::{self, Header},
+        prompts, requires_python,
+    },
+    CliConfig, PackToInstall,
+};
+
+static SKIP_DEV: AtomicBool = AtomicBool::new(false);
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+/// For `install_dev_by_default = false` in `[tool.pyflow.settings]`, or `--no-dev`: dev
+/// dependencies are still locked, but not installed.
+pub fn skip_dev() {
+    SKIP_DEV.store(true, Ordering::SeqCst);
+}
+
+/// From `--yes`: Install and uninstall packages without asking first.
+pub fn assume_yes() {
+    ASSUME_YES.store(true, Ordering::SeqCst);
+}
+
+/// Function used by `Install` and `Uninstall` subcommands to syn dependencies with
+/// the config and lock files. With `prune_foreign`, from `--prune-foreign`, installed packages
+/// pyflow didn't install are uninstalled without asking.
+#[allow(clippy::too_many_arguments)]
+pub fn sync(
+    paths: &util::Paths,
+    lockpacks: &[LockPackage],
+    reqs: &[Req],
+    dev_reqs: &[Req],
+    dont_uninstall: &[String],
+    os: util::Os,
+    py_vers: &Version,
+    lock_path: &Path,
+    prune_foreign: bool,
+) {
+    sync_with(
+        paths,
+        lockpacks,
+        reqs,
+        dev_reqs,
+        dont_uninstall,
+        os,
+        py_vers,
+        lock_path,
+        prune_foreign.then_some(true),
+        |reqs, locked, resolution| {
+            res::resolve(reqs, locked, os, py_vers, resolution)
+                .unwrap_or_else(|_| abort("Problem resolving dependencies"))
+        },
+    );
+}
+
+/// As `sync`. `prune_foreign` decides what happens to installed packages pyflow didn't install;
+/// if `None`, we ask. `resolve` resolves reqs that aren't locked, given the locked packages, with
+/// the resolution the lock was made with, so the lock's header stays true to its versions.
+#[allow(clippy::too_many_arguments)]
+fn sync_with(
+    paths: &util::Paths,
+    lockpacks: &[LockPackage],
+    reqs: &[Req],
+    dev_reqs: &[Req],
+    dont_uninstall: &[String],
+    os: util::Os,
+    py_vers: &Version,
+    lock_path: &Path,
+    prune_foreign: Option<bool>,
+    resolve: impl FnOnce(&[Req], &[Package], Resolution) -> Vec<Package>,
+) {
+    let installed = util::find_installed(&paths.lib);
+    let previous_lock = lockpacks;
+    let foreign = find_foreign(&installed, previous_lock, dont_uninstall);
+    let kept_foreign = if !foreign.is_empty() && !confirm_prune(&foreign, prune_foreign) {
+        foreign
+    } else {
+        vec![]
+    };
+    let protected = [dont_uninstall, &kept_foreign].concat();
+    let lockpacks = &offer_re_resolve(lockpacks, &paths.lib, py_vers);
+
+    // todo: Only show this when needed.
+    // todo: Temporarily? Removed.
+    // Powershell  doesn't like emojis
+    //    #[cfg(target_os = "windows")]
+    //    println!("Resolving dependencies...");
+    //    #[cfg(target_os = "linux")]
+    //    println!("🔍 Resolving dependencies...");
+    //    #[cfg(target_os = "macos")]
+    //    println!("🔍 Resolving dependencies...");
+
+    // Dev reqs and normal reqs are both installed here; we only commit dev reqs
+    // when packaging.
+    let mut combined_reqs = reqs.to_vec();
+    for dev_req in dev_reqs.iter().cloned() {
+        combined_reqs.push(dev_req);
+    }
+
+    let skip_dev = SKIP_DEV.load(Ordering::SeqCst);
+    let current = if skip_dev {
+        dev_skipped_view(lockpacks, reqs, &installed, os, py_vers)
+    } else {
+        Some((lockpacks.to_vec(), installed.clone()))
+    };
+    if current.is_some_and(|(lock, installed)| {
+        up_to_date(&lock, &combined_reqs, &protected, &installed, os, py_vers).is_some()
+    }) {
+        lock_header::refresh(lock_path);
+        return;
+    }
+
+    util::hooks::run(Event::PreLock, paths);
+    let resolution = lock_header::resolution(lock_path);
+    if resolution != Resolution::Highest {
+        util::print_color(
+            &format!(
+                "Resolving with `--resolution {}`, which `pyflow.lock` was made with. Run \
+                 `pyflow lock --resolution highest` to lock the newest versions instead.",
+                resolution.as_str()
+            ),
+            Color::Yellow,
+        );
+    }
+    let mut updated_lock_packs = relock_using(
+        lockpacks,
+        &combined_reqs,
+        dont_uninstall,
+        os,
+        py_vers,
+        |reqs, locked| resolve(reqs, locked, resolution),
+    );
+
+    let legacy = lockpacks.iter().filter(|lp| lp.files.is_none()).count();
+    if legacy > 0 {
+        util::print_color(
+            &format!(
+                "Recording release files for {} packages locked by an older version of pyflow. \
+                 Please review the changes to `pyflow.lock`.",
+                legacy
+            ),
+            Color::Yellow,
+        );
+    }
+    record_files(&mut updated_lock_packs);
+    pin_wheels(&mut updated_lock_packs, &combined_reqs);
+
+    let mut metadata = HashMap::new();
+    Header::new(
+        lock_path,
+        resolution,
+        std::slice::from_ref(py_vers),
+        &[lock_header::platform_name(os)],
+    )
+    .mark(&mut metadata);
+    if let Some(project_path) = lock_path.parent() {
+        util::editable::mark(&combined_reqs, project_path, &mut metadata);
+    }
+    let updated_lock = Lock {
+        metadata: metadata.clone(),
+        package: Some(updated_lock_packs.clone()),
+    };
+    let lock_timer = util::timings::start("lock write", None);
+    if util::write_lock(lock_path, &updated_lock).is_err() {
+        abort("Problem writing lock file");
+    }
+    drop(lock_timer);
+    util::hooks::run(Event::PostLock, paths);
+
+    // Now that we've confirmed or modified the lock file, we're ready to sync installed
+    // dependencies with it.
+    // A package we kept is replaced if it's now locked.
+    let protected: Vec<String> = protected
+        .into_iter()
+        .filter(|name| {
+            dont_uninstall.contains(name)
+                || !updated_lock_packs
+                    .iter()
+                    .any(|lp| util::compare_names(&lp.name, name))
+        })
+        .collect();
+    util::hooks::run(Event::PreInstall, paths);
+    // A package whose pinned wheel changed is reinstalled from the new one.
+    let (repinned, installed): (Vec<_>, Vec<_>) = installed.into_iter().partition(|(n, v, _)| {
+        let pin = |lps: &[LockPackage]| {
+            lps.iter()
+                .find(|lp| {
+                    util::compare_names(&lp.name, n)
+                        && Version::from_str(&lp.version).ok().as_ref() == Some(v)
+                })
+                .map(|lp| lp.wheel.clone())
+        };
+        let (old, new) = (pin(previous_lock), pin(&updated_lock_packs));
+        old.is_some() && new.is_some() && old != new
+    });
+    for (name, version, _) in &repinned {
+        install::uninstall(name, version, paths);
+    }
+    let to_install = if skip_dev {
+        runtime_packs(&updated_lock_packs, &applicable(reqs, os, py_vers))
+            .unwrap_or_else(|e| abort(&e))
+    } else {
+        updated_lock_packs.clone()
+    };
+    sync_deps(paths, &to_install, &protected, &installed, os, py_vers);
+
+    let diff = LockDiff::new(previous_lock, &updated_lock_packs, &combined_reqs);
+    if record_modules(&mut updated_lock_packs, &paths.lib) {
+        let updated_lock = Lock {
+            metadata,
+            package: Some(updated_lock_packs),
+        };
+        if util::write_lock(lock_path, &updated_lock).is_err() {
+            abort("Problem writing lock file");
+        }
+    }
+    if !diff.is_empty() {
+        diff.print("Changes to `pyflow.lock`:");
+    }
+    util::hooks::run(Event::PostInstall, paths);
+}
+
+/// Reqs whose markers apply on this OS and Python version.
+fn applicable(reqs: &[Req], os: util::Os, py_vers: &Version) -> Vec<Req> {
+    reqs.iter()
+        .filter(|r| r.marker.as_ref().is_none_or(|m| m.evaluate(os, py_vers)))
+        .cloned()
+        .collect()
+}
+
+/// The lock doesn't record which packages are dev-only, so find the ones `reqs` depend on,
+/// directly or indirectly.
+pub fn runtime_packs(lock_packs: &[LockPackage], reqs: &[Req]) -> Result<Vec<LockPackage>, String> {
+    let mut keep: HashSet<u32> = HashSet::new();
+    let mut to_visit: Vec<&LockPackage> = vec![];
+    for req in reqs.iter().filter(|r| r.path.is_none() && r.git.is_none()) {
+        let found: Vec<&LockPackage> = lock_packs
+            .iter()
+            .filter(|lp| util::compare_names(&lp.name, &req.name))
+            .collect();
+        if found.is_empty() {
+            return Err(format!(
+                "{} isn't in `pyflow.lock`. Run `pyflow install` to update it.",
+                req.name
+            ));
+        }
+        to_visit.extend(found);
+    }
+
+    while let Some(lp) = to_visit.pop() {
+        if !keep.insert(lp.id) {
+            continue;
+        }
+        for dep in lp.dependencies.as_ref().unwrap_or(&vec![]) {
+            let dep = LockDep::parse(dep);
+            to_visit.extend(lock_packs.iter().filter(|p| {
+                util::compare_names(&p.name, &dep.name)
+                    && Version::from_str(&p.version).ok() == dep.version
+            }));
+        }
+    }
+
+    Ok(lock_packs
+        .iter()
+        .filter(|lp| keep.contains(&lp.id))
+        .cloned()
+        .collect())
+}
+
+/// The lock, and what's installed, as `up_to_date` should see them when dev dependencies are
+/// skipped: packages only dev dependencies need count as installed. `None` if one of them is
+/// installed, and needs uninstalling.
+#[allow(clippy::type_complexity)]
+fn dev_skipped_view(
+    lockpacks: &[LockPackage],
+    reqs: &[Req],
+    installed: &[(String, Version, Vec<String>)],
+    os: util::Os,
+    py_vers: &Version,
+) -> Option<(Vec<LockPackage>, Vec<(String, Version, Vec<String>)>)> {
+    let runtime = runtime_packs(lockpacks, &applicable(reqs, os, py_vers)).ok()?;
+    let mut lock = lockpacks.to_vec();
+    let mut as_installed = installed.to_vec();
+    let dev_only = lock
+        .iter_mut()
+        .filter(|lp| applies(lp, os, py_vers) && !runtime.iter().any(|r| r.id == lp.id));
+    for lp in dev_only {
+        if installed
+            .iter()
+            .any(|(n, _, _)| util::compare_names(n, &lp.name))
+        {
+            return None;
+        }
+        as_installed.push((
+            lp.name.clone(),
+            Version::from_str(&lp.version).ok()?,
+            vec![],
+        ));
+        // Their modules are recorded once they're installed.
+        lp.modules.get_or_insert_with(Vec::new);
+    }
+    Some((lock, as_installed))
+}
+
+/// Installed packages pyflow didn't install, eg dropped into `lib` by hand, or with
+/// `pip install --target`: those neither locked, nor protected from uninstalling. Without a
+/// previous lock, there's no record of what pyflow installed, so none are.
+fn find_foreign(
+    installed: &[(String, Version, Vec<String>)],
+    lockpacks: &[LockPackage],
+    dont_uninstall: &[String],
+) -> Vec<String> {
+    if lockpacks.is_empty() {
+        return vec![];
+    }
+    installed
+        .iter()
+        .map(|(name, _, _)| name)
+        .filter(|name| {
+            !dont_uninstall
+                .iter()
+                .chain(lockpacks.iter().map(|lp| &lp.name))
+                .any(|n| util::compare_names(n, name))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Whether to uninstall packages pyflow didn't install. Unless decided already, we ask if
+/// there's someone to ask, and keep them otherwise.
+fn confirm_prune(foreign: &[String], prune_foreign: Option<bool>) -> bool {
+    util::print_color(
+        &format!(
+            "These installed packages weren't installed by pyflow: {}",
+            foreign.join(", ")
+        ),
+        Color::Yellow,
+    );
+    let prune = prune_foreign.unwrap_or_else(|| {
+        prompts::interactive()
+            && prompts::confirm(
+                "Uninstall them?",
+                "Pass `--prune-foreign` to uninstall them.",
+            )
+    });
+    if !prune {
+        util::print_color(
+            "Keeping them. Remove them with `--prune-foreign`, or add them to `preserve` in \
+             `[tool.pyflow]` to keep them without this warning.",
+            Color::Yellow,
+        );
+    }
+    prune
+}
+
+/// Resolve `reqs`, keeping the versions already locked, and return the updated lock packages.
+/// Packages that aren't locked are picked with `resolution`.
+pub fn relock_with(
+    lockpacks: &[LockPackage],
+    reqs: &[Req],
+    dont_uninstall: &[String],
+    os: util::Os,
+    py_vers: &Version,
+    resolution: Resolution,
+) -> Vec<LockPackage> {
+    relock_using(
+        lockpacks,
+        reqs,
+        dont_uninstall,
+        os,
+        py_vers,
+        |reqs, locked| {
+            res::resolve(reqs, locked, os, py_vers, resolution)
+                .unwrap_or_else(|_| abort("Problem resolving dependencies"))
+        },
+    )
+}
+
+/// As `relock_with`, resolving with `resolve`.
+fn relock_using(
+    lockpacks: &[LockPackage],
+    reqs: &[Req],
+    dont_uninstall: &[String],
+    os: util::Os,
+    py_vers: &Version,
+    resolve: impl FnOnce(&[Req], &[Package]) -> Vec<Package>,
+) -> Vec<LockPackage> {
+    // We don't need to resolve reqs that are already locked. Packages locked for other
+    // Python versions or platforms aren't resolved here, but stay in the lock. Packages now
+    // required with extras their dependencies weren't resolved with are resolved again.
+    let required = required_extras(lockpacks, reqs, dont_uninstall);
+    let (here, elsewhere): (Vec<LockPackage>, Vec<LockPackage>) = lockpacks
+        .iter()
+        .zip(&required)
+        .filter(|(lp, extras)| extras_locked(lp, extras.as_deref()))
+        .map(|(lp, _)| lp.clone())
+        .partition(|lp| applies(lp, os, py_vers));
+    let locked = locked_packages(&here);
+
+    let mut resolved = resolve(reqs, &locked);
+    resolved.extend(locked_packages(&elsewhere));
+
+    // Now merge the existing lock packages with new ones from resolved packages. Packages
+    // locked elsewhere that are no longer required are dropped.
+    let lock_packs = merge_platforms(make_lock_packs(&resolved, lockpacks), &resolved);
+    let mut lock_packs = prune_lock(&lock_packs, reqs, dont_uninstall);
+
+    // Dependencies of extras that are no longer requested stay in the lock entry, so the
+    // extras stay recorded too.
+    let required = required_extras(&lock_packs, reqs, dont_uninstall);
+    for (lp, extras) in lock_packs.iter_mut().zip(required) {
+        let mut all = lp.extras.take().unwrap_or_default();
+        all.extend(extras.unwrap_or_default());
+        all.sort();
+        all.dedup();
+        lp.extras = (!all.is_empty()).then_some(all);
+    }
+    lock_packs
+}
+
+/// For each lock package, the extras it's required with, if it's required. Extras only pull in
+/// dependencies if they're requested, by `reqs` or a required package.
+fn required_extras(
+    lockpacks: &[LockPackage],
+    reqs: &[Req],
+    dont_uninstall: &[String],
+) -> Vec<Option<Vec<String>>> {
+    let graph = DepGraph::from_lock(lockpacks);
+    let mut starts = vec![];
+    for req in reqs {
+        for i in graph.named([req.name.as_str()]) {
+            starts.push((i, req.install_with_extras.clone().unwrap_or_default()));
+        }
+    }
+    for i in graph.named(dont_uninstall.iter().map(String::as_str)) {
+        starts.push((i, vec![]));
+    }
+    graph.required_extras(&starts)
+}
+
+/// Whether a lock package's dependencies were resolved with all of `extras`.
+fn extras_locked(lp: &LockPackage, extras: Option<&[String]>) -> bool {
+    let locked = lp.extras.as_deref().unwrap_or_default();
+    extras
+        .unwrap_or_default()
+        .iter()
+        .all(|e| locked.iter().any(|l| util::compare_names(l, e)))
+}
+
+/// The same version of a package may be resolved here and locked for other platforms, eg from
+/// entries in `pyproject.toml` with different `platform`s. Lock it once, with a marker that
+/// applies on any of them.
+fn merge_platforms(lock_packs: Vec<LockPackage>, resolved: &[Package]) -> Vec<LockPackage> {
+    let mut result: Vec<LockPackage> = vec![];
+    for mut lp in lock_packs {
+        if result
+            .iter()
+            .any(|r| util::compare_names(&r.name, &lp.name) && r.version == lp.version)
+        {
+            continue;
+        }
+        let version = Version::from_str(&lp.version).ok();
+        let markers: Vec<Option<&Marker>> = resolved
+            .iter()
+            .filter(|p| {
+                util::compare_names(&p.name, &lp.name) && Some(&p.version) == version.as_ref()
+            })
+            .map(|p| p.marker.as_ref())
+            .collect();
+        if markers.len() > 1 {
+            let mut combined: Vec<Marker> = vec![];
+            for m in markers.iter().flatten() {
+                let ms = match m {
+                    Marker::Or(ms) => ms.as_slice(),
+                    _ => std::slice::from_ref(*m),
+                };
+                for m in ms {
+                    if !combined.contains(m) {
+                        combined.push(m.clone());
+                    }
+                }
+            }
+            lp.marker = match (markers.contains(&None), combined.len()) {
+                (true, _) | (false, 0) => None,
+                (false, 1) => Some(combined[0].to_string()),
+                _ => Some(Marker::Or(combined).to_string()),
+            };
+        }
+        result.push(lp);
+    }
+    result
+}
+
+/// Record the modules each installed package provides, for lock packages that don't have them
+/// yet. Returns whether any were added.
+fn record_modules(lock_packs: &mut [LockPackage], lib_path: &Path) -> bool {
+    let installed = util::find_installed(lib_path);
+    let mut added = false;
+    for lp in lock_packs.iter_mut().filter(|lp| lp.modules.is_none()) {
+        let version = Version::from_str(&lp.version).ok();
+        if let Some((_, _, tops)) = installed
+            .iter()
+            .find(|(n, v, _)| util::compare_names(n, &lp.name) && Some(v) == version.as_ref())
+        {
+            lp.modules = Some(tops.clone());
+            added = true;
+        }
+    }
+    added
+}
+
+/// The modules a package installs, eg `PIL` for `Pillow`. From the lock if recorded, or
+/// its installed metadata.
+fn import_names(
+    lib_path: &Path,
+    lock_packs: &[LockPackage],
+    name: &str,
+    version: &Version,
+) -> Vec<String> {
+    let locked = lock_packs
+        .iter()
+        .find(|lp| {
+            util::compare_names(&lp.name, name)
+                && Version::from_str(&lp.version).ok().as_ref() == Some(version)
+        })
+        .and_then(|lp| lp.modules.clone());
+
+    locked.unwrap_or_else(|| installed_modules(lib_path, name, version))
+}
+
+fn installed_modules(lib_path: &Path, name: &str, version: &Version) -> Vec<String> {
+    util::find_installed(lib_path)
+        .into_iter()
+        .find(|(n, v, _)| util::compare_names(n, name) && v == version)
+        .map(|(_, _, tops)| tops)
+        .unwrap_or_else(|| vec![util::standardize_name(name)])
+}
+
+/// Packages may be installed that don't support this Python version, eg after switching
+/// versions. Offer to resolve them again, by leaving them out of the lock we resolve with.
+fn offer_re_resolve(
+    lockpacks: &[LockPackage],
+    lib_path: &Path,
+    py_vers: &Version,
+) -> Vec<LockPackage> {
+    let incompatible = requires_python::find_incompatible(lib_path, py_vers);
+    if incompatible.is_empty() {
+        return lockpacks.to_vec();
+    }
+
+    let listed: Vec<String> = incompatible
+        .iter()
+        .map(|i| {
+            format!(
+                "{} {} (requires Python {})",
+                i.name, i.version, i.requires_python
+            )
+        })
+        .collect();
+    util::print_color(
+        &format!(
+            "These installed packages don't support Python {}: {}",
+            py_vers,
+            listed.join(", ")
+        ),
+        Color::Yellow,
+    );
+    // Without a terminal, they're kept.
+    if !util::prompts::interactive()
+        || !util::prompts::confirm(
+            "Resolve them again for this Python version?",
+            "Run `pyflow install` in a terminal to resolve them again.",
+        )
+    {
+        return lockpacks.to_vec();
+    }
+    let names: Vec<String> = incompatible.into_iter().map(|i| i.name).collect();
+    without_packages(lockpacks, &names)
+}
+
+/// Remove packages from the lock, along with ones that depend on them, since those pin their
+/// versions.
+fn without_packages(lockpacks: &[LockPackage], names: &[String]) -> Vec<LockPackage> {
+    let mut names: Vec<String> = names.iter().map(|n| util::standardize_name(n)).collect();
+    let mut remaining = lockpacks.to_vec();
+    loop {
+        let (removed, kept): (Vec<LockPackage>, Vec<LockPackage>) =
+            remaining.into_iter().partition(|lp| {
+                names.contains(&util::standardize_name(&lp.name))
+                    || lp
+                        .dependencies
+                        .iter()
+                        .flatten()
+                        .any(|d| names.contains(&util::standardize_name(&LockDep::parse(d).name)))
+            });
+        remaining = kept;
+        let new_names: Vec<String> = removed
+            .iter()
+            .map(|lp| util::standardize_name(&lp.name))
+            .filter(|n| !names.contains(n))
+            .collect();
+        if new_names.is_empty() {
+            return remaining;
+        }
+        names.extend(new_names);
+    }
+}
+
+/// Older versions of pyflow could lock a package under several capitalizations, eg `Django`
+/// and `django`. Keep the newest, and warn. Renamed packages are left alone, since they're
+/// meant to be locked more than once. Returns whether any were removed.
+pub fn dedupe_lock_names(lockpacks: &mut Vec<LockPackage>) -> bool {
+    let mut by_name: HashMap<String, Vec<&LockPackage>> = HashMap::new();
+    for lp in lockpacks.iter().filter(|lp| lp.rename.is_none()) {
+        by_name
+            .entry(util::standardize_name(&lp.name))
+            .or_default()
+            .push(lp);
+    }
+
+    let mut removed = vec![];
+    for group in by_name.values() {
+        if group.iter().all(|lp| lp.name == group[0].name) {
+            continue;
+        }
+        let newest = group
+            .iter()
+            .max_by_key(|lp| (Version::from_str(&lp.version).ok(), lp.id))
+            .unwrap();
+        let dropped: Vec<&&LockPackage> =
+            group.iter().filter(|lp| lp.name != newest.name).collect();
+        let listed: Vec<String> = dropped
+            .iter()
+            .map(|lp| format!("{} {}", lp.name, lp.version))
+            .collect();
+        util::print_color(
+            &format!(
+                "`pyflow.lock` lists {} separately from {} {}; keeping {} {}",
+                listed.join(", "),
+                newest.name,
+                newest.version,
+                newest.name,
+                newest.version
+            ),
+            Color::Yellow,
+        );
+        removed.extend(
+            dropped
+                .into_iter()
+                .map(|lp| (lp.name.clone(), lp.version.clone())),
+        );
+    }
+
+    lockpacks.retain(|lp| !removed.contains(&(lp.name.clone(), lp.version.clone())));
+    !removed.is_empty()
+}
+
+/// Keep only lock packages reachable from `reqs` or `dont_uninstall` through locked
+/// dependencies, on any platform or Python version. Others are left over from removed reqs, or
+/// from extras no longer requested.
+pub fn prune_lock(
+    lockpacks: &[LockPackage],
+    reqs: &[Req],
+    dont_uninstall: &[String],
+) -> Vec<LockPackage> {
+    lockpacks
+        .iter()
+        .zip(required_extras(lockpacks, reqs, dont_uninstall))
+        .filter(|(_, k)| k.is_some())
+        .map(|(lp, _)| lp.clone())
+        .collect()
+}
+
+/// If the lock satisfies every req, and exactly the locked packages are installed, there's
+/// nothing to sync. Returns the number of packages installed in that case. This doesn't use
+/// the network.
+pub fn up_to_date(
+    lockpacks: &[LockPackage],
+    reqs: &[Req],
+    dont_uninstall: &[String],
+    installed: &[(String, Version, Vec<String>)],
+    os: util::Os,
+    py_vers: &Version,
+) -> Option<usize> {
+    // Packages locked by an older version of pyflow need their files and modules recorded.
+    if lockpacks
+        .iter()
+        .any(|lp| lp.files.is_none() || lp.modules.is_none())
+    {
+        return None;
+    }
+    // Stale packages need removing from the lock, and uninstalling.
+    if prune_lock(lockpacks, reqs, dont_uninstall).len() != lockpacks.len() {
+        return None;
+    }
+    // Packages required with extras need their dependencies locked.
+    let required = required_extras(lockpacks, reqs, dont_uninstall);
+    if lockpacks
+        .iter()
+        .zip(&required)
+        .any(|(lp, extras)| !extras_locked(lp, extras.as_deref()))
+        || !DepGraph::from_lock(lockpacks)
+            .missing_extra_deps(&required)
+            .is_empty()
+    {
+        return None;
+    }
+    // Wheels pinned with `wheel-tag` need recording, and unpinned ones forgetting.
+    if lockpacks.iter().any(|lp| !pin_recorded(lp, reqs)) {
+        return None;
+    }
+
+    let mut locked = vec![];
+    for lp in lockpacks.iter().filter(|lp| applies(lp, os, py_vers)) {
+        locked.push((&lp.name, Version::from_str(&lp.version).ok()?));
+    }
+
+    // Path and git reqs aren't locked; they're installed separately.
+    let satisfied = reqs
+        .iter()
+        .filter(|r| r.path.is_none() && r.git.is_none() && r.url.is_none())
+        .filter(|r| {
+            !dont_uninstall
+                .iter()
+                .any(|n| util::compare_names(n, &r.name))
+        })
+        .filter(|r| r.marker.as_ref().is_none_or(|m| m.evaluate(os, py_vers)))
+        .filter(|r| {
+            r.python_version
+                .as_ref()
+                .is_none_or(|cs| cs.iter().all(|c| c.is_compatible(py_vers)))
+        })
+        .all(|r| {
+            locked.iter().any(|(name, version)| {
+                util::compare_names(name, &r.name)
+                    && r.constraints.iter().all(|c| c.is_compatible(version))
+            })
+        });
+
+    // As in `sync_deps`, `typing` isn't installed.
+    let all_installed = locked
+        .iter()
+        .filter(|(name, _)| util::standardize_name(name) != "typing")
+        .all(|(name, version)| {
+            installed
+                .iter()
+                .any(|(n, v, _)| util::compare_names(n, name) && v == version)
+        });
+    let none_extra = installed.iter().all(|(n, v, _)| {
+        dont_uninstall.iter().any(|d| util::compare_names(d, n))
+            || locked
+                .iter()
+                .any(|(name, version)| util::compare_names(n, name) && v == version)
+    });
+
+    (satisfied && all_installed && none_extra).then_some(locked.len())
+}
+
+/// Whether a locked package is installed on this OS and Python version, from its marker.
+pub fn applies(lp: &LockPackage, os: util::Os, py_vers: &Version) -> bool {
+    lp.marker
+        .as_ref()
+        .and_then(|m| Marker::from_str(m).ok())
+        .is_none_or(|m| m.evaluate(os, py_vers))
+}
+
+/// How a package was locked across Python versions.
+#[derive(Debug, PartialEq)]
+pub enum PythonLock {
+    /// One version, for the Python versions listed.
+    Unified(Version, Vec<Version>),
+    /// Different versions, each for the Python versions listed.
+    Split(Vec<(Version, Vec<Version>)>),
+}
+
+/// Resolve once for each of `py_versions`, and lock the results together. Packages
+/// needed on some of them only get a `python_version` marker. Versions picked for earlier
+/// Python versions are preferred for later ones, so a package uses one version for all
+/// where it can. Versions are picked with the resolution the lock was made with.
+pub fn lock_pythons(
+    lockpacks: &[LockPackage],
+    reqs: &[Req],
+    os: util::Os,
+    py_versions: &[Version],
+    lock_path: &Path,
+) -> (Vec<LockPackage>, Vec<(String, PythonLock)>) {
+    let mut lock_packs = lockpacks.to_vec();
+    let mut resolutions: Vec<(Version, Vec<Package>)> = vec![];
+    let resolution = lock_header::resolution(lock_path);
+
+    for py_vers in py_versions {
+        let candidates: Vec<Package> = combine_resolutions(&resolutions)
+            .into_iter()
+            .map(|(p, _)| p)
+            .filter(|p| installable(&lock_packs, p, os, py_vers))
+            .collect();
+
+        let resolved =
+            res::resolve(reqs, &candidates, os, py_vers, resolution).unwrap_or_else(|_| {
+                abort(&format!(
+                    "Problem resolving dependencies for Python {}",
+                    py_vers
+                ))
+            });
+        resolutions.push((py_vers.clone(), resolved));
+
+        let merged = with_python_markers(combine_resolutions(&resolutions), py_versions);
+        lock_packs = make_lock_packs(&merged, &lock_packs);
+        record_files(&mut lock_packs);
+        pin_wheels(&mut lock_packs, reqs);
+    }
+
+    // The markers of existing lock entries may be from another set of Python versions.
+    let combined = combine_resolutions(&resolutions);
+    let report = python_report(&combined);
+    let merged = with_python_markers(combined, py_versions);
+    for lp in &mut lock_packs {
+        if let Some(p) = merged.iter().find(|p| {
+            util::compare_names(&p.name, &lp.name)
+                && Version::from_str(&lp.version).ok().as_ref() == Some(&p.version)
+        }) {
+            lp.marker = p.marker.as_ref().map(|m| m.to_string());
+        }
+    }
+
+    let mut lock = Lock {
+        metadata: HashMap::new(),
+        package: Some(lock_packs.clone()),
+    };
+    Header::new(
+        lock_path,
+        resolution,
+        py_versions,
+        &[lock_header::platform_name(os)],
+    )
+    .mark(&mut lock.metadata);
+    if util::write_lock(lock_path, &lock).is_err() {
+        abort("Problem writing lock file");
+    }
+    (lock_packs, report)
+}
+
+/// Whether a package has a release file for this Python version, from the files recorded
+/// in the lock. If we don't know, assume it does.
+fn installable(lock_packs: &[LockPackage], p: &Package, os: util::Os, py_vers: &Version) -> bool {
+    lock_packs
+        .iter()
+        .find(|lp| {
+            util::compare_names(&lp.name, &p.name)
+                && Version::from_str(&lp.version).ok().as_ref() == Some(&p.version)
+        })
+        .is_none_or(|lp| {
+            lp.files.as_ref().is_none_or(|files| {
+                let pinned = lp.wheel.as_deref();
+                util::find_locked_release(files, &p.name, &p.version, os, py_vers, pinned).is_ok()
+            })
+        })
+}
+
+/// The `python_version` marker for a set of Python versions, eg
+/// `python_version == '3.8.*' or python_version == '3.9.*'`.
+fn python_marker(py_versions: &[Version]) -> Marker {
+    let mut markers: Vec<Marker> = py_versions
+        .iter()
+        .map(|v| {
+            Marker::PythonVersion(Constraint::new(
+                ReqType::Exact,
+                Version::new_star(v.major, v.minor, None, true),
+            ))
+        })
+        .collect();
+    if markers.len() == 1 {
+        markers.remove(0)
+    } else {
+        Marker::Or(markers)
+    }
+}
+
+/// Combine the packages resolved for each Python version, with the Python versions each
+/// package and version was resolved for. Ids are made unique across resolutions.
+fn combine_resolutions(resolutions: &[(Version, Vec<Package>)]) -> Vec<(Package, Vec<Version>)> {
+    let key = |p: &Package| (util::standardize_name(&p.name), p.version.clone());
+
+    let mut found: Vec<(Package, Vec<Version>)> = vec![];
+    let mut ids: HashMap<(usize, u32), u32> = HashMap::new();
+    for (i, (py_vers, resolved)) in resolutions.iter().enumerate() {
+        for p in resolved {
+            match found.iter_mut().position(|(f, _)| key(f) == key(p)) {
+                Some(j) => {
+                    found[j].1.push(py_vers.clone());
+                    ids.insert((i, p.id), found[j].0.id);
+                }
+                None => {
+                    let id = found.len() as u32 + 1;
+                    ids.insert((i, p.id), id);
+                    found.push((Package { id, ..p.clone() }, vec![py_vers.clone()]));
+                }
+            }
+        }
+    }
+
+    // Point renames at the combined ids of their parents.
+    for (i, (_, resolved)) in resolutions.iter().enumerate() {
+        for p in resolved {
+            if let Rename::Yes(parent_id, _, name) = &p.rename {
+                let id = ids[&(i, p.id)];
+                if let Some((f, _)) = found.iter_mut().find(|(f, _)| f.id == id) {
+                    let parent = ids.get(&(i, *parent_id)).copied().unwrap_or(*parent_id);
+                    f.rename = Rename::Yes(parent, id, name.clone());
+                }
+            }
+        }
+    }
+    found
+}
+
+/// Whether each package has one version for all Python versions that need it.
+fn python_report(combined: &[(Package, Vec<Version>)]) -> Vec<(String, PythonLock)> {
+    let mut report: Vec<(String, PythonLock)> = vec![];
+    for (p, pys) in combined {
+        let name = util::standardize_name(&p.name);
+        match report.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, PythonLock::Split(versions))) => {
+                versions.push((p.version.clone(), pys.clone()))
+            }
+            Some((_, lock)) => {
+                if let PythonLock::Unified(v, v_pys) = lock {
+                    *lock = PythonLock::Split(vec![
+                        (v.clone(), v_pys.clone()),
+                        (p.version.clone(), pys.clone()),
+                    ]);
+                }
+            }
+            None => report.push((name, PythonLock::Unified(p.version.clone(), pys.clone()))),
+        }
+    }
+    report.sort_by(|a, b| a.0.cmp(&b.0));
+    report
+}
+
+/// Add a `python_version` marker to packages that aren't for all of `py_versions`.
+fn with_python_markers(
+    combined: Vec<(Package, Vec<Version>)>,
+    py_versions: &[Version],
+) -> Vec<Package> {
+    combined
+        .into_iter()
+        .map(|(p, pys)| {
+            let marker = if pys.len() == py_versions.len() {
+                p.marker.clone()
+            } else {
+                let py_marker = python_marker(&pys);
+                Some(match &p.marker {
+                    Some(m) => Marker::And(vec![m.clone(), py_marker]),
+                    None => py_marker,
+                })
+            };
+            Package { marker, ..p }
+        })
+        .collect()
+}
+
+/// Merge resolved packages with the existing lock. Packages are sorted by name, then version.
+/// Packages already locked keep their lock entry and id; new ones take the lowest free ids, in
+/// sorted order. This way, re-running with no changes produces an identical lock.
+fn make_lock_packs(resolved: &[Package], lockpacks: &[LockPackage]) -> Vec<LockPackage> {
+    let mut resolved: Vec<&Package> = resolved.iter().collect();
+    resolved.sort_by(|a, b| {
+        util::standardize_name(&a.name)
+            .cmp(&util::standardize_name(&b.name))
+            .then_with(|| a.version.cmp(&b.version))
+    });
+
+    let find_existing = |package: &Package| {
+        lockpacks.iter().find(|lp| {
+            util::compare_names(&lp.name, &package.name)
+                && Version::from_str(&lp.version).ok().as_ref() == Some(&package.version)
+        })
+    };
+
+    // Map the resolver's ids to ids in the lock.
+    let mut ids: HashMap<u32, u32> = HashMap::new();
+    for package in &resolved {
+        if let Some(existing) = find_existing(package) {
+            ids.insert(package.id, existing.id);
+        }
+    }
+    let mut next_id = 0;
+    for package in &resolved {
+        if !ids.contains_key(&package.id) {
+            next_id += 1;
+            while ids.values().any(|id| *id == next_id) {
+                next_id += 1;
+            }
+            ids.insert(package.id, next_id);
+        }
+    }
+
+    resolved
+        .into_iter()
+        .map(|package| {
+            let mut deps: Vec<String> = package
+                .deps
+                .iter()
+                .map(|(_, name, version, extras)| LockDep::format(name, version, extras))
+                .collect();
+
+            if let Some(existing) = find_existing(package) {
+                // Keep dependencies that weren't resolved this time, eg ones for extras that
+                // aren't requested, or for other platforms.
+                let mut existing = existing.clone();
+                if let Some(old) = existing.dependencies.take() {
+                    deps.extend(old.into_iter().filter(|d| {
+                        let name = LockDep::parse(d).name;
+                        !package
+                            .deps
+                            .iter()
+                            .any(|(_, n, _, _)| util::compare_names(n, &name))
+                    }));
+                }
+                deps.sort();
+                deps.dedup();
+                existing.dependencies = Some(deps);
+                return existing;
+            }
+            deps.sort();
+
+            LockPackage {
+                id: ids[&package.id],
+                name: package.name.clone(),
+                version: package.version.to_string(),
+                source: Some(format!(
+                    "pypi+{}",
+                    util::index::json_url(&package.name, &package.version.to_string())
+                )),
+                dependencies: Some(deps),
+                rename: match &package.rename {
+                    Rename::Yes(parent_id, _, name) => Some(format!(
+                        "{} {}",
+                        ids.get(parent_id).unwrap_or(parent_id),
+                        name
+                    )),
+                    Rename::No => None,
+                },
+                marker: package.marker.as_ref().map(|m| m.to_string()),
+                modules: None,
+                files: None, // Filled in by `record_files`.
+                extras: None,
+                wheel: None,
+            }
+        })
+        .collect()
+}
 
-use regex::Regex;
-use termcolor::Color;
+/// Record the release files available for lock packages that don't have them yet.
+pub fn record_files(lock_packs: &mut [LockPackage]) {
+    for lp in lock_packs.iter_mut().filter(|lp| lp.files.is_none()) {
+        let version = Version::from_str(&lp.version).expect("Problem parsing lock version");
+        let releases = match res::get_warehouse_release(&lp.name, &version) {
+            Ok(r) => r,
+            Err(_) => {
+                util::print_color(
+                    &format!(
+                        "Unable to record release files for {}; we'll pick one when installing.",
+                        lp.name
+                    ),
+                    Color::Yellow,
+                );
+                continue;
+            }
+        };
 
-use crate::{
-    dep_resolution::res,
-    dep_types::{Constraint, Lock, LockPackage, Package, Rename, Req, ReqType, Version},
-    install,
-    util::{self, abort},
-    PackToInstall,
-};
+        let mut files: Vec<LockFile> = releases
+            .into_iter()
+            .filter(|r| r.packagetype == "bdist_wheel" || r.packagetype == "sdist")
+            .map(|r| LockFile {
+                platform: util::platform_tag(&r.filename),
+                filename: r.filename,
+                url: r.url,
+                sha256: r.digests.sha256,
+                packagetype: r.packagetype,
+                python_version: r.python_version,
+                requires_python: r.requires_python,
+            })
+            .collect();
+        files.sort_by(|a, b| a.filename.cmp(&b.filename));
+        // Packages installed only from `find_links` files.
+        let local_dirs: Option<Vec<String>> = files
+            .iter()
+            .map(|f| util::find_links::source_dir(&f.url))
+            .collect();
+        if let Some(dir) = local_dirs.and_then(|d| d.into_iter().next()) {
+            lp.source = Some(dir);
+        }
+        lp.files = Some(files);
+    }
+}
 
-/// Function used by `Install` and `Uninstall` subcommands to syn dependencies with
-/// the config and lock files.
-#[allow(clippy::too_many_arguments)]
-pub fn sync(
-    paths: &util::Paths,
-    lockpacks: &[LockPackage],
-    reqs: &[Req],
-    dev_reqs: &[Req],
-    dont_uninstall: &[String],
-    os: util::Os,
-    py_vers: &Version,
-    lock_path: &Path,
-) {
-    let installed = util::find_installed(&paths.lib);
-    // We control the lock format, so this regex will always match
-    let dep_re = Regex::new(r"^(.*?)\s(.*)\s.*$").unwrap();
+/// The `wheel-tag` of the lock package's req, if any.
+fn pinned_tag<'a>(lp: &LockPackage, reqs: &'a [Req]) -> Option<&'a str> {
+    reqs.iter()
+        .find(|r| util::compare_names(&r.name, &lp.name))
+        .and_then(|r| r.wheel_tag.as_deref())
+}
+
+/// Whether the lock package's pinned wheel matches its req's `wheel-tag`, if any.
+fn pin_recorded(lp: &LockPackage, reqs: &[Req]) -> bool {
+    match (pinned_tag(lp, reqs), &lp.wheel) {
+        (Some(tag), Some(wheel)) => wheel.contains(tag),
+        (None, None) => true,
+        _ => false,
+    }
+}
 
-    // We don't need to resolve reqs that are already locked.
-    let locked: Vec<Package> = lockpacks
+/// Record the wheels pinned with `wheel-tag`, from each package's release files.
+pub fn pin_wheels(lock_packs: &mut [LockPackage], reqs: &[Req]) {
+    for lp in lock_packs.iter_mut() {
+        lp.wheel = match (pinned_tag(lp, reqs), &lp.files) {
+            (Some(tag), Some(files)) => {
+                let version = Version::from_str(&lp.version).expect("Problem parsing lock version");
+                let filenames = files.iter().map(|f| f.filename.as_str());
+                let filename = util::wheel_tag::find(filenames, &lp.name, &version, tag)
+                    .unwrap_or_else(|e| abort(&e));
+                Some(filename.to_owned())
+            }
+            _ => None,
+        };
+    }
+}
+
+/// Convert lock packages to the format used by the resolver.
+pub fn locked_packages(lockpacks: &[LockPackage]) -> Vec<Package> {
+    lockpacks
         .iter()
         .map(|lp| {
             let mut deps = vec![];
             for dep in lp.dependencies.as_ref().unwrap_or(&vec![]) {
-                let caps = dep_re
-                    .captures(dep)
-                    .expect("Problem reading lock file dependencies");
-                let name = caps.get(1).unwrap().as_str().to_owned();
-                let vers = Version::from_str(caps.get(2).unwrap().as_str())
-                    .expect("Problem parsing version from lock");
-                deps.push((999, name, vers)); // dummy id
+                let dep = LockDep::parse(dep);
+                let vers = dep.version.expect("Problem parsing version from lock");
+                deps.push((999, dep.name, vers, dep.extras)); // dummy id
             }
 
+            // Renamed packages keep their names, and their parents.
+            let (parent, rename) = match lp.rename.as_deref().map(parse_lockpack_rename) {
+                Some((parent_id, name)) => (parent_id, Rename::Yes(parent_id, lp.id, name)),
+                None => (0, Rename::No), // todo: Parents of other packages
+            };
+
             Package {
-                id: lp.id, // todo
-                parent: 0, // todo
+                id: lp.id,
+                parent,
                 name: lp.name.clone(),
                 version: Version::from_str(&lp.version).expect("Problem parsing lock version"),
                 deps,
-                rename: Rename::No, // todo
+                rename,
+                marker: lp.marker.as_ref().and_then(|m| Marker::from_str(m).ok()),
             }
         })
-        .collect();
-
-    // todo: Only show this when needed.
-    // todo: Temporarily? Removed.
-    // Powershell  doesn't like emojis
-    //    #[cfg(target_os = "windows")]
-    //    println!("Resolving dependencies...");
-    //    #[cfg(target_os = "linux")]
-    //    println!("🔍 Resolving dependencies...");
-    //    #[cfg(target_os = "macos")]
-    //    println!("🔍 Resolving dependencies...");
+        .collect()
+}
 
-    // Dev reqs and normal reqs are both installed here; we only commit dev reqs
-    // when packaging.
-    let mut combined_reqs = reqs.to_vec();
-    for dev_req in dev_reqs.to_vec() {
-        combined_reqs.push(dev_req);
-    }
+/// Packages listed by size in the summary after installing.
+const LARGEST_SHOWN: usize = 3;
 
-    let resolved = if let Ok(r) = res::resolve(&combined_reqs, &locked, os, py_vers) {
-        r
+/// The line shown after installing, from the bytes each package installed, eg
+/// `Installed 47 packages (312 MB) in 1m42s — largest: torch 210 MB, scipy 38 MB, pandas 21 MB`
+fn install_summary(sizes: &[(String, u64)], elapsed: Duration) -> String {
+    let total = sizes.iter().map(|(_, s)| s).sum();
+    let time = if elapsed.as_secs() < 60 {
+        format!("{:.1}s", elapsed.as_secs_f64())
     } else {
-        abort("Problem resolving dependencies")
+        format!("{}m{:02}s", elapsed.as_secs() / 60, elapsed.as_secs() % 60)
     };
-
-    // Now merge the existing lock packages with new ones from resolved packages.
-    // We have a collection of requirements; attempt to merge them with the already-locked ones.
-    let mut updated_lock_packs = vec![];
-
-    for package in &resolved {
-        let dummy_constraints = vec![Constraint::new(ReqType::Exact, package.version.clone())];
-        if already_locked(&locked, &package.name, &dummy_constraints) {
-            let existing: Vec<&LockPackage> = lockpacks
-                .iter()
-                .filter(|lp| util::compare_names(&lp.name, &package.name))
-                .collect();
-            let existing2 = existing[0];
-
-            updated_lock_packs.push(existing2.clone());
-            continue;
-        }
-
-        let deps = package
-            .deps
-            .iter()
-            .map(|(_, name, version)| {
-                format!(
-                    "{} {} pypi+https://pypi.org/pypi/{}/{}/json",
-                    name, version, name, version,
-                )
-            })
+    let mut result = format!(
+        "Installed {} package{} ({}) in {}",
+        sizes.len(),
+        if sizes.len() == 1 { "" } else { "s" },
+        util::format_size(total),
+        time
+    );
+    if sizes.len() > 1 {
+        let mut largest: Vec<&(String, u64)> = sizes.iter().collect();
+        largest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let listed: Vec<String> = largest
+            .into_iter()
+            .take(LARGEST_SHOWN)
+            .map(|(name, size)| format!("{} {}", name, util::format_size(*size)))
             .collect();
-
-        updated_lock_packs.push(LockPackage {
-            id: package.id,
-            name: package.name.clone(),
-            version: package.version.to_string(),
-            source: Some(format!(
-                "pypi+https://pypi.org/pypi/{}/{}/json",
-                package.name,
-                package.version.to_string()
-            )),
-            dependencies: Some(deps),
-            rename: match &package.rename {
-                Rename::Yes(parent_id, _, name) => Some(format!("{} {}", parent_id, name)),
-                Rename::No => None,
-            },
-        });
+        result.push_str(&format!(" — largest: {}", listed.join(", ")));
     }
+    result
+}
 
-    let updated_lock = Lock {
-        //        metadata: Some(lock_metadata),
-        metadata: HashMap::new(), // todo: Problem with toml conversion.
-        package: Some(updated_lock_packs.clone()),
-    };
-    if util::write_lock(lock_path, &updated_lock).is_err() {
-        abort("Problem writing lock file");
+/// Show what we're about to install and uninstall, before asking to go ahead.
+fn print_plan(to_install: &[&PackToInstall], to_uninstall: &[(String, Version)]) {
+    let to_install: Vec<String> = to_install
+        .iter()
+        .map(|((name, version), _)| format!("{} {}", name, version))
+        .collect();
+    let to_uninstall: Vec<String> = to_uninstall
+        .iter()
+        .map(|(name, version)| format!("{} {}", name, version))
+        .collect();
+    for (verb, packs) in [("install", to_install), ("uninstall", to_uninstall)] {
+        if !packs.is_empty() {
+            util::print_color(&format!("To {}: {}", verb, packs.join(", ")), Color::Cyan);
+        }
     }
-
-    // Now that we've confirmed or modified the lock file, we're ready to sync installed
-    // dependencies with it.
-    sync_deps(
-        paths,
-        &updated_lock_packs,
-        dont_uninstall,
-        &installed,
-        os,
-        py_vers,
-    );
 }
+
 /// Install/uninstall deps as required from the passed list, and re-write the lock file.
-fn sync_deps(
+pub fn sync_deps(
     paths: &util::Paths,
     lock_packs: &[LockPackage],
     dont_uninstall: &[String],
@@ -151,8 +1236,11 @@ fn sync_deps(
     os: util::Os,
     python_vers: &Version,
 ) {
+    let start = Instant::now();
+    // The lock may include packages for other platforms or Python versions; skip them.
     let packages: Vec<PackToInstall> = lock_packs
         .iter()
+        .filter(|lp| applies(lp, os, python_vers))
         .map(|lp| {
             (
                 (
@@ -164,71 +1252,107 @@ fn sync_deps(
         })
         .collect();
 
-    // todo shim. Use top-level A/R. We discard it temporarily while working other issues.
-    let installed: Vec<(String, Version)> = installed
-        .iter()
-        // Don't standardize name here; see note below in to_uninstall.
-        .map(|t| (t.0.clone(), t.1.clone()))
-        .collect();
+    // A renamed package is only installed if its module has the name it's locked with; a
+    // package that isn't is only installed under its own modules.
+    let installed_as = |(pack, rename): &PackToInstall, inst: &(String, Version, Vec<String>)| {
+        util::compare_names(&pack.0, &inst.0)
+            && pack.1 == inst.1
+            && match rename {
+                Some((_, new)) => inst.2.contains(&util::standardize_name(new)),
+                None => !inst.2.iter().any(|m| m.contains(RENAMED_INFIX)),
+            }
+    };
 
     // Filter by not-already-installed.
     let to_install: Vec<&PackToInstall> = packages
         .iter()
-        .filter(|(pack, _)| {
-            let mut contains = false;
-            for inst in &installed {
-                if util::compare_names(&pack.0, &inst.0) && pack.1 == inst.1 {
-                    contains = true;
-                    break;
-                }
-            }
-
+        .filter(|pack| {
+            let contains = installed.iter().any(|inst| installed_as(pack, inst));
             // The typing module is sometimes downloaded, causing a conflict/improper
             // behavior compared to the built in module.
-            !contains && pack.0 != "typing"
+            !contains && pack.0 .0 != "typing"
         })
         .collect();
 
-    // todo: Once you include rename info in installed, you won't need to use the map logic here.
-    let packages_only: Vec<&(String, Version)> = packages.iter().map(|(p, _)| p).collect();
-    let to_uninstall: Vec<&(String, Version)> = installed
+    let to_uninstall: Vec<(String, Version)> = installed
         .iter()
         .filter(|inst| {
             // Don't standardize the name here; we need original capitalization to uninstall
             // metadata etc.
-            let inst = (inst.0.clone(), inst.1.clone());
-            let mut contains = false;
-            // We can't just use the contains method, due to needing compare_names().
-            for pack in &packages_only {
-                if util::compare_names(&pack.0, &inst.0) && pack.1 == inst.1 {
-                    contains = true;
-                    break;
-                }
-            }
+            let contains = packages.iter().any(|pack| installed_as(pack, inst))
+                || dont_uninstall
+                    .iter()
+                    .any(|name| util::compare_names(name, &inst.0));
+            !contains
+        })
+        .map(|(name, version, _)| (name.clone(), version.clone()))
+        .collect();
+
+    // Pick all releases before changing anything, so we don't stop partway if one is missing.
+    let releases: Vec<(WarehouseRelease, PackageType)> = to_install
+        .iter()
+        .map(|((name, version), _)| {
+            let locked = lock_packs.iter().find(|lp| {
+                util::compare_names(&lp.name, name)
+                    && Version::from_str(&lp.version).ok().as_ref() == Some(version)
+            });
+            let pinned = locked.and_then(|lp| lp.wheel.as_deref());
 
-            for name in dont_uninstall {
-                if util::compare_names(name, &inst.0) {
-                    contains = true;
-                    break;
+            match locked.and_then(|lp| lp.files.as_ref()) {
+                Some(files) => {
+                    util::find_locked_release(files, name, version, os, python_vers, pinned)
+                        .unwrap_or_else(|e| abort(&e))
+                }
+                None => {
+                    let data = res::get_warehouse_release(name, version)
+                        .unwrap_or_else(|e| abort(&e.to_string()));
+                    let choice =
+                        util::find_best_release(&data, name, version, os, python_vers, pinned);
+                    util::record_release_choice(&paths.cache, name, version, &choice);
+                    (choice.release, choice.package_type)
                 }
             }
+        })
+        .collect();
 
-            !contains
+    // Download in the background, while we ask to go ahead, then uninstall and install the
+    // packages ahead.
+    let items = releases
+        .iter()
+        .map(|(release, _)| util::prefetch::Item {
+            url: release.url.clone(),
+            filename: release.filename.clone(),
+            sha256: release.digests.sha256.clone(),
         })
         .collect();
+    let changes = !to_install.is_empty() || !to_uninstall.is_empty();
+    let prefetch = if changes && !ASSUME_YES.load(Ordering::SeqCst) && prompts::interactive() {
+        print_plan(&to_install, &to_uninstall);
+        util::prefetch::Prefetch::start_asking(items, &paths.cache, || {
+            prompts::confirm("Continue?", "Pass `--yes` to install without being asked.")
+        })
+        .unwrap_or_else(|| {
+            abort("Not installing. The packages downloaded so far are kept for next time.")
+        })
+    } else {
+        util::prefetch::Prefetch::start(items, &paths.cache)
+    };
 
     for (name, version) in &to_uninstall {
         // todo: Deal with renamed. Currently won't work correctly with them.
-        install::uninstall(name, version, &paths.lib)
+        install::uninstall(name, version, paths)
     }
 
-    for ((name, version), rename) in &to_install {
-        let data =
-            res::get_warehouse_release(name, version).expect("Problem getting warehouse data");
-
-        let (best_release, package_type) =
-            util::find_best_release(&data, name, version, os, python_vers);
+    // Download sizes of each package, and those after it, for if we run out of space.
+    let mut needed: Vec<Option<u64>> = releases.iter().map(|(r, _)| r.size).collect();
+    for i in (0..needed.len().saturating_sub(1)).rev() {
+        needed[i] = needed[i].zip(needed[i + 1]).map(|(a, b)| a + b);
+    }
 
+    let mut sizes = vec![];
+    for (i, (((name, version), rename), (best_release, package_type))) in
+        to_install.iter().zip(releases).enumerate()
+    {
         // Powershell  doesn't like emojis
         // todo format literal issues, so repeating this whole statement.
         #[cfg(target_os = "windows")]
@@ -239,7 +1363,7 @@ fn sync_deps(
         util::print_color_(&format!("⬇ Installing {}", &name), Color::Cyan);
         println!(" {} ...", &version.to_string_color());
 
-        if install::download_and_install_package(
+        match install::download_and_install_package(
             name,
             version,
             &best_release.url,
@@ -248,58 +1372,57 @@ fn sync_deps(
             paths,
             package_type,
             rename,
-        )
-        .is_err()
-        {
-            abort("Problem downloading packages");
+        ) {
+            Ok(size) => sizes.push((name.clone(), size)),
+            Err(install::InstallError::Download(e)) => abort(&format!(
+                "Problem downloading packages. {}",
+                util::http::describe(&e)
+            )),
+            // What this package extracted so far has been removed.
+            Err(install::InstallError::Fs(e)) => abort(&util::fs_errors::describe(&e, needed[i])),
         }
     }
+    prefetch.wait();
     // Perform renames after all packages are installed, or we may attempt to rename a package
     // we haven't yet installed.
     for ((name, version), rename) in &to_install {
         if let Some((id, new)) = rename {
-            // Rename in the renamed package
+            // Rename in the renamed package. Its metadata still lists the original modules,
+            // which may not match its name.
+            let old_module = installed_modules(&paths.lib, name, version).remove(0);
+            let new_module = util::standardize_name(new);
+            let renamed_path = install::module_path(&paths.lib, &new_module);
 
-            let renamed_path = &paths.lib.join(util::standardize_name(new));
-
-            util::wait_for_dirs(&[renamed_path.clone()]).expect("Problem creating renamed path");
-            install::rename_package_files(renamed_path, name, new);
+            util::wait_for_dirs(std::slice::from_ref(&renamed_path))
+                .expect("Problem creating renamed path");
+            install::rename_package_files(&renamed_path, &old_module, &new_module);
 
             // Rename in the parent calling the renamed package. // todo: Multiple parents?
             let parent = lock_packs
                 .iter()
                 .find(|lp| lp.id == *id)
                 .expect("Can't find parent calling renamed package");
-            install::rename_package_files(
-                &paths.lib.join(util::standardize_name(&parent.name)),
-                name,
-                new,
-            );
+            let parent_version =
+                Version::from_str(&parent.version).expect("Problem parsing lock version");
+            for module in import_names(&paths.lib, lock_packs, &parent.name, &parent_version) {
+                let parent_path = install::module_path(&paths.lib, &module);
+                if parent_path.exists() {
+                    install::rename_package_files(&parent_path, &old_module, &new_module);
+                }
+            }
 
-            // todo: Handle this more generally, in case we don't have proper semver dist-info paths.
             install::rename_metadata(
-                &paths
-                    .lib
-                    .join(&format!("{}-{}.dist-info", name, version.to_string())),
+                &install::find_dist_info_path(name, version, &paths.lib),
                 name,
-                new,
+                &new_module,
             );
+            util::installed_cache::update(&paths.lib, name, version);
         }
     }
-}
 
-fn already_locked(locked: &[Package], name: &str, constraints: &[Constraint]) -> bool {
-    let mut result = true;
-    for constr in constraints.iter() {
-        if !locked
-            .iter()
-            .any(|p| util::compare_names(&p.name, name) && constr.is_compatible(&p.version))
-        {
-            result = false;
-            break;
-        }
+    if !sizes.is_empty() && !CliConfig::current().quiet {
+        util::print_color(&install_summary(&sizes, start.elapsed()), Color::Green);
     }
-    result
 }
 
 fn parse_lockpack_rename(rename: &str) -> (u32, String) {
@@ -313,3 +1436,923 @@ fn parse_lockpack_rename(rename: &str) -> (u32, String) {
 
     (id, name)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dep_resolution, dep_types::DepExtras};
+
+    fn package(id: u32, name: &str, version: Version, deps: &[(u32, &str, Version)]) -> Package {
+        Package {
+            id,
+            parent: 0,
+            name: name.to_owned(),
+            version,
+            deps: deps
+                .iter()
+                .map(|(id, n, v)| (*id, n.to_string(), v.clone(), DepExtras::default()))
+                .collect(),
+            rename: Rename::No,
+            marker: None,
+        }
+    }
+
+    fn lock_pack(id: u32, name: &str, version: &str, deps: &[&str]) -> LockPackage {
+        LockPackage {
+            id,
+            name: name.to_owned(),
+            version: version.to_owned(),
+            source: None,
+            dependencies: Some(
+                deps.iter()
+                    .map(|d| {
+                        format!(
+                            "{} pypi+https://pypi.org/pypi/{}/json",
+                            d,
+                            d.replace(' ', "/")
+                        )
+                    })
+                    .collect(),
+            ),
+            rename: None,
+            marker: None,
+            modules: None,
+            files: None,
+            extras: None,
+            wheel: None,
+        }
+    }
+
+    /// Two resolutions of the same graph, with ids and order that differ as they would
+    /// between runs.
+    fn resolutions() -> (Vec<Package>, Vec<Package>) {
+        let (v1, v2, v3) = (
+            Version::new(2, 31, 0),
+            Version::new(3, 4, 0),
+            Version::new(1, 26, 18),
+        );
+        let first = vec![
+            package(
+                1,
+                "requests",
+                v1.clone(),
+                &[(2, "idna", v2.clone()), (3, "urllib3", v3.clone())],
+            ),
+            package(2, "idna", v2.clone(), &[]),
+            package(3, "urllib3", v3.clone(), &[]),
+        ];
+        let second = vec![
+            package(9, "urllib3", v3.clone(), &[]),
+            package(
+                7,
+                "requests",
+                v1,
+                &[(9, "urllib3", v3), (8, "idna", v2.clone())],
+            ),
+            package(8, "idna", v2, &[]),
+        ];
+        (first, second)
+    }
+
+    fn serialize(packs: Vec<LockPackage>) -> String {
+        toml::to_string(&Lock {
+            package: Some(packs),
+            metadata: HashMap::new(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn lock_output_is_deterministic() {
+        let reqs = [
+            Req::new("requests".into(), vec![]),
+            Req::new("httpx".into(), vec![]),
+        ];
+        let index: &[(&str, &str, &[&str])] = &[
+            (
+                "requests",
+                "2.31.0",
+                &["idna (>=2.5)", "urllib3 (>=1.21.1)", "certifi"],
+            ),
+            ("httpx", "0.27.0", &["idna", "certifi", "sniffio"]),
+            ("idna", "3.4.0", &[]),
+            ("urllib3", "1.26.18", &[]),
+            ("certifi", "2024.2.2", &[]),
+            ("sniffio", "1.3.1", &[]),
+        ];
+        let resolve_lock = |lockpacks: &[LockPackage]| {
+            serialize(relock_using(
+                lockpacks,
+                &reqs,
+                &[],
+                util::Os::Linux,
+                &Version::new(3, 9, 0),
+                |reqs, locked| dep_resolution::tests::resolve_from(reqs, locked, index),
+            ))
+        };
+
+        // The resolver's maps iterate in a different order each time.
+        let lock1 = resolve_lock(&[]);
+        for _ in 0..5 {
+            assert_eq!(resolve_lock(&[]), lock1);
+        }
+
+        // Re-resolving against the lock we just wrote doesn't change it.
+        let lock: Lock = toml::from_str(&lock1).unwrap();
+        assert_eq!(resolve_lock(&lock.package.unwrap()), lock1);
+    }
+
+    #[test]
+    fn existing_ids_are_kept() {
+        let (first, _) = resolutions();
+        let mut packs = make_lock_packs(&first, &[]);
+        let names: Vec<&str> = packs.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["idna", "requests", "urllib3"]);
+
+        // An existing package with a high id keeps it; new ones fill in around it.
+        packs.retain(|p| p.name == "urllib3");
+        packs[0].id = 5;
+        let updated = make_lock_packs(&first, &packs);
+        let ids: Vec<u32> = updated.iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![1, 2, 5]);
+    }
+
+    #[test]
+    fn platforms_locked_once() {
+        let marker = |m: &str| Some(Marker::from_str(m).unwrap());
+        let torch = Version::new(2, 1, 0);
+        // Resolved on macOS, with the entry already locked on Linux.
+        let resolved = vec![
+            Package {
+                marker: marker("sys_platform == 'darwin'"),
+                ..package(1, "torch", torch.clone(), &[])
+            },
+            Package {
+                marker: marker("sys_platform == 'linux'"),
+                ..package(2, "torch", torch.clone(), &[])
+            },
+            package(3, "six", Version::new(1, 16, 0), &[]),
+        ];
+        let lock_packs = merge_platforms(make_lock_packs(&resolved, &[]), &resolved);
+
+        assert_eq!(lock_packs.len(), 2);
+        let torch_lock = lock_packs.iter().find(|lp| lp.name == "torch").unwrap();
+        assert_eq!(
+            torch_lock.marker.as_deref(),
+            Some("sys_platform == 'darwin' or sys_platform == 'linux'")
+        );
+        for os in [util::Os::Linux, util::Os::Mac] {
+            assert!(applies(torch_lock, os, &Version::new(3, 11, 0)));
+        }
+        assert!(!applies(
+            torch_lock,
+            util::Os::Windows,
+            &Version::new(3, 11, 0)
+        ));
+    }
+
+    #[test]
+    fn modules_recorded_in_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let dist_info = dir.join("python_dateutil-2.8.2.dist-info");
+        std::fs::create_dir_all(&dist_info).unwrap();
+        std::fs::write(dist_info.join("top_level.txt"), "dateutil\n").unwrap();
+
+        let (first, _) = resolutions();
+        let mut packs = make_lock_packs(&first, &[]);
+        packs[0].name = "python-dateutil".into();
+        packs[0].version = "2.8.2".into();
+        assert!(record_modules(&mut packs, &dir));
+        assert_eq!(packs[0].modules, Some(vec!["dateutil".to_owned()]));
+        assert_eq!(packs[1].modules, None);
+        assert_eq!(
+            import_names(&dir, &packs, "python-dateutil", &Version::new(2, 8, 2)),
+            vec!["dateutil"]
+        );
+
+        // Already recorded, so nothing changes.
+        assert!(!record_modules(&mut packs, &dir));
+    }
+
+    #[test]
+    fn resolutions_merged_across_pythons() {
+        let (py38, py312) = (Version::new_short(3, 8), Version::new_short(3, 12));
+        let (numpy_old, numpy_new, importlib) = (
+            Version::new(1, 24, 4),
+            Version::new(1, 26, 4),
+            Version::new(6, 8, 0),
+        );
+        let six = Version::new(1, 16, 0);
+        let resolutions = vec![
+            (
+                py38.clone(),
+                vec![
+                    package(1, "numpy", numpy_old.clone(), &[]),
+                    package(2, "six", six.clone(), &[]),
+                    package(3, "importlib-metadata", importlib.clone(), &[]),
+                ],
+            ),
+            (
+                py312.clone(),
+                vec![
+                    package(1, "six", six.clone(), &[]),
+                    package(2, "numpy", numpy_new.clone(), &[]),
+                ],
+            ),
+        ];
+        let pys = [py38.clone(), py312.clone()];
+        let combined = combine_resolutions(&resolutions);
+
+        assert_eq!(
+            python_report(&combined),
+            vec![
+                (
+                    "importlib_metadata".to_owned(),
+                    PythonLock::Unified(importlib, vec![py38.clone()])
+                ),
+                (
+                    "numpy".to_owned(),
+                    PythonLock::Split(vec![
+                        (numpy_old, vec![py38.clone()]),
+                        (numpy_new, vec![py312.clone()])
+                    ])
+                ),
+                ("six".to_owned(), PythonLock::Unified(six, pys.to_vec())),
+            ]
+        );
+
+        let merged = with_python_markers(combined, &pys);
+        let markers: Vec<(&str, Option<String>)> = merged
+            .iter()
+            .map(|p| (p.name.as_str(), p.marker.as_ref().map(|m| m.to_string())))
+            .collect();
+        assert_eq!(
+            markers,
+            vec![
+                ("numpy", Some("python_version == '3.8.*'".to_owned())),
+                ("six", None),
+                (
+                    "importlib-metadata",
+                    Some("python_version == '3.8.*'".to_owned())
+                ),
+                ("numpy", Some("python_version == '3.12.*'".to_owned())),
+            ]
+        );
+        let ids: Vec<u32> = merged.iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+
+        // Markers are evaluated against the full version of the interpreter.
+        let os = util::Os::Linux;
+        let marker = Marker::from_str("python_version == '3.8.*'").unwrap();
+        assert!(marker.evaluate(os, &Version::new(3, 8, 10)));
+        assert!(!marker.evaluate(os, &Version::new(3, 12, 1)));
+        assert!(python_marker(&pys).evaluate(os, &Version::new(3, 12, 1)));
+    }
+
+    #[test]
+    fn sync_skips_when_up_to_date() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let paths = util::Paths {
+            bin: dir.join(".venv/bin"),
+            lib: dir.join("lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        };
+        let dist_info = paths.lib.join("six-1.16.0.dist-info");
+        std::fs::create_dir_all(&dist_info).unwrap();
+        std::fs::write(dist_info.join("top_level.txt"), "six\n").unwrap();
+        std::fs::create_dir_all(paths.lib.join("mylib-0.1.0.dist-info")).unwrap();
+
+        let (first, _) = resolutions();
+        let mut six = make_lock_packs(&first, &[]).remove(0);
+        six.name = "six".into();
+        six.version = "1.16.0".into();
+        six.dependencies = Some(vec![]);
+        six.files = Some(vec![]);
+        six.modules = Some(vec!["six".into()]);
+        let lockpacks = vec![six];
+        let lock_path = dir.join("pyflow.lock");
+        let lock = Lock {
+            metadata: HashMap::new(),
+            package: Some(lockpacks.clone()),
+        };
+        util::write_lock(&lock_path, &lock).unwrap();
+        let written = std::fs::read_to_string(&lock_path).unwrap();
+        let modified = std::fs::metadata(&lock_path).unwrap().modified().unwrap();
+
+        let reqs = vec![Req::from_str("six = \">=1.0\"", false).unwrap()];
+        let dont_uninstall = vec!["mylib".to_owned()];
+        let (os, py_vers) = (util::Os::Linux, Version::new(3, 11, 7));
+        let installed = util::find_installed(&paths.lib);
+        assert_eq!(
+            up_to_date(&lockpacks, &reqs, &dont_uninstall, &installed, os, &py_vers),
+            Some(1)
+        );
+        // A new req, or a missing package, needs a sync.
+        let new_reqs = [reqs.clone(), vec![Req::from_str("toml", false).unwrap()]].concat();
+        assert_eq!(
+            up_to_date(
+                &lockpacks,
+                &new_reqs,
+                &dont_uninstall,
+                &installed,
+                os,
+                &py_vers
+            ),
+            None
+        );
+        assert_eq!(
+            up_to_date(&lockpacks, &reqs, &dont_uninstall, &[], os, &py_vers),
+            None
+        );
+
+        for _ in 0..2 {
+            sync(
+                &paths,
+                &lockpacks,
+                &reqs,
+                &[],
+                &dont_uninstall,
+                os,
+                &py_vers,
+                &lock_path,
+                false,
+            );
+        }
+        assert_eq!(std::fs::read_to_string(&lock_path).unwrap(), written);
+        assert_eq!(
+            std::fs::metadata(&lock_path).unwrap().modified().unwrap(),
+            modified
+        );
+    }
+
+    #[test]
+    fn uninstalled_dependencies_pruned() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let paths = util::Paths {
+            bin: dir.join(".venv/bin"),
+            lib: dir.join("lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        };
+        let (first, _) = resolutions();
+        let mut lockpacks = vec![];
+        // `flask` pulls in `werkzeug`; both were installed before `flask` was removed.
+        for (name, version, deps) in [
+            ("six", "1.16.0", vec![]),
+            (
+                "flask",
+                "3.0.0",
+                vec!["werkzeug 3.0.1 pypi+https://pypi.org/pypi/werkzeug/3.0.1/json"],
+            ),
+            ("werkzeug", "3.0.1", vec![]),
+        ] {
+            let dist_info = paths.lib.join(format!("{}-{}.dist-info", name, version));
+            std::fs::create_dir_all(&dist_info).unwrap();
+            std::fs::write(dist_info.join("top_level.txt"), format!("{}\n", name)).unwrap();
+            std::fs::create_dir_all(paths.lib.join(name)).unwrap();
+
+            let mut lp = make_lock_packs(&first, &[]).remove(0);
+            lp.id = lockpacks.len() as u32 + 1;
+            lp.name = name.into();
+            lp.version = version.into();
+            lp.dependencies = Some(deps.into_iter().map(String::from).collect());
+            lp.files = Some(vec![]);
+            lp.modules = Some(vec![name.into()]);
+            lockpacks.push(lp);
+        }
+        let lock_path = dir.join("pyflow.lock");
+        let reqs = vec![Req::from_str("six = \">=1.0\"", false).unwrap()];
+        let (os, py_vers) = (util::Os::Linux, Version::new(3, 11, 7));
+
+        let names = |packs: &[LockPackage]| -> Vec<String> {
+            packs.iter().map(|lp| lp.name.clone()).collect()
+        };
+        let with_flask = [reqs.clone(), vec![Req::from_str("flask", false).unwrap()]].concat();
+        assert_eq!(
+            names(&prune_lock(&lockpacks, &with_flask, &[])),
+            names(&lockpacks)
+        );
+        assert_eq!(names(&prune_lock(&lockpacks, &reqs, &[])), vec!["six"]);
+        let installed = util::find_installed(&paths.lib);
+        assert_eq!(
+            up_to_date(&lockpacks, &reqs, &[], &installed, os, &py_vers),
+            None
+        );
+
+        sync(
+            &paths,
+            &lockpacks,
+            &reqs,
+            &[],
+            &[],
+            os,
+            &py_vers,
+            &lock_path,
+            false,
+        );
+        let lock = util::read_lock(&lock_path).unwrap();
+        assert_eq!(names(&lock.package.unwrap()), vec!["six"]);
+        let installed: Vec<String> = util::find_installed(&paths.lib)
+            .into_iter()
+            .map(|(n, _, _)| n)
+            .collect();
+        assert_eq!(installed, vec!["six"]);
+        assert!(!paths.lib.join("werkzeug").exists());
+    }
+
+    #[test]
+    fn foreign_packages_kept_unless_pruned() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let paths = util::Paths {
+            bin: dir.join(".venv/bin"),
+            lib: dir.join("lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        };
+        for (name, version) in [("six", "1.16.0"), ("patched", "0.1.0"), ("vendored", "2.0")] {
+            let dist_info = paths.lib.join(format!("{}-{}.dist-info", name, version));
+            std::fs::create_dir_all(&dist_info).unwrap();
+            std::fs::write(dist_info.join("top_level.txt"), format!("{}\n", name)).unwrap();
+            std::fs::create_dir_all(paths.lib.join(name)).unwrap();
+        }
+
+        let (first, _) = resolutions();
+        let mut six = make_lock_packs(&first, &[]).remove(0);
+        six.name = "six".into();
+        six.version = "1.16.0".into();
+        six.dependencies = Some(vec![]);
+        six.files = Some(vec![]);
+        six.modules = Some(vec!["six".into()]);
+        let lockpacks = vec![six];
+        let lock_path = dir.join("pyflow.lock");
+        let reqs = vec![Req::from_str("six = \">=1.0\"", false).unwrap()];
+        let (os, py_vers) = (util::Os::Linux, Version::new(3, 11, 7));
+        let installed = || -> Vec<String> {
+            let mut names: Vec<String> = util::find_installed(&paths.lib)
+                .into_iter()
+                .map(|(n, _, _)| n)
+                .collect();
+            names.sort();
+            names
+        };
+
+        let preserve = vec!["vendored".to_owned()];
+        assert_eq!(
+            find_foreign(&util::find_installed(&paths.lib), &lockpacks, &preserve),
+            vec!["patched"]
+        );
+        // Without a lock, there's no telling what pyflow installed.
+        assert!(find_foreign(&util::find_installed(&paths.lib), &[], &preserve).is_empty());
+        let sync_ = |prune| {
+            sync_with(
+                &paths,
+                &lockpacks,
+                &reqs,
+                &[],
+                &preserve,
+                os,
+                &py_vers,
+                &lock_path,
+                Some(prune),
+                |reqs, locked, resolution| {
+                    res::resolve(reqs, locked, os, &py_vers, resolution).unwrap()
+                },
+            )
+        };
+        sync_(false);
+        assert_eq!(installed(), vec!["patched", "six", "vendored"]);
+        sync_(true);
+        assert_eq!(installed(), vec!["six", "vendored"]);
+        assert!(!paths.lib.join("patched").exists());
+    }
+
+    #[test]
+    fn lowest_lock_keeps_its_resolution() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let paths = util::Paths {
+            bin: dir.join(".venv/bin"),
+            lib: dir.join("lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        };
+        for (name, version) in [("six", "1.0.0"), ("idna", "2.5.0")] {
+            let dist_info = paths.lib.join(format!("{}-{}.dist-info", name, version));
+            std::fs::create_dir_all(&dist_info).unwrap();
+            std::fs::write(dist_info.join("top_level.txt"), format!("{}\n", name)).unwrap();
+            std::fs::create_dir_all(paths.lib.join(name)).unwrap();
+        }
+        let (os, py_vers) = (util::Os::Linux, Version::new(3, 11, 7));
+
+        // Locked with `pyflow lock --resolution lowest`.
+        let mut six = make_lock_packs(&[package(1, "six", Version::new(1, 0, 0), &[])], &[]);
+        six[0].files = Some(vec![]);
+        let lock_path = dir.join("pyflow.lock");
+        let mut lock = Lock {
+            metadata: HashMap::new(),
+            package: Some(six.clone()),
+        };
+        Header::new(
+            &lock_path,
+            Resolution::Lowest,
+            std::slice::from_ref(&py_vers),
+            &[lock_header::platform_name(os)],
+        )
+        .mark(&mut lock.metadata);
+        util::write_lock(&lock_path, &lock).unwrap();
+
+        // Then `pyflow add idna`.
+        let reqs = vec![
+            Req::from_str("six = \">=1.0\"", false).unwrap(),
+            Req::from_str("idna = \">=2.5\"", false).unwrap(),
+        ];
+        sync_with(
+            &paths,
+            &six,
+            &reqs,
+            &[],
+            &[],
+            os,
+            &py_vers,
+            &lock_path,
+            Some(false),
+            |_, locked, resolution| {
+                assert_eq!(resolution, Resolution::Lowest);
+                let mut resolved = locked.to_vec();
+                resolved.push(package(2, "idna", Version::new(2, 5, 0), &[]));
+                resolved
+            },
+        );
+
+        let lock = util::read_lock(&lock_path).unwrap();
+        assert_eq!(lock.package.unwrap().len(), 2);
+        let header = Header::from_lock(&lock.metadata);
+        assert_eq!(header.resolution, Resolution::Lowest);
+        assert!(header
+            .warnings(os, &py_vers)
+            .iter()
+            .any(|w| w.contains("--resolution lowest")));
+    }
+
+    #[test]
+    fn renamed_packages_stable() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let paths = util::Paths {
+            bin: dir.join(".venv/bin"),
+            lib: dir.join("lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        };
+        // `oldlib` pins `six` below the version we require, so its copy is renamed.
+        let renamed = dep_resolution::renamed_name("six", Some(("oldlib", &Version::new(1, 0, 0))));
+        assert_eq!(renamed, "six_renamed_oldlib_1_0_0");
+        let (first, _) = resolutions();
+        let mut lockpacks = vec![];
+        for (name, version, module, deps, rename) in [
+            (
+                "oldlib",
+                "1.0.0",
+                "oldlib",
+                vec!["six 1.10.0 pypi+https://pypi.org/pypi/six/1.10.0/json"],
+                None,
+            ),
+            (
+                "six",
+                "1.10.0",
+                renamed.as_str(),
+                vec![],
+                Some(format!("1 {}", renamed)),
+            ),
+            ("six", "1.16.0", "six", vec![], None),
+        ] {
+            let dist_info = paths.lib.join(format!("{}-{}.dist-info", name, version));
+            std::fs::create_dir_all(&dist_info).unwrap();
+            std::fs::write(dist_info.join("top_level.txt"), module).unwrap();
+            std::fs::create_dir_all(paths.lib.join(module)).unwrap();
+
+            let mut lp = make_lock_packs(&first, &[]).remove(0);
+            lp.id = lockpacks.len() as u32 + 1;
+            lp.name = name.into();
+            lp.version = version.into();
+            lp.dependencies = Some(deps.into_iter().map(String::from).collect());
+            lp.rename = rename;
+            lp.files = Some(vec![]);
+            // Not recorded yet, so the first sync resolves with the lock.
+            lp.modules = None;
+            lockpacks.push(lp);
+        }
+        let locked = locked_packages(&lockpacks);
+        assert_eq!(locked[1].rename, Rename::Yes(1, 2, renamed.clone()));
+
+        let lock_path = dir.join("pyflow.lock");
+        let reqs = vec![
+            Req::from_str("six = \">=1.16\"", false).unwrap(),
+            Req::from_str("oldlib = \"1.0.0\"", false).unwrap(),
+        ];
+        let (os, py_vers) = (util::Os::Linux, Version::new(3, 11, 7));
+        sync(
+            &paths,
+            &lockpacks,
+            &reqs,
+            &[],
+            &[],
+            os,
+            &py_vers,
+            &lock_path,
+            false,
+        );
+
+        let lock = util::read_lock(&lock_path).unwrap().package.unwrap();
+        let six = lock.iter().find(|lp| lp.version == "1.10.0").unwrap();
+        assert_eq!(six.rename, Some(format!("1 {}", renamed)));
+        assert_eq!(six.modules, Some(vec![renamed.clone()]));
+        assert!(paths.lib.join(&renamed).exists());
+        let written = std::fs::read_to_string(&lock_path).unwrap();
+        let modified = std::fs::metadata(&lock_path).unwrap().modified().unwrap();
+
+        // Nothing changes the second time.
+        let installed = util::find_installed(&paths.lib);
+        assert_eq!(
+            up_to_date(&lock, &reqs, &[], &installed, os, &py_vers),
+            Some(3)
+        );
+        sync(
+            &paths,
+            &lock,
+            &reqs,
+            &[],
+            &[],
+            os,
+            &py_vers,
+            &lock_path,
+            false,
+        );
+        assert_eq!(std::fs::read_to_string(&lock_path).unwrap(), written);
+        assert_eq!(
+            std::fs::metadata(&lock_path).unwrap().modified().unwrap(),
+            modified
+        );
+        assert_eq!(util::find_installed(&paths.lib).len(), 3);
+    }
+
+    #[test]
+    fn lock_casings_deduped() {
+        let (first, _) = resolutions();
+        let mut lockpacks = make_lock_packs(&first, &[]);
+        lockpacks[0].name = "Django".into();
+        lockpacks[0].version = "3.2.0".into();
+        lockpacks[1].name = "django".into();
+        lockpacks[1].version = "4.2.0".into();
+
+        assert!(dedupe_lock_names(&mut lockpacks));
+        let names: Vec<(&str, &str)> = lockpacks
+            .iter()
+            .map(|lp| (lp.name.as_str(), lp.version.as_str()))
+            .collect();
+        assert_eq!(names, vec![("django", "4.2.0"), ("urllib3", "1.26.18")]);
+        assert!(!dedupe_lock_names(&mut lockpacks));
+    }
+
+    #[test]
+    fn dependents_removed_with_package() {
+        let (first, _) = resolutions();
+        let packs = make_lock_packs(&first, &[]);
+
+        let names = |packs: Vec<LockPackage>| -> Vec<String> {
+            packs.into_iter().map(|lp| lp.name).collect()
+        };
+        // `requests` pins `urllib3`, so is resolved again too.
+        assert_eq!(
+            names(without_packages(&packs, &["urllib3".into()])),
+            vec!["idna"]
+        );
+        assert_eq!(
+            names(without_packages(&packs, &["requests".into()])),
+            vec!["idna", "urllib3"]
+        );
+    }
+
+    #[test]
+    fn install_summary_shape() {
+        let sizes = vec![
+            ("pandas".to_owned(), 21_400_000),
+            ("six".to_owned(), 38_000),
+            ("torch".to_owned(), 210_000_000),
+            ("scipy".to_owned(), 38_200_000),
+        ];
+        assert_eq!(
+            install_summary(&sizes, Duration::from_secs(102)),
+            "Installed 4 packages (270 MB) in 1m42s — largest: torch 210 MB, scipy 38 MB, \
+             pandas 21 MB"
+        );
+        assert_eq!(
+            install_summary(&sizes[1..2], Duration::from_millis(2340)),
+            "Installed 1 package (38 kB) in 2.3s"
+        );
+        assert_eq!(util::format_size(512), "512 B");
+        assert_eq!(util::format_size(2_450_000_000), "2.5 GB");
+    }
+
+    #[test]
+    fn extra_dependencies_follow_requested_extras() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let paths = util::Paths {
+            bin: dir.join(".venv/bin"),
+            lib: dir.join("lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        };
+        let wheels = dir.join("wheels");
+        std::fs::create_dir_all(&wheels).unwrap();
+        let filename = "watchfiles-0.21.0-py3-none-any.whl";
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(wheels.join(filename)).unwrap());
+        for (name, contents) in [
+            ("watchfiles/__init__.py", ""),
+            ("watchfiles-0.21.0.dist-info/top_level.txt", "watchfiles\n"),
+        ] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        let wheel = LockFile {
+            filename: filename.into(),
+            url: format!("path:{}", wheels.join(filename).to_string_lossy()),
+            sha256: String::new(),
+            packagetype: "bdist_wheel".into(),
+            python_version: "py3".into(),
+            requires_python: None,
+            platform: util::platform_tag(filename),
+        };
+
+        // `uvicorn[standard]` pulls in `watchfiles`; plain `uvicorn` doesn't.
+        let (first, _) = resolutions();
+        let mut lockpacks = vec![];
+        for (name, version, deps) in [
+            (
+                "uvicorn",
+                "0.23.2",
+                vec![
+                    "h11 0.14.0 pypi+https://pypi.org/pypi/h11/0.14.0/json",
+                    "watchfiles 0.21.0 pypi+https://pypi.org/pypi/watchfiles/0.21.0/json ; \
+                     extra == 'standard'",
+                ],
+            ),
+            ("h11", "0.14.0", vec![]),
+            ("watchfiles", "0.21.0", vec![]),
+        ] {
+            let dist_info = paths.lib.join(format!("{}-{}.dist-info", name, version));
+            std::fs::create_dir_all(&dist_info).unwrap();
+            std::fs::write(dist_info.join("top_level.txt"), format!("{}\n", name)).unwrap();
+            std::fs::create_dir_all(paths.lib.join(name)).unwrap();
+
+            let mut lp = make_lock_packs(&first, &[]).remove(0);
+            lp.id = lockpacks.len() as u32 + 1;
+            lp.name = name.into();
+            lp.version = version.into();
+            lp.dependencies = Some(deps.into_iter().map(String::from).collect());
+            lp.files = Some(vec![]);
+            lp.modules = Some(vec![name.into()]);
+            lockpacks.push(lp);
+        }
+        lockpacks[0].extras = Some(vec!["standard".into()]);
+        lockpacks[2].files = Some(vec![wheel.clone()]);
+
+        let lock_path = dir.join("pyflow.lock");
+        let plain = vec![Req::from_str("uvicorn", false).unwrap()];
+        let standard = vec![Req::from_str("uvicorn[standard]", true).unwrap()];
+        let (os, py_vers) = (util::Os::Linux, Version::new(3, 11, 7));
+        let names = |packs: &[LockPackage]| -> Vec<String> {
+            packs.iter().map(|lp| lp.name.clone()).collect()
+        };
+        let installed = || -> Vec<String> {
+            let mut names: Vec<String> = util::find_installed(&paths.lib)
+                .into_iter()
+                .map(|(n, _, _)| n)
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            up_to_date(
+                &lockpacks,
+                &standard,
+                &[],
+                &util::find_installed(&paths.lib),
+                os,
+                &py_vers
+            ),
+            Some(3)
+        );
+
+        // Dropping the extra uninstalls its dependencies, but its lock entry keeps them.
+        sync_with(
+            &paths,
+            &lockpacks,
+            &plain,
+            &[],
+            &[],
+            os,
+            &py_vers,
+            &lock_path,
+            Some(true),
+            |reqs, locked, resolution| {
+                res::resolve(reqs, locked, os, &py_vers, resolution).unwrap()
+            },
+        );
+        let lockpacks = util::read_lock(&lock_path).unwrap().package.unwrap();
+        assert_eq!(names(&lockpacks), vec!["h11", "uvicorn"]);
+        assert_eq!(installed(), vec!["h11", "uvicorn"]);
+        let uvicorn = &lockpacks[1];
+        assert_eq!(uvicorn.extras, Some(vec!["standard".into()]));
+        assert!(uvicorn
+            .dependencies
+            .iter()
+            .flatten()
+            .any(|d| d.starts_with("watchfiles 0.21.0")));
+
+        // Requesting it again locks and installs them.
+        let installed_now = util::find_installed(&paths.lib);
+        assert_eq!(
+            up_to_date(&lockpacks, &plain, &[], &installed_now, os, &py_vers),
+            Some(2)
+        );
+        assert_eq!(
+            up_to_date(&lockpacks, &standard, &[], &installed_now, os, &py_vers),
+            None
+        );
+        let mut relocked = relock_using(&lockpacks, &standard, &[], os, &py_vers, |_, locked| {
+            assert_eq!(locked.len(), 2);
+            let mut resolved = locked.to_vec();
+            resolved.push(package(9, "watchfiles", Version::new(0, 21, 0), &[]));
+            resolved
+        });
+        assert_eq!(names(&relocked), vec!["h11", "uvicorn", "watchfiles"]);
+        relocked[2].files = Some(vec![wheel]);
+        sync_deps(&paths, &relocked, &[], &installed_now, os, &py_vers);
+        assert_eq!(installed(), vec!["h11", "uvicorn", "watchfiles"]);
+        assert!(paths.lib.join("watchfiles/__init__.py").exists());
+    }
+
+    #[test]
+    fn no_dev_keeps_runtime_deps() {
+        let lock_packs = vec![
+            lock_pack(1, "requests", "2.31.0", &["idna 3.4.0", "urllib3 1.26.18"]),
+            lock_pack(2, "idna", "3.4.0", &[]),
+            lock_pack(3, "urllib3", "1.26.18", &[]),
+            lock_pack(4, "pytest", "7.4.0", &["pluggy 1.3.0"]),
+            lock_pack(5, "pluggy", "1.3.0", &[]),
+        ];
+        let reqs = vec![Req::new("requests".into(), vec![])];
+
+        let mut names: Vec<String> = runtime_packs(&lock_packs, &reqs)
+            .unwrap()
+            .into_iter()
+            .map(|lp| lp.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["idna", "requests", "urllib3"]);
+
+        let missing = vec![Req::new("flask".into(), vec![])];
+        assert!(runtime_packs(&lock_packs, &missing).is_err());
+    }
+
+    #[test]
+    fn skipped_dev_packages_count_as_installed() {
+        let lock_packs = vec![
+            lock_pack(1, "requests", "2.31.0", &["idna 3.4.0"]),
+            lock_pack(2, "idna", "3.4.0", &[]),
+            lock_pack(3, "pytest", "7.4.0", &[]),
+        ];
+        let reqs = vec![Req::new("requests".into(), vec![])];
+        let (os, py_vers) = (util::Os::Linux, Version::new(3, 11, 0));
+        let installed = vec![
+            ("requests".to_owned(), Version::new(2, 31, 0), vec![]),
+            ("idna".to_owned(), Version::new(3, 4, 0), vec![]),
+        ];
+
+        let (lock, view) = dev_skipped_view(&lock_packs, &reqs, &installed, os, &py_vers).unwrap();
+        assert!(view
+            .iter()
+            .any(|(n, v, _)| n == "pytest" && *v == Version::new(7, 4, 0)));
+        assert_eq!(lock[2].modules, Some(vec![]));
+        assert_eq!(lock[0].modules, None);
+
+        // An installed dev package needs uninstalling.
+        let with_dev = [
+            installed.clone(),
+            vec![("pytest".to_owned(), Version::new(7, 4, 0), vec![])],
+        ]
+        .concat();
+        assert!(dev_skipped_view(&lock_packs, &reqs, &with_dev, os, &py_vers).is_none());
+    }
+}
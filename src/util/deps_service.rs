@@ -0,0 +1,148 @@
+//! The service we query for the requirements of many packages at once. It defaults to `pydeps`;
+//! mirrors can be set with `PYFLOW_DEPS_URL`, or `deps_service_url` in `[tool.pyflow]`.
+
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use termcolor::Color;
+
+use crate::util;
+
+/// A comma-separated list of service URLs, tried in order. Takes precedence over the config.
+pub const URL_VAR: &str = "PYFLOW_DEPS_URL";
+
+const DEFAULT_URL: &str = "https://pydeps.herokuapp.com/multiple/";
+
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Attempts for each endpoint, before moving on to the next. Only transient failures are retried.
+const ATTEMPTS: u32 = 3;
+
+static ENDPOINTS: OnceLock<Vec<String>> = OnceLock::new();
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+fn parse_urls(urls: &str) -> Vec<String> {
+    urls.split(',')
+        .map(str::trim)
+        .filter(|u| !u.is_empty())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Endpoints to try, in order.
+fn select(env_urls: Option<&str>, cfg_urls: Option<&str>) -> Vec<String> {
+    [env_urls, cfg_urls]
+        .iter()
+        .flatten()
+        .map(|u| parse_urls(u))
+        .find(|u| !u.is_empty())
+        .unwrap_or_else(|| vec![DEFAULT_URL.to_owned()])
+}
+
+/// For `--no-deps-service`: always query PyPI.
+pub fn disable() {
+    DISABLED.store(true, Ordering::SeqCst);
+}
+
+/// Set the endpoints from the project's config. Only the first call has an effect.
+pub fn configure(cfg_urls: Option<&str>) {
+    ENDPOINTS.get_or_init(|| select(env::var(URL_VAR).ok().as_deref(), cfg_urls));
+}
+
+fn endpoints() -> &'static [String] {
+    if DISABLED.load(Ordering::SeqCst) {
+        return &[];
+    }
+    ENDPOINTS.get_or_init(|| select(env::var(URL_VAR).ok().as_deref(), None))
+}
+
+/// Whether a request may succeed if we try again: a timeout, a dropped connection, or a server
+/// error such as a 502 from the service's proxy.
+fn is_transient(e: &reqwest::Error) -> bool {
+    e.is_timeout()
+        || e.is_connect()
+        || e.is_request()
+        || e.status().is_some_and(|s| s.is_server_error())
+}
+
+fn post_one<B: Serialize, T: DeserializeOwned>(url: &str, body: &B) -> reqwest::Result<T> {
+    let mut attempt = 1;
+    loop {
+        let result = util::http::client()
+            .post(url)
+            .timeout(TIMEOUT)
+            .json(body)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.json());
+        match result {
+            Err(e) if attempt < ATTEMPTS && is_transient(&e) => {
+                thread::sleep(Duration::from_millis(500 << attempt));
+                attempt += 1;
+            }
+            _ => return result,
+        }
+    }
+}
+
+/// Post `body` to each endpoint until one responds, warning about those that failed. Returns
+/// `None` if none did, or the service is disabled; query PyPI for each package instead.
+pub fn post<B: Serialize, T: DeserializeOwned>(body: &B) -> Option<T> {
+    let endpoints = endpoints();
+    let mut failed = false;
+    for url in endpoints {
+        match post_one(url, body) {
+            Ok(data) => {
+                if failed {
+                    util::print_color(&format!("Got dependency data from {}", url), Color::Yellow);
+                }
+                return Some(data);
+            }
+            Err(e) => {
+                failed = true;
+                util::print_color(
+                    &format!(
+                        "The dependency service at {} failed: {}",
+                        url,
+                        util::http::describe(&e)
+                    ),
+                    Color::Yellow,
+                );
+            }
+        }
+    }
+    if failed {
+        util::print_color(
+            "Querying PyPI for each package instead, which is slower",
+            Color::Yellow,
+        );
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoints_selected_in_order() {
+        let mirrors = "https://a.example/multiple/, ,https://b.example/multiple/";
+        assert_eq!(
+            select(Some(mirrors), Some("https://c.example/")),
+            vec!["https://a.example/multiple/", "https://b.example/multiple/"]
+        );
+        assert_eq!(
+            select(Some(" "), Some("https://c.example/")),
+            vec!["https://c.example/"]
+        );
+        assert_eq!(select(None, None), vec![DEFAULT_URL]);
+    }
+}
@@ -0,0 +1,125 @@
+//! Environment variables for `pyflow run` and `pyflow python`, from `--env KEY=VALUE` and a
+//! `.env` file. They're only set for the commands we run; not for pyflow itself.
+
+use std::{fs, path::Path, process::Command, sync::Mutex};
+
+use crate::util::abort;
+
+static VARS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// Parse a `.env` file: `KEY=value` lines, with optional `export ` prefixes, single or double
+/// quoted values, and `#` comments. Lines we can't read are skipped.
+pub fn parse(data: &str) -> Vec<(String, String)> {
+    data.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line.split_once('=')?;
+            let key = key.trim();
+            if key.is_empty() || key.contains(char::is_whitespace) {
+                return None;
+            }
+            Some((key.to_owned(), parse_value(value.trim())))
+        })
+        .collect()
+}
+
+fn parse_value(value: &str) -> String {
+    if let Some(quoted) = value.strip_prefix('\'') {
+        // Single quotes are literal.
+        return quoted.split('\'').next().unwrap_or_default().to_owned();
+    }
+    if let Some(quoted) = value.strip_prefix('"') {
+        let mut result = String::new();
+        let mut chars = quoted.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next() {
+                    Some('n') => result.push('\n'),
+                    Some('t') => result.push('\t'),
+                    Some(other) => result.push(other),
+                    None => break,
+                },
+                c => result.push(c),
+            }
+        }
+        return result;
+    }
+    // Unquoted values end at a comment, eg `PORT=8000  # local only`.
+    let end = value
+        .char_indices()
+        .find(|(i, c)| *c == '#' && value[..*i].ends_with(char::is_whitespace))
+        .map_or(value.len(), |(i, _)| i);
+    value[..end].trim().to_owned()
+}
+
+/// Parse a `--env` value, eg `DATABASE_URL=postgres://localhost/app`.
+pub fn parse_arg(arg: &str) -> (String, String) {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => (key.to_owned(), value.to_owned()),
+        _ => abort(&format!(
+            "Problem parsing `--env {}`. It should look like `--env KEY=VALUE`",
+            arg
+        )),
+    }
+}
+
+/// Set the variables for commands we run: From `dotenv`, if passed, or the project's `.env` if
+/// `load_dotenv` is set in `pyproject.toml`, then `--env` arguments, which replace them.
+pub fn configure(project_path: &Path, load_dotenv: bool, dotenv: Option<&Path>, env: &[String]) {
+    let mut vars = match dotenv {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(data) => parse(&data),
+            Err(_) => abort(&format!("Problem reading {}", path.display())),
+        },
+        None if load_dotenv => fs::read_to_string(project_path.join(".env"))
+            .map(|data| parse(&data))
+            .unwrap_or_default(),
+        None => vec![],
+    };
+    vars.extend(env.iter().map(|arg| parse_arg(arg)));
+    *VARS.lock().unwrap() = vars;
+}
+
+/// Add the variables to a command we're about to run. Later ones replace earlier ones, and
+/// both replace variables pyflow was run with.
+pub fn apply(command: &mut Command) -> &mut Command {
+    command.envs(VARS.lock().unwrap().iter().map(|(k, v)| (k, v)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dotenv() {
+        let data = r#"
+# Local settings
+export DATABASE_URL="postgres://localhost/app?sslmode=disable"
+SECRET = 'p#ss "word"'
+PORT=8000  # dev only
+GREETING="hello\n\"world\"" # trailing
+ANCHOR=page#top
+EMPTY=
+not a line
+"#;
+        assert_eq!(
+            parse(data),
+            vec![
+                (
+                    "DATABASE_URL".to_owned(),
+                    "postgres://localhost/app?sslmode=disable".to_owned()
+                ),
+                ("SECRET".to_owned(), "p#ss \"word\"".to_owned()),
+                ("PORT".to_owned(), "8000".to_owned()),
+                ("GREETING".to_owned(), "hello\n\"world\"".to_owned()),
+                ("ANCHOR".to_owned(), "page#top".to_owned()),
+                ("EMPTY".to_owned(), String::new()),
+            ]
+        );
+    }
+}
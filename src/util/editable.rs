@@ -0,0 +1,268 @@
+//! Projects installed in place, eg the current one with `pyflow install -e .`, or a sibling with
+//! `pyflow install -e ../mylib`. They're path dependencies with `editable = true`. A `.pth` file
+//! in the environment's `site-packages` points Python at their source, and their console
+//! scripts, from `[tool.pyflow.scripts]`, import from there, so changes apply without
+//! reinstalling.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use termcolor::Color;
+
+use crate::{
+    dep_types::{Req, Version},
+    install,
+    pyproject::{Config, ScriptCmd},
+    util,
+};
+
+/// Of keys in the lock's metadata, eg `editable.mylib = "/home/me/mylib"`.
+pub const LOCK_KEY_PREFIX: &str = "editable.";
+
+/// A project to install in place.
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub name: String,
+    pub version: Option<Version>,
+    pub root: PathBuf,
+    /// Where its modules are: `src`, in a src layout, otherwise its root.
+    pub source: PathBuf,
+    /// Entry points, eg `greet=mylib.cli:main`.
+    pub scripts: Vec<String>,
+}
+
+/// Read the name, version, and console scripts of the project at `path`, from its
+/// `pyproject.toml`.
+pub fn read(path: &Path) -> Result<Target, String> {
+    let root = fs::canonicalize(path).map_err(|_| format!("Can't find {}", path.display()))?;
+    let cfg_path = root.join("pyproject.toml");
+    if !cfg_path.exists() {
+        return Err(format!(
+            "{} has no `pyproject.toml`, so can't be installed in place",
+            root.display()
+        ));
+    }
+    let cfg = Config::from_file(&cfg_path)
+        .ok_or_else(|| format!("Problem reading {}", cfg_path.display()))?;
+    let name = cfg
+        .name
+        .clone()
+        .ok_or_else(|| format!("{} has no `name` under `[tool.pyflow]`", cfg_path.display()))?;
+
+    let mut scripts: Vec<String> = cfg
+        .scripts
+        .iter()
+        .filter_map(|(script, task)| match &task.cmd {
+            Some(ScriptCmd::Function(module, function)) => {
+                Some(format!("{}={}:{}", script, module, function))
+            }
+            _ => None,
+        })
+        .collect();
+    scripts.sort();
+
+    let src = root.join("src");
+    Ok(Target {
+        name,
+        version: cfg.version,
+        source: if src.is_dir() { src } else { root.clone() },
+        root,
+        scripts,
+    })
+}
+
+/// The environment's `site-packages`, from its `bin` folder.
+fn site_packages(bin_path: &Path) -> Option<PathBuf> {
+    let venv = bin_path.parent()?;
+    let windows = venv.join("Lib").join("site-packages");
+    if windows.is_dir() {
+        return Some(windows);
+    }
+    fs::read_dir(venv.join("lib"))
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path().join("site-packages"))
+        .find(|path| path.is_dir())
+}
+
+fn pth_filename(name: &str) -> String {
+    format!("__editable__.{}.pth", util::standardize_name(name))
+}
+
+/// Point the environment's Python at the target's source, and create its console scripts.
+/// Only what's missing or changed is written, so this is cheap to run on each sync.
+pub fn install(target: &Target, paths: &util::Paths) {
+    match site_packages(&paths.bin) {
+        Some(site_packages) => {
+            let pth = site_packages.join(pth_filename(&target.name));
+            let contents = format!("{}\n", target.source.to_string_lossy());
+            if fs::read_to_string(&pth).ok().as_deref() != Some(&contents)
+                && fs::write(&pth, contents).is_err()
+            {
+                util::abort(&format!("Problem writing {}", pth.display()));
+            }
+        }
+        None => util::print_color(
+            &format!(
+                "Can't find the environment's `site-packages`; {} is only importable with \
+                 `pyflow run` and `pyflow python`",
+                target.name
+            ),
+            Color::Yellow,
+        ),
+    }
+
+    let version = target
+        .version
+        .as_ref()
+        .map(Version::to_string)
+        .unwrap_or_default();
+    install::setup_editable_scripts(
+        &target.name,
+        &version,
+        &target.scripts,
+        &paths.lib,
+        &paths.entry_pt,
+    );
+}
+
+/// Record the editable reqs in a lock's metadata, with paths relative to the project.
+pub fn mark(reqs: &[Req], project_path: &Path, metadata: &mut HashMap<String, String>) {
+    for req in reqs.iter().filter(|r| r.editable) {
+        if let Some(path) = &req.path {
+            let path = Path::new(path);
+            let relative = match path.strip_prefix(project_path) {
+                Ok(p) if p.as_os_str().is_empty() => Path::new("."),
+                Ok(p) => p,
+                Err(_) => path,
+            };
+            metadata.insert(
+                format!("{}{}", LOCK_KEY_PREFIX, util::standardize_name(&req.name)),
+                relative.to_string_lossy().into_owned(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use crate::commands;
+
+    fn fixture(dir: &Path, message: &str) {
+        fs::write(
+            dir.join("src/fixture/__init__.py"),
+            format!("def main():\n    print({:?})\n", message),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn reads_target() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        fs::create_dir_all(dir.join("src/fixture")).unwrap();
+        fs::write(
+            dir.join("pyproject.toml"),
+            "[tool.pyflow]\nname = \"fixture\"\nversion = \"0.1.0\"\n\n\
+             [tool.pyflow.scripts]\ngreet = \"fixture:main\"\nfmt = \"black .\"\n",
+        )
+        .unwrap();
+
+        let target = read(&dir).unwrap();
+        assert_eq!(target.name, "fixture");
+        assert_eq!(target.version, Some(Version::new(0, 1, 0)));
+        assert_eq!(target.source, fs::canonicalize(&dir).unwrap().join("src"));
+        // Shell tasks aren't console scripts.
+        assert_eq!(target.scripts, vec!["greet=fixture:main"]);
+
+        assert!(read(&dir.join("missing")).is_err());
+    }
+
+    #[test]
+    fn marks_lock() {
+        let editable = |name: &str, path: &str| Req {
+            path: Some(path.into()),
+            editable: true,
+            ..Req::new(name.into(), vec![])
+        };
+        let other = Req {
+            path: Some("/home/me/other".into()),
+            ..Req::new("other".into(), vec![])
+        };
+        let mut metadata = HashMap::new();
+        mark(
+            &[
+                editable("proj", "/home/me/proj"),
+                editable("MyLib", "/home/me/proj/libs/mylib"),
+                other,
+            ],
+            Path::new("/home/me/proj"),
+            &mut metadata,
+        );
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata["editable.proj"], ".");
+        assert_eq!(metadata["editable.mylib"], "libs/mylib");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn edits_apply_without_reinstalling() {
+        if commands::find_py_version("python3").is_none() {
+            return;
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let project = dir.join("fixture");
+        fs::create_dir_all(project.join("src/fixture")).unwrap();
+        fs::write(
+            project.join("pyproject.toml"),
+            "[tool.pyflow]\nname = \"fixture\"\nversion = \"0.1.0\"\n\n\
+             [tool.pyflow.scripts]\ngreet = \"fixture:main\"\n",
+        )
+        .unwrap();
+        fixture(&project, "hello");
+
+        let env = dir.join("env");
+        let venv = Command::new("python3")
+            .args(["-m", "venv", "--without-pip"])
+            .arg(env.join(".venv"))
+            .status();
+        if !venv.is_ok_and(|s| s.success()) {
+            // Eg `python3-venv` isn't installed.
+            return;
+        }
+        let paths = util::Paths {
+            bin: env.join(".venv/bin"),
+            lib: env.join("lib"),
+            entry_pt: env.join("bin"),
+            cache: dir.join("cache"),
+        };
+        fs::create_dir_all(&paths.lib).unwrap();
+        fs::create_dir_all(&paths.entry_pt).unwrap();
+
+        let target = read(&project).unwrap();
+        install(&target, &paths);
+        assert!(site_packages(&paths.bin)
+            .unwrap()
+            .join(pth_filename("fixture"))
+            .is_file());
+
+        let greet = || {
+            let output = Command::new(paths.bin.join("python"))
+                .arg(paths.entry_pt.join("greet"))
+                .env_remove("PYTHONPATH")
+                .output()
+                .unwrap();
+            String::from_utf8_lossy(&output.stdout).trim().to_owned()
+        };
+        assert_eq!(greet(), "hello");
+        fixture(&project, "changed");
+        assert_eq!(greet(), "changed");
+    }
+}
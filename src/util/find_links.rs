@@ -0,0 +1,279 @@
+//! Local directories of wheels and source dists to resolve and install from, eg on machines
+//! without access to PyPI. Set with `find_links` in `[tool.pyflow]`, or `--find-links`.
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use regex::Regex;
+
+use crate::{
+    dep_resolution::{WarehouseDigests, WarehouseRelease},
+    dep_types::Version,
+    install, util,
+};
+
+/// Marks release urls that point to a local file.
+pub const PATH_PREFIX: &str = "path:";
+
+/// A release file found in one of the directories.
+#[derive(Clone, Debug)]
+pub struct LocalRelease {
+    pub name: String,
+    pub version: Version,
+    /// The version as written in the filename.
+    pub version_str: String,
+    pub release: WarehouseRelease,
+    /// From the wheel's `METADATA`. Empty for source dists, which we don't build to find out.
+    pub requires_dist: Vec<String>,
+}
+
+#[derive(Default)]
+struct FindLinks {
+    dirs: Vec<PathBuf>,
+    no_index: bool,
+    releases: OnceLock<Vec<LocalRelease>>,
+}
+
+static FIND_LINKS: OnceLock<FindLinks> = OnceLock::new();
+
+/// Set the directories to search, and whether to skip PyPI. Only the first call has an effect.
+pub fn configure(dirs: &[PathBuf], no_index: bool) {
+    let dirs = dirs
+        .iter()
+        .map(|d| {
+            fs::canonicalize(d).unwrap_or_else(|_| {
+                util::abort(&format!(
+                    "Can't find the find-links directory {}",
+                    d.to_string_lossy()
+                ))
+            })
+        })
+        .collect();
+    FIND_LINKS.get_or_init(|| FindLinks {
+        dirs,
+        no_index,
+        releases: OnceLock::new(),
+    });
+}
+
+fn current() -> &'static FindLinks {
+    FIND_LINKS.get_or_init(FindLinks::default)
+}
+
+/// For `--no-index`: only use the local directories.
+pub fn no_index() -> bool {
+    current().no_index
+}
+
+/// Release files for a package, from all directories.
+pub fn releases(name: &str) -> Vec<LocalRelease> {
+    let fl = current();
+    fl.releases
+        .get_or_init(|| scan(&fl.dirs))
+        .iter()
+        .filter(|r| util::compare_names(&r.name, name))
+        .cloned()
+        .collect()
+}
+
+/// Split a release filename into name, version, package type and python tag.
+fn parse_filename(filename: &str) -> Option<(String, String, &'static str, String)> {
+    if let Some(stem) = filename.strip_suffix(".whl") {
+        // name-version(-build)?-python-abi-platform
+        let parts: Vec<&str> = stem.split('-').collect();
+        if parts.len() < 5 {
+            return None;
+        }
+        let py_tag = parts[parts.len() - 3];
+        return Some((
+            parts[0].to_owned(),
+            parts[1].to_owned(),
+            "bdist_wheel",
+            py_tag.to_owned(),
+        ));
+    }
+    let re = Regex::new(r"^(.+)-([^-]+)\.(?:tar\.gz|zip)$").unwrap();
+    let caps = re.captures(filename)?;
+    Some((
+        caps[1].to_owned(),
+        caps[2].to_owned(),
+        "sdist",
+        "source".to_owned(),
+    ))
+}
+
+/// The `METADATA` headers we need from a wheel: `Requires-Python`, and `Requires-Dist` lines.
+fn wheel_requirements(path: &Path) -> (Option<String>, Vec<String>) {
+    let mut data = String::new();
+    if let Ok(file) = fs::File::open(path) {
+        if let Ok(mut archive) = zip::ZipArchive::new(io::BufReader::new(file)) {
+            let metadata = archive
+                .file_names()
+                .find(|n| n.ends_with(".dist-info/METADATA"))
+                .map(str::to_owned);
+            if let Some(metadata) = metadata {
+                if let Ok(mut f) = archive.by_name(&metadata) {
+                    f.read_to_string(&mut data).ok();
+                }
+            }
+        }
+    }
+
+    let mut requires_python = None;
+    let mut requires_dist = vec![];
+    for line in data.lines().take_while(|l| !l.trim().is_empty()) {
+        if let Some(val) = line.strip_prefix("Requires-Python:") {
+            requires_python = Some(val.trim().to_owned());
+        } else if let Some(val) = line.strip_prefix("Requires-Dist:") {
+            requires_dist.push(val.trim().to_owned());
+        }
+    }
+    (requires_python, requires_dist)
+}
+
+fn local_release(path: &Path) -> Option<LocalRelease> {
+    let filename = path.file_name()?.to_str()?.to_owned();
+    let (name, version_str, packagetype, python_version) = parse_filename(&filename)?;
+    let version = Version::from_str(&version_str).ok()?;
+    let file = fs::File::open(path).ok()?;
+    let digest = install::sha256_digest(io::BufReader::new(file)).ok()?;
+    let sha256 = data_encoding::HEXLOWER.encode(digest.as_ref());
+
+    let (requires_python, requires_dist) = if packagetype == "bdist_wheel" {
+        wheel_requirements(path)
+    } else {
+        (None, vec![])
+    };
+
+    Some(LocalRelease {
+        name,
+        version,
+        version_str,
+        release: WarehouseRelease {
+            filename,
+            has_sig: false,
+            digests: WarehouseDigests {
+                md5: String::new(),
+                sha256,
+            },
+            packagetype: packagetype.to_owned(),
+            python_version,
+            requires_python,
+            url: format!("{}{}", PATH_PREFIX, path.to_string_lossy()),
+            dependencies: None,
+            size: fs::metadata(path).ok().map(|m| m.len()),
+        },
+        requires_dist,
+    })
+}
+
+fn scan(dirs: &[PathBuf]) -> Vec<LocalRelease> {
+    let mut result = vec![];
+    for dir in dirs {
+        let entries = match fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        let mut paths: Vec<PathBuf> = entries.flatten().map(|e| e.path()).collect();
+        paths.sort();
+        result.extend(paths.iter().filter_map(|p| local_release(p)));
+    }
+    result
+}
+
+/// The directory a `path:` url's file is in, for the lock's `source`.
+pub fn source_dir(url: &str) -> Option<String> {
+    let path = Path::new(url.strip_prefix(PATH_PREFIX)?);
+    path.parent()
+        .map(|p| format!("{}{}", PATH_PREFIX, p.to_string_lossy()))
+}
+
+/// The file a `path:` url refers to. If it's not there, eg the lock was made on another
+/// machine, look for a file of the same name in the configured directories.
+pub fn locate(url: &str, filename: &str) -> Option<PathBuf> {
+    let path = PathBuf::from(url.strip_prefix(PATH_PREFIX)?);
+    if path.exists() {
+        return Some(path);
+    }
+    current()
+        .dirs
+        .iter()
+        .map(|d| d.join(filename))
+        .find(|p| p.exists())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::{install::PackageType, util::Os};
+
+    fn make_wheel(path: &Path, metadata: &str) {
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        zip.start_file(
+            "requests-2.31.0.dist-info/METADATA",
+            zip::write::SimpleFileOptions::default(),
+        )
+        .unwrap();
+        zip.write_all(metadata.as_bytes()).unwrap();
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn local_files_become_releases() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        make_wheel(
+            &dir.join("requests-2.31.0-py3-none-any.whl"),
+            "Name: requests\nVersion: 2.31.0\nRequires-Python: >=3.7\n\
+             Requires-Dist: idna (<4,>=2.5)\n\nDescription",
+        );
+        fs::write(dir.join("requests-2.31.0.tar.gz"), b"sdist").unwrap();
+        fs::write(dir.join("urllib3-2.0.4.tar.gz"), b"sdist").unwrap();
+        fs::write(dir.join("notes.txt"), b"").unwrap();
+
+        let found: Vec<LocalRelease> = scan(std::slice::from_ref(&dir))
+            .into_iter()
+            .filter(|r| util::compare_names(&r.name, "Requests"))
+            .collect();
+        assert_eq!(found.len(), 2);
+        let wheel = &found[0].release;
+        assert_eq!(wheel.packagetype, "bdist_wheel");
+        assert_eq!(wheel.python_version, "py3");
+        assert_eq!(wheel.requires_python.as_deref(), Some(">=3.7"));
+        assert_eq!(found[0].requires_dist, vec!["idna (<4,>=2.5)"]);
+        assert_eq!(found[1].release.packagetype, "sdist");
+        assert_eq!(
+            source_dir(&wheel.url),
+            Some(format!("path:{}", dir.to_string_lossy()))
+        );
+
+        // Selected the same way as releases from PyPI.
+        let releases: Vec<WarehouseRelease> = found.iter().map(|r| r.release.clone()).collect();
+        let version = Version::new(2, 31, 0);
+        let choice = util::find_best_release(
+            &releases,
+            "requests",
+            &version,
+            Os::Linux,
+            &Version::new(3, 11, 0),
+            None,
+        );
+        assert!(matches!(choice.package_type, PackageType::Wheel));
+        let choice = util::find_best_release(
+            &releases,
+            "requests",
+            &version,
+            Os::Linux,
+            &Version::new(3, 6, 0),
+            None,
+        );
+        assert!(matches!(choice.package_type, PackageType::Source));
+    }
+}
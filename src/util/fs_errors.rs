@@ -0,0 +1,161 @@
+//! Filesystem errors while installing, eg a full disk or a read-only mount, with the path and
+//! operation that failed, and descriptions that point users to a fix.
+
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use crate::util::{self, paths::CACHE_DIR_VAR};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsErrorKind {
+    /// The disk, or the user's quota, is full.
+    NoSpace,
+    /// We aren't allowed to write there.
+    PermissionDenied,
+    /// The destination is on a read-only mount.
+    ReadOnly,
+    Other,
+}
+
+pub fn classify(e: &io::Error) -> FsErrorKind {
+    // Raw OS errors, eg `ENOSPC` or `ERROR_DISK_FULL`, map to these kinds.
+    match e.kind() {
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => FsErrorKind::NoSpace,
+        io::ErrorKind::PermissionDenied => FsErrorKind::PermissionDenied,
+        io::ErrorKind::ReadOnlyFilesystem => FsErrorKind::ReadOnly,
+        _ => FsErrorKind::Other,
+    }
+}
+
+/// A failed filesystem operation, eg `creating` a file.
+#[derive(Debug)]
+pub struct FsError {
+    pub operation: &'static str,
+    pub path: PathBuf,
+    pub source: io::Error,
+}
+
+impl FsError {
+    pub fn new(operation: &'static str, path: &Path, source: io::Error) -> Self {
+        Self {
+            operation,
+            path: path.to_owned(),
+            source,
+        }
+    }
+
+    pub fn kind(&self) -> FsErrorKind {
+        classify(&self.source)
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Problem {} {}: {}",
+            self.operation,
+            self.path.display(),
+            self.source
+        )
+    }
+}
+
+/// The closest folder above `path` that exists; the one we couldn't write to.
+fn existing_parent(path: &Path) -> &Path {
+    path.ancestors()
+        .skip(1)
+        .find(|p| p.is_dir())
+        .unwrap_or(path)
+}
+
+/// A message for a failed install that names the path, and what to try. `needed` is how many
+/// bytes the packages left to install take to download, if we know.
+pub fn describe(e: &FsError, needed: Option<u64>) -> String {
+    let path = e.path.display();
+    let dir = existing_parent(&e.path).display();
+    match e.kind() {
+        FsErrorKind::NoSpace => {
+            let mut msg = format!("Ran out of disk space {} {}.", e.operation, path);
+            if let Some(needed) = needed {
+                msg.push_str(&format!(
+                    " The packages left to install are about {} to download, and more once \
+                     unpacked.",
+                    util::format_size(needed)
+                ));
+            }
+            msg.push_str(&format!(
+                " Free some space, or set {} to keep downloads on another disk, then try again.",
+                CACHE_DIR_VAR
+            ));
+            msg
+        }
+        FsErrorKind::PermissionDenied => format!(
+            "Permission denied {} {}: you can't write to {}. Check its owner and permissions, \
+             then try again.",
+            e.operation, path, dir
+        ),
+        FsErrorKind::ReadOnly => format!(
+            "Can't finish {} {}: {} is on a read-only mount. Remount it read-write, or run \
+             pyflow from a project on a writable disk.",
+            e.operation, path, dir
+        ),
+        FsErrorKind::Other => e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+        kind,
+        expected,
+        case(io::ErrorKind::StorageFull, FsErrorKind::NoSpace),
+        case(io::ErrorKind::QuotaExceeded, FsErrorKind::NoSpace),
+        case(io::ErrorKind::PermissionDenied, FsErrorKind::PermissionDenied),
+        case(io::ErrorKind::ReadOnlyFilesystem, FsErrorKind::ReadOnly),
+        case(io::ErrorKind::NotFound, FsErrorKind::Other)
+    )]
+    fn classify_errors(kind: io::ErrorKind, expected: FsErrorKind) {
+        assert_eq!(classify(&io::Error::from(kind)), expected);
+    }
+
+    #[cfg(unix)]
+    #[rstest(
+        code,
+        expected,
+        // `ENOSPC`, `EACCES`, `EROFS`, and `EIO`.
+        case(28, FsErrorKind::NoSpace),
+        case(13, FsErrorKind::PermissionDenied),
+        case(30, FsErrorKind::ReadOnly),
+        case(5, FsErrorKind::Other)
+    )]
+    fn classify_os_errors(code: i32, expected: FsErrorKind) {
+        assert_eq!(classify(&io::Error::from_raw_os_error(code)), expected);
+    }
+
+    #[test]
+    fn messages_name_what_to_fix() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let path = dir.join("lib/numpy/core.py");
+        let error = |kind| FsError::new("creating", &path, io::Error::from(kind));
+
+        let msg = describe(&error(io::ErrorKind::StorageFull), Some(25_000_000));
+        assert!(msg.contains(&path.display().to_string()));
+        assert!(msg.contains("25 MB"));
+        assert!(msg.contains(CACHE_DIR_VAR));
+        assert!(!describe(&error(io::ErrorKind::StorageFull), None).contains("download,"));
+
+        // The folder that exists, not the file we were creating.
+        let msg = describe(&error(io::ErrorKind::PermissionDenied), None);
+        assert!(msg.contains(&format!("write to {}.", dir.display())));
+
+        let msg = describe(&error(io::ErrorKind::ReadOnlyFilesystem), None);
+        assert!(msg.contains("read-only mount"));
+    }
+}
@@ -0,0 +1,353 @@
+//! Keeps pyflow processes from changing the same project, or writing the same cached file, at
+//! once. We hold an OS lock on a guard file, and record who holds it, so others can report
+//! what they're waiting on.
+
+use std::{
+    fmt,
+    fs::{self, File, TryLockError},
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+    process::{self, Command},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use termcolor::Color;
+
+use crate::util;
+
+/// In `__pypackages__`.
+pub const GUARD_FILENAME: &str = ".pyflow-guard";
+
+/// How long to wait for another process before giving up.
+pub const TIMEOUT: Duration = Duration::from_secs(600);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Handles to the guard files we hold, by guard id, so exiting can clear them.
+static HELD: Mutex<Vec<(u64, File)>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The process holding a guard, as recorded in the guard file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Holder {
+    pub pid: u32,
+    /// Seconds since the Unix epoch.
+    pub started: u64,
+}
+
+impl Holder {
+    fn current() -> Self {
+        Self {
+            pid: process::id(),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    fn parse(data: &str) -> Option<Self> {
+        let mut parts = data.split_whitespace();
+        Some(Self {
+            pid: parts.next()?.parse().ok()?,
+            started: parts.next()?.parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ago = Holder::current().started.saturating_sub(self.started);
+        write!(f, "pid {}, started {}s ago", self.pid, ago)
+    }
+}
+
+/// Another process held the guard for longer than we waited.
+#[derive(Debug)]
+pub struct Timeout(pub Option<Holder>);
+
+/// Held while operating on a project. Released when dropped.
+#[derive(Debug)]
+pub struct Guard {
+    id: u64,
+    file: File,
+    /// A process that held the guard without releasing it, eg because it was killed. What it
+    /// was doing may be incomplete.
+    pub stale: Option<Holder>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Ok(mut held) = HELD.lock() {
+            held.retain(|(id, _)| *id != self.id);
+        }
+        // Clear the holder, so the next process knows we finished. Closing the file releases
+        // the OS lock.
+        self.file.set_len(0).ok();
+    }
+}
+
+/// Clear the holders of the guards we hold, since exiting skips dropping them, and the next
+/// process would report us as interrupted. Called by `util::exit`; Ctrl-C doesn't, since it
+/// may leave the project half-changed.
+pub fn release_all() {
+    if let Ok(mut held) = HELD.lock() {
+        for (_, file) in held.drain(..) {
+            file.set_len(0).ok();
+        }
+    }
+}
+
+fn read_holder(file: &mut File) -> Option<Holder> {
+    let mut data = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut data).ok()?;
+    Holder::parse(&data)
+}
+
+fn write_holder(file: &mut File) {
+    let holder = Holder::current();
+    file.set_len(0).ok();
+    file.rewind().ok();
+    write!(file, "{} {}", holder.pid, holder.started).ok();
+    file.flush().ok();
+}
+
+/// Whether a process is running.
+fn pid_alive(pid: u32) -> bool {
+    if cfg!(target_os = "linux") {
+        return Path::new("/proc").join(pid.to_string()).exists();
+    }
+    if cfg!(target_os = "windows") {
+        return Command::new("tasklist")
+            .args(["/FI", &format!("PID eq {}", pid), "/NH"])
+            .output()
+            .map(|o| String::from_utf8_lossy(&o.stdout).contains(&pid.to_string()))
+            .unwrap_or(true);
+    }
+    Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(true)
+}
+
+/// Wait until we hold the guard at `path`, creating it if needed. `on_wait` is called once, with
+/// the holder, if another process has it. On filesystems without locking, we go by whether the
+/// recorded holder is still running.
+pub fn acquire(
+    path: &Path,
+    timeout: Duration,
+    on_wait: impl FnOnce(Option<&Holder>),
+) -> Result<Guard, Timeout> {
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .unwrap_or_else(|e| {
+            util::abort(&format!(
+                "Problem opening {}: {}",
+                path.to_string_lossy(),
+                e
+            ))
+        });
+
+    let start = Instant::now();
+    let mut on_wait = Some(on_wait);
+    loop {
+        let previous = read_holder(&mut file);
+        let acquired = match file.try_lock() {
+            Ok(()) => true,
+            Err(TryLockError::WouldBlock) => false,
+            // Eg some network filesystems.
+            Err(TryLockError::Error(_)) => previous.as_ref().is_none_or(|h| !pid_alive(h.pid)),
+        };
+        if acquired {
+            write_holder(&mut file);
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            if let (Ok(mut held), Ok(handle)) = (HELD.lock(), file.try_clone()) {
+                held.push((id, handle));
+            }
+            return Ok(Guard {
+                id,
+                file,
+                stale: previous.filter(|h| h.pid != process::id()),
+            });
+        }
+
+        if let Some(f) = on_wait.take() {
+            f(previous.as_ref());
+        }
+        if start.elapsed() >= timeout {
+            return Err(Timeout(previous));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Guard a project's `__pypackages__` directory for the rest of the run, waiting for other
+/// pyflow processes that hold it.
+pub fn guard_project(pypackages_path: &Path) -> Guard {
+    if !pypackages_path.exists() {
+        fs::create_dir_all(pypackages_path).expect("Problem creating __pypackages__");
+    }
+    let on_wait = |holder: Option<&Holder>| {
+        let who = holder.map_or_else(String::new, |h| format!(" ({})", h));
+        util::print_color(
+            &format!(
+                "Another pyflow process{} is operating on this project; waiting…",
+                who
+            ),
+            Color::Yellow,
+        );
+    };
+    let guard = acquire(&pypackages_path.join(GUARD_FILENAME), TIMEOUT, on_wait).unwrap_or_else(
+        |Timeout(holder)| {
+            let who = holder.map_or_else(String::new, |h| format!(" ({})", h));
+            util::abort(&format!(
+                "Gave up waiting for another pyflow process{} after {}s. If none is running, \
+                 remove `__pypackages__/{}`.",
+                who,
+                TIMEOUT.as_secs(),
+                GUARD_FILENAME
+            ))
+        },
+    );
+    if let Some(stale) = &guard.stale {
+        util::print_color(
+            &format!(
+                "A previous pyflow process ({}) on this project was interrupted. If packages \
+                 fail to import, run `pyflow reset`, then install again.",
+                stale
+            ),
+            Color::Yellow,
+        );
+    }
+    guard
+}
+
+/// Guard a file in the shared cache while we check for, or download it, so processes working on
+/// other projects don't write it at the same time.
+pub fn guard_cached(path: &Path) -> Guard {
+    let mut guard_path = PathBuf::from(path);
+    guard_path.as_mut_os_string().push(".lock");
+    acquire(&guard_path, TIMEOUT, |_| ()).unwrap_or_else(|_| {
+        util::abort(&format!(
+            "Timed out waiting for another pyflow process to download {}",
+            path.to_string_lossy()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    const PATH_VAR: &str = "PYFLOW_GUARD_TEST_PATH";
+    const MODE_VAR: &str = "PYFLOW_GUARD_TEST_MODE";
+
+    /// Run by the tests below in a child process: hold the guard for a while, exit without
+    /// releasing it, or exit as `util::abort` does.
+    #[test]
+    #[ignore]
+    fn guard_holder() {
+        let path = match env::var(PATH_VAR) {
+            Ok(p) => PathBuf::from(p),
+            Err(_) => return,
+        };
+        let guard = acquire(&path, TIMEOUT, |_| ()).unwrap();
+        match env::var(MODE_VAR).as_deref() {
+            Ok("crash") => process::exit(0),
+            Ok("exit") => util::exit(1),
+            _ => (),
+        }
+        thread::sleep(Duration::from_millis(1500));
+        drop(guard);
+    }
+
+    fn spawn_holder(path: &Path, mode: &str) -> process::Child {
+        Command::new(env::current_exe().unwrap())
+            .args(["--ignored", "--exact", "util::guard::tests::guard_holder"])
+            .env(PATH_VAR, path)
+            .env(MODE_VAR, mode)
+            .stdout(process::Stdio::null())
+            .spawn()
+            .unwrap()
+    }
+
+    fn wait_for_holder(path: &Path) -> Holder {
+        let start = Instant::now();
+        loop {
+            if let Some(h) = fs::read_to_string(path)
+                .ok()
+                .and_then(|d| Holder::parse(&d))
+            {
+                return h;
+            }
+            assert!(start.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn waits_for_another_process() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let path = dir.join(GUARD_FILENAME);
+
+        let mut child = spawn_holder(&path, "hold");
+        let holder = wait_for_holder(&path);
+        assert_eq!(holder.pid, child.id());
+
+        let mut waited_on = None;
+        let result = acquire(&path, Duration::from_millis(200), |h| {
+            waited_on = h.cloned()
+        });
+        assert_eq!(result.unwrap_err().0, Some(holder.clone()));
+        assert_eq!(waited_on, Some(holder));
+
+        // Acquired once the child finishes.
+        let guard = acquire(&path, Duration::from_secs(30), |_| ()).unwrap();
+        assert!(guard.stale.is_none());
+        child.wait().unwrap();
+        drop(guard);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+    }
+
+    #[test]
+    fn stale_guard_detected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let path = dir.join(GUARD_FILENAME);
+
+        let mut child = spawn_holder(&path, "crash");
+        let pid = child.id();
+        child.wait().unwrap();
+        assert!(!pid_alive(pid));
+
+        let guard = acquire(&path, Duration::from_secs(1), |_| panic!("Shouldn't wait")).unwrap();
+        assert_eq!(guard.stale.as_ref().map(|h| h.pid), Some(pid));
+    }
+
+    #[test]
+    fn exiting_releases_guard() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(GUARD_FILENAME);
+
+        let status = spawn_holder(&path, "exit").wait().unwrap();
+        assert_eq!(status.code(), Some(1));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        let guard = acquire(&path, Duration::from_secs(1), |_| panic!("Shouldn't wait")).unwrap();
+        assert!(guard.stale.is_none());
+    }
+}
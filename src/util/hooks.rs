@@ -0,0 +1,196 @@
+//! Commands from `[tool.pyflow.hooks]`, run before and after locking and installing, eg to
+//! generate code from installed packages. They run in the project directory, with the
+//! environment's `bin` directory first on the `PATH`.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::Instant,
+};
+
+use serde::Deserialize;
+use termcolor::Color;
+
+use crate::{commands, util, CliConfig};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    PreLock,
+    PostLock,
+    PreInstall,
+    PostInstall,
+}
+
+impl Event {
+    /// As in `[tool.pyflow.hooks]`, and `PYFLOW_EVENT`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PreLock => "pre_lock",
+            Self::PostLock => "post_lock",
+            Self::PreInstall => "pre_install",
+            Self::PostInstall => "post_install",
+        }
+    }
+
+    /// A pre-hook that fails stops what it runs before.
+    fn is_pre(self) -> bool {
+        matches!(self, Self::PreLock | Self::PreInstall)
+    }
+}
+
+/// Each event's commands, run in order.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Hooks {
+    pub pre_lock: Vec<String>,
+    pub post_lock: Vec<String>,
+    pub pre_install: Vec<String>,
+    pub post_install: Vec<String>,
+}
+
+impl Hooks {
+    pub fn commands(&self, event: Event) -> &[String] {
+        match event {
+            Event::PreLock => &self.pre_lock,
+            Event::PostLock => &self.post_lock,
+            Event::PreInstall => &self.pre_install,
+            Event::PostInstall => &self.post_install,
+        }
+    }
+}
+
+static HOOKS: OnceLock<(Hooks, PathBuf)> = OnceLock::new();
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set from `pyproject.toml`. Only the first call has an effect.
+pub fn configure(hooks: &Hooks, project_path: &Path) {
+    HOOKS.get_or_init(|| (hooks.clone(), project_path.to_owned()));
+}
+
+/// For `--no-hooks`.
+pub fn disable() {
+    DISABLED.store(true, Ordering::SeqCst);
+}
+
+/// Run the commands for `event`. If a pre-hook fails, we abort with its output; if a post-hook
+/// fails, we report it, and carry on.
+pub fn run(event: Event, paths: &util::Paths) {
+    if DISABLED.load(Ordering::SeqCst) {
+        return;
+    }
+    if let Some((hooks, project_path)) = HOOKS.get() {
+        if let Err(e) = run_commands(hooks.commands(event), event, project_path, paths) {
+            if event.is_pre() {
+                util::abort(&e);
+            }
+            util::print_color(&e, Color::Red);
+        }
+    }
+}
+
+fn run_commands(
+    cmds: &[String],
+    event: Event,
+    project_path: &Path,
+    paths: &util::Paths,
+) -> Result<(), String> {
+    for cmd in cmds {
+        let mut path = vec![paths.bin.clone()];
+        if let Some(existing) = std::env::var_os("PATH") {
+            path.extend(std::env::split_paths(&existing));
+        }
+        let start = Instant::now();
+        let output = commands::shell_command(cmd)
+            .current_dir(project_path)
+            .env("PATH", std::env::join_paths(path).unwrap_or_default())
+            .env(
+                "PYTHONPATH",
+                util::pythonpath::value(std::slice::from_ref(&paths.lib)),
+            )
+            .env("PYFLOW_PYTHON", paths.bin.join("python"))
+            .env("PYFLOW_LIB_PATH", &paths.lib)
+            .env("PYFLOW_EVENT", event.name())
+            .output()
+            .map_err(|e| format!("Problem running the {} hook `{}`: {}", event.name(), cmd, e))?;
+
+        if CliConfig::current().verbose {
+            println!(
+                "{} hook `{}` exited with {} in {:.2?}",
+                event.name(),
+                cmd,
+                output.status.code().unwrap_or(-1),
+                start.elapsed()
+            );
+        }
+        let text = [
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr),
+        ]
+        .concat();
+        if !output.status.success() {
+            return Err(format!(
+                "The {} hook `{}` failed with exit code {}:\n{}",
+                event.name(),
+                cmd,
+                output.status.code().unwrap_or(-1),
+                text.trim_end()
+            ));
+        }
+        if !text.trim().is_empty() {
+            println!("{}", text.trim_end());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    #[test]
+    fn hooks_run_in_project_with_env() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let paths = util::Paths {
+            bin: dir.join(".venv/bin"),
+            lib: dir.join("lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        };
+        fs::create_dir_all(&paths.bin).unwrap();
+        fs::write(
+            paths.bin.join("gen-stubs"),
+            "#!/bin/sh\necho stub > stubs.txt\n",
+        )
+        .unwrap();
+        fs::set_permissions(
+            paths.bin.join("gen-stubs"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+
+        let cmds = vec![
+            "gen-stubs".to_owned(),
+            "echo \"$PYFLOW_EVENT $PYFLOW_LIB_PATH\" > event.txt".to_owned(),
+        ];
+        run_commands(&cmds, Event::PostInstall, &dir, &paths).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("stubs.txt")).unwrap(), "stub\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("event.txt")).unwrap(),
+            format!("post_install {}\n", paths.lib.display())
+        );
+
+        // Later commands don't run once one fails.
+        let cmds = vec![
+            "echo 'license not allowed: GPL' && exit 3".to_owned(),
+            "touch after.txt".to_owned(),
+        ];
+        let e = run_commands(&cmds, Event::PreInstall, &dir, &paths).unwrap_err();
+        assert!(e.contains("exit code 3"));
+        assert!(e.contains("license not allowed: GPL"));
+        assert!(!dir.join("after.txt").exists());
+    }
+}
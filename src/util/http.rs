@@ -0,0 +1,174 @@
+//! The HTTP client used for all requests, and descriptions of network errors that point
+//! users to a fix.
+
+use std::{env, fs, sync::OnceLock};
+
+use reqwest::blocking::{Client, Response};
+
+use crate::util::abort;
+
+/// A PEM file of extra root certificates to trust, eg for a corporate proxy.
+pub const CA_BUNDLE_VAR: &str = "PYFLOW_CA_BUNDLE";
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// The shared client. It trusts the OS certificate store, bundled roots, and certificates in
+/// `PYFLOW_CA_BUNDLE`. Proxies are read from `HTTPS_PROXY` etc.
+pub fn client() -> &'static Client {
+    CLIENT.get_or_init(|| {
+        let mut builder = Client::builder();
+        if let Ok(path) = env::var(CA_BUNDLE_VAR) {
+            let pem = fs::read(&path).unwrap_or_else(|_| {
+                abort(&format!("Can't read {}, set by {}", path, CA_BUNDLE_VAR))
+            });
+            let certs = reqwest::Certificate::from_pem_bundle(&pem).unwrap_or_else(|_| {
+                abort(&format!(
+                    "{}, set by {}, isn't a valid PEM file",
+                    path, CA_BUNDLE_VAR
+                ))
+            });
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        builder
+            .build()
+            .unwrap_or_else(|_| abort("Problem setting up the HTTP client"))
+    })
+}
+
+pub fn get(url: &str) -> reqwest::Result<Response> {
+    client().get(url).send()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NetErrorKind {
+    /// We don't trust the server's certificate.
+    Tls,
+    /// We couldn't look up the host.
+    Dns,
+    Timeout,
+    /// We couldn't open a connection, eg it was refused.
+    Connect,
+    Other,
+}
+
+pub fn classify(e: &reqwest::Error) -> NetErrorKind {
+    classify_text(&error_chain(e), e.is_timeout(), e.is_connect())
+}
+
+/// Classify from the error and its sources, since reqwest doesn't expose TLS or DNS failures
+/// directly.
+fn classify_text(chain: &str, timeout: bool, connect: bool) -> NetErrorKind {
+    let chain = chain.to_lowercase();
+    if ["certificate", "unknownissuer", "unknown issuer"]
+        .iter()
+        .any(|s| chain.contains(s))
+    {
+        NetErrorKind::Tls
+    } else if [
+        "dns error",
+        "failed to lookup address",
+        "name or service not known",
+        "no such host",
+    ]
+    .iter()
+    .any(|s| chain.contains(s))
+    {
+        NetErrorKind::Dns
+    } else if timeout || chain.contains("timed out") {
+        NetErrorKind::Timeout
+    } else if connect {
+        NetErrorKind::Connect
+    } else {
+        NetErrorKind::Other
+    }
+}
+
+fn error_chain(e: &reqwest::Error) -> String {
+    let mut chain = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(s) = source {
+        chain.push_str(": ");
+        chain.push_str(&s.to_string());
+        source = s.source();
+    }
+    chain
+}
+
+/// A message for a failed request that names the URL, and what to try.
+pub fn describe(e: &reqwest::Error) -> String {
+    let url = e
+        .url()
+        .map(|u| u.to_string())
+        .unwrap_or_else(|| "the server".into());
+    describe_kind(classify(e), &url, &error_chain(e))
+}
+
+fn describe_kind(kind: NetErrorKind, url: &str, details: &str) -> String {
+    match kind {
+        NetErrorKind::Tls => format!(
+            "Couldn't verify the TLS certificate for {}. This means your system doesn't trust \
+             the server's certificate; it isn't a bug in pyflow. To fix it:\n\
+             - If your network uses its own certificates, set {} to a PEM file containing them\n\
+             - If you're behind a proxy, set `HTTPS_PROXY`\n\
+             - Check that your system clock is correct\n\
+             Details: {}",
+            url, CA_BUNDLE_VAR, details
+        ),
+        NetErrorKind::Dns => format!(
+            "Couldn't look up the address of {}. Check your internet connection and DNS \
+             settings, or set `HTTPS_PROXY` if you're behind a proxy.",
+            url
+        ),
+        NetErrorKind::Timeout => format!(
+            "The request to {} timed out. Please try again, or check your proxy settings.",
+            url
+        ),
+        NetErrorKind::Connect => format!(
+            "Couldn't connect to {}: {}. Is the internet connection ok?",
+            url, details
+        ),
+        NetErrorKind::Other => format!("Problem with the request to {}: {}", url, details),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+        chain,
+        timeout,
+        connect,
+        expected,
+        case(
+            "error sending request: client error (Connect): invalid peer certificate: UnknownIssuer",
+            false,
+            true,
+            NetErrorKind::Tls
+        ),
+        case(
+            "error sending request: dns error: failed to lookup address information",
+            false,
+            true,
+            NetErrorKind::Dns
+        ),
+        case("error sending request: operation timed out", true, false, NetErrorKind::Timeout),
+        case("error sending request: Connection refused", false, true, NetErrorKind::Connect),
+        case("error decoding response body", false, false, NetErrorKind::Other)
+    )]
+    fn classify_errors(chain: &str, timeout: bool, connect: bool, expected: NetErrorKind) {
+        assert_eq!(classify_text(chain, timeout, connect), expected);
+    }
+
+    #[test]
+    fn tls_message_has_remedies() {
+        let msg = describe_kind(NetErrorKind::Tls, "https://pypi.org/pypi/requests/json", "");
+        assert!(msg.contains("https://pypi.org/pypi/requests/json"));
+        assert!(msg.contains(CA_BUNDLE_VAR));
+        assert!(msg.contains("HTTPS_PROXY"));
+        assert!(msg.contains("clock"));
+    }
+}
@@ -0,0 +1,29 @@
+//! The package index we query for releases. It defaults to PyPI; another index with the same
+//! JSON API can be set with `--index-url`, or `index_url` in `[tool.pyflow.settings]`.
+
+use std::sync::OnceLock;
+
+pub const DEFAULT_URL: &str = "https://pypi.org/pypi";
+
+static URL: OnceLock<String> = OnceLock::new();
+
+/// Set the index from the effective settings. Only the first call has an effect.
+pub fn configure(url: &str) {
+    URL.get_or_init(|| url.trim_end_matches('/').to_owned());
+}
+
+/// The index's base URL, without a trailing slash, eg `https://pypi.org/pypi`.
+pub fn url() -> &'static str {
+    URL.get().map_or(DEFAULT_URL, String::as_str)
+}
+
+/// The JSON for one version of a package, eg `https://pypi.org/pypi/requests/2.31.0/json`. Lock
+/// entries record it, after `pypi+`, as their source.
+pub fn json_url(name: &str, version: &str) -> String {
+    format!("{}/{}/{}/json", url(), name, version)
+}
+
+/// Whether we query PyPI. The dependency service only knows about packages on PyPI.
+pub fn is_default() -> bool {
+    url() == DEFAULT_URL
+}
@@ -0,0 +1,260 @@
+//! The packages `find_installed` reads from a lib directory's `dist-info` folders, kept next to
+//! it in `__pypackages__/x.y`. Reading hundreds of folders on each run is slow, so we reuse the
+//! list while lib's modification time and entry count are unchanged; adding or removing a
+//! folder there changes both. Corrupt or out-of-date lists are replaced by a full scan.
+//! The bytes each package installed are kept too, for `pyflow list --sizes`.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{dep_types::Version, util};
+
+/// In the version's folder, eg `__pypackages__/3.11`.
+pub const FILENAME: &str = ".pyflow-state.toml";
+
+type Installed = (String, Version, Vec<String>);
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Package {
+    name: String,
+    version: String,
+    modules: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct State {
+    /// Lib's modification time, in nanoseconds since the Unix epoch.
+    lib_modified: u64,
+    lib_entries: u64,
+    package: Vec<Package>,
+    /// Bytes installed, by `name==version`. Only known for packages pyflow installed.
+    #[serde(default)]
+    sizes: BTreeMap<String, u64>,
+}
+
+fn state_path(lib_path: &Path) -> PathBuf {
+    lib_path.parent().unwrap_or(lib_path).join(FILENAME)
+}
+
+fn size_key(name: &str, version: &Version) -> String {
+    format!("{}=={}", util::standardize_name(name), version)
+}
+
+/// Lib's modification time and number of entries.
+fn stamp(lib_path: &Path) -> Option<(u64, u64)> {
+    let modified = fs::metadata(lib_path)
+        .ok()?
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_nanos();
+    let entries = fs::read_dir(lib_path).ok()?.count();
+    Some((u64::try_from(modified).ok()?, entries as u64))
+}
+
+fn load(lib_path: &Path) -> Option<State> {
+    toml::from_str(&fs::read_to_string(state_path(lib_path)).ok()?).ok()
+}
+
+fn packages(state: &State) -> Option<Vec<Installed>> {
+    state
+        .package
+        .iter()
+        .map(|p| {
+            let version = Version::from_str(&p.version).ok()?;
+            Some((p.name.clone(), version, p.modules.clone()))
+        })
+        .collect()
+}
+
+fn save(lib_path: &Path, installed: &[Installed]) {
+    let (lib_modified, lib_entries) = match stamp(lib_path) {
+        Some(s) => s,
+        None => return,
+    };
+    // Keep the sizes of packages still installed.
+    let mut sizes = load(lib_path).map(|s| s.sizes).unwrap_or_default();
+    sizes.retain(|key, _| installed.iter().any(|(n, v, _)| size_key(n, v) == *key));
+    let state = State {
+        lib_modified,
+        lib_entries,
+        package: installed
+            .iter()
+            .map(|(name, version, modules)| Package {
+                name: name.clone(),
+                version: version.to_string(),
+                modules: modules.clone(),
+            })
+            .collect(),
+        sizes,
+    };
+    write(lib_path, &state);
+}
+
+fn write(lib_path: &Path, state: &State) {
+    if let Ok(data) = toml::to_string(state) {
+        fs::write(state_path(lib_path), data).ok();
+    }
+}
+
+/// The stored list, if lib hasn't changed since it was saved. Otherwise, scan with `scan`, and
+/// store the result.
+fn find_with(lib_path: &Path, scan: impl Fn(&Path) -> Vec<Installed>) -> Vec<Installed> {
+    if let Some(state) = load(lib_path) {
+        if stamp(lib_path) == Some((state.lib_modified, state.lib_entries)) {
+            if let Some(installed) = packages(&state) {
+                return installed;
+            }
+        }
+    }
+    let installed = scan(lib_path);
+    save(lib_path, &installed);
+    installed
+}
+
+/// As `util::scan_installed`, using the stored list if lib hasn't changed.
+pub fn find(lib_path: &Path) -> Vec<Installed> {
+    find_with(lib_path, util::scan_installed)
+}
+
+/// Update the stored list after we install, uninstall, or rename `name`, so the next run doesn't
+/// scan. Only lib's folder names are read; other packages' modules are reused, unless their
+/// `dist-info` folders aren't in the list.
+pub fn update(lib_path: &Path, name: &str, version: &Version) {
+    let state = match load(lib_path) {
+        Some(s) => s,
+        None => return,
+    };
+    let mut known = match packages(&state) {
+        Some(p) => p,
+        None => return,
+    };
+    known.retain(|(n, v, _)| !(util::compare_names(n, name) && v == version));
+
+    let mut installed = Vec::new();
+    for folder in util::find_folders(lib_path) {
+        if let Some((n, v)) = util::parse_dist_info_name(&folder) {
+            let modules = match known.iter().position(|(kn, kv, _)| kn == n && *kv == v) {
+                Some(i) => known.swap_remove(i).2,
+                None => util::top_level_modules(&lib_path.join(&folder), n),
+            };
+            installed.push((n.to_owned(), v, modules));
+        }
+    }
+    save(lib_path, &installed);
+}
+
+/// Store the bytes a package installed. Lib's stamp is left alone; this doesn't change lib.
+pub fn record_size(lib_path: &Path, name: &str, version: &Version, bytes: u64) {
+    if let Some(mut state) = load(lib_path) {
+        state.sizes.insert(size_key(name, version), bytes);
+        write(lib_path, &state);
+    }
+}
+
+/// The bytes a package installed, if pyflow installed it.
+pub fn size(lib_path: &Path, name: &str, version: &Version) -> Option<u64> {
+    load(lib_path)?.sizes.get(&size_key(name, version)).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, thread, time::Duration};
+
+    use crate::install;
+
+    fn add_package(lib: &Path, name: &str, version: &str, module: &str) {
+        let dist_info = lib.join(format!("{}-{}.dist-info", name, version));
+        fs::create_dir_all(&dist_info).unwrap();
+        fs::create_dir_all(lib.join(module)).unwrap();
+        fs::write(lib.join(module).join("__init__.py"), "").unwrap();
+        fs::write(dist_info.join("top_level.txt"), format!("{}\n", module)).unwrap();
+        fs::write(
+            dist_info.join("RECORD"),
+            format!(
+                "{}/__init__.py,,\n{}-{}.dist-info/RECORD,,\n",
+                module, name, version
+            ),
+        )
+        .unwrap();
+    }
+
+    fn setup() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let lib = dir.path().join("__pypackages__/3.11/lib");
+        fs::create_dir_all(&lib).unwrap();
+        add_package(&lib, "python_dateutil", "2.8.2", "dateutil");
+        add_package(&lib, "six", "1.16.0", "six");
+        (dir, lib)
+    }
+
+    #[test]
+    fn scan_skipped_until_lib_changes() {
+        let (dir, lib) = setup();
+        let scans = Cell::new(0);
+        let scan = |lib: &Path| {
+            scans.set(scans.get() + 1);
+            util::scan_installed(lib)
+        };
+
+        let first = find_with(&lib, scan);
+        assert_eq!(first.len(), 2);
+        assert!(dir
+            .path()
+            .join("__pypackages__/3.11")
+            .join(FILENAME)
+            .exists());
+        assert_eq!(find_with(&lib, scan), first);
+        assert_eq!(scans.get(), 1);
+
+        // Installed by something else, eg pip.
+        thread::sleep(Duration::from_millis(20));
+        add_package(&lib, "idna", "3.6", "idna");
+        assert_eq!(find_with(&lib, scan).len(), 3);
+        assert_eq!(scans.get(), 2);
+
+        // A corrupt list is replaced.
+        fs::write(state_path(&lib), "lib_modified = \"soon\"").unwrap();
+        assert_eq!(find_with(&lib, scan).len(), 3);
+        assert_eq!(scans.get(), 3);
+    }
+
+    #[test]
+    fn uninstall_updates_list() {
+        let (dir, lib) = setup();
+        let paths = util::Paths {
+            bin: dir.path().join("bin"),
+            lib: lib.clone(),
+            entry_pt: dir.path().join("bin"),
+            cache: dir.path().join("cache"),
+        };
+        let scans = Cell::new(0);
+        let scan = |lib: &Path| {
+            scans.set(scans.get() + 1);
+            util::scan_installed(lib)
+        };
+        find_with(&lib, scan);
+
+        install::uninstall("six", &Version::new(1, 16, 0), &paths);
+        let stored = packages(&load(&lib).unwrap()).unwrap();
+        assert_eq!(
+            stored,
+            vec![(
+                "python_dateutil".to_owned(),
+                Version::new(2, 8, 2),
+                vec!["dateutil".to_owned()]
+            )]
+        );
+        assert_eq!(find_with(&lib, scan), stored);
+        assert_eq!(scans.get(), 1);
+    }
+}
@@ -0,0 +1,48 @@
+//! The Ctrl-C handler. It can only be installed once per process, so dependency resolution and
+//! `pyflow run --watch` share it.
+
+use std::{
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering},
+        OnceLock,
+    },
+};
+
+use crate::commands;
+
+pub struct InterruptFlags {
+    /// While set, Ctrl-C sets `cancelled` instead of exiting, so resolution can stop cleanly.
+    pub resolving: AtomicBool,
+    pub cancelled: AtomicBool,
+    /// The process group of `--watch`'s current run, which we stop along with ourselves; 0 if
+    /// there isn't one.
+    pub watched: AtomicU32,
+    /// What we exit with on Ctrl-C.
+    pub exit_code: AtomicI32,
+}
+
+pub fn flags() -> &'static InterruptFlags {
+    static FLAGS: OnceLock<InterruptFlags> = OnceLock::new();
+    FLAGS.get_or_init(|| {
+        // Outside of resolution, Ctrl-C exits immediately, as usual.
+        let _ = ctrlc::set_handler(|| {
+            let flags = flags();
+            if flags.resolving.load(Ordering::SeqCst) {
+                flags.cancelled.store(true, Ordering::SeqCst);
+                return;
+            }
+            let watched = flags.watched.swap(0, Ordering::SeqCst);
+            if watched != 0 {
+                commands::stop_group(watched);
+            }
+            process::exit(flags.exit_code.load(Ordering::SeqCst));
+        });
+        InterruptFlags {
+            resolving: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+            watched: AtomicU32::new(0),
+            exit_code: AtomicI32::new(130),
+        }
+    })
+}
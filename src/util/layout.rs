@@ -0,0 +1,363 @@
+//! Versions the layout of pyflow's data directory, so a release that moves things around can
+//! migrate what earlier releases left behind. The version is recorded in `layout_version`,
+//! and each file a migration moves is recorded in `migrations.log`.
+//!
+//! A migration plans its moves from what's on disk, and the marker is only updated after all
+//! of them are done, so running again after a failure picks up where it stopped.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use termcolor::Color;
+
+use crate::{
+    util::{self, guard, paths::PyflowDirs},
+    CliConfig,
+};
+
+/// In the data directory.
+pub const MARKER_FILENAME: &str = "layout_version";
+pub const LOG_FILENAME: &str = "migrations.log";
+const GUARD_FILENAME: &str = ".layout-guard";
+
+const ARCHIVE_EXTENSIONS: &[&str] = &[".whl", ".tar.gz", ".tgz", ".tar.bz2", ".zip"];
+
+/// A file or directory to move.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Move {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+struct Migration {
+    /// The layout version once this has run.
+    version: u32,
+    description: &'static str,
+    plan: fn(&PyflowDirs) -> Vec<Move>,
+}
+
+/// In the order they run.
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    description: "Move downloaded packages into `archives` in the dependency cache",
+    plan: archives_into_subdir,
+}];
+
+/// The layout this version of pyflow expects.
+pub fn current() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Wheels and source dists used to sit directly in the dependency cache, next to the built
+/// wheels and release choices. Their guard files move with them.
+fn archives_into_subdir(dirs: &PyflowDirs) -> Vec<Move> {
+    let entries = match fs::read_dir(&dirs.cache) {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+    let mut result: Vec<_> = entries
+        .flatten()
+        .filter(|entry| entry.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_owned();
+            let archive = name.strip_suffix(".lock").unwrap_or(&name);
+            ARCHIVE_EXTENSIONS
+                .iter()
+                .any(|ext| archive.ends_with(ext))
+                .then(|| Move {
+                    from: entry.path(),
+                    to: util::paths::archive_path(&dirs.cache, &name),
+                })
+        })
+        .collect();
+    result.sort_by(|a, b| a.from.cmp(&b.from));
+    result
+}
+
+fn is_empty(dir: &Path) -> bool {
+    fs::read_dir(dir).map_or(true, |mut entries| entries.next().is_none())
+}
+
+/// The version recorded on disk. Without a marker, an empty data directory is a fresh install,
+/// and anything else predates versioning.
+fn on_disk(dirs: &PyflowDirs) -> u32 {
+    match fs::read_to_string(dirs.data.join(MARKER_FILENAME)) {
+        Ok(data) => data.trim().parse().unwrap_or(0),
+        Err(_) if is_empty(&dirs.data) && is_empty(&dirs.cache) => current(),
+        Err(_) => 0,
+    }
+}
+
+/// Written to a temporary file first, so an interruption can't leave it half-written.
+fn write_marker(data: &Path, version: u32) -> io::Result<()> {
+    fs::create_dir_all(data)?;
+    let tmp = data.join(format!("{}.tmp", MARKER_FILENAME));
+    fs::write(&tmp, format!("{}\n", version))?;
+    fs::rename(&tmp, data.join(MARKER_FILENAME))
+}
+
+/// The migrations still to run from `version`, with what each would move.
+fn pending(dirs: &PyflowDirs, version: u32) -> Vec<(&'static Migration, Vec<Move>)> {
+    MIGRATIONS
+        .iter()
+        .filter(|m| m.version > version)
+        .map(|m| (m, (m.plan)(dirs)))
+        .collect()
+}
+
+fn describe(plan: &[(&Migration, Vec<Move>)]) -> Vec<String> {
+    let mut result = vec![];
+    for (migration, moves) in plan {
+        result.push(format!("{}: {}", migration.version, migration.description));
+        for mv in moves {
+            result.push(format!(
+                "  {} -> {}",
+                mv.from.to_string_lossy(),
+                mv.to.to_string_lossy()
+            ));
+        }
+    }
+    result
+}
+
+fn apply(mv: &Move) -> io::Result<()> {
+    if let Some(parent) = mv.to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // From a run that was interrupted after copying, or a newer pyflow that wrote both.
+    if mv.to.exists() {
+        return if mv.from.is_dir() {
+            fs::remove_dir_all(&mv.from)
+        } else {
+            fs::remove_file(&mv.from)
+        };
+    }
+    fs::rename(&mv.from, &mv.to)
+}
+
+/// Run the migrations after `version`, logging each move as it's made.
+fn migrate(dirs: &PyflowDirs, version: u32) -> io::Result<()> {
+    let mut log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dirs.data.join(LOG_FILENAME))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    for (migration, moves) in pending(dirs, version) {
+        writeln!(
+            log,
+            "{} migration {}: {}",
+            now, migration.version, migration.description
+        )?;
+        for mv in &moves {
+            apply(mv)?;
+            writeln!(
+                log,
+                "  {} -> {}",
+                mv.from.to_string_lossy(),
+                mv.to.to_string_lossy()
+            )?;
+        }
+        write_marker(&dirs.data, migration.version)?;
+    }
+    Ok(())
+}
+
+/// Bring the data directory up to the current layout, or with `skip`, only say what would
+/// change.
+pub fn init(dirs: &PyflowDirs, skip: bool) {
+    let version = on_disk(dirs);
+    if version > current() {
+        util::print_color(
+            &format!(
+                "{} was laid out by a newer version of pyflow (layout {}, this version \
+                 expects {}). Leaving it as it is.",
+                dirs.data.to_string_lossy(),
+                version,
+                current()
+            ),
+            Color::Yellow,
+        );
+        return;
+    }
+    if version == current() {
+        if !dirs.data.join(MARKER_FILENAME).exists() {
+            // A fresh install; not being able to write here is reported where it matters.
+            write_marker(&dirs.data, version).ok();
+        }
+        return;
+    }
+
+    if skip {
+        util::print_color(
+            &format!(
+                "Skipping migrations of {} from layout {} to {}. Packages etc left in their \
+                 old places won't be found:",
+                dirs.data.to_string_lossy(),
+                version,
+                current()
+            ),
+            Color::Yellow,
+        );
+        for line in describe(&pending(dirs, version)) {
+            println!("{}", line);
+        }
+        return;
+    }
+
+    fs::create_dir_all(&dirs.data).unwrap_or_else(|e| {
+        util::abort(&format!(
+            "Problem creating {}: {}",
+            dirs.data.to_string_lossy(),
+            e
+        ))
+    });
+    let _guard = guard::acquire(&dirs.data.join(GUARD_FILENAME), guard::TIMEOUT, |holder| {
+        let holder = holder.map(|h| format!(" ({})", h)).unwrap_or_default();
+        util::print_color(
+            &format!(
+                "Waiting for another pyflow process to migrate {}{}",
+                dirs.data.to_string_lossy(),
+                holder
+            ),
+            Color::Yellow,
+        );
+    })
+    .unwrap_or_else(|_| util::abort("Timed out waiting for another pyflow process to migrate"));
+
+    // Another process may have migrated while we waited.
+    let version = on_disk(dirs);
+    if version >= current() {
+        return;
+    }
+    if CliConfig::current().verbose {
+        for line in describe(&pending(dirs, version)) {
+            println!("{}", line);
+        }
+    }
+    if let Err(e) = migrate(dirs, version) {
+        util::abort(&format!(
+            "Problem migrating {}: {}. Run again to pick up where this stopped, or pass \
+             `--skip-migrations`.",
+            dirs.data.to_string_lossy(),
+            e
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pyflow's dirs, in a `pyflow` folder in `dir` that doesn't exist yet.
+    fn dirs(dir: &Path) -> PyflowDirs {
+        let data = dir.join("pyflow");
+        PyflowDirs {
+            cache: data.join("dependency_cache"),
+            python: data.clone(),
+            script_envs: data.join("script_envs"),
+            git: data.join("git"),
+            data,
+        }
+    }
+
+    fn marker(dirs: &PyflowDirs) -> String {
+        fs::read_to_string(dirs.data.join(MARKER_FILENAME)).unwrap()
+    }
+
+    #[test]
+    fn migrates_old_layout() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dirs = dirs(tmp.path());
+        fs::create_dir_all(dirs.cache.join(util::wheel_cache::DIR_NAME)).unwrap();
+        fs::write(dirs.cache.join("six-1.16.0-py2.py3-none-any.whl"), "wheel").unwrap();
+        fs::write(dirs.cache.join("six-1.16.0-py2.py3-none-any.whl.lock"), "").unwrap();
+        fs::write(dirs.cache.join("fixture-1.0.tar.gz"), "sdist").unwrap();
+        fs::write(dirs.cache.join("notes.txt"), "").unwrap();
+        assert_eq!(on_disk(&dirs), 0);
+
+        init(&dirs, false);
+
+        let archives = dirs.cache.join(util::paths::ARCHIVE_DIR);
+        assert_eq!(
+            fs::read_to_string(archives.join("six-1.16.0-py2.py3-none-any.whl")).unwrap(),
+            "wheel"
+        );
+        assert!(archives
+            .join("six-1.16.0-py2.py3-none-any.whl.lock")
+            .exists());
+        assert!(archives.join("fixture-1.0.tar.gz").exists());
+        assert!(!dirs.cache.join("six-1.16.0-py2.py3-none-any.whl").exists());
+        // Not ours to move.
+        assert!(dirs.cache.join("notes.txt").exists());
+        assert!(dirs.cache.join(util::wheel_cache::DIR_NAME).is_dir());
+
+        assert_eq!(marker(&dirs).trim(), current().to_string());
+        let log = fs::read_to_string(dirs.data.join(LOG_FILENAME)).unwrap();
+        assert!(log.contains("fixture-1.0.tar.gz ->"));
+    }
+
+    #[test]
+    fn resumes_interrupted_migration() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dirs = dirs(tmp.path());
+        let archives = dirs.cache.join(util::paths::ARCHIVE_DIR);
+        fs::create_dir_all(&archives).unwrap();
+        // One archive was moved before the interruption; another was copied, but its original
+        // not yet removed.
+        fs::write(archives.join("a-1.0-py3-none-any.whl"), "a").unwrap();
+        fs::write(archives.join("b-1.0-py3-none-any.whl"), "b").unwrap();
+        fs::write(dirs.cache.join("b-1.0-py3-none-any.whl"), "b").unwrap();
+        fs::write(dirs.cache.join("c-1.0.zip"), "c").unwrap();
+
+        init(&dirs, false);
+
+        for name in &[
+            "a-1.0-py3-none-any.whl",
+            "b-1.0-py3-none-any.whl",
+            "c-1.0.zip",
+        ] {
+            assert!(archives.join(name).exists());
+            assert!(!dirs.cache.join(name).exists());
+        }
+        assert_eq!(marker(&dirs).trim(), current().to_string());
+    }
+
+    #[test]
+    fn skipping_leaves_layout() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dirs = dirs(tmp.path());
+        fs::create_dir_all(&dirs.cache).unwrap();
+        fs::write(dirs.cache.join("fixture-1.0.tar.gz"), "sdist").unwrap();
+
+        let plan = describe(&pending(&dirs, 0));
+        assert_eq!(plan.len(), 2);
+        init(&dirs, true);
+
+        assert!(dirs.cache.join("fixture-1.0.tar.gz").exists());
+        assert!(!dirs.data.join(MARKER_FILENAME).exists());
+    }
+
+    #[test]
+    fn fresh_and_newer_layouts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dirs = dirs(tmp.path());
+        init(&dirs, false);
+        assert_eq!(marker(&dirs).trim(), current().to_string());
+        assert!(!dirs.data.join(LOG_FILENAME).exists());
+
+        fs::create_dir_all(&dirs.cache).unwrap();
+        fs::write(dirs.cache.join("fixture-1.0.tar.gz"), "sdist").unwrap();
+        write_marker(&dirs.data, current() + 1).unwrap();
+        init(&dirs, false);
+        assert!(dirs.cache.join("fixture-1.0.tar.gz").exists());
+        assert_eq!(marker(&dirs).trim(), (current() + 1).to_string());
+    }
+}
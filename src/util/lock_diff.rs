@@ -0,0 +1,302 @@
+//! What changed in `pyflow.lock`, shown after installing, and by `pyflow lock --check`.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, IsTerminal},
+    str::FromStr,
+};
+
+use serde::Serialize;
+use termcolor::{Color, ColorChoice};
+
+use crate::{
+    dep_types::{LockPackage, Req, Version},
+    util::{self, dep_graph::LockDep},
+    CliConfig,
+};
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum LockChange {
+    Added {
+        name: String,
+        version: String,
+        via: Option<String>,
+    },
+    Removed {
+        name: String,
+        version: String,
+    },
+    Upgraded {
+        name: String,
+        from: String,
+        to: String,
+        via: Option<String>,
+    },
+    Downgraded {
+        name: String,
+        from: String,
+        to: String,
+        via: Option<String>,
+    },
+}
+
+/// Changes between two locks, sorted by package name.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct LockDiff {
+    pub changes: Vec<LockChange>,
+}
+
+/// Versions of each package, keyed by standardized name. A package may be locked at several
+/// versions, eg for different Python versions.
+fn versions_by_name(packs: &[LockPackage]) -> BTreeMap<String, (String, Vec<String>)> {
+    let mut result: BTreeMap<String, (String, Vec<String>)> = BTreeMap::new();
+    for lp in packs {
+        let entry = result
+            .entry(util::standardize_name(&lp.name))
+            .or_insert_with(|| (lp.name.clone(), vec![]));
+        if !entry.1.contains(&lp.version) {
+            entry.1.push(lp.version.clone());
+        }
+    }
+    for (_, versions) in result.values_mut() {
+        versions.sort_by_key(|v| Version::from_str(v).ok());
+    }
+    result
+}
+
+/// What brought a package into the lock: the project's requirement on it, or the package
+/// that depends on it.
+fn find_via(name: &str, packs: &[LockPackage], reqs: &[Req]) -> Option<String> {
+    if let Some(req) = reqs.iter().find(|r| util::compare_names(&r.name, name)) {
+        let constrs: Vec<String> = req.constraints.iter().map(|c| c.to_string()).collect();
+        return Some(
+            format!("{} {}", req.name, constrs.join(","))
+                .trim_end()
+                .to_owned(),
+        );
+    }
+    packs
+        .iter()
+        .find(|lp| {
+            lp.dependencies
+                .iter()
+                .flatten()
+                .any(|d| util::compare_names(&LockDep::parse(d).name, name))
+        })
+        .map(|lp| format!("{} {}", lp.name, lp.version))
+}
+
+impl LockDiff {
+    pub fn new(old: &[LockPackage], new: &[LockPackage], reqs: &[Req]) -> Self {
+        let old_versions = versions_by_name(old);
+        let new_versions = versions_by_name(new);
+        let mut changes = vec![];
+
+        for (key, (name, versions)) in &new_versions {
+            let via = || find_via(name, new, reqs);
+            let joined = versions.join(", ");
+            match old_versions.get(key) {
+                None => changes.push(LockChange::Added {
+                    name: name.clone(),
+                    version: joined,
+                    via: via(),
+                }),
+                Some((_, old_vers)) if old_vers != versions => {
+                    let newest = |vs: &[String]| vs.last().and_then(|v| Version::from_str(v).ok());
+                    let from = old_vers.join(", ");
+                    if newest(versions) >= newest(old_vers) {
+                        changes.push(LockChange::Upgraded {
+                            name: name.clone(),
+                            from,
+                            to: joined,
+                            via: via(),
+                        });
+                    } else {
+                        changes.push(LockChange::Downgraded {
+                            name: name.clone(),
+                            from,
+                            to: joined,
+                            via: via(),
+                        });
+                    }
+                }
+                Some(_) => (),
+            }
+        }
+        for (key, (name, versions)) in &old_versions {
+            if !new_versions.contains_key(key) {
+                changes.push(LockChange::Removed {
+                    name: name.clone(),
+                    version: versions.join(", "),
+                });
+            }
+        }
+        changes.sort_by_key(|c| util::standardize_name(c.name()));
+        Self { changes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// One line per change, with names and versions aligned in columns.
+    fn lines(&self) -> Vec<(String, Color)> {
+        let name_width = self
+            .changes
+            .iter()
+            .map(|c| c.name().len())
+            .max()
+            .unwrap_or(0);
+        self.changes
+            .iter()
+            .map(|c| {
+                let (sign, verb, versions, via, color) = match c {
+                    LockChange::Added { version, via, .. } => {
+                        ("+", "added", version.clone(), via, Color::Green)
+                    }
+                    LockChange::Removed { version, .. } => {
+                        ("-", "removed", version.clone(), &None, Color::Red)
+                    }
+                    LockChange::Upgraded { from, to, via, .. } => (
+                        "~",
+                        "upgraded",
+                        format!("{} → {}", from, to),
+                        via,
+                        Color::Yellow,
+                    ),
+                    LockChange::Downgraded { from, to, via, .. } => (
+                        "~",
+                        "downgraded",
+                        format!("{} → {}", from, to),
+                        via,
+                        Color::Yellow,
+                    ),
+                };
+                let mut line = format!(
+                    "{} {:<10} {:<width$}  {}",
+                    sign,
+                    verb,
+                    c.name(),
+                    versions,
+                    width = name_width
+                );
+                if let Some(via) = via {
+                    line.push_str(&format!(" (via {})", via));
+                }
+                (line, color)
+            })
+            .collect()
+    }
+
+    /// Print the changes, in color unless disabled or not printing to a terminal. With
+    /// `--format json`, print them as JSON instead.
+    pub fn print(&self, heading: &str) {
+        let cli = CliConfig::current();
+        if cli.json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(self).expect("Problem serializing lock changes")
+            );
+            return;
+        }
+        let colored = match cli.color_choice {
+            ColorChoice::Never => false,
+            ColorChoice::Auto => io::stdout().is_terminal(),
+            _ => true,
+        };
+        println!("{}", heading);
+        for (line, color) in self.lines() {
+            if colored {
+                util::print_color(&line, color);
+            } else {
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+impl LockChange {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Added { name, .. }
+            | Self::Removed { name, .. }
+            | Self::Upgraded { name, .. }
+            | Self::Downgraded { name, .. } => name,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack(name: &str, version: &str, dependencies: &[&str]) -> LockPackage {
+        LockPackage {
+            id: 0,
+            name: name.to_owned(),
+            version: version.to_owned(),
+            source: None,
+            dependencies: Some(dependencies.iter().map(|d| (*d).to_owned()).collect()),
+            rename: None,
+            marker: None,
+            modules: None,
+            files: None,
+            extras: None,
+            wheel: None,
+        }
+    }
+
+    #[test]
+    fn changes_keyed_by_standardized_name() {
+        let old = vec![
+            pack("requests", "2.28.0", &["urllib3 1.26.0 https://pypi.org"]),
+            pack("urllib3", "1.26.0", &[]),
+            pack("Flask", "2.0.0", &[]),
+            pack("six", "1.16.0", &[]),
+        ];
+        let new = vec![
+            pack("requests", "2.31.0", &["urllib3 2.0.4 https://pypi.org"]),
+            pack("urllib3", "2.0.4", &[]),
+            pack("flask", "2.0.0", &[]),
+            pack("idna", "3.4", &[]),
+        ];
+        let reqs = vec![Req::from_str("requests>=2.30", true).unwrap()];
+        let diff = LockDiff::new(&old, &new, &reqs);
+
+        assert_eq!(
+            diff.changes,
+            vec![
+                LockChange::Added {
+                    name: "idna".into(),
+                    version: "3.4".into(),
+                    via: None,
+                },
+                LockChange::Upgraded {
+                    name: "requests".into(),
+                    from: "2.28.0".into(),
+                    to: "2.31.0".into(),
+                    via: Some("requests >=2.30".into()),
+                },
+                LockChange::Removed {
+                    name: "six".into(),
+                    version: "1.16.0".into(),
+                },
+                LockChange::Upgraded {
+                    name: "urllib3".into(),
+                    from: "1.26.0".into(),
+                    to: "2.0.4".into(),
+                    via: Some("requests 2.31.0".into()),
+                },
+            ]
+        );
+
+        let lines: Vec<String> = diff.lines().into_iter().map(|(l, _)| l).collect();
+        assert_eq!(lines[0], "+ added      idna      3.4");
+        assert_eq!(
+            lines[3],
+            "~ upgraded   urllib3   1.26.0 → 2.0.4 (via requests 2.31.0)"
+        );
+        assert!(LockDiff::new(&new, &new, &reqs).is_empty());
+    }
+}
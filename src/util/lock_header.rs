@@ -0,0 +1,373 @@
+//! What a lock was resolved from, recorded in its `[metadata]` table: the pyflow version and lock
+//! format that wrote it, the resolution, the Python versions and platforms it was resolved for,
+//! and a hash of `pyproject.toml`'s dependency tables. Values are strings, as older versions of
+//! pyflow expect, so they can still read locks with them.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use ring::digest;
+use termcolor::Color;
+
+use crate::{
+    dep_resolution::Resolution,
+    dep_types::Version,
+    pyproject::CFG_FILENAME,
+    util::{self, abort, print_color, Os},
+};
+
+/// Raised when a lock written in this format would be misread by an older pyflow. Locks from
+/// before it was recorded are format 1.
+pub const LOCK_FORMAT: u32 = 2;
+
+const FORMAT_KEY: &str = "lock_format";
+const VERSION_KEY: &str = "pyflow_version";
+const PYTHONS_KEY: &str = "python_versions";
+const PLATFORMS_KEY: &str = "platforms";
+const HASH_KEY: &str = "dependencies_hash";
+
+/// The tables in `pyproject.toml` that locking depends on.
+const DEP_TABLES: [&[&str]; 7] = [
+    &["tool", "pyflow", "dependencies"],
+    &["tool", "pyflow", "dev-dependencies"],
+    &["project", "dependencies"],
+    &["project", "optional-dependencies"],
+    &["tool", "poetry", "dependencies"],
+    &["tool", "poetry", "dev-dependencies"],
+    &["tool", "poetry", "group"],
+];
+
+static ALLOW_NEWER: AtomicBool = AtomicBool::new(false);
+
+/// From `--allow-newer-lock`: Use locks in a newer format than this version of pyflow writes.
+pub fn allow_newer() {
+    ALLOW_NEWER.store(true, Ordering::Relaxed);
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    /// `None` if the lock is from before it was recorded.
+    pub pyflow_version: Option<String>,
+    pub format: u32,
+    pub resolution: Resolution,
+    pub pythons: Vec<String>,
+    /// eg `linux`, or a wheel platform tag like `manylinux2014_x86_64`.
+    pub platforms: Vec<String>,
+    /// sha256 of `pyproject.toml`'s dependency tables.
+    pub dependencies_hash: Option<String>,
+}
+
+/// The resolution the lock at `lock_path` was made with, so changes to it keep picking versions
+/// the same way. The default if there's no lock.
+pub fn resolution(lock_path: &Path) -> Resolution {
+    util::read_lock(lock_path)
+        .map(|lock| Resolution::from_lock(&lock.metadata))
+        .unwrap_or_default()
+}
+
+/// eg `linux`.
+pub fn platform_name(os: Os) -> String {
+    format!("{:?}", os).to_lowercase()
+}
+
+fn split_list(value: Option<&String>) -> Vec<String> {
+    value
+        .map(|v| {
+            v.split(',')
+                .map(|s| s.trim().to_owned())
+                .filter(|s| !s.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// A hash of the dependency tables in the `pyproject.toml` next to the lock, so we can tell when
+/// they've changed since locking. Formatting and comments don't change it. `None` if there's no
+/// `pyproject.toml`, or it won't parse.
+pub fn dependencies_hash(lock_path: &Path) -> Option<String> {
+    let data = fs::read_to_string(lock_path.with_file_name(CFG_FILENAME)).ok()?;
+    let cfg: toml::Value = toml::from_str(&data).ok()?;
+
+    let mut contents = String::new();
+    for table in DEP_TABLES {
+        let value = table.iter().try_fold(&cfg, |value, key| value.get(key));
+        if let Some(value) = value {
+            // Tables are sorted by key, so reordering entries doesn't change this either.
+            contents.push_str(&format!("{} = {}\n", table.join("."), value));
+        }
+    }
+    let digest = digest::digest(&digest::SHA256, contents.as_bytes());
+    Some(data_encoding::HEXLOWER.encode(digest.as_ref()))
+}
+
+impl Header {
+    /// For a lock being resolved now, from the `pyproject.toml` next to `lock_path`.
+    pub fn new(
+        lock_path: &Path,
+        resolution: Resolution,
+        pythons: &[Version],
+        platforms: &[String],
+    ) -> Self {
+        Self {
+            pyflow_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            format: LOCK_FORMAT,
+            resolution,
+            pythons: pythons.iter().map(|v| v.to_string()).collect(),
+            platforms: platforms.to_vec(),
+            dependencies_hash: dependencies_hash(lock_path),
+        }
+    }
+
+    /// From a lock's metadata. Missing values are those of locks from before they were recorded.
+    pub fn from_lock(metadata: &HashMap<String, String>) -> Self {
+        Self {
+            pyflow_version: metadata.get(VERSION_KEY).cloned(),
+            format: metadata
+                .get(FORMAT_KEY)
+                .and_then(|f| f.parse().ok())
+                .unwrap_or(1),
+            resolution: Resolution::from_lock(metadata),
+            pythons: split_list(metadata.get(PYTHONS_KEY)),
+            platforms: split_list(metadata.get(PLATFORMS_KEY)),
+            dependencies_hash: metadata.get(HASH_KEY).cloned(),
+        }
+    }
+
+    /// Mark a lock's metadata with this header.
+    pub fn mark(&self, metadata: &mut HashMap<String, String>) {
+        stamp(metadata);
+        self.resolution.mark(metadata);
+        metadata.insert(PYTHONS_KEY.into(), self.pythons.join(", "));
+        metadata.insert(PLATFORMS_KEY.into(), self.platforms.join(", "));
+        match &self.dependencies_hash {
+            Some(hash) => metadata.insert(HASH_KEY.into(), hash.clone()),
+            None => metadata.remove(HASH_KEY),
+        };
+    }
+
+    /// If `pyproject.toml`'s dependency tables changed since the lock was resolved. Locks that
+    /// didn't record them never are.
+    pub fn is_stale(&self, lock_path: &Path) -> bool {
+        match &self.dependencies_hash {
+            Some(hash) => dependencies_hash(lock_path).is_some_and(|current| &current != hash),
+            None => false,
+        }
+    }
+
+    /// If a newer version of pyflow wrote the lock; it may resolve differently from this one.
+    pub fn written_by_newer(&self) -> bool {
+        let parse = |v: &str| Version::from_str(v).ok();
+        match (
+            self.pyflow_version.as_deref().and_then(parse),
+            parse(env!("CARGO_PKG_VERSION")),
+        ) {
+            (Some(written), Some(current)) => written > current,
+            _ => false,
+        }
+    }
+
+    /// Why the lock may not suit installing on `os` with Python `py_vers`: it was made with a
+    /// resolution that doesn't pick the newest versions, by a newer pyflow, or for other Python
+    /// versions or platforms.
+    pub fn warnings(&self, os: Os, py_vers: &Version) -> Vec<String> {
+        let mut warnings = vec![];
+        if self.resolution != Resolution::Highest {
+            warnings.push(format!(
+                "`pyflow.lock` was made with `--resolution {}`, so installs older versions than it \
+                 could, eg to test lower bounds. Run `pyflow lock --resolution highest` to lock the \
+                 newest ones.",
+                self.resolution.as_str()
+            ));
+        }
+        if self.written_by_newer() {
+            warnings.push(format!(
+                "`pyflow.lock` was written by pyflow {}, which is newer than this one, {}, and may \
+                 resolve differently. Upgrade pyflow to lock with the same behavior.",
+                self.pyflow_version.as_deref().unwrap_or_default(),
+                env!("CARGO_PKG_VERSION")
+            ));
+        }
+
+        let python_locked = self.pythons.iter().any(|p| {
+            Version::from_str(p).is_ok_and(|v| v.major == py_vers.major && v.minor == py_vers.minor)
+        });
+        if !self.pythons.is_empty() && !python_locked {
+            warnings.push(format!(
+                "`pyflow.lock` was resolved for Python {}, not this environment's {}, so packages \
+                 only it needs may be missing. Add it with `pyflow lock --python`.",
+                self.pythons.join(", "),
+                py_vers
+            ));
+        }
+        let platform_locked = self
+            .platforms
+            .iter()
+            .any(|p| Os::from_str(&p.to_lowercase()).is_ok_and(|p| p == os));
+        if !self.platforms.is_empty() && !platform_locked {
+            warnings.push(format!(
+                "`pyflow.lock` was resolved for {}, not {}, so packages only this platform needs \
+                 may be missing.",
+                self.platforms.join(", "),
+                platform_name(os)
+            ));
+        }
+        warnings
+    }
+}
+
+/// Record the pyflow version and lock format writing a lock. Done on each write, since the lock
+/// is written in this format whatever it was read in.
+pub fn stamp(metadata: &mut HashMap<String, String>) {
+    metadata.insert(VERSION_KEY.into(), env!("CARGO_PKG_VERSION").into());
+    metadata.insert(FORMAT_KEY.into(), LOCK_FORMAT.to_string());
+}
+
+/// Stop if the lock is in a newer format than this version of pyflow understands, since we'd
+/// misread it, then overwrite it in the older format. Unless `--allow-newer-lock` was passed.
+pub fn refuse_newer(header: &Header) {
+    if header.format <= LOCK_FORMAT {
+        return;
+    }
+    let msg = format!(
+        "`pyflow.lock` was written by pyflow {}, in lock format {}; this version of pyflow, {}, \
+         only understands up to format {}.",
+        header.pyflow_version.as_deref().unwrap_or("(unknown)"),
+        header.format,
+        env!("CARGO_PKG_VERSION"),
+        LOCK_FORMAT
+    );
+    if !ALLOW_NEWER.load(Ordering::Relaxed) {
+        abort(&format!(
+            "{} Upgrade pyflow, or pass `--allow-newer-lock` to use it anyway.",
+            msg
+        ));
+    }
+    print_color(
+        &format!(
+            "{} Using it anyway; it may be rewritten in the older format.",
+            msg
+        ),
+        Color::Yellow,
+    );
+}
+
+/// Warn if `pyproject.toml`'s dependencies changed since the lock was resolved.
+pub fn warn_if_stale(header: &Header, lock_path: &Path) {
+    if header.is_stale(lock_path) {
+        print_color(
+            "`pyproject.toml`'s dependencies changed since `pyflow.lock` was resolved, so it may \
+             be out of date.",
+            Color::Yellow,
+        );
+    }
+}
+
+/// Record `pyproject.toml`'s dependency tables in a lock that still satisfies them after they
+/// changed, eg a loosened constraint, so it isn't reported as stale. Locks that didn't record
+/// them are left alone.
+pub fn refresh(lock_path: &Path) {
+    let mut lock = match util::read_lock(lock_path) {
+        Ok(l) => l,
+        Err(_) => return,
+    };
+    if !Header::from_lock(&lock.metadata).is_stale(lock_path) {
+        return;
+    }
+    if let Some(hash) = dependencies_hash(lock_path) {
+        lock.metadata.insert(HASH_KEY.into(), hash);
+        util::write_lock(lock_path, &lock).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dep_types::Lock;
+
+    /// Write `cfg` to `pyproject.toml` in `dir`, returning the path of the lock next to it.
+    fn project(dir: &Path, cfg: &str) -> std::path::PathBuf {
+        fs::write(dir.join(CFG_FILENAME), cfg).unwrap();
+        dir.join("pyflow.lock")
+    }
+
+    #[test]
+    fn header_round_trips_through_old_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = project(
+            dir.path(),
+            "[tool.pyflow]\nname = \"proj\"\n\n[tool.pyflow.dependencies]\nrequests = \"^2.31\"\n",
+        );
+        let header = Header::new(
+            &lock_path,
+            Resolution::Lowest,
+            &[Version::new_short(3, 11), Version::new_short(3, 12)],
+            &[platform_name(Os::Linux)],
+        );
+        let mut lock = Lock::default();
+        header.mark(&mut lock.metadata);
+        let written = toml::to_string(&lock).unwrap();
+        assert_eq!(
+            Header::from_lock(&toml::from_str::<Lock>(&written).unwrap().metadata),
+            header
+        );
+        assert_eq!(header.platforms, ["linux"]);
+
+        // Older versions of pyflow read the metadata as strings, and ignore unknown keys.
+        let metadata: HashMap<String, String> = toml::from_str::<toml::Value>(&written).unwrap()
+            ["metadata"]
+            .clone()
+            .try_into()
+            .unwrap();
+        assert_eq!(metadata["lock_format"], LOCK_FORMAT.to_string());
+
+        // Locks from before the header.
+        let old = Header::from_lock(&HashMap::new());
+        assert!(!old.is_stale(&lock_path));
+        assert!(!old.written_by_newer());
+        assert_eq!(
+            (old.format, old.resolution, old.pyflow_version),
+            (1, Resolution::Highest, None)
+        );
+        let newer = Header {
+            pyflow_version: Some("999.0.0".into()),
+            ..header.clone()
+        };
+        assert!(newer.written_by_newer());
+    }
+
+    #[test]
+    fn stale_when_dependencies_change() {
+        let cfg = "[tool.pyflow]\nname = \"proj\"\n\n[tool.pyflow.dependencies]\n\
+                   requests = \"^2.31\"\nnumpy = \"^1.26\"\n";
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = project(dir.path(), cfg);
+        let header = Header::new(&lock_path, Resolution::Highest, &[], &[]);
+        assert!(!header.is_stale(&lock_path));
+
+        // Formatting, order, and other keys don't matter.
+        let reformatted = "# Deps\n[tool.pyflow]\nname = \"renamed\"\n\n\
+                           [tool.pyflow.dependencies]\nnumpy = '^1.26'\n  requests = \"^2.31\"\n";
+        fs::write(lock_path.with_file_name(CFG_FILENAME), reformatted).unwrap();
+        assert!(!header.is_stale(&lock_path));
+
+        fs::write(
+            lock_path.with_file_name(CFG_FILENAME),
+            cfg.replace("^2.31", ">=2.31"),
+        )
+        .unwrap();
+        assert!(header.is_stale(&lock_path));
+
+        // A lock that still satisfies them records them again.
+        let mut lock = Lock::default();
+        header.mark(&mut lock.metadata);
+        util::write_lock(&lock_path, &lock).unwrap();
+        refresh(&lock_path);
+        let refreshed = Header::from_lock(&util::read_lock(&lock_path).unwrap().metadata);
+        assert!(!refreshed.is_stale(&lock_path));
+    }
+}
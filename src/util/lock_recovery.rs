@@ -0,0 +1,161 @@
+//! Backups of `pyflow.lock`, and what to do when it won't parse, eg after a crash. Starting
+//! fresh would silently drop every pin, so we restore the backup, or stop, unless passed
+//! `--ignore-corrupt-lock`.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use termcolor::Color;
+
+use crate::{
+    dep_types::Lock,
+    util::{abort, print_color, prompts},
+};
+
+static IGNORE_CORRUPT: AtomicBool = AtomicBool::new(false);
+
+/// From `--ignore-corrupt-lock`: Start a new lock if the current one won't parse.
+pub fn ignore_corrupt() {
+    IGNORE_CORRUPT.store(true, Ordering::Relaxed);
+}
+
+/// eg `pyflow.lock.bak`
+pub fn backup_path(lock_path: &Path) -> PathBuf {
+    lock_path.with_extension("lock.bak")
+}
+
+fn parse(path: &Path) -> Option<Lock> {
+    toml::from_str(&fs::read_to_string(path).ok()?).ok()
+}
+
+/// Copy `from` to `to` through a temporary file, so an interrupted copy doesn't leave part of it.
+fn copy_atomic(from: &Path, to: &Path) -> io::Result<()> {
+    let tmp_path = to.with_extension("bak.tmp");
+    fs::copy(from, &tmp_path)?;
+    fs::rename(&tmp_path, to)
+}
+
+/// Back up the lock before it's rewritten. A lock that won't parse isn't backed up, so it can't
+/// replace a good backup.
+pub fn backup(lock_path: &Path) {
+    if parse(lock_path).is_some() {
+        copy_atomic(lock_path, &backup_path(lock_path)).ok();
+    }
+}
+
+/// Read the lock. `Ok(None)` if there isn't one, or it's corrupt and `ignore_corrupt` is set. If
+/// it's corrupt, and `restore` agrees, it's replaced by its backup. Otherwise, the error says why
+/// we won't continue.
+pub fn load_with(
+    lock_path: &Path,
+    ignore_corrupt: bool,
+    restore: impl FnOnce(&Path) -> bool,
+) -> Result<Option<Lock>, String> {
+    let data = match fs::read_to_string(lock_path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Problem reading `pyflow.lock`: {}", e)),
+    };
+    let err = match toml::from_str(&data) {
+        Ok(lock) => return Ok(Some(lock)),
+        Err(e) => e,
+    };
+
+    print_color(
+        &format!(
+            "`pyflow.lock` is corrupt, eg from an interrupted run:\n{}",
+            err
+        ),
+        Color::Red,
+    );
+    if ignore_corrupt {
+        print_color(
+            "Ignoring it, and locking again from `pyproject.toml`.",
+            Color::Yellow,
+        );
+        return Ok(None);
+    }
+
+    let backup = backup_path(lock_path);
+    if let Some(lock) = parse(&backup) {
+        if restore(&backup) {
+            if copy_atomic(&backup, lock_path).is_err() {
+                return Err(format!("Problem restoring {}", backup.display()));
+            }
+            print_color(
+                &format!("Restored `pyflow.lock` from {}", backup.display()),
+                Color::Green,
+            );
+            return Ok(Some(lock));
+        }
+        return Err(format!(
+            "Not continuing, so its pins aren't lost. Copy {} over it, fix it, or pass \
+             `--ignore-corrupt-lock` to lock again.",
+            backup.display()
+        ));
+    }
+    Err(
+        "Not continuing, so its pins aren't lost. Fix it, or pass `--ignore-corrupt-lock` to \
+         lock again."
+            .into(),
+    )
+}
+
+/// As `load_with`, asking before restoring the backup; without a terminal to ask on, we don't.
+pub fn load(lock_path: &Path) -> Option<Lock> {
+    let ignore = IGNORE_CORRUPT.load(Ordering::Relaxed);
+    load_with(lock_path, ignore, |backup| {
+        prompts::interactive()
+            && prompts::confirm(
+                &format!("Restore it from {}?", backup.display()),
+                "Pass `--ignore-corrupt-lock` to lock again.",
+            )
+    })
+    .unwrap_or_else(|e| abort(&e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+
+    const LOCK: &str = r#"
+[metadata]
+
+[[package]]
+id = 1
+name = "six"
+version = "1.16.0"
+dependencies = []
+"#;
+
+    #[test]
+    fn corrupt_lock_refused_then_restored() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let lock_path = dir.join("pyflow.lock");
+
+        assert!(load_with(&lock_path, false, |_| true).unwrap().is_none());
+
+        // Rewriting the lock backs up the current one.
+        fs::write(&lock_path, LOCK).unwrap();
+        util::write_lock(&lock_path, &toml::from_str(LOCK).unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(backup_path(&lock_path)).unwrap(), LOCK);
+
+        // Truncated by a crash.
+        fs::write(&lock_path, &LOCK[..LOCK.len() - 20]).unwrap();
+        let err = load_with(&lock_path, false, |_| false).unwrap_err();
+        assert!(err.contains("--ignore-corrupt-lock"));
+        assert!(parse(&lock_path).is_none());
+
+        let restored = load_with(&lock_path, false, |_| true).unwrap().unwrap();
+        assert_eq!(restored.package.unwrap()[0].name, "six");
+        assert_eq!(fs::read_to_string(&lock_path).unwrap(), LOCK);
+
+        fs::write(&lock_path, "[[package]\n").unwrap();
+        assert!(load_with(&lock_path, true, |_| true).unwrap().is_none());
+    }
+}
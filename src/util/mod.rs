@@ -1,63 +1,66 @@
-<<<<<<< HEAD:src/util.rs
-use std::{
-    collections::HashMap,
-    env, fs,
-    io::{self, BufRead, BufReader, Read, Write},
-    path::{Path, PathBuf},
-    process,
-    str::FromStr,
-    thread, time,
-};
-
-use ini::Ini;
-use regex::Regex;
-use serde::Deserialize;
-use tar::Archive;
-use termcolor::{Color, ColorSpec, StandardStream, WriteColor};
-use xz2::read::XzDecoder;
-=======
+pub mod archive_limits;
+pub mod cache_policy;
+pub mod cross_target;
+pub mod dep_graph;
 pub mod deps;
+pub mod deps_service;
+pub mod dotenv;
+pub mod editable;
+pub mod find_links;
+pub mod fs_errors;
+pub mod guard;
+pub mod hooks;
+pub mod http;
+pub mod index;
+pub mod installed_cache;
+pub mod interrupt;
+pub mod layout;
+pub mod lock_diff;
+pub mod lock_header;
+pub mod lock_recovery;
+pub mod nesting;
 pub mod paths;
+pub mod prefetch;
 pub mod prompts;
+pub mod py_probes;
+pub mod pythonpath;
+pub mod requires_python;
+pub mod scripts;
+pub mod shadowing;
+pub mod source_build;
+pub mod timings;
+pub mod watch;
+pub mod wheel_cache;
+pub mod wheel_tag;
 
 mod os;
 pub use os::{get_os, Os};
 
-#[mockall_double::double]
-use crate::dep_resolution::res;
->>>>>>> 4c6ec9bc8dcf2c486d5820627d70162e44d6b5a7:src/util/mod.rs
-
 use crate::{
     commands,
-<<<<<<< HEAD:src/util.rs
-    dep_resolution::{res, WarehouseRelease},
-    dep_types::{Constraint, DependencyError, Extras, Req, ReqType, Version},
-=======
-    dep_types::{Constraint, DependencyError, Lock, Req, ReqType, Version},
->>>>>>> 4c6ec9bc8dcf2c486d5820627d70162e44d6b5a7:src/util/mod.rs
+    dep_resolution::{WarehouseDigests, WarehouseRelease},
+    dep_types::{Constraint, DependencyError, Lock, LockFile, Req, Version},
     files,
     install::{self, PackageType},
-    py_versions, util, CliConfig,
+    py_versions::{self, VenvHealth},
+    util, CliConfig,
 };
-<<<<<<< HEAD:src/util.rs
-=======
 use ini::Ini;
 use regex::Regex;
 
 use std::fs;
-use std::io::{self, BufRead, BufReader, Read, Write};
+use std::io::{self, Read, Write};
 use std::path::Component;
 use std::str::FromStr;
 use std::{
-    env,
     error::Error,
+    fmt,
     path::{Path, PathBuf},
     process, thread, time,
 };
 use tar::Archive;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 use xz2::read::XzDecoder;
->>>>>>> 4c6ec9bc8dcf2c486d5820627d70162e44d6b5a7:src/util/mod.rs
 
 #[derive(Debug)]
 pub struct Paths {
@@ -69,6 +72,7 @@ pub struct Paths {
 
 /// Used to store a Wheel's metadata, from dist-info/METADATA
 #[derive(Debug, Default)]
+#[allow(dead_code)]
 pub struct Metadata {
     pub name: String,
     pub summary: Option<String>,
@@ -76,9 +80,17 @@ pub struct Metadata {
     pub author: Option<String>,
     pub author_email: Option<String>,
     pub license: Option<String>,
+    /// An SPDX expression, eg `MIT OR Apache-2.0`. Newer than `License`.
+    pub license_expression: Option<String>,
+    /// Trove classifiers for the license, eg `License :: OSI Approved :: MIT License`.
+    pub license_classifiers: Vec<String>,
     pub keywords: Vec<String>,
     pub platform: Option<String>,
     pub requires_dist: Vec<Req>,
+    pub provides_extra: Vec<String>,
+    pub requires_python: Option<String>,
+    /// `Requires-Dist` lines we couldn't parse.
+    pub warnings: Vec<String>,
 }
 
 /// Print line in a color, then reset formatting.
@@ -89,6 +101,7 @@ pub fn print_color(message: &str, color: Color) {
 }
 
 fn print_color_res(message: &str, color: Color) -> io::Result<()> {
+    crate::crash::log(message);
     let mut stdout = StandardStream::stdout(CliConfig::current().color_choice);
     stdout.set_color(ColorSpec::new().set_fg(Some(color)))?;
     writeln!(&mut stdout, "{}", message)?;
@@ -104,6 +117,7 @@ pub fn print_color_(message: &str, color: Color) {
 }
 
 fn print_color_res_(message: &str, color: Color) -> io::Result<()> {
+    crate::crash::log(message);
     let mut stdout = StandardStream::stdout(CliConfig::current().color_choice);
     stdout.set_color(ColorSpec::new().set_fg(Some(color)))?;
     write!(&mut stdout, "{}", message)?;
@@ -116,12 +130,19 @@ fn print_color_res_(message: &str, color: Color) -> io::Result<()> {
 /// We use `expect`, `panic!` etc for problems that indicate a bug in this program.
 pub fn abort(message: &str) -> ! {
     print_color(message, Color::Red);
-    process::exit(1)
+    exit(1)
 }
 
 pub fn success(message: &str) {
     print_color(message, Color::Green);
-    process::exit(0)
+    exit(0)
+}
+
+/// Exit with `code`, first releasing the guards we hold, so the next run doesn't report this
+/// one as interrupted.
+pub fn exit(code: i32) -> ! {
+    guard::release_all();
+    process::exit(code)
 }
 
 /// Find which virtual environments exist.
@@ -148,7 +169,7 @@ pub fn find_venvs(pypackages_dir: &Path) -> Vec<(u32, u32)> {
 
     let mut result = vec![];
     for (maj, mi) in py_versions.iter() {
-        let venv_path = pypackages_dir.join(&format!("{}.{}/.venv", maj, mi));
+        let venv_path = pypackages_dir.join(format!("{}.{}/.venv", maj, mi));
 
         if venv_path.join("bin/python").exists() || venv_path.join("Scripts/python.exe").exists() {
             result.push((*maj, *mi))
@@ -191,64 +212,238 @@ pub fn wait_for_dirs(dirs: &[PathBuf]) -> Result<(), crate::py_versions::AliasEr
     })
 }
 
-/// Sets the `PYTHONPATH` environment variable, causing Python to look for
-/// dependencies in `__pypackages__`,
-pub fn set_pythonpath(paths: &[PathBuf]) {
-    let formatted_paths = paths
-        .iter()
-        .map(|p| p.to_str().unwrap())
-        .collect::<Vec<&str>>()
-        .join(":");
-    env::set_var("PYTHONPATH", formatted_paths);
-}
-
-/// Find the packages installed, by browsing the lib folder for metadata.
+/// Find the packages installed, by browsing the lib folder for metadata, or the list stored
+/// when it last changed.
 /// Returns package-name, version, folder names
 pub fn find_installed(lib_path: &Path) -> Vec<(String, Version, Vec<String>)> {
     if !lib_path.exists() {
         return vec![];
     }
+    installed_cache::find(lib_path)
+}
 
+/// As `find_installed`, reading each package's metadata.
+pub fn scan_installed(lib_path: &Path) -> Vec<(String, Version, Vec<String>)> {
     let mut result = vec![];
 
     for folder_name in &find_folders(lib_path) {
-        let re_dist = Regex::new(r"^(.*?)-(.*?)\.dist-info$").unwrap();
-
-        if let Some(caps) = re_dist.captures(folder_name) {
-            let name = caps.get(1).unwrap().as_str();
-            let vers = Version::from_str(
-                caps.get(2)
-                    .expect("Problem parsing version in folder name")
-                    .as_str(),
-            )
-            .expect("Problem parsing version in package folder");
+        if let Some((name, vers)) = parse_dist_info_name(folder_name) {
+            let tops = top_level_modules(&lib_path.join(folder_name), name);
 
-            let top_level = lib_path.join(folder_name).join("top_level.txt");
+            result.push((name.to_owned(), vers, tops));
+        }
+    }
+    result
+}
 
-            let mut tops = vec![];
-            match fs::File::open(top_level) {
-                Ok(f) => {
-                    for line in BufReader::new(f).lines().flatten() {
-                        tops.push(line);
-                    }
+/// The package name and version from a `dist-info` folder's name.
+pub fn parse_dist_info_name(folder_name: &str) -> Option<(&str, Version)> {
+    let re_dist = Regex::new(r"^(.*?)-(.*?)\.dist-info$").unwrap();
+    let caps = re_dist.captures(folder_name)?;
+    let vers = Version::from_str(caps.get(2).unwrap().as_str())
+        .expect("Problem parsing version in package folder");
+    Some((caps.get(1).unwrap().as_str(), vers))
+}
+/// Read the paths of a package's installed files from its `RECORD`, relative to `lib`.
+pub fn record_paths(dist_info_path: &Path) -> Vec<String> {
+    record_entries(dist_info_path)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect()
+}
+
+/// Read each path in a package's `RECORD`, with its hash, eg `sha256=<urlsafe base64>`. The
+/// hash is empty for files not hashed, like `RECORD` itself.
+pub fn record_entries(dist_info_path: &Path) -> Vec<(String, String)> {
+    let data = match fs::read_to_string(dist_info_path.join("RECORD")) {
+        Ok(d) => d,
+        Err(_) => return vec![],
+    };
+
+    data.lines()
+        .filter_map(|line| {
+            // RECORD is a CSV of path, hash, and size. Paths containing commas are quoted.
+            let (path, rest) = match line.strip_prefix('"') {
+                Some(quoted) => {
+                    let (path, rest) = quoted.split_once("\",").unwrap_or((quoted, ""));
+                    (path.replace("\"\"", "\""), rest)
                 }
-                Err(_) => tops.push(folder_name.to_owned()),
+                None => {
+                    let (path, rest) = line.split_once(',').unwrap_or((line, ""));
+                    (path.to_owned(), rest)
+                }
+            };
+            if path.is_empty() {
+                return None;
             }
+            let hash = rest.split(',').next().unwrap_or_default();
+            Some((path.replace('\\', "/"), hash.to_owned()))
+        })
+        .collect()
+}
 
-            result.push((name.to_owned(), vers, tops));
+/// Find the top-level modules a package's `RECORD` installs: the first component of each
+/// path, skipping metadata, `.data` trees, and files outside `lib`.
+fn modules_from_record(paths: &[String]) -> Vec<String> {
+    let mut result: Vec<String> = vec![];
+    for path in paths {
+        let mut components = path.split('/');
+        let first = components.next().unwrap_or_default();
+        let is_file = components.next().is_none();
+
+        if first.is_empty()
+            || first.starts_with("..")
+            || first == "__pycache__"
+            || first.ends_with(".dist-info")
+            || first.ends_with(".egg-info")
+            || first.ends_with(".data")
+            // Shared libraries vendored by `auditwheel` and `delvewheel`, eg `pillow.libs`.
+            || first.ends_with(".libs")
+        {
+            continue;
+        }
+
+        let module = if is_file {
+            // A single-file module, or a compiled extension like
+            // `foo.cpython-311-x86_64-linux-gnu.so`. Other files here, like `.pth` files,
+            // aren't importable.
+            if first.ends_with(".py") || first.ends_with(".so") || first.ends_with(".pyd") {
+                first.split('.').next().unwrap()
+            } else {
+                continue;
+            }
+        } else {
+            first
+        };
+
+        if !result.iter().any(|m| m == module) {
+            result.push(module.to_owned());
         }
     }
+    result.sort();
     result
 }
-/// Handle reqs added via the CLI. Result is (normal reqs, dev reqs)
+
+/// Find the importable top-level modules of an installed package, from `top_level.txt`. Many
+/// wheels don't include it, so fall back to the files listed in `RECORD`, then to the
+/// package name.
+pub fn top_level_modules(dist_info_path: &Path, name: &str) -> Vec<String> {
+    if let Ok(data) = fs::read_to_string(dist_info_path.join("top_level.txt")) {
+        let tops: Vec<String> = data
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if !tops.is_empty() {
+            return tops;
+        }
+    }
+
+    let from_record = modules_from_record(&record_paths(dist_info_path));
+    if !from_record.is_empty() {
+        return from_record;
+    }
+    vec![name.to_lowercase().replace('-', "_")]
+}
+
+/// Find the top-level modules in a wheel before installing it, eg to rename them. As with
+/// installed packages, prefer `top_level.txt`, then the paths of its files.
+pub fn wheel_modules(file: &fs::File) -> Vec<String> {
+    let mut archive = match zip::ZipArchive::new(file) {
+        Ok(a) => a,
+        Err(_) => return vec![],
+    };
+    let names: Vec<String> = archive.file_names().map(str::to_owned).collect();
+
+    let top_level = names
+        .iter()
+        .find(|n| n.ends_with(".dist-info/top_level.txt"))
+        .cloned();
+    if let Some(top_level) = top_level {
+        let mut data = String::new();
+        if let Ok(mut f) = archive.by_name(&top_level) {
+            f.read_to_string(&mut data).ok();
+        }
+        let tops: Vec<String> = data
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if !tops.is_empty() {
+            return tops;
+        }
+    }
+    modules_from_record(&names)
+}
+
+/// How a req added via the CLI changes one already in `pyproject.toml`.
+#[derive(Debug, PartialEq)]
+enum ReqChange {
+    New,
+    /// The same constraints, or none, which keeps the existing ones.
+    Unchanged,
+    /// `disjoint` if no version satisfies both the old and new constraints.
+    Replaced {
+        disjoint: bool,
+    },
+}
+
+fn req_change(added: &Req, existing: &[Req]) -> ReqChange {
+    match existing
+        .iter()
+        .find(|r| compare_names(&r.name, &added.name))
+    {
+        None => ReqChange::New,
+        Some(r) if added.git.is_some() && r.git != added.git => {
+            ReqChange::Replaced { disjoint: false }
+        }
+        Some(r) if added.wheel_tag.is_some() && r.wheel_tag != added.wheel_tag => {
+            ReqChange::Replaced { disjoint: false }
+        }
+        Some(r) if added.constraints.is_empty() || r.constraints == added.constraints => {
+            ReqChange::Unchanged
+        }
+        Some(r) => {
+            let both = [r.constraints.as_slice(), added.constraints.as_slice()].concat();
+            ReqChange::Replaced {
+                disjoint: crate::dep_types::intersection_many(&both).is_empty(),
+            }
+        }
+    }
+}
+
+fn constraints_str(req: &Req) -> String {
+    if let Some(git) = &req.git {
+        return format!("git+{}", git);
+    }
+    if req.constraints.is_empty() {
+        return "*".into();
+    }
+    req.constraints
+        .iter()
+        .map(|c| c.to_string2(true, false))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Handle reqs added via the CLI. Result is (normal reqs, dev reqs). Replacing a req's
+/// constraints with ones that don't overlap them requires `force`, or confirmation.
 pub fn merge_reqs(
     added: &[String],
     dev: bool,
+    force: bool,
+    wheel_tag: Option<&str>,
     cfg: &crate::Config,
     cfg_path: &Path,
 ) -> (Vec<Req>, Vec<Req>) {
     let mut added_reqs = vec![];
     for p in added.iter() {
+        if let Some(r) = install::git_req_from_arg(p) {
+            added_reqs.push(r);
+            continue;
+        }
         let trimmed = p.replace(',', "");
         match Req::from_str(&trimmed, false) {
             Ok(r) => added_reqs.push(r),
@@ -260,53 +455,59 @@ pub fn merge_reqs(
 
     let existing = if dev { &cfg.dev_reqs } else { &cfg.reqs };
 
+    if let Some(tag) = wheel_tag {
+        match added_reqs.as_mut_slice() {
+            [req] if req.git.is_none() => {
+                // Keep the constraints it has, if none were passed.
+                if req.constraints.is_empty() {
+                    if let Some(r) = existing.iter().find(|r| compare_names(&r.name, &req.name)) {
+                        req.constraints = r.constraints.clone();
+                    }
+                }
+                req.wheel_tag = Some(tag.to_owned());
+            }
+            _ => abort(
+                "`--wheel-tag` pins the wheel of one package from PyPI, eg \
+                 `pyflow install \"numpy==1.26.2\" --wheel-tag cp311-cp311-manylinux_2_28_x86_64`",
+            ),
+        }
+    }
+
     // Reqs to add to `pyproject.toml`
-    let mut added_reqs_unique: Vec<Req> = added_reqs
-        .into_iter()
-        .filter(|ar| {
-            // return true if the added req's not in the cfg reqs, or if it is
-            // and the version's different.
-            let mut add = true;
-
-            for cr in existing.iter() {
-                if cr == ar
-                    || (cr.name.to_lowercase() == ar.name.to_lowercase()
-                        && ar.constraints.is_empty())
+    let mut added_reqs_unique = vec![];
+    for ar in added_reqs {
+        match req_change(&ar, existing) {
+            ReqChange::New => added_reqs_unique.push(ar),
+            ReqChange::Unchanged => (),
+            ReqChange::Replaced { disjoint } => {
+                let old = existing
+                    .iter()
+                    .find(|r| compare_names(&r.name, &ar.name))
+                    .map(constraints_str)
+                    .unwrap_or_default();
+                print_color(
+                    &format!(
+                        "{} is already required as `{}`; `pyproject.toml` will be updated to `{}`",
+                        ar.name,
+                        old,
+                        constraints_str(&ar)
+                    ),
+                    Color::Yellow,
+                );
+                if disjoint
+                    && !force
+                    && !prompts::confirm(
+                        "No version satisfies both. Replace the old constraint?",
+                        "Pass `--force` to replace it.",
+                    )
                 {
-                    // Same req/version exists
-                    add = false;
-                    break;
+                    abort(&format!(
+                        "Keeping `{}` for {}. Pass `--force` to replace it",
+                        old, ar.name
+                    ));
                 }
+                added_reqs_unique.push(ar);
             }
-            add
-        })
-        .collect();
-
-    // If no constraints are specified, use a caret constraint with the latest
-    // version.
-    for added_req in &mut added_reqs_unique {
-        if added_req.constraints.is_empty() {
-            let (_, vers, _) = if let Ok(r) = res::get_version_info(
-                &added_req.name,
-                Some(Req::new_with_extras(
-                    added_req.name.clone(),
-                    vec![Constraint::new_any()],
-                    Extras::new_py(Constraint::new(
-                        ReqType::Exact,
-                        cfg.py_version.clone().unwrap_or_else(Version::new_any),
-                    )),
-                )),
-            ) {
-                r
-            } else {
-                abort("Problem getting latest version of the package you added. Is it spelled correctly? Is the internet OK?")
-            };
-
-            added_req.constraints.push(Constraint::new(
-                ReqType::Caret,
-                //                Version::new(vers.major, vers.minor, vers.patch),
-                vers,
-            ));
         }
     }
 
@@ -317,7 +518,9 @@ pub fn merge_reqs(
     for cr in existing.iter() {
         let mut replaced = false;
         for added_req in &added_reqs_unique {
-            if compare_names(&added_req.name, &cr.name) && added_req.constraints != cr.constraints {
+            if compare_names(&added_req.name, &cr.name)
+                && (added_req.constraints != cr.constraints || added_req.git != cr.git)
+            {
                 result.push(added_req.clone());
                 replaced = true;
                 break;
@@ -328,7 +531,12 @@ pub fn merge_reqs(
         }
     }
 
-    result.append(&mut added_reqs_unique.clone());
+    result.extend(
+        added_reqs_unique
+            .iter()
+            .filter(|ar| !existing.iter().any(|cr| compare_names(&cr.name, &ar.name)))
+            .cloned(),
+    );
 
     if dev {
         if !added_reqs_unique.is_empty() {
@@ -344,7 +552,7 @@ pub fn merge_reqs(
 }
 
 pub fn standardize_name(name: &str) -> String {
-    name.to_lowercase().replace('-', "_").replace('.', "_")
+    name.to_lowercase().replace(['-', '.'], "_")
 }
 
 // PyPi naming isn't consistent; it capitalization and _ vs -
@@ -352,16 +560,87 @@ pub fn compare_names(name1: &str, name2: &str) -> bool {
     standardize_name(name1) == standardize_name(name2)
 }
 
-/// Extract the wheel or zip.
+/// Extract the wheel or zip at `archive_path`, within the limits in `archive_limits`. Returns
+/// the number of bytes written. If writing fails, eg as the disk is full, what was extracted
+/// so far is removed, and the error returned.
 /// From [this example](https://github.com/mvdnes/zip-rs/blob/master/examples/extract.rs#L32)
 pub fn extract_zip(
     file: &fs::File,
+    archive_path: &Path,
     out_path: &Path,
     rename: &Option<(String, String)>,
     package_names: &Option<(&str, &str)>,
-) {
+) -> Result<u64, fs_errors::FsError> {
+    let mut budget = archive_limits::Budget::new();
+    let mut created = vec![];
+    match unpack_zip(
+        file,
+        out_path,
+        rename,
+        package_names,
+        &mut budget,
+        &mut created,
+    ) {
+        Ok(()) => Ok(budget.used()),
+        Err(UnpackError::Refused(e)) => abort(&format!(
+            "Refusing to unpack {}: {}",
+            archive_path.display(),
+            e
+        )),
+        Err(UnpackError::Fs(e)) => {
+            // Newest first, so folders are empty by the time we reach them.
+            for path in created.iter().rev() {
+                if path.is_dir() {
+                    fs::remove_dir(path).ok();
+                } else {
+                    fs::remove_file(path).ok();
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+/// A size for display, eg `312 MB`.
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1_000_000_000 => format!("{:.1} GB", b as f64 / 1e9),
+        b if b >= 1_000_000 => format!("{} MB", (b as f64 / 1e6).round()),
+        b if b >= 1_000 => format!("{} kB", (b as f64 / 1e3).round()),
+        b => format!("{} B", b),
+    }
+}
+
+/// Why unpacking an archive stopped.
+enum UnpackError {
+    /// It's unsafe, or over a limit.
+    Refused(String),
+    Fs(fs_errors::FsError),
+}
+
+impl From<String> for UnpackError {
+    fn from(e: String) -> Self {
+        Self::Refused(e)
+    }
+}
+
+impl From<fs_errors::FsError> for UnpackError {
+    fn from(e: fs_errors::FsError) -> Self {
+        Self::Fs(e)
+    }
+}
+
+/// Unpack into `out_path`, adding the files and folders we create to `created`.
+fn unpack_zip(
+    file: &fs::File,
+    out_path: &Path,
+    rename: &Option<(String, String)>,
+    package_names: &Option<(&str, &str)>,
+    budget: &mut archive_limits::Budget,
+    created: &mut Vec<PathBuf>,
+) -> Result<(), UnpackError> {
     // Separate function, since we use it twice.
-    let mut archive = if let Ok(a) = zip::ZipArchive::new(file) {
+    let mut archive = if let Ok(a) = zip::ZipArchive::new(io::BufReader::new(file)) {
         a
     } else {
         abort(&format!(
@@ -372,9 +651,16 @@ pub fn extract_zip(
 
     for i in 0..archive.len() {
         let mut file = archive.by_index(i).unwrap();
+        let entry_name = file.name().to_owned();
+        archive_limits::check_ratio(&entry_name, file.compressed_size(), file.size())?;
         // Change name here instead of after in case we've already installed a non-renamed version.
         // (which would be overwritten by this one.)
-        let entry_path = file.enclosed_name().unwrap();
+        let entry_path = file.enclosed_name().ok_or_else(|| {
+            format!(
+                "the entry `{}` is written outside the folder it's unpacked into",
+                entry_name
+            )
+        })?;
         let mut final_entry_path = PathBuf::with_capacity(entry_path.as_os_str().len());
         // The `hexdump` Python package intentionally strips its own root folder from its zip source
         // distribution, which breaks wheel building. As a workaround, add the package name and version
@@ -409,18 +695,31 @@ pub fn extract_zip(
                 Ok(final_entry_path)
             };
 
-        let outpath = out_path.join(extracted_file.unwrap());
+        let extracted_file = extracted_file.unwrap();
+        archive_limits::check_path(&entry_name, &extracted_file)?;
+        let outpath = out_path.join(extracted_file);
 
-        if (&*file.name()).ends_with('/') {
-            fs::create_dir_all(&outpath).unwrap();
+        if file.name().ends_with('/') {
+            create_dirs(&outpath, created)?;
         } else {
             if let Some(p) = outpath.parent() {
-                if !p.exists() {
-                    fs::create_dir_all(&p).unwrap();
+                create_dirs(p, created)?;
+            }
+            let mut outfile = fs::File::create(&outpath)
+                .map_err(|e| fs_errors::FsError::new("creating", &outpath, e))?;
+            created.push(outpath.clone());
+            match budget.copy(&entry_name, &mut file, &mut outfile) {
+                Ok(()) => (),
+                Err(archive_limits::CopyError::Refused(e)) => return Err(e.into()),
+                Err(archive_limits::CopyError::Io(e))
+                    if fs_errors::classify(&e) != fs_errors::FsErrorKind::Other =>
+                {
+                    return Err(fs_errors::FsError::new("writing", &outpath, e).into())
+                }
+                Err(archive_limits::CopyError::Io(e)) => {
+                    return Err(format!("problem writing the entry `{}`: {}", entry_name, e).into())
                 }
             }
-            let mut outfile = fs::File::create(&outpath).unwrap();
-            io::copy(&mut file, &mut outfile).unwrap();
         }
 
         // Get and Set permissions
@@ -429,32 +728,42 @@ pub fn extract_zip(
             use std::os::unix::fs::PermissionsExt;
 
             if let Some(mode) = file.unix_mode() {
-                fs::set_permissions(&outpath, fs::Permissions::from_mode(mode)).unwrap();
+                fs::set_permissions(&outpath, fs::Permissions::from_mode(mode))
+                    .map_err(|e| fs_errors::FsError::new("setting permissions on", &outpath, e))?;
             }
         }
     }
+    Ok(())
 }
 
-pub fn unpack_tar_xz(archive_path: &Path, dest: &Path) {
-    let archive_bytes = fs::read(archive_path).expect("Problem reading archive as bytes");
+/// Create `dir` and any missing parents, adding those we create to `created`, outermost first.
+fn create_dirs(dir: &Path, created: &mut Vec<PathBuf>) -> Result<(), fs_errors::FsError> {
+    let missing: Vec<&Path> = dir.ancestors().take_while(|p| !p.exists()).collect();
+    for path in missing.into_iter().rev() {
+        match fs::create_dir(path) {
+            Ok(()) => created.push(path.to_owned()),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(fs_errors::FsError::new("creating", path, e)),
+        }
+    }
+    Ok(())
+}
 
-    let mut tar: Vec<u8> = Vec::new();
-    let mut decompressor = XzDecoder::new(&archive_bytes[..]);
-    if decompressor.read_to_end(&mut tar).is_err() {
+/// A `.tar.xz` archive, decompressed as it's read, so neither it nor the tar it contains is held
+/// in memory.
+fn open_tar_xz(archive_path: &Path) -> io::Result<Archive<XzDecoder<io::BufReader<fs::File>>>> {
+    let file = fs::File::open(archive_path)?;
+    Ok(Archive::new(XzDecoder::new(io::BufReader::new(file))))
+}
+
+/// Unpack a `.tar.xz`, eg a Python installation.
+pub fn unpack_tar_xz(archive_path: &Path, dest: &Path) {
+    if let Err(e) = open_tar_xz(archive_path).and_then(|mut a| a.unpack(dest)) {
         abort(&format!(
-            "Problem decompressing the archive: {:?}. This may be due to a failed download. \
+            "Problem unpacking the archive: {:?}: {}. This may be due to a failed download. \
         Try deleting it, then try again. Note that Pyflow will only install officially-released \
         Python versions. If you'd like to use a pre-release, you must install it manually.",
-            archive_path
-        ))
-    }
-
-    // We've decompressed the .xz; now unpack the tar.
-    let mut archive = Archive::new(&tar[..]);
-    if archive.unpack(dest).is_err() {
-        abort(&format!(
-            "Problem unpacking tar: {}",
-            archive_path.to_str().unwrap()
+            archive_path, e
         ))
     }
 }
@@ -465,6 +774,8 @@ pub fn find_or_create_venv(
     pypackages_dir: &Path,
     pyflow_dir: &Path,
     dep_cache_path: &Path,
+    build_tools: &[Req],
+    interpreter: Option<&Path>,
 ) -> (PathBuf, Version) {
     let venvs = find_venvs(pypackages_dir);
     // The version's explicitly specified; check if an environment for that version
@@ -477,17 +788,63 @@ pub fn find_or_create_venv(
     let py_vers;
     match compatible_venvs.len() {
         0 => {
-            let vers =
-                py_versions::create_venv(cfg_vers, pypackages_dir, pyflow_dir, dep_cache_path);
+            let vers = py_versions::create_venv(
+                cfg_vers,
+                pypackages_dir,
+                pyflow_dir,
+                dep_cache_path,
+                build_tools,
+                interpreter,
+            );
             vers_path = pypackages_dir.join(vers.to_string_med());
             py_vers = Version::new_opt(vers.major, vers.minor, None); // Don't include patch.
         }
         1 => {
-            vers_path = pypackages_dir.join(&format!(
+            vers_path = pypackages_dir.join(format!(
                 "{}.{}",
                 compatible_venvs[0].0, compatible_venvs[0].1
             ));
             py_vers = Version::new_short(compatible_venvs[0].0, compatible_venvs[0].1);
+
+            let rebuild = CliConfig::current().rebuild_venv;
+            match py_versions::check_venv(&vers_path) {
+                VenvHealth::Broken(msg) if !rebuild => {
+                    print_color(&msg, Color::Yellow);
+                    if !prompts::confirm(
+                        "Rebuild the environment? Installed packages are kept.",
+                        "Pass `--rebuild-venv` to rebuild it.",
+                    ) {
+                        abort("This environment can't run. Rebuild it with `--rebuild-venv`.")
+                    }
+                    rebuild_venv(
+                        cfg_vers,
+                        &vers_path,
+                        pyflow_dir,
+                        dep_cache_path,
+                        build_tools,
+                        interpreter,
+                    );
+                }
+                VenvHealth::Updated(old, new) if !rebuild => {
+                    print_color(
+                        &format!(
+                            "This environment's Python was updated from {} to {}",
+                            old, new
+                        ),
+                        Color::Green,
+                    );
+                    py_versions::record_interpreter_update(&vers_path, &new);
+                }
+                _ if rebuild => rebuild_venv(
+                    cfg_vers,
+                    &vers_path,
+                    pyflow_dir,
+                    dep_cache_path,
+                    build_tools,
+                    interpreter,
+                ),
+                _ => (),
+            }
         }
         _ => {
             abort(
@@ -508,13 +865,10 @@ pub fn find_or_create_venv(
         let vers_path = fs::canonicalize(vers_path);
         let vers_path = match vers_path {
             Ok(path) => path,
-            Err(error) => {
-                abort(&format!(
-                    "Problem converting path to absolute path: {:?}",
-                    error
-                ));
-                unreachable!()
-            }
+            Err(error) => abort(&format!(
+                "Problem converting path to absolute path: {:?}",
+                error
+            )),
         };
         (vers_path, py_vers)
     }
@@ -533,6 +887,33 @@ pub fn find_or_create_venv(
     }
 }
 
+/// Recreate a venv's `.venv` folder. `lib` is kept, and console scripts in `bin` don't
+/// refer to the interpreter, so they keep working.
+fn rebuild_venv(
+    cfg_vers: &Version,
+    vers_path: &Path,
+    pyflow_dir: &Path,
+    dep_cache_path: &Path,
+    build_tools: &[Req],
+    interpreter: Option<&Path>,
+) {
+    let venv_path = vers_path.join(".venv");
+    if venv_path.exists() && fs::remove_dir_all(&venv_path).is_err() {
+        abort(&format!("Problem removing {}", venv_path.to_string_lossy()))
+    }
+    let pypackages_dir = vers_path
+        .parent()
+        .expect("Problem finding `__pypackages__`");
+    py_versions::create_venv(
+        cfg_vers,
+        pypackages_dir,
+        pyflow_dir,
+        dep_cache_path,
+        build_tools,
+        interpreter,
+    );
+}
+
 ///// Remove all files (but not folders) in a path.
 //pub fn wipe_dir(path: &Path) {
 //    if !path.exists() {
@@ -577,98 +958,289 @@ fn os_from_wheel_fname(filename: &str) -> Result<Os, DependencyError> {
     Err(DependencyError::new("Problem parsing os from wheel name"))
 }
 
-/// Find the most appropriate release to download. Ie Windows vs Linux, wheel vs source.
+/// Find the platform tag from a release's filename, eg `manylinux1_x86_64` or `any`. Source
+/// releases are `source`.
+pub fn platform_tag(filename: &str) -> String {
+    match filename.strip_suffix(".whl") {
+        Some(stem) => stem.rsplit('-').next().unwrap_or(stem).to_owned(),
+        None => "source".to_owned(),
+    }
+}
+
+/// Why `find_best_release` skipped a release file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RejectReason {
+    /// The wheel's platform tag, and the OS we're installing for.
+    Platform(String, Os),
+    /// The release's `requires_python`.
+    RequiresPython(String, Version),
+    /// The wheel's python tag, eg `cp38`.
+    PythonTag(String, Version),
+    PackageType(String),
+    /// Another wheel is pinned with `wheel-tag`.
+    NotPinned(String),
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Platform(tag, os) => {
+                write!(
+                    f,
+                    "platform {} != {}",
+                    tag,
+                    format!("{:?}", os).to_lowercase()
+                )
+            }
+            Self::RequiresPython(constrs, py_vers) => {
+                write!(f, "requires_python {} excludes Python {}", constrs, py_vers)
+            }
+            Self::PythonTag(tag, py_vers) => {
+                write!(f, "python tag {} doesn't match Python {}", tag, py_vers)
+            }
+            Self::PackageType(type_) => write!(f, "unsupported package type {}", type_),
+            Self::NotPinned(tag) => write!(f, "doesn't match wheel-tag {}", tag),
+        }
+    }
+}
+
+/// The release `find_best_release` picked, and the files it skipped.
+#[derive(Clone, Debug)]
+pub struct ReleaseChoice {
+    pub release: WarehouseRelease,
+    pub package_type: PackageType,
+    /// Filenames, and why each was skipped.
+    pub rejected: Vec<(String, RejectReason)>,
+}
+
+/// Determine if a wheel is appropriate for the Os and Python version; if not, why.
+fn wheel_rejection(rel: &WarehouseRelease, os: Os, python_vers: &Version) -> Option<RejectReason> {
+    let wheel_os = os_from_wheel_fname(&rel.filename).expect("Problem getting os from wheel name");
+    let platform = platform_tag(&rel.filename);
+    if wheel_os != os && wheel_os != Os::Any || !cross_target::wheel_matches(&platform) {
+        return Some(RejectReason::Platform(platform, os));
+    }
+
+    if let Some(py_ver) = &rel.requires_python {
+        // If a version constraint exists, make sure it's compatible.
+        let py_constrs = Constraint::from_str_multiple(py_ver)
+            .expect("Problem parsing constraint from requires_python");
+
+        if !py_constrs.iter().all(|c| c.is_compatible(python_vers)) {
+            return Some(RejectReason::RequiresPython(
+                py_ver.clone(),
+                python_vers.clone(),
+            ));
+        }
+    }
+
+    // Packages that use C code(eg numpy) may fail to load C extensions if installing
+    // for the wrong version of python (eg  cp35 when python 3.7 is installed), even
+    // if `requires_python` doesn't indicate an incompatibility. Check `python_version`
+    // instead of `requires_python`.
+    // Note that the result of this parse is an any match. If we can't parse it, rely on
+    // `requires_python`, checked above.
+    if let Ok(constrs) = Constraint::from_wh_py_vers(&rel.python_version) {
+        if !constrs.iter().any(|c| c.is_compatible(python_vers)) {
+            return Some(RejectReason::PythonTag(
+                rel.python_version.clone(),
+                python_vers.clone(),
+            ));
+        }
+    } else if CliConfig::current().verbose {
+        println!(
+            "Unrecognized python_version {} for {}; relying on requires_python",
+            &rel.python_version, &rel.filename
+        )
+    };
+    None
+}
+
+fn wheel_compatible(rel: &WarehouseRelease, os: Os, python_vers: &Version) -> bool {
+    wheel_rejection(rel, os, python_vers).is_none()
+}
+
+/// Find the most appropriate release to download. Ie Windows vs Linux, wheel vs source. With
+/// a `wheel_tag`, only the wheel with that tag is picked.
 pub fn find_best_release(
     data: &[WarehouseRelease],
     name: &str,
     version: &Version,
     os: Os,
     python_vers: &Version,
-) -> (WarehouseRelease, PackageType) {
+    wheel_tag: Option<&str>,
+) -> ReleaseChoice {
+    if let Some(tag) = wheel_tag {
+        let filename =
+            wheel_tag::find(data.iter().map(|r| r.filename.as_str()), name, version, tag)
+                .unwrap_or_else(|e| abort(&e));
+        let release = data
+            .iter()
+            .find(|r| r.filename == filename)
+            .unwrap()
+            .clone();
+        wheel_tag::check(&release, name, os, python_vers).unwrap_or_else(|e| abort(&e));
+        return ReleaseChoice {
+            rejected: data
+                .iter()
+                .filter(|r| r.filename != filename)
+                .map(|r| (r.filename.clone(), RejectReason::NotPinned(tag.to_owned())))
+                .collect(),
+            release,
+            package_type: PackageType::Wheel,
+        };
+    }
+
     // Find which release we should download. Preferably wheels, and if so, for the right OS and
     // Python version.
     let mut compatible_releases = vec![];
     // Store source releases as a fallback, for if no wheels are found.
     let mut source_releases = vec![];
+    let mut rejected = vec![];
 
     for rel in data.iter() {
-        let mut compatible = true;
         match rel.packagetype.as_ref() {
-            "bdist_wheel" => {
-                // Now determine if this wheel is appropriate for the Os and Python version.
-                if let Some(py_ver) = &rel.requires_python {
-                    // If a version constraint exists, make sure it's compatible.
-                    let py_constrs = Constraint::from_str_multiple(py_ver)
-                        .expect("Problem parsing constraint from requires_python");
-
-                    for constr in &py_constrs {
-                        if !constr.is_compatible(python_vers) {
-                            compatible = false;
-                        }
-                    }
-                }
+            "bdist_wheel" => match wheel_rejection(rel, os, python_vers) {
+                Some(reason) => rejected.push((rel.filename.clone(), reason)),
+                None => compatible_releases.push(rel.clone()),
+            },
+            "sdist" => source_releases.push(rel.clone()),
+            // Don't execute Windows installers
+            _ => rejected.push((
+                rel.filename.clone(),
+                RejectReason::PackageType(rel.packagetype.clone()),
+            )),
+        }
+    }
 
-                let wheel_os =
-                    os_from_wheel_fname(&rel.filename).expect("Problem getting os from wheel name");
-                if wheel_os != os && wheel_os != Os::Any {
-                    compatible = false;
-                }
+    // todo: Sort further / try to match exact python_version if able.
+    let (release, package_type) = if let Some(wheel) = compatible_releases.first() {
+        (wheel.clone(), PackageType::Wheel)
+    } else if let Some(source) = source_releases
+        .first()
+        .filter(|_| !cross_target::wheels_only())
+    {
+        (source.clone(), PackageType::Source)
+    } else {
+        let mut reasons: Vec<String> = rejected
+            .iter()
+            .map(|(filename, reason)| format!("\n  {}: {}", filename, reason))
+            .collect();
+        if !source_releases.is_empty() {
+            reasons.push("\nIts source can't be built for another platform.".into());
+        }
+        abort(&format!(
+            "Unable to find a compatible release for {}: {}{}",
+            name,
+            version.to_string_color(),
+            reasons.concat()
+        ))
+    };
 
-                // Packages that use C code(eg numpy) may fail to load C extensions if installing
-                // for the wrong version of python (eg  cp35 when python 3.7 is installed), even
-                // if `requires_python` doesn't indicate an incompatibility. Check `python_version`
-                // instead of `requires_python`.
-                // Note that the result of this parse is an any match.
-                if let Ok(constrs) = Constraint::from_wh_py_vers(&rel.python_version) {
-                    let mut compat_py_v = false;
-                    for constr in &constrs {
-                        if constr.is_compatible(python_vers) {
-                            compat_py_v = true;
-                        }
-                    }
-                    if !compat_py_v {
-                        compatible = false;
-                    }
-                } else {
-                    println!(
-                        "Unable to match python version from python_version: {}",
-                        &rel.python_version
-                    )
-                };
+    ReleaseChoice {
+        release,
+        package_type,
+        rejected,
+    }
+}
 
-                if compatible {
-                    compatible_releases.push(rel.clone());
-                }
-            }
-            "sdist" => source_releases.push(rel.clone()),
-            "bdist_wininst" | "bdist_msi" | "bdist_egg" => (), // Don't execute Windows installers
-            _ => {
-                println!("Found surprising package type: {}", rel.packagetype);
-                continue;
-            }
+/// Print the files we skipped under `-v`, and save them in the cache for later reference.
+pub fn record_release_choice(cache: &Path, name: &str, version: &Version, choice: &ReleaseChoice) {
+    let mut record = format!("chosen {}\n", choice.release.filename);
+    for (filename, reason) in &choice.rejected {
+        if CliConfig::current().verbose {
+            println!("skipping {}: {}", filename, reason);
         }
+        record.push_str(&format!("skipped {}: {}\n", filename, reason));
     }
 
-    let best_release;
-    let package_type;
-    // todo: Sort further / try to match exact python_version if able.
-    if compatible_releases.is_empty() {
-        if source_releases.is_empty() {
-            abort(&format!(
-                "Unable to find a compatible release for {}: {}",
-                name,
-                version.to_string_color()
-            ))
-        } else {
-            best_release = source_releases[0].clone();
-            package_type = install::PackageType::Source;
+    // This is only informational, so don't stop the install if we can't save it.
+    let dir = cache.join("release-choices");
+    if fs::create_dir_all(&dir).is_ok() {
+        fs::write(dir.join(format!("{}-{}.txt", name, version)), record).ok();
+    }
+}
+
+/// Find the release to install from the files recorded in the lock. Unlike `find_best_release`,
+/// we only build from source if the package has no wheels at all: If it has wheels, but none
+/// for this platform, the source dist is likely untested here. `pinned` is the wheel pinned
+/// with `wheel-tag`, if any.
+pub fn find_locked_release(
+    files: &[LockFile],
+    name: &str,
+    version: &Version,
+    os: Os,
+    python_vers: &Version,
+    pinned: Option<&str>,
+) -> Result<(WarehouseRelease, PackageType), String> {
+    let releases: Vec<WarehouseRelease> = files
+        .iter()
+        .map(|f| WarehouseRelease {
+            filename: f.filename.clone(),
+            has_sig: false,
+            digests: WarehouseDigests {
+                md5: String::new(),
+                sha256: f.sha256.clone(),
+            },
+            packagetype: f.packagetype.clone(),
+            python_version: f.python_version.clone(),
+            requires_python: f.requires_python.clone(),
+            url: f.url.clone(),
+            dependencies: None,
+            size: None,
+        })
+        .collect();
+
+    if let Some(filename) = pinned {
+        let release = releases
+            .iter()
+            .find(|r| r.filename == filename)
+            .ok_or_else(|| {
+                format!(
+                    "`pyflow.lock` pins {} for {} {}, but doesn't list it. Run `pyflow install` \
+                     to update it.",
+                    filename, name, version
+                )
+            })?;
+        wheel_tag::check(release, name, os, python_vers)?;
+        return Ok((release.clone(), PackageType::Wheel));
+    }
+
+    let wheels: Vec<&WarehouseRelease> = releases
+        .iter()
+        .filter(|r| r.packagetype == "bdist_wheel")
+        .collect();
+
+    if let Some(wheel) = wheels.iter().find(|r| wheel_compatible(r, os, python_vers)) {
+        return Ok(((*wheel).clone(), PackageType::Wheel));
+    }
+    if wheels.is_empty() && cross_target::wheels_only() {
+        return Err(format!(
+            "{} {} has no wheels, and its source can't be built for another platform",
+            name, version
+        ));
+    }
+    if wheels.is_empty() {
+        if let Some(source) = releases.iter().find(|r| r.packagetype == "sdist") {
+            return Ok((source.clone(), PackageType::Source));
         }
-    } else {
-        best_release = compatible_releases[0].clone();
-        package_type = install::PackageType::Wheel;
+        return Err(format!(
+            "`pyflow.lock` has no installable files for {} {}",
+            name, version
+        ));
     }
 
-    (best_release, package_type)
+    let mut platforms: Vec<String> = wheels.iter().map(|r| platform_tag(&r.filename)).collect();
+    platforms.sort();
+    platforms.dedup();
+    Err(format!(
+        "{} {} has no wheel in `pyflow.lock` for {:?} and Python {}. It has wheels for: {}",
+        name,
+        version,
+        os,
+        python_vers,
+        platforms.join(", ")
+    ))
 }
 
 /// Find the global git config's user and email, and format it to go in the config's `authors` field.
@@ -686,8 +1258,8 @@ pub fn get_git_author() -> Vec<String> {
     let conf = Ini::load_from_file(gitcfg).expect("Could not read ~/.gitconfig");
     let user = conf.section(Some("user".to_owned()));
     if let Some(user) = user {
-        let name: String = user.get("name").unwrap_or(&String::from("")).to_string();
-        let email: String = user.get("email").unwrap_or(&String::from("")).to_string();
+        let name: String = user.get("name").unwrap_or("").to_string();
+        let email: String = user.get("email").unwrap_or("").to_string();
         vec![format!("{} <{}>", name, email)]
     } else {
         vec![]
@@ -717,7 +1289,7 @@ pub fn find_first_file(path: &Path) -> PathBuf {
 /// Mainly to avoid repeating error-handling code.
 pub fn open_archive(path: &Path) -> fs::File {
     // We must re-open the file after computing the hash.
-    if let Ok(f) = fs::File::open(&path) {
+    if let Ok(f) = fs::File::open(path) {
         f
     } else {
         abort(&format!(
@@ -729,27 +1301,95 @@ pub fn open_archive(path: &Path) -> fs::File {
 }
 
 /// Parse a wheel's `METADATA` file.
-pub fn parse_metadata(path: &Path) -> Metadata {
+/// Parse the headers of a `METADATA` file. `Requires-Dist` lines we can't parse are kept in
+/// `warnings` instead of aborting.
+pub fn parse_metadata(path: &Path) -> Result<Metadata, DependencyError> {
+    let data = fs::read_to_string(path).map_err(|_| {
+        DependencyError::new(&format!("Problem reading {}", path.to_string_lossy()))
+    })?;
+    parse_metadata_str(&data)
+}
+
+/// Read the `METADATA` file in a wheel, without extracting it.
+pub fn wheel_metadata(path: &Path) -> Result<Metadata, DependencyError> {
+    let problem = || DependencyError::new(&format!("Problem reading {}", path.to_string_lossy()));
+    let file = fs::File::open(path).map_err(|_| problem())?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|_| problem())?;
+    let metadata_name = archive
+        .file_names()
+        .find(|n| n.ends_with(".dist-info/METADATA"))
+        .map(str::to_owned)
+        .ok_or_else(problem)?;
+    let mut data = String::new();
+    archive
+        .by_name(&metadata_name)
+        .map_err(|_| problem())?
+        .read_to_string(&mut data)
+        .map_err(|_| problem())?;
+    parse_metadata_str(&data)
+}
+
+/// As `parse_metadata`, from the file's contents.
+pub fn parse_metadata_str(data: &str) -> Result<Metadata, DependencyError> {
     let re = |key: &str| Regex::new(&format!(r"^{}:\s*(.*)$", key)).unwrap();
 
     let mut result = Metadata::default();
 
-    let data = fs::read_to_string(path).expect("Problem reading METADATA");
-    for line in data.lines() {
+    // The description may follow the headers, after a blank line.
+    for line in data.lines().take_while(|l| !l.trim().is_empty()) {
+        if let Some(caps) = re("Name").captures(line) {
+            result.name = caps.get(1).unwrap().as_str().trim().to_owned();
+        }
         if let Some(caps) = re("Version").captures(line) {
-            let val = caps.get(1).unwrap().as_str();
-            result.version =
-                Version::from_str(val).expect("Problem parsing version from `METADATA`");
+            let val = caps.get(1).unwrap().as_str().trim();
+            result.version = Version::from_str(val).map_err(|_| {
+                DependencyError::new(&format!("Problem parsing version from METADATA: {}", val))
+            })?;
         }
         if let Some(caps) = re("Requires-Dist").captures(line) {
-            let val = caps.get(1).unwrap().as_str();
-            let req =
-                Req::from_str(val, true).expect("Problem parsing requirement from `METADATA`");
-            result.requires_dist.push(req);
+            let val = caps.get(1).unwrap().as_str().trim();
+            match Req::from_str(val, true) {
+                Ok(req) => result.requires_dist.push(req),
+                Err(_) => result.warnings.push(val.to_owned()),
+            }
+        }
+        if let Some(caps) = re("Requires-Python").captures(line) {
+            result.requires_python = Some(caps.get(1).unwrap().as_str().trim().to_owned());
+        }
+        if let Some(caps) = re("License").captures(line) {
+            result.license = Some(caps.get(1).unwrap().as_str().trim().to_owned());
+        }
+        if let Some(caps) = re("License-Expression").captures(line) {
+            result.license_expression = Some(caps.get(1).unwrap().as_str().trim().to_owned());
+        }
+        if let Some(caps) = re("Classifier").captures(line) {
+            let val = caps.get(1).unwrap().as_str().trim();
+            if val.starts_with("License ::") {
+                result.license_classifiers.push(val.to_owned());
+            }
+        }
+        if let Some(caps) = re("Provides-Extra").captures(line) {
+            result
+                .provides_extra
+                .push(caps.get(1).unwrap().as_str().trim().to_owned());
         }
     }
-    // todo: For now, just pull version and requires_dist. Add more as-required.
-    result
+    Ok(result)
+}
+
+/// Print the `Requires-Dist` lines we skipped while parsing a package's metadata.
+pub fn print_metadata_warnings(metadata: &Metadata) {
+    if metadata.warnings.is_empty() {
+        return;
+    }
+    print_color(
+        &format!(
+            "Skipping dependencies of {} we couldn't parse:\n{}",
+            metadata.name,
+            metadata.warnings.join("\n")
+        ),
+        Color::Yellow,
+    );
 }
 
 pub fn find_folders(path: &Path) -> Vec<String> {
@@ -780,8 +1420,8 @@ fn default_python() -> Version {
 }
 
 /// We've removed the git repos from packages to install form pypi, but make
-/// sure we flag them as not-to-uninstall.
-pub fn find_dont_uninstall(reqs: &[Req], dev_reqs: &[Req]) -> Vec<String> {
+/// sure we flag them as not-to-uninstall, along with those in `preserve`.
+pub fn find_dont_uninstall(reqs: &[Req], dev_reqs: &[Req], preserve: &[String]) -> Vec<String> {
     let mut result: Vec<String> = reqs
         .iter()
         .filter_map(|r| {
@@ -798,6 +1438,7 @@ pub fn find_dont_uninstall(reqs: &[Req], dev_reqs: &[Req]) -> Vec<String> {
             result.push(r.name.to_owned());
         }
     }
+    result.extend(preserve.iter().cloned());
 
     result
 }
@@ -842,9 +1483,16 @@ pub fn canon_join(path: &Path, extend: &str) -> PathBuf {
 ///
 /// The git requirements are removed from the `reqs` vector, and are replaced
 /// by all their downstream requirements.
-pub fn process_reqs(reqs: Vec<Req>, git_path: &Path, paths: &util::Paths) -> Vec<Req> {
+/// Install git reqs, and return the reqs left to resolve, including theirs. Also returns
+/// `(declared, actual)` names of git reqs whose metadata names a different package.
+pub fn process_reqs(
+    reqs: Vec<Req>,
+    git_path: &Path,
+    paths: &util::Paths,
+) -> (Vec<Req>, Vec<(String, String)>) {
     // git_reqs is used to store requirements from packages installed via git.
     let mut git_reqs = vec![]; // For path reqs too.
+    let mut renamed = vec![];
     for req in reqs.iter().filter(|r| r.git.is_some()) {
         // todo: as_ref() would be better than clone, if we can get it working.
         let mut metadata = install::download_and_install_git(
@@ -854,6 +1502,9 @@ pub fn process_reqs(reqs: Vec<Req>, git_path: &Path, paths: &util::Paths) -> Vec
             git_path,
             paths,
         );
+        if !metadata.name.is_empty() && !compare_names(&metadata.name, &req.name) {
+            renamed.push((req.name.clone(), metadata.name.clone()));
+        }
         git_reqs.append(&mut metadata.requires_dist);
     }
     // We don't pass the git requirement itself, since we've directly installed it,
@@ -861,11 +1512,25 @@ pub fn process_reqs(reqs: Vec<Req>, git_path: &Path, paths: &util::Paths) -> Vec
     let mut updated_reqs: Vec<Req> = reqs
         .into_iter()
         .filter(|r| r.git.is_none() && r.path.is_none())
+        .filter(|r| match &r.url {
+            Some(url) => {
+                print_color(
+                    &format!(
+                        "Skipping {}: we can't install direct references that aren't git \
+                         repos, like {}",
+                        r.name, url
+                    ),
+                    Color::Yellow,
+                );
+                false
+            }
+            None => true,
+        })
         .collect();
     for r in git_reqs {
         updated_reqs.push(r);
     }
-    updated_reqs
+    (updated_reqs, renamed)
 }
 
 /// Read dependency data from a lock file.
@@ -874,10 +1539,17 @@ pub fn read_lock(path: &Path) -> Result<Lock, Box<dyn Error>> {
     Ok(toml::from_str(&data)?)
 }
 
-/// Write dependency data to a lock file.
+/// Write dependency data to a lock file, marked with the pyflow version and lock format.
+/// Written to a temporary file first, so an interrupted write doesn't leave a truncated lock. The
+/// lock it replaces is kept in `pyflow.lock.bak`.
 pub fn write_lock(path: &Path, data: &Lock) -> Result<(), Box<dyn Error>> {
-    let data = toml::to_string(data)?;
-    fs::write(path, data)?;
+    let mut data = data.clone();
+    lock_header::stamp(&mut data.metadata);
+    let data = toml::to_string(&data)?;
+    let tmp_path = path.with_extension("lock.tmp");
+    fs::write(&tmp_path, data)?;
+    lock_recovery::backup(path);
+    fs::rename(&tmp_path, path)?;
     Ok(())
 }
 
@@ -898,6 +1570,8 @@ pub fn handle_color_option(s: &str) -> ColorChoice {
 
 #[cfg(test)]
 mod tests {
+    use std::env;
+
     use rstest::rstest;
 
     use super::*;
@@ -906,6 +1580,81 @@ mod tests {
     #[test]
     fn dummy_test() {}
 
+    fn cfg_req(s: &str) -> Req {
+        Req::from_str(s, false).unwrap()
+    }
+
+    #[test]
+    fn added_req_changes() {
+        let existing = vec![cfg_req(r#"django = "^4.2""#)];
+
+        assert_eq!(req_change(&cfg_req("requests"), &existing), ReqChange::New);
+        assert_eq!(
+            req_change(&cfg_req("Django"), &existing),
+            ReqChange::Unchanged
+        );
+        assert_eq!(
+            req_change(&cfg_req(r#"django = "^4.2""#), &existing),
+            ReqChange::Unchanged
+        );
+        assert_eq!(
+            req_change(&cfg_req(r#"django = "^4.5""#), &existing),
+            ReqChange::Replaced { disjoint: false }
+        );
+        assert_eq!(
+            req_change(&cfg_req(r#"django = "^5.0""#), &existing),
+            ReqChange::Replaced { disjoint: true }
+        );
+    }
+
+    #[test]
+    fn merged_dev_reqs_keep_or_replace_pins() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let cfg_path = dir.join("pyproject.toml");
+        let data = "[tool.pyflow.dev-dependencies]\npytest = \"^7.0\"\n";
+        fs::write(&cfg_path, data).unwrap();
+        let cfg = crate::Config {
+            dev_reqs: vec![cfg_req(r#"pytest = "^7.0""#)],
+            ..Default::default()
+        };
+
+        // A bare name leaves the pin alone.
+        let (_, dev_reqs) = merge_reqs(&["pytest".into()], true, false, None, &cfg, &cfg_path);
+        assert_eq!(dev_reqs, cfg.dev_reqs);
+        assert_eq!(fs::read_to_string(&cfg_path).unwrap(), data);
+
+        // Overlapping constraints replace it, without a prompt.
+        let (_, dev_reqs) = merge_reqs(
+            &[r#"pytest = "^7.4""#.into()],
+            true,
+            false,
+            None,
+            &cfg,
+            &cfg_path,
+        );
+        assert_eq!(dev_reqs, vec![cfg_req(r#"pytest = "^7.4""#)]);
+        assert_eq!(
+            fs::read_to_string(&cfg_path).unwrap(),
+            "[tool.pyflow.dev-dependencies]\npytest = \"^7.4\"\n"
+        );
+
+        // Disjoint ones need `force`.
+        let (reqs, dev_reqs) = merge_reqs(
+            &[r#"pytest = "^8.0""#.into()],
+            true,
+            true,
+            None,
+            &cfg,
+            &cfg_path,
+        );
+        assert!(reqs.is_empty());
+        assert_eq!(dev_reqs, vec![cfg_req(r#"pytest = "^8.0""#)]);
+        assert!(fs::read_to_string(&cfg_path)
+            .unwrap()
+            .contains("pytest = \"^8.0\""));
+    }
+
     #[rstest(
         input,
         expected,
@@ -934,4 +1683,524 @@ mod tests {
     fn test_os_from_str(input: &str, expected: Result<Os, dep_types::DependencyError>) {
         assert_eq!(Os::from_str(input), expected);
     }
+
+    fn lock_file(filename: &str, python_version: &str) -> LockFile {
+        LockFile {
+            filename: filename.to_owned(),
+            url: format!("https://files.pythonhosted.org/{}", filename),
+            sha256: "0".repeat(64),
+            packagetype: if filename.ends_with(".whl") {
+                "bdist_wheel".into()
+            } else {
+                "sdist".into()
+            },
+            python_version: python_version.to_owned(),
+            requires_python: None,
+            platform: platform_tag(filename),
+        }
+    }
+
+    fn release(filename: &str, python_version: &str) -> WarehouseRelease {
+        let packagetype = match filename.rsplit('.').next() {
+            Some("whl") => "bdist_wheel",
+            Some("exe") => "bdist_wininst",
+            _ => "sdist",
+        };
+        WarehouseRelease {
+            filename: filename.to_owned(),
+            has_sig: false,
+            digests: WarehouseDigests {
+                md5: String::new(),
+                sha256: String::new(),
+            },
+            packagetype: packagetype.to_owned(),
+            python_version: python_version.to_owned(),
+            requires_python: Some(">=3.9".to_owned()),
+            url: format!("https://files.pythonhosted.org/{}", filename),
+            dependencies: None,
+            size: None,
+        }
+    }
+
+    #[test]
+    fn best_release_rejections() {
+        let linux = "manylinux_2_17_x86_64.manylinux2014_x86_64";
+        let data = vec![
+            release("numpy-1.26.0-cp39-cp39-win_amd64.whl", "cp39"),
+            release("numpy-1.26.0-cp39-cp39-macosx_11_0_arm64.whl", "cp39"),
+            release(&format!("numpy-1.26.0-cp310-cp310-{}.whl", linux), "cp310"),
+            release(&format!("numpy-1.26.0-cp39-cp39-{}.whl", linux), "cp39"),
+            release("numpy-1.26.0.win-amd64-py3.9.exe", "3.9"),
+            release("numpy-1.26.0.tar.gz", "source"),
+        ];
+        let version = Version::new(1, 26, 0);
+
+        let choice = find_best_release(
+            &data,
+            "numpy",
+            &version,
+            Os::Linux,
+            &Version::new(3, 9, 0),
+            None,
+        );
+        assert_eq!(choice.release.filename, data[3].filename);
+        assert!(matches!(choice.package_type, PackageType::Wheel));
+        let reasons: Vec<String> = choice.rejected.iter().map(|(_, r)| r.to_string()).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                "platform win_amd64 != linux",
+                "platform macosx_11_0_arm64 != linux",
+                "python tag cp310 doesn't match Python 3.9.0",
+                "unsupported package type bdist_wininst",
+            ]
+        );
+        assert_eq!(choice.rejected[2].0, data[2].filename);
+
+        // Without a compatible wheel, fall back to the source dist.
+        let py38 = Version::new(3, 8, 0);
+        let choice = find_best_release(&data, "numpy", &version, Os::Linux, &py38, None);
+        assert!(matches!(choice.package_type, PackageType::Source));
+        assert!(choice.rejected.contains(&(
+            data[3].filename.clone(),
+            RejectReason::RequiresPython(">=3.9".to_owned(), py38.clone())
+        )));
+        assert_eq!(
+            choice.rejected[3].1.to_string(),
+            "requires_python >=3.9 excludes Python 3.8.0"
+        );
+    }
+
+    /// Interpreters report a patch version; tags and short `requires_python` bounds don't.
+    #[test]
+    fn best_release_for_patch_versions() {
+        let linux = "manylinux_2_17_x86_64.manylinux2014_x86_64";
+        let mut data = vec![
+            release(&format!("numpy-1.26.0-cp310-cp310-{}.whl", linux), "cp310"),
+            release(&format!("numpy-1.26.0-cp312-cp312-{}.whl", linux), "cp312"),
+            release("numpy-1.26.0.tar.gz", "source"),
+        ];
+        for rel in &mut data {
+            rel.requires_python = Some(">=3.7, <4".to_owned());
+        }
+        let version = Version::new(1, 26, 0);
+
+        for (py_vers, expected) in [((3, 10, 12), 0), ((3, 12, 1), 1), ((3, 11, 7), 2)] {
+            let py_vers = Version::new(py_vers.0, py_vers.1, py_vers.2);
+            let choice = find_best_release(&data, "numpy", &version, Os::Linux, &py_vers, None);
+            assert_eq!(choice.release.filename, data[expected].filename);
+        }
+    }
+
+    #[test]
+    fn unrecognized_python_tag_left_to_requires_python() {
+        let data = vec![
+            release("pkg-1.0-jy27.py3-none-any.whl", "jy27.py3"),
+            release("pkg-1.0.tar.gz", "source"),
+        ];
+        let version = Version::new(1, 0, 0);
+
+        let choice = find_best_release(
+            &data,
+            "pkg",
+            &version,
+            Os::Linux,
+            &Version::new(3, 11, 0),
+            None,
+        );
+        assert!(matches!(choice.package_type, PackageType::Wheel));
+        assert!(choice.rejected.is_empty());
+
+        let choice = find_best_release(
+            &data,
+            "pkg",
+            &version,
+            Os::Linux,
+            &Version::new(3, 8, 0),
+            None,
+        );
+        assert!(matches!(choice.package_type, PackageType::Source));
+
+        // Nor does it stop us from finding the version.
+        let req = Req::from_warehouse_release("pkg".into(), "1.0".into(), data[0].clone());
+        assert!(req.python_version.is_some());
+    }
+
+    #[test]
+    fn locked_release_for_platform() {
+        let py_vers = Version::new(3, 9, 0);
+        let mac_only = vec![
+            lock_file("saturn-1.0.0-cp39-cp39-macosx_11_0_arm64.whl", "cp39"),
+            lock_file("saturn-1.0.0.tar.gz", "source"),
+        ];
+        let version = Version::new(1, 0, 0);
+
+        let (release, _) =
+            find_locked_release(&mac_only, "saturn", &version, Os::Mac, &py_vers, None).unwrap();
+        assert_eq!(release.filename, mac_only[0].filename);
+
+        // Don't fall back to the source dist when other platforms have wheels.
+        let err = find_locked_release(&mac_only, "saturn", &version, Os::Linux, &py_vers, None)
+            .unwrap_err();
+        assert!(err.contains("macosx_11_0_arm64"));
+
+        let pure = vec![lock_file("saturn-1.0.0-py3-none-any.whl", "py3")];
+        assert!(find_locked_release(&pure, "saturn", &version, Os::Linux, &py_vers, None).is_ok());
+
+        let (_, package_type) = find_locked_release(
+            &mac_only[1..],
+            "saturn",
+            &version,
+            Os::Linux,
+            &py_vers,
+            None,
+        )
+        .unwrap();
+        assert!(matches!(package_type, PackageType::Source));
+    }
+
+    /// Create a `dist-info` folder in `dir` containing a `RECORD` with these paths.
+    fn dist_info_with_record(dir: &Path, name: &str, paths: &[&str]) -> PathBuf {
+        let dist_info = dir.join(format!("{}-1.0.dist-info", name));
+        fs::create_dir_all(&dist_info).unwrap();
+        let record: Vec<String> = paths.iter().map(|p| format!("{},,", p)).collect();
+        fs::write(dist_info.join("RECORD"), record.join("\n")).unwrap();
+        dist_info
+    }
+
+    #[rstest(
+        name,
+        paths,
+        expected,
+        // Flit builds single-module packages without `top_level.txt`.
+        case::flit_module("foo", &[
+            "foo.py,sha256=abc,120",
+            "foo-1.0.dist-info/METADATA,sha256=def,300",
+            "foo-1.0.dist-info/RECORD,,",
+            "../../bin/foo,sha256=ghi,200",
+        ], &["foo"]),
+        // Installed from a src-layout project, with a compiled extension, data, and a `.pth` file.
+        case::src_layout("my-pkg", &[
+            "my_pkg/__init__.py,sha256=abc,0",
+            "my_pkg/core.py,sha256=abc,10",
+            "_my_pkg_speedups.cpython-311-x86_64-linux-gnu.so,sha256=abc,10",
+            "my_pkg-1.0.data/scripts/run.sh,sha256=abc,10",
+            "my_pkg.pth,sha256=abc,10",
+            "my_pkg-1.0.dist-info/METADATA,,",
+        ], &["_my_pkg_speedups", "my_pkg"]),
+        case::namespace("google-api-core", &[
+            "google/api_core/__init__.py,sha256=abc,0",
+            "google/api_core/client_options.py,sha256=abc,0",
+            "\"google/api_core/odd,name.py\",sha256=abc,0",
+        ], &["google"]),
+    )]
+    fn modules_without_top_level(name: &str, paths: &[&str], expected: &[&str]) {
+        let dir = tempfile::tempdir().unwrap();
+        let dist_info = dist_info_with_record(dir.path(), name, paths);
+        let modules = top_level_modules(&dist_info, name);
+
+        assert_eq!(modules, expected);
+    }
+
+    #[test]
+    fn top_level_txt_preferred() {
+        let dir = tempfile::tempdir().unwrap();
+        let dist_info = dist_info_with_record(
+            dir.path(),
+            "pyyaml",
+            &["yaml/__init__.py,,", "_yaml/__init__.py,,"],
+        );
+        fs::write(dist_info.join("top_level.txt"), "_yaml\nyaml\n").unwrap();
+        let modules = top_level_modules(&dist_info, "PyYAML");
+
+        assert_eq!(modules, vec!["_yaml", "yaml"]);
+        assert_eq!(
+            top_level_modules(Path::new("/nonexistent"), "My-Pkg"),
+            vec!["my_pkg"]
+        );
+    }
+
+    const RICH_METADATA: &str = "Metadata-Version: 2.1
+Name: rich
+Version: 13.7.1
+Requires-Python: >=3.7.0
+Provides-Extra: jupyter
+Requires-Dist: ipywidgets (>=7.5.1,<9) ; extra == \"jupyter\"
+Requires-Dist: markdown-it-py (>=2.2.0)
+Requires-Dist: pygments (>=2.13.0,<3.0.0)
+Requires-Dist: typing-extensions (>=4.0.0,<5.0) ; python_version < \"3.9\"
+
+# Rich
+Version: this line is in the description
+";
+
+    const PYDANTIC_METADATA: &str = "Metadata-Version: 2.1
+Name: pydantic
+Version: 2.6.4
+Requires-Dist: annotated-types>=0.4.0
+Requires-Dist: pydantic-core==2.16.3
+Requires-Dist: typing-extensions>=4.6.1
+Provides-Extra: email
+Requires-Dist: email-validator>=2.0.0; extra == 'email'
+Requires-Dist: tzdata; (python_version >= '3.9' and platform_system == 'Windows') and extra == 'timezone'
+";
+
+    const JUPYTER_CLIENT_METADATA: &str = "Metadata-Version: 2.1
+Name: jupyter_client
+Version: 8.6.1
+Requires-Dist: importlib-metadata>=4.8.3; python_version < '3.10'
+Requires-Dist: jupyter-core!=5.0.*,>=4.12
+Requires-Dist: pyzmq>=23.0
+Provides-Extra: test
+Requires-Dist: pytest-jupyter[client]>=0.4.1; extra == 'test'
+";
+
+    const DIRECT_REF_METADATA: &str = "Metadata-Version: 2.1
+Name: mylib
+Version: 1.0.0
+Requires-Dist: cffi>=1.12; platform_python_implementation != 'PyPy'
+Requires-Dist: vendored @ https://example.com/vendored-1.0.tar.gz
+Requires-Dist: saturn[cli, yaml] @ git+https://github.com/org/saturn ; python_version >= \"3.8\"
+Requires-Dist: broken (>>1.0)
+";
+
+    #[rstest(
+        metadata,
+        name,
+        expected,
+        warnings,
+        case(
+            RICH_METADATA,
+            "rich",
+            &["ipywidgets", "markdown-it-py", "pygments", "typing-extensions"],
+            &[]
+        ),
+        case(
+            PYDANTIC_METADATA,
+            "pydantic",
+            &["annotated-types", "pydantic-core", "typing-extensions", "email-validator", "tzdata"],
+            &[]
+        ),
+        case(
+            JUPYTER_CLIENT_METADATA,
+            "jupyter_client",
+            &["importlib-metadata", "jupyter-core", "pyzmq", "pytest-jupyter"],
+            &[]
+        ),
+        case(
+            DIRECT_REF_METADATA,
+            "mylib",
+            &["cffi", "vendored", "saturn"],
+            &["broken (>>1.0)"]
+        )
+    )]
+    fn metadata_requires_dist(metadata: &str, name: &str, expected: &[&str], warnings: &[&str]) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("METADATA");
+        fs::write(&path, metadata).unwrap();
+        let parsed = parse_metadata(&path).unwrap();
+
+        assert_eq!(parsed.name, name);
+        let names: Vec<&str> = parsed
+            .requires_dist
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect();
+        assert_eq!(names, expected);
+        assert_eq!(parsed.warnings, warnings);
+    }
+
+    #[test]
+    fn metadata_direct_refs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("METADATA");
+        fs::write(&path, DIRECT_REF_METADATA).unwrap();
+        let parsed = parse_metadata(&path).unwrap();
+
+        let reqs = &parsed.requires_dist;
+        assert_eq!(
+            reqs[1].url,
+            Some("https://example.com/vendored-1.0.tar.gz".to_owned())
+        );
+        assert_eq!(
+            reqs[2].git,
+            Some("https://github.com/org/saturn".to_owned())
+        );
+        assert_eq!(
+            reqs[2].install_with_extras,
+            Some(vec!["cli".to_owned(), "yaml".to_owned()])
+        );
+        assert!(reqs[2].python_version.is_some());
+        assert!(reqs[0]
+            .marker
+            .as_ref()
+            .unwrap()
+            .evaluate(Os::Linux, &Version::new(3, 11, 0)));
+
+        fs::write(&path, RICH_METADATA).unwrap();
+        assert_eq!(
+            parse_metadata(&path).unwrap().version,
+            Version::new(13, 7, 1)
+        );
+        assert!(parse_metadata(Path::new("/nonexistent/METADATA")).is_err());
+    }
+
+    const LARGE_ARCHIVE_VAR: &str = "PYFLOW_LARGE_ARCHIVE_TEST_PATH";
+
+    /// Run by `large_archive_unpacked_in_bounded_memory` in a child process, so the peak memory
+    /// use measured is the unpacking's.
+    #[test]
+    #[ignore]
+    fn unpack_large_archive() {
+        let path = match env::var(LARGE_ARCHIVE_VAR) {
+            Ok(p) => PathBuf::from(p),
+            Err(_) => return,
+        };
+        unpack_tar_xz(&path, path.parent().unwrap());
+        let status = fs::read_to_string("/proc/self/status").unwrap();
+        let peak = status.lines().find(|l| l.starts_with("VmHWM:")).unwrap();
+        println!("{}", peak);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn large_archive_unpacked_in_bounded_memory() {
+        const SIZE: u64 = 128 * 1024 * 1024;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let archive_path = dir.join("python.tar.xz");
+
+        // Zeros compress to almost nothing, so only unpacking uses much memory.
+        let file = fs::File::create(&archive_path).unwrap();
+        let mut builder = tar::Builder::new(xz2::write::XzEncoder::new(file, 0));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(SIZE);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "python/lib/big.bin", io::repeat(0).take(SIZE))
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let output = process::Command::new(env::current_exe().unwrap())
+            .args([
+                "--ignored",
+                "--exact",
+                "util::tests::unpack_large_archive",
+                "--nocapture",
+            ])
+            .env(LARGE_ARCHIVE_VAR, &archive_path)
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let peak_kb: u64 = stdout
+            .lines()
+            .find_map(|l| l.split("VmHWM:").nth(1))
+            .and_then(|l| l.trim().trim_end_matches("kB").trim().parse().ok())
+            .unwrap_or_else(|| panic!("No peak memory use in {}", stdout));
+
+        assert_eq!(
+            fs::metadata(dir.join("python/lib/big.bin")).unwrap().len(),
+            SIZE
+        );
+        // Holding the unpacked tar would take at least `SIZE`.
+        assert!(peak_kb * 1024 < SIZE / 4, "Peak memory use: {} kB", peak_kb);
+    }
+
+    fn crafted_zip(path: &Path, name: &str, contents: &[u8]) -> fs::File {
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+        zip.start_file(name, options).unwrap();
+        zip.write_all(contents).unwrap();
+        zip.finish().unwrap();
+        fs::File::open(path).unwrap()
+    }
+
+    #[test]
+    fn unsafe_zips_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        let unpack = |file: &fs::File, rename: Option<(&str, &str)>, limit: u64| {
+            let rename = rename.map(|(o, n)| (o.to_owned(), n.to_owned()));
+            let mut budget = archive_limits::Budget::with_limit(limit);
+            unpack_zip(
+                file,
+                &dir.join("lib"),
+                &rename,
+                &None,
+                &mut budget,
+                &mut vec![],
+            )
+            .map_err(|e| match e {
+                UnpackError::Refused(e) => e,
+                UnpackError::Fs(e) => e.to_string(),
+            })
+        };
+
+        let ok = crafted_zip(&dir.join("ok.whl"), "pkg/__init__.py", b"print()");
+        assert!(unpack(&ok, None, 1000).is_ok());
+        assert!(dir.join("lib/pkg/__init__.py").exists());
+
+        let traversal = crafted_zip(&dir.join("traversal.whl"), "../evil.py", b"print()");
+        let err = unpack(&traversal, None, 1000).unwrap_err();
+        assert!(err.contains("../evil.py"));
+        assert!(!dir.join("evil.py").exists());
+
+        // Rewriting for a renamed package mustn't be able to escape either.
+        let err = unpack(&ok, Some(("pkg", "/tmp/pkg")), 1000).unwrap_err();
+        assert!(err.contains("pkg/__init__.py"));
+
+        let bomb = crafted_zip(&dir.join("bomb.whl"), "zeros.bin", &[0; 4 * 1024 * 1024]);
+        let err = unpack(&bomb, None, u64::MAX).unwrap_err();
+        assert!(err.contains("zeros.bin") && err.contains("zip bomb"));
+
+        let big = crafted_zip(&dir.join("big.whl"), "pkg/data.txt", &[b'a'; 2000]);
+        let err = unpack(&big, None, 1000).unwrap_err();
+        assert!(err.contains("pkg/data.txt") && err.contains("more than 1000 bytes"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extract_into_read_only_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let lib = dir.join("lib");
+        fs::create_dir_all(lib.join("shared")).unwrap();
+
+        let path = dir.join("fixture-1.0-py3-none-any.whl");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+        for name in ["fixture/__init__.py", "fixture.py", "shared/fixture.pth"] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(b"").unwrap();
+        }
+        zip.finish().unwrap();
+
+        let read_only = |mode| {
+            fs::set_permissions(lib.join("shared"), fs::Permissions::from_mode(mode)).unwrap()
+        };
+        read_only(0o555);
+        // Eg running as root, which can write there anyway.
+        if fs::write(lib.join("shared/probe"), "").is_ok() {
+            read_only(0o755);
+            return;
+        }
+
+        let file = fs::File::open(&path).unwrap();
+        let err = extract_zip(&file, &path, &lib, &None, &None).unwrap_err();
+        read_only(0o755);
+        assert_eq!(err.kind(), fs_errors::FsErrorKind::PermissionDenied);
+        assert_eq!(err.path, lib.join("shared/fixture.pth"));
+        assert!(fs_errors::describe(&err, None)
+            .contains(&format!("write to {}.", lib.join("shared").display())));
+
+        // What was extracted before it is removed, leaving what was there.
+        assert!(!lib.join("fixture").exists());
+        assert!(!lib.join("fixture.py").exists());
+        assert!(lib.join("shared").exists());
+    }
 }
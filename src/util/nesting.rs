@@ -0,0 +1,52 @@
+//! Stop pyflow from running itself without end, eg from a script in `[tool.pyflow.scripts]` that
+//! runs `pyflow run` with its own name. Each pyflow process records how deeply it's nested in
+//! `PYFLOW_ACTIVE`, which the commands it runs inherit.
+
+use std::env;
+
+use crate::util;
+
+pub const VAR: &str = "PYFLOW_ACTIVE";
+
+/// Nested pyflow processes allowed, including the outermost one.
+const MAX_DEPTH: usize = 8;
+
+/// This process's depth, from its parent's `PYFLOW_ACTIVE`; 1 if it's not run by pyflow.
+fn depth(parent: Option<&str>) -> Result<usize, String> {
+    let depth = parent
+        .and_then(|d| d.trim().parse::<usize>().ok())
+        .unwrap_or(0)
+        + 1;
+    if depth > MAX_DEPTH {
+        return Err(format!(
+            "pyflow has run itself {} levels deep, so it's probably stuck in a loop: eg a script \
+             in `[tool.pyflow.scripts]` that runs `pyflow run` with its own name, or two that \
+             run each other.",
+            MAX_DEPTH
+        ));
+    }
+    Ok(depth)
+}
+
+/// Record this process's depth for the commands it runs, or abort if it's too deep.
+pub fn enter() {
+    match depth(env::var(VAR).ok().as_deref()) {
+        Ok(d) => env::set_var(VAR, d.to_string()),
+        Err(e) => util::abort(&e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nesting_limited() {
+        assert_eq!(depth(None), Ok(1));
+        assert_eq!(depth(Some("1")), Ok(2));
+        assert_eq!(depth(Some("7")), Ok(MAX_DEPTH));
+        assert!(depth(Some("8")).unwrap_err().contains("8 levels deep"));
+        // Set by something else.
+        assert_eq!(depth(Some("yes")), Ok(1));
+    }
+}
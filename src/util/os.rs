@@ -48,3 +48,48 @@ pub const fn get_os() -> Os {
     #[cfg(target_os = "macos")]
     return Os::Mac;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use regex::Regex;
+
+    fn visit(dir: &Path, result: &mut Vec<(String, String)>) {
+        for entry in fs::read_dir(dir).unwrap().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                visit(&path, result);
+            } else if path.extension().is_some_and(|e| e == "rs") {
+                let data = fs::read_to_string(&path).unwrap();
+                result.push((path.to_string_lossy().replace('\\', "/"), data));
+            }
+        }
+    }
+
+    /// `util` once existed twice, as `util.rs` and `util/mod.rs`, each with its own `Os`, and
+    /// fixes landed in one but not the other. Make sure it can't come back.
+    #[test]
+    fn one_util_module() {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        assert!(!src.join("util.rs").exists());
+
+        let mut files = vec![];
+        visit(&src, &mut files);
+        let os_def = Regex::new(r"(?m)^\s*(pub(\(\w+\))?\s+)?enum Os\b").unwrap();
+        let defined_in: Vec<&str> = files
+            .iter()
+            .filter(|(_, data)| os_def.is_match(data))
+            .map(|(path, _)| path.as_str())
+            .collect();
+        assert_eq!(defined_in.len(), 1, "`Os` is defined in: {:?}", defined_in);
+        assert!(defined_in[0].ends_with("src/util/os.rs"));
+
+        let unmerged: Vec<&str> = files
+            .iter()
+            .filter(|(_, data)| data.lines().any(|l| l.starts_with("<<<<<<< ")))
+            .map(|(path, _)| path.as_str())
+            .collect();
+        assert!(unmerged.is_empty(), "Unmerged changes in: {:?}", unmerged);
+    }
+}
@@ -1,4 +1,62 @@
-use std::path::{Path, PathBuf};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use super::cache_policy;
+
+pub const CACHE_DIR_VAR: &str = "PYFLOW_CACHE_DIR";
+pub const PYTHON_DIR_VAR: &str = "PYFLOW_PYTHON_DIR";
+pub const SCRIPT_ENV_DIR_VAR: &str = "PYFLOW_SCRIPT_ENV_DIR";
+pub const GIT_DIR_VAR: &str = "PYFLOW_GIT_DIR";
+
+/// In the dependency cache: downloaded wheels and source dists.
+pub const ARCHIVE_DIR: &str = "archives";
+
+/// Where pyflow keeps data shared between projects. Each location can be moved with its
+/// environment variable, or the same key in `~/.config/pyflow/config.toml`. Environment
+/// variables take precedence.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PyflowDirs {
+    /// Holds the others by default, and crash reports.
+    pub data: PathBuf,
+    /// Downloaded packages
+    pub cache: PathBuf,
+    /// Python installations
+    pub python: PathBuf,
+    pub script_envs: PathBuf,
+    /// Git dependencies
+    pub git: PathBuf,
+}
+
+impl PyflowDirs {
+    /// Each location, with the key that moves it.
+    pub fn entries(&self) -> [(&'static str, &Path); 4] {
+        [
+            (CACHE_DIR_VAR, &self.cache),
+            (PYTHON_DIR_VAR, &self.python),
+            (SCRIPT_ENV_DIR_VAR, &self.script_envs),
+            (GIT_DIR_VAR, &self.git),
+        ]
+    }
+}
+
+/// `~/.config/pyflow/config.toml`
+#[derive(Debug, Default, Deserialize)]
+struct UserConfig {
+    #[serde(rename = "PYFLOW_CACHE_DIR")]
+    cache_dir: Option<PathBuf>,
+    #[serde(rename = "PYFLOW_PYTHON_DIR")]
+    python_dir: Option<PathBuf>,
+    #[serde(rename = "PYFLOW_SCRIPT_ENV_DIR")]
+    script_env_dir: Option<PathBuf>,
+    #[serde(rename = "PYFLOW_GIT_DIR")]
+    git_dir: Option<PathBuf>,
+    #[serde(flatten)]
+    cache_limits: cache_policy::Config,
+}
 
 pub fn pyflow_path() -> PathBuf {
     directories::BaseDirs::new()
@@ -8,22 +66,105 @@ pub fn pyflow_path() -> PathBuf {
         .join("pyflow")
 }
 
-pub fn dep_cache_path(pyflow_path: &Path) -> PathBuf {
-    pyflow_path.join("dependency_cache")
+pub fn user_config_path() -> Option<PathBuf> {
+    directories::BaseDirs::new().map(|d| d.home_dir().join(".config/pyflow/config.toml"))
 }
 
-pub fn script_env_path(pyflow_path: &Path) -> PathBuf {
-    pyflow_path.join("script_envs")
+fn read_user_config(path: Option<&Path>) -> UserConfig {
+    let data = match path.and_then(|p| fs::read_to_string(p).ok()) {
+        Some(d) => d,
+        None => return UserConfig::default(),
+    };
+    toml::from_str(&data).unwrap_or_else(|e| {
+        super::abort(&format!(
+            "Problem parsing {}: {}",
+            path.unwrap().to_string_lossy(),
+            e
+        ))
+    })
 }
 
-pub fn git_path(pyflow_path: &Path) -> PathBuf {
-    pyflow_path.join("git")
+fn resolve_dirs(
+    data: PathBuf,
+    user_cfg: UserConfig,
+    var: impl Fn(&str) -> Option<String>,
+) -> PyflowDirs {
+    let pick = |name: &str, configured: Option<PathBuf>, default: PathBuf| {
+        var(name)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or(configured)
+            .unwrap_or(default)
+    };
+    PyflowDirs {
+        cache: pick(
+            CACHE_DIR_VAR,
+            user_cfg.cache_dir,
+            data.join("dependency_cache"),
+        ),
+        python: pick(PYTHON_DIR_VAR, user_cfg.python_dir, data.clone()),
+        script_envs: pick(
+            SCRIPT_ENV_DIR_VAR,
+            user_cfg.script_env_dir,
+            data.join("script_envs"),
+        ),
+        git: pick(GIT_DIR_VAR, user_cfg.git_dir, data.join("git")),
+        data,
+    }
 }
 
-pub fn get_paths() -> (PathBuf, PathBuf, PathBuf, PathBuf) {
-    let pyflow_path = pyflow_path();
-    let dep_cache_path = dep_cache_path(&pyflow_path);
-    let script_env_path = script_env_path(&pyflow_path);
-    let git_path = git_path(&pyflow_path);
-    (pyflow_path, dep_cache_path, script_env_path, git_path)
+/// Where a downloaded wheel or source dist is kept in the dependency cache.
+pub fn archive_path(cache: &Path, filename: &str) -> PathBuf {
+    cache.join(ARCHIVE_DIR).join(filename)
+}
+
+/// The cache limits set in `~/.config/pyflow/config.toml`.
+pub fn cache_config() -> cache_policy::Config {
+    read_user_config(user_config_path().as_deref()).cache_limits
+}
+
+pub fn get_paths() -> PyflowDirs {
+    let user_cfg = read_user_config(user_config_path().as_deref());
+    resolve_dirs(pyflow_path(), user_cfg, |name| env::var(name).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_vars_override_user_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let cfg_path = dir.join("config.toml");
+        fs::write(
+            &cfg_path,
+            "PYFLOW_CACHE_DIR = \"/srv/pyflow/cache\"\nPYFLOW_GIT_DIR = \"/srv/pyflow/git\"\n\
+             max_cache_size = \"2GiB\"\n",
+        )
+        .unwrap();
+
+        let data = dir.join("data");
+        let var = |name: &str| (name == CACHE_DIR_VAR).then(|| "/mnt/big/cache".to_owned());
+        let user_cfg = read_user_config(Some(&cfg_path));
+        assert_eq!(
+            user_cfg.cache_limits.max_cache_size.as_deref(),
+            Some("2GiB")
+        );
+        let dirs = resolve_dirs(data.clone(), user_cfg, var);
+        assert_eq!(
+            dirs,
+            PyflowDirs {
+                data: data.clone(),
+                cache: "/mnt/big/cache".into(),
+                python: data.clone(),
+                script_envs: data.join("script_envs"),
+                git: "/srv/pyflow/git".into(),
+            }
+        );
+
+        // A missing config file means the defaults.
+        let dirs = resolve_dirs(data.clone(), read_user_config(None), |_| None);
+        assert_eq!(dirs.cache, data.join("dependency_cache"));
+    }
 }
@@ -0,0 +1,319 @@
+//! Download release archives into the cache in the background, a few at a time, while we do
+//! other work: asking whether to install them, then installing the ones already downloaded.
+//! Each is written to a `.part` file, and only moved into place once its hash matches, so a
+//! cancelled or interrupted download is never mistaken for a cached archive. Archives kept
+//! after cancelling, eg when the install's declined, are reused next time.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{install, util};
+
+/// How many archives to download at once.
+pub const MAX_CONCURRENT: usize = 4;
+
+/// A release archive to download.
+#[derive(Clone, Debug)]
+pub struct Item {
+    pub url: String,
+    pub filename: String,
+    pub sha256: String,
+}
+
+type Fetch = dyn Fn(&Item) -> Result<Box<dyn Read>, String> + Send + Sync;
+
+/// Downloads in progress. Cancelled, and waited for, when dropped.
+pub struct Prefetch {
+    cancelled: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Prefetch {
+    /// Start downloading `items` that aren't in `cache` yet. Local `find_links` files are
+    /// copied when installing instead.
+    pub fn start(items: Vec<Item>, cache: &Path) -> Self {
+        let items = items
+            .into_iter()
+            .filter(|item| !item.url.starts_with(util::find_links::PATH_PREFIX))
+            .collect();
+        Self::start_with(items, cache, |item| {
+            util::http::get(&item.url)
+                .map(|resp| Box::new(resp) as Box<dyn Read>)
+                .map_err(|e| util::http::describe(&e))
+        })
+    }
+
+    /// Start downloading `items` while `ask` waits on whether to install them. If that's
+    /// declined, stop, and return `None`; the archives already downloaded are kept.
+    pub fn start_asking(
+        items: Vec<Item>,
+        cache: &Path,
+        ask: impl FnOnce() -> bool,
+    ) -> Option<Self> {
+        Self::start(items, cache).unless_declined(ask)
+    }
+
+    fn unless_declined(self, ask: impl FnOnce() -> bool) -> Option<Self> {
+        if ask() {
+            return Some(self);
+        }
+        self.cancel();
+        self.wait();
+        None
+    }
+
+    fn start_with(
+        items: Vec<Item>,
+        cache: &Path,
+        fetch: impl Fn(&Item) -> Result<Box<dyn Read>, String> + Send + Sync + 'static,
+    ) -> Self {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let count = items.len().min(MAX_CONCURRENT);
+        // Popped from the end, so reverse to download in order.
+        let queue = Arc::new(Mutex::new(items.into_iter().rev().collect::<Vec<_>>()));
+        let fetch: Arc<Fetch> = Arc::new(fetch);
+        let archive_dir = cache.join(util::paths::ARCHIVE_DIR);
+        if count > 0 && !archive_dir.exists() {
+            fs::create_dir_all(&archive_dir).ok();
+        }
+
+        let workers = (0..count)
+            .map(|_| {
+                let (queue, fetch, cancelled) = (queue.clone(), fetch.clone(), cancelled.clone());
+                let cache = cache.to_owned();
+                thread::spawn(move || loop {
+                    let item = match queue.lock().unwrap().pop() {
+                        Some(i) => i,
+                        None => break,
+                    };
+                    if cancelled.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    // Failed downloads are retried, and reported, when installing.
+                    download(&item, &cache, fetch.as_ref(), &cancelled).ok();
+                })
+            })
+            .collect();
+
+        Self { cancelled, workers }
+    }
+
+    /// Stop starting downloads, and abandon those in progress.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Wait for downloads to finish, or stop if cancelled.
+    pub fn wait(mut self) {
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+    }
+}
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        self.cancel();
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+    }
+}
+
+fn part_path(archive_path: &Path) -> PathBuf {
+    let mut path = PathBuf::from(archive_path);
+    path.as_mut_os_string().push(".part");
+    path
+}
+
+/// Download an archive to the cache, unless it's there already.
+fn download(
+    item: &Item,
+    cache: &Path,
+    fetch: &Fetch,
+    cancelled: &AtomicBool,
+) -> Result<(), String> {
+    let archive_path = util::paths::archive_path(cache, &item.filename);
+    // Installing waits on this while we download the same file.
+    let _guard = util::guard::guard_cached(&archive_path);
+    if archive_path.exists() {
+        return Ok(());
+    }
+
+    let part = part_path(&archive_path);
+    let result = fetch(item).and_then(|mut reader| {
+        let mut out = File::create(&part).map_err(|e| e.to_string())?;
+        let mut buffer = [0; 8192];
+        loop {
+            if cancelled.load(Ordering::SeqCst) {
+                return Err("Cancelled".to_owned());
+            }
+            let n = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.to_string()),
+            };
+            out.write_all(&buffer[..n]).map_err(|e| e.to_string())?;
+        }
+        drop(out);
+
+        let digest = File::open(&part)
+            .and_then(install::sha256_digest)
+            .map_err(|e| e.to_string())?;
+        if !data_encoding::HEXLOWER
+            .encode(digest.as_ref())
+            .eq_ignore_ascii_case(&item.sha256)
+        {
+            return Err(format!("Hash mismatch for {}", item.filename));
+        }
+        fs::rename(&part, &archive_path).map_err(|e| e.to_string())
+    });
+
+    if result.is_err() {
+        fs::remove_file(&part).ok();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::paths::archive_path;
+    use std::{sync::atomic::AtomicUsize, time::Duration};
+
+    /// Never finishes; yields a byte at a time, slowly.
+    struct Endless;
+
+    impl Read for Endless {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(5));
+            buf[0] = b'x';
+            Ok(1)
+        }
+    }
+
+    fn item(filename: &str, data: &[u8]) -> Item {
+        let digest = install::sha256_digest(data).unwrap();
+        Item {
+            url: format!("https://files.example.com/{}", filename),
+            filename: filename.to_owned(),
+            sha256: data_encoding::HEXLOWER.encode(digest.as_ref()),
+        }
+    }
+
+    fn wait_for(path: &Path) {
+        for _ in 0..400 {
+            if path.exists() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("{} wasn't downloaded", path.display());
+    }
+
+    #[test]
+    fn cancelled_downloads_not_cached() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = tmp.path().to_owned();
+
+        let items = vec![item("slow-1.0-py3-none-any.whl", b"")];
+        let prefetch = Prefetch::start_with(items, &cache, |_| Ok(Box::new(Endless)));
+        let part = part_path(&archive_path(&cache, "slow-1.0-py3-none-any.whl"));
+        wait_for(&part);
+
+        prefetch.cancel();
+        prefetch.wait();
+        assert!(!archive_path(&cache, "slow-1.0-py3-none-any.whl").exists());
+        assert!(!part.exists());
+    }
+
+    #[test]
+    fn declined_prompt_cancels_downloads() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = tmp.path().to_owned();
+
+        let data: &[u8] = b"wheel contents";
+        let six = item("six-1.16.0-py2.py3-none-any.whl", data);
+        let slow = item("slow-1.0-py3-none-any.whl", data);
+        let part = part_path(&archive_path(&cache, &slow.filename));
+        let fetch = move |item: &Item| {
+            if item.filename.starts_with("slow") {
+                Ok(Box::new(Endless) as Box<dyn Read>)
+            } else {
+                Ok(Box::new(data) as Box<dyn Read>)
+            }
+        };
+
+        let items = vec![six.clone(), slow.clone()];
+        let prefetch = Prefetch::start_with(items.clone(), &cache, fetch).unless_declined(|| {
+            // Downloading while waiting on the answer.
+            wait_for(&archive_path(&cache, &six.filename));
+            wait_for(&part);
+            false
+        });
+        assert!(prefetch.is_none());
+        assert!(archive_path(&cache, &six.filename).exists());
+        assert!(!archive_path(&cache, &slow.filename).exists());
+        assert!(!part.exists());
+
+        // Accepted: the downloads go on.
+        let prefetch = Prefetch::start_with(items, &cache, fetch).unless_declined(|| true);
+        wait_for(&part);
+        prefetch.unwrap().cancel();
+    }
+
+    #[test]
+    fn prefetched_files_reused() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = tmp.path().to_owned();
+
+        let data: &[u8] = b"wheel contents";
+        let six = item("six-1.16.0-py2.py3-none-any.whl", data);
+        let slow = item("slow-1.0-py3-none-any.whl", data);
+        let bad = Item {
+            sha256: "00".repeat(32),
+            ..item("bad-1.0-py3-none-any.whl", data)
+        };
+        let fetched = Arc::new(AtomicUsize::new(0));
+
+        // Declined partway: what finished is kept.
+        let counter = fetched.clone();
+        let items = vec![six.clone(), bad.clone(), slow.clone()];
+        let prefetch = Prefetch::start_with(items, &cache, move |item| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            if item.filename.starts_with("slow") {
+                Ok(Box::new(Endless) as Box<dyn Read>)
+            } else {
+                Ok(Box::new(data))
+            }
+        });
+        wait_for(&archive_path(&cache, &six.filename));
+        drop(prefetch);
+        assert_eq!(fs::read(archive_path(&cache, &six.filename)).unwrap(), data);
+        assert!(!archive_path(&cache, &slow.filename).exists());
+        assert!(!archive_path(&cache, &bad.filename).exists());
+
+        // The next run only downloads what's missing.
+        fetched.store(0, Ordering::SeqCst);
+        let counter = fetched.clone();
+        let prefetch = Prefetch::start_with(vec![six.clone(), slow.clone()], &cache, move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::new(data))
+        });
+        prefetch.wait();
+        assert_eq!(fetched.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            fs::read(archive_path(&cache, &slow.filename)).unwrap(),
+            data
+        );
+    }
+}
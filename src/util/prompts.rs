@@ -1,85 +1,236 @@
+//! Questions for the user. Every prompt goes through here, so there's one notion of whether
+//! anyone can answer: without a terminal on stdin, prompts stop with a message saying how to run
+//! without them, eg the flag to pass, instead of waiting or reading garbage.
+
 use std::{
-    collections::HashMap,
-    io::{self, Write},
+    io::{self, BufRead, IsTerminal, Write},
+    str::FromStr,
 };
 
 use termcolor::Color;
 
 use crate::{
     dep_types::Version,
-    util::{abort, default_python, fallible_v_parse, print_color},
+    py_versions,
+    util::{abort, compare_names, default_python, print_color},
 };
 
-/// Ask the user what Python version to use.
-pub fn py_vers() -> Version {
-    print_color(
-        "Please enter the Python version for this project: (eg: 3.8)",
-        Color::Magenta,
-    );
+/// How many answers we take before giving up on a prompt.
+const ATTEMPTS: usize = 3;
+
+/// Whether there's someone to answer prompts.
+pub fn interactive() -> bool {
+    io::stdin().is_terminal()
+}
+
+/// Stop if there's nobody to answer `question`. `avoid` says how to run without being asked,
+/// eg "Pass `--force` to replace it."
+fn require_terminal(question: &str, avoid: &str) {
+    if !interactive() {
+        abort(&format!(
+            "{} There's no terminal to answer on. {}",
+            question, avoid
+        ));
+    }
+}
+
+/// The next line of input, without its line ending. `None` at the end of input.
+fn read_answer(input: &mut impl BufRead) -> Option<String> {
+    io::stdout().flush().ok();
+    let mut line = String::new();
+    match input.read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim().to_owned()),
+    }
+}
+
+/// Ask until `parse` accepts an answer, up to `ATTEMPTS` times, telling the user what was wrong
+/// with each. An empty answer is passed to `parse` too, eg to pick a default.
+fn ask<T>(
+    input: &mut impl BufRead,
+    mut parse: impl FnMut(&str) -> Result<T, String>,
+) -> Result<T, String> {
+    let mut problem = "No answer".to_owned();
+    for attempt in 1..=ATTEMPTS {
+        let answer = match read_answer(input) {
+            Some(a) => a,
+            None => return Err(problem),
+        };
+        match parse(&answer) {
+            Ok(result) => return Ok(result),
+            Err(e) => {
+                if attempt < ATTEMPTS {
+                    print_color(&format!("{} Please try again:", e), Color::Yellow);
+                }
+                problem = e;
+            }
+        }
+    }
+    Err(problem)
+}
+
+fn parse_version(answer: &str, default: &Version) -> Result<Version, String> {
+    if answer.is_empty() {
+        return Ok(default.clone());
+    }
+    let version = Version::from_str(&answer.replace(' ', "")).map_err(|_| {
+        format!(
+            "\"{}\" isn't a version. It should look like this: 3.11",
+            answer
+        )
+    })?;
+    py_versions::check_supported(&version)?;
+    Ok(version)
+}
+
+/// Ask the user what Python version to use. `avoid` says how to pick one without being asked,
+/// eg "Pass one with `--py 3.11`."
+pub fn py_vers(avoid: &str) -> Version {
+    let question = "Please enter the Python version for this project: (eg: 3.8)";
+    require_terminal(question, avoid);
+    print_color(question, Color::Magenta);
     let default_ver = default_python();
     print!("Default [{}]:", default_ver);
-    std::io::stdout().flush().unwrap();
-    let mut input = String::new();
-    io::stdin()
-        .read_line(&mut input)
-        .expect("Unable to read user input for version");
-
-    input.pop(); // Remove trailing newline.
-    let input = input.replace("\n", "").replace("\r", "");
-    if !input.is_empty() {
-        fallible_v_parse(&input)
-    } else {
-        default_ver
+    ask(&mut io::stdin().lock(), |a| parse_version(a, &default_ver)).unwrap_or_else(|e| abort(&e))
+}
+
+fn parse_confirm(answer: &str) -> Result<bool, String> {
+    match answer.to_lowercase().as_str() {
+        "y" | "yes" => Ok(true),
+        "" | "n" | "no" => Ok(false),
+        _ => Err(format!("\"{}\" isn't yes or no.", answer)),
+    }
+}
+
+/// Ask a yes or no question. Defaults to no, including when we don't get an answer. `avoid` says
+/// how to run without being asked.
+pub fn confirm(msg: &str, avoid: &str) -> bool {
+    require_terminal(msg, avoid);
+    print_color(&format!("{} [y/N]", msg), Color::Magenta);
+    ask(&mut io::stdin().lock(), parse_confirm).unwrap_or(false)
+}
+
+/// The index of the item an answer picks: by its number, from 1, or by its name. An empty answer
+/// picks the default.
+fn parse_choice(
+    answer: &str,
+    names: &[&str],
+    default: Option<usize>,
+    type_: &str,
+) -> Result<usize, String> {
+    let numbers = format!("a number from 1 to {}", names.len());
+    if answer.is_empty() {
+        return default.ok_or_else(|| format!("Please enter {}.", numbers));
+    }
+    if let Ok(n) = answer.parse::<usize>() {
+        return if (1..=names.len()).contains(&n) {
+            Ok(n - 1)
+        } else {
+            Err(format!("{} isn't in the list; enter {}.", n, numbers))
+        };
+    }
+    let matches: Vec<usize> = (0..names.len())
+        .filter(|&i| compare_names(answer, names[i]))
+        .collect();
+    match matches.as_slice() {
+        [i] => Ok(*i),
+        [] => Err(format!(
+            "There's no {} \"{}\" in the list; enter its number or name.",
+            type_, answer
+        )),
+        _ => Err(format!(
+            "More than one {} is called \"{}\"; enter its number.",
+            type_, answer
+        )),
     }
 }
 
-/// A generic prompt function, where the user selects from a list
+/// Ask the user to pick from a list, by number or name. With a `default`, an index into `items`,
+/// an empty answer picks it. `avoid` says how to run without being asked.
 pub fn list<T: Clone + ToString>(
     init_msg: &str,
     type_: &str,
     items: &[(String, T)],
     show_item: bool,
+    default: Option<usize>,
+    avoid: &str,
 ) -> (String, T) {
+    require_terminal(init_msg, avoid);
     print_color(init_msg, Color::Magenta);
+    // Line up the names when there are more than 9.
+    let width = items.len().to_string().len();
     for (i, (name, content)) in items.iter().enumerate() {
         if show_item {
-            println!("{}: {}: {}", i + 1, name, content.to_string())
+            println!("{:>width$}: {}: {}", i + 1, name, content.to_string())
         } else {
-            println!("{}: {}", i + 1, name)
+            println!("{:>width$}: {}", i + 1, name)
         }
     }
+    if let Some(d) = default {
+        print!("Default [{}]:", d + 1);
+    }
+
+    let names: Vec<&str> = items.iter().map(|(name, _)| name.as_str()).collect();
+    let chosen = ask(&mut io::stdin().lock(), |a| {
+        parse_choice(a, &names, default, type_)
+    })
+    .unwrap_or_else(|e| abort(&format!("No {} chosen: {}", type_, e)));
+    items[chosen].clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALIASES: &[&str] = &["python3.10", "python3.11", "python3.12"];
 
-    let mut mapping = HashMap::new();
-    for (i, item) in items.iter().enumerate() {
-        mapping.insert(i + 1, item);
+    #[test]
+    fn choices_by_number_or_name() {
+        let names: Vec<String> = (1..=12).map(|i| format!("item {}", i)).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        // Past the first digit.
+        assert_eq!(parse_choice("12", &names, None, "item"), Ok(11));
+        assert_eq!(parse_choice("1", &names, None, "item"), Ok(0));
+        assert!(parse_choice("13", &names, None, "item").is_err());
+        assert!(parse_choice("0", &names, None, "item").is_err());
+        assert!(parse_choice("", &names, None, "item").is_err());
+        assert_eq!(parse_choice("", &names, Some(3), "item"), Ok(3));
+
+        assert_eq!(
+            parse_choice("Python3.11", ALIASES, None, "Python alias"),
+            Ok(1)
+        );
+        assert!(parse_choice("python3", ALIASES, None, "Python alias")
+            .unwrap_err()
+            .contains("no Python alias \"python3\""));
     }
 
-    let mut input = String::new();
-    io::stdin()
-        .read_line(&mut input)
-        .expect("Problem reading input");
-
-    let input = input
-        .chars()
-        .next()
-        .expect("Problem parsing input")
-        .to_string()
-        .parse::<usize>();
-
-    let input = if let Ok(ip) = input {
-        ip
-    } else {
-        abort("Please try again; enter a number like 1 or 2 .")
-    };
-
-    let (name, content) = if let Some(r) = mapping.get(&input) {
-        r
-    } else {
-        abort(&format!(
-            "Can't find the {} associated with that number. Is it in the list above?",
-            type_
-        ))
-    };
+    #[test]
+    fn bad_answers_asked_again() {
+        let choose = |script: &str, default| {
+            ask(&mut script.as_bytes(), |a| {
+                parse_choice(a, ALIASES, default, "Python alias")
+            })
+        };
+        assert_eq!(choose("x\n9\n2\n", None), Ok(1));
+        assert_eq!(choose("\r\n", Some(2)), Ok(2));
+        assert_eq!(choose("  python3.12  \n", None), Ok(2));
+        // Out of attempts, or input, with the last problem.
+        assert!(choose("x\n9\n7\n1\n", None)
+            .unwrap_err()
+            .contains("7 isn't in the list"));
+        assert!(choose("x\n", None).unwrap_err().contains("\"x\""));
+        assert_eq!(choose("", None), Err("No answer".to_owned()));
 
-    (name.to_string(), content.clone())
+        let confirm = |script: &str| ask(&mut script.as_bytes(), parse_confirm);
+        assert_eq!(confirm("maybe\nYes\n"), Ok(true));
+        assert_eq!(confirm("\n"), Ok(false));
+        assert!(confirm("").is_err());
+
+        let default = Version::new_short(3, 11);
+        let version = |script: &str| ask(&mut script.as_bytes(), |a| parse_version(a, &default));
+        assert_eq!(version("three\n3.12\n"), Ok(Version::new_short(3, 12)));
+        assert_eq!(version("2.7\n3.8\n"), Ok(Version::new_short(3, 8)));
+        assert_eq!(version("\n"), Ok(default.clone()));
+    }
 }
@@ -0,0 +1,281 @@
+//! Results of running `python --version`, kept in the Pyflow directory, since spawning each
+//! interpreter alias we look for is slow, eg on Windows with antivirus scanning. An entry is
+//! probed again when its binary's modification time or size changes, or it's a week old.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::{self, PyVersionInfo},
+    dep_types::Version,
+};
+
+/// In the Pyflow directory.
+pub const FILENAME: &str = "python_probes.json";
+
+/// Seconds before an entry is probed again, even if its binary hasn't changed.
+const TTL: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct Entry {
+    /// The binary the alias ran.
+    path: PathBuf,
+    /// Nanoseconds since the Unix epoch.
+    modified: u128,
+    size: u64,
+    /// Seconds since the Unix epoch.
+    probed: u64,
+    /// `None` if it's not a Python, eg a Windows Store stub.
+    version: Option<String>,
+    pypy: bool,
+    /// What the binary resolves to, with links followed.
+    executable: Option<PathBuf>,
+}
+
+impl Entry {
+    fn info(&self) -> Option<PyVersionInfo> {
+        Some(PyVersionInfo {
+            version: Version::from_str(self.version.as_ref()?).ok()?,
+            pypy: self.pypy,
+        })
+    }
+}
+
+/// Probe results by alias, or path.
+#[derive(Debug, Default)]
+struct ProbeCache {
+    path: PathBuf,
+    entries: HashMap<String, Entry>,
+}
+
+impl ProbeCache {
+    /// A missing or unreadable file gives an empty cache, which replaces it when saved.
+    fn load(path: &Path) -> Self {
+        let entries = fs::read_to_string(path)
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
+        Self {
+            path: path.to_owned(),
+            entries,
+        }
+    }
+
+    fn save(&self) {
+        let data = match serde_json::to_string_pretty(&self.entries) {
+            Ok(d) => d,
+            Err(_) => return,
+        };
+        // Written in full before replacing, in case another pyflow is reading it.
+        let tmp = self
+            .path
+            .with_extension(format!("{}.tmp", std::process::id()));
+        if fs::write(&tmp, data).is_ok() && fs::rename(&tmp, &self.path).is_err() {
+            fs::remove_file(&tmp).ok();
+        }
+    }
+
+    /// The entry for `alias`, probing with `probe` if there isn't a current one. `None` if the
+    /// alias doesn't name a binary.
+    fn get(
+        &mut self,
+        alias: &str,
+        now: u64,
+        probe: impl Fn(&str) -> (Option<PyVersionInfo>, Option<PathBuf>),
+    ) -> Option<&Entry> {
+        let path = resolve(alias)?;
+        let metadata = fs::metadata(&path).ok()?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos());
+        let current = self.entries.get(alias).is_some_and(|e| {
+            e.path == path
+                && e.modified == modified
+                && e.size == metadata.len()
+                && now.saturating_sub(e.probed) < TTL
+        });
+
+        if !current {
+            let (info, executable) = probe(alias);
+            let entry = Entry {
+                path,
+                modified,
+                size: metadata.len(),
+                probed: now,
+                version: info.as_ref().map(|i| i.version.to_string()),
+                pypy: info.is_some_and(|i| i.pypy),
+                executable,
+            };
+            self.entries.insert(alias.to_owned(), entry);
+            self.save();
+        }
+        self.entries.get(alias)
+    }
+}
+
+/// The file an alias runs, eg `/usr/bin/python3` for `python3`, found as `Command` would.
+fn resolve(alias: &str) -> Option<PathBuf> {
+    let alias_path = Path::new(alias);
+    if alias_path.components().count() > 1 {
+        return alias_path.is_file().then(|| alias_path.to_owned());
+    }
+    let names = if cfg!(windows) {
+        vec![alias.to_owned(), format!("{}.exe", alias)]
+    } else {
+        vec![alias.to_owned()]
+    };
+    env::split_paths(&env::var_os("PATH")?)
+        .flat_map(|dir| names.iter().map(move |n| dir.join(n)))
+        .find(|p| p.is_file())
+}
+
+static CACHE: Mutex<Option<ProbeCache>> = Mutex::new(None);
+
+/// Keep results in `pyflow_dir`. Until this is called, we probe each time. With `refresh`, for
+/// `--refresh-python-cache`, stored results are ignored, and replaced.
+pub fn configure(pyflow_dir: &Path, refresh: bool) {
+    let mut cache = CACHE.lock().unwrap();
+    if cache.is_none() {
+        let mut loaded = ProbeCache::load(&pyflow_dir.join(FILENAME));
+        if refresh {
+            loaded.entries.clear();
+        }
+        *cache = Some(loaded);
+    }
+}
+
+/// Remove stored results, eg from `pyflow clear`.
+pub fn clear(pyflow_dir: &Path) -> std::io::Result<()> {
+    if let Some(cache) = CACHE.lock().unwrap().as_mut() {
+        cache.entries.clear();
+    }
+    let path = pyflow_dir.join(FILENAME);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn probe(alias: &str) -> (Option<PyVersionInfo>, Option<PathBuf>) {
+    let info = commands::find_py_info(alias);
+    // Only Pythons can tell us where they are.
+    let executable = info
+        .as_ref()
+        .and_then(|_| commands::find_py_executable(Path::new(alias)));
+    (info, executable)
+}
+
+/// The stored entry for `alias`, probing it if needed. `None` if we're not keeping results, or
+/// can't find its binary ourselves.
+fn cached(alias: &str) -> Option<Entry> {
+    CACHE
+        .lock()
+        .unwrap()
+        .as_mut()
+        .and_then(|c| c.get(alias, now(), probe).cloned())
+}
+
+/// As `commands::find_py_info`, using a stored result if the binary hasn't changed.
+pub fn find_py_info(alias: &str) -> Option<PyVersionInfo> {
+    match cached(alias) {
+        Some(entry) => entry.info(),
+        None => commands::find_py_info(alias),
+    }
+}
+
+/// As `commands::find_py_executable`, using a stored result if the binary hasn't changed.
+pub fn find_py_executable(alias: &str) -> Option<PathBuf> {
+    match cached(alias) {
+        Some(entry) => entry.executable,
+        None => commands::find_py_executable(Path::new(alias)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, thread, time::Duration};
+
+    #[test]
+    fn probes_cached_until_binary_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let python = dir.join("python3.11");
+        fs::write(&python, "#!/bin/sh\n").unwrap();
+        let alias = python.to_str().unwrap();
+        let cache_path = dir.join(FILENAME);
+
+        let probes = Cell::new(0);
+        let probe = |_: &str| {
+            probes.set(probes.get() + 1);
+            let info = PyVersionInfo {
+                version: Version::new(3, 11, 7),
+                pypy: false,
+            };
+            (Some(info), Some(PathBuf::from("/usr/bin/python3.11")))
+        };
+
+        let mut cache = ProbeCache::load(&cache_path);
+        let entry = cache.get(alias, 1_000, probe).unwrap();
+        assert_eq!(entry.info().unwrap().version, Version::new(3, 11, 7));
+        cache.get(alias, 2_000, probe).unwrap();
+        assert_eq!(probes.get(), 1);
+
+        // Stored between runs, until it's too old.
+        let mut cache = ProbeCache::load(&cache_path);
+        let entry = cache.get(alias, 3_000, probe).unwrap();
+        assert_eq!(entry.executable, Some(PathBuf::from("/usr/bin/python3.11")));
+        assert_eq!(probes.get(), 1);
+        cache.get(alias, 1_000 + TTL, probe).unwrap();
+        assert_eq!(probes.get(), 2);
+
+        // Replaced, eg by an upgrade.
+        thread::sleep(Duration::from_millis(20));
+        fs::write(&python, "#!/bin/sh\n# 3.11.8\n").unwrap();
+        cache.get(alias, 1_000 + TTL, probe).unwrap();
+        assert_eq!(probes.get(), 3);
+
+        // Missing binaries aren't probed.
+        assert!(cache
+            .get(dir.join("python3.99").to_str().unwrap(), 0, probe)
+            .is_none());
+        assert_eq!(probes.get(), 3);
+    }
+
+    #[test]
+    fn corrupt_cache_rebuilt() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let python = dir.join("python3");
+        fs::write(&python, "").unwrap();
+        let cache_path = dir.join(FILENAME);
+        fs::write(&cache_path, "{\"python3\": {\"path\": 3").unwrap();
+
+        let mut cache = ProbeCache::load(&cache_path);
+        assert!(cache.entries.is_empty());
+        let entry = cache
+            .get(python.to_str().unwrap(), 0, |_| (None, None))
+            .unwrap();
+        assert_eq!(entry.info(), None);
+
+        let reloaded = ProbeCache::load(&cache_path);
+        assert_eq!(reloaded.entries.len(), 1);
+        assert_eq!(reloaded.entries.values().next().unwrap().version, None);
+    }
+}
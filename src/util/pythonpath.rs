@@ -0,0 +1,112 @@
+//! `PYTHONPATH` for the commands we run, so Python finds packages in `__pypackages__`. Ours
+//! come first, followed by any the user already set, eg an IDE's generated stubs.
+
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+use crate::util;
+
+static ISOLATED: AtomicBool = AtomicBool::new(false);
+
+/// As pyflow was started with, before we set it.
+static INHERITED: OnceLock<Option<OsString>> = OnceLock::new();
+
+/// For `--isolated-pythonpath`: only our paths, dropping any the user set.
+pub fn isolate() {
+    ISOLATED.store(true, Ordering::SeqCst);
+}
+
+fn inherited() -> Option<&'static OsStr> {
+    if ISOLATED.load(Ordering::SeqCst) {
+        return None;
+    }
+    INHERITED
+        .get_or_init(|| env::var_os("PYTHONPATH"))
+        .as_deref()
+}
+
+/// `paths`, then those in `existing`, in order, without duplicates or empty entries.
+fn merge(paths: &[PathBuf], existing: Option<&OsStr>) -> OsString {
+    let mut result: Vec<PathBuf> = vec![];
+    let existing = existing.map(env::split_paths).into_iter().flatten();
+    for path in paths.iter().cloned().chain(existing) {
+        if !path.as_os_str().is_empty() && !result.contains(&path) {
+            result.push(path);
+        }
+    }
+    env::join_paths(result)
+        .unwrap_or_else(|e| util::abort(&format!("Problem setting `PYTHONPATH`: {}", e)))
+}
+
+/// The value to run Python with.
+pub fn value(paths: &[PathBuf]) -> OsString {
+    merge(paths, inherited())
+}
+
+/// Set `PYTHONPATH` for the commands we start from here on.
+pub fn set(paths: &[PathBuf]) {
+    env::set_var("PYTHONPATH", value(paths));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lib() -> Vec<PathBuf> {
+        vec![
+            PathBuf::from("/proj/__pypackages__/3.11/lib"),
+            PathBuf::from("/proj/mylib"),
+        ]
+    }
+
+    fn split(value: OsString) -> Vec<PathBuf> {
+        env::split_paths(&value).collect()
+    }
+
+    #[test]
+    fn merges_after_ours() {
+        let existing = env::join_paths(["/stubs", "/generated"]).unwrap();
+        assert_eq!(
+            split(merge(&lib(), Some(&existing))),
+            [lib(), vec!["/stubs".into(), "/generated".into()]].concat()
+        );
+    }
+
+    #[test]
+    fn drops_duplicates() {
+        let existing = env::join_paths([
+            "/stubs",
+            "/proj/mylib",
+            "/stubs",
+            "/proj/__pypackages__/3.11/lib",
+        ])
+        .unwrap();
+        assert_eq!(
+            split(merge(&lib(), Some(&existing))),
+            [lib(), vec!["/stubs".into()]].concat()
+        );
+    }
+
+    #[test]
+    fn empty_existing() {
+        assert_eq!(split(merge(&lib(), Some(OsStr::new("")))), lib());
+        assert_eq!(split(merge(&lib(), None)), lib());
+    }
+
+    #[test]
+    fn isolated() {
+        // Read before we isolate, as it would be when set without the flag.
+        inherited();
+        isolate();
+        assert_eq!(inherited(), None);
+        assert_eq!(split(value(&lib())), lib());
+        ISOLATED.store(false, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,154 @@
+//! Find installed packages whose `Requires-Python` excludes the environment's Python version,
+//! eg ones left over after switching versions.
+
+use std::{collections::HashMap, fs, path::Path, time::UNIX_EPOCH};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dep_types::{Constraint, Version},
+    util,
+};
+
+/// Stored in the environment's folder, next to `lib`.
+const CACHE_FILENAME: &str = "requires_python.toml";
+
+/// `Requires-Python` by dist-info folder, with the folder's modified time, so we don't read
+/// every `METADATA` on each run.
+#[derive(Default, Deserialize, Serialize)]
+struct Cache {
+    packages: HashMap<String, CacheEntry>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct CacheEntry {
+    modified: u64,
+    requires_python: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Incompatible {
+    pub name: String,
+    pub version: Version,
+    pub requires_python: String,
+}
+
+fn modified(path: &Path) -> Option<u64> {
+    let time = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    let since_epoch = time.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_nanos()).ok()
+}
+
+/// Whether `py_vers` satisfies a `Requires-Python` value. We assume it does if we can't parse
+/// the value.
+pub fn is_compatible(requires_python: &str, py_vers: &Version) -> bool {
+    Constraint::from_str_multiple(requires_python)
+        .map(|cs| cs.iter().all(|c| c.is_compatible(py_vers)))
+        .unwrap_or(true)
+}
+
+/// Find installed packages that don't support `py_vers`.
+pub fn find_incompatible(lib_path: &Path, py_vers: &Version) -> Vec<Incompatible> {
+    if !lib_path.exists() {
+        return vec![];
+    }
+    let cache_path = lib_path.parent().unwrap_or(lib_path).join(CACHE_FILENAME);
+    let cache: Cache = fs::read_to_string(&cache_path)
+        .ok()
+        .and_then(|d| toml::from_str(&d).ok())
+        .unwrap_or_default();
+
+    let re_dist = Regex::new(r"^(.*?)-(.*?)\.dist-info$").unwrap();
+    let mut updated = Cache::default();
+    let mut result = vec![];
+    for folder_name in util::find_folders(lib_path) {
+        let caps = match re_dist.captures(&folder_name) {
+            Some(c) => c,
+            None => continue,
+        };
+        let dist_info = lib_path.join(&folder_name);
+        let modified = modified(&dist_info).unwrap_or_default();
+
+        let entry = match cache.packages.get(&folder_name) {
+            Some(e) if e.modified == modified => e.clone(),
+            _ => CacheEntry {
+                modified,
+                requires_python: util::parse_metadata(&dist_info.join("METADATA"))
+                    .ok()
+                    .and_then(|m| m.requires_python),
+            },
+        };
+
+        if let (Some(rp), Ok(version)) = (
+            &entry.requires_python,
+            caps.get(2).unwrap().as_str().parse::<Version>(),
+        ) {
+            if !is_compatible(rp, py_vers) {
+                result.push(Incompatible {
+                    name: caps.get(1).unwrap().as_str().to_owned(),
+                    version,
+                    requires_python: rp.clone(),
+                });
+            }
+        }
+        updated.packages.insert(folder_name, entry);
+    }
+
+    let changed = updated.packages.len() != cache.packages.len()
+        || updated.packages.iter().any(|(k, v)| {
+            cache
+                .packages
+                .get(k)
+                .is_none_or(|c| c.modified != v.modified)
+        });
+    if changed {
+        if let Ok(data) = toml::to_string(&updated) {
+            fs::write(&cache_path, data).ok();
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn install(lib: &Path, folder: &str, requires_python: Option<&str>) {
+        let dist_info = lib.join(folder);
+        fs::create_dir_all(&dist_info).unwrap();
+        let mut metadata = "Metadata-Version: 2.1\nName: pkg\nVersion: 1.0\n".to_owned();
+        if let Some(rp) = requires_python {
+            metadata.push_str(&format!("Requires-Python: {}\n", rp));
+        }
+        fs::write(dist_info.join("METADATA"), metadata).unwrap();
+    }
+
+    #[test]
+    fn incompatible_found_and_cached() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let lib = dir.join("lib");
+        install(&lib, "oldlib-1.0.0.dist-info", Some(">=3.7,<3.12"));
+        install(
+            &lib,
+            "six-1.16.0.dist-info",
+            Some(">=2.7, !=3.0.*, !=3.1.*"),
+        );
+        install(&lib, "toml-0.10.2.dist-info", None);
+
+        let py312 = Version::new(3, 12, 1);
+        let expected = vec![Incompatible {
+            name: "oldlib".into(),
+            version: Version::new(1, 0, 0),
+            requires_python: ">=3.7,<3.12".into(),
+        }];
+        assert_eq!(find_incompatible(&lib, &py312), expected);
+        assert!(find_incompatible(&lib, &Version::new(3, 11, 7)).is_empty());
+        assert!(dir.join(CACHE_FILENAME).exists());
+
+        // A cached entry is used while the dist-info folder is unchanged.
+        install(&lib, "oldlib-1.0.0.dist-info", None);
+        assert_eq!(find_incompatible(&lib, &py312), expected);
+    }
+}
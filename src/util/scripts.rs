@@ -0,0 +1,360 @@
+//! Which package each console script in `entry_pt` came from, so installing a package doesn't
+//! replace another's script of the same name, uninstalling removes exactly its scripts, and
+//! `pyflow which` can say what a script runs. Recorded in `script_owners.toml`, next to the
+//! `bin` folder.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use serde::{Deserialize, Serialize};
+use termcolor::Color;
+
+use crate::util;
+
+pub const OWNERS_FILENAME: &str = "script_owners.toml";
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Options {
+    skip: bool,
+    force: bool,
+}
+
+static OPTIONS: OnceLock<Options> = OnceLock::new();
+
+/// Set from `generate_scripts` in `[tool.pyflow.settings]`, or `--no-scripts`, and
+/// `--force-scripts`. Only the first call has an effect.
+pub fn configure(skip: bool, force: bool) {
+    OPTIONS.get_or_init(|| Options { skip, force });
+}
+
+fn options() -> Options {
+    *OPTIONS.get_or_init(Options::default)
+}
+
+/// Don't create scripts when installing. Entry points stay in each package's `dist-info`, for
+/// `pyflow scripts regenerate`.
+pub fn skip() -> bool {
+    options().skip
+}
+
+/// Replace scripts other packages provide.
+pub fn force() -> bool {
+    options().force
+}
+
+/// The package that provides a console script, and what the script runs.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ScriptOwner {
+    pub package: String,
+    #[serde(default)]
+    pub version: String,
+    /// The callable, eg `black:patched_main`.
+    #[serde(default)]
+    pub target: String,
+    /// The launcher in `entry_pt`.
+    #[serde(default)]
+    pub path: PathBuf,
+}
+
+/// Script name to the package that provides it.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Owners {
+    #[serde(default)]
+    pub scripts: BTreeMap<String, ScriptOwner>,
+}
+
+/// Older versions of pyflow only recorded the package's name.
+#[derive(Deserialize)]
+struct LegacyOwners {
+    #[serde(default)]
+    scripts: BTreeMap<String, String>,
+}
+
+fn owners_path(entry_pt: &Path) -> PathBuf {
+    entry_pt.parent().unwrap_or(entry_pt).join(OWNERS_FILENAME)
+}
+
+impl Owners {
+    pub fn read(entry_pt: &Path) -> Self {
+        let data = match fs::read_to_string(owners_path(entry_pt)) {
+            Ok(d) => d,
+            Err(_) => return Self::default(),
+        };
+        if let Ok(owners) = toml::from_str(&data) {
+            return owners;
+        }
+        let legacy: LegacyOwners = toml::from_str(&data).unwrap_or(LegacyOwners {
+            scripts: BTreeMap::new(),
+        });
+        let scripts = legacy
+            .scripts
+            .into_iter()
+            .map(|(name, package)| {
+                let owner = ScriptOwner {
+                    package,
+                    path: entry_pt.join(&name),
+                    ..Default::default()
+                };
+                (name, owner)
+            })
+            .collect();
+        Self { scripts }
+    }
+
+    pub fn write(&self, entry_pt: &Path) {
+        let data = toml::to_string(self).expect("Problem serializing script owners");
+        if fs::write(owners_path(entry_pt), data).is_err() {
+            util::abort("Problem writing script owners")
+        }
+    }
+}
+
+/// The name of an entry point, eg `black` from `black=black:patched_main`.
+fn script_name(script: &str) -> Option<&str> {
+    script
+        .split('=')
+        .next()
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+}
+
+/// What an entry point runs, eg `black:patched_main` from `black=black:patched_main`.
+fn script_target(script: &str) -> String {
+    script
+        .split_once('=')
+        .map(|(_, t)| t.trim().to_owned())
+        .unwrap_or_default()
+}
+
+/// Of `package`'s entry points, the ones to create scripts for, and record it as their owner.
+/// A script already provided by another installed package is kept, with a warning, unless
+/// `force`.
+pub fn claim(
+    scripts: &[String],
+    package: &str,
+    version: &str,
+    entry_pt: &Path,
+    lib_path: &Path,
+    force: bool,
+) -> Vec<String> {
+    let mut owners = Owners::read(entry_pt);
+    let installed = util::find_installed(lib_path);
+    let mut result = vec![];
+
+    for script in scripts {
+        let name = match script_name(script) {
+            Some(n) => n,
+            None => continue,
+        };
+        let conflict = owners
+            .scripts
+            .get(name)
+            .map(|o| &o.package)
+            .filter(|owner| {
+                !util::compare_names(owner, package)
+                    && entry_pt.join(name).exists()
+                    && installed
+                        .iter()
+                        .any(|(n, _, _)| util::compare_names(n, owner))
+            });
+        if let Some(owner) = conflict {
+            if !force {
+                util::print_color(
+                    &format!(
+                        "{} and {} both provide the console script `{}`; keeping the one from {}. \
+                         Install with `--force-scripts` to replace it",
+                        owner, package, name, owner
+                    ),
+                    Color::Yellow,
+                );
+                continue;
+            }
+            util::print_color(
+                &format!(
+                    "Replacing the console script `{}` from {} with the one from {}",
+                    name, owner, package
+                ),
+                Color::Yellow,
+            );
+        }
+        let owner = ScriptOwner {
+            package: package.to_owned(),
+            version: version.to_owned(),
+            target: script_target(script),
+            path: entry_pt.join(name),
+        };
+        owners.scripts.insert(name.to_owned(), owner);
+        result.push(script.clone());
+    }
+
+    if !result.is_empty() {
+        owners.write(entry_pt);
+    }
+    result
+}
+
+/// Remove `package`'s console scripts, and their records. `entry_points` are the package's own,
+/// for scripts created before we recorded owners; ones another package owns are kept. Returns
+/// the names of the removed scripts.
+pub fn release(package: &str, entry_points: &[String], entry_pt: &Path) -> Vec<String> {
+    let mut owners = Owners::read(entry_pt);
+    let mut removed = vec![];
+
+    let owned: Vec<String> = owners
+        .scripts
+        .iter()
+        .filter(|(_, o)| util::compare_names(&o.package, package))
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in owned {
+        let owner = owners.scripts.remove(&name).unwrap();
+        let path = if owner.path.as_os_str().is_empty() {
+            entry_pt.join(&name)
+        } else {
+            owner.path
+        };
+        if fs::remove_file(path).is_ok() {
+            removed.push(name);
+        }
+    }
+    // Scripts that were just removed fail to be removed again.
+    let unrecorded = entry_points
+        .iter()
+        .filter_map(|s| script_name(s))
+        .filter(|name| !owners.scripts.contains_key(*name));
+    for name in unrecorded {
+        if fs::remove_file(entry_pt.join(name)).is_ok() {
+            removed.push(name.to_owned());
+        }
+    }
+
+    if !removed.is_empty() {
+        owners.write(entry_pt);
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn install_dist(lib_path: &Path, name: &str) {
+        fs::create_dir_all(lib_path.join(format!("{}-1.0.0.dist-info", name))).unwrap();
+    }
+
+    #[test]
+    fn conflicting_scripts_keep_first_owner() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let lib_path = dir.join("lib");
+        let entry_pt = dir.join("bin");
+        fs::create_dir_all(&entry_pt).unwrap();
+        install_dist(&lib_path, "chardet");
+        install_dist(&lib_path, "charset_normalizer");
+
+        let chardet = vec!["chardetect=chardet.cli.chardetect:main".to_owned()];
+        assert_eq!(
+            claim(&chardet, "chardet", "5.2.0", &entry_pt, &lib_path, false),
+            chardet
+        );
+        fs::write(entry_pt.join("chardetect"), "").unwrap();
+
+        let normalizer = vec![
+            "chardetect = charset_normalizer.cli:cli_detect".to_owned(),
+            "normalizer=charset_normalizer.cli:cli_detect".to_owned(),
+        ];
+        assert_eq!(
+            claim(
+                &normalizer,
+                "charset-normalizer",
+                "3.3.2",
+                &entry_pt,
+                &lib_path,
+                false
+            ),
+            &normalizer[1..]
+        );
+        assert_eq!(
+            Owners::read(&entry_pt).scripts["chardetect"].package,
+            "chardet"
+        );
+
+        // Reinstalling the owner replaces its own scripts.
+        assert_eq!(
+            claim(&chardet, "chardet", "5.2.0", &entry_pt, &lib_path, false),
+            chardet
+        );
+
+        assert_eq!(
+            claim(
+                &normalizer,
+                "charset-normalizer",
+                "3.3.2",
+                &entry_pt,
+                &lib_path,
+                true
+            ),
+            normalizer
+        );
+        assert_eq!(
+            Owners::read(&entry_pt).scripts["chardetect"].package,
+            "charset-normalizer"
+        );
+
+        // Once the owner is uninstalled, its script may be replaced.
+        fs::remove_dir_all(lib_path.join("charset_normalizer-1.0.0.dist-info")).unwrap();
+        assert_eq!(
+            claim(&chardet, "chardet", "5.2.0", &entry_pt, &lib_path, false),
+            chardet
+        );
+    }
+
+    #[test]
+    fn scripts_released_on_uninstall() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let lib_path = dir.join("lib");
+        let entry_pt = dir.join("bin");
+        fs::create_dir_all(&entry_pt).unwrap();
+        // Recorded by an older version of pyflow, with only the package name.
+        fs::write(
+            dir.join(OWNERS_FILENAME),
+            "[scripts]\nflake8 = \"flake8\"\n",
+        )
+        .unwrap();
+        let owners = Owners::read(&entry_pt);
+        assert_eq!(owners.scripts["flake8"].package, "flake8");
+        assert_eq!(owners.scripts["flake8"].path, entry_pt.join("flake8"));
+
+        let black = vec!["black=black:patched_main".to_owned()];
+        claim(&black, "black", "23.1.0", &entry_pt, &lib_path, false);
+        let owners = Owners::read(&entry_pt);
+        assert_eq!(
+            owners.scripts["black"],
+            ScriptOwner {
+                package: "black".into(),
+                version: "23.1.0".into(),
+                target: "black:patched_main".into(),
+                path: entry_pt.join("black"),
+            }
+        );
+
+        for name in ["black", "blackd", "flake8"] {
+            fs::write(entry_pt.join(name), "").unwrap();
+        }
+        // `flake8` is named in the entry points, but recorded as another package's.
+        let entry_points = [
+            "blackd = blackd:patched_main".to_owned(),
+            "flake8=flake8.main.cli:main".to_owned(),
+        ];
+        let mut removed = release("Black", &entry_points, &entry_pt);
+        removed.sort();
+        assert_eq!(removed, ["black", "blackd"]);
+        assert!(entry_pt.join("flake8").exists());
+        assert!(!Owners::read(&entry_pt).scripts.contains_key("black"));
+    }
+}
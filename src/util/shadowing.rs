@@ -0,0 +1,364 @@
+//! Find project dependencies imported from somewhere other than the environment, eg a copy in
+//! the system `site-packages`, or a folder in the working directory with the same name. These
+//! cause "it imports the wrong version" bugs that are hard to track down.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use termcolor::Color;
+
+use crate::{
+    dep_types::Version,
+    util::{self, print_color},
+};
+
+/// In the environment's folder, eg `__pypackages__/3.11`.
+pub const FILENAME: &str = "shadowing.json";
+
+/// Seconds before a package is checked again, in case something was installed globally.
+const TTL: u64 = 24 * 60 * 60;
+
+/// Prints where each module named in the arguments is imported from, with the project's paths,
+/// passed as JSON, after the script's folder on `sys.path`, as `PYTHONPATH` puts them, and
+/// without them. `find_spec` on `PathFinder` doesn't import anything.
+const PROBE: &str = r#"
+import json, sys
+from importlib.machinery import PathFinder
+
+def where(name, path):
+    try:
+        spec = PathFinder.find_spec(name, path)
+    except (ImportError, ValueError):
+        return None
+    if spec is None:
+        return None
+    if spec.origin and spec.has_location:
+        return spec.origin
+    locations = list(spec.submodule_search_locations or [])
+    return locations[0] if locations else None
+
+project = json.loads(sys.argv[1])
+with_project = sys.path[:1] + project + sys.path[1:]
+print(json.dumps({m: [where(m, with_project), where(m, sys.path)] for m in sys.argv[2:]}))
+"#;
+
+/// Where each module is imported from, with the project's paths, and without them.
+type Locations = HashMap<String, (Option<PathBuf>, Option<PathBuf>)>;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Shadowed {
+    pub package: String,
+    pub module: String,
+    /// Where it's imported from instead.
+    pub location: PathBuf,
+    /// Of the copy there, from its `dist-info`, if it has one.
+    pub version: Option<String>,
+    /// If it's shadowed with the project's paths on `PYTHONPATH`, as `pyflow run` sets them.
+    /// Otherwise, only code that ignores `PYTHONPATH`, eg `python -I`, imports the other copy.
+    pub with_pythonpath: bool,
+}
+
+impl Shadowed {
+    pub fn describe(&self) -> String {
+        let version = self
+            .version
+            .as_ref()
+            .map(|v| format!(" (version {})", v))
+            .unwrap_or_default();
+        let when = if self.with_pythonpath {
+            ""
+        } else {
+            ", when `PYTHONPATH` is ignored"
+        };
+        format!(
+            "`{}`, from {}, is imported from {}{}{}",
+            self.module,
+            self.package,
+            self.location.display(),
+            version,
+            when
+        )
+    }
+}
+
+/// Results by package, for one interpreter.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Cache {
+    /// The interpreter's path and modification time.
+    interpreter: String,
+    /// By `name==version`: when it was checked, in seconds since the Unix epoch, and the result.
+    packages: HashMap<String, (u64, Vec<Shadowed>)>,
+}
+
+impl Cache {
+    /// A missing or unreadable file, or one for another interpreter, gives an empty cache.
+    fn load(path: &Path, interpreter: &str) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|data| serde_json::from_str::<Self>(&data).ok())
+            .filter(|c| c.interpreter == interpreter)
+            .unwrap_or_else(|| Self {
+                interpreter: interpreter.to_owned(),
+                packages: HashMap::new(),
+            })
+    }
+
+    fn save(&self, path: &Path) {
+        let data = match serde_json::to_string_pretty(self) {
+            Ok(d) => d,
+            Err(_) => return,
+        };
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        if fs::write(&tmp, data).is_ok() && fs::rename(&tmp, path).is_err() {
+            fs::remove_file(&tmp).ok();
+        }
+    }
+}
+
+fn interpreter_id(python: &Path) -> String {
+    let modified = fs::metadata(python)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    format!("{}@{}", python.display(), modified)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Ask `python` where `modules` import from, from `cwd`. Empty if it can't run.
+fn probe(python: &Path, cwd: &Path, project_paths: &[PathBuf], modules: &[String]) -> Locations {
+    let paths: Vec<String> = project_paths
+        .iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    let output = Command::new(python)
+        .args([
+            "-c",
+            PROBE,
+            &serde_json::to_string(&paths).unwrap_or_default(),
+        ])
+        .args(modules)
+        .current_dir(cwd)
+        .env_remove("PYTHONPATH")
+        .output();
+    match output {
+        Ok(o) if o.status.success() => serde_json::from_slice(&o.stdout).unwrap_or_default(),
+        _ => HashMap::new(),
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+/// The version of the package at `location`, from a `dist-info` folder beside it. We don't
+/// parse it, since other tools accept versions we don't.
+fn version_at(location: &Path, package: &str, module: &str) -> Option<String> {
+    location.ancestors().skip(1).take(3).find_map(|dir| {
+        fs::read_dir(dir).ok()?.flatten().find_map(|entry| {
+            let folder_name = entry.file_name().to_string_lossy().into_owned();
+            let (name, version) = folder_name.strip_suffix(".dist-info")?.rsplit_once('-')?;
+            (util::compare_names(name, package) || util::compare_names(name, module))
+                .then(|| version.to_owned())
+        })
+    })
+}
+
+/// Check packages that aren't in the cache, or were checked more than a day ago, with one call
+/// to `probe`, and return what shadows each of `installed`.
+fn find_with(
+    installed: &[(String, Version, Vec<String>)],
+    project_paths: &[PathBuf],
+    cache: &mut Cache,
+    now: u64,
+    probe: impl FnOnce(&[String]) -> Locations,
+) -> Vec<Shadowed> {
+    let key = |name: &str, version: &Version| format!("{}=={}", name.to_lowercase(), version);
+    let stale: Vec<&(String, Version, Vec<String>)> = installed
+        .iter()
+        .filter(|(name, version, _)| {
+            cache
+                .packages
+                .get(&key(name, version))
+                .is_none_or(|(checked, _)| now.saturating_sub(*checked) >= TTL)
+        })
+        .collect();
+
+    if !stale.is_empty() {
+        let modules: Vec<String> = stale.iter().flat_map(|(_, _, m)| m.clone()).collect();
+        let locations = probe(&modules);
+        let roots: Vec<PathBuf> = project_paths.iter().map(|p| canonical(p)).collect();
+        let outside = |location: &Option<PathBuf>| {
+            location
+                .as_ref()
+                .filter(|l| !roots.iter().any(|r| canonical(l).starts_with(r)))
+                .cloned()
+        };
+
+        for (name, version, modules) in stale {
+            let mut shadowed = vec![];
+            for module in modules {
+                let (with_paths, without) = match locations.get(module) {
+                    Some(l) => l,
+                    None => continue,
+                };
+                let found = match (outside(with_paths), outside(without)) {
+                    (Some(location), _) => (location, true),
+                    (None, Some(location)) => (location, false),
+                    (None, None) => continue,
+                };
+                shadowed.push(Shadowed {
+                    package: name.clone(),
+                    module: module.clone(),
+                    version: version_at(&found.0, name, module),
+                    location: found.0,
+                    with_pythonpath: found.1,
+                });
+            }
+            cache.packages.insert(key(name, version), (now, shadowed));
+        }
+    }
+
+    installed
+        .iter()
+        .filter_map(|(name, version, _)| cache.packages.get(&key(name, version)))
+        .flat_map(|(_, shadowed)| shadowed.clone())
+        .collect()
+}
+
+/// Find modules of the packages in `project_paths[0]`, the environment's `lib`, that `python`
+/// imports from elsewhere, when run from `cwd`. `project_paths` also has path requirements.
+/// Results are kept in `cache_path`, if passed.
+pub fn find(
+    python: &Path,
+    cwd: &Path,
+    project_paths: &[PathBuf],
+    cache_path: Option<&Path>,
+) -> Vec<Shadowed> {
+    let installed = match project_paths.first() {
+        Some(lib) => util::find_installed(lib),
+        None => return vec![],
+    };
+    let interpreter = interpreter_id(python);
+    let mut cache = match cache_path {
+        Some(path) => Cache::load(path, &interpreter),
+        None => Cache::default(),
+    };
+    let result = find_with(&installed, project_paths, &mut cache, now(), |modules| {
+        probe(python, cwd, project_paths, modules)
+    });
+    if let Some(path) = cache_path {
+        cache.save(path);
+    }
+    result
+}
+
+/// Before `pyflow run`, if `check_shadowing` is set: Warn about dependencies it would import
+/// from elsewhere. Only packages installed or changed since the last run spawn Python.
+pub fn warn(python: &Path, cwd: &Path, project_paths: &[PathBuf], cache_path: &Path) {
+    for shadowed in find(python, cwd, project_paths, Some(cache_path)) {
+        if shadowed.with_pythonpath {
+            print_color(&format!("Warning: {}", shadowed.describe()), Color::Yellow);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shadowed_packages_cached() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let lib = dir.join("lib");
+        let site = dir.join("site-packages");
+        fs::create_dir_all(&lib).unwrap();
+        fs::create_dir_all(site.join("requests-2.0.0.dist-info")).unwrap();
+
+        let installed = vec![
+            (
+                "requests".to_owned(),
+                Version::new(2, 31, 0),
+                vec!["requests".to_owned()],
+            ),
+            (
+                "six".to_owned(),
+                Version::new(1, 16, 0),
+                vec!["six".to_owned()],
+            ),
+            (
+                "attrs".to_owned(),
+                Version::new(23, 1, 0),
+                vec!["attrs".to_owned()],
+            ),
+        ];
+        let locations: Locations = [
+            (
+                "requests",
+                Some(site.join("requests/__init__.py")),
+                Some(site.join("requests/__init__.py")),
+            ),
+            ("six", Some(lib.join("six.py")), None),
+            (
+                "attrs",
+                Some(lib.join("attrs/__init__.py")),
+                Some(site.join("attrs")),
+            ),
+        ]
+        .into_iter()
+        .map(|(m, with, without)| (m.to_owned(), (with, without)))
+        .collect();
+
+        let mut cache = Cache::default();
+        let shadowed = find_with(
+            &installed,
+            std::slice::from_ref(&lib),
+            &mut cache,
+            1_000,
+            |modules| {
+                assert_eq!(modules, ["requests", "six", "attrs"]);
+                locations.clone()
+            },
+        );
+        assert_eq!(
+            shadowed,
+            vec![
+                Shadowed {
+                    package: "requests".into(),
+                    module: "requests".into(),
+                    location: site.join("requests/__init__.py"),
+                    version: Some("2.0.0".into()),
+                    with_pythonpath: true,
+                },
+                Shadowed {
+                    package: "attrs".into(),
+                    module: "attrs".into(),
+                    location: site.join("attrs"),
+                    version: None,
+                    with_pythonpath: false,
+                },
+            ]
+        );
+
+        // Only a newly installed package is checked.
+        let mut updated = installed.clone();
+        updated[1].1 = Version::new(1, 17, 0);
+        let again = find_with(&updated, &[lib], &mut cache, 2_000, |modules| {
+            assert_eq!(modules, ["six"]);
+            locations.clone()
+        });
+        assert_eq!(again, shadowed);
+    }
+}
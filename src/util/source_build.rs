@@ -0,0 +1,234 @@
+//! Run builds from source, eg `setup.py bdist_wheel`, which can take minutes. Their output is
+//! streamed into the verbose log as it arrives, while a status line shows how long the build
+//! has taken, so it doesn't look hung. Builds that take longer than the timeout are stopped.
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+    sync::{mpsc, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::CliConfig;
+
+/// How long a build may take, unless `build_timeout` in `[tool.pyflow]` sets another number of
+/// seconds.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// How many of the last lines of output to show when a build fails.
+const OUTPUT_TAIL: usize = 20;
+
+/// How often to print a status line when stdout isn't a terminal, and we can't update in place.
+const PLAIN_STATUS_INTERVAL: Duration = Duration::from_secs(30);
+
+static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// Set the timeout from the project's config. Only the first call has an effect.
+pub fn configure(timeout_secs: Option<u64>) {
+    TIMEOUT.get_or_init(|| timeout_secs.map_or(DEFAULT_TIMEOUT, Duration::from_secs));
+}
+
+fn timeout() -> Duration {
+    *TIMEOUT.get_or_init(|| DEFAULT_TIMEOUT)
+}
+
+/// Eg `1m23s`.
+fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    if secs < 60 {
+        format!("{}s", secs)
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+/// Send each line from `stream` to `tx`, until it closes.
+fn forward_lines(stream: impl Read + Send + 'static, tx: mpsc::Sender<String>) {
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// A single status line, eg `building numpy 1.24.2 from source… 1m23s`.
+struct Status<'a> {
+    label: &'a str,
+    start: Instant,
+    is_tty: bool,
+    last_line_len: usize,
+    last_print: Option<Instant>,
+}
+
+impl Status<'_> {
+    fn show(&mut self) {
+        let line = format!("{}… {}", self.label, format_elapsed(self.start.elapsed()));
+        if self.is_tty {
+            let padding = self.last_line_len.saturating_sub(line.len());
+            print!("\r{}{}", line, " ".repeat(padding));
+            io::stdout().flush().ok();
+            self.last_line_len = line.len();
+        } else if self
+            .last_print
+            .is_none_or(|t| t.elapsed() >= PLAIN_STATUS_INTERVAL)
+        {
+            println!("{}", line);
+            self.last_print = Some(Instant::now());
+        }
+    }
+
+    /// Remove the status line, so it doesn't mix with other output.
+    fn clear(&mut self) {
+        if self.is_tty && self.last_line_len > 0 {
+            print!("\r{}\r", " ".repeat(self.last_line_len));
+            io::stdout().flush().ok();
+            self.last_line_len = 0;
+        }
+    }
+}
+
+/// Run `python` with `args` in `dir`, labelled eg `building numpy 1.24.2 from source`. Errors
+/// end with the last lines of the build's output.
+pub fn run(python: &Path, dir: &Path, args: &[&str], label: &str) -> Result<(), String> {
+    run_with_timeout(python, dir, args, label, timeout())
+}
+
+fn run_with_timeout(
+    python: &Path,
+    dir: &Path,
+    args: &[&str],
+    label: &str,
+    timeout: Duration,
+) -> Result<(), String> {
+    let mut child = Command::new(python)
+        .current_dir(dir)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Problem running {}: {}", python.display(), e))?;
+
+    let (tx, rx) = mpsc::channel();
+    forward_lines(child.stdout.take().unwrap(), tx.clone());
+    forward_lines(child.stderr.take().unwrap(), tx);
+
+    let verbose = CliConfig::current().verbose;
+    let mut status = Status {
+        label,
+        start: Instant::now(),
+        is_tty: atty::is(atty::Stream::Stdout),
+        last_line_len: 0,
+        last_print: None,
+    };
+    let mut tail = VecDeque::with_capacity(OUTPUT_TAIL);
+    let tail_str = |tail: &VecDeque<String>| Vec::from(tail.clone()).join("\n");
+
+    loop {
+        match rx.recv_timeout(Duration::from_millis(250)) {
+            Ok(line) => {
+                if verbose {
+                    status.clear();
+                    println!("{}", line);
+                }
+                if tail.len() == OUTPUT_TAIL {
+                    tail.pop_front();
+                }
+                tail.push_back(line);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            // Both streams are closed, so the build is done.
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        if status.start.elapsed() > timeout {
+            child.kill().ok();
+            child.wait().ok();
+            status.clear();
+            return Err(format!(
+                "{} took longer than {}, so it was stopped. Set `build_timeout` in \
+                 `[tool.pyflow]` to a number of seconds to allow longer builds. Its last \
+                 output:\n{}",
+                label,
+                format_elapsed(timeout),
+                tail_str(&tail)
+            ));
+        }
+        status.show();
+    }
+
+    let exit = child.wait();
+    status.clear();
+    match exit {
+        Ok(s) if s.success() => Ok(()),
+        Ok(s) => Err(format!("{} failed ({}):\n{}", label, s, tail_str(&tail))),
+        Err(e) => Err(format!("{} failed: {}", label, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn python() -> Option<&'static Path> {
+        let python = Path::new("python3");
+        Command::new(python)
+            .arg("--version")
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|_| python)
+    }
+
+    #[test]
+    fn elapsed_formatted() {
+        assert_eq!(format_elapsed(Duration::from_secs(9)), "9s");
+        assert_eq!(format_elapsed(Duration::from_secs(83)), "1m23s");
+    }
+
+    #[test]
+    fn build_output_kept_and_slow_builds_stopped() {
+        let python = match python() {
+            Some(p) => p,
+            None => return,
+        };
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        fs::write(
+            dir.join("setup.py"),
+            "import sys, time\n\
+             for i in range(30):\n    print('compiling', i, flush=True)\n\
+             print('missing compiler', file=sys.stderr, flush=True)\n\
+             if sys.argv[1] == 'slow':\n    time.sleep(30)\n\
+             sys.exit(1)\n",
+        )
+        .unwrap();
+
+        let started = Instant::now();
+        let err = run_with_timeout(
+            python,
+            &dir,
+            &["setup.py", "fail"],
+            "building demo",
+            Duration::from_secs(20),
+        )
+        .unwrap_err();
+        assert!(err.contains("compiling 29") && err.contains("missing compiler"));
+        assert!(!err.contains("compiling 9\n"));
+
+        let err = run_with_timeout(
+            python,
+            &dir,
+            &["setup.py", "slow"],
+            "building demo",
+            Duration::from_secs(1),
+        )
+        .unwrap_err();
+        assert!(err.contains("took longer than 1s"));
+        assert!(started.elapsed() < Duration::from_secs(20));
+    }
+}
@@ -0,0 +1,229 @@
+//! Wall time spent in each phase of a command, shown with `--timings`. Timers do nothing
+//! unless that flag is set.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TIMINGS: Mutex<Vec<Timing>> = Mutex::new(Vec::new());
+
+/// How many packages to list in the report.
+const SLOWEST_PACKAGES: usize = 10;
+
+struct Timing {
+    phase: &'static str,
+    package: Option<String>,
+    duration: Duration,
+}
+
+/// Records the time from its creation to when it's dropped.
+pub struct Timer {
+    phase: &'static str,
+    package: Option<String>,
+    start: Option<Instant>,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(start) = self.start {
+            record(self.phase, self.package.take(), start.elapsed());
+        }
+    }
+}
+
+/// Prints the report when dropped, eg at the end of `main`.
+pub struct Report {
+    json: bool,
+}
+
+impl Drop for Report {
+    fn drop(&mut self) {
+        print_report(self.json);
+    }
+}
+
+/// Start recording. The report is printed when the result is dropped.
+pub fn enable(json: bool) -> Report {
+    ENABLED.store(true, Ordering::Relaxed);
+    Report { json }
+}
+
+/// Time a phase until the result is dropped. `package` is set for per-package work, eg
+/// downloads.
+pub fn start(phase: &'static str, package: Option<&str>) -> Timer {
+    let enabled = ENABLED.load(Ordering::Relaxed);
+    Timer {
+        phase,
+        package: package.filter(|_| enabled).map(str::to_owned),
+        start: enabled.then(Instant::now),
+    }
+}
+
+fn record(phase: &'static str, package: Option<String>, duration: Duration) {
+    if let Ok(mut timings) = TIMINGS.lock() {
+        timings.push(Timing {
+            phase,
+            package,
+            duration,
+        });
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct PhaseSummary {
+    phase: String,
+    count: usize,
+    seconds: f64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct PackageSummary {
+    package: String,
+    seconds: f64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Summary {
+    phases: Vec<PhaseSummary>,
+    slowest_packages: Vec<PackageSummary>,
+}
+
+/// Total each phase and package, slowest first.
+fn summarize(timings: &[Timing]) -> Summary {
+    let mut phases: HashMap<&str, (usize, Duration)> = HashMap::new();
+    let mut packages: HashMap<&str, Duration> = HashMap::new();
+    for t in timings {
+        let phase = phases.entry(t.phase).or_default();
+        phase.0 += 1;
+        phase.1 += t.duration;
+        if let Some(package) = &t.package {
+            *packages.entry(package).or_default() += t.duration;
+        }
+    }
+
+    let mut phases: Vec<(&str, (usize, Duration))> = phases.into_iter().collect();
+    phases.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then(a.0.cmp(b.0)));
+    let mut packages: Vec<(&str, Duration)> = packages.into_iter().collect();
+    packages.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+    Summary {
+        phases: phases
+            .into_iter()
+            .map(|(phase, (count, duration))| PhaseSummary {
+                phase: phase.to_owned(),
+                count,
+                seconds: duration.as_secs_f64(),
+            })
+            .collect(),
+        slowest_packages: packages
+            .into_iter()
+            .take(SLOWEST_PACKAGES)
+            .map(|(package, duration)| PackageSummary {
+                package: package.to_owned(),
+                seconds: duration.as_secs_f64(),
+            })
+            .collect(),
+    }
+}
+
+fn print_report(json: bool) {
+    let summary = match TIMINGS.lock() {
+        Ok(timings) => summarize(&timings),
+        Err(_) => return,
+    };
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&summary).expect("Problem serializing timings")
+        );
+        return;
+    }
+
+    let width = summary
+        .phases
+        .iter()
+        .map(|p| p.phase.len())
+        .chain(summary.slowest_packages.iter().map(|p| p.package.len()))
+        .max()
+        .unwrap_or_default();
+
+    println!("\n{:<width$}  {:>5}  {:>9}", "Phase", "Count", "Time (s)");
+    for p in &summary.phases {
+        println!("{:<width$}  {:>5}  {:>9.3}", p.phase, p.count, p.seconds);
+    }
+    if !summary.slowest_packages.is_empty() {
+        println!("\n{:<width$}  {:>9}", "Slowest packages", "Time (s)");
+        for p in &summary.slowest_packages {
+            println!("{:<width$}  {:>9.3}", p.package, p.seconds);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(phase: &'static str, package: Option<&str>, millis: u64) -> Timing {
+        Timing {
+            phase,
+            package: package.map(str::to_owned),
+            duration: Duration::from_millis(millis),
+        }
+    }
+
+    #[test]
+    fn summary_sorted_by_time() {
+        let timings = vec![
+            timing("download", Some("numpy"), 3000),
+            timing("download", Some("six"), 500),
+            timing("extract and build", Some("six"), 250),
+            timing("resolve", None, 2000),
+            timing("lock write", None, 10),
+        ];
+        let summary = summarize(&timings);
+
+        let phases: Vec<(&str, usize)> = summary
+            .phases
+            .iter()
+            .map(|p| (p.phase.as_str(), p.count))
+            .collect();
+        assert_eq!(
+            phases,
+            vec![
+                ("download", 2),
+                ("resolve", 1),
+                ("extract and build", 1),
+                ("lock write", 1)
+            ]
+        );
+        assert_eq!(
+            summary.slowest_packages,
+            vec![
+                PackageSummary {
+                    package: "numpy".into(),
+                    seconds: 3.
+                },
+                PackageSummary {
+                    package: "six".into(),
+                    seconds: 0.75
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_timer_records_nothing() {
+        let timer = start("download", Some("numpy"));
+        assert!(timer.start.is_none());
+        assert!(timer.package.is_none());
+    }
+}
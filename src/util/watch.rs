@@ -0,0 +1,296 @@
+//! Find changed files for `pyflow run --watch`. We poll modification times instead of using OS
+//! file events, which differ between platforms, and often come in bursts, or twice for one save.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ignore::{
+    overrides::{Override, OverrideBuilder},
+    WalkBuilder,
+};
+
+use crate::util;
+
+/// How often to check for changes.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long files must stay unchanged before we run again, eg while an editor saves several.
+pub const QUIET_PERIOD: Duration = Duration::from_millis(300);
+
+/// Folders never watched; Python writes to `__pycache__` while running, which would otherwise
+/// trigger another run.
+const SKIPPED: [&str; 3] = ["__pycache__", "__pypackages__", ".venv"];
+
+/// Whether a `--watch` argument is a glob, eg `src/**/*.py`, as opposed to the command to run.
+pub fn is_glob(arg: &str) -> bool {
+    arg.contains(['*', '?', '[', '/', '\\'])
+}
+
+/// What to watch when no globs are given: The project's package folder, and `tests`.
+pub fn default_globs(root: &Path, project_name: Option<&str>) -> Vec<String> {
+    let package = project_name.map(util::standardize_name);
+    let mut folders: Vec<String> = package
+        .iter()
+        .flat_map(|p| [p.clone(), format!("src/{}", p)])
+        .collect();
+    folders.push("tests".into());
+    let mut result: Vec<String> = folders
+        .into_iter()
+        .filter(|f| root.join(f).is_dir())
+        .map(|f| format!("{}/**", f))
+        .collect();
+    if result.is_empty() {
+        result.push("**/*.py".into());
+    }
+    result
+}
+
+/// The folder a glob's matches are in, so we don't walk the whole project for each, eg `src`
+/// for `src/**/*.py`.
+fn glob_base(glob: &str) -> PathBuf {
+    glob.split('/')
+        .take_while(|part| !is_glob(part))
+        .collect::<Vec<_>>()
+        .join("/")
+        .into()
+}
+
+pub struct Watcher {
+    root: PathBuf,
+    bases: Vec<PathBuf>,
+    globs: Override,
+}
+
+impl Watcher {
+    pub fn new(root: &Path, globs: &[String]) -> Result<Self, String> {
+        let mut builder = OverrideBuilder::new(root);
+        for glob in globs {
+            builder
+                .add(glob)
+                .map_err(|e| format!("Problem with the glob {}: {}", glob, e))?;
+        }
+        let mut bases: Vec<PathBuf> = globs.iter().map(|g| glob_base(g)).collect();
+        bases.sort();
+        // Nested folders would be walked twice.
+        bases.dedup_by(|b, parent| b.starts_with(parent));
+        Ok(Self {
+            root: root.to_owned(),
+            bases,
+            globs: builder.build().map_err(|e| e.to_string())?,
+        })
+    }
+
+    /// Modification times of the watched files.
+    pub fn snapshot(&self) -> BTreeMap<PathBuf, SystemTime> {
+        let mut result = BTreeMap::new();
+        for base in &self.bases {
+            let walk = WalkBuilder::new(self.root.join(base))
+                .filter_entry(|e| !SKIPPED.contains(&e.file_name().to_string_lossy().as_ref()))
+                .build();
+            for entry in walk.flatten() {
+                let path = entry.path();
+                let relative = path.strip_prefix(&self.root).unwrap_or(path);
+                if !entry.file_type().is_some_and(|t| t.is_file())
+                    || !self.globs.matched(relative, false).is_whitelist()
+                {
+                    continue;
+                }
+                if let Some(modified) = entry.metadata().ok().and_then(|m| m.modified().ok()) {
+                    result.insert(relative.to_owned(), modified);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Files added, changed, or removed between two snapshots.
+pub fn changes(
+    old: &BTreeMap<PathBuf, SystemTime>,
+    new: &BTreeMap<PathBuf, SystemTime>,
+) -> Vec<PathBuf> {
+    let changed = new.iter().filter(|(p, t)| old.get(*p) != Some(t));
+    let removed = old.iter().filter(|(p, _)| !new.contains_key(*p));
+    changed.chain(removed).map(|(p, _)| p.clone()).collect()
+}
+
+/// Combines bursts of changes into one, once they've stopped for `quiet`.
+pub struct Debounce {
+    quiet: Duration,
+    /// The first path changed in the current burst, and when the latest change was.
+    pending: Option<(PathBuf, Instant)>,
+}
+
+impl Debounce {
+    pub fn new(quiet: Duration) -> Self {
+        Self {
+            quiet,
+            pending: None,
+        }
+    }
+
+    pub fn changed(&mut self, path: &Path, at: Instant) {
+        let first = match self.pending.take() {
+            Some((first, _)) => first,
+            None => path.to_owned(),
+        };
+        self.pending = Some((first, at));
+    }
+
+    /// The path that started a burst of changes, once it's over.
+    pub fn ready(&mut self, now: Instant) -> Option<PathBuf> {
+        match &self.pending {
+            Some((_, last)) if now.duration_since(*last) >= self.quiet => {
+                self.pending.take().map(|(path, _)| path)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The current time of day, in UTC, eg `14:03:27 UTC`.
+pub fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+        % 86_400;
+    format!(
+        "{:02}:{:02}:{:02} UTC",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli_options::ExternalCommand;
+    use std::fs;
+
+    #[test]
+    fn bursts_and_duplicates_debounced() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut debounce = Debounce::new(Duration::from_millis(300));
+        assert_eq!(debounce.ready(ms(0)), None);
+
+        // An editor's save: The file twice, as on macOS, then a temporary file, as on Windows.
+        let events = [
+            (0, "pkg/a.py"),
+            (5, "pkg/a.py"),
+            (40, "pkg/a.py~"),
+            (120, "pkg/a.py"),
+        ];
+        for (at, path) in events {
+            debounce.changed(Path::new(path), ms(at));
+            assert_eq!(debounce.ready(ms(at)), None);
+        }
+        assert_eq!(debounce.ready(ms(400)), None);
+        assert_eq!(debounce.ready(ms(420)), Some("pkg/a.py".into()));
+        assert_eq!(debounce.ready(ms(2000)), None);
+
+        // Changes further apart each run.
+        debounce.changed(Path::new("tests/test_a.py"), ms(3000));
+        assert_eq!(debounce.ready(ms(3300)), Some("tests/test_a.py".into()));
+        debounce.changed(Path::new("pkg/b.py"), ms(3400));
+        assert_eq!(debounce.ready(ms(3700)), Some("pkg/b.py".into()));
+    }
+
+    #[test]
+    fn watched_files_and_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        for folder in [
+            "mypkg/__pycache__",
+            "tests",
+            "docs",
+            "__pypackages__/3.11/lib",
+        ] {
+            fs::create_dir_all(dir.join(folder)).unwrap();
+        }
+        for file in [
+            "mypkg/__init__.py",
+            "mypkg/data.json",
+            "mypkg/__pycache__/__init__.cpython-311.pyc",
+            "tests/test_a.py",
+            "docs/index.md",
+            "__pypackages__/3.11/lib/six.py",
+        ] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+
+        let globs = default_globs(&dir, Some("MyPkg"));
+        assert_eq!(globs, vec!["mypkg/**", "tests/**"]);
+        let watched = |globs: &[String]| -> Vec<PathBuf> {
+            let watcher = Watcher::new(&dir, globs).unwrap();
+            watcher.snapshot().into_keys().collect()
+        };
+        assert_eq!(
+            watched(&globs),
+            vec![
+                PathBuf::from("mypkg/__init__.py"),
+                "mypkg/data.json".into(),
+                "tests/test_a.py".into()
+            ]
+        );
+        let py: Vec<String> = vec!["**/*.py".into()];
+        assert_eq!(
+            watched(&py),
+            vec![PathBuf::from("mypkg/__init__.py"), "tests/test_a.py".into()]
+        );
+        assert_eq!(default_globs(&dir.join("docs"), None), py);
+        assert_eq!(glob_base("src/**/*.py"), PathBuf::from("src"));
+        assert_eq!(glob_base("*.py"), PathBuf::from(""));
+
+        let watcher = Watcher::new(&dir, &globs).unwrap();
+        let before = watcher.snapshot();
+        assert!(changes(&before, &watcher.snapshot()).is_empty());
+        fs::remove_file(dir.join("mypkg/data.json")).unwrap();
+        fs::write(dir.join("tests/test_b.py"), "").unwrap();
+        assert_eq!(
+            changes(&before, &watcher.snapshot()),
+            vec![PathBuf::from("tests/test_b.py"), "mypkg/data.json".into()]
+        );
+    }
+
+    #[test]
+    fn watch_options_parsed() {
+        assert!(is_glob("src/**/*.py"));
+        assert!(is_glob("*.py"));
+        assert!(is_glob("mypkg/"));
+        assert!(!is_glob("pytest"));
+        assert!(!is_glob("black"));
+
+        let parse = |args: &[&str]| {
+            let cmd = ExternalCommand::from_opt(args.iter().map(|a| a.to_string()).collect());
+            (cmd.watch, cmd.kill, cmd.args)
+        };
+        assert_eq!(
+            parse(&["run", "--watch", "src/**/*.py", "pytest", "tests/"]),
+            (
+                Some(vec!["src/**/*.py".into()]),
+                true,
+                vec!["pytest".into(), "tests/".into()]
+            )
+        );
+        assert_eq!(
+            parse(&["run", "--watch", "--no-kill", "pytest"]),
+            (Some(vec![]), false, vec!["pytest".into()])
+        );
+        assert_eq!(
+            parse(&["run", "--watch=mypkg", "--watch", "*.toml", "--", "--watch"]),
+            (
+                Some(vec!["mypkg".into(), "*.toml".into()]),
+                true,
+                vec!["--watch".into()]
+            )
+        );
+        assert_eq!(parse(&["pytest", "--watch"]).0, None);
+        assert_eq!(parse(&["python", "--watch"]).0, None);
+    }
+}
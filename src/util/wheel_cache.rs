@@ -0,0 +1,148 @@
+//! Wheels we've built from source dists and git repos, kept in the dependency cache so other
+//! installs don't build them again. Each is stored in a folder named for the package, its
+//! source, and the Python that built it, with its hash, which we check before reusing it.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use ring::digest;
+use termcolor::Color;
+
+use crate::{commands, dep_types::Version, install, util};
+
+/// In the dependency cache.
+pub const DIR_NAME: &str = "built_wheels";
+
+const HASH_FILENAME: &str = "sha256";
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// For `--no-build-cache`: always build, and don't store what we build.
+pub fn disable() {
+    DISABLED.store(true, Ordering::SeqCst);
+}
+
+fn disabled() -> bool {
+    DISABLED.load(Ordering::SeqCst)
+}
+
+fn hash_file(path: &Path) -> Option<String> {
+    let file = fs::File::open(path).ok()?;
+    let digest = install::sha256_digest(io::BufReader::new(file)).ok()?;
+    Some(data_encoding::HEXLOWER.encode(digest.as_ref()))
+}
+
+/// The tag of the Python that builds wheels, eg `cp311`.
+pub fn py_tag(python: &Path) -> String {
+    commands::find_py_version(&python.to_string_lossy())
+        .map(|v| format!("cp{}{}", v.major.unwrap_or(0), v.minor.unwrap_or(0)))
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// Identifies a build. `source` is the sdist's sha256, or a git repo's commit, which decide
+/// what's built. Git dependencies have no version until they're built.
+pub fn key(name: &str, version: Option<&Version>, source: &str, py_tag: &str) -> String {
+    let source_hash =
+        data_encoding::HEXLOWER.encode(digest::digest(&digest::SHA256, source.as_bytes()).as_ref());
+    let version = version.map_or_else(|| "git".to_owned(), |v| v.to_string());
+    format!(
+        "{}-{}-{}-{}",
+        util::standardize_name(name),
+        version,
+        &source_hash[..16],
+        py_tag
+    )
+}
+
+/// A cached wheel for the build, if there's one, and it's intact. Broken entries are removed.
+pub fn lookup(cache: &Path, key: &str) -> Option<PathBuf> {
+    if disabled() {
+        return None;
+    }
+    let entry = cache.join(DIR_NAME).join(key);
+    let expected = fs::read_to_string(entry.join(HASH_FILENAME)).ok()?;
+    let wheel = fs::read_dir(&entry)
+        .ok()?
+        .flatten()
+        .map(|e| e.path())
+        .find(|p| p.extension().is_some_and(|e| e == "whl"));
+
+    match wheel {
+        Some(w) if hash_file(&w).as_deref() == Some(expected.trim()) => {
+            util::cache_policy::record_use(&cache.join(DIR_NAME), key);
+            Some(w)
+        }
+        _ => {
+            util::print_color(
+                &format!("Rebuilding {}; its cached wheel is damaged", key),
+                Color::Yellow,
+            );
+            fs::remove_dir_all(&entry).ok();
+            None
+        }
+    }
+}
+
+/// Copy a wheel we've built into the cache, and return its path there. Returns `wheel` as-is if
+/// the cache is disabled, or we can't write to it.
+pub fn store(cache: &Path, key: &str, wheel: &Path) -> PathBuf {
+    if disabled() {
+        return wheel.to_owned();
+    }
+    let entry = cache.join(DIR_NAME).join(key);
+    let filename = match wheel.file_name() {
+        Some(f) => f,
+        None => return wheel.to_owned(),
+    };
+    // Assemble the entry elsewhere, so other processes never see it half-written.
+    let tmp = cache
+        .join(DIR_NAME)
+        .join(format!(".{}-{}", key, process::id()));
+    let stored = (|| -> io::Result<()> {
+        fs::create_dir_all(&tmp)?;
+        fs::copy(wheel, tmp.join(filename))?;
+        let hash = hash_file(wheel).ok_or_else(|| io::Error::other("Can't hash the wheel"))?;
+        fs::write(tmp.join(HASH_FILENAME), hash)?;
+        if entry.exists() {
+            fs::remove_dir_all(&entry)?;
+        }
+        fs::rename(&tmp, &entry)
+    })();
+
+    if stored.is_err() {
+        fs::remove_dir_all(&tmp).ok();
+        return wheel.to_owned();
+    }
+    util::cache_policy::record_use(&cache.join(DIR_NAME), key);
+    entry.join(filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn damaged_wheels_not_reused() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let built = dir.join("fixture-1.0-py3-none-any.whl");
+        fs::write(&built, b"wheel").unwrap();
+
+        let key = key("Fixture", Some(&Version::new(1, 0, 0)), "abc123", "cp311");
+        assert!(key.starts_with("fixture-1.0.0-"));
+        assert!(key.ends_with("-cp311"));
+        assert_eq!(lookup(&dir, &key), None);
+
+        let cached = store(&dir, &key, &built);
+        assert_ne!(cached, built);
+        assert_eq!(lookup(&dir, &key), Some(cached.clone()));
+
+        fs::write(&cached, b"damaged").unwrap();
+        assert_eq!(lookup(&dir, &key), None);
+        assert!(!dir.join(DIR_NAME).join(&key).exists());
+    }
+}
@@ -0,0 +1,187 @@
+//! Wheels pinned with `wheel-tag`, eg `numpy = { version = "1.26.2", wheel-tag =
+//! "cp311-cp311-manylinux_2_28_x86_64" }`, for when the wheel we'd pick misbehaves. The pinned
+//! file is recorded in the lock, and installed instead of the one we'd pick.
+
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    dep_resolution::{WarehouseDigests, WarehouseRelease},
+    dep_types::Version,
+    util::{wheel_rejection, Os, RejectReason},
+};
+
+static ALLOW_FOREIGN: AtomicBool = AtomicBool::new(false);
+
+/// For `--allow-foreign-wheel`: install pinned wheels even if they're for another platform or
+/// Python version, eg when bundling for one.
+pub fn allow_foreign() {
+    ALLOW_FOREIGN.store(true, Ordering::SeqCst);
+}
+
+/// A wheel's tag, eg `cp311-cp311-manylinux_2_28_x86_64`: its Python, ABI, and platform tags.
+pub fn of(filename: &str) -> Option<String> {
+    let stem = filename.strip_suffix(".whl")?;
+    let mut parts: Vec<&str> = stem.rsplitn(4, '-').collect();
+    if parts.len() < 4 {
+        return None;
+    }
+    parts.truncate(3);
+    parts.reverse();
+    Some(parts.join("-"))
+}
+
+/// If `tag` has the form of a wheel's, with a platform we know.
+pub fn is_valid(tag: &str) -> bool {
+    let parts: Vec<&str> = tag.split('-').collect();
+    parts.len() == 3 && parts.iter().all(|p| !p.is_empty()) && Os::from_str(parts[2]).is_ok()
+}
+
+/// The wheel among `filenames` whose name contains `tag`. If there's none, the error lists the
+/// tags there are.
+pub fn find<'a>(
+    filenames: impl Iterator<Item = &'a str> + Clone,
+    name: &str,
+    version: &Version,
+    tag: &str,
+) -> Result<&'a str, String> {
+    let wheels = filenames.filter(|f| f.ends_with(".whl"));
+    if let Some(found) = wheels.clone().find(|f| f.contains(tag)) {
+        return Ok(found);
+    }
+    let mut tags: Vec<String> = wheels.filter_map(of).collect();
+    tags.sort();
+    tags.dedup();
+    Err(if tags.is_empty() {
+        format!(
+            "{} {} has no wheels, so can't be installed with `wheel-tag = \"{}\"`",
+            name, version, tag
+        )
+    } else {
+        format!(
+            "{} {} has no wheel tagged {}. Its wheels are tagged:\n  {}",
+            name,
+            version,
+            tag,
+            tags.join("\n  ")
+        )
+    })
+}
+
+/// Why a pinned wheel can't be installed for this OS and Python version, unless
+/// `--allow-foreign-wheel` was passed.
+fn rejection(release: &WarehouseRelease, os: Os, python_vers: &Version) -> Option<RejectReason> {
+    if ALLOW_FOREIGN.load(Ordering::SeqCst) {
+        return None;
+    }
+    wheel_rejection(release, os, python_vers)
+}
+
+/// Check a pinned wheel can be installed here.
+pub fn check(
+    release: &WarehouseRelease,
+    name: &str,
+    os: Os,
+    python_vers: &Version,
+) -> Result<(), String> {
+    match rejection(release, os, python_vers) {
+        Some(reason) => Err(format!(
+            "The wheel pinned for {} with `wheel-tag`, {}, doesn't fit this environment: {}. Pass \
+             `--allow-foreign-wheel` to install it anyway",
+            name, release.filename, reason
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Check a tag from `--wheel-tag` before saving it, the way its wheel would be checked.
+pub fn check_tag(tag: &str, os: Os, python_vers: &Version) -> Result<(), String> {
+    if !is_valid(tag) {
+        return Err(format!(
+            "`{}` isn't a wheel tag. Expected its Python, ABI, and platform tags, eg \
+             `cp311-cp311-manylinux_2_28_x86_64`",
+            tag
+        ));
+    }
+    let release = WarehouseRelease {
+        filename: format!("pinned-0-{}.whl", tag),
+        has_sig: false,
+        digests: WarehouseDigests::default(),
+        packagetype: "bdist_wheel".to_owned(),
+        python_version: tag.split('-').next().unwrap_or_default().to_owned(),
+        requires_python: None,
+        url: String::new(),
+        dependencies: None,
+        size: None,
+    };
+    match rejection(&release, os, python_vers) {
+        Some(reason) => Err(format!(
+            "`{}` doesn't fit this environment: {}. Pass `--allow-foreign-wheel` to use it anyway",
+            tag, reason
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILES: [&str; 4] = [
+        "numpy-1.26.2-cp311-cp311-manylinux_2_17_x86_64.manylinux2014_x86_64.whl",
+        "numpy-1.26.2-cp311-cp311-musllinux_1_1_x86_64.whl",
+        "numpy-1.26.2-cp312-cp312-musllinux_1_1_x86_64.whl",
+        "numpy-1.26.2.tar.gz",
+    ];
+
+    #[test]
+    fn tags_parsed() {
+        assert_eq!(
+            of("numpy-1.26.2-cp311-cp311-musllinux_1_1_x86_64.whl").as_deref(),
+            Some("cp311-cp311-musllinux_1_1_x86_64")
+        );
+        // With a build tag.
+        assert_eq!(
+            of("PyQt5-5.13.0-5.13.0-cp35.cp36-none-win32.whl").as_deref(),
+            Some("cp35.cp36-none-win32")
+        );
+        assert_eq!(of("numpy-1.26.2.tar.gz"), None);
+
+        assert!(is_valid("cp311-cp311-manylinux_2_28_x86_64"));
+        assert!(!is_valid("manylinux_2_28_x86_64"));
+        assert!(!is_valid("cp311--any"));
+        assert!(!is_valid("cp311-cp311-beos"));
+    }
+
+    #[test]
+    fn tags_checked_for_this_environment() {
+        let py311 = Version::new(3, 11, 4);
+        assert!(check_tag("cp311-cp311-musllinux_1_1_x86_64", Os::Linux, &py311).is_ok());
+        assert!(check_tag("py3-none-any", Os::Mac, &py311).is_ok());
+
+        let err = check_tag("cp311-cp311-win_amd64", Os::Linux, &py311).unwrap_err();
+        assert!(err.contains("platform") && err.contains("--allow-foreign-wheel"));
+        let err = check_tag("cp312-cp312-musllinux_1_1_x86_64", Os::Linux, &py311).unwrap_err();
+        assert!(err.contains("cp312"));
+        assert!(check_tag("musllinux_1_1_x86_64", Os::Linux, &py311)
+            .unwrap_err()
+            .contains("isn't a wheel tag"));
+    }
+
+    #[test]
+    fn pinned_wheel_found() {
+        let version = Version::new(1, 26, 2);
+        let pinned = |tag| find(FILES.iter().copied(), "numpy", &version, tag);
+        assert_eq!(pinned("cp311-cp311-musllinux_1_1_x86_64"), Ok(FILES[1]));
+
+        let err = pinned("cp311-cp311-manylinux_2_28_aarch64").unwrap_err();
+        assert!(err.contains("cp311-cp311-musllinux_1_1_x86_64"));
+        assert!(err.contains("cp312-cp312-musllinux_1_1_x86_64"));
+        assert!(!err.contains("tar.gz"));
+
+        let err = find(FILES[3..].iter().copied(), "numpy", &version, "any").unwrap_err();
+        assert!(err.contains("has no wheels"));
+    }
+}
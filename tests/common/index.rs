@@ -0,0 +1,245 @@
+//! A package index on localhost with PyPI's JSON API, serving wheels from `WheelBuilder` and
+//! source dists from `SdistBuilder`:
+//! `/pypi/<name>/json`, `/pypi/<name>/<version>/json`, and the files at `/files/<filename>`.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use data_encoding::HEXLOWER;
+use ring::digest::{digest, SHA256};
+use serde_json::{json, Value};
+
+use super::wheel::WheelBuilder;
+
+/// A built wheel or source dist, and what the index says about it.
+#[derive(Clone, Debug)]
+pub struct Dist {
+    pub name: String,
+    pub version: String,
+    pub requires_dist: Vec<String>,
+    pub filename: String,
+    pub data: Vec<u8>,
+    /// The hash the index lists; the file's, unless `with_wrong_hash`.
+    pub sha256: String,
+}
+
+impl Dist {
+    pub fn new(
+        filename: String,
+        name: String,
+        version: String,
+        requires_dist: Vec<String>,
+        data: Vec<u8>,
+    ) -> Self {
+        Self {
+            sha256: HEXLOWER.encode(digest(&SHA256, &data).as_ref()),
+            filename,
+            name,
+            version,
+            requires_dist,
+            data,
+        }
+    }
+
+    /// List a hash that doesn't match the file, as if it were tampered with in transit.
+    pub fn with_wrong_hash(mut self) -> Self {
+        self.sha256 = HEXLOWER.encode(digest(&SHA256, b"something else").as_ref());
+        self
+    }
+
+    fn is_wheel(&self) -> bool {
+        self.filename.ends_with(".whl")
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// By standardized name.
+    packages: BTreeMap<String, Vec<Dist>>,
+    /// The paths requested, in order.
+    requests: Vec<String>,
+}
+
+pub struct FakeIndex {
+    base: String,
+    state: Arc<Mutex<State>>,
+}
+
+fn standardize(name: &str) -> String {
+    name.to_lowercase().replace(['-', '.'], "_")
+}
+
+/// For picking the latest version; fixtures only use numeric ones.
+fn version_key(version: &str) -> Vec<u32> {
+    version.split('.').map(|p| p.parse().unwrap_or(0)).collect()
+}
+
+impl FakeIndex {
+    /// Start serving on a free port. Stub `wheel` and `setuptools` are published, so setting up
+    /// an environment doesn't reach PyPI.
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let index = Self {
+            base: format!("http://{}", listener.local_addr().unwrap()),
+            state: Arc::new(Mutex::new(State::default())),
+        };
+
+        let (base, state) = (index.base.clone(), index.state.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (base, state) = (base.clone(), state.clone());
+                thread::spawn(move || serve(stream, &base, &state));
+            }
+        });
+
+        index.publish(
+            WheelBuilder::new("wheel", "0.42.0")
+                .file("wheel/__init__.py", "")
+                .build(),
+        );
+        index.publish(
+            WheelBuilder::new("setuptools", "69.0.3")
+                .file("setuptools/__init__.py", "")
+                .build(),
+        );
+        index
+    }
+
+    /// For `index_url`, eg `http://127.0.0.1:51234/pypi`.
+    pub fn url(&self) -> String {
+        format!("{}/pypi", self.base)
+    }
+
+    pub fn publish(&self, dist: Dist) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .packages
+            .entry(standardize(&dist.name))
+            .or_default()
+            .push(dist);
+    }
+
+    /// The files downloaded so far, in order.
+    pub fn downloads(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .filter_map(|path| path.strip_prefix("/files/"))
+            .map(ToOwned::to_owned)
+            .collect()
+    }
+}
+
+fn release(base: &str, dist: &Dist) -> Value {
+    let (packagetype, python_version) = if dist.is_wheel() {
+        ("bdist_wheel", "py3")
+    } else {
+        ("sdist", "source")
+    };
+    json!({
+        "filename": dist.filename,
+        "url": format!("{}/files/{}", base, dist.filename),
+        "digests": { "md5": "", "sha256": dist.sha256 },
+        "packagetype": packagetype,
+        "python_version": python_version,
+        "requires_python": null,
+        "has_sig": false,
+        "size": dist.data.len(),
+    })
+}
+
+fn info(dist: &Dist) -> Value {
+    json!({
+        "name": dist.name,
+        "version": dist.version,
+        "requires_dist": dist.requires_dist,
+        "requires_python": null,
+    })
+}
+
+/// The response body for a path, if there's anything there.
+fn respond(path: &str, base: &str, state: &State) -> Option<Vec<u8>> {
+    if let Some(filename) = path.strip_prefix("/files/") {
+        return state
+            .packages
+            .values()
+            .flatten()
+            .find(|d| d.filename == filename)
+            .map(|d| d.data.clone());
+    }
+
+    let parts: Vec<&str> = path.strip_prefix("/pypi/")?.split('/').collect();
+    let (name, version) = match parts.as_slice() {
+        [name, "json"] => (name, None),
+        [name, version, "json"] => (name, Some(*version)),
+        _ => return None,
+    };
+    let dists = state.packages.get(&standardize(name))?;
+    let body = match version {
+        Some(version) => {
+            let dist = dists.iter().find(|d| d.version == version)?;
+            json!({ "info": info(dist), "urls": [release(base, dist)] })
+        }
+        None => {
+            let latest = dists
+                .iter()
+                .max_by_key(|d| version_key(&d.version))
+                .unwrap();
+            let mut releases = BTreeMap::new();
+            for dist in dists {
+                releases
+                    .entry(dist.version.clone())
+                    .or_insert_with(Vec::new)
+                    .push(release(base, dist));
+            }
+            json!({
+                "info": info(latest),
+                "releases": releases,
+                "urls": [release(base, latest)],
+            })
+        }
+    };
+    Some(body.to_string().into_bytes())
+}
+
+fn serve(mut stream: TcpStream, base: &str, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    // Skip the headers; we only serve `GET`s, without bodies.
+    let mut header = String::new();
+    while reader.read_line(&mut header).is_ok_and(|n| n > 0) && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_owned();
+    let body = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(path.clone());
+        respond(&path, base, &state)
+    };
+    let (status, body) = match body {
+        Some(b) => ("200 OK", b),
+        None => ("404 Not Found", b"Not Found".to_vec()),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).ok();
+    stream.write_all(&body).ok();
+}
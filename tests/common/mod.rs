@@ -0,0 +1,202 @@
+//! Helpers for the integration tests: a fake index, fixture wheels and source dists, and
+//! projects that run the `pyflow` binary against them, with pyflow's shared folders in a
+//! temporary directory.
+
+pub mod index;
+pub mod sdist;
+pub mod wheel;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+use index::FakeIndex;
+use tempfile::TempDir;
+
+/// A Python on this machine to create environments from.
+pub struct Python {
+    pub path: PathBuf,
+    /// eg `3.11`
+    pub version: String,
+}
+
+/// Find a Python 3 to test with. Without one, the tests are skipped, except in CI, where they
+/// fail so they can't silently stop running.
+pub fn python() -> Option<Python> {
+    for alias in ["python3", "python"] {
+        let output = Command::new(alias)
+            .args([
+                "-c",
+                "import sys; print(sys.executable); print('%d.%d' % sys.version_info[:2])",
+            ])
+            .output();
+        let stdout = match output {
+            Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout).into_owned(),
+            _ => continue,
+        };
+        let mut lines = stdout.lines();
+        if let (Some(path), Some(version)) = (lines.next(), lines.next()) {
+            if version.trim().starts_with("3.") {
+                return Some(Python {
+                    path: PathBuf::from(path.trim()),
+                    version: version.trim().to_owned(),
+                });
+            }
+        }
+    }
+    if std::env::var("CI").is_ok_and(|v| !v.is_empty() && v != "false") {
+        panic!("The integration tests need Python 3 on the PATH");
+    }
+    None
+}
+
+/// Whether source dists can be built here: pyflow builds them with the `python3` on the `PATH`
+/// (`python` on Windows), which needs `setuptools`, and `wheel` for setuptools older than 70.1.
+pub fn can_build_sdists() -> bool {
+    let python = if cfg!(windows) { "python" } else { "python3" };
+    Command::new(python)
+        .args([
+            "-c",
+            "import setuptools\n\
+             try:\n    from setuptools.command import bdist_wheel\n\
+             except ImportError:\n    import wheel",
+        ])
+        .output()
+        .is_ok_and(|o| o.status.success())
+}
+
+/// A project in a temporary directory, whose dependencies come from a `FakeIndex`.
+pub struct Project {
+    root: TempDir,
+    pub path: PathBuf,
+    py_version: String,
+}
+
+impl Project {
+    /// `deps` are lines for `[tool.pyflow.dependencies]`, eg `greeter = "^1.0"`.
+    pub fn new(name: &str, python: &Python, index: &FakeIndex, deps: &[&str]) -> Self {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("project");
+        fs::create_dir_all(&path).unwrap();
+
+        let interpreter = toml::Value::String(python.path.to_string_lossy().into_owned());
+        fs::write(
+            path.join("pyproject.toml"),
+            format!(
+                "[tool.pyflow]\nname = \"{}\"\nversion = \"0.1.0\"\npy_version = \"{}\"\n\
+                 py_interpreter = {}\n\n[tool.pyflow.settings]\nindex_url = \"{}\"\n\n\
+                 [tool.pyflow.dependencies]\n{}\n",
+                name,
+                python.version,
+                interpreter,
+                index.url(),
+                deps.join("\n")
+            ),
+        )
+        .unwrap();
+
+        Self {
+            root,
+            path,
+            py_version: python.version.clone(),
+        }
+    }
+
+    /// Run `pyflow` in the project, without a terminal, so it can't wait on prompts.
+    pub fn pyflow(&self, args: &[&str]) -> Output {
+        self.pyflow_in(Path::new("."), args)
+    }
+
+    /// Run `pyflow` in `cwd`, relative to the project, eg a subdirectory of it.
+    pub fn pyflow_in(&self, cwd: &Path, args: &[&str]) -> Output {
+        let dir = |name: &str| self.root.path().join(name);
+        let mut command = Command::new(env!("CARGO_BIN_EXE_pyflow"));
+        command
+            .args(args)
+            .current_dir(self.path.join(cwd))
+            .stdin(Stdio::null())
+            .env("PYFLOW_CACHE_DIR", dir("cache"))
+            .env("PYFLOW_PYTHON_DIR", dir("pythons"))
+            .env("PYFLOW_SCRIPT_ENV_DIR", dir("script_envs"))
+            .env("PYFLOW_GIT_DIR", dir("git"))
+            .env_remove("PYTHONPATH");
+        // Keep crash reports and the user config out of the real home directory, where we can.
+        #[cfg(not(windows))]
+        command
+            .env("HOME", dir("home"))
+            .env("XDG_DATA_HOME", dir("home/data"));
+        command.output().unwrap()
+    }
+
+    /// Run `pyflow`, and fail with its output if it does.
+    pub fn pyflow_ok(&self, args: &[&str]) -> String {
+        self.pyflow_ok_in(Path::new("."), args)
+    }
+
+    /// Run `pyflow` in `cwd`, relative to the project, and fail with its output if it does.
+    pub fn pyflow_ok_in(&self, cwd: &Path, args: &[&str]) -> String {
+        let output = self.pyflow_in(cwd, args);
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        assert!(
+            output.status.success(),
+            "`pyflow {}` failed:\n{}\n{}",
+            args.join(" "),
+            stdout,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        stdout
+    }
+
+    fn vers_path(&self) -> PathBuf {
+        self.path.join("__pypackages__").join(&self.py_version)
+    }
+
+    pub fn lib(&self) -> PathBuf {
+        self.vers_path().join("lib")
+    }
+
+    /// Where console scripts go.
+    pub fn scripts(&self) -> PathBuf {
+        self.vers_path().join("bin")
+    }
+
+    /// The names in `dir`, sorted.
+    pub fn contents(dir: &Path) -> Vec<String> {
+        let mut result: Vec<String> = fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|e| e.file_name().to_string_lossy().into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        result.sort();
+        result
+    }
+
+    pub fn lock(&self) -> String {
+        fs::read_to_string(self.path.join("pyflow.lock")).unwrap_or_default()
+    }
+
+    /// The locked packages, as `name version`.
+    pub fn locked(&self) -> Vec<String> {
+        let lock: toml::Value = toml::from_str(&self.lock()).unwrap();
+        let mut result: Vec<String> = lock
+            .get("package")
+            .and_then(|p| p.as_array())
+            .into_iter()
+            .flatten()
+            .map(|p| {
+                format!(
+                    "{} {}",
+                    p["name"].as_str().unwrap(),
+                    p["version"].as_str().unwrap()
+                )
+            })
+            .collect();
+        result.sort();
+        result
+    }
+}
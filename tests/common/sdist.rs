@@ -0,0 +1,66 @@
+//! Source dists built in memory, as `.tar.gz` archives with everything under a
+//! `<name>-<version>/` folder, the way `setup.py sdist` lays them out.
+
+use flate2::{write::GzEncoder, Compression};
+use tar::{Builder, Header};
+
+use super::index::Dist;
+
+pub struct SdistBuilder {
+    name: String,
+    version: String,
+    files: Vec<(String, String)>,
+}
+
+impl SdistBuilder {
+    /// A source dist with a `PKG-INFO`, and no other files.
+    pub fn new(name: &str, version: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            files: vec![],
+        }
+    }
+
+    /// A file, relative to the top folder, eg `("setup.cfg", "[metadata]\n...")`.
+    pub fn file(mut self, path: &str, contents: &str) -> Self {
+        self.files.push((path.to_owned(), contents.to_owned()));
+        self
+    }
+
+    pub fn build(self) -> Dist {
+        let top = format!("{}-{}", self.name, self.version);
+        let mut files = vec![(
+            "PKG-INFO".to_owned(),
+            format!(
+                "Metadata-Version: 2.1\nName: {}\nVersion: {}\n",
+                self.name, self.version
+            ),
+        )];
+        files.extend(self.files);
+
+        let mut tar = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, contents) in &files {
+            let mut header = Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(0);
+            header.set_cksum();
+            tar.append_data(
+                &mut header,
+                format!("{}/{}", top, path),
+                contents.as_bytes(),
+            )
+            .unwrap();
+        }
+        let data = tar.into_inner().unwrap().finish().unwrap();
+
+        Dist::new(
+            format!("{}.tar.gz", top),
+            self.name,
+            self.version,
+            vec![],
+            data,
+        )
+    }
+}
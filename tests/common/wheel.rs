@@ -0,0 +1,119 @@
+//! Wheels built in memory, with the metadata pyflow reads: `METADATA`, `top_level.txt`,
+//! `entry_points.txt`, and a `RECORD` with each file's hash.
+
+use std::io::{Cursor, Write};
+
+use data_encoding::BASE64URL_NOPAD;
+use ring::digest::{digest, SHA256};
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+use super::index::Dist;
+
+pub struct WheelBuilder {
+    name: String,
+    version: String,
+    files: Vec<(String, String)>,
+    requires_dist: Vec<String>,
+    scripts: Vec<(String, String)>,
+}
+
+impl WheelBuilder {
+    pub fn new(name: &str, version: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            files: vec![],
+            requires_dist: vec![],
+            scripts: vec![],
+        }
+    }
+
+    /// A file in the wheel, eg `("greeter/__init__.py", "def main(): ...")`.
+    pub fn file(mut self, path: &str, contents: &str) -> Self {
+        self.files.push((path.to_owned(), contents.to_owned()));
+        self
+    }
+
+    /// A `Requires-Dist` line, eg `colorist>=0.2` or `shim; python_version < "3"`.
+    pub fn requires(mut self, dist: &str) -> Self {
+        self.requires_dist.push(dist.to_owned());
+        self
+    }
+
+    /// A console script, eg `("greet", "greeter:main")`.
+    pub fn script(mut self, name: &str, target: &str) -> Self {
+        self.scripts.push((name.to_owned(), target.to_owned()));
+        self
+    }
+
+    pub fn build(self) -> Dist {
+        let dist = self.name.replace('-', "_");
+        let dist_info = format!("{}-{}.dist-info", dist, self.version);
+
+        let mut metadata = format!(
+            "Metadata-Version: 2.1\nName: {}\nVersion: {}\n",
+            self.name, self.version
+        );
+        for req in &self.requires_dist {
+            metadata.push_str(&format!("Requires-Dist: {}\n", req));
+        }
+        let mut top_level: Vec<&str> = self
+            .files
+            .iter()
+            .map(|(path, _)| {
+                let first = path.split('/').next().unwrap_or(path);
+                first.strip_suffix(".py").unwrap_or(first)
+            })
+            .collect();
+        top_level.sort_unstable();
+        top_level.dedup();
+
+        let mut files = self.files.clone();
+        files.push((format!("{}/METADATA", dist_info), metadata));
+        files.push((
+            format!("{}/WHEEL", dist_info),
+            "Wheel-Version: 1.0\nGenerator: pyflow-tests\nRoot-Is-Purelib: true\n\
+             Tag: py3-none-any\n"
+                .to_owned(),
+        ));
+        files.push((
+            format!("{}/top_level.txt", dist_info),
+            top_level.join("\n") + "\n",
+        ));
+        if !self.scripts.is_empty() {
+            let mut entry_points = "[console_scripts]\n".to_owned();
+            for (name, target) in &self.scripts {
+                entry_points.push_str(&format!("{} = {}\n", name, target));
+            }
+            files.push((format!("{}/entry_points.txt", dist_info), entry_points));
+        }
+
+        let mut record = String::new();
+        for (path, contents) in &files {
+            record.push_str(&format!(
+                "{},sha256={},{}\n",
+                path,
+                BASE64URL_NOPAD.encode(digest(&SHA256, contents.as_bytes()).as_ref()),
+                contents.len()
+            ));
+        }
+        record.push_str(&format!("{}/RECORD,,\n", dist_info));
+        files.push((format!("{}/RECORD", dist_info), record));
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (path, contents) in &files {
+            zip.start_file(path.as_str(), SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        let data = zip.finish().unwrap().into_inner();
+
+        Dist::new(
+            format!("{}-{}-py3-none-any.whl", dist, self.version),
+            self.name,
+            self.version,
+            self.requires_dist,
+            data,
+        )
+    }
+}
@@ -0,0 +1,396 @@
+//! The install pipeline, end to end: resolving against an index, downloading, checking hashes,
+//! extracting, setting up scripts, and locking. Each test runs the `pyflow` binary against a
+//! `FakeIndex`, and is skipped if there's no Python 3 to create an environment from.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use common::{index::FakeIndex, sdist::SdistBuilder, wheel::WheelBuilder, Project};
+
+/// `greeter`, with a console script, requires `colorist`, and `legacy-shim` only on Python 2.
+fn publish_fixtures(index: &FakeIndex) {
+    for version in ["0.1.0", "0.2.0"] {
+        index.publish(
+            WheelBuilder::new("colorist", version)
+                .file(
+                    "colorist/__init__.py",
+                    "def paint(text):\n    return text\n",
+                )
+                .build(),
+        );
+    }
+    index.publish(
+        WheelBuilder::new("greeter", "1.0.0")
+            .file(
+                "greeter/__init__.py",
+                "import colorist\n\n\n\
+                 def main():\n    print(colorist.paint(\"hello from greeter\"))\n",
+            )
+            .requires("colorist>=0.2")
+            .requires("legacy-shim>=1.0; python_version < \"3\"")
+            .script("greet", "greeter:main")
+            .build(),
+    );
+    index.publish(
+        WheelBuilder::new("legacy-shim", "1.0.0")
+            .file("legacy_shim/__init__.py", "")
+            .build(),
+    );
+}
+
+fn installed(project: &Project, prefix: &str) -> bool {
+    Project::contents(&project.lib())
+        .iter()
+        .any(|name| name.starts_with(prefix))
+}
+
+#[test]
+fn clean_install() {
+    let python = match common::python() {
+        Some(p) => p,
+        None => return,
+    };
+    let index = FakeIndex::start();
+    publish_fixtures(&index);
+    let project = Project::new("clean-install", &python, &index, &["greeter = \"^1.0\""]);
+
+    project.pyflow_ok(&["install"]);
+    let lib = Project::contents(&project.lib());
+    for name in [
+        "colorist",
+        "colorist-0.2.0.dist-info",
+        "greeter",
+        "greeter-1.0.0.dist-info",
+    ] {
+        assert!(
+            lib.contains(&name.to_owned()),
+            "{} isn't in {:?}",
+            name,
+            lib
+        );
+    }
+    // Its marker doesn't apply.
+    assert!(!installed(&project, "legacy_shim"));
+
+    assert!(Project::contents(&project.scripts()).contains(&"greet".to_owned()));
+    let stdout = project.pyflow_ok(&["run", "greet"]);
+    assert!(stdout.contains("hello from greeter"));
+
+    let locked = project.locked();
+    assert!(locked.contains(&"colorist 0.2.0".to_owned()));
+    assert!(locked.contains(&"greeter 1.0.0".to_owned()));
+    // Sources name the index the packages came from.
+    assert!(project.lock().contains(&index.url()));
+}
+
+#[test]
+fn reinstall_is_idempotent() {
+    let python = match common::python() {
+        Some(p) => p,
+        None => return,
+    };
+    let index = FakeIndex::start();
+    publish_fixtures(&index);
+    let project = Project::new("reinstall", &python, &index, &["greeter = \"^1.0\""]);
+
+    project.pyflow_ok(&["install"]);
+    let (lib, scripts, lock) = (
+        Project::contents(&project.lib()),
+        Project::contents(&project.scripts()),
+        project.lock(),
+    );
+    let downloads = index.downloads();
+    assert!(downloads.contains(&"greeter-1.0.0-py3-none-any.whl".to_owned()));
+
+    project.pyflow_ok(&["install"]);
+    assert_eq!(Project::contents(&project.lib()), lib);
+    assert_eq!(Project::contents(&project.scripts()), scripts);
+    assert_eq!(project.lock(), lock);
+    // Nothing's downloaded again.
+    assert_eq!(index.downloads(), downloads);
+}
+
+#[test]
+fn hash_mismatch_rejected() {
+    let python = match common::python() {
+        Some(p) => p,
+        None => return,
+    };
+    let index = FakeIndex::start();
+    index.publish(
+        WheelBuilder::new("tampered", "1.0.0")
+            .file("tampered/__init__.py", "")
+            .build()
+            .with_wrong_hash(),
+    );
+    let project = Project::new("hash-mismatch", &python, &index, &["tampered = \"1.0.0\""]);
+
+    // Without a terminal to ask whether to continue on, and with hashes required.
+    for args in [&["install"][..], &["--require-hashes", "install"][..]] {
+        let output = project.pyflow(args);
+        assert!(!output.status.success());
+        let printed = format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(printed.contains("Hash failed for tampered-1.0.0-py3-none-any.whl"));
+        assert!(!installed(&project, "tampered"));
+    }
+}
+
+#[test]
+fn uninstall_leaves_nothing_behind() {
+    let python = match common::python() {
+        Some(p) => p,
+        None => return,
+    };
+    let index = FakeIndex::start();
+    publish_fixtures(&index);
+    let project = Project::new("uninstall", &python, &index, &["greeter = \"^1.0\""]);
+
+    project.pyflow_ok(&["install"]);
+    assert!(installed(&project, "greeter"));
+
+    project.pyflow_ok(&["uninstall", "greeter"]);
+    // Including `colorist`, which nothing requires anymore, and the dist-info folders.
+    for prefix in ["greeter", "colorist"] {
+        assert!(
+            !installed(&project, prefix),
+            "{} is still installed",
+            prefix
+        );
+    }
+    assert!(!Project::contents(&project.scripts()).contains(&"greet".to_owned()));
+    assert!(project.locked().is_empty());
+    let cfg = fs::read_to_string(project.path.join("pyproject.toml")).unwrap();
+    assert!(!cfg.contains("greeter"));
+}
+
+#[test]
+fn uninstall_keeps_preserved_packages() {
+    let python = match common::python() {
+        Some(p) => p,
+        None => return,
+    };
+    let index = FakeIndex::start();
+    publish_fixtures(&index);
+    let project = Project::new(
+        "uninstall-preserve",
+        &python,
+        &index,
+        &["greeter = \"^1.0\""],
+    );
+    let cfg_path = project.path.join("pyproject.toml");
+    let cfg = fs::read_to_string(&cfg_path).unwrap();
+    fs::write(
+        &cfg_path,
+        cfg.replacen(
+            "[tool.pyflow]\n",
+            "[tool.pyflow]\npreserve = [\"vendored\"]\n",
+            1,
+        ),
+    )
+    .unwrap();
+
+    project.pyflow_ok(&["install"]);
+    // Copied into `lib` by hand, so pyflow never installed them.
+    for name in ["vendored", "patched"] {
+        let dist_info = project.lib().join(format!("{}-0.1.0.dist-info", name));
+        fs::create_dir_all(&dist_info).unwrap();
+        fs::write(dist_info.join("top_level.txt"), format!("{}\n", name)).unwrap();
+        fs::create_dir_all(project.lib().join(name)).unwrap();
+    }
+
+    project.pyflow_ok(&["--prune-foreign", "uninstall", "greeter"]);
+    assert!(!installed(&project, "greeter"));
+    assert!(!installed(&project, "patched"));
+    assert!(installed(&project, "vendored"));
+}
+
+#[test]
+fn extras_without_editable_rejected_before_syncing() {
+    let python = match common::python() {
+        Some(p) => p,
+        None => return,
+    };
+    let index = FakeIndex::start();
+    publish_fixtures(&index);
+    let project = Project::new("extras", &python, &index, &["greeter = \"^1.0\""]);
+
+    let output = project.pyflow(&["install", "--extras", "cli"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("requires `-e .`"));
+    assert!(!installed(&project, "greeter"));
+    assert!(project.lock().is_empty());
+}
+
+#[test]
+fn lock_round_trip() {
+    let python = match common::python() {
+        Some(p) => p,
+        None => return,
+    };
+    let index = FakeIndex::start();
+    publish_fixtures(&index);
+    let project = Project::new("lock-round-trip", &python, &index, &["greeter = \"^1.0\""]);
+
+    project.pyflow_ok(&["install"]);
+    let lock = project.lock();
+    let lib = Project::contents(&project.lib());
+
+    // A newer `colorist` is published, but reinstalling from the lock keeps the locked one.
+    index.publish(
+        WheelBuilder::new("colorist", "0.3.0")
+            .file(
+                "colorist/__init__.py",
+                "def paint(text):\n    return text.upper()\n",
+            )
+            .build(),
+    );
+    fs::remove_dir_all(project.path.join("__pypackages__")).unwrap();
+    project.pyflow_ok(&["install"]);
+
+    assert_eq!(project.lock(), lock);
+    assert_eq!(Project::contents(&project.lib()), lib);
+    let stdout = project.pyflow_ok(&["run", "greet"]);
+    assert!(stdout.contains("hello from greeter"));
+}
+
+#[test]
+fn setup_cfg_only_sdist_built() {
+    let python = match common::python() {
+        Some(p) if common::can_build_sdists() => p,
+        _ => return,
+    };
+    let index = FakeIndex::start();
+    // A flat module, without a `setup.py`.
+    index.publish(
+        SdistBuilder::new("flat", "1.0.0")
+            .file(
+                "setup.cfg",
+                "[metadata]\nname = flat\nversion = 1.0.0\n\n[options]\npy_modules = flat\n",
+            )
+            .file("flat.py", "GREETING = \"hello from flat\"\n")
+            .build(),
+    );
+    let project = Project::new("setup-cfg-only", &python, &index, &["flat = \"1.0.0\""]);
+
+    project.pyflow_ok(&["install"]);
+    assert!(installed(&project, "flat.py"));
+    // The unpacked source was cleaned up.
+    assert!(!Project::contents(&project.lib()).contains(&"flat-1.0.0".to_owned()));
+    let stdout = project.pyflow_ok(&["python", "-c", "import flat; print(flat.GREETING)"]);
+    assert!(stdout.contains("hello from flat"));
+}
+
+#[test]
+fn bare_sdist_layout_reported() {
+    let python = match common::python() {
+        Some(p) => p,
+        None => return,
+    };
+    let index = FakeIndex::start();
+    // Neither `setup.py` nor `setup.cfg`.
+    index.publish(
+        SdistBuilder::new("bare", "1.0.0")
+            .file("bare.py", "")
+            .file("docs/index.md", "")
+            .build(),
+    );
+    let project = Project::new("bare-sdist", &python, &index, &["bare = \"1.0.0\""]);
+
+    let output = project.pyflow(&["install"]);
+    assert!(!output.status.success());
+    let printed = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        printed.contains(
+            "no `setup.py` or `setup.cfg` in bare-1.0.0.tar.gz; it has PKG-INFO, bare.py, docs/"
+        ),
+        "{}",
+        printed
+    );
+    // The unpacked source isn't left behind.
+    assert!(!installed(&project, "bare"));
+}
+
+#[test]
+fn python2_project_stops_only_to_create_an_environment() {
+    let python = match common::python() {
+        Some(p) => p,
+        None => return,
+    };
+    let index = FakeIndex::start();
+    publish_fixtures(&index);
+    let project = Project::new("python2-poetry", &python, &index, &[]);
+    fs::write(
+        project.path.join("pyproject.toml"),
+        format!(
+            "[tool.poetry]\nname = \"legacy\"\nversion = \"0.1.0\"\n\n\
+             [tool.poetry.dependencies]\npython = \"^2.7\"\ngreeter = \"^1.0\"\n\n\
+             [tool.pyflow.settings]\nindex_url = \"{}\"\n",
+            index.url()
+        ),
+    )
+    .unwrap();
+    fs::write(
+        project.path.join("pyflow.lock"),
+        "[[package]]\nid = 1\nname = \"greeter\"\nversion = \"1.0.0\"\n\n[metadata]\n",
+    )
+    .unwrap();
+    let warning = "only allows Python 2";
+
+    // Reading the config and lock doesn't need an environment.
+    let stdout = project.pyflow_ok(&["graph"]);
+    assert!(stdout.contains("greeter"), "{}", stdout);
+    assert_eq!(stdout.matches(warning).count(), 1, "{}", stdout);
+
+    let output = project.pyflow(&["install"]);
+    assert!(!output.status.success());
+    let printed = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(printed.contains(warning), "{}", printed);
+    assert!(
+        printed.contains("pyflow can't target Python 2.7")
+            && printed.contains("`pyflow config set py_version 3.11`"),
+        "{}",
+        printed
+    );
+    assert!(!project.path.join("__pypackages__/2.7").exists());
+}
+
+#[test]
+fn commands_run_from_subdirectories() {
+    let python = match common::python() {
+        Some(p) => p,
+        None => return,
+    };
+    let index = FakeIndex::start();
+    publish_fixtures(&index);
+    let project = Project::new("subdirectories", &python, &index, &["greeter = \"^1.0\""]);
+    let subdir = Path::new("src/mylib");
+    fs::create_dir_all(project.path.join(subdir)).unwrap();
+
+    // The project's environment and lock are used, not new ones in the subdirectory.
+    project.pyflow_ok_in(subdir, &["install"]);
+    assert!(installed(&project, "greeter"));
+    assert!(project.path.join("pyflow.lock").exists());
+    assert!(Project::contents(&project.path.join(subdir)).is_empty());
+
+    let listed = project.pyflow_ok_in(subdir, &["list"]);
+    assert!(listed.contains("greeter"), "{}", listed);
+
+    // Including from inside the environment.
+    for cwd in [subdir.to_owned(), project.lib()] {
+        let stdout = project.pyflow_ok_in(&cwd, &["run", "greet"]);
+        assert!(stdout.contains("hello from greeter"));
+    }
+}
@@ -0,0 +1,46 @@
+//! `pyflow new`, run without a terminal, so it can't ask for the Python version.
+
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Output, Stdio},
+};
+
+fn pyflow_new(dir: &Path, args: &[&str]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_pyflow"));
+    command
+        .arg("new")
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .env("PYFLOW_CACHE_DIR", dir.join("cache"))
+        .env("PYFLOW_PYTHON_DIR", dir.join("pythons"))
+        .env("PYFLOW_SCRIPT_ENV_DIR", dir.join("script_envs"))
+        .env("PYFLOW_GIT_DIR", dir.join("git"));
+    #[cfg(not(windows))]
+    command
+        .env("HOME", dir.join("home"))
+        .env("XDG_DATA_HOME", dir.join("home/data"));
+    command.output().unwrap()
+}
+
+#[test]
+fn nothing_created_without_a_python_version() {
+    let tmp = tempfile::tempdir().unwrap();
+
+    let output = pyflow_new(tmp.path(), &["demo"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("pyflow new demo --py 3.11"));
+    assert!(!tmp.path().join("demo").exists());
+}
+
+#[test]
+fn py_sets_the_version() {
+    let tmp = tempfile::tempdir().unwrap();
+
+    let output = pyflow_new(tmp.path(), &["demo", "--py", "3.11"]);
+    assert!(output.status.success());
+    let cfg = fs::read_to_string(tmp.path().join("demo/pyproject.toml")).unwrap();
+    assert!(cfg.contains("py_version = \"3.11\""));
+    assert!(tmp.path().join("demo/demo/__init__.py").exists());
+}